@@ -144,4 +144,20 @@ pub enum ScanCode {
     SBCSChar = 0x77,
     Convert = 0x79,
     NonConvert = 0x7B, /* VK_OEM_PA1 */
+
+    /* Scancodes without a common key, e.g. from compact or international keyboards. */
+    Sc55 = 0x55,
+    Sc59 = 0x59,
+    Sc60 = 0x60,
+    Sc61 = 0x61,
+    Sc72 = 0x72,
+    Sc73 = 0x73,
+    Sc74 = 0x74,
+    Sc75 = 0x75,
+    Sc78 = 0x78,
+    Sc7A = 0x7A,
+    Sc7C = 0x7C,
+    Sc7D = 0x7D,
+    Sc7E = 0x7E,
+    Sc7F = 0x7F,
 }
@@ -1,7 +1,7 @@
 //! The different actions that can be executed via any given key.
 
 use crate::key_code::KeyCode;
-use crate::layout::{QueuedIter, WaitingAction};
+use crate::layout::{KCoord, QueuedIter, WaitingAction};
 use core::fmt::Debug;
 
 pub mod switch;
@@ -26,6 +26,12 @@ pub enum SequenceEvent<'a, T: 'a> {
     },
     /// Custom event in sequence.
     Custom(&'a T),
+    /// Sets the minimum delay after each following press and release of this sequence,
+    /// overriding `Layout::sequence_type_delay`.
+    TypeDelay {
+        /// How long (in ticks) to wait after each press and release
+        duration: u16,
+    },
     /// Cancels the running sequence and can be used to mark the end of a sequence
     /// instead of using a number of Release() events
     Complete,
@@ -42,6 +48,10 @@ impl<'a, T> Debug for SequenceEvent<'a, T> {
                 f.debug_struct("Delay").field("duration", duration).finish()
             }
             Self::Custom(_) => write!(f, "Custom"),
+            Self::TypeDelay { duration } => f
+                .debug_struct("TypeDelay")
+                .field("duration", duration)
+                .finish(),
             Self::Complete => write!(f, "Complete"),
         }
     }
@@ -85,6 +95,20 @@ pub enum HoldTapConfig<'a> {
     /// timeout is not triggered, the next tick will call the custom handler
     /// again.
     Custom(&'a (dyn Fn(QueuedIter) -> Option<WaitingAction> + Send + Sync)),
+    /// Chordal hold. If the next key pressed is typed with the same hand as the HoldTap key, the
+    /// tap action is activated right away. This avoids accidental holds when rolling over keys
+    /// of one hand. Otherwise, this behaves as [HoldTapConfig::PermissiveHold].
+    ///
+    /// The function returns the hand that types the key at the given coordinate, or [None] if the
+    /// key is not assigned a hand. A key without a hand is never on the same hand as another key.
+    ChordalHold(&'a (dyn Fn(KCoord) -> Option<Hand> + Send + Sync)),
+}
+
+/// A hand that types a key, used by [HoldTapConfig::ChordalHold].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hand {
+    Left,
+    Right,
 }
 
 impl<'a> Debug for HoldTapConfig<'a> {
@@ -94,6 +118,7 @@ impl<'a> Debug for HoldTapConfig<'a> {
             HoldTapConfig::HoldOnOtherKeyPress => f.write_str("HoldOnOtherKeyPress"),
             HoldTapConfig::PermissiveHold => f.write_str("PermissiveHold"),
             HoldTapConfig::Custom(_) => f.write_str("Custom"),
+            HoldTapConfig::ChordalHold(_) => f.write_str("ChordalHold"),
         }
     }
 }
@@ -241,6 +266,11 @@ where
     /// Timeout after which a chord will expire and either trigger its action or be discarded if there is no corresponding action.
     /// A chord may trigger its action even before this timeout expires, if a chord key is released, a non-chord key is pressed or the pressed chord is already uniquely identifyable.
     pub timeout: u16,
+    /// Chords with their own timeout, which should not be longer than [ChordsGroup.timeout].
+    /// Such a chord only triggers if all of its keys are pressed within its timeout after the first key, and once its timeout has passed it can no longer make a shorter chord ambiguous.
+    pub chord_timeouts: &'a [(ChordKeys, u16)],
+    /// Chords whose action is released together with the key that started the chord, instead of being held until all of the chord keys are released.
+    pub release_on_first: &'a [ChordKeys],
 }
 
 impl<'a, T> ChordsGroup<'a, T> {
@@ -249,22 +279,41 @@ impl<'a, T> ChordsGroup<'a, T> {
         self.coords.iter().find(|c| c.0 == coord).map(|c| c.1)
     }
 
-    /// Gets the chord action assigned to the given chord keys.
-    pub fn get_chord(&self, keys: ChordKeys) -> Option<&'a Action<'a, T>> {
+    /// Gets the timeout of the given chord keys: their own timeout if they have one, otherwise the group timeout.
+    pub fn chord_timeout(&self, keys: ChordKeys) -> u16 {
+        self.chord_timeouts
+            .iter()
+            .find(|(chord_keys, _)| *chord_keys == keys)
+            .map(|(_, timeout)| *timeout)
+            .unwrap_or(self.timeout)
+    }
+
+    /// Gets the chord action assigned to the given chord keys, if they were pressed within the timeout of the chord.
+    /// `spread` is the time between the first and the last press of the keys.
+    pub fn get_chord(&self, keys: ChordKeys, spread: u16) -> Option<&'a Action<'a, T>> {
         self.chords
             .iter()
             .find(|(chord_keys, _)| *chord_keys == keys)
+            .filter(|_| spread <= self.chord_timeout(keys))
             .map(|(_, action)| *action)
     }
 
     /// Gets the chord action assigned to the given chord keys if they are already unambigous (i.e. there is no key that could still be pressed that would result in a different chord).
-    pub fn get_chord_if_unambiguous(&self, keys: ChordKeys) -> Option<&'a Action<'a, T>> {
+    /// `elapsed` is the time since the first press: a chord whose timeout has passed can no longer be pressed.
+    pub fn get_chord_if_unambiguous(
+        &self,
+        keys: ChordKeys,
+        spread: u16,
+        elapsed: u16,
+    ) -> Option<&'a Action<'a, T>> {
         self.chords
             .iter()
             .try_fold(None, |res, &(chord_keys, action)| {
                 if chord_keys == keys {
-                    Ok(Some(action))
-                } else if chord_keys | keys == chord_keys {
+                    Ok(Some(action).filter(|_| spread <= self.chord_timeout(keys)))
+                } else if chord_keys | keys == chord_keys
+                    && elapsed <= self.chord_timeout(chord_keys)
+                {
                     // The given keys are a subset of this chord but not an exact match
                     // -> ambiguity
                     Err(())
@@ -274,6 +323,11 @@ impl<'a, T> ChordsGroup<'a, T> {
             })
             .unwrap_or_default()
     }
+
+    /// Whether the action of the given chord keys is released with the key that started the chord.
+    pub fn releases_on_first(&self, keys: ChordKeys) -> bool {
+        self.release_on_first.contains(&keys)
+    }
 }
 
 /// A set of virtual keys (represented as a bit mask) pressed together.
@@ -4,6 +4,9 @@
 //! - Maximum opcode length: 4095
 //! - Maximum boolean expression depth: 8
 //! - Maximum key recency: 7, where 0 is the most recent key press
+//! - Maximum input device index: 4095
+//! - Maximum input device index for checking held keys: 63
+//! - Maximum flag index: 63
 //!
 //! The intended use is to build up a `Switch` struct and use that in the `Layout`.
 //!
@@ -20,6 +23,8 @@ use BreakOrFallthrough::*;
 pub const MAX_OPCODE_LEN: u16 = 0x0FFF;
 pub const MAX_BOOL_EXPR_DEPTH: usize = 8;
 pub const MAX_KEY_RECENCY: u8 = 7;
+pub const MAX_FLAG: u16 = 63;
+pub const MAX_HELD_INPUT_DEVICE: u16 = 63;
 
 pub type Case<'a, T> = (&'a [OpCode], &'a Action<'a, T>, BreakOrFallthrough);
 
@@ -35,6 +40,9 @@ pub struct Switch<'a, T: 'a> {
 
 const OR_VAL: u16 = 0x1000;
 const AND_VAL: u16 = 0x2000;
+const INPUT_DEVICE_VAL: u16 = 0x3000;
+const FLAG_VAL: u16 = 0x4000;
+const INPUT_DEVICE_HELD_VAL: u16 = 0x5000;
 // Highest bit in u16. Lower 3 bits in the highest nibble are "how far back". This means that
 // switch can look back up to 8 keys.
 const HISTORICAL_KEYCODE_VAL: u16 = 0x8000;
@@ -56,6 +64,9 @@ enum OpCodeType {
     BooleanOp(OperatorAndEndIndex),
     KeyCode(u16),
     HistoricalKeyCode(HistoricalKeyCode),
+    InputDevice(u16),
+    InputDeviceHeld(u16),
+    Flag(u16),
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -83,10 +94,21 @@ pub enum BreakOrFallthrough {
 
 impl<'a, T> Switch<'a, T> {
     /// Iterates over the actions (if any) that are activated in the `Switch` based on its cases,
-    /// the currently active keys, and historically pressed keys.
+    /// the currently active keys, historically pressed keys, the input device of the most recent
+    /// key press, the input devices with keys held, and the flags that are set.
     ///
-    /// The `historical_keys` parameter should iterate in the order of most-recent-first.
-    pub fn actions<A, H>(&self, active_keys: A, historical_keys: H) -> SwitchActions<'a, T, A, H>
+    /// The `historical_keys` parameter should iterate in the order of most-recent-first. The
+    /// `held_input_devices` parameter is a bitmask where bit `n` is set if input device `n` has
+    /// at least one key held. The `flags` parameter is a bitmask where bit `n` is set if flag `n`
+    /// is set.
+    pub fn actions<A, H>(
+        &self,
+        active_keys: A,
+        historical_keys: H,
+        input_device: u16,
+        held_input_devices: u64,
+        flags: u64,
+    ) -> SwitchActions<'a, T, A, H>
     where
         A: Iterator<Item = KeyCode> + Clone,
         H: Iterator<Item = KeyCode> + Clone,
@@ -95,6 +117,9 @@ impl<'a, T> Switch<'a, T> {
             cases: self.cases,
             active_keys,
             historical_keys,
+            input_device,
+            held_input_devices,
+            flags,
             case_index: 0,
         }
     }
@@ -110,6 +135,9 @@ where
     cases: &'a [(&'a [OpCode], &'a Action<'a, T>, BreakOrFallthrough)],
     active_keys: A,
     historical_keys: H,
+    input_device: u16,
+    held_input_devices: u64,
+    flags: u64,
     case_index: usize,
 }
 
@@ -127,6 +155,9 @@ where
                 case.0,
                 self.active_keys.clone(),
                 self.historical_keys.clone(),
+                self.input_device,
+                self.held_input_devices,
+                self.flags,
             ) {
                 let ret_ac = case.1;
                 match case.2 {
@@ -166,6 +197,26 @@ impl OpCode {
         Self((kc as u16 & MAX_OPCODE_LEN) | HISTORICAL_KEYCODE_VAL | ((key_recency as u16) << 12))
     }
 
+    /// Return a new OpCode that checks if the most recent key press came from the input device
+    /// with the given index.
+    pub fn new_input_device(device: u16) -> Self {
+        assert!(device <= MAX_OPCODE_LEN);
+        Self(INPUT_DEVICE_VAL | (device & MAX_OPCODE_LEN))
+    }
+
+    /// Return a new OpCode that checks if a key is currently held on the input device with the
+    /// given index.
+    pub fn new_input_device_held(device: u16) -> Self {
+        assert!(device <= MAX_HELD_INPUT_DEVICE);
+        Self(INPUT_DEVICE_HELD_VAL | device)
+    }
+
+    /// Return a new OpCode that checks if the flag with the given index is set.
+    pub fn new_flag(flag: u16) -> Self {
+        assert!(flag <= MAX_FLAG);
+        Self(FLAG_VAL | flag)
+    }
+
     /// Return a new OpCode for a boolean operation that ends (non-inclusive) at the specified
     /// index.
     pub fn new_bool(op: BooleanOperator, end_idx: u16) -> Self {
@@ -181,6 +232,12 @@ impl OpCode {
                 key_code: self.0 & 0x0FFF,
                 how_far_back: ((self.0 & 0x7000) >> 12) as u8,
             })
+        } else if self.0 & 0xF000 == INPUT_DEVICE_VAL {
+            OpCodeType::InputDevice(self.0 & MAX_OPCODE_LEN)
+        } else if self.0 & 0xF000 == INPUT_DEVICE_HELD_VAL {
+            OpCodeType::InputDeviceHeld(self.0 & MAX_OPCODE_LEN)
+        } else if self.0 & 0xF000 == FLAG_VAL {
+            OpCodeType::Flag(self.0 & MAX_OPCODE_LEN)
         } else {
             OpCodeType::BooleanOp(OperatorAndEndIndex::from(self.0))
         }
@@ -205,6 +262,9 @@ fn evaluate_boolean(
     bool_expr: &[OpCode],
     key_codes: impl Iterator<Item = KeyCode> + Clone,
     historical_keys: impl Iterator<Item = KeyCode> + Clone,
+    input_device: u16,
+    held_input_devices: u64,
+    flags: u64,
 ) -> bool {
     let mut ret = true;
     let mut current_index = 0;
@@ -246,6 +306,27 @@ fn evaluate_boolean(
                     continue;
                 }
             }
+            OpCodeType::InputDevice(device) => {
+                ret = device == input_device;
+                if matches!((ret, current_op), (true, Or) | (false, And)) {
+                    current_index = current_end_index;
+                    continue;
+                }
+            }
+            OpCodeType::InputDeviceHeld(device) => {
+                ret = held_input_devices & (1 << device) != 0;
+                if matches!((ret, current_op), (true, Or) | (false, And)) {
+                    current_index = current_end_index;
+                    continue;
+                }
+            }
+            OpCodeType::Flag(flag) => {
+                ret = flags & (1 << flag) != 0;
+                if matches!((ret, current_op), (true, Or) | (false, And)) {
+                    current_index = current_end_index;
+                    continue;
+                }
+            }
             OpCodeType::BooleanOp(operator) => {
                 let res = stack.push_back(OperatorAndEndIndex {
                     op: current_op,
@@ -282,7 +363,10 @@ fn bool_evaluation_test_0() {
         evaluate_boolean(
             opcodes.as_slice(),
             keycodes.iter().copied(),
-            [].iter().copied()
+            [].iter().copied(),
+            0,
+            0,
+            0
         ),
         true
     );
@@ -313,7 +397,10 @@ fn bool_evaluation_test_1() {
         evaluate_boolean(
             opcodes.as_slice(),
             keycodes.iter().copied(),
-            [].iter().copied()
+            [].iter().copied(),
+            0,
+            0,
+            0
         ),
         true
     );
@@ -337,7 +424,10 @@ fn bool_evaluation_test_2() {
         evaluate_boolean(
             opcodes.as_slice(),
             keycodes.iter().copied(),
-            [].iter().copied()
+            [].iter().copied(),
+            0,
+            0,
+            0
         ),
         false
     );
@@ -361,7 +451,10 @@ fn bool_evaluation_test_3() {
         evaluate_boolean(
             opcodes.as_slice(),
             keycodes.iter().copied(),
-            [].iter().copied()
+            [].iter().copied(),
+            0,
+            0,
+            0
         ),
         false
     );
@@ -375,7 +468,10 @@ fn bool_evaluation_test_4() {
         evaluate_boolean(
             opcodes.as_slice(),
             keycodes.iter().copied(),
-            [].iter().copied()
+            [].iter().copied(),
+            0,
+            0,
+            0
         ),
         true
     );
@@ -396,7 +492,10 @@ fn bool_evaluation_test_5() {
         evaluate_boolean(
             opcodes.as_slice(),
             keycodes.iter().copied(),
-            [].iter().copied()
+            [].iter().copied(),
+            0,
+            0,
+            0
         ),
         true
     );
@@ -417,7 +516,10 @@ fn bool_evaluation_test_6() {
         evaluate_boolean(
             opcodes.as_slice(),
             keycodes.iter().copied(),
-            [].iter().copied()
+            [].iter().copied(),
+            0,
+            0,
+            0
         ),
         true
     );
@@ -431,7 +533,10 @@ fn bool_evaluation_test_7() {
         evaluate_boolean(
             opcodes.as_slice(),
             keycodes.iter().copied(),
-            [].iter().copied()
+            [].iter().copied(),
+            0,
+            0,
+            0
         ),
         false
     );
@@ -450,7 +555,10 @@ fn bool_evaluation_test_9() {
         evaluate_boolean(
             opcodes.as_slice(),
             keycodes.iter().copied(),
-            [].iter().copied()
+            [].iter().copied(),
+            0,
+            0,
+            0
         ),
         true
     );
@@ -469,7 +577,10 @@ fn bool_evaluation_test_10() {
         evaluate_boolean(
             opcodes.as_slice(),
             keycodes.iter().copied(),
-            [].iter().copied()
+            [].iter().copied(),
+            0,
+            0,
+            0
         ),
         false
     );
@@ -487,7 +598,10 @@ fn bool_evaluation_test_11() {
         evaluate_boolean(
             opcodes.as_slice(),
             keycodes.iter().copied(),
-            [].iter().copied()
+            [].iter().copied(),
+            0,
+            0,
+            0
         ),
         false
     );
@@ -507,7 +621,10 @@ fn bool_evaluation_test_12() {
         evaluate_boolean(
             opcodes.as_slice(),
             keycodes.iter().copied(),
-            [].iter().copied()
+            [].iter().copied(),
+            0,
+            0,
+            0
         ),
         true
     );
@@ -530,7 +647,10 @@ fn bool_evaluation_test_max_depth_does_not_panic() {
         evaluate_boolean(
             opcodes.as_slice(),
             keycodes.iter().copied(),
-            [].iter().copied()
+            [].iter().copied(),
+            0,
+            0,
+            0
         ),
         true
     );
@@ -555,7 +675,10 @@ fn bool_evaluation_test_more_than_max_depth_panics() {
         evaluate_boolean(
             opcodes.as_slice(),
             keycodes.iter().copied(),
-            [].iter().copied()
+            [].iter().copied(),
+            0,
+            0,
+            0
         ),
         true
     );
@@ -569,7 +692,7 @@ fn switch_fallthrough() {
             (&[], &Action::<()>::KeyCode(KeyCode::B), Fallthrough),
         ],
     };
-    let mut actions = sw.actions([].iter().copied(), [].iter().copied());
+    let mut actions = sw.actions([].iter().copied(), [].iter().copied(), 0, 0, 0);
     assert_eq!(actions.next(), Some(&Action::<()>::KeyCode(KeyCode::A)));
     assert_eq!(actions.next(), Some(&Action::<()>::KeyCode(KeyCode::B)));
     assert_eq!(actions.next(), None);
@@ -583,7 +706,7 @@ fn switch_break() {
             (&[], &Action::<()>::KeyCode(KeyCode::B), Break),
         ],
     };
-    let mut actions = sw.actions([].iter().copied(), [].iter().copied());
+    let mut actions = sw.actions([].iter().copied(), [].iter().copied(), 0, 0, 0);
     assert_eq!(actions.next(), Some(&Action::<()>::KeyCode(KeyCode::A)));
     assert_eq!(actions.next(), None);
 }
@@ -604,7 +727,7 @@ fn switch_no_actions() {
             ),
         ],
     };
-    let mut actions = sw.actions([].iter().copied(), [].iter().copied());
+    let mut actions = sw.actions([].iter().copied(), [].iter().copied(), 0, 0, 0);
     assert_eq!(actions.next(), None);
 }
 
@@ -637,6 +760,9 @@ fn switch_historical_1() {
             opcode_true.as_slice(),
             [].iter().copied(),
             hist_keycodes.iter().copied(),
+            0,
+            0,
+            0
         ),
         true
     );
@@ -645,6 +771,9 @@ fn switch_historical_1() {
             opcode_true2.as_slice(),
             [].iter().copied(),
             hist_keycodes.iter().copied(),
+            0,
+            0,
+            0
         ),
         true
     );
@@ -653,6 +782,9 @@ fn switch_historical_1() {
             opcode_false.as_slice(),
             [].iter().copied(),
             hist_keycodes.iter().copied(),
+            0,
+            0,
+            0
         ),
         false
     );
@@ -661,6 +793,9 @@ fn switch_historical_1() {
             opcode_false2.as_slice(),
             [].iter().copied(),
             hist_keycodes.iter().copied(),
+            0,
+            0,
+            0
         ),
         false
     );
@@ -716,7 +851,14 @@ fn switch_historical_bools() {
 
     let test = |opcodes: &[OpCode], expectation: bool| {
         assert_eq!(
-            evaluate_boolean(opcodes, [].iter().copied(), hist_keycodes.iter().copied(),),
+            evaluate_boolean(
+                opcodes,
+                [].iter().copied(),
+                hist_keycodes.iter().copied(),
+                0,
+                0,
+                0
+            ),
             expectation
         );
     };
@@ -728,3 +870,97 @@ fn switch_historical_bools() {
     test(&opcodes_false_and2, false);
     test(&opcodes_false_or, false);
 }
+
+#[test]
+fn switch_input_device() {
+    let opcodes = [
+        OpCode::new_bool(And, 3),
+        OpCode::new_input_device(2),
+        OpCode::new_key(KeyCode::A),
+    ];
+    assert_eq!(
+        OpCode::new_input_device(2).opcode_type(),
+        OpCodeType::InputDevice(2)
+    );
+    let keycodes = [KeyCode::A];
+    assert!(evaluate_boolean(
+        opcodes.as_slice(),
+        keycodes.iter().copied(),
+        [].iter().copied(),
+        2,
+        0,
+        0
+    ));
+    assert!(!evaluate_boolean(
+        opcodes.as_slice(),
+        keycodes.iter().copied(),
+        [].iter().copied(),
+        1,
+        0,
+        0
+    ));
+    assert!(!evaluate_boolean(
+        opcodes.as_slice(),
+        [].iter().copied(),
+        [].iter().copied(),
+        2,
+        0,
+        0
+    ));
+}
+
+#[test]
+fn switch_flag() {
+    let opcodes = [
+        OpCode::new_bool(Or, 3),
+        OpCode::new_flag(5),
+        OpCode::new_flag(63),
+    ];
+    assert_eq!(OpCode::new_flag(5).opcode_type(), OpCodeType::Flag(5));
+    for (flags, expectation) in [(0, false), (1 << 4, false), (1 << 5, true), (1 << 63, true)] {
+        assert_eq!(
+            evaluate_boolean(
+                opcodes.as_slice(),
+                [].iter().copied(),
+                [].iter().copied(),
+                0,
+                0,
+                flags
+            ),
+            expectation
+        );
+    }
+}
+
+#[test]
+fn switch_input_device_held() {
+    // A pedal on device 3 held together with A on the keyboard.
+    let opcodes = [
+        OpCode::new_bool(And, 3),
+        OpCode::new_input_device_held(3),
+        OpCode::new_key(KeyCode::A),
+    ];
+    assert_eq!(
+        OpCode::new_input_device_held(3).opcode_type(),
+        OpCodeType::InputDeviceHeld(3)
+    );
+    let keycodes = [KeyCode::A, KeyCode::F13];
+    for (input_device, held_input_devices, expectation) in [
+        (1, 1 << 1 | 1 << 3, true),
+        (3, 1 << 3, true),
+        (1, 1 << 1, false),
+        (1, 1 << 2, false),
+    ] {
+        assert_eq!(
+            evaluate_boolean(
+                opcodes.as_slice(),
+                keycodes.iter().copied(),
+                [].iter().copied(),
+                input_device,
+                held_input_devices,
+                0
+            ),
+            expectation
+        );
+    }
+}
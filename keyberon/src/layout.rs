@@ -42,6 +42,80 @@ pub type KCoord = (u8, u16);
 pub type Layers<'a, const C: usize, const R: usize, const L: usize, T = core::convert::Infallible> =
     [[[Action<'a, T>; C]; R]; L];
 
+/// The layers of a [Layout], which refer to their rows instead of containing them.
+///
+/// A row that is the same in several layers, e.g. a row of only [`Action::Trans`], can be
+/// stored once and shared by all of them, so that each layer does not need its own full table.
+/// Use [layer_rows] to create it from [Layers].
+pub type LayerRows<
+    'a,
+    const C: usize,
+    const R: usize,
+    const L: usize,
+    T = core::convert::Infallible,
+> = [[LayerRow<'a, C, T>; R]; L];
+
+/// A row of a layer.
+#[derive(Debug, PartialEq)]
+pub enum LayerRow<'a, const C: usize, T = core::convert::Infallible>
+where
+    T: 'a,
+{
+    /// The action of every column.
+    Dense(&'a [Action<'a, T>; C]),
+    /// The actions of the columns that differ from `fallback`, sorted by column. Rows that
+    /// differ from another row, e.g. one of only [`Action::Trans`], in only a few columns take
+    /// much less memory this way.
+    Sparse {
+        actions: &'a [(u16, Action<'a, T>)],
+        fallback: &'a [Action<'a, T>; C],
+    },
+}
+
+// Not derived, since the row only refers to its actions and is `Copy` whatever `T` is.
+impl<const C: usize, T> Clone for LayerRow<'_, C, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<const C: usize, T> Copy for LayerRow<'_, C, T> {}
+
+impl<'a, const C: usize, T> LayerRow<'a, C, T> {
+    /// Returns the action of the column, or `None` if the row has no such column.
+    pub fn get(&self, col: usize) -> Option<&'a Action<'a, T>> {
+        match *self {
+            LayerRow::Dense(actions) => actions.get(col),
+            LayerRow::Sparse { actions, fallback } => {
+                match actions.binary_search_by_key(&col, |(c, _)| usize::from(*c)) {
+                    Ok(i) => Some(&actions[i].1),
+                    Err(_) => fallback.get(col),
+                }
+            }
+        }
+    }
+
+    /// Returns the action of every column in order.
+    pub fn iter(self) -> impl Iterator<Item = &'a Action<'a, T>> {
+        (0..C).filter_map(move |col| self.get(col))
+    }
+}
+
+impl<'a, const C: usize, T> core::ops::Index<usize> for LayerRow<'a, C, T> {
+    type Output = Action<'a, T>;
+
+    fn index(&self, col: usize) -> &Self::Output {
+        self.get(col).expect("column out of range")
+    }
+}
+
+/// Returns the rows of every layer of `layers`.
+pub fn layer_rows<'a, const C: usize, const R: usize, const L: usize, T>(
+    layers: &'a Layers<'a, C, R, L, T>,
+) -> LayerRows<'a, C, R, L, T> {
+    core::array::from_fn(|layer| core::array::from_fn(|row| LayerRow::Dense(&layers[layer][row])))
+}
+
 const QUEUE_SIZE: usize = 32;
 
 /// The current event queue.
@@ -71,7 +145,7 @@ pub struct Layout<'a, const C: usize, const R: usize, const L: usize, T = core::
 where
     T: 'a + std::fmt::Debug,
 {
-    pub layers: &'a [[[Action<'a, T>; C]; R]; L],
+    pub layers: &'a LayerRows<'a, C, R, L, T>,
     pub default_layer: usize,
     /// Key states.
     pub states: Vec<State<'a, T>, 64>,
@@ -84,6 +158,22 @@ where
     pub action_queue: ActionQueue<'a, T>,
     pub rpt_action: Option<&'a Action<'a, T>>,
     pub historical_keys: ArrayDeque<[KeyCode; 8], arraydeque::behavior::Wrapping>,
+    /// Index of the input device that produced the most recent key press, used by switch.
+    pub input_device: u16,
+    /// Bitmask of the input devices that have at least one key held, used by switch.
+    pub held_input_devices: u64,
+    /// Bitmask of the flags that are set, used by switch.
+    pub flags: u64,
+    /// Simultaneity thresholds of pairs of keys, used by chords and HoldTap.
+    pub pair_thresholds: PairThresholds<'a>,
+    /// Percentage by which the delays of sequences are scaled. 100 keeps the delays as they are.
+    pub sequence_delay_percent: u16,
+    /// Minimum number of ticks to wait after each press and release of a sequence. Unlike
+    /// explicit delays, it is not scaled by `sequence_delay_percent`.
+    pub sequence_type_delay: u16,
+    /// While true, the running sequences do not advance, e.g. while a custom action of a sequence
+    /// completes in the background.
+    pub sequences_paused: bool,
 }
 
 /// An event on the key matrix.
@@ -329,6 +419,41 @@ pub struct WaitingState<'a, T: 'a + std::fmt::Debug> {
     config: WaitingConfig<'a, T>,
 }
 
+/// Simultaneity thresholds of pairs of keys, in ticks. Pairs are unordered.
+///
+/// For chords, a key only joins the chord if it is pressed within the threshold of the pair after
+/// the first key of the chord. The timeout of the chords group still applies.
+///
+/// For HoldTap, the first key pressed after the HoldTap key is only used to decide between hold
+/// and tap once both keys have been held together for the threshold of the pair.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PairThresholds<'a>(pub &'a [(KCoord, KCoord, u16)]);
+
+impl PairThresholds<'_> {
+    /// Returns the threshold of the pair of keys, if there is one.
+    pub fn get(&self, a: KCoord, b: KCoord) -> Option<u16> {
+        self.0
+            .iter()
+            .find(|&&(x, y, _)| (x, y) == (a, b) || (x, y) == (b, a))
+            .map(|&(_, _, threshold)| threshold)
+    }
+}
+
+/// Returns [WaitingAction::Hold] if a key pressed after the HoldTap key has also been released.
+fn permissive_hold(queued: &Queue) -> Option<WaitingAction> {
+    let mut queued = queued.iter();
+    while let Some(q) = queued.next() {
+        if q.event.is_press() {
+            let (i, j) = q.event.coord();
+            let target = Event::Release(i, j);
+            if queued.clone().any(|q| q.event == target) {
+                return Some(WaitingAction::Hold);
+            }
+        }
+    }
+    None
+}
+
 /// Actions that can be triggered for a key configured for HoldTap.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum WaitingAction {
@@ -347,12 +472,13 @@ impl<'a, T: std::fmt::Debug> WaitingState<'a, T> {
         &mut self,
         queued: &mut Queue,
         action_queue: &mut ActionQueue<'a, T>,
+        thresholds: PairThresholds,
     ) -> Option<(WaitingAction, Option<PressedQueue>)> {
         self.timeout = self.timeout.saturating_sub(1);
         self.ticks = self.ticks.saturating_add(1);
         let mut pq = None;
         let (ret, cfg_change) = match self.config {
-            WaitingConfig::HoldTap(htc) => (self.handle_hold_tap(htc, queued), None),
+            WaitingConfig::HoldTap(htc) => (self.handle_hold_tap(htc, queued, thresholds), None),
             WaitingConfig::TapDance(ref tds) => {
                 let (ret, num_taps) =
                     self.handle_tap_dance(tds.num_taps, tds.actions.len(), queued);
@@ -371,7 +497,9 @@ impl<'a, T: std::fmt::Debug> WaitingState<'a, T> {
                 )
             }
             WaitingConfig::Chord(config) => {
-                if let Some((ret, action, cpq)) = self.handle_chord(config, queued, action_queue) {
+                if let Some((ret, action, cpq)) =
+                    self.handle_chord(config, queued, action_queue, thresholds)
+                {
                     self.tap = action;
                     pq = Some(cpq);
                     (Some(ret), None)
@@ -386,7 +514,17 @@ impl<'a, T: std::fmt::Debug> WaitingState<'a, T> {
         ret.map(|v| (v, pq))
     }
 
-    fn handle_hold_tap(&mut self, cfg: HoldTapConfig, queued: &Queue) -> Option<WaitingAction> {
+    fn handle_hold_tap(
+        &mut self,
+        cfg: HoldTapConfig,
+        queued: &Queue,
+        thresholds: PairThresholds,
+    ) -> Option<WaitingAction> {
+        // Until the other key overlaps for long enough, only the HoldTap key itself decides.
+        let cfg = match self.other_key_overlaps(queued, thresholds) {
+            true => cfg,
+            false => HoldTapConfig::Default,
+        };
         match cfg {
             HoldTapConfig::Default => (),
             HoldTapConfig::HoldOnOtherKeyPress => {
@@ -395,15 +533,8 @@ impl<'a, T: std::fmt::Debug> WaitingState<'a, T> {
                 }
             }
             HoldTapConfig::PermissiveHold => {
-                let mut queued = queued.iter();
-                while let Some(q) = queued.next() {
-                    if q.event.is_press() {
-                        let (i, j) = q.event.coord();
-                        let target = Event::Release(i, j);
-                        if queued.clone().any(|q| q.event == target) {
-                            return Some(WaitingAction::Hold);
-                        }
-                    }
+                if let waiting_action @ Some(_) = permissive_hold(queued) {
+                    return waiting_action;
                 }
             }
             HoldTapConfig::Custom(func) => {
@@ -411,6 +542,17 @@ impl<'a, T: std::fmt::Debug> WaitingState<'a, T> {
                     return waiting_action;
                 }
             }
+            HoldTapConfig::ChordalHold(hand_of) => {
+                if let Some(q) = queued.iter().find(|q| q.event.is_press()) {
+                    let hand = hand_of(self.coord);
+                    if hand.is_some() && hand == hand_of(q.event.coord()) {
+                        return Some(WaitingAction::Tap);
+                    }
+                }
+                if let waiting_action @ Some(_) = permissive_hold(queued) {
+                    return waiting_action;
+                }
+            }
         }
         if let Some(&Queued { since, .. }) = queued
             .iter()
@@ -428,6 +570,25 @@ impl<'a, T: std::fmt::Debug> WaitingState<'a, T> {
         }
     }
 
+    /// Returns whether the first key pressed after the HoldTap key has been held together with it
+    /// for the threshold of the pair. Keys without a threshold are always considered to overlap.
+    fn other_key_overlaps(&self, queued: &Queue, thresholds: PairThresholds) -> bool {
+        let mut queued = queued.iter();
+        let press = match queued.find(|q| q.event.is_press()) {
+            Some(press) => press,
+            None => return true,
+        };
+        let threshold = match thresholds.get(self.coord, press.event.coord()) {
+            Some(threshold) => threshold,
+            None => return true,
+        };
+        let (i, j) = press.event.coord();
+        let overlap_end = queued
+            .find(|q| q.event == Event::Release(i, j) || self.is_corresponding_release(&q.event))
+            .map_or(0, |q| q.since);
+        press.since.saturating_sub(overlap_end) >= threshold
+    }
+
     fn handle_tap_dance(
         &self,
         num_taps: u16,
@@ -481,10 +642,14 @@ impl<'a, T: std::fmt::Debug> WaitingState<'a, T> {
         config: &'a ChordsGroup<'a, T>,
         queued: &mut Queue,
         action_queue: &mut ActionQueue<'a, T>,
+        thresholds: PairThresholds,
     ) -> Option<(WaitingAction, &'a Action<'a, T>, PressedQueue)> {
         // need to keep track of how many Press events we handled so we can filter them out later
         let mut handled_press_events = 0;
         let mut released_coord = None;
+        // time between the first press and the last press that joined the chord
+        let mut spread = 0;
+        let elapsed = self.delay.saturating_add(self.ticks);
 
         // Compute the set of chord keys that are currently pressed
         // `Ok` when chording mode may continue
@@ -496,9 +661,20 @@ impl<'a, T: std::fmt::Debug> WaitingState<'a, T> {
                     Ok(active)
                 } else if let Some(chord_keys) = config.get_keys(s.event.coord()) {
                     match s.event {
-                        Event::Press(_, _) => {
-                            handled_press_events += 1;
-                            Ok(active | chord_keys)
+                        Event::Press(i, j) => {
+                            let offset = self
+                                .delay
+                                .saturating_add(self.ticks)
+                                .saturating_sub(s.since);
+                            match thresholds.get(self.coord, (i, j)) {
+                                // pressed too long after the first key of the chord, abort
+                                Some(threshold) if offset > threshold => Err(active),
+                                _ => {
+                                    handled_press_events += 1;
+                                    spread = spread.max(offset);
+                                    Ok(active | chord_keys)
+                                }
+                            }
                         }
                         Event::Release(i, j) => {
                             // release chord quickly by changing the coordinate to the released
@@ -521,13 +697,15 @@ impl<'a, T: std::fmt::Debug> WaitingState<'a, T> {
                 }
             });
 
+        let mut chord = None;
         let res = match active {
             Ok(active) => {
                 // Chording mode still active, only trigger action if it's unambiguous
-                if let Some(action) = config.get_chord_if_unambiguous(active) {
+                if let Some(action) = config.get_chord_if_unambiguous(active, spread, elapsed) {
                     if let Some(coord) = released_coord {
                         self.coord = coord;
                     }
+                    chord = Some(active);
                     (WaitingAction::Tap, action)
                 } else {
                     return None; // nothing to do yet, we'll check back later
@@ -535,10 +713,11 @@ impl<'a, T: std::fmt::Debug> WaitingState<'a, T> {
             }
             Err(active) => {
                 // Abort chording mode. Trigger a chord action if there is one.
-                if let Some(action) = config.get_chord(active) {
+                if let Some(action) = config.get_chord(active, spread) {
                     if let Some(coord) = released_coord {
                         self.coord = coord;
                     }
+                    chord = Some(active);
                     (WaitingAction::Tap, action)
                 } else {
                     self.decompose_chord_into_action_queue(config, queued, action_queue);
@@ -546,6 +725,8 @@ impl<'a, T: std::fmt::Debug> WaitingState<'a, T> {
                 }
             }
         };
+        // The other keys of a chord that is released with its first key do not hold the action.
+        let hold_with_other_keys = !chord.is_some_and(|keys| config.releases_on_first(keys));
 
         let mut pq = PressedQueue::new();
 
@@ -557,7 +738,9 @@ impl<'a, T: std::fmt::Debug> WaitingState<'a, T> {
                 && handled_press_events > 0
             {
                 handled_press_events -= 1;
-                let _ = pq.push_back(s.event().coord());
+                if hold_with_other_keys {
+                    let _ = pq.push_back(s.event().coord());
+                }
                 false
             } else {
                 true
@@ -574,6 +757,9 @@ impl<'a, T: std::fmt::Debug> WaitingState<'a, T> {
         action_queue: &mut ActionQueue<'a, T>,
     ) {
         let mut chord_key_order = [0u32; ChordKeys::BITS as usize];
+        // time of each press in `chord_key_order` after the first press
+        let mut press_offsets = [0u16; ChordKeys::BITS as usize];
+        let elapsed = self.delay.saturating_add(self.ticks);
 
         // Default to the initial coordinate. But if a key is released early (before the timeout
         // occurs), use that key for action releases. That way the chord is released as early as
@@ -591,6 +777,7 @@ impl<'a, T: std::fmt::Debug> WaitingState<'a, T> {
                     Event::Press(_, _) => {
                         if active | chord_keys != active {
                             chord_key_order[mask_bits_set] = chord_keys;
+                            press_offsets[mask_bits_set] = elapsed.saturating_sub(s.since);
                             mask_bits_set += 1;
                         }
                         Ok(active | chord_keys)
@@ -637,6 +824,8 @@ impl<'a, T: std::fmt::Debug> WaitingState<'a, T> {
         // (h) (j) (k   l)
         // (h) (j) (k) (l)
 
+        let spread =
+            |start: usize, end: usize| press_offsets[end - 1].saturating_sub(press_offsets[start]);
         let mut start = 0;
         let mut end = len;
         while start < len {
@@ -646,7 +835,7 @@ impl<'a, T: std::fmt::Debug> WaitingState<'a, T> {
                 .copied()
                 .reduce(|acc, e| acc | e)
                 .unwrap_or(0);
-            if let Some(action) = config.get_chord(chord_mask) {
+            if let Some(action) = config.get_chord(chord_mask, spread(start, end)) {
                 let _ = action_queue.push_back(Some((action_queue_coord, action)));
             } else {
                 end -= 1;
@@ -659,7 +848,7 @@ impl<'a, T: std::fmt::Debug> WaitingState<'a, T> {
                         .copied()
                         .reduce(|acc, e| acc | e)
                         .unwrap_or(0);
-                    if let Some(action) = config.get_chord(chord_mask) {
+                    if let Some(action) = config.get_chord(chord_mask, spread(start, end)) {
                         let _ = action_queue.push_back(Some((action_queue_coord, action)));
                         break;
                     }
@@ -685,6 +874,7 @@ pub struct SequenceState<'a, T: 'a> {
     cur_event: Option<SequenceEvent<'a, T>>,
     delay: u32,              // Keeps track of SequenceEvent::Delay time remaining
     tapped: Option<KeyCode>, // Keycode of a key that should be released at the next tick
+    type_delay: Option<u16>, // Set by SequenceEvent::TypeDelay
     remaining_events: &'a [SequenceEvent<'a, T>],
 }
 
@@ -836,7 +1026,7 @@ impl<'a, const C: usize, const R: usize, const L: usize, T: 'a + Copy + std::fmt
     Layout<'a, C, R, L, T>
 {
     /// Creates a new `Layout` object.
-    pub fn new(layers: &'a [[[Action<T>; C]; R]; L]) -> Self {
+    pub fn new(layers: &'a LayerRows<'a, C, R, L, T>) -> Self {
         Self {
             layers,
             default_layer: 0,
@@ -857,6 +1047,13 @@ impl<'a, const C: usize, const R: usize, const L: usize, T: 'a + Copy + std::fmt
             action_queue: ArrayDeque::new(),
             rpt_action: None,
             historical_keys: ArrayDeque::new(),
+            input_device: 0,
+            held_input_devices: 0,
+            flags: 0,
+            pair_thresholds: PairThresholds::default(),
+            sequence_delay_percent: 100,
+            sequence_type_delay: 0,
+            sequences_paused: false,
         }
     }
     /// Iterates on the key codes of the current state.
@@ -968,7 +1165,11 @@ impl<'a, const C: usize, const R: usize, const L: usize, T: 'a + Copy + std::fmt
         }
 
         custom.update(match &mut self.waiting {
-            Some(w) => match w.tick(&mut self.queue, &mut self.action_queue) {
+            Some(w) => match w.tick(
+                &mut self.queue,
+                &mut self.action_queue,
+                self.pair_thresholds,
+            ) {
                 Some((WaitingAction::Hold, _)) => self.waiting_into_hold(),
                 Some((WaitingAction::Tap, pq)) => self.waiting_into_tap(pq),
                 Some((WaitingAction::Timeout, _)) => self.waiting_into_timeout(),
@@ -982,9 +1183,17 @@ impl<'a, const C: usize, const R: usize, const L: usize, T: 'a + Copy + std::fmt
         });
         self.process_sequence_custom(custom)
     }
+    /// Returns the remaining delay to set after a press or release of the sequence. The current
+    /// tick counts towards the delay.
+    fn type_delay_ticks(&self, seq: &SequenceState<'a, T>) -> u32 {
+        u32::from(seq.type_delay.unwrap_or(self.sequence_type_delay)).saturating_sub(1)
+    }
     /// Takes care of draining and populating the `active_sequences` ArrayDeque,
     /// giving us sequences (aka macros) of nearly limitless length!
     fn process_sequences(&mut self) {
+        if self.sequences_paused {
+            return;
+        }
         // Iterate over all active sequence events
         for _ in 0..self.active_sequences.len() {
             if let Some(mut seq) = self.active_sequences.pop_front() {
@@ -996,6 +1205,7 @@ impl<'a, const C: usize, const R: usize, const L: usize, T: 'a + Copy + std::fmt
                     // Clear out the Press() matching this Tap()'s keycode
                     self.states.retain(|s| s.seq_release(keycode).is_some());
                     seq.tapped = None;
+                    seq.delay = self.type_delay_ticks(&seq);
                 } else {
                     // Pull the next SequenceEvent
                     match seq.remaining_events {
@@ -1023,6 +1233,7 @@ impl<'a, const C: usize, const R: usize, const L: usize, T: 'a + Copy + std::fmt
                             // valid should be at (0, 0) that this would interfere with.
                             self.oneshot
                                 .handle_press(OneShotHandlePressKey::Other((0, 0)));
+                            seq.delay = self.type_delay_ticks(&seq);
                         }
                         Some(SequenceEvent::Tap(keycode)) => {
                             // Same as Press() except we track it for one tick via seq.tapped:
@@ -1031,13 +1242,20 @@ impl<'a, const C: usize, const R: usize, const L: usize, T: 'a + Copy + std::fmt
                             self.oneshot
                                 .handle_press(OneShotHandlePressKey::Other((0, 0)));
                             seq.tapped = Some(keycode);
+                            seq.delay = self.type_delay_ticks(&seq);
                         }
                         Some(SequenceEvent::Release(keycode)) => {
                             // Nothing valid should be at (0, 0). It's fine to fake this.
                             self.oneshot.handle_release((0, 0));
                             self.states.retain(|s| s.seq_release(keycode).is_some());
+                            seq.delay = self.type_delay_ticks(&seq);
                         }
                         Some(SequenceEvent::Delay { duration }) => {
+                            let duration = (u64::from(duration)
+                                * u64::from(self.sequence_delay_percent)
+                                / 100)
+                                .min(u64::from(u32::MAX))
+                                as u32;
                             // Setup a delay that will be decremented once per tick until 0
                             if duration > 0 {
                                 // -1 to start since this tick counts
@@ -1047,6 +1265,9 @@ impl<'a, const C: usize, const R: usize, const L: usize, T: 'a + Copy + std::fmt
                         Some(SequenceEvent::Custom(custom)) => {
                             let _ = self.states.push(State::SeqCustomPending(custom));
                         }
+                        Some(SequenceEvent::TypeDelay { duration }) => {
+                            seq.type_delay = Some(duration);
+                        }
                         _ => {} // We'll never get here
                     }
                 }
@@ -1068,11 +1289,52 @@ impl<'a, const C: usize, const R: usize, const L: usize, T: 'a + Copy + std::fmt
                     cur_event: None,
                     delay: 0,
                     tapped: None,
+                    type_delay: None,
                     remaining_events: sequence,
                 });
             }
         }
     }
+    /// Stops all running sequences, including the repetition of held repeating sequences, and
+    /// releases the keys that they pressed.
+    pub fn cancel_all_sequences(&mut self) {
+        self.active_sequences.clear();
+        self.states
+            .retain(|s| !matches!(s, FakeKey { .. } | RepeatingSequence { .. }));
+    }
+    /// Stops the running sequences that contain the custom event, e.g. so that a sequence can
+    /// cancel itself, and releases the keys that they would have released later.
+    pub fn cancel_sequences_with_custom(&mut self, custom: &'a T) {
+        let is_custom = |e: &SequenceEvent<'a, T>| match e {
+            SequenceEvent::Custom(c) => core::ptr::eq(*c, custom),
+            _ => false,
+        };
+        for _ in 0..self.active_sequences.len() {
+            let Some(seq) = self.active_sequences.pop_front() else {
+                break;
+            };
+            if !seq
+                .cur_event
+                .iter()
+                .chain(seq.remaining_events)
+                .any(is_custom)
+            {
+                self.active_sequences.push_back(seq);
+                continue;
+            }
+            let pressed = seq
+                .remaining_events
+                .iter()
+                .filter_map(|e| match e {
+                    SequenceEvent::Release(keycode) => Some(*keycode),
+                    _ => None,
+                })
+                .chain(seq.tapped);
+            for keycode in pressed {
+                self.states.retain(|s| s.seq_release(keycode).is_some());
+            }
+        }
+    }
     fn process_sequence_custom(
         &mut self,
         mut current_custom: CustomEvent<'a, T>,
@@ -1159,7 +1421,7 @@ impl<'a, const C: usize, const R: usize, const L: usize, T: 'a + Copy + std::fmt
             .layers
             .get(layer)
             .and_then(|l| l.get(coord.0 as usize))
-            .and_then(|l| l.get(coord.1 as usize));
+            .and_then(|row| row.get(coord.1 as usize));
         match action {
             None => &NoOp,
             Some(Trans) => {
@@ -1381,6 +1643,7 @@ impl<'a, const C: usize, const R: usize, const L: usize, T: 'a + Copy + std::fmt
                     cur_event: None,
                     delay: 0,
                     tapped: None,
+                    type_delay: None,
                     remaining_events: events,
                 });
                 if !is_oneshot {
@@ -1394,6 +1657,7 @@ impl<'a, const C: usize, const R: usize, const L: usize, T: 'a + Copy + std::fmt
                     cur_event: None,
                     delay: 0,
                     tapped: None,
+                    type_delay: None,
                     remaining_events: events,
                 });
                 let _ = self.states.push(RepeatingSequence {
@@ -1476,7 +1740,13 @@ impl<'a, const C: usize, const R: usize, const L: usize, T: 'a + Copy + std::fmt
                 let active_keys = self.states.iter().filter_map(State::keycode);
                 let historical_keys = self.historical_keys.iter().copied();
                 let action_queue = &mut self.action_queue;
-                for ac in sw.actions(active_keys, historical_keys) {
+                for ac in sw.actions(
+                    active_keys,
+                    historical_keys,
+                    self.input_device,
+                    self.held_input_devices,
+                    self.flags,
+                ) {
                     action_queue.push_back(Some((coord, ac)));
                 }
                 // Switch is not properly repeatable. This has to use the action queue for the
@@ -1582,7 +1852,8 @@ mod test {
             ]],
             [[Trans, MultipleKeyCodes(&[LCtrl, Enter].as_slice())]],
         ];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
         assert_eq!(CustomEvent::NoEvent, layout.tick());
         assert_keys(&[], layout.keycodes());
         layout.event(Press(0, 1));
@@ -1634,7 +1905,8 @@ mod test {
             ]],
             [[Trans, MultipleKeyCodes(&[LCtrl, Enter].as_slice())]],
         ];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
         assert_eq!(CustomEvent::NoEvent, layout.tick());
         assert_keys(&[], layout.keycodes());
         layout.event(Press(0, 1));
@@ -1683,7 +1955,8 @@ mod test {
                 tap_hold_interval: 0,
             }),
         ]]];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
         assert_eq!(CustomEvent::NoEvent, layout.tick());
         assert_keys(&[], layout.keycodes());
         layout.event(Press(0, 0));
@@ -1725,7 +1998,8 @@ mod test {
             }),
             k(Enter),
         ]]];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
 
         // Press another key before timeout
         assert_eq!(CustomEvent::NoEvent, layout.tick());
@@ -1770,6 +2044,56 @@ mod test {
         assert_keys(&[], layout.keycodes());
     }
 
+    #[test]
+    fn hold_on_press_pair_threshold() {
+        static LAYERS: Layers<2, 1, 1> = [[[
+            HoldTap(&HoldTapAction {
+                timeout: 200,
+                hold: k(LAlt),
+                timeout_action: k(LAlt),
+                tap: k(Space),
+                config: HoldTapConfig::HoldOnOtherKeyPress,
+                tap_hold_interval: 0,
+            }),
+            k(Enter),
+        ]]];
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
+        layout.pair_thresholds = PairThresholds(&[((0, 1), (0, 0), 30)]);
+
+        // Release the HoldTap key before the keys overlap for the threshold: tap
+        layout.event(Press(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        layout.event(Press(0, 1));
+        for _ in 0..10 {
+            assert_eq!(CustomEvent::NoEvent, layout.tick());
+            assert_keys(&[], layout.keycodes());
+        }
+        layout.event(Release(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[Space], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[Space, Enter], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[Enter], layout.keycodes());
+        layout.event(Release(0, 1));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+
+        // Keys overlap for the threshold: hold
+        layout.event(Press(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        layout.event(Press(0, 1));
+        for _ in 0..29 {
+            assert_eq!(CustomEvent::NoEvent, layout.tick());
+            assert_keys(&[], layout.keycodes());
+        }
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[LAlt], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[LAlt, Enter], layout.keycodes());
+    }
+
     #[test]
     fn permissive_hold() {
         static LAYERS: Layers<2, 1, 1> = [[[
@@ -1783,7 +2107,8 @@ mod test {
             }),
             k(Enter),
         ]]];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
 
         // Press and release another key before timeout
         assert_eq!(CustomEvent::NoEvent, layout.tick());
@@ -1810,13 +2135,68 @@ mod test {
         assert_keys(&[], layout.keycodes());
     }
 
+    #[test]
+    fn chordal_hold() {
+        fn hand_of(coord: KCoord) -> Option<Hand> {
+            match coord.1 {
+                0 | 1 => Some(Hand::Left),
+                2 => Some(Hand::Right),
+                _ => None,
+            }
+        }
+        static LAYERS: Layers<3, 1, 1> = [[[
+            HoldTap(&HoldTapAction {
+                timeout: 200,
+                hold: k(LAlt),
+                timeout_action: k(LAlt),
+                tap: k(Space),
+                config: HoldTapConfig::ChordalHold(&hand_of),
+                tap_hold_interval: 0,
+            }),
+            k(A),
+            k(Enter),
+        ]]];
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
+
+        // Press a key of the same hand: tap right away
+        layout.event(Press(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+        layout.event(Press(0, 1));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[Space], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[Space, A], layout.keycodes());
+        layout.event(Release(0, 0));
+        layout.event(Release(0, 1));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+
+        // Press and release a key of the other hand: hold
+        layout.event(Press(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        layout.event(Press(0, 2));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+        layout.event(Release(0, 2));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[LAlt], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[LAlt, Enter], layout.keycodes());
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[LAlt], layout.keycodes());
+    }
+
     #[test]
     fn multiple_actions() {
         static LAYERS: Layers<2, 1, 2> = [
             [[MultipleActions(&[l(1), k(LShift)].as_slice()), k(F)]],
             [[Trans, k(E)]],
         ];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
         assert_eq!(CustomEvent::NoEvent, layout.tick());
         assert_keys(&[], layout.keycodes());
         layout.event(Press(0, 0));
@@ -1836,7 +2216,8 @@ mod test {
     #[test]
     fn custom() {
         static LAYERS: Layers<1, 1, 1, u8> = [[[Action::Custom(42)]]];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
         assert_eq!(CustomEvent::NoEvent, layout.tick());
         assert_keys(&[], layout.keycodes());
 
@@ -1863,7 +2244,8 @@ mod test {
             [[l(0), k(B)]],
             [[k(C), k(D)]],
         ];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
         assert_eq!(CustomEvent::NoEvent, layout.tick());
         assert_eq!(0, layout.current_layer());
         assert_keys(&[], layout.keycodes());
@@ -1966,7 +2348,8 @@ mod test {
                 tap_hold_interval: 0,
             }),
         ]]];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
         assert_eq!(CustomEvent::NoEvent, layout.tick());
         assert_keys(&[], layout.keycodes());
 
@@ -2040,7 +2423,8 @@ mod test {
             }),
             k(Enter),
         ]]];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
 
         // press and release the HT key, expect tap action
         assert_eq!(CustomEvent::NoEvent, layout.tick());
@@ -2103,7 +2487,8 @@ mod test {
                 tap_hold_interval: 200,
             }),
         ]]];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
 
         // press and release the HT key, expect tap action
         assert_eq!(CustomEvent::NoEvent, layout.tick());
@@ -2215,7 +2600,8 @@ mod test {
             config: HoldTapConfig::Default,
             tap_hold_interval: 200,
         })]]];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
 
         // press and hold the HT key, expect hold action
         assert_eq!(CustomEvent::NoEvent, layout.tick());
@@ -2267,7 +2653,8 @@ mod test {
                 tap_hold_interval: 200,
             }),
         ]]];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
 
         // press HT1, press HT2, release HT1 after hold timeout, release HT2, press HT2
         layout.event(Press(0, 0));
@@ -2308,7 +2695,8 @@ mod test {
             k(A),
             k(B),
         ]]];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
 
         // Test:
         // 1. press one-shot
@@ -2419,7 +2807,8 @@ mod test {
             k(A),
             k(B),
         ]]];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
 
         // Test:
         // 1. press one-shot
@@ -2572,7 +2961,8 @@ mod test {
             k(A),
             k(B),
         ]]];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
 
         // Test:
         // 1. press one-shot
@@ -2729,7 +3119,8 @@ mod test {
             ]],
             [[k(A), k(B), k(C), k(D)]],
         ];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
 
         layout.event(Press(0, 0));
         layout.event(Release(0, 0));
@@ -2784,7 +3175,8 @@ mod test {
             ]],
             [[k(A), k(B), k(C)]],
         ];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
 
         layout.event(Press(0, 0));
         layout.event(Release(0, 0));
@@ -2851,7 +3243,8 @@ mod test {
             ],
             [k(B), k(C)],
         ]];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
 
         // Test: tap-dance first key, timeout
         layout.event(Press(0, 0));
@@ -2978,7 +3371,8 @@ mod test {
             ],
             [k(B), k(C)],
         ]];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
 
         // Test: tap-dance-eager first key
         layout.event(Press(0, 0));
@@ -3067,7 +3461,8 @@ mod test {
             ]],
         ];
 
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
 
         layout.event(Press(0, 1));
         assert_eq!(CustomEvent::NoEvent, layout.tick());
@@ -3112,6 +3507,8 @@ mod test {
                 (11, &KeyCode(Kb6)),
             ],
             timeout: 100,
+            chord_timeouts: &[],
+            release_on_first: &[],
         };
         static LAYERS: Layers<6, 1, 1> = [[[
             NoOp,
@@ -3122,7 +3519,8 @@ mod test {
             Chords(&GROUP),
         ]]];
 
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
         layout.event(Press(0, 2));
         // timeout on non-terminal chord
         for _ in 0..50 {
@@ -3222,6 +3620,106 @@ mod test {
         assert_keys(&[], layout.keycodes());
     }
 
+    #[test]
+    fn test_chord_pair_threshold() {
+        const GROUP: ChordsGroup<core::convert::Infallible> = ChordsGroup {
+            coords: &[((0, 0), 1), ((0, 1), 2)],
+            chords: &[(1, &KeyCode(Kb1)), (2, &KeyCode(Kb2)), (3, &KeyCode(Kb3))],
+            timeout: 100,
+            chord_timeouts: &[],
+            release_on_first: &[],
+        };
+        static LAYERS: Layers<2, 1, 1> = [[[Chords(&GROUP), Chords(&GROUP)]]];
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
+        layout.pair_thresholds = PairThresholds(&[((0, 0), (0, 1), 20)]);
+
+        // pressed within the threshold: chord
+        layout.event(Press(0, 0));
+        for _ in 0..10 {
+            assert_eq!(CustomEvent::NoEvent, layout.tick());
+            assert_keys(&[], layout.keycodes());
+        }
+        layout.event(Press(0, 1));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[Kb3], layout.keycodes());
+        layout.event(Release(0, 0));
+        layout.event(Release(0, 1));
+        for _ in 0..3 {
+            assert_eq!(CustomEvent::NoEvent, layout.tick());
+        }
+        assert_keys(&[], layout.keycodes());
+
+        // pressed after the threshold but within the group timeout: not a chord
+        layout.event(Press(0, 0));
+        for _ in 0..30 {
+            assert_eq!(CustomEvent::NoEvent, layout.tick());
+            assert_keys(&[], layout.keycodes());
+        }
+        layout.event(Press(0, 1));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[Kb1], layout.keycodes());
+    }
+
+    #[test]
+    fn test_chord_timeouts() {
+        const GROUP: ChordsGroup<core::convert::Infallible> = ChordsGroup {
+            coords: &[((0, 0), 1), ((0, 1), 2), ((0, 2), 4)],
+            chords: &[
+                (1, &KeyCode(Kb1)),
+                (2, &KeyCode(Kb2)),
+                (4, &KeyCode(Kb3)),
+                (3, &KeyCode(Escape)),
+                (6, &KeyCode(Tab)),
+            ],
+            timeout: 100,
+            chord_timeouts: &[(3, 30)],
+            release_on_first: &[6],
+        };
+        static LAYERS: Layers<3, 1, 1> = [[[Chords(&GROUP), Chords(&GROUP), Chords(&GROUP)]]];
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
+
+        // within the chord timeout: chord
+        layout.event(Press(0, 0));
+        for _ in 0..20 {
+            assert_eq!(CustomEvent::NoEvent, layout.tick());
+        }
+        layout.event(Press(0, 1));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[Escape], layout.keycodes());
+        layout.event(Release(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[Escape], layout.keycodes());
+        layout.event(Release(0, 1));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+
+        // the single key triggers once the chord can no longer be pressed, long before the group
+        // timeout
+        layout.event(Press(0, 0));
+        for _ in 0..30 {
+            assert_eq!(CustomEvent::NoEvent, layout.tick());
+            assert_keys(&[], layout.keycodes());
+        }
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[Kb1], layout.keycodes());
+        layout.event(Release(0, 0));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+
+        // released with the first key
+        layout.event(Press(0, 1));
+        layout.event(Press(0, 2));
+        for _ in 0..2 {
+            assert_eq!(CustomEvent::NoEvent, layout.tick());
+        }
+        assert_keys(&[Tab], layout.keycodes());
+        layout.event(Release(0, 1));
+        assert_eq!(CustomEvent::NoEvent, layout.tick());
+        assert_keys(&[], layout.keycodes());
+    }
+
     #[test]
     fn test_chord_normalkey_order() {
         const GROUP: ChordsGroup<core::convert::Infallible> = ChordsGroup {
@@ -3235,6 +3733,8 @@ mod test {
                 (11, &KeyCode(Kb6)),
             ],
             timeout: 100,
+            chord_timeouts: &[],
+            release_on_first: &[],
         };
         static LAYERS: Layers<6, 1, 1> = [[[
             NoOp,
@@ -3245,7 +3745,8 @@ mod test {
             Chords(&GROUP),
         ]]];
 
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
         layout.event(Press(0, 2));
         // timeout on non-terminal chord
         for _ in 0..50 {
@@ -3275,7 +3776,8 @@ mod test {
             }),
             k(Space),
         ]]];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
 
         layout.event(Press(0, 0));
         assert_eq!(CustomEvent::NoEvent, layout.tick());
@@ -3316,7 +3818,8 @@ mod test {
                 Layer(1),
             ]],
         ];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
 
         // Press a key
         layout.event(Press(0, 0));
@@ -3406,7 +3909,8 @@ mod test {
     #[test]
     fn test_clear_multiple_keycodes() {
         static LAYERS: Layers<2, 1, 1> = [[[k(A), MultipleKeyCodes(&[LCtrl, Enter].as_slice())]]];
-        let mut layout = Layout::new(&LAYERS);
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
         layout.event(Press(0, 1));
         assert_eq!(CustomEvent::NoEvent, layout.tick());
         assert_keys(&[LCtrl, Enter], layout.keycodes());
@@ -3415,4 +3919,140 @@ mod test {
         assert_eq!(CustomEvent::NoEvent, layout.tick());
         assert_keys(&[A], layout.keycodes());
     }
+
+    #[test]
+    fn sequence_delay_percent() {
+        static EVENTS: &[SequenceEvent<core::convert::Infallible>] = &[
+            SequenceEvent::Press(A),
+            SequenceEvent::Delay { duration: 10 },
+            SequenceEvent::Release(A),
+        ];
+        static LAYERS: Layers<1, 1, 1> = [[[Sequence { events: &EVENTS }]]];
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
+        layout.sequence_delay_percent = 50;
+        layout.event(Press(0, 0));
+        layout.event(Release(0, 0));
+        for _ in 0..3 {
+            assert_eq!(CustomEvent::NoEvent, layout.tick());
+        }
+        assert_keys(&[A], layout.keycodes());
+        for _ in 0..5 {
+            assert_eq!(CustomEvent::NoEvent, layout.tick());
+        }
+        assert_keys(&[], layout.keycodes());
+
+        // Cancelling releases the keys pressed by the sequence.
+        layout.event(Press(0, 0));
+        for _ in 0..3 {
+            assert_eq!(CustomEvent::NoEvent, layout.tick());
+        }
+        assert_keys(&[A], layout.keycodes());
+        layout.cancel_all_sequences();
+        assert_keys(&[], layout.keycodes());
+        assert!(layout.active_sequences.is_empty());
+    }
+
+    #[test]
+    fn cancel_sequences_with_custom() {
+        static GUARD: u8 = 0;
+        static GUARDED: &[SequenceEvent<u8>] = &[
+            SequenceEvent::Press(A),
+            SequenceEvent::Custom(&GUARD),
+            SequenceEvent::Delay { duration: 10 },
+            SequenceEvent::Release(A),
+        ];
+        static OTHER: &[SequenceEvent<u8>] = &[
+            SequenceEvent::Press(B),
+            SequenceEvent::Delay { duration: 10 },
+            SequenceEvent::Release(B),
+        ];
+        static LAYERS: Layers<2, 1, 1, u8> =
+            [[[Sequence { events: &GUARDED }, Sequence { events: &OTHER }]]];
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
+        layout.event(Press(0, 0));
+        layout.event(Press(0, 1));
+        let custom = (0..5)
+            .map(|_| layout.tick())
+            .find(|e| *e != CustomEvent::NoEvent);
+        let Some(CustomEvent::Press(guard)) = custom else {
+            panic!(
+                "expected the custom event of the sequence, got {:?}",
+                custom
+            );
+        };
+        assert_keys(&[A, B], layout.keycodes());
+
+        // Only the sequence with the custom event is cancelled.
+        layout.cancel_sequences_with_custom(guard);
+        assert_keys(&[B], layout.keycodes());
+        assert_eq!(layout.active_sequences.len(), 1);
+        for _ in 0..15 {
+            layout.tick();
+        }
+        assert_keys(&[], layout.keycodes());
+    }
+
+    #[test]
+    fn sequences_paused() {
+        static EVENTS: &[SequenceEvent<core::convert::Infallible>] =
+            &[SequenceEvent::Tap(A), SequenceEvent::Tap(B)];
+        static LAYERS: Layers<1, 1, 1> = [[[Sequence { events: &EVENTS }]]];
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
+        layout.event(Press(0, 0));
+        layout.event(Release(0, 0));
+        layout.tick();
+        layout.tick();
+        assert_keys(&[A], layout.keycodes());
+        layout.sequences_paused = true;
+        for _ in 0..5 {
+            layout.tick();
+            assert_keys(&[A], layout.keycodes());
+        }
+        layout.sequences_paused = false;
+        layout.tick();
+        assert_keys(&[], layout.keycodes());
+        layout.tick();
+        assert_keys(&[B], layout.keycodes());
+    }
+
+    #[test]
+    fn sequence_type_delay() {
+        static EVENTS: &[SequenceEvent<core::convert::Infallible>] = &[
+            SequenceEvent::Press(A),
+            SequenceEvent::Release(A),
+            SequenceEvent::TypeDelay { duration: 1 },
+            SequenceEvent::Press(B),
+            SequenceEvent::Release(B),
+        ];
+        static LAYERS: Layers<1, 1, 1> = [[[Sequence { events: &EVENTS }]]];
+        let rows = layer_rows(&LAYERS);
+        let mut layout = Layout::new(&rows);
+        layout.sequence_type_delay = 3;
+        layout.event(Press(0, 0));
+        layout.event(Release(0, 0));
+        let mut keys = vec![];
+        for _ in 0..12 {
+            layout.tick();
+            keys.push(layout.keycodes().collect::<std::vec::Vec<_>>());
+        }
+        // Without the delays, the release of A would come right after its press.
+        let expected: &[&[KeyCode]] = &[
+            &[],
+            &[A],
+            &[A],
+            &[A],
+            &[],
+            &[],
+            &[],
+            &[],
+            &[B],
+            &[],
+            &[],
+            &[],
+        ];
+        assert_eq!(keys, expected);
+    }
 }
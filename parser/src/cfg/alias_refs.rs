@@ -0,0 +1,152 @@
+//! Finds the aliases that each part of a configuration refers to, so that kanata can report the
+//! aliases that were never used while it ran. An alias is used if a binding, an alias or a chord
+//! that refers to it was used, which includes the aliases nested in other actions, e.g. in
+//! `tap-hold`, `multi` or `switch`.
+
+use super::sexpr::SExpr;
+use super::*;
+
+/// The aliases that the parts of a configuration refer to, by name without `@`.
+#[derive(Debug, Default, Clone)]
+pub struct AliasRefs {
+    /// Every defined alias and the aliases that its action refers to.
+    pub aliases: HashMap<String, Vec<String>>,
+    /// The aliases that the item of each binding refers to, by layer name and `defsrc` key. A
+    /// `chord` item refers to the aliases of every chord of its `defchords` group.
+    pub bindings: HashMap<(String, String), Vec<String>>,
+    /// The keys of each chord of `defchordsv2` and the aliases that its action refers to.
+    pub chords_v2: Vec<(Vec<OsCode>, Vec<String>)>,
+    /// The aliases that are referred to by parts of the configuration whose use is not tracked,
+    /// e.g. `defvirtualkeys`, `defseq` or `on-startup`.
+    pub untracked: HashSet<String>,
+}
+
+/// The forms whose aliases are tracked, or that only refer to aliases through other forms.
+const TRACKED_FORMS: [&str; 6] = [
+    "defalias",
+    "defaliasenvcond",
+    "deflayer",
+    "defchords",
+    "defchordsv2",
+    "defvar",
+];
+
+pub(super) fn find_alias_refs(root_exprs: &[Vec<SExpr>], s: &ParsedState) -> AliasRefs {
+    let mut chord_groups: HashMap<String, Vec<String>> = HashMap::default();
+    for expr in root_exprs.iter().filter(gen_first_atom_filter("defchords")) {
+        if let Some(group) = expr.get(1).and_then(|group| group.atom(s.vars())) {
+            let mut group_refs = vec![];
+            for chord_expr in expr.iter().skip(3) {
+                aliases_in(chord_expr, s, &chord_groups, &mut group_refs);
+            }
+            chord_groups.insert(group.to_owned(), group_refs);
+        }
+    }
+    let refs_of = |expr: &SExpr| {
+        let mut refs = vec![];
+        aliases_in(expr, s, &chord_groups, &mut refs);
+        refs
+    };
+
+    let mut refs = AliasRefs::default();
+    for expr in root_exprs.iter() {
+        let alias_exprs = match expr.first().and_then(|first| first.atom(None)) {
+            Some("defalias") => &expr[1..],
+            Some("defaliasenvcond") => expr.get(2..).unwrap_or_default(),
+            _ => continue,
+        };
+        for pair in alias_exprs.chunks_exact(2) {
+            let Some(name) = pair[0].atom(s.vars()) else {
+                continue;
+            };
+            // The aliases of `defaliasenvcond` are only defined if its condition is met.
+            if s.aliases.contains_key(name) {
+                refs.aliases
+                    .entry(name.to_owned())
+                    .or_default()
+                    .extend(refs_of(&pair[1]));
+            }
+        }
+    }
+
+    if let Some(src_expr) = root_exprs.iter().find(gen_first_atom_filter("defsrc")) {
+        for layer_expr in root_exprs.iter().filter(gen_first_atom_filter("deflayer")) {
+            let Some(layer) = layer_expr.get(1).and_then(|name| name.atom(None)) else {
+                continue;
+            };
+            for (src, item) in src_expr.iter().skip(1).zip(layer_expr.iter().skip(2)) {
+                let (Some(key), item_refs) = (src.atom(None), refs_of(item)) else {
+                    continue;
+                };
+                if !item_refs.is_empty() {
+                    refs.bindings
+                        .insert((layer.to_owned(), key.to_owned()), item_refs);
+                }
+            }
+        }
+    }
+
+    for expr in root_exprs
+        .iter()
+        .filter(gen_first_atom_filter("defchordsv2"))
+    {
+        for chord in expr[1..].chunks_exact(4) {
+            if let Ok(keys) = parse_chord_v2_keys(&chord[0], s) {
+                let keys = keys.into_iter().filter_map(OsCode::from_u16).collect();
+                refs.chords_v2.push((keys, refs_of(&chord[1])));
+            }
+        }
+    }
+
+    for expr in root_exprs.iter().filter(|expr| {
+        !matches!(
+            expr.first().and_then(|first| first.atom(None)),
+            Some(form) if TRACKED_FORMS.contains(&form)
+        )
+    }) {
+        for subexpr in expr.iter() {
+            refs.untracked.extend(refs_of(subexpr));
+        }
+    }
+    refs
+}
+
+/// Returns the aliases that an item of a layer refers to. Items with `chord` actions cannot be
+/// changed while kanata runs, so the aliases of `defchords` groups are left out.
+pub(super) fn item_alias_refs(item: &SExpr, s: &ParsedState) -> Vec<String> {
+    let mut refs = vec![];
+    aliases_in(item, s, &HashMap::default(), &mut refs);
+    refs
+}
+
+/// Appends the aliases that the expression refers to, including through variables and the `chord`
+/// actions of `defchords` groups.
+fn aliases_in(
+    expr: &SExpr,
+    s: &ParsedState,
+    chord_groups: &HashMap<String, Vec<String>>,
+    refs: &mut Vec<String>,
+) {
+    match expr {
+        SExpr::Atom(atom) => {
+            if let Some(alias) = atom.t.strip_prefix('@') {
+                refs.push(alias.to_owned());
+            } else if let Some(var) = atom.t.strip_prefix('$').and_then(|v| s.vars.get(v)) {
+                if !matches!(var, SExpr::Atom(value) if value.t == atom.t) {
+                    aliases_in(var, s, chord_groups, refs);
+                }
+            }
+        }
+        SExpr::List(list) => {
+            if let [first, group, ..] = list.t.as_slice() {
+                if first.atom(None) == Some("chord") {
+                    let group_refs = group.atom(s.vars()).and_then(|g| chord_groups.get(g));
+                    refs.extend(group_refs.into_iter().flatten().cloned());
+                }
+            }
+            for subexpr in list.t.iter() {
+                aliases_in(subexpr, s, chord_groups, refs);
+            }
+        }
+    }
+}
@@ -13,6 +13,9 @@ use std::sync::Arc;
 /// method.
 pub(crate) struct Allocations {
     allocations: Mutex<Vec<usize>>,
+    /// Groups whose allocations are referenced by this group, e.g. the layers that live reload
+    /// reused from the previous configuration. They are freed after this group.
+    kept: Mutex<Vec<Arc<Allocations>>>,
 }
 
 impl std::fmt::Debug for Allocations {
@@ -43,9 +46,31 @@ impl Allocations {
     pub(super) unsafe fn new() -> Arc<Self> {
         Arc::new(Self {
             allocations: Mutex::new(vec![]),
+            kept: Mutex::new(vec![]),
         })
     }
 
+    /// Keeps the allocations of `other` alive for as long as this group, so that this group can
+    /// reference them.
+    pub(super) fn keep(&self, other: Arc<Allocations>) {
+        self.kept.lock().push(other);
+    }
+
+    pub(super) fn kept_includes(&self, other: &Arc<Allocations>) -> bool {
+        self.kept.lock().iter().any(|a| Arc::ptr_eq(a, other))
+    }
+
+    /// Returns the length of the longest chain of groups kept by `keep`, including this group.
+    pub(super) fn depth(&self) -> usize {
+        1 + self
+            .kept
+            .lock()
+            .iter()
+            .map(|a| a.depth())
+            .max()
+            .unwrap_or(0)
+    }
+
     /// Returns a `&'static T` by leaking the existing box.
     pub(super) fn bref<T>(&self, v: Box<T>) -> &'static T {
         let p = Box::into_raw(v);
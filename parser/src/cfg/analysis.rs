@@ -0,0 +1,173 @@
+//! Finds parts of a configuration that are valid but probably not what the user intended, e.g. a
+//! layer that no action switches to. The findings are warnings for `--check` and live reload
+//! rather than errors, since layers can also be changed from outside of the configuration, e.g.
+//! by a TCP client.
+
+use super::*;
+use crate::trie::TrieVal;
+
+/// A layer that an action changes to, by index of the keyberon layout.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum LayerTarget {
+    /// The layer of `layer-while-held`.
+    Held(usize),
+    /// The base layer of `layer-switch` or `layer-cycle`.
+    Base(usize),
+}
+
+/// Returns a warning for each suspicious part of the configuration.
+pub fn analyze_cfg(cfg: &Cfg) -> Vec<String> {
+    let layers = cfg.layout.b().layers;
+    // The keyberon layout has two versions of each layer.
+    let num_layers = cfg.layer_info.len() / 2;
+    let layer_name = |layout_idx: usize| &cfg.layer_info[layout_idx].name;
+    let targets = (0..num_layers)
+        .map(|layer| {
+            let mut targets = vec![];
+            for version in [layer * 2, layer * 2 + 1] {
+                for action in layers[version].iter().flat_map(|row| row.iter()) {
+                    layer_targets(action, &mut targets);
+                }
+            }
+            targets
+        })
+        .collect::<Vec<_>>();
+    let mut warnings = vec![];
+
+    let mut is_reachable = vec![false; num_layers];
+    let mut to_visit = vec![0];
+    while let Some(layer) = to_visit.pop() {
+        if layer >= num_layers || is_reachable[layer] {
+            continue;
+        }
+        is_reachable[layer] = true;
+        to_visit.extend(targets[layer].iter().map(|target| match target {
+            LayerTarget::Held(idx) | LayerTarget::Base(idx) => idx / 2,
+        }));
+    }
+    for layer in (0..num_layers).filter(|layer| !is_reachable[*layer]) {
+        warnings.push(format!(
+            "layer {} is unreachable: no action of the first layer, or of a layer that it \
+            changes to, changes to it",
+            layer_name(layer * 2)
+        ));
+    }
+
+    // Layers that a tap-hold key switches the base layer to while held.
+    let mut hold_targets = vec![];
+    for action in layers[..num_layers * 2]
+        .iter()
+        .flat_map(|layer| layer.iter().flat_map(|row| row.iter()))
+    {
+        if let Action::HoldTap(HoldTapAction { hold, .. }) = action {
+            layer_targets(hold, &mut hold_targets);
+        }
+    }
+    for layer in (1..num_layers).filter(|layer| is_reachable[*layer]) {
+        let base = LayerTarget::Base(layer * 2);
+        let is_base_target = targets.iter().flatten().any(|target| *target == base);
+        // The exit can be in a layer that is held while the layer is the base layer.
+        let mut is_held = vec![false; num_layers];
+        let mut to_visit = vec![layer];
+        let mut has_exit = false;
+        while let Some(held) = to_visit.pop() {
+            if held >= num_layers || is_held[held] {
+                continue;
+            }
+            is_held[held] = true;
+            for target in targets[held].iter() {
+                match target {
+                    LayerTarget::Held(idx) => to_visit.push(idx / 2),
+                    LayerTarget::Base(idx) => has_exit |= *idx != layer * 2,
+                }
+            }
+        }
+        if !is_base_target || has_exit {
+            continue;
+        }
+        let switched_by = match hold_targets.contains(&base) {
+            true => "the hold action of a tap-hold key",
+            false => "layer-switch",
+        };
+        warnings.push(format!(
+            "layer {} has no exit: {switched_by} switches the base layer to it, but neither it \
+            nor the layers held from it have an action that switches to another base layer",
+            layer_name(layer * 2)
+        ));
+    }
+
+    let fake_key_name = |(_, idx): TrieVal| {
+        cfg.fake_keys
+            .iter()
+            .find(|(_, fake_key_idx)| **fake_key_idx == usize::from(idx))
+            .map(|(name, _)| name.as_str())
+            .unwrap_or_default()
+    };
+    for (shadowed, shadowing) in cfg.sequences.shadowed() {
+        warnings.push(format!(
+            "sequence for {} can never complete: the sequence for {} completes first",
+            fake_key_name(shadowed),
+            fake_key_name(shadowing)
+        ));
+    }
+    warnings
+}
+
+/// Pushes the layers that the action changes to, including those of nested actions.
+fn layer_targets(action: &Action<&&[&CustomAction]>, targets: &mut Vec<LayerTarget>) {
+    match action {
+        Action::Layer(idx) => targets.push(LayerTarget::Held(*idx)),
+        Action::DefaultLayer(idx) => targets.push(LayerTarget::Base(*idx)),
+        Action::Custom(customs) => {
+            for custom in customs.iter() {
+                if let CustomAction::LayerCycle(idxs) = custom {
+                    targets.extend(idxs.iter().copied().map(LayerTarget::Base));
+                }
+            }
+        }
+        Action::NoOp
+        | Action::Trans
+        | Action::Repeat
+        | Action::KeyCode(_)
+        | Action::MultipleKeyCodes(_)
+        | Action::Sequence { .. }
+        | Action::RepeatableSequence { .. }
+        | Action::CancelSequences
+        | Action::ReleaseState(_) => {}
+        Action::HoldTap(HoldTapAction {
+            tap,
+            hold,
+            timeout_action,
+            ..
+        }) => {
+            layer_targets(tap, targets);
+            layer_targets(hold, targets);
+            layer_targets(timeout_action, targets);
+        }
+        Action::OneShot(OneShot { action: ac, .. }) => layer_targets(ac, targets),
+        Action::MultipleActions(actions) => {
+            for ac in actions.iter() {
+                layer_targets(ac, targets);
+            }
+        }
+        Action::TapDance(TapDance { actions, .. }) => {
+            for ac in actions.iter() {
+                layer_targets(ac, targets);
+            }
+        }
+        Action::Chords(ChordsGroup { chords, .. }) => {
+            for (_, ac) in chords.iter() {
+                layer_targets(ac, targets);
+            }
+        }
+        Action::Fork(ForkConfig { left, right, .. }) => {
+            layer_targets(left, targets);
+            layer_targets(right, targets);
+        }
+        Action::Switch(Switch { cases }) => {
+            for case in cases.iter() {
+                layer_targets(case.1, targets);
+            }
+        }
+    }
+}
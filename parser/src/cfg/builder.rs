@@ -0,0 +1,206 @@
+//! Builds configurations in code instead of writing their text, e.g. for programs that edit a
+//! configuration with a GUI. The builder writes the text of the configuration and parses it like a
+//! file, so a built configuration behaves exactly like the same configuration written by hand.
+//!
+//! Actions are built from [`BuilderAction`]. Actions that it has no variant for, e.g.
+//! `(one-shot 500 lsft)`, and forms that the builder has no method for can be added as text.
+//!
+//! The built [`Cfg`] is the configuration that the kanata binary runs, but the binary is not a
+//! library, so a program that builds a configuration runs it by writing the text of
+//! [`ConfigBuilder::to_text`] to a file and starting kanata with it.
+
+use super::*;
+
+/// An action of a layer or of an alias.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuilderAction {
+    Key(OsCode),
+    /// `_`: the action of the key in the layer below.
+    Trans,
+    /// `XX`: nothing.
+    NoOp,
+    /// `@name`: the action of an alias.
+    Alias(String),
+    LayerSwitch(String),
+    LayerWhileHeld(String),
+    TapHold {
+        tap_timeout: u16,
+        hold_timeout: u16,
+        tap: Box<BuilderAction>,
+        hold: Box<BuilderAction>,
+    },
+    Multi(Vec<BuilderAction>),
+    /// An action written as text, e.g. `(one-shot 500 lsft)`.
+    Text(String),
+}
+
+impl BuilderAction {
+    fn write(&self, text: &mut String) -> Result<()> {
+        match self {
+            Self::Key(osc) => text.push_str(oscode_to_str(*osc).ok_or_else(|| {
+                ParseError::new_without_span(format!(
+                    "{osc:?} has no name, write it as text with BuilderAction::Text"
+                ))
+            })?),
+            Self::Trans => text.push('_'),
+            Self::NoOp => text.push_str("XX"),
+            Self::Alias(name) => {
+                text.push('@');
+                text.push_str(name);
+            }
+            Self::LayerSwitch(layer) => text.push_str(&format!("(layer-switch {layer})")),
+            Self::LayerWhileHeld(layer) => text.push_str(&format!("(layer-while-held {layer})")),
+            Self::TapHold {
+                tap_timeout,
+                hold_timeout,
+                tap,
+                hold,
+            } => {
+                text.push_str(&format!("(tap-hold {tap_timeout} {hold_timeout} "));
+                tap.write(text)?;
+                text.push(' ');
+                hold.write(text)?;
+                text.push(')');
+            }
+            Self::Multi(actions) => {
+                text.push_str("(multi");
+                for action in actions.iter() {
+                    text.push(' ');
+                    action.write(text)?;
+                }
+                text.push(')');
+            }
+            Self::Text(action) => text.push_str(action),
+        }
+        Ok(())
+    }
+}
+
+impl From<OsCode> for BuilderAction {
+    fn from(osc: OsCode) -> Self {
+        Self::Key(osc)
+    }
+}
+
+impl From<&str> for BuilderAction {
+    fn from(action: &str) -> Self {
+        Self::Text(action.to_owned())
+    }
+}
+
+impl From<String> for BuilderAction {
+    fn from(action: String) -> Self {
+        Self::Text(action)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ConfigBuilder {
+    cfg_items: Vec<(String, String)>,
+    src: Vec<String>,
+    aliases: Vec<(String, BuilderAction)>,
+    layers: Vec<(String, Vec<BuilderAction>)>,
+    forms: Vec<String>,
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a `defcfg` option. The value is quoted if needed. Values cannot contain `"` or line
+    /// breaks since a quoted value cannot escape them.
+    pub fn cfg(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.cfg_items.push((key.into(), value.into()));
+        self
+    }
+
+    /// Sets the keys of `defsrc`.
+    pub fn src<S: Into<String>>(mut self, keys: impl IntoIterator<Item = S>) -> Self {
+        self.src = keys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Adds an alias that actions can refer to as `@name`.
+    pub fn alias(mut self, name: impl Into<String>, action: impl Into<BuilderAction>) -> Self {
+        self.aliases.push((name.into(), action.into()));
+        self
+    }
+
+    /// Adds a layer with one action for each key of `defsrc`. The first layer is the one that is
+    /// active at startup.
+    pub fn layer<A: Into<BuilderAction>>(
+        mut self,
+        name: impl Into<String>,
+        actions: impl IntoIterator<Item = A>,
+    ) -> Self {
+        self.layers
+            .push((name.into(), actions.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    /// Adds a top-level form, e.g. `(defchords ...)`, written as text.
+    pub fn form(mut self, text: impl Into<String>) -> Self {
+        self.forms.push(text.into());
+        self
+    }
+
+    /// Returns the text of the configuration.
+    pub fn to_text(&self) -> MResult<String> {
+        let mut text = String::new();
+        if !self.cfg_items.is_empty() {
+            text.push_str("(defcfg\n");
+            for (key, value) in self.cfg_items.iter() {
+                text.push_str(&format!("  {key} {}\n", quote_if_needed(key, value)?));
+            }
+            text.push_str(")\n\n");
+        }
+        text.push_str(&format!("(defsrc {})\n\n", self.src.join(" ")));
+        if !self.aliases.is_empty() {
+            text.push_str("(defalias\n");
+            for (name, action) in self.aliases.iter() {
+                text.push_str(&format!("  {name} "));
+                action.write(&mut text)?;
+                text.push('\n');
+            }
+            text.push_str(")\n\n");
+        }
+        for (name, actions) in self.layers.iter() {
+            text.push_str(&format!("(deflayer {name}"));
+            for action in actions.iter() {
+                text.push(' ');
+                action.write(&mut text)?;
+            }
+            text.push_str(")\n\n");
+        }
+        for form in self.forms.iter() {
+            text.push_str(form);
+            text.push_str("\n\n");
+        }
+        Ok(text)
+    }
+
+    /// Parses the configuration.
+    pub fn build(&self) -> MResult<Cfg> {
+        new_from_str(&self.to_text()?)
+    }
+}
+
+/// Quotes a value that would otherwise be parsed as more than one atom. Strings have no escapes,
+/// so a value with `"` or a line break cannot be written.
+fn quote_if_needed(key: &str, value: &str) -> Result<String> {
+    if value.contains(['"', '\n', '\r']) {
+        return Err(ParseError::new_without_span(format!(
+            "the value of {key} cannot contain \" or line breaks"
+        )));
+    }
+    if value.is_empty()
+        || value
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '(' | ')' | ';'))
+    {
+        Ok(format!("\"{value}\""))
+    } else {
+        Ok(value.to_owned())
+    }
+}
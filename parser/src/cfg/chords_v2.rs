@@ -0,0 +1,233 @@
+//! Parses the `defchordsv2` configuration item. Unlike `defchords`, the chords are made of `defsrc`
+//! keys and every chord has its own timeout and release behaviour. A key that is not part of a
+//! chord keeps the action of the active layer.
+//!
+//! Example:
+//!
+//! (defchordsv2
+//!   (j k) esc 50 all-released
+//!   (s d) tab 30 first-release
+//! )
+//!
+//! The chords are turned into a `ChordsGroup` for each layer and each set of keys that share
+//! chords. The action of a key in a layer is the chord action of that key by itself.
+
+use super::sexpr::SExpr;
+use super::*;
+
+const DEFCHORDSV2_ERR: &str = "defchordsv2 expects groups of four parameters: \
+    <key list> <action> <timeout> <release behaviour>";
+
+/// How long the action of a chord is held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChordRelease {
+    /// Until all keys of the chord are released.
+    AllReleased,
+    /// Until the key that started the chord is released.
+    FirstRelease,
+}
+
+struct ChordV2 {
+    keys_expr: SExpr,
+    keys: Vec<u16>,
+    action: &'static KanataAction,
+    timeout: u16,
+    release: ChordRelease,
+}
+
+/// Replaces the actions of the keys used in `defchordsv2` in every layer with chord actions.
+pub(super) fn resolve_chords_v2(
+    exprs: &[&Vec<SExpr>],
+    layers: &mut ParsedLayers,
+    s: &ParsedState,
+) -> Result<()> {
+    let chords = parse_chords_v2(exprs, s)?;
+    for component in chord_components(&chords) {
+        if component.len() > MAX_CHORD_KEYS {
+            let chord = chords
+                .iter()
+                .find(|chord| component.contains(&chord.keys[0]))
+                .expect("component comes from the chords");
+            bail_expr!(
+                &chord.keys_expr,
+                "Maximum number of keys that share chords in defchordsv2 ({MAX_CHORD_KEYS}) \
+                exceeded - found {}",
+                component.len()
+            );
+        }
+        let mask_of = |keys: &[u16]| -> ChordKeys {
+            keys.iter().fold(0, |mask, key| {
+                let index = component
+                    .iter()
+                    .position(|k| k == key)
+                    .expect("in component");
+                mask | (1 << index)
+            })
+        };
+        let component_chords: Vec<&ChordV2> = chords
+            .iter()
+            .filter(|chord| component.contains(&chord.keys[0]))
+            .collect();
+        let timeout = component_chords
+            .iter()
+            .map(|chord| chord.timeout)
+            .max()
+            .expect("a component has chords");
+        let coords = s.a.sref_vec(
+            component
+                .iter()
+                .enumerate()
+                .map(|(i, &osc)| ((NORMAL_KEY_ROW, osc), 1 << i))
+                .collect(),
+        );
+        let chord_timeouts = s.a.sref_vec(
+            component_chords
+                .iter()
+                .map(|chord| (mask_of(&chord.keys), chord.timeout))
+                .collect(),
+        );
+        let release_on_first = s.a.sref_vec(
+            component_chords
+                .iter()
+                .filter(|chord| chord.release == ChordRelease::FirstRelease)
+                .map(|chord| mask_of(&chord.keys))
+                .collect(),
+        );
+        let mut groups = vec![];
+        for layer_idx in 0..layers.len() {
+            let mut group_chords: Vec<(ChordKeys, &'static KanataAction)> = component_chords
+                .iter()
+                .map(|chord| (mask_of(&chord.keys), chord.action))
+                .collect();
+            for (i, &osc) in component.iter().enumerate() {
+                let mut action = layers[layer_idx][0][usize::from(osc)];
+                // A transparent action cannot be used within a chord, so the "layer-while-held"
+                // version uses the resolved action of the "layer-switch" version instead.
+                if action == Action::Trans && layer_idx % 2 == 1 {
+                    action = layers[layer_idx - 1][0][usize::from(osc)];
+                }
+                if matches!(action, Action::Chords(_)) {
+                    let chord = component_chords
+                        .iter()
+                        .find(|chord| chord.keys.contains(&osc))
+                        .expect("key comes from the chords");
+                    bail_expr!(
+                        &chord.keys_expr,
+                        "Keys used in defchordsv2 cannot use chord actions in layers"
+                    );
+                }
+                group_chords.push((1 << i, s.a.sref(action)));
+            }
+            groups.push(s.a.sref(ChordsGroup {
+                coords,
+                chords: s.a.sref_vec(group_chords),
+                timeout,
+                chord_timeouts,
+                release_on_first,
+            }));
+        }
+        // The layers are only changed once all groups are made, since the groups of the
+        // "layer-while-held" versions read the "layer-switch" versions.
+        for (layer, group) in layers.iter_mut().zip(groups) {
+            for &osc in component.iter() {
+                layer[0][usize::from(osc)] = Action::Chords(group);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_chords_v2(exprs: &[&Vec<SExpr>], s: &ParsedState) -> Result<Vec<ChordV2>> {
+    let mut chords: Vec<ChordV2> = vec![];
+    for expr in exprs {
+        let mut subexprs = check_first_expr(expr.iter(), "defchordsv2")?;
+        while let Some(keys_expr) = subexprs.next() {
+            let (Some(action_expr), Some(timeout_expr), Some(release_expr)) =
+                (subexprs.next(), subexprs.next(), subexprs.next())
+            else {
+                bail_expr!(keys_expr, "{DEFCHORDSV2_ERR}");
+            };
+            let keys = parse_chord_v2_keys(keys_expr, s)?;
+            if chords.iter().any(|chord| {
+                chord.keys.len() == keys.len() && keys.iter().all(|k| chord.keys.contains(k))
+            }) {
+                bail_expr!(keys_expr, "Duplicate chord in defchordsv2");
+            }
+            let release = match release_expr.atom(s.vars()) {
+                Some("all-released") => ChordRelease::AllReleased,
+                Some("first-release") => ChordRelease::FirstRelease,
+                _ => bail_expr!(
+                    release_expr,
+                    "Release behaviour must be all-released or first-release"
+                ),
+            };
+            let action = parse_action(action_expr, s)?;
+            if matches!(action, Action::Chords(_)) {
+                bail_expr!(action_expr, "Chord actions cannot be used in defchordsv2");
+            }
+            chords.push(ChordV2 {
+                keys_expr: keys_expr.clone(),
+                keys,
+                action,
+                timeout: parse_non_zero_u16(timeout_expr, s, "chord timeout")?,
+                release,
+            });
+        }
+    }
+    Ok(chords)
+}
+
+pub(super) fn parse_chord_v2_keys(keys_expr: &SExpr, s: &ParsedState) -> Result<Vec<u16>> {
+    let names = match s.key_set(keys_expr) {
+        Some(set) => set.to_vec(),
+        None => keys_expr
+            .list(s.vars())
+            .ok_or_else(|| anyhow_expr!(keys_expr, "Chord must be a list/set of keys"))?
+            .iter()
+            .try_fold(vec![], |mut names, key| -> Result<_> {
+                if let Some(set) = s.key_set(key) {
+                    names.extend(set.iter().cloned());
+                    return Ok(names);
+                }
+                names.push(
+                    key.atom(s.vars())
+                        .ok_or_else(|| anyhow_expr!(key, "Chord keys cannot be lists"))?
+                        .to_owned(),
+                );
+                Ok(names)
+            })?,
+    };
+    let mut keys: Vec<u16> = vec![];
+    for name in names {
+        let osc = str_to_oscode(&name)
+            .ok_or_else(|| anyhow_expr!(keys_expr, "Unknown key in chord: {name}"))?;
+        if !s.mapping_order.contains(&usize::from(osc)) {
+            bail_expr!(keys_expr, "Chord key {name} must be in defsrc");
+        }
+        if !keys.contains(&u16::from(osc)) {
+            keys.push(u16::from(osc));
+        }
+    }
+    if keys.len() < 2 {
+        bail_expr!(keys_expr, "A chord must have at least two different keys");
+    }
+    Ok(keys)
+}
+
+/// Returns the sets of keys that share chords, directly or through other keys.
+fn chord_components(chords: &[ChordV2]) -> Vec<Vec<u16>> {
+    let mut components: Vec<Vec<u16>> = vec![];
+    for chord in chords {
+        let mut component = chord.keys.clone();
+        components.retain(|other| {
+            if other.iter().any(|key| chord.keys.contains(key)) {
+                component.extend(other.iter().filter(|key| !chord.keys.contains(key)));
+                false
+            } else {
+                true
+            }
+        });
+        components.push(component);
+    }
+    components
+}
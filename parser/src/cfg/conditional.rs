@@ -0,0 +1,132 @@
+//! Expands the `platform` and `environment` configuration items. They contain configuration items
+//! that are only used on the listed platforms or when an environment variable has a value when
+//! the configuration is parsed, so one configuration file can be shared between platforms and
+//! machines that need different `defcfg` options, aliases or layers.
+//!
+//! Example:
+//!
+//! (platform (linux)
+//!   (defcfg linux-dev /dev/input/by-id/my-keyboard)
+//! )
+//! (platform (win wintercept)
+//!   (defcfg windows-altgr cancel-lctl-press)
+//! )
+//! (environment (HOSTNAME laptop)
+//!   (deflayer base ...)
+//! )
+
+use super::sexpr::{SExpr, TopLevel};
+use super::*;
+
+const PLATFORM_ERR: &str =
+    "platform expects a list of platform names followed by configuration items";
+const ENVIRONMENT_ERR: &str = "environment expects a list with 2 strings: \
+    (<env var name> <env var value>) followed by configuration items";
+
+/// Names of the platforms that `platform` items can list.
+const PLATFORM_NAMES: [&str; 4] = ["linux", "macos", "win", "wintercept"];
+
+/// Names of the platform that kanata is built for. `win` is any Windows build while `wintercept`
+/// is only the one that uses the Interception driver.
+#[cfg(all(not(feature = "interception_driver"), target_os = "windows"))]
+const CURRENT_PLATFORMS: &[&str] = &["win"];
+#[cfg(all(feature = "interception_driver", target_os = "windows"))]
+const CURRENT_PLATFORMS: &[&str] = &["win", "wintercept"];
+#[cfg(any(target_os = "linux", target_os = "unknown"))]
+const CURRENT_PLATFORMS: &[&str] = &["linux"];
+#[cfg(target_os = "macos")]
+const CURRENT_PLATFORMS: &[&str] = &["macos"];
+
+/// Replaces every `platform` and `environment` item with its configuration items if it applies to
+/// the current platform and environment, or removes it otherwise.
+pub(crate) fn expand_conditionals(xs: Vec<TopLevel>) -> Result<Vec<TopLevel>> {
+    expand_conditionals_with(xs, CURRENT_PLATFORMS, &|name| std::env::var(name).ok())
+}
+
+/// Like `expand_conditionals`, with the platforms and the environment variable values given by
+/// the caller.
+pub(crate) fn expand_conditionals_with(
+    xs: Vec<TopLevel>,
+    platforms: &[&str],
+    env_var: &dyn Fn(&str) -> Option<String>,
+) -> Result<Vec<TopLevel>> {
+    let mut expanded = Vec::with_capacity(xs.len());
+    for top_level in xs {
+        let is_applicable = match top_level.t.first().and_then(|first| first.atom(None)) {
+            Some("platform") => is_platform_applicable(&top_level, platforms)?,
+            Some("environment") => is_environment_applicable(&top_level, env_var)?,
+            _ => {
+                expanded.push(top_level);
+                continue;
+            }
+        };
+        let mut items = vec![];
+        for item in top_level.t[2..].iter() {
+            match item {
+                SExpr::List(list) => items.push(list.clone()),
+                SExpr::Atom(_) => bail_expr!(
+                    item,
+                    "{} can only contain configuration items",
+                    top_level.t[0].atom(None).expect("checked above")
+                ),
+            }
+        }
+        // Items that are not used are still checked for nested items with errors.
+        let items = expand_conditionals_with(items, platforms, env_var)?;
+        if is_applicable {
+            expanded.extend(items);
+        }
+    }
+    Ok(expanded)
+}
+
+fn is_platform_applicable(top_level: &TopLevel, platforms: &[&str]) -> Result<bool> {
+    let names_expr = top_level
+        .t
+        .get(1)
+        .ok_or_else(|| anyhow_span!(top_level, "{PLATFORM_ERR}"))?;
+    let names = names_expr
+        .list(None)
+        .ok_or_else(|| anyhow_expr!(names_expr, "{PLATFORM_ERR}"))?;
+    let mut is_applicable = false;
+    for name_expr in names {
+        let name = name_expr
+            .atom(None)
+            .ok_or_else(|| anyhow_expr!(name_expr, "Platform names must be strings"))?;
+        if !PLATFORM_NAMES.contains(&name) {
+            bail_expr!(
+                name_expr,
+                "Unknown platform: {name}. Valid platforms are: {}",
+                PLATFORM_NAMES.join(" ")
+            );
+        }
+        is_applicable |= platforms.contains(&name);
+    }
+    Ok(is_applicable)
+}
+
+fn is_environment_applicable(
+    top_level: &TopLevel,
+    env_var: &dyn Fn(&str) -> Option<String>,
+) -> Result<bool> {
+    let cond_expr = top_level
+        .t
+        .get(1)
+        .ok_or_else(|| anyhow_span!(top_level, "{ENVIRONMENT_ERR}"))?;
+    let (name, value) = match cond_expr.list(None) {
+        Some([name, value]) => match (name.atom(None), value.atom(None)) {
+            (Some(name), Some(value)) => (name, value.trim_matches('"')),
+            _ => bail_expr!(cond_expr, "{ENVIRONMENT_ERR}"),
+        },
+        _ => bail_expr!(cond_expr, "{ENVIRONMENT_ERR}"),
+    };
+    let is_applicable = env_var(name).is_some_and(|v| v == value);
+    if is_applicable {
+        log::info!("Found env var ({name} {value}), using associated configuration items");
+    } else {
+        log::info!(
+            "Did not find env var ({name} {value}), skipping associated configuration items"
+        );
+    }
+    Ok(is_applicable)
+}
@@ -1,4 +1,7 @@
-use kanata_keyberon::layout::{Event, QueuedIter, WaitingAction};
+use kanata_keyberon::action::Hand;
+use kanata_keyberon::layout::{Event, KCoord, QueuedIter, WaitingAction};
+
+use rustc_hash::FxHashMap as HashMap;
 
 use crate::keys::OsCode;
 
@@ -30,3 +33,18 @@ pub(crate) fn custom_tap_hold_release(
         None
     })
 }
+
+/// Returns a closure that can be used in `HoldTapConfig::ChordalHold`, which returns the hand
+/// assigned to a key in `defhands`. Keys that are not in the normal key row, e.g. fake keys and
+/// chords, are not assigned a hand.
+pub(crate) fn chordal_hold_hands(
+    hands: &'static HashMap<u16, Hand>,
+    a: &Allocations,
+) -> &'static (dyn Fn(KCoord) -> Option<Hand> + Send + Sync) {
+    a.sref(move |(row, col): KCoord| -> Option<Hand> {
+        if row != super::NORMAL_KEY_ROW {
+            return None;
+        }
+        hands.get(&col).copied()
+    })
+}
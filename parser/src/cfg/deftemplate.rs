@@ -0,0 +1,163 @@
+//! Expands `deftemplate` configuration items. A template is a named list of expressions with
+//! parameters, and `(t! name args...)` is replaced by the expressions of the template with `$param`
+//! replaced by the arguments. The expansion happens before the rest of the configuration is
+//! parsed, so templates can be used anywhere.
+//!
+//! Example:
+//!
+//! (deftemplate home-row-mod (key mod)
+//!   (tap-hold 200 200 $key $mod)
+//! )
+//! (deflayer base (t! home-row-mod a lmet) (t! home-row-mod s lalt))
+
+use super::sexpr::{SExpr, Spanned, TopLevel};
+use super::*;
+
+const DEFTEMPLATE_ERR: &str =
+    "deftemplate expects a template name, a list of parameter names, and the template content";
+
+struct Template {
+    params: Vec<String>,
+    content: Vec<SExpr>,
+}
+
+/// Replaces every `(t! ...)` with the content of its template and removes the `deftemplate`
+/// items. A template can only be used after it has been defined, which also means that a
+/// template can not use itself.
+pub(crate) fn expand_templates(xs: Vec<TopLevel>) -> Result<Vec<TopLevel>> {
+    let mut templates: HashMap<String, Template> = HashMap::default();
+    let mut expanded = Vec::with_capacity(xs.len());
+    for top_level in xs {
+        match top_level.t.first().and_then(|first| first.atom(None)) {
+            Some("deftemplate") => {
+                let (name, template) = parse_deftemplate(&top_level, &templates)?;
+                if templates.contains_key(&name) {
+                    bail_span!(&top_level, "Duplicate template name: {name}");
+                }
+                templates.insert(name, template);
+            }
+            Some("t!") => {
+                let list = SExpr::List(top_level.clone());
+                for expr in expand_use(&top_level.t, &list, &templates)? {
+                    match expr {
+                        SExpr::List(list) => expanded.push(list),
+                        SExpr::Atom(atom) => bail_span!(
+                            &atom,
+                            "Templates used outside of a configuration item must only \
+                            contain lists"
+                        ),
+                    }
+                }
+            }
+            _ => expanded.push(Spanned::new(
+                expand_exprs(&top_level.t, &templates)?,
+                top_level.span,
+            )),
+        }
+    }
+    Ok(expanded)
+}
+
+fn parse_deftemplate(
+    top_level: &TopLevel,
+    templates: &HashMap<String, Template>,
+) -> Result<(String, Template)> {
+    let mut exprs = top_level.t.iter().skip(1);
+    let name = exprs
+        .next()
+        .and_then(|name| name.atom(None))
+        .ok_or_else(|| anyhow_span!(top_level, "{DEFTEMPLATE_ERR}"))?;
+    let params_expr = exprs
+        .next()
+        .ok_or_else(|| anyhow_span!(top_level, "{DEFTEMPLATE_ERR}"))?;
+    let params = params_expr
+        .list(None)
+        .ok_or_else(|| anyhow_expr!(params_expr, "{DEFTEMPLATE_ERR}"))?
+        .iter()
+        .map(|param| {
+            param
+                .atom(None)
+                .map(|p| p.trim_start_matches('$').to_owned())
+                .ok_or_else(|| anyhow_expr!(param, "Template parameter names must be strings"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let content: Vec<_> = exprs.cloned().collect();
+    if content.is_empty() {
+        bail_span!(top_level, "{DEFTEMPLATE_ERR}");
+    }
+    Ok((
+        name.to_owned(),
+        Template {
+            params,
+            content: expand_exprs(&content, templates)?,
+        },
+    ))
+}
+
+fn expand_exprs(exprs: &[SExpr], templates: &HashMap<String, Template>) -> Result<Vec<SExpr>> {
+    let mut expanded = Vec::with_capacity(exprs.len());
+    for expr in exprs {
+        match expr {
+            SExpr::List(list) if list.t.first().and_then(|e| e.atom(None)) == Some("t!") => {
+                expanded.extend(expand_use(&list.t, expr, templates)?);
+            }
+            SExpr::List(list) => expanded.push(SExpr::List(Spanned::new(
+                expand_exprs(&list.t, templates)?,
+                list.span.clone(),
+            ))),
+            SExpr::Atom(_) => expanded.push(expr.clone()),
+        }
+    }
+    Ok(expanded)
+}
+
+/// Returns the content of the template used by `(t! name args...)`.
+fn expand_use(
+    use_exprs: &[SExpr],
+    use_expr: &SExpr,
+    templates: &HashMap<String, Template>,
+) -> Result<Vec<SExpr>> {
+    let name = use_exprs
+        .get(1)
+        .and_then(|name| name.atom(None))
+        .ok_or_else(|| anyhow_expr!(use_expr, "t! expects a template name and its arguments"))?;
+    let template = templates.get(name).ok_or_else(|| {
+        anyhow_expr!(
+            &use_exprs[1],
+            "Unknown template name: {name}. Templates must be defined before they are used."
+        )
+    })?;
+    let args = expand_exprs(&use_exprs[2..], templates)?;
+    if args.len() != template.params.len() {
+        bail_expr!(
+            use_expr,
+            "Template {name} expects {} arguments, found {}",
+            template.params.len(),
+            args.len()
+        );
+    }
+    let args: HashMap<&str, &SExpr> = template
+        .params
+        .iter()
+        .map(String::as_str)
+        .zip(args.iter())
+        .collect();
+    Ok(template
+        .content
+        .iter()
+        .map(|expr| substitute(expr, &args))
+        .collect())
+}
+
+fn substitute(expr: &SExpr, args: &HashMap<&str, &SExpr>) -> SExpr {
+    match expr {
+        SExpr::Atom(atom) => match atom.t.strip_prefix('$').and_then(|p| args.get(p)) {
+            Some(&arg) => arg.clone(),
+            None => expr.clone(),
+        },
+        SExpr::List(list) => SExpr::List(Spanned::new(
+            list.t.iter().map(|e| substitute(e, args)).collect(),
+            list.span.clone(),
+        )),
+    }
+}
@@ -0,0 +1,135 @@
+//! Parses `deftest` configuration items. Each item declares a sequence of simulated inputs and the
+//! key outputs that the inputs are expected to produce.
+//!
+//! Example:
+//!
+//! (deftest caps-tap-is-esc
+//!   (input d:caps t:50 u:caps t:50)
+//!   (output d:esc u:esc)
+//! )
+
+use super::sexpr::SExpr;
+use super::*;
+
+const DEFTEST_ERR: &str =
+    "deftest expects a test name followed by an (input ...) list and an (output ...) list";
+const INPUT_ITEM_ERR: &str =
+    "input items must be d:<key> (press), u:<key> (release), or t:<ms> (wait)";
+const OUTPUT_ITEM_ERR: &str = "output items must be d:<key> (press) or u:<key> (release)";
+
+/// A simulated input event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimInput {
+    Press(OsCode),
+    Release(OsCode),
+    /// Wait for the given number of milliseconds.
+    Wait(u16),
+}
+
+/// An output event that a test expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimOutput {
+    Press(OsCode),
+    Release(OsCode),
+}
+
+impl std::fmt::Display for SimOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SimOutput::Press(osc) => write!(f, "d:{osc:?}"),
+            SimOutput::Release(osc) => write!(f, "u:{osc:?}"),
+        }
+    }
+}
+
+/// A test defined with `deftest`.
+#[derive(Debug, Clone)]
+pub struct CfgTest {
+    pub name: String,
+    pub inputs: Vec<SimInput>,
+    pub expected_outputs: Vec<SimOutput>,
+}
+
+pub(super) fn parse_tests(exprs: &[&Spanned<Vec<SExpr>>], s: &ParsedState) -> Result<Vec<CfgTest>> {
+    let mut names = HashSet::default();
+    let mut tests = vec![];
+    for expr in exprs {
+        let mut subexprs = check_first_expr(expr.t.iter(), "deftest")?;
+        let name = subexprs
+            .next()
+            .and_then(|e| e.atom(s.vars()))
+            .ok_or_else(|| anyhow_span!(expr, "{DEFTEST_ERR}"))?
+            .to_owned();
+        if !names.insert(name.clone()) {
+            bail_span!(expr, "Duplicate deftest name: {name}");
+        }
+        let mut inputs = None;
+        let mut expected_outputs = None;
+        for subexpr in subexprs {
+            let list = subexpr
+                .list(s.vars())
+                .ok_or_else(|| anyhow_expr!(subexpr, "{DEFTEST_ERR}"))?;
+            match list.first().and_then(|e| e.atom(s.vars())) {
+                Some("input") if inputs.is_none() => {
+                    inputs = Some(
+                        list[1..]
+                            .iter()
+                            .map(|item| parse_sim_input(item, s))
+                            .collect::<Result<Vec<_>>>()?,
+                    );
+                }
+                Some("output") if expected_outputs.is_none() => {
+                    expected_outputs = Some(
+                        list[1..]
+                            .iter()
+                            .map(|item| parse_sim_output(item, s))
+                            .collect::<Result<Vec<_>>>()?,
+                    );
+                }
+                Some(kind @ ("input" | "output")) => {
+                    bail_expr!(subexpr, "deftest {name} has more than one {kind} list")
+                }
+                _ => bail_expr!(subexpr, "{DEFTEST_ERR}"),
+            }
+        }
+        match (inputs, expected_outputs) {
+            (Some(inputs), Some(expected_outputs)) => tests.push(CfgTest {
+                name,
+                inputs,
+                expected_outputs,
+            }),
+            _ => bail_span!(expr, "{DEFTEST_ERR}"),
+        }
+    }
+    Ok(tests)
+}
+
+fn parse_sim_input(expr: &SExpr, s: &ParsedState) -> Result<SimInput> {
+    let item = expr
+        .atom(s.vars())
+        .ok_or_else(|| anyhow_expr!(expr, "{INPUT_ITEM_ERR}"))?;
+    match item.split_once(':') {
+        Some(("d", key)) => Ok(SimInput::Press(parse_sim_key(expr, key)?)),
+        Some(("u", key)) => Ok(SimInput::Release(parse_sim_key(expr, key)?)),
+        Some(("t", ms)) => ms
+            .parse::<u16>()
+            .map(SimInput::Wait)
+            .map_err(|_| anyhow_expr!(expr, "wait time must be a number 0-65535")),
+        _ => bail_expr!(expr, "{INPUT_ITEM_ERR}"),
+    }
+}
+
+fn parse_sim_output(expr: &SExpr, s: &ParsedState) -> Result<SimOutput> {
+    let item = expr
+        .atom(s.vars())
+        .ok_or_else(|| anyhow_expr!(expr, "{OUTPUT_ITEM_ERR}"))?;
+    match item.split_once(':') {
+        Some(("d", key)) => Ok(SimOutput::Press(parse_sim_key(expr, key)?)),
+        Some(("u", key)) => Ok(SimOutput::Release(parse_sim_key(expr, key)?)),
+        _ => bail_expr!(expr, "{OUTPUT_ITEM_ERR}"),
+    }
+}
+
+fn parse_sim_key(expr: &SExpr, key: &str) -> Result<OsCode> {
+    str_to_oscode(key).ok_or_else(|| anyhow_expr!(expr, "Unknown key name: {key}"))
+}
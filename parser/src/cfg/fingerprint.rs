@@ -0,0 +1,231 @@
+//! Fingerprints of configurations, used by live reload to avoid rebuilding what has not changed.
+//! Parsing every action and building the layers is most of the time spent reloading a large
+//! configuration, while reading the top-level forms for the fingerprint is quick.
+//!
+//! The layout is reused as a whole when only `defcfg` options that it does not depend on have
+//! changed. Otherwise the layers whose `deflayer` is unchanged reuse their compiled actions from
+//! the previous configuration, as long as nothing that the actions of a layer can depend on has
+//! changed either, i.e. the other top-level forms such as aliases, variables and templates, the
+//! layer names and the layout options of `defcfg`.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use super::sexpr::TopLevel;
+use super::*;
+
+/// Reused layers keep the allocations of the configuration that they were compiled with alive.
+/// After this many reloads in a row that reuse layers, all layers are compiled again so that the
+/// configurations before do not pile up.
+const MAX_KEPT_CFGS: usize = 8;
+
+/// The `defcfg` options that are read while parsing the layout. Changing any other option does
+/// not change the layout.
+const LAYOUT_CFG_KEYS: [&str; 7] = [
+    "process-unmapped-keys",
+    "danger-enable-cmd",
+    "delegate-to-first-layer",
+    SEQUENCE_TIMEOUT_CFG_NAME,
+    SEQUENCE_INPUT_MODE_CFG_NAME,
+    ON_STARTUP_CFG_NAME,
+    ON_SHUTDOWN_CFG_NAME,
+];
+
+#[derive(Debug)]
+pub(super) struct CfgFingerprint {
+    /// Configuration items in `defcfg`.
+    pub items: HashMap<String, String>,
+    /// A hash of everything that the layout is built from: the text of every top-level form except
+    /// `defcfg`, in order, and the options of `LAYOUT_CFG_KEYS`. Two configurations with the same
+    /// hash have the same layout.
+    pub layout: u64,
+}
+
+/// A configuration read by live reload.
+pub enum CfgReload {
+    /// The layout has not changed, only the `defcfg` items are parsed.
+    Unchanged(HashMap<String, String>),
+    Changed(Box<Cfg>),
+}
+
+/// Reads the configuration file at `p`, including the files that it includes, and only parses
+/// all of it if its layout fingerprint differs from `layout_fingerprint`. The unchanged layers of
+/// `layer_cache`, which are the layers of the running configuration, are reused.
+pub fn reload_from_file(
+    p: &Path,
+    layout_fingerprint: u64,
+    layer_cache: &LayerCache,
+) -> MResult<CfgReload> {
+    let exprs = read_cfg_exprs(p, &EditedFiles::default())?;
+    let fingerprint = fingerprint(&exprs)?;
+    if fingerprint.layout == layout_fingerprint {
+        return Ok(CfgReload::Unchanged(fingerprint.items));
+    }
+    new_from_exprs(exprs, layer_cache.clone()).map(|cfg| CfgReload::Changed(Box::new(cfg)))
+}
+
+/// The compiled actions of the layers of a configuration, which live reload reuses for the
+/// layers that did not change.
+#[derive(Debug, Clone, Default)]
+pub struct LayerCache {
+    /// The `layer_context` of the configuration.
+    context: u64,
+    /// The actions of each layer that can be reused, in `defsrc` order, by `layer_hash` of its
+    /// `deflayer`. Layers whose actions add to the parsed state, e.g. unnamed fake keys, or that
+    /// use chords, are left out since reusing them would not add those again.
+    pub(super) layers: HashMap<u64, Vec<KanataAction>>,
+    /// The allocations of the actions. None for a configuration that was not parsed.
+    pub(super) a: Option<Arc<Allocations>>,
+}
+
+impl LayerCache {
+    pub(super) fn new(context: u64, a: Arc<Allocations>) -> Self {
+        Self {
+            context,
+            layers: HashMap::default(),
+            a: Some(a),
+        }
+    }
+
+    /// Returns the actions of the layer with `layer_hash` if they can be reused by a configuration
+    /// with `context` that allocates with `a`, which then keeps the allocations of the actions.
+    pub(super) fn reuse(
+        &self,
+        context: u64,
+        layer_hash: u64,
+        a: &Allocations,
+    ) -> Option<&[KanataAction]> {
+        let cache_a = self.a.as_ref()?;
+        if self.context != context || cache_a.depth() >= MAX_KEPT_CFGS {
+            return None;
+        }
+        let actions = self.layers.get(&layer_hash)?;
+        if !a.kept_includes(cache_a) {
+            a.keep(cache_a.clone());
+        }
+        Some(actions)
+    }
+
+    pub(super) fn insert(&mut self, layer_hash: u64, actions: Vec<KanataAction>) {
+        self.layers.insert(layer_hash, actions);
+    }
+}
+
+/// Hashes everything other than the text of the `deflayer`s that parsing the actions of a layer
+/// can depend on: the other top-level forms, the names of the layers in order and the layout
+/// options of `defcfg`. With `delegate-to-first-layer`, the other layers depend on the first
+/// layer, so it is included as well.
+pub(super) fn layer_context(root_exprs: &[Vec<SExpr>], cfg_items: &HashMap<String, String>) -> u64 {
+    let is_deflayer = gen_first_atom_filter("deflayer");
+    let is_defcfg = gen_first_atom_filter("defcfg");
+    let mut hasher = DefaultHasher::new();
+    for expr in root_exprs.iter() {
+        if is_deflayer(&expr) {
+            if let Some(name) = expr.get(1) {
+                hash_sexpr(name, &mut hasher);
+            }
+        } else if !is_defcfg(&expr) {
+            hash_sexprs(expr, &mut hasher);
+        }
+    }
+    for key in LAYOUT_CFG_KEYS {
+        cfg_items.get(key).hash(&mut hasher);
+    }
+    let delegates = cfg_items
+        .get("delegate-to-first-layer")
+        .is_some_and(|v| TRUE_VALUES.contains(&v.to_lowercase().as_str()));
+    if delegates {
+        if let Some(first_layer) = root_exprs.iter().find(&is_deflayer) {
+            hash_sexprs(first_layer, &mut hasher);
+        }
+    }
+    hasher.finish()
+}
+
+/// Hashes the items of a `deflayer`. Comments and whitespace do not change the hash.
+pub(super) fn layer_hash(layer_expr: &[SExpr]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hash_sexprs(layer_expr, &mut hasher);
+    hasher.finish()
+}
+
+fn hash_sexprs(exprs: &[SExpr], hasher: &mut DefaultHasher) {
+    exprs.len().hash(hasher);
+    for expr in exprs {
+        hash_sexpr(expr, hasher);
+    }
+}
+
+/// Hashes the atoms and the structure of the lists of `expr`, but not where they are.
+fn hash_sexpr(expr: &SExpr, hasher: &mut DefaultHasher) {
+    match expr {
+        SExpr::Atom(atom) => {
+            0u8.hash(hasher);
+            atom.t.hash(hasher);
+        }
+        SExpr::List(list) => {
+            1u8.hash(hasher);
+            hash_sexprs(&list.t, hasher);
+        }
+    }
+}
+
+pub(super) fn fingerprint(exprs: &[TopLevel]) -> Result<CfgFingerprint> {
+    let mut defcfgs = exprs.iter().filter(gen_first_atom_filter_spanned("defcfg"));
+    let items = defcfgs
+        .next()
+        .map(|cfg| parse_defcfg(&cfg.t))
+        .transpose()?
+        .unwrap_or_default();
+    if let Some(spanned) = defcfgs.next() {
+        bail_span!(
+            spanned,
+            "Only one defcfg is allowed, found more. Delete the extras."
+        )
+    }
+    let mut hasher = DefaultHasher::new();
+    for expr in exprs
+        .iter()
+        .filter(|expr| !gen_first_atom_filter_spanned("defcfg")(expr))
+    {
+        expr.span.file_content[expr.span.start()..expr.span.end()].hash(&mut hasher);
+    }
+    for key in LAYOUT_CFG_KEYS {
+        items.get(key).hash(&mut hasher);
+    }
+    Ok(CfgFingerprint {
+        items,
+        layout: hasher.finish(),
+    })
+}
+
+#[test]
+fn fingerprint_ignores_runtime_options() {
+    let layout_hash = |text: &str| {
+        fingerprint(&sexpr::parse(text, "test").unwrap())
+            .unwrap()
+            .layout
+    };
+    let base = layout_hash("(defcfg log-layer-changes no) (defsrc a) (deflayer base b)");
+    assert_eq!(
+        base,
+        layout_hash(
+            "(defcfg log-layer-changes yes\n  movemouse-speed-percent 50)\n(defsrc a) (deflayer base b)"
+        )
+    );
+    assert_eq!(base, layout_hash("(defsrc a) (deflayer base b)"));
+    assert_ne!(
+        base,
+        layout_hash("(defcfg process-unmapped-keys yes) (defsrc a) (deflayer base b)")
+    );
+    assert_ne!(base, layout_hash("(defsrc a) (deflayer base c)"));
+    assert_ne!(
+        base,
+        layout_hash("(defcfg on-startup (layer-switch base)) (defsrc a) (deflayer base b)")
+    );
+    assert_ne!(
+        base,
+        layout_hash("(defsrc a) (deflayer base b) ;; comment\n(deflayer other c)")
+    );
+    assert!(fingerprint(&sexpr::parse("(defcfg) (defcfg)", "test").unwrap()).is_err());
+}
@@ -0,0 +1,331 @@
+//! Changes the action of a single key in a layer while kanata is running, used by the
+//! `SetKeyAction` TCP message. Only the new action is parsed, with the aliases, variables and
+//! layers of the parsed configuration, and kanata swaps it into the running layout. The edit is
+//! also made to the text of the file that defines the layer, so that it can be written back to the
+//! file and so that live reload can tell whether the files still match the layout.
+//!
+//! Actions that need more of the layout to be rebuilt than the key itself, e.g. ones that add fake
+//! keys or use chords, are rejected. These have to be changed in the file and live reloaded.
+
+use super::sexpr::SExpr;
+use super::*;
+
+/// Edited text of configuration files, by absolute path. The files in the map are read from their
+/// edited text instead of from the disk.
+pub type EditedFiles = HashMap<PathBuf, String>;
+
+/// Parses the actions of single keys with the state of the parsed configuration.
+pub struct KeyActionParser {
+    s: ParsedState,
+    /// The keys whose actions are part of chords, by index of the "layer-switch" version of the
+    /// layer and key index. Changing these would change the whole chords group.
+    chord_keys: HashSet<(usize, usize)>,
+}
+
+/// The actions of a key in both versions of a layer. They are allocated with the layout of the
+/// configuration, which they keep alive.
+#[derive(Debug, Clone)]
+pub struct KeyActions {
+    /// The action in the "layer-switch" version of the layer, in which transparent actions are
+    /// resolved to the `defsrc` action.
+    pub(super) switch: &'static KanataAction,
+    /// The action in the "layer-while-held" version of the layer.
+    pub(super) held: &'static KanataAction,
+    pub(super) _allocations: Arc<Allocations>,
+}
+
+impl KeyActions {
+    /// Returns the action of the key while its layer is the base layer.
+    pub fn action(&self) -> &KanataAction {
+        self.switch
+    }
+}
+
+/// A change of the actions of a key, to be swapped into the running layout.
+#[derive(Debug, Clone)]
+pub struct KeyActionSwap {
+    /// The index of the "layer-switch" version of the layer in the layout and in `layer_info`.
+    /// The "layer-while-held" version follows it.
+    pub layer: usize,
+    pub osc: OsCode,
+    pub actions: KeyActions,
+    /// The name of the key in `defsrc`.
+    pub key: String,
+    /// The aliases that the item of the key refers to.
+    pub alias_refs: Vec<String>,
+    /// The text of the edited `deflayer`, as in `LayerInfo::cfg_text`.
+    pub layer_text: String,
+    /// The bindings of the edited `deflayer`, as in `LayerInfo::bindings`.
+    pub bindings: Vec<(String, String)>,
+    /// The `layout` fingerprint of the edited configuration.
+    pub layout_fingerprint: u64,
+}
+
+impl KeyActionSwap {
+    /// Changes the text and bindings of both versions of the layer in `layer_info`.
+    pub fn update_layer_info(&self, layer_info: &mut [LayerInfo]) {
+        for info in layer_info[self.layer..=self.layer + 1].iter_mut() {
+            info.cfg_text.clone_from(&self.layer_text);
+            info.bindings.clone_from(&self.bindings);
+        }
+    }
+
+    /// Replaces the potential outputs of the key, e.g. for key repeat, by the ones of the new
+    /// actions.
+    pub fn update_key_outputs(&self, key_outputs: &mut KeyOutputs, overrides: &Overrides) {
+        for (layer, action) in [
+            (self.layer, self.actions.switch),
+            (self.layer + 1, self.actions.held),
+        ] {
+            let outputs = &mut key_outputs[layer];
+            outputs.remove(&self.osc);
+            add_key_output_from_action_to_key_pos(self.osc, action, outputs, overrides);
+        }
+    }
+}
+
+impl KeyActionParser {
+    pub(super) fn new(s: ParsedState, layers: &ParsedLayers) -> Self {
+        let mut chord_keys = HashSet::default();
+        for (layer, versions) in layers.chunks_exact(2).enumerate() {
+            for osc_idx in s.mapping_order.iter().copied() {
+                if versions
+                    .iter()
+                    .any(|version| contains_chords(&version[0][osc_idx]))
+                {
+                    chord_keys.insert((layer * 2, osc_idx));
+                }
+            }
+        }
+        Self { s, chord_keys }
+    }
+
+    /// Parses `action` as the action of `key` in `layer` of the configuration file at `p`, in
+    /// which the edits in `edited` are made first. If the action is valid, the edit is added to
+    /// `edited`.
+    pub fn parse(
+        &self,
+        p: &Path,
+        edited: &mut EditedFiles,
+        layer: &str,
+        key: &str,
+        action: &str,
+    ) -> MResult<KeyActionSwap> {
+        let (path, text) = edit_key_action(p, edited, layer, key, action)?;
+        let mut new_edited = edited.clone();
+        new_edited.insert(path, text);
+        let swap = self.swap(p, &new_edited, layer, key, |item, level, osc_idx| {
+            self.parse_actions(item, level, osc_idx)
+        })?;
+        *edited = new_edited;
+        Ok(swap)
+    }
+
+    /// Returns the change of `key` in `layer` back to `actions`, e.g. to undo a change, where
+    /// `edited` are the edits that have the item of `actions`.
+    pub fn restore(
+        &self,
+        p: &Path,
+        edited: &EditedFiles,
+        layer: &str,
+        key: &str,
+        actions: KeyActions,
+    ) -> MResult<KeyActionSwap> {
+        self.swap(p, edited, layer, key, |_, _, _| Ok(actions))
+    }
+
+    /// Returns the change of the actions of `key` in `layer` to the ones of `actions`, with the
+    /// rest of the change read from the files in which the edits in `edited` are made.
+    fn swap(
+        &self,
+        p: &Path,
+        edited: &EditedFiles,
+        layer: &str,
+        key: &str,
+        actions: impl FnOnce(&SExpr, usize, usize) -> Result<KeyActions>,
+    ) -> MResult<KeyActionSwap> {
+        let s = &self.s;
+        read_edited_cfg_file(p, edited, |text, file_content_provider| {
+            let exprs = sexpr::parse(text, &p.to_string_lossy())
+                .and_then(|xs| expand_includes(xs, file_content_provider))?;
+            let layout_fingerprint = fingerprint(&exprs)?.layout;
+            let located = locate_item(&exprs, layer, key)?;
+            let level = *s
+                .layer_idxs
+                .get(layer)
+                .ok_or_else(|| anyhow!("Layer {layer} was added since kanata last loaded it"))?;
+            let osc_idx = *s
+                .mapping_order
+                .get(located.index)
+                .ok_or_else(|| anyhow!("Key {key} was added since kanata last loaded it"))?;
+            Ok(KeyActionSwap {
+                layer: level * 2,
+                osc: OsCode::from_u16(osc_idx as u16).expect("defsrc keys are valid"),
+                actions: actions(located.item, level, osc_idx)?,
+                key: located.src.atom(None).unwrap_or_default().to_owned(),
+                alias_refs: item_alias_refs(located.item, s),
+                layer_text: located.deflayer.span.file_content()[located.deflayer.span.clone()]
+                    .to_string(),
+                bindings: layer_bindings(located.src_expr, &located.deflayer.t),
+                layout_fingerprint,
+            })
+        })
+    }
+
+    /// Parses the item at `osc_idx` of the layer at `level` into its actions in both versions of
+    /// the layer, as `parse_layers` does.
+    fn parse_actions(&self, item: &SExpr, level: usize, osc_idx: usize) -> Result<KeyActions> {
+        let s = &self.s;
+        const RELOAD: &str = "Change it in the configuration file and live reload instead.";
+        if level == 0 && s.delegate_to_first_layer {
+            bail_expr!(
+                item,
+                "The first layer cannot be changed with delegate-to-first-layer, since the \
+                transparent keys of the other layers use it. {RELOAD}"
+            );
+        }
+        if s.device_layers
+            .iter()
+            .any(|dl| dl.layer_idx == level && dl.mapping_order.contains(&osc_idx))
+        {
+            bail_expr!(item, "The key is also changed by deflayer-for. {RELOAD}");
+        }
+        if self.chord_keys.contains(&(level * 2, osc_idx)) {
+            bail_expr!(item, "The key is part of chords. {RELOAD}");
+        }
+        let before = s.added_state_lens();
+        let held = parse_action(item, s).map(|ac| resolve_defsrc_keys(ac, osc_idx, s));
+        if s.added_state_lens() != before {
+            // The layout has no room for what the action added, so forget it for the next action.
+            s.unnamed_fake_keys.borrow_mut().truncate(before[0]);
+            s.input_devices.borrow_mut().truncate(before[1]);
+            s.output_device_keys.borrow_mut().truncate(before[2]);
+            s.external_states.borrow_mut().truncate(before[3]);
+            bail_expr!(
+                item,
+                "The action adds fake keys, input devices, output devices or external states, \
+                which needs the whole layout to be rebuilt. {RELOAD}"
+            );
+        }
+        let held = held?;
+        if contains_chords(held) {
+            bail_expr!(item, "The action uses chords. {RELOAD}");
+        }
+        Ok(KeyActions {
+            switch: s
+                .a
+                .sref(layer_switch_action(*held, osc_idx, &s.defsrc_layer)),
+            held,
+            _allocations: s.a.clone(),
+        })
+    }
+}
+
+/// The item of a key in a `deflayer`.
+struct LocatedItem<'a> {
+    src_expr: &'a [SExpr],
+    /// The key in `defsrc`.
+    src: &'a SExpr,
+    /// The index of the key in `defsrc`.
+    index: usize,
+    deflayer: &'a TopLevel,
+    item: &'a SExpr,
+}
+
+/// Finds the item of `key`, which is a `defsrc` key or a name of the same key, in the `deflayer` of
+/// `layer`.
+fn locate_item<'a>(exprs: &'a [TopLevel], layer: &str, key: &str) -> Result<LocatedItem<'a>> {
+    let defsrc = exprs
+        .iter()
+        .filter(gen_first_atom_filter_spanned("defsrc"))
+        .find(|expr| !is_device_defsrc(&expr.t))
+        .ok_or_else(|| anyhow!("Exactly one defsrc must exist; found none"))?;
+    let osc = str_to_oscode(key);
+    let index = defsrc.t[1..]
+        .iter()
+        .position(|src| {
+            src.atom(None)
+                .is_some_and(|name| name == key || osc.is_some() && str_to_oscode(name) == osc)
+        })
+        .ok_or_else(|| anyhow!("Key {key} is not in defsrc"))?;
+    let deflayer = exprs
+        .iter()
+        .filter(gen_first_atom_filter_spanned("deflayer"))
+        .find(|deflayer| deflayer.t.get(1).and_then(|name| name.atom(None)) == Some(layer))
+        .ok_or_else(|| anyhow!("Layer {layer} is not defined with deflayer"))?;
+    let Some(item) = deflayer.t.get(index + 2) else {
+        bail_span!(deflayer, "Layer {layer} has no item for key {key}");
+    };
+    Ok(LocatedItem {
+        src_expr: &defsrc.t,
+        src: &defsrc.t[index + 1],
+        index,
+        deflayer,
+        item,
+    })
+}
+
+/// Returns the absolute path and the edited text of the file with the `deflayer` of `layer`.
+fn edit_key_action(
+    p: &Path,
+    edited: &EditedFiles,
+    layer: &str,
+    key: &str,
+    action: &str,
+) -> MResult<(PathBuf, String)> {
+    // Anything other than a single item would shift the following keys of the layer.
+    match sexpr::parse(&format!("(action {action})"), "action") {
+        Ok(exprs) if exprs.len() == 1 && exprs[0].t.len() == 2 => {}
+        _ => {
+            return Err(miette::miette!(
+                "The action must be a single item: {action}"
+            ))
+        }
+    }
+    read_edited_cfg_file(p, edited, |text, file_content_provider| {
+        let exprs = sexpr::parse(text, &p.to_string_lossy())
+            .and_then(|xs| expand_includes(xs, file_content_provider))?;
+        let span = locate_item(&exprs, layer, key)?.item.span();
+        let mut text = span.file_content();
+        text.replace_range(span.start()..span.end(), action);
+        // The main file is read by its file name, see `read_edited_cfg_file`.
+        let file_name = span.file_name();
+        let file_path = match p.file_name() {
+            Some(main_file_name) if file_name == p.to_string_lossy() => {
+                PathBuf::from(main_file_name)
+            }
+            _ => PathBuf::from(file_name),
+        };
+        let path = cfg_file_abs_path(p, &file_path).map_err(|e| anyhow!(e))?;
+        Ok((path, text))
+    })
+}
+
+/// Returns true if the action or an action within it is a chord.
+pub(super) fn contains_chords(action: &KanataAction) -> bool {
+    match action {
+        Action::Chords(_) => true,
+        Action::NoOp
+        | Action::Trans
+        | Action::Repeat
+        | Action::KeyCode(_)
+        | Action::MultipleKeyCodes(_)
+        | Action::Layer(_)
+        | Action::DefaultLayer(_)
+        | Action::Sequence { .. }
+        | Action::RepeatableSequence { .. }
+        | Action::CancelSequences
+        | Action::ReleaseState(_)
+        | Action::Custom(_) => false,
+        Action::HoldTap(HoldTapAction { tap, hold, .. }) => {
+            contains_chords(tap) || contains_chords(hold)
+        }
+        Action::OneShot(OneShot { action, .. }) => contains_chords(action),
+        Action::MultipleActions(actions) => actions.iter().any(contains_chords),
+        Action::TapDance(TapDance { actions, .. }) => actions.iter().any(|ac| contains_chords(ac)),
+        Action::Fork(ForkConfig { left, right, .. }) => {
+            contains_chords(left) || contains_chords(right)
+        }
+        Action::Switch(Switch { cases }) => cases.iter().any(|case| contains_chords(case.1)),
+    }
+}
@@ -0,0 +1,53 @@
+//! Parses the `on-startup` and `on-shutdown` options of `defcfg`. Their actions are tapped like
+//! fake keys when kanata starts and when it exits, e.g. to switch to the layer that matches the
+//! layout of the operating system or to tell a status bar whether kanata is running.
+//!
+//! Example:
+//!
+//! (defcfg
+//!   danger-enable-cmd yes
+//!   on-startup (multi (layer-switch qwerty) (cmd notify-send "kanata started"))
+//!   on-shutdown (cmd notify-send "kanata stopped")
+//! )
+
+use super::sexpr::SExpr;
+use super::*;
+
+pub(super) const ON_STARTUP_CFG_NAME: &str = "on-startup";
+pub(super) const ON_SHUTDOWN_CFG_NAME: &str = "on-shutdown";
+
+/// The `defcfg` options whose value is an action. Unlike other options, the value can be a list.
+pub(super) const LIFECYCLE_CFG_KEYS: [&str; 2] = [ON_STARTUP_CFG_NAME, ON_SHUTDOWN_CFG_NAME];
+
+/// The fake keys that run the actions of `on-startup` and `on-shutdown`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LifecycleKeys {
+    pub on_startup: Option<Coord>,
+    pub on_shutdown: Option<Coord>,
+}
+
+/// Adds an unnamed fake key for the action of each of `on-startup` and `on-shutdown` in `defcfg`.
+/// The options are otherwise checked by `parse_defcfg`.
+pub(super) fn parse_lifecycle_actions(
+    defcfg: Option<&Vec<SExpr>>,
+    s: &mut ParsedState,
+) -> Result<()> {
+    let Some(defcfg) = defcfg else {
+        return Ok(());
+    };
+    for pair in defcfg[1..].chunks_exact(2) {
+        let (key, value) = (&pair[0], &pair[1]);
+        let is_startup = match key.atom(None) {
+            Some(ON_STARTUP_CFG_NAME) => true,
+            Some(ON_SHUTDOWN_CFG_NAME) => false,
+            _ => continue,
+        };
+        let coord = Some(s.add_unnamed_fake_key(parse_action(value, s)?));
+        if is_startup {
+            s.lifecycle_keys.on_startup = coord;
+        } else {
+            s.lifecycle_keys.on_shutdown = coord;
+        }
+    }
+    Ok(())
+}
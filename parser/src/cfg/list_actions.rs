@@ -11,11 +11,13 @@ pub const TAP_HOLD_RELEASE: &str = "tap-hold-release";
 pub const TAP_HOLD_PRESS_TIMEOUT: &str = "tap-hold-press-timeout";
 pub const TAP_HOLD_RELEASE_TIMEOUT: &str = "tap-hold-release-timeout";
 pub const TAP_HOLD_RELEASE_KEYS: &str = "tap-hold-release-keys";
+pub const TAP_HOLD_OPPOSITE_HAND: &str = "tap-hold-opposite-hand";
 pub const MULTI: &str = "multi";
 pub const MACRO: &str = "macro";
 pub const MACRO_REPEAT: &str = "macro-repeat";
 pub const MACRO_RELEASE_CANCEL: &str = "macro-release-cancel";
 pub const MACRO_REPEAT_RELEASE_CANCEL: &str = "macro-repeat-release-cancel";
+pub const MACRO_GUARDED: &str = "macro-guarded";
 pub const UNICODE: &str = "unicode";
 pub const ONE_SHOT: &str = "one-shot";
 pub const ONE_SHOT_PRESS: &str = "one-shot-press";
@@ -46,11 +48,28 @@ pub const MOVEMOUSE_ACCEL_LEFT: &str = "movemouse-accel-left";
 pub const MOVEMOUSE_ACCEL_RIGHT: &str = "movemouse-accel-right";
 pub const MOVEMOUSE_SPEED: &str = "movemouse-speed";
 pub const SETMOUSE: &str = "setmouse";
+pub const REPEAT_ACCEL: &str = "repeat-accel";
+pub const OUTPUT_DEVICE: &str = "output-device";
+pub const GAMEPAD_BUTTON: &str = "gamepad-button";
+pub const GAMEPAD_AXIS: &str = "gamepad-axis";
+pub const AXIS: &str = "axis";
+pub const MIDI_NOTE: &str = "midi-note";
+pub const MIDI_CC: &str = "midi-cc";
+pub const OSC: &str = "osc";
+pub const HTTP_POST: &str = "http-post";
+pub const NOTIFY: &str = "notify";
+pub const WM_FOCUS: &str = "wm-focus";
+pub const WM_MOVE: &str = "wm-move";
+pub const WM_WORKSPACE: &str = "wm-workspace";
+pub const CLIPBOARD_SET: &str = "clipboard-set";
+pub const CLIPBOARD_SAVE_SLOT: &str = "clipboard-save-slot";
+pub const CLIPBOARD_RESTORE_SLOT: &str = "clipboard-restore-slot";
 pub const DYNAMIC_MACRO_RECORD: &str = "dynamic-macro-record";
 pub const DYNAMIC_MACRO_PLAY: &str = "dynamic-macro-play";
 pub const ARBITRARY_CODE: &str = "arbitrary-code";
 pub const CMD: &str = "cmd";
 pub const CMD_OUTPUT_KEYS: &str = "cmd-output-keys";
+pub const CMD_OUTPUT_TEXT: &str = "cmd-output-text";
 pub const FORK: &str = "fork";
 pub const CAPS_WORD: &str = "caps-word";
 pub const CAPS_WORD_CUSTOM: &str = "caps-word-custom";
@@ -58,9 +77,13 @@ pub const DYNAMIC_MACRO_RECORD_STOP_TRUNCATE: &str = "dynamic-macro-record-stop-
 pub const SWITCH: &str = "switch";
 pub const SEQUENCE: &str = "sequence";
 pub const UNMOD: &str = "unmod";
+pub const LAYER_CYCLE: &str = "layer-cycle";
+pub const LAYER_CYCLE_REVERSE: &str = "layer-cycle-reverse";
+pub const PRESS_RELEASE: &str = "press-release";
+pub const MODS_PASSTHROUGH: &str = "mods-passthrough";
 
 pub fn is_list_action(ac: &str) -> bool {
-    const LIST_ACTIONS: [&str; 56] = [
+    const LIST_ACTIONS: [&str; 79] = [
         LAYER_SWITCH,
         LAYER_TOGGLE,
         LAYER_WHILE_HELD,
@@ -70,11 +93,13 @@ pub fn is_list_action(ac: &str) -> bool {
         TAP_HOLD_PRESS_TIMEOUT,
         TAP_HOLD_RELEASE_TIMEOUT,
         TAP_HOLD_RELEASE_KEYS,
+        TAP_HOLD_OPPOSITE_HAND,
         MULTI,
         MACRO,
         MACRO_REPEAT,
         MACRO_RELEASE_CANCEL,
         MACRO_REPEAT_RELEASE_CANCEL,
+        MACRO_GUARDED,
         UNICODE,
         ONE_SHOT,
         ONE_SHOT_PRESS,
@@ -105,11 +130,28 @@ pub fn is_list_action(ac: &str) -> bool {
         MOVEMOUSE_ACCEL_RIGHT,
         MOVEMOUSE_SPEED,
         SETMOUSE,
+        REPEAT_ACCEL,
+        OUTPUT_DEVICE,
+        GAMEPAD_BUTTON,
+        GAMEPAD_AXIS,
+        AXIS,
+        MIDI_NOTE,
+        MIDI_CC,
+        OSC,
+        HTTP_POST,
+        NOTIFY,
+        WM_FOCUS,
+        WM_MOVE,
+        WM_WORKSPACE,
+        CLIPBOARD_SET,
+        CLIPBOARD_SAVE_SLOT,
+        CLIPBOARD_RESTORE_SLOT,
         DYNAMIC_MACRO_RECORD,
         DYNAMIC_MACRO_PLAY,
         ARBITRARY_CODE,
         CMD,
         CMD_OUTPUT_KEYS,
+        CMD_OUTPUT_TEXT,
         FORK,
         CAPS_WORD,
         CAPS_WORD_CUSTOM,
@@ -117,6 +159,10 @@ pub fn is_list_action(ac: &str) -> bool {
         SWITCH,
         SEQUENCE,
         UNMOD,
+        LAYER_CYCLE,
+        LAYER_CYCLE_REVERSE,
+        PRESS_RELEASE,
+        MODS_PASSTHROUGH,
     ];
     LIST_ACTIONS.contains(&ac)
 }
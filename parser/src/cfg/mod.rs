@@ -58,8 +58,10 @@ use crate::layers::*;
 mod error;
 pub use error::*;
 
-use crate::trie::Trie;
+use crate::sequences::{KeySequences, SequencePatternItem};
+use crate::trie::{GetOrDescendentExistsResult, Trie};
 use anyhow::anyhow;
+use std::cell::RefCell;
 use std::collections::hash_map::Entry;
 use std::path::Path;
 use std::path::PathBuf;
@@ -126,6 +128,39 @@ macro_rules! anyhow_span {
     };
 }
 
+mod deftest;
+pub use deftest::*;
+
+mod deftemplate;
+use deftemplate::*;
+
+mod chords_v2;
+use chords_v2::*;
+
+mod key_action_edit;
+pub use key_action_edit::*;
+
+mod conditional;
+use conditional::*;
+
+mod trainer;
+pub use trainer::*;
+
+mod lifecycle;
+pub use lifecycle::*;
+
+mod fingerprint;
+pub use fingerprint::*;
+
+mod alias_refs;
+pub use alias_refs::*;
+
+mod builder;
+pub use builder::*;
+
+mod analysis;
+pub use analysis::*;
+
 pub struct FileContentProvider<'a> {
     /// A function to load content of a file from a filepath.
     /// Optionally, it could implement caching and a mechanism preventing "file" and "./file" from loading twice.
@@ -151,7 +186,7 @@ type KLayout =
 
 pub type BorrowedKLayout<'a> =
     Layout<'a, KEYS_IN_ROW, 2, ACTUAL_NUM_LAYERS, &'a &'a [&'a CustomAction]>;
-pub type KeySeqsToFKeys = Trie;
+pub type KeySeqsToFKeys = KeySequences;
 
 pub struct KanataLayout {
     layout: KLayout,
@@ -177,6 +212,46 @@ impl KanataLayout {
         // shrink the lifetime
         unsafe { std::mem::transmute(&self.layout) }
     }
+
+    /// Returns the actions of the key in the layer whose "layer-switch" version is at `layer`.
+    pub fn key_actions(&self, layer: usize, osc: OsCode) -> KeyActions {
+        let layers = self.layout.layers;
+        KeyActions {
+            switch: &layers[layer][0][usize::from(osc)],
+            held: &layers[layer + 1][0][usize::from(osc)],
+            _allocations: self._allocations.clone(),
+        }
+    }
+
+    /// Changes the actions of the key in the layer whose "layer-switch" version is at `layer`. The
+    /// state of the layout, e.g. the held keys and the active layers, is kept.
+    pub fn set_key_actions(&mut self, layer: usize, osc: OsCode, actions: &KeyActions) {
+        debug_assert!(Arc::ptr_eq(&actions._allocations, &self._allocations));
+        let a = &self._allocations;
+        let mut layers = *self.layout.layers;
+        for (layer, action) in [(layer, actions.switch), (layer + 1, actions.held)] {
+            let row = layers[layer][0];
+            let fallback = match row {
+                LayerRow::Dense(actions) => actions,
+                LayerRow::Sparse { fallback, .. } => fallback,
+            };
+            let mut actions = Box::new(*fallback);
+            for (col, action) in row.iter().enumerate() {
+                actions[col] = *action;
+            }
+            actions[usize::from(osc)] = *action;
+            layers[layer][0] = new_layer_row(&actions, &[fallback], a);
+        }
+        self.layout.layers = a.sref(layers);
+    }
+
+    /// Replaces the layout with a new one of the same layers, which resets the layout state as if
+    /// the configuration had been parsed again.
+    pub fn reset(&mut self) {
+        let mut layout = Layout::new(self.layout.layers);
+        layout.pair_thresholds = self.layout.pair_thresholds;
+        self.layout = layout;
+    }
 }
 
 pub struct Cfg {
@@ -197,11 +272,102 @@ pub struct Cfg {
     pub sequences: KeySeqsToFKeys,
     /// Overrides defined in `defoverrides`.
     pub overrides: Overrides,
+    /// Tests defined in `deftest`.
+    pub tests: Vec<CfgTest>,
+    /// Device names used by `input-device` switch conditions. A device matching the name at index
+    /// `i` has the device index `i + 1` in switch conditions.
+    pub input_devices: Vec<String>,
+    /// Conditions on external state used by `state-file` and `env-var` switch conditions.
+    pub external_states: Vec<ExternalStateCondition>,
+    /// Names of the fake keys defined in `deffakekeys`, mapped to their index in the fake key
+    /// row.
+    pub fake_keys: HashMap<String, usize>,
+    /// Keys that `output-device` actions send through a specific output device.
+    pub output_device_keys: Vec<(OutputDevice, OsCode)>,
+    /// Layers that block wrong-finger keys, defined with `deftrainer`.
+    pub trainer: Trainer,
+    /// The fake keys of the `on-startup` and `on-shutdown` actions in `defcfg`.
+    pub lifecycle_keys: LifecycleKeys,
+    /// The `layout` of the fingerprint of the configuration. Live reload compares it to skip
+    /// rebuilding an unchanged layout.
+    pub layout_fingerprint: u64,
+    /// The aliases that the parts of the configuration refer to, for the action usage report.
+    pub alias_refs: AliasRefs,
+    /// Parses new actions of single keys for `SetKeyAction`.
+    pub key_action_parser: KeyActionParser,
+    /// The compiled layers, which live reload reuses for the layers that did not change.
+    pub layer_cache: LayerCache,
 }
 
 /// Parse a new configuration from a file.
 pub fn new_from_file(p: &Path) -> MResult<Cfg> {
-    let (items, mapped_keys, layer_info, key_outputs, layout, sequences, overrides) = parse_cfg(p)?;
+    new_from_raw(|s| parse_cfg_raw(p, s))
+}
+
+/// Parse a new configuration from the top-level forms read by `read_cfg_exprs`, reusing the
+/// unchanged layers of `layer_cache`.
+fn new_from_exprs(exprs: Vec<TopLevel>, layer_cache: LayerCache) -> MResult<Cfg> {
+    new_from_raw(|s| {
+        s.layer_cache = layer_cache;
+        parse_cfg_raw_exprs(exprs, s, DEF_LOCAL_KEYS).map_err(|e| e.into())
+    })
+}
+
+/// The file name in the errors of configurations that are parsed from text.
+const CFG_TEXT_NAME: &str = "configuration";
+
+/// Parse a new configuration from text, e.g. the text made by [`ConfigBuilder`]. The text cannot
+/// contain `include` since there is no file that the paths of included files are relative to.
+pub fn new_from_str(text: &str) -> MResult<Cfg> {
+    new_from_raw(|s| {
+        let mut get_file_content = |_: &Path| -> std::result::Result<String, String> {
+            Err("include is not supported in configurations that are not read from a file".into())
+        };
+        parse_cfg_raw_string(
+            text,
+            s,
+            Path::new(CFG_TEXT_NAME),
+            &mut FileContentProvider::new(&mut get_file_content),
+            DEF_LOCAL_KEYS,
+        )
+        .map_err(|e| e.into())
+    })
+}
+
+#[allow(clippy::type_complexity)] // parameter type is not pub
+fn new_from_raw(
+    parse_raw: impl FnOnce(
+        &mut ParsedState,
+    ) -> MResult<(
+        HashMap<String, String>,
+        MappedKeys,
+        Vec<LayerInfo>,
+        ParsedLayers,
+        KeySeqsToFKeys,
+        Overrides,
+        Vec<CfgTest>,
+    )>,
+) -> MResult<Cfg> {
+    let (
+        items,
+        mapped_keys,
+        layer_info,
+        key_outputs,
+        layout,
+        sequences,
+        overrides,
+        tests,
+        input_devices,
+        external_states,
+        fake_keys,
+        output_device_keys,
+        trainer,
+        lifecycle_keys,
+        layout_fingerprint,
+        alias_refs,
+        layer_cache,
+        key_action_parser,
+    ) = parse_cfg(parse_raw)?;
     log::info!("config parsed");
     Ok(Cfg {
         items,
@@ -211,6 +377,17 @@ pub fn new_from_file(p: &Path) -> MResult<Cfg> {
         layout,
         sequences,
         overrides,
+        tests,
+        input_devices,
+        external_states,
+        fake_keys,
+        output_device_keys,
+        trainer,
+        lifecycle_keys,
+        layout_fingerprint,
+        alias_refs,
+        key_action_parser,
+        layer_cache,
     })
 }
 
@@ -224,11 +401,25 @@ pub type KeyOutputs = Vec<HashMap<OsCode, Vec<OsCode>>>;
 pub struct LayerInfo {
     pub name: String,
     pub cfg_text: String,
+    /// The `defsrc` keys that the layer changes, with the text of their item in the layer.
+    pub bindings: Vec<(String, String)>,
+    /// The defcfg options overridden by `deflayer-cfg` while the layer is active.
+    pub cfg_overrides: HashMap<String, String>,
 }
 
 #[allow(clippy::type_complexity)] // return type is not pub
 fn parse_cfg(
-    p: &Path,
+    parse_raw: impl FnOnce(
+        &mut ParsedState,
+    ) -> MResult<(
+        HashMap<String, String>,
+        MappedKeys,
+        Vec<LayerInfo>,
+        ParsedLayers,
+        KeySeqsToFKeys,
+        Overrides,
+        Vec<CfgTest>,
+    )>,
 ) -> MResult<(
     HashMap<String, String>,
     MappedKeys,
@@ -237,17 +428,46 @@ fn parse_cfg(
     KanataLayout,
     KeySeqsToFKeys,
     Overrides,
+    Vec<CfgTest>,
+    Vec<String>,
+    Vec<ExternalStateCondition>,
+    HashMap<String, usize>,
+    Vec<(OutputDevice, OsCode)>,
+    Trainer,
+    LifecycleKeys,
+    u64,
+    AliasRefs,
+    LayerCache,
+    KeyActionParser,
 )> {
     let mut s = ParsedState::default();
-    let (cfg, src, layer_info, klayers, seqs, overrides) = parse_cfg_raw(p, &mut s)?;
+    let (cfg, src, layer_info, klayers, seqs, overrides, tests) = parse_raw(&mut s)?;
+    // The parsed state is kept to parse the actions of `SetKeyAction`.
+    let input_devices = s.input_devices.borrow().clone();
+    let external_states = s.external_states.borrow().clone();
+    let output_device_keys = s.output_device_keys.borrow().clone();
     Ok((
         cfg,
         src,
         layer_info,
         create_key_outputs(&klayers, &overrides),
-        create_layout(klayers, s.a),
+        create_layout(&klayers, s.pair_thresholds, s.a.clone()),
         seqs,
         overrides,
+        tests,
+        input_devices,
+        external_states,
+        s.fake_keys
+            .iter()
+            .map(|(name, (idx, _))| (name.clone(), *idx))
+            .collect(),
+        output_device_keys,
+        std::mem::take(&mut s.trainer),
+        s.lifecycle_keys,
+        s.layout_fingerprint,
+        std::mem::take(&mut s.alias_refs),
+        std::mem::take(&mut s.layer_cache),
+        KeyActionParser::new(s, &klayers),
     ))
 }
 
@@ -261,6 +481,8 @@ const DEF_LOCAL_KEYS: &str = "deflocalkeys-win";
 const DEF_LOCAL_KEYS: &str = "deflocalkeys-wintercept";
 #[cfg(any(target_os = "linux", target_os = "unknown"))]
 const DEF_LOCAL_KEYS: &str = "deflocalkeys-linux";
+#[cfg(target_os = "macos")]
+const DEF_LOCAL_KEYS: &str = "deflocalkeys-macos";
 
 #[allow(clippy::type_complexity)] // return type is not pub
 fn parse_cfg_raw(
@@ -270,39 +492,46 @@ fn parse_cfg_raw(
     HashMap<String, String>,
     MappedKeys,
     Vec<LayerInfo>,
-    Box<KanataLayers>,
+    ParsedLayers,
     KeySeqsToFKeys,
     Overrides,
+    Vec<CfgTest>,
 )> {
-    const INVALID_PATH_ERROR: &str = "The provided config file path is not valid";
+    let exprs = read_cfg_exprs(p, &EditedFiles::default())?;
+    parse_cfg_raw_exprs(exprs, s, DEF_LOCAL_KEYS).map_err(|e| e.into())
+}
+
+/// Reads the top-level forms of the configuration file at `p` and of the files that it includes.
+/// The files in `edited` are read from their edited text.
+pub(super) fn read_cfg_exprs(p: &Path, edited: &EditedFiles) -> MResult<Vec<TopLevel>> {
+    read_edited_cfg_file(p, edited, |text, file_content_provider| {
+        sexpr::parse(text, &p.to_string_lossy())
+            .and_then(|xs| expand_includes(xs, file_content_provider))
+    })
+}
 
+/// Reads the configuration file at `p` and calls `f` with its text and a provider of the files
+/// that it includes. The files in `edited` are read from their edited text instead.
+fn read_edited_cfg_file<T>(
+    p: &Path,
+    edited: &EditedFiles,
+    f: impl FnOnce(&str, &mut FileContentProvider) -> Result<T>,
+) -> MResult<T> {
     let mut loaded_files: HashSet<PathBuf> = HashSet::default();
 
     let mut get_file_content_fn_impl = |filepath: &Path| {
-        // Make the include paths relative to main config file instead of kanata executable.
-        let filepath_relative_to_loaded_kanata_cfg = if filepath.is_absolute() {
-            filepath.to_owned()
-        } else {
-            let relative_main_cfg_file_dir = p.parent().ok_or(INVALID_PATH_ERROR)?;
-            relative_main_cfg_file_dir.join(filepath)
-        };
+        let abs_filepath = cfg_file_abs_path(p, filepath)?;
 
         // Forbid loading the same file multiple times.
         // This prevents a potential recursive infinite loop of includes
         // (if includes within includes were to be allowed).
-        let abs_filepath: PathBuf = filepath_relative_to_loaded_kanata_cfg
-            .canonicalize()
-            .map_err(|e| {
-                format!(
-                    "Failed to resolve absolute path: {}: {}",
-                    filepath_relative_to_loaded_kanata_cfg.to_string_lossy(),
-                    e
-                )
-            })?;
         if !loaded_files.insert(abs_filepath.clone()) {
             return Err("The provided config file was already included before".to_string());
         };
 
+        if let Some(text) = edited.get(&abs_filepath) {
+            return Ok(text.clone());
+        }
         std::fs::read_to_string(abs_filepath.to_str().ok_or(INVALID_PATH_ERROR)?)
             .map_err(|e| format!("Failed to include file: {e}"))
     };
@@ -318,8 +547,29 @@ fn parse_cfg_raw(
         .get_file_content(&cfg_file_name)
         .map_err(|e| miette::miette!(e))?;
 
-    parse_cfg_raw_string(&text, s, p, &mut file_content_provider, DEF_LOCAL_KEYS)
-        .map_err(|e| e.into())
+    f(&text, &mut file_content_provider).map_err(|e| e.into())
+}
+
+const INVALID_PATH_ERROR: &str = "The provided config file path is not valid";
+
+/// Returns the absolute path of a file of the configuration at `p`, e.g. an included file. Relative
+/// paths are relative to the directory of `p` instead of the kanata executable.
+fn cfg_file_abs_path(p: &Path, filepath: &Path) -> std::result::Result<PathBuf, String> {
+    let filepath_relative_to_loaded_kanata_cfg = if filepath.is_absolute() {
+        filepath.to_owned()
+    } else {
+        let relative_main_cfg_file_dir = p.parent().ok_or(INVALID_PATH_ERROR)?;
+        relative_main_cfg_file_dir.join(filepath)
+    };
+    filepath_relative_to_loaded_kanata_cfg
+        .canonicalize()
+        .map_err(|e| {
+            format!(
+                "Failed to resolve absolute path: {}: {}",
+                filepath_relative_to_loaded_kanata_cfg.to_string_lossy(),
+                e
+            )
+        })
 }
 
 fn expand_includes(
@@ -327,6 +577,8 @@ fn expand_includes(
     file_content_provider: &mut FileContentProvider,
 ) -> Result<Vec<TopLevel>> {
     let include_is_first_atom = gen_first_atom_filter("include");
+    // Platform and environment items are expanded first so that they can contain includes.
+    let xs = expand_conditionals(xs)?;
     xs.iter().try_fold(Vec::new(), |mut acc, spanned_exprs| {
         if include_is_first_atom(&&spanned_exprs.t) {
             let mut exprs =
@@ -353,7 +605,7 @@ fn expand_includes(
             let include_file_path = spanned_filepath.t.trim_matches('"');
             let file_content = file_content_provider.get_file_content(Path::new(include_file_path)).map_err(|e| anyhow_span!(spanned_filepath, "{e}"))?;
             let tree = sexpr::parse(&file_content, include_file_path)?;
-            acc.extend(tree);
+            acc.extend(expand_conditionals(tree)?);
 
             Ok(acc)
         } else {
@@ -374,19 +626,39 @@ pub fn parse_cfg_raw_string(
     HashMap<String, String>,
     MappedKeys,
     Vec<LayerInfo>,
-    Box<KanataLayers>,
+    ParsedLayers,
     KeySeqsToFKeys,
     Overrides,
+    Vec<CfgTest>,
 )> {
     let spanned_root_exprs = sexpr::parse(text, &cfg_path.to_string_lossy())
         .and_then(|xs| expand_includes(xs, file_content_provider))?;
+    parse_cfg_raw_exprs(spanned_root_exprs, s, def_local_keys_variant_to_apply)
+}
 
+/// Like `parse_cfg_raw_string`, but parses top-level forms whose includes are already expanded.
+#[allow(clippy::type_complexity)] // return type is not pub
+fn parse_cfg_raw_exprs(
+    spanned_root_exprs: Vec<TopLevel>,
+    s: &mut ParsedState,
+    def_local_keys_variant_to_apply: &str,
+) -> Result<(
+    HashMap<String, String>,
+    MappedKeys,
+    Vec<LayerInfo>,
+    ParsedLayers,
+    KeySeqsToFKeys,
+    Overrides,
+    Vec<CfgTest>,
+)> {
+    let layout_fingerprint = fingerprint(&spanned_root_exprs)?.layout;
     if let Some(spanned) = spanned_root_exprs
         .iter()
         .find(gen_first_atom_filter_spanned("include"))
     {
         bail_span!(spanned, "Nested includes are not allowed.")
     }
+    let spanned_root_exprs = expand_templates(spanned_root_exprs)?;
 
     let root_exprs: Vec<_> = spanned_root_exprs.iter().map(|t| t.t.clone()).collect();
 
@@ -415,6 +687,7 @@ pub fn parse_cfg_raw_string(
         "deflocalkeys-win",
         "deflocalkeys-wintercept",
         "deflocalkeys-linux",
+        "deflocalkeys-macos",
     ] {
         if let Some(result) = root_exprs
             .iter()
@@ -444,21 +717,34 @@ pub fn parse_cfg_raw_string(
     }
     replace_custom_str_oscode_mapping(&local_keys.unwrap_or_default());
 
+    let defsrc_filter = gen_first_atom_filter("defsrc");
     let src_expr = root_exprs
         .iter()
-        .find(gen_first_atom_filter("defsrc"))
+        .filter(&defsrc_filter)
+        .find(|expr| !is_device_defsrc(expr))
         .ok_or_else(|| anyhow!("Exactly one defsrc must exist; found none"))?;
     if let Some(spanned) = spanned_root_exprs
         .iter()
         .filter(gen_first_atom_filter_spanned("defsrc"))
+        .filter(|expr| !is_device_defsrc(&expr.t))
         .nth(1)
     {
         bail_span!(
             spanned,
-            "Exactly one defsrc is allowed, found more. Delete the extras."
+            "Exactly one defsrc without a device binding is allowed, found more. Delete the extras."
         )
     }
-    let (src, mapping_order) = parse_defsrc(src_expr, &cfg)?;
+    let (mut src, mapping_order) = parse_defsrc(src_expr, &cfg)?;
+
+    let device_src_exprs = root_exprs
+        .iter()
+        .filter(&defsrc_filter)
+        .filter(|expr| is_device_defsrc(expr))
+        .collect::<Vec<_>>();
+    let device_sources = parse_device_sources(&device_src_exprs, &cfg)?;
+    for source in device_sources.values() {
+        src.extend(source.mapped_keys.iter().copied());
+    }
 
     let deflayer_filter = gen_first_atom_filter("deflayer");
     let layer_exprs = spanned_root_exprs
@@ -512,12 +798,49 @@ pub fn parse_cfg_raw_string(
         })
         .collect::<Vec<_>>();
 
+    let layer_bindings = layer_exprs
+        .iter()
+        .map(|expr| layer_bindings(src_expr, &expr.t))
+        .flat_map(|bindings| {
+            // Duplicate the same layer for `layer_bindings` because the keyberon layout itself
+            // has two versions of each layer.
+            std::iter::repeat(bindings).take(2)
+        });
+
+    let layer_cfg_exprs = root_exprs
+        .iter()
+        .filter(gen_first_atom_filter("deflayer-cfg"))
+        .collect::<Vec<_>>();
+    let layer_cfg_overrides = parse_layer_cfgs(&layer_cfg_exprs, &layer_idxs)?;
+
     let layer_info: Vec<LayerInfo> = layer_names
         .into_iter()
         .zip(layer_strings)
-        .map(|(name, cfg_text)| LayerInfo { name, cfg_text })
+        .zip(layer_bindings)
+        .enumerate()
+        .map(|(i, ((name, cfg_text), bindings))| LayerInfo {
+            name,
+            cfg_text,
+            bindings,
+            // The keyberon layout has two versions of each layer.
+            cfg_overrides: layer_cfg_overrides
+                .get(&(i / 2))
+                .cloned()
+                .unwrap_or_default(),
+        })
         .collect();
 
+    let device_layer_exprs = root_exprs
+        .iter()
+        .filter(gen_first_atom_filter("deflayer-for"))
+        .collect::<Vec<_>>();
+    let device_layers = parse_device_layers(
+        &device_layer_exprs,
+        &device_sources,
+        &mapping_order,
+        &layer_idxs,
+    )?;
+
     let defsrc_layer = parse_defsrc_layer(src_expr, &mapping_order, s);
 
     let layer_exprs = root_exprs
@@ -528,9 +851,13 @@ pub fn parse_cfg_raw_string(
 
     *s = ParsedState {
         a: s.a.clone(),
+        layout_fingerprint,
+        layer_context: layer_context(&root_exprs, &cfg),
+        layer_cache: std::mem::take(&mut s.layer_cache),
         layer_exprs,
         layer_idxs,
         mapping_order,
+        device_layers,
         defsrc_layer,
         is_cmd_enabled: {
             #[cfg(feature = "cmd")]
@@ -578,6 +905,34 @@ pub fn parse_cfg_raw_string(
         .collect::<Vec<_>>();
     parse_vars(&var_exprs, s)?;
 
+    let key_set_exprs = root_exprs
+        .iter()
+        .filter(gen_first_atom_filter("defkeys"))
+        .collect::<Vec<_>>();
+    parse_key_sets(&key_set_exprs, s)?;
+
+    let hands_exprs = root_exprs
+        .iter()
+        .filter(gen_first_atom_filter("defhands"))
+        .collect::<Vec<_>>();
+    parse_hands(&hands_exprs, s)?;
+
+    let finger_exprs = root_exprs
+        .iter()
+        .filter(gen_first_atom_filter("deffingers"))
+        .collect::<Vec<_>>();
+    let trainer_exprs = root_exprs
+        .iter()
+        .filter(gen_first_atom_filter("deftrainer"))
+        .collect::<Vec<_>>();
+    parse_trainer(&finger_exprs, &trainer_exprs, s)?;
+
+    let overlap_exprs = root_exprs
+        .iter()
+        .filter(gen_first_atom_filter("defoverlap"))
+        .collect::<Vec<_>>();
+    parse_pair_thresholds(&overlap_exprs, s)?;
+
     let chords_exprs = spanned_root_exprs
         .iter()
         .filter(gen_first_atom_filter_spanned("defchords"))
@@ -602,10 +957,18 @@ pub fn parse_cfg_raw_string(
         .collect::<Vec<_>>();
     parse_aliases(&alias_exprs, s)?;
 
+    parse_lifecycle_actions(root_exprs.iter().find(gen_first_atom_filter("defcfg")), s)?;
+
     let mut klayers = parse_layers(s)?;
 
     resolve_chord_groups(&mut klayers, s)?;
 
+    let chords_v2_exprs = root_exprs
+        .iter()
+        .filter(gen_first_atom_filter("defchordsv2"))
+        .collect::<Vec<_>>();
+    resolve_chords_v2(&chords_v2_exprs, &mut klayers, s)?;
+
     let override_exprs = root_exprs
         .iter()
         .filter(gen_first_atom_filter("defoverrides"))
@@ -626,7 +989,38 @@ pub fn parse_cfg_raw_string(
         }
     };
 
-    Ok((cfg, src, layer_info, klayers, sequences, overrides))
+    let test_exprs = spanned_root_exprs
+        .iter()
+        .filter(gen_first_atom_filter_spanned("deftest"))
+        .collect::<Vec<_>>();
+    let tests = parse_tests(&test_exprs, s)?;
+
+    s.alias_refs = find_alias_refs(&root_exprs, s);
+
+    Ok((cfg, src, layer_info, klayers, sequences, overrides, tests))
+}
+
+/// Returns the `defsrc` keys that the layer changes, with the text of their item in the layer.
+/// Transparent items and items that are the same as the `defsrc` key are left out.
+fn layer_bindings(src_expr: &[SExpr], layer_expr: &[SExpr]) -> Vec<(String, String)> {
+    src_expr
+        .iter()
+        .skip(1)
+        .zip(layer_expr.iter().skip(2))
+        .filter_map(|(src, item)| {
+            let key = src.atom(None)?;
+            let span = item.span();
+            let text = span.file_content[span.clone()]
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ");
+            match text.as_str() {
+                "_" => None,
+                text if text == key => None,
+                _ => Some((key.to_owned(), text)),
+            }
+        })
+        .collect()
 }
 
 fn error_on_unknown_top_level_atoms(exprs: &[Spanned<Vec<SExpr>>]) -> Result<()> {
@@ -646,14 +1040,24 @@ fn error_on_unknown_top_level_atoms(exprs: &[Spanned<Vec<SExpr>>]) -> Result<()>
                 | "defaliasenvcond"
                 | "defsrc"
                 | "deflayer"
+                | "deflayer-for"
+                | "deflayer-cfg"
                 | "defoverrides"
                 | "deflocalkeys-linux"
                 | "deflocalkeys-win"
                 | "deflocalkeys-wintercept"
+                | "deflocalkeys-macos"
                 | "deffakekeys"
                 | "defchords"
+                | "defchordsv2"
                 | "defvar"
-                | "defseq" => Ok(()),
+                | "defkeys"
+                | "defhands"
+                | "deffingers"
+                | "deftrainer"
+                | "defoverlap"
+                | "defseq"
+                | "deftest" => Ok(()),
                 _ => bail_span!(expr, "Found unknown configuration item"),
             })
             .ok_or_else(|| {
@@ -740,14 +1144,48 @@ fn parse_defcfg(expr: &[SExpr]) -> Result<HashMap<String, String>> {
         "sequence-timeout",
         "sequence-input-mode",
         "dynamic-macro-max-presses",
+        "macro-delay-percent",
+        "type-delay",
+        "movemouse-speed-percent",
+        "mwheel-speed-percent",
         "linux-dev",
         "linux-dev-names-include",
         "linux-dev-names-exclude",
+        "linux-dev-priority",
+        "linux-device-detect-mode",
         "linux-unicode-u-code",
         "linux-unicode-termination",
         "linux-x11-repeat-delay-rate",
+        "linux-device-repeat-delay-rate",
+        "linux-mwheel-hi-res-threshold",
+        "linux-touchpad-swipe-distance",
+        "process-priority",
+        "event-loop-cpus",
+        "processing-loop-cpus",
+        "linux-output-mirror-name",
+        "linux-output-name",
+        "linux-output-id",
+        "linux-output-bus",
+        "linux-output-events",
+        "linux-layer-leds",
+        "linux-midi-device",
+        "osc-target",
+        "wm-socket",
+        "http-post-timeout",
+        "http-post-min-interval",
         "windows-altgr",
         "windows-interception-mouse-hwid",
+        "macos-app-layers",
+        "editor",
+        "tcp-mouse-position-interval",
+        "tcp-typing-stats-interval",
+        "max-held-key-duration",
+        "output-delay",
+        "min-press-duration",
+        "break-after-typing",
+        "break-duration",
+        "break-layer",
+        "break-override-keys",
     ];
     let bool_cfg_keys = &[
         "process-unmapped-keys",
@@ -758,6 +1196,14 @@ fn parse_defcfg(expr: &[SExpr]) -> Result<HashMap<String, String>> {
         "linux-continue-if-no-devs-found",
         "movemouse-smooth-diagonals",
         "movemouse-inherit-accel-state",
+        "key-repeat",
+        "edit-config-live-reload",
+        "linux-output-separate-mouse",
+        "linux-output-gamepad",
+        "linux-output-self-test",
+        "linux-lock-leds",
+        "linux-release-keys-on-device-removal",
+        "windows-sendinput-scancode",
     ];
     let mut cfg = HashMap::default();
     let mut exprs = check_first_expr(expr.iter(), "defcfg")?;
@@ -772,6 +1218,16 @@ fn parse_defcfg(expr: &[SExpr]) -> Result<HashMap<String, String>> {
             None => bail_expr!(key, "Found a defcfg option missing a value"),
         };
         match (&key, &val) {
+            (SExpr::Atom(k), _) if LIFECYCLE_CFG_KEYS.contains(&&*k.t) => {
+                // The action is parsed with the layout, so only its text is kept here.
+                let span = val.span();
+                if cfg
+                    .insert(k.t.clone(), span.file_content[span.clone()].to_owned())
+                    .is_some()
+                {
+                    bail_expr!(key, "Duplicate defcfg option {}", k.t);
+                }
+            }
             (SExpr::Atom(k), SExpr::Atom(v)) => {
                 if non_bool_cfg_keys.contains(&&*k.t) {
                     // nothing to do
@@ -840,7 +1296,11 @@ fn parse_deflocalkeys(
                     })
                 })
                 .and_then(|osc| {
-                    OsCode::from_u16(osc).ok_or_else(|| {
+                    match def_local_keys_variant {
+                        "deflocalkeys-macos" => mac_keycode_to_oscode(osc),
+                        _ => OsCode::from_u16(osc),
+                    }
+                    .ok_or_else(|| {
                         anyhow_expr!(v, "Unknown number in {def_local_keys_variant}: {osc}")
                     })
                 })?,
@@ -898,6 +1358,255 @@ fn parse_defsrc(
     Ok((mkeys, ordered_codes))
 }
 
+/// Returns true if the `defsrc` expression is bound to input devices, i.e. it has the form
+/// `(defsrc <name> (devices <device names>) <keys>)`.
+fn is_device_defsrc(expr: &[SExpr]) -> bool {
+    matches!(expr.get(2), Some(SExpr::List(_)))
+}
+
+/// A `defsrc` that is bound to input devices.
+#[derive(Debug)]
+struct DeviceSource {
+    devices: Vec<String>,
+    mapped_keys: MappedKeys,
+    mapping_order: Vec<usize>,
+}
+
+/// Parse the `defsrc` expressions that are bound to input devices. Returns the sources by name.
+fn parse_device_sources(
+    exprs: &[&Vec<SExpr>],
+    defcfg: &HashMap<String, String>,
+) -> Result<HashMap<String, DeviceSource>> {
+    const ERR_MSG: &str =
+        "a defsrc bound to devices expects: <name> (devices <device names>) <keys>";
+    let mut sources = HashMap::default();
+    for expr in exprs {
+        let name_expr = &expr[1];
+        let name = name_expr.atom(None).ok_or_else(|| {
+            anyhow_expr!(
+                name_expr,
+                "{ERR_MSG}
+The name must be a string"
+            )
+        })?;
+        if sources.contains_key(name) {
+            bail_expr!(name_expr, "duplicate defsrc name: {name}");
+        }
+        let devices = parse_device_names(&expr[2], ERR_MSG)?;
+        // Reuse the parsing of the default defsrc by leaving out the name and devices.
+        let keys_expr = std::iter::once(expr[0].clone())
+            .chain(expr[3..].iter().cloned())
+            .collect::<Vec<_>>();
+        let (mapped_keys, mapping_order) = parse_defsrc(&keys_expr, defcfg)?;
+        sources.insert(
+            name.to_owned(),
+            DeviceSource {
+                devices,
+                mapped_keys,
+                mapping_order,
+            },
+        );
+    }
+    Ok(sources)
+}
+
+/// Parse a list of the form `(devices <device names>)`.
+fn parse_device_names(devices_expr: &SExpr, err_msg: &str) -> Result<Vec<String>> {
+    match devices_expr.list(None) {
+        Some([SExpr::Atom(first), devices @ ..]) if first.t == "devices" && !devices.is_empty() => {
+            devices
+                .iter()
+                .map(|device| {
+                    device
+                        .atom(None)
+                        .map(|device| device.trim_matches('"').to_owned())
+                        .ok_or_else(|| anyhow_expr!(device, "device name must be a string"))
+                })
+                .collect()
+        }
+        _ => bail_expr!(
+            devices_expr,
+            "{err_msg}
+This must be a list of one or more device names beginning with devices"
+        ),
+    }
+}
+
+/// A layer defined with `deflayer-for`, which applies to the input devices of its `defsrc` or to
+/// the devices listed in place of a `defsrc` name.
+#[derive(Debug, Clone)]
+struct DeviceLayer {
+    layer_idx: usize,
+    devices: Vec<String>,
+    mapping_order: Vec<usize>,
+    items: Vec<SExpr>,
+}
+
+/// Parse the `deflayer-for` expressions. Each one gives the items of a layer for a `defsrc` that
+/// is bound to input devices, or for the default `defsrc` on the devices of a `(devices ...)` list.
+fn parse_device_layers(
+    exprs: &[&Vec<SExpr>],
+    sources: &HashMap<String, DeviceSource>,
+    default_mapping_order: &[usize],
+    layer_idxs: &LayerIndexes,
+) -> Result<Vec<DeviceLayer>> {
+    const ERR_MSG: &str =
+        "deflayer-for expects: <defsrc name or (devices <device names>)> <layer name> <items>";
+    let mut device_layers = vec![];
+    let mut seen = HashSet::default();
+    for expr in exprs {
+        let mut subexprs = check_first_expr(expr.iter(), "deflayer-for")?;
+        let src_expr = subexprs
+            .next()
+            .ok_or_else(|| anyhow_expr!(&expr[0], "{ERR_MSG}"))?;
+        let (src_name, devices, mapping_order) = match src_expr {
+            SExpr::Atom(src_name) => {
+                let src_name = src_name.t.as_str();
+                let source = sources.get(src_name).ok_or_else(|| {
+                    anyhow_expr!(
+                        src_expr,
+                        "no defsrc bound to devices has the name {src_name}"
+                    )
+                })?;
+                (
+                    src_name.to_owned(),
+                    source.devices.clone(),
+                    source.mapping_order.as_slice(),
+                )
+            }
+            SExpr::List(_) => {
+                let devices = parse_device_names(src_expr, ERR_MSG)?;
+                (
+                    format!("(devices {})", devices.join(" ")),
+                    devices,
+                    default_mapping_order,
+                )
+            }
+        };
+        let layer_expr = subexprs
+            .next()
+            .ok_or_else(|| anyhow_expr!(src_expr, "{ERR_MSG}"))?;
+        let layer_name = layer_expr.atom(None).ok_or_else(|| {
+            anyhow_expr!(
+                layer_expr,
+                "{ERR_MSG}
+The layer name must be a string"
+            )
+        })?;
+        let layer_idx = *layer_idxs.get(layer_name).ok_or_else(|| {
+            anyhow_expr!(
+                layer_expr,
+                "layer {layer_name} must be defined with deflayer before deflayer-for"
+            )
+        })?;
+        if !seen.insert((src_name.clone(), layer_name)) {
+            bail_expr!(
+                layer_expr,
+                "layer {layer_name} is already defined for {src_name}"
+            );
+        }
+        let items = subexprs.cloned().collect::<Vec<_>>();
+        if items.len() != mapping_order.len() {
+            bail_expr!(
+                layer_expr,
+                "Layer {layer_name} for {src_name} has {} item(s), but requires {} to match its defsrc",
+                items.len(),
+                mapping_order.len()
+            );
+        }
+        device_layers.push(DeviceLayer {
+            layer_idx,
+            devices,
+            mapping_order: mapping_order.to_vec(),
+            items,
+        });
+    }
+    Ok(device_layers)
+}
+
+/// The defcfg options that `deflayer-cfg` can override, and whether each is a boolean.
+const LAYER_CFG_KEYS: &[(&str, bool)] = &[
+    ("type-delay", false),
+    ("key-repeat", true),
+    ("movemouse-speed-percent", false),
+    ("mwheel-speed-percent", false),
+    ("windows-sendinput-scancode", true),
+];
+
+/// Parse the `deflayer-cfg` expressions. Each one gives the defcfg options to override while a
+/// layer is active. The returned overrides are keyed by layer index.
+fn parse_layer_cfgs(
+    exprs: &[&Vec<SExpr>],
+    layer_idxs: &LayerIndexes,
+) -> Result<HashMap<usize, HashMap<String, String>>> {
+    const ERR_MSG: &str = "deflayer-cfg expects: <layer name> followed by defcfg option pairs";
+    let mut layer_cfgs: HashMap<usize, HashMap<String, String>> = HashMap::default();
+    for expr in exprs {
+        let mut subexprs = check_first_expr(expr.iter(), "deflayer-cfg")?;
+        let layer_expr = subexprs
+            .next()
+            .ok_or_else(|| anyhow_expr!(&expr[0], "{ERR_MSG}"))?;
+        let layer_name = layer_expr.atom(None).ok_or_else(|| {
+            anyhow_expr!(
+                layer_expr,
+                "{ERR_MSG}
+The layer name must be a string"
+            )
+        })?;
+        let layer_idx = *layer_idxs.get(layer_name).ok_or_else(|| {
+            anyhow_expr!(
+                layer_expr,
+                "layer {layer_name} must be defined with deflayer before deflayer-cfg"
+            )
+        })?;
+        if layer_cfgs.contains_key(&layer_idx) {
+            bail_expr!(
+                layer_expr,
+                "deflayer-cfg for layer {layer_name} is duplicated"
+            );
+        }
+        let mut overrides = HashMap::default();
+        while let Some(key_expr) = subexprs.next() {
+            let key = key_expr
+                .atom(None)
+                .ok_or_else(|| anyhow_expr!(key_expr, "Lists are not allowed in deflayer-cfg"))?;
+            let Some(&(_, is_bool)) = LAYER_CFG_KEYS.iter().find(|(k, _)| *k == key) else {
+                bail_expr!(
+                    key_expr,
+                    "{key} cannot be overridden per layer. Options that can be: {}",
+                    LAYER_CFG_KEYS
+                        .iter()
+                        .map(|(k, _)| *k)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            };
+            let val_expr = subexprs.next().ok_or_else(|| {
+                anyhow_expr!(key_expr, "Found a deflayer-cfg option missing a value")
+            })?;
+            let val = val_expr
+                .atom(None)
+                .ok_or_else(|| anyhow_expr!(val_expr, "Lists are not allowed in deflayer-cfg"))?;
+            if is_bool {
+                if !BOOLEAN_VALUES.contains(&val) {
+                    bail_expr!(
+                        val_expr,
+                        "The value for {key} must be one of: {}",
+                        BOOLEAN_VALUES.join(", ")
+                    );
+                }
+            } else if val.parse::<u16>().is_err() {
+                bail_expr!(val_expr, "The value for {key} must be 0-65535");
+            }
+            if overrides.insert(key.to_owned(), val.to_owned()).is_some() {
+                bail_expr!(key_expr, "Duplicate deflayer-cfg option {key}");
+            }
+        }
+        layer_cfgs.insert(layer_idx, overrides);
+    }
+    Ok(layer_cfgs)
+}
+
 type LayerIndexes = HashMap<String, usize>;
 type Aliases = HashMap<String, &'static KanataAction>;
 
@@ -939,7 +1648,12 @@ pub struct ParsedState {
     aliases: Aliases,
     layer_idxs: LayerIndexes,
     mapping_order: Vec<usize>,
+    /// Layers defined with `deflayer-for` for input devices that have their own `defsrc`.
+    device_layers: Vec<DeviceLayer>,
     fake_keys: HashMap<String, (usize, &'static KanataAction)>,
+    /// Fake keys without a name, created by actions such as `press-release`. These use the fake
+    /// key indexes that follow the ones used by `fake_keys` at the time they are created.
+    unnamed_fake_keys: RefCell<Vec<(usize, &'static KanataAction)>>,
     chord_groups: HashMap<String, ChordGroup>,
     defsrc_layer: [KanataAction; KEYS_IN_ROW],
     is_cmd_enabled: bool,
@@ -947,6 +1661,32 @@ pub struct ParsedState {
     default_sequence_timeout: u16,
     default_sequence_input_mode: SequenceInputMode,
     vars: HashMap<String, SExpr>,
+    /// Named key sets defined with `defkeys`.
+    key_sets: HashMap<String, Vec<String>>,
+    /// The hand that types each key, defined with `defhands`.
+    hands: Option<&'static HashMap<u16, Hand>>,
+    /// Simultaneity thresholds of pairs of keys, defined with `defoverlap`.
+    pair_thresholds: &'static [(KCoord, KCoord, u16)],
+    /// Device names used by `input-device` switch conditions, in order of first use.
+    input_devices: RefCell<Vec<String>>,
+    /// Keys that `output-device` actions send through a specific output device, in order of
+    /// first use.
+    output_device_keys: RefCell<Vec<(OutputDevice, OsCode)>>,
+    /// Conditions used by `state-file` and `env-var` switch conditions, in order of first use.
+    external_states: RefCell<Vec<ExternalStateCondition>>,
+    /// Layers that block wrong-finger keys, defined with `deffingers` and `deftrainer`.
+    trainer: Trainer,
+    /// The fake keys of the `on-startup` and `on-shutdown` actions in `defcfg`.
+    lifecycle_keys: LifecycleKeys,
+    /// The `layout` of the fingerprint of the configuration.
+    layout_fingerprint: u64,
+    /// The aliases that the parts of the configuration refer to.
+    alias_refs: AliasRefs,
+    /// The `layer_context` of the configuration.
+    layer_context: u64,
+    /// Before the layers are parsed, the layers of the previous configuration that can be reused.
+    /// Afterwards, the layers of this configuration.
+    layer_cache: LayerCache,
     a: Arc<Allocations>,
 }
 
@@ -954,6 +1694,35 @@ impl ParsedState {
     fn vars(&self) -> Option<&HashMap<String, SExpr>> {
         Some(&self.vars)
     }
+
+    /// Returns the keys of the `defkeys` key set that the expression names, if any.
+    fn key_set(&self, expr: &SExpr) -> Option<&[String]> {
+        expr.atom(self.vars())
+            .and_then(|name| self.key_sets.get(name))
+            .map(Vec::as_slice)
+    }
+
+    /// Returns the lengths of the state that parsing an action can add to.
+    fn added_state_lens(&self) -> [usize; 4] {
+        [
+            self.unnamed_fake_keys.borrow().len(),
+            self.input_devices.borrow().len(),
+            self.output_device_keys.borrow().len(),
+            self.external_states.borrow().len(),
+        ]
+    }
+
+    fn fake_key_count(&self) -> usize {
+        self.fake_keys.len() + self.unnamed_fake_keys.borrow().len()
+    }
+
+    /// Add a fake key that can only be referred to by its coordinate.
+    fn add_unnamed_fake_key(&self, action: &'static KanataAction) -> Coord {
+        let idx = self.fake_key_count();
+        self.unnamed_fake_keys.borrow_mut().push((idx, action));
+        let (x, y) = get_fake_key_coords(idx);
+        Coord { x, y }
+    }
 }
 
 const SEQUENCE_TIMEOUT_CFG_NAME: &str = "sequence-timeout";
@@ -969,12 +1738,26 @@ impl Default for ParsedState {
             aliases: Default::default(),
             layer_idxs: Default::default(),
             mapping_order: Default::default(),
+            device_layers: Default::default(),
             defsrc_layer: [KanataAction::Trans; KEYS_IN_ROW],
             fake_keys: Default::default(),
+            unnamed_fake_keys: Default::default(),
             chord_groups: Default::default(),
             is_cmd_enabled: false,
             delegate_to_first_layer: false,
             vars: Default::default(),
+            key_sets: Default::default(),
+            hands: None,
+            pair_thresholds: &[],
+            input_devices: Default::default(),
+            output_device_keys: Default::default(),
+            external_states: Default::default(),
+            trainer: Default::default(),
+            lifecycle_keys: Default::default(),
+            layout_fingerprint: 0,
+            alias_refs: Default::default(),
+            layer_context: 0,
+            layer_cache: Default::default(),
             default_sequence_timeout: SEQUENCE_TIMEOUT_DEFAULT,
             default_sequence_input_mode: SEQUENCE_INPUT_MODE_DEFAULT,
             a: unsafe { Allocations::new() },
@@ -1016,37 +1799,170 @@ fn parse_vars(exprs: &[&Vec<SExpr>], s: &mut ParsedState) -> Result<()> {
     Ok(())
 }
 
-/// Parse alias->action mappings from multiple exprs starting with defalias.
-/// Mutates the input `s` by storing aliases inside.
-fn parse_aliases(exprs: &[&Vec<SExpr>], s: &mut ParsedState) -> Result<()> {
+/// Parse the hand of each key from multiple exprs starting with defhands. A key list may contain
+/// the names of key sets.
+fn parse_hands(exprs: &[&Vec<SExpr>], s: &mut ParsedState) -> Result<()> {
+    let mut hands = HashMap::default();
     for expr in exprs {
-        handle_standard_defalias(expr, s)?;
-        handle_envcond_defalias(expr, s)?;
+        let mut subexprs = check_first_expr(expr.iter(), "defhands")?;
+        while let Some(hand_expr) = subexprs.next() {
+            let hand = match hand_expr.atom(s.vars()) {
+                Some("left") => Hand::Left,
+                Some("right") => Hand::Right,
+                _ => bail_expr!(hand_expr, "expected a hand: left or right"),
+            };
+            let keys_expr = match subexprs.next() {
+                Some(v) => v,
+                None => bail_expr!(
+                    hand_expr,
+                    "hand has no keys - you should add a list of keys."
+                ),
+            };
+            let keys = keys_expr
+                .list(s.vars())
+                .ok_or_else(|| anyhow_expr!(keys_expr, "hand keys must be a list of keys"))?;
+            for key in keys {
+                let names = match s.key_set(key) {
+                    Some(set) => set.to_vec(),
+                    None => match key.atom(s.vars()) {
+                        Some(k) => vec![k.to_owned()],
+                        None => {
+                            bail_expr!(key, "string of a known key is expected, found list instead")
+                        }
+                    },
+                };
+                for name in names {
+                    let osc = str_to_oscode(&name)
+                        .ok_or_else(|| anyhow_expr!(key, "string of a known key is expected"))?;
+                    if hands.insert(u16::from(osc), hand).is_some() {
+                        bail_expr!(key, "key is assigned a hand more than once: {name}");
+                    }
+                }
+            }
+        }
+    }
+    if !exprs.is_empty() {
+        s.hands = Some(s.a.sref(hands));
     }
     Ok(())
 }
 
-fn handle_standard_defalias(expr: &[SExpr], s: &mut ParsedState) -> Result<()> {
-    let subexprs = match check_first_expr(expr.iter(), "defalias") {
-        Ok(s) => s,
-        Err(_) => return Ok(()),
-    };
-    read_alias_name_action_pairs(subexprs, s)
+fn parse_pair_thresholds(exprs: &[&Vec<SExpr>], s: &mut ParsedState) -> Result<()> {
+    const ERR_MSG: &str = "defoverlap expects pairs of parameters: <key pair> <threshold>";
+    let mut thresholds: Vec<(KCoord, KCoord, u16)> = vec![];
+    for expr in exprs {
+        let mut subexprs = check_first_expr(expr.iter(), "defoverlap")?;
+        while let Some(pair_expr) = subexprs.next() {
+            let pair = match pair_expr.list(s.vars()) {
+                Some(pair) if pair.len() == 2 => pair,
+                _ => bail_expr!(pair_expr, "{ERR_MSG}\nkey pair must be a list of two keys"),
+            };
+            let mut coords = [(NORMAL_KEY_ROW, 0); 2];
+            for (coord, key) in coords.iter_mut().zip(pair.iter()) {
+                let osc = key
+                    .atom(s.vars())
+                    .and_then(str_to_oscode)
+                    .ok_or_else(|| anyhow_expr!(key, "string of a known key is expected"))?;
+                coord.1 = u16::from(osc);
+            }
+            let [a, b] = coords;
+            if a == b {
+                bail_expr!(pair_expr, "key pair must be two different keys");
+            }
+            let threshold = match subexprs.next() {
+                Some(v) => parse_u16(v, s, "overlap threshold")?,
+                None => bail_expr!(pair_expr, "{ERR_MSG}\nkey pair has no threshold"),
+            };
+            if thresholds
+                .iter()
+                .any(|&(x, y, _)| (x, y) == (a, b) || (x, y) == (b, a))
+            {
+                bail_expr!(pair_expr, "key pair has more than one threshold");
+            }
+            thresholds.push((a, b, threshold));
+        }
+    }
+    s.pair_thresholds = s.a.sref_vec(thresholds);
+    Ok(())
 }
 
-fn handle_envcond_defalias(expr: &[SExpr], s: &mut ParsedState) -> Result<()> {
-    let mut subexprs = match check_first_expr(expr.iter(), "defaliasenvcond") {
-        Ok(exprs) => exprs,
-        Err(_) => return Ok(()),
-    };
-
-    let conderr = "defaliasenvcond must have a list with 2 strings as the first parameter:\n\
-            (<env var name> <env var value>)";
-
-    // Check that there is a list containing the environment variable name and value that
-    // determines if this defalias entry should be used. If there is no match, return early.
-    match subexprs.next() {
-        Some(expr) => {
+/// Parse name->key list mappings from multiple exprs starting with defkeys. A key list may contain
+/// the names of key sets that were defined before it.
+fn parse_key_sets(exprs: &[&Vec<SExpr>], s: &mut ParsedState) -> Result<()> {
+    for expr in exprs {
+        let mut subexprs = check_first_expr(expr.iter(), "defkeys")?;
+        while let Some(name_expr) = subexprs.next() {
+            let name = match name_expr.atom(s.vars()) {
+                Some(name) => name.to_owned(),
+                None => bail_expr!(name_expr, "key set name must not be a list"),
+            };
+            if str_to_oscode(&name).is_some() {
+                bail_expr!(name_expr, "key set name must not be a key name: {name}");
+            }
+            let keys_expr = match subexprs.next() {
+                Some(v) => v,
+                None => bail_expr!(
+                    name_expr,
+                    "key set name has no keys - you should add a list of keys."
+                ),
+            };
+            let keys = keys_expr
+                .list(s.vars())
+                .ok_or_else(|| anyhow_expr!(keys_expr, "key set must be a list of keys"))?
+                .iter()
+                .try_fold(vec![], |mut keys: Vec<String>, key| -> Result<_> {
+                    if let Some(set) = s.key_set(key) {
+                        keys.extend(set.iter().cloned());
+                        return Ok(keys);
+                    }
+                    match key.atom(s.vars()) {
+                        Some(k) if str_to_oscode(k).is_some() => keys.push(k.to_owned()),
+                        Some(_) => bail_expr!(key, "string of a known key is expected"),
+                        None => {
+                            bail_expr!(key, "string of a known key is expected, found list instead")
+                        }
+                    }
+                    Ok(keys)
+                })?;
+            if s.key_sets.insert(name.clone(), keys).is_some() {
+                bail_expr!(name_expr, "duplicate key set name: {name}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse alias->action mappings from multiple exprs starting with defalias.
+/// Mutates the input `s` by storing aliases inside.
+fn parse_aliases(exprs: &[&Vec<SExpr>], s: &mut ParsedState) -> Result<()> {
+    for expr in exprs {
+        handle_standard_defalias(expr, s)?;
+        handle_envcond_defalias(expr, s)?;
+    }
+    Ok(())
+}
+
+fn handle_standard_defalias(expr: &[SExpr], s: &mut ParsedState) -> Result<()> {
+    let subexprs = match check_first_expr(expr.iter(), "defalias") {
+        Ok(s) => s,
+        Err(_) => return Ok(()),
+    };
+    read_alias_name_action_pairs(subexprs, s)
+}
+
+fn handle_envcond_defalias(expr: &[SExpr], s: &mut ParsedState) -> Result<()> {
+    let mut subexprs = match check_first_expr(expr.iter(), "defaliasenvcond") {
+        Ok(exprs) => exprs,
+        Err(_) => return Ok(()),
+    };
+
+    let conderr = "defaliasenvcond must have a list with 2 strings as the first parameter:\n\
+            (<env var name> <env var value>)";
+
+    // Check that there is a list containing the environment variable name and value that
+    // determines if this defalias entry should be used. If there is no match, return early.
+    match subexprs.next() {
+        Some(expr) => {
             let envcond = expr.list(s.vars()).ok_or_else(|| {
                 anyhow_expr!(expr, "Found a string, but expected a list.\n{conderr}")
             })?;
@@ -1147,6 +2063,52 @@ fn parse_action_atom(ac_span: &Spanned<String>, s: &ParsedState) -> Result<&'sta
                 s.a.sref(s.a.sref_slice(CustomAction::LiveReloadPrev)),
             )))
         }
+        "edit-config" => {
+            if !s.is_cmd_enabled {
+                bail_span!(
+                    ac_span,
+                    "edit-config runs an editor and requires danger-enable-cmd in defcfg"
+                );
+            }
+            return Ok(s.a.sref(Action::Custom(
+                s.a.sref(s.a.sref_slice(CustomAction::EditConfig)),
+            )));
+        }
+        "fn-lock-toggle" => {
+            return Ok(s.a.sref(Action::Custom(
+                s.a.sref(s.a.sref_slice(CustomAction::FnLockToggle)),
+            )))
+        }
+        "invert-scroll-toggle" => {
+            return Ok(s.a.sref(Action::Custom(
+                s.a.sref(s.a.sref_slice(CustomAction::InvertScrollToggle)),
+            )))
+        }
+        "resync-mods" => {
+            return Ok(s.a.sref(Action::Custom(
+                s.a.sref(s.a.sref_slice(CustomAction::ResyncMods)),
+            )))
+        }
+        "show-help" => {
+            return Ok(s.a.sref(Action::Custom(
+                s.a.sref(s.a.sref_slice(CustomAction::ShowHelp { type_text: false })),
+            )))
+        }
+        "cancel-macros" => {
+            return Ok(s.a.sref(Action::Custom(
+                s.a.sref(s.a.sref_slice(CustomAction::CancelMacros)),
+            )))
+        }
+        "show-action-usage" => {
+            return Ok(s.a.sref(Action::Custom(
+                s.a.sref(s.a.sref_slice(CustomAction::ShowActionUsage)),
+            )))
+        }
+        "show-help-type" => {
+            return Ok(s.a.sref(Action::Custom(
+                s.a.sref(s.a.sref_slice(CustomAction::ShowHelp { type_text: true })),
+            )))
+        }
         "sldr" => {
             return Ok(s.a.sref(Action::Custom(s.a.sref(s.a.sref_slice(
                 CustomAction::SequenceLeader(
@@ -1297,6 +2259,8 @@ fn parse_action_list(ac: &[SExpr], s: &ParsedState) -> Result<&'static KanataAct
     }
     match ac_type.as_str() {
         LAYER_SWITCH => parse_layer_base(&ac[1..], s),
+        LAYER_CYCLE => parse_layer_cycle(&ac[1..], s, false),
+        LAYER_CYCLE_REVERSE => parse_layer_cycle(&ac[1..], s, true),
         LAYER_TOGGLE | LAYER_WHILE_HELD => parse_layer_toggle(&ac[1..], s),
         TAP_HOLD => parse_tap_hold(&ac[1..], s, HoldTapConfig::Default),
         TAP_HOLD_PRESS => parse_tap_hold(&ac[1..], s, HoldTapConfig::HoldOnOtherKeyPress),
@@ -1308,11 +2272,22 @@ fn parse_action_list(ac: &[SExpr], s: &ParsedState) -> Result<&'static KanataAct
             parse_tap_hold_timeout(&ac[1..], s, HoldTapConfig::PermissiveHold)
         }
         TAP_HOLD_RELEASE_KEYS => parse_tap_hold_release_keys(&ac[1..], s),
+        TAP_HOLD_OPPOSITE_HAND => {
+            let hands = s.hands.ok_or_else(|| {
+                anyhow!("{TAP_HOLD_OPPOSITE_HAND} requires the hands of keys to be set in defhands")
+            })?;
+            parse_tap_hold(
+                &ac[1..],
+                s,
+                HoldTapConfig::ChordalHold(chordal_hold_hands(hands, &s.a)),
+            )
+        }
         MULTI => parse_multi(&ac[1..], s),
         MACRO => parse_macro(&ac[1..], s, RepeatMacro::No),
         MACRO_REPEAT => parse_macro(&ac[1..], s, RepeatMacro::Yes),
         MACRO_RELEASE_CANCEL => parse_macro_release_cancel(&ac[1..], s, RepeatMacro::No),
         MACRO_REPEAT_RELEASE_CANCEL => parse_macro_release_cancel(&ac[1..], s, RepeatMacro::Yes),
+        MACRO_GUARDED => parse_macro_guarded(&ac[1..], s),
         UNICODE => parse_unicode(&ac[1..], s),
         ONE_SHOT | ONE_SHOT_PRESS => parse_one_shot(&ac[1..], s, OneShotEndConfig::EndOnFirstPress),
         ONE_SHOT_RELEASE => parse_one_shot(&ac[1..], s, OneShotEndConfig::EndOnFirstRelease),
@@ -1331,6 +2306,7 @@ fn parse_action_list(ac: &[SExpr], s: &ParsedState) -> Result<&'static KanataAct
         ON_RELEASE_FAKEKEY => parse_on_release_fake_key_op(&ac[1..], s),
         ON_PRESS_FAKEKEY_DELAY => parse_fake_key_delay(&ac[1..], s),
         ON_RELEASE_FAKEKEY_DELAY => parse_on_release_fake_key_delay(&ac[1..], s),
+        PRESS_RELEASE => parse_press_release(&ac[1..], s),
         ON_IDLE_FAKEKEY => parse_on_idle_fakekey(&ac[1..], s),
         MWHEEL_UP => parse_mwheel(&ac[1..], MWheelDirection::Up, s),
         MWHEEL_DOWN => parse_mwheel(&ac[1..], MWheelDirection::Down, s),
@@ -1346,12 +2322,30 @@ fn parse_action_list(ac: &[SExpr], s: &ParsedState) -> Result<&'static KanataAct
         MOVEMOUSE_ACCEL_RIGHT => parse_move_mouse_accel(&ac[1..], MoveDirection::Right, s),
         MOVEMOUSE_SPEED => parse_move_mouse_speed(&ac[1..], s),
         SETMOUSE => parse_set_mouse(&ac[1..], s),
+        REPEAT_ACCEL => parse_repeat_accel(&ac[1..], s),
+        OUTPUT_DEVICE => parse_output_device(&ac[1..], s),
+        GAMEPAD_BUTTON => parse_gamepad_button(&ac[1..], s),
+        GAMEPAD_AXIS => parse_gamepad_axis(&ac[1..], s),
+        AXIS => parse_axis_ramp(&ac[1..], s),
+        MIDI_NOTE => parse_midi(&ac[1..], s, MidiKind::Note),
+        MIDI_CC => parse_midi(&ac[1..], s, MidiKind::ControlChange),
+        OSC => parse_osc(&ac[1..], s),
+        HTTP_POST => parse_http_post(&ac[1..], s),
+        NOTIFY => parse_notify(&ac[1..], s),
+        WM_FOCUS => parse_wm_direction(&ac[1..], s, WM_FOCUS),
+        WM_MOVE => parse_wm_direction(&ac[1..], s, WM_MOVE),
+        WM_WORKSPACE => parse_wm_workspace(&ac[1..], s),
+        CLIPBOARD_SET => parse_clipboard_set(&ac[1..], s),
+        CLIPBOARD_SAVE_SLOT => parse_clipboard_slot(&ac[1..], s, CLIPBOARD_SAVE_SLOT),
+        CLIPBOARD_RESTORE_SLOT => parse_clipboard_slot(&ac[1..], s, CLIPBOARD_RESTORE_SLOT),
         DYNAMIC_MACRO_RECORD => parse_dynamic_macro_record(&ac[1..], s),
         DYNAMIC_MACRO_PLAY => parse_dynamic_macro_play(&ac[1..], s),
         ARBITRARY_CODE => parse_arbitrary_code(&ac[1..], s),
         CMD => parse_cmd(&ac[1..], s, CmdType::Standard),
         CMD_OUTPUT_KEYS => parse_cmd(&ac[1..], s, CmdType::OutputKeys),
+        CMD_OUTPUT_TEXT => parse_cmd(&ac[1..], s, CmdType::OutputText),
         FORK => parse_fork(&ac[1..], s),
+        MODS_PASSTHROUGH => parse_mods_passthrough(&ac[1..], s),
         CAPS_WORD => parse_caps_word(&ac[1..], s),
         CAPS_WORD_CUSTOM => parse_caps_word_custom(&ac[1..], s),
         DYNAMIC_MACRO_RECORD_STOP_TRUNCATE => parse_macro_record_stop_truncate(&ac[1..], s),
@@ -1368,6 +2362,35 @@ fn parse_layer_base(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static Kan
     )))
 }
 
+fn parse_layer_cycle(
+    ac_params: &[SExpr],
+    s: &ParsedState,
+    is_reverse: bool,
+) -> Result<&'static KanataAction> {
+    const ERR_MSG: &str = "layer-cycle expects one item: a list of two or more layer names";
+    if ac_params.len() != 1 {
+        bail!("{ERR_MSG}, found {} items", ac_params.len());
+    }
+    let layer_names = ac_params[0]
+        .list(s.vars())
+        .ok_or_else(|| anyhow_expr!(&ac_params[0], "{ERR_MSG}"))?;
+    if layer_names.len() < 2 {
+        bail_expr!(&ac_params[0], "{ERR_MSG}");
+    }
+    let mut layers = layer_names
+        .iter()
+        .map(|name| layer_idx(std::slice::from_ref(name), &s.layer_idxs).map(|idx| idx * 2))
+        .collect::<Result<Vec<_>>>()?;
+    if is_reverse {
+        // Going forward through the reversed list is the same as going backward through the
+        // original list.
+        layers.reverse();
+    }
+    Ok(s.a.sref(Action::Custom(
+        s.a.sref(s.a.sref_slice(CustomAction::LayerCycle(layers))),
+    )))
+}
+
 fn parse_layer_toggle(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
     Ok(s.a.sref(Action::Layer(layer_idx(ac_params, &s.layer_idxs)? * 2 + 1)))
 }
@@ -1516,9 +2539,16 @@ fn parse_non_zero_u16(expr: &SExpr, s: &ParsedState, label: &str) -> Result<u16>
 }
 
 fn parse_key_list(expr: &SExpr, s: &ParsedState, label: &str) -> Result<Vec<OsCode>> {
+    if let Some(set) = s.key_set(expr) {
+        return Ok(key_set_oscodes(set).collect());
+    }
     expr.list(s.vars())
         .map(|keys| {
             keys.iter().try_fold(vec![], |mut keys, key| {
+                if let Some(set) = s.key_set(key) {
+                    keys.extend(key_set_oscodes(set));
+                    return Ok(keys);
+                }
                 key.atom(s.vars())
                     .map(|a| -> Result<()> {
                         keys.push(str_to_oscode(a).ok_or_else(|| {
@@ -1535,6 +2565,11 @@ fn parse_key_list(expr: &SExpr, s: &ParsedState, label: &str) -> Result<Vec<OsCo
         .ok_or_else(|| anyhow_expr!(expr, "{label} must be a list of keys"))?
 }
 
+fn key_set_oscodes(set: &[String]) -> impl Iterator<Item = OsCode> + '_ {
+    set.iter()
+        .map(|k| str_to_oscode(k).expect("key set keys are validated by defkeys"))
+}
+
 fn parse_multi(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
     if ac_params.is_empty() {
         bail!("multi expects at least one item after it")
@@ -1636,6 +2671,45 @@ fn parse_macro_release_cancel(
     ])))))
 }
 
+fn parse_macro_guarded(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
+    const ERR_MSG: &str = "macro-guarded expects a guard key followed by macro items";
+    if ac_params.len() < 2 {
+        bail!(ERR_MSG);
+    }
+    let guard = ac_params[0]
+        .atom(s.vars())
+        .and_then(str_to_oscode)
+        .ok_or_else(|| anyhow_expr!(&ac_params[0], "{ERR_MSG}: expected a key name"))?;
+    // Only keys in a defsrc are seen as held, so any other guard would stop the macro at once.
+    let guard_idx = usize::from(guard);
+    if !s.mapping_order.contains(&guard_idx)
+        && !s
+            .device_layers
+            .iter()
+            .any(|dl| dl.mapping_order.contains(&guard_idx))
+    {
+        bail_expr!(
+            &ac_params[0],
+            "The guard key of macro-guarded must be in defsrc"
+        );
+    }
+    let Action::Sequence { events } = parse_macro(&ac_params[1..], s, RepeatMacro::No)? else {
+        unreachable!("parse_macro without repeat returns a sequence");
+    };
+    let guard_event =
+        SequenceEvent::Custom(s.a.sref(s.a.sref(s.a.sref_slice(CustomAction::MacroGuard(guard)))));
+    let mut guarded_events = Vec::with_capacity(events.len() * 2);
+    for event in events.iter() {
+        if matches!(event, SequenceEvent::Press(_) | SequenceEvent::Tap(_)) {
+            guarded_events.push(guard_event);
+        }
+        guarded_events.push(*event);
+    }
+    Ok(s.a.sref(Action::Sequence {
+        events: s.a.sref(s.a.sref(s.a.sref_vec(guarded_events))),
+    }))
+}
+
 #[derive(PartialEq)]
 enum MacroNumberParseMode {
     Delay,
@@ -1677,6 +2751,16 @@ fn parse_macro_item_impl<'a>(
             }
         }
     }
+    if let Some(list) = acs[0].list(s.vars()) {
+        if list.first().and_then(|a| a.atom(s.vars())) == Some("type-delay") {
+            const ERR_MSG: &str = "type-delay expects one parameter: <delay (ms)>";
+            if list.len() != 2 {
+                bail_expr!(&acs[0], "{ERR_MSG}");
+            }
+            let duration = parse_u16(&list[1], s, "type-delay")?;
+            return Ok((vec![SequenceEvent::TypeDelay { duration }], &acs[1..]));
+        }
+    }
     match parse_action(&acs[0], s) {
         Ok(Action::KeyCode(kc)) => {
             // Should note that I tried `SequenceEvent::Tap` initially but it seems to be buggy
@@ -1846,8 +2930,12 @@ fn parse_unicode(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static Kanata
 enum CmdType {
     Standard,
     OutputKeys,
+    OutputText,
 }
 
+const CMD_ON_SUCCESS: &str = "on-success";
+const CMD_ON_FAILURE: &str = "on-failure";
+
 fn parse_cmd(
     ac_params: &[SExpr],
     s: &ParsedState,
@@ -1857,6 +2945,61 @@ fn parse_cmd(
     if !s.is_cmd_enabled {
         bail!("cmd is not enabled but cmd action is specified somewhere");
     }
+    let mut on_success = None;
+    let mut on_failure = None;
+    let mut ac_params = ac_params;
+    if matches!(cmd_type, CmdType::Standard) {
+        while let Some(callback) = ac_params.first().and_then(|p| p.list(s.vars())) {
+            let (callback_kind, slot) = match callback.first().and_then(|c| c.atom(s.vars())) {
+                Some(CMD_ON_SUCCESS) => (CMD_ON_SUCCESS, &mut on_success),
+                Some(CMD_ON_FAILURE) => (CMD_ON_FAILURE, &mut on_failure),
+                _ => bail_expr!(
+                    &ac_params[0],
+                    "{ERR_STR}, optionally preceded by lists beginning with {CMD_ON_SUCCESS} or {CMD_ON_FAILURE}"
+                ),
+            };
+            if slot.is_some() {
+                bail_expr!(&ac_params[0], "{callback_kind} is specified more than once");
+            }
+            let (coord, action) = parse_fake_key_op_coord_action(&callback[1..], s)
+                .map_err(|e| anyhow_expr!(&ac_params[0], "{callback_kind}: {}", e.msg))?;
+            *slot = Some(FakeKeyCallback { coord, action });
+            ac_params = &ac_params[1..];
+        }
+    }
+    let mut typing = TextTypingCfg::default();
+    if matches!(cmd_type, CmdType::OutputText) {
+        while let Some(option) = ac_params.first().and_then(|p| p.list(s.vars())) {
+            const OPTIONS_ERR: &str = "cmd-output-text options must be lists of two items: \
+                (newline enter|shift-enter|space|ignore), (ascii keys|unicode), (interval <ms>) \
+                or (chunk <characters>)";
+            if option.len() != 2 {
+                bail_expr!(&ac_params[0], "{OPTIONS_ERR}");
+            }
+            match option[0].atom(s.vars()) {
+                Some("newline") => {
+                    typing.newline = match option[1].atom(s.vars()) {
+                        Some("enter") => NewlinePolicy::Enter,
+                        Some("shift-enter") => NewlinePolicy::ShiftEnter,
+                        Some("space") => NewlinePolicy::Space,
+                        Some("ignore") => NewlinePolicy::Ignore,
+                        _ => bail_expr!(&option[1], "{OPTIONS_ERR}"),
+                    }
+                }
+                Some("ascii") => {
+                    typing.ascii_as_keys = match option[1].atom(s.vars()) {
+                        Some("keys") => true,
+                        Some("unicode") => false,
+                        _ => bail_expr!(&option[1], "{OPTIONS_ERR}"),
+                    }
+                }
+                Some("interval") => typing.interval = parse_u16(&option[1], s, "interval")?,
+                Some("chunk") => typing.chunk = parse_non_zero_u16(&option[1], s, "chunk")?,
+                _ => bail_expr!(&ac_params[0], "{OPTIONS_ERR}"),
+            }
+            ac_params = &ac_params[1..];
+        }
+    }
     if ac_params.is_empty() {
         bail!(ERR_STR);
     }
@@ -1870,8 +3013,16 @@ fn parse_cmd(
         })?;
     Ok(s.a
         .sref(Action::Custom(s.a.sref(s.a.sref_slice(match cmd_type {
+            CmdType::Standard if on_success.is_some() || on_failure.is_some() => {
+                CustomAction::CmdOnResult {
+                    cmd,
+                    on_success,
+                    on_failure,
+                }
+            }
             CmdType::Standard => CustomAction::Cmd(cmd),
             CmdType::OutputKeys => CustomAction::CmdOutputKeys(cmd),
+            CmdType::OutputText => CustomAction::CmdOutputText { cmd, typing },
         })))))
 }
 
@@ -1965,6 +3116,8 @@ fn parse_chord(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAc
         timeout: group.timeout,
         coords: s.a.sref_vec(vec![((0, group.id), chord_keys)]),
         chords: s.a.sref_vec(vec![]),
+        chord_timeouts: &[],
+        release_on_first: &[],
     }))))
 }
 
@@ -2037,22 +3190,32 @@ fn parse_chord_groups(exprs: &[&Spanned<Vec<SExpr>>], s: &mut ParsedState) -> Re
                     "Key list found without action - add an action for this chord"
                 ),
             };
-            let mut keys = keys_expr
-                .list(s.vars())
-                .map(|keys| {
-                    keys.iter().map(|key| {
-                        key.atom(s.vars()).ok_or_else(|| {
-                            anyhow_expr!(
-                                key,
-                                "Chord keys cannot be lists. Invalid key name: {:?}",
-                                key
-                            )
-                        })
-                    })
-                })
-                .ok_or_else(|| anyhow_expr!(keys_expr, "Chord must be a list/set of keys"))?;
-            let mask = keys.try_fold(0, |mask, key| {
-                let key = key?;
+            let keys = match s.key_set(keys_expr) {
+                Some(set) => set.to_vec(),
+                None => keys_expr
+                    .list(s.vars())
+                    .ok_or_else(|| anyhow_expr!(keys_expr, "Chord must be a list/set of keys"))?
+                    .iter()
+                    .try_fold(vec![], |mut keys, key| -> Result<_> {
+                        if let Some(set) = s.key_set(key) {
+                            keys.extend(set.iter().cloned());
+                            return Ok(keys);
+                        }
+                        keys.push(
+                            key.atom(s.vars())
+                                .ok_or_else(|| {
+                                    anyhow_expr!(
+                                        key,
+                                        "Chord keys cannot be lists. Invalid key name: {:?}",
+                                        key
+                                    )
+                                })?
+                                .to_owned(),
+                        );
+                        Ok(keys)
+                    })?,
+            };
+            let mask = keys.iter().try_fold(0, |mask, key| {
                 let index = match group.keys.iter().position(|k| k == key) {
                     Some(i) => i,
                     None => {
@@ -2077,7 +3240,7 @@ fn parse_chord_groups(exprs: &[&Spanned<Vec<SExpr>>], s: &mut ParsedState) -> Re
     Ok(())
 }
 
-fn resolve_chord_groups(layers: &mut KanataLayers, s: &ParsedState) -> Result<()> {
+fn resolve_chord_groups(layers: &mut ParsedLayers, s: &ParsedState) -> Result<()> {
     let mut chord_groups = s.chord_groups.values().cloned().collect::<Vec<_>>();
     chord_groups.sort_by_key(|group| group.id);
 
@@ -2106,6 +3269,8 @@ fn resolve_chord_groups(layers: &mut KanataLayers, s: &ParsedState) -> Result<()
             coords: s.a.sref_vec(group.coords),
             chords: s.a.sref_vec(chords),
             timeout: group.timeout,
+            chord_timeouts: &[],
+            release_on_first: &[],
         }))
     }).collect::<Result<Vec<_>>>()?;
 
@@ -2300,7 +3465,7 @@ fn parse_fake_keys(exprs: &[&Vec<SExpr>], s: &mut ParsedState) -> Result<()> {
                 ),
             };
             let action = parse_action(action, s)?;
-            let idx = s.fake_keys.len();
+            let idx = s.fake_key_count();
             log::trace!("inserting {key_name}->{idx}:{action:?}");
             if s.fake_keys
                 .insert(key_name.clone(), (idx, action))
@@ -2310,10 +3475,10 @@ fn parse_fake_keys(exprs: &[&Vec<SExpr>], s: &mut ParsedState) -> Result<()> {
             }
         }
     }
-    if s.fake_keys.len() > KEYS_IN_ROW {
+    if s.fake_key_count() > KEYS_IN_ROW {
         bail!(
             "Maximum number of fake keys is {KEYS_IN_ROW}, found {}",
-            s.fake_keys.len()
+            s.fake_key_count()
         );
     }
     Ok(())
@@ -2326,6 +3491,33 @@ fn parse_fake_key_op(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static Ka
     )))
 }
 
+fn parse_press_release(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
+    const ERR_MSG: &str = "press-release expects two actions: <press action> <release action>";
+    if ac_params.len() != 2 {
+        bail!("{ERR_MSG}, found {} items", ac_params.len());
+    }
+    let press_action = parse_action(&ac_params[0], s)?;
+    let release_action = parse_action(&ac_params[1], s)?;
+    let press_coord = s.add_unnamed_fake_key(press_action);
+    let release_coord = s.add_unnamed_fake_key(release_action);
+    // The press action is held for as long as the key is held, and the release action is tapped
+    // after the key is released.
+    Ok(s.a.sref(Action::Custom(s.a.sref(s.a.sref_vec(vec![
+        s.a.sref(CustomAction::FakeKey {
+            coord: press_coord,
+            action: FakeKeyAction::Press,
+        }),
+        s.a.sref(CustomAction::FakeKeyOnRelease {
+            coord: press_coord,
+            action: FakeKeyAction::Release,
+        }),
+        s.a.sref(CustomAction::FakeKeyOnRelease {
+            coord: release_coord,
+            action: FakeKeyAction::Tap,
+        }),
+    ])))))
+}
+
 fn parse_on_release_fake_key_op(
     ac_params: &[SExpr],
     s: &ParsedState,
@@ -2505,106 +3697,556 @@ fn parse_move_mouse_speed(ac_params: &[SExpr], s: &ParsedState) -> Result<&'stat
     )))
 }
 
-fn parse_set_mouse(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
+fn parse_repeat_accel(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
+    const ERR_MSG: &str = "repeat-accel expects four parameters: <action> <start interval (ms)> <end interval (ms)> <acceleration time (ms)>";
+    if ac_params.len() != 4 {
+        bail!("{ERR_MSG}\nfound {} parameters", ac_params.len());
+    }
+    // The action is tapped through a fake key, so that any action can be repeated.
+    let coord = s.add_unnamed_fake_key(parse_action(&ac_params[0], s)?);
+    let start_interval = parse_non_zero_u16(&ac_params[1], s, "start interval")?;
+    let end_interval = parse_non_zero_u16(&ac_params[2], s, "end interval")?;
+    if end_interval > start_interval {
+        bail_expr!(
+            &ac_params[2],
+            "end interval should not be greater than start interval"
+        )
+    }
+    let accel_time = parse_non_zero_u16(&ac_params[3], s, "acceleration time")?;
+    Ok(s.a.sref(Action::Custom(s.a.sref(s.a.sref_slice(
+        CustomAction::RepeatAccel {
+            coord,
+            start_interval,
+            end_interval,
+            accel_time,
+        },
+    )))))
+}
+
+fn parse_output_device(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
+    const ERR_MSG: &str = "output-device expects two parameters: <keyboard|mouse> <key>";
     if ac_params.len() != 2 {
-        bail!(
-            "movemouse-accel expects two parameters, found {}: <x> <y>",
-            ac_params.len()
-        );
+        bail!("{ERR_MSG}\nfound {} parameters", ac_params.len());
     }
-    let x = parse_u16(&ac_params[0], s, "x")?;
-    let y = parse_u16(&ac_params[1], s, "y")?;
-    Ok(s.a.sref(Action::Custom(
-        s.a.sref(s.a.sref_slice(CustomAction::SetMouse { x, y })),
-    )))
+    let device = match ac_params[0].atom(s.vars()) {
+        Some("keyboard") => OutputDevice::Keyboard,
+        Some("mouse") => OutputDevice::Mouse,
+        _ => bail_expr!(&ac_params[0], "{ERR_MSG}\nThis must be keyboard or mouse"),
+    };
+    let key = ac_params[1]
+        .atom(s.vars())
+        .and_then(str_to_oscode)
+        .ok_or_else(|| anyhow_expr!(&ac_params[1], "{ERR_MSG}\nThis must be a key name"))?;
+    let mut output_device_keys = s.output_device_keys.borrow_mut();
+    if !output_device_keys.contains(&(device, key)) {
+        output_device_keys.push((device, key));
+    }
+    Ok(s.a.sref(Action::Custom(s.a.sref(
+        s.a.sref_slice(CustomAction::OutputDeviceKey { device, key }),
+    ))))
 }
 
-fn parse_dynamic_macro_record(
-    ac_params: &[SExpr],
-    s: &ParsedState,
-) -> Result<&'static KanataAction> {
-    const ERR_MSG: &str = "dynamic-macro-record expects 1 parameter: <macro ID (0-65535)>";
+fn parse_gamepad_button(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
+    const ERR_MSG: &str = "gamepad-button expects one parameter: <button>";
     if ac_params.len() != 1 {
-        bail!("{ERR_MSG}, found {}", ac_params.len());
+        bail!("{ERR_MSG}\nfound {} parameters", ac_params.len());
     }
-    let key = parse_u16(&ac_params[0], s, "macro ID")?;
+    use GamepadButton::*;
+    let button = match ac_params[0].atom(s.vars()) {
+        Some("south") => South,
+        Some("east") => East,
+        Some("north") => North,
+        Some("west") => West,
+        Some("tl") => TL,
+        Some("tr") => TR,
+        Some("tl2") => TL2,
+        Some("tr2") => TR2,
+        Some("select") => Select,
+        Some("start") => Start,
+        Some("mode") => Mode,
+        Some("thumbl") => ThumbL,
+        Some("thumbr") => ThumbR,
+        Some("dpad-up") => DpadUp,
+        Some("dpad-down") => DpadDown,
+        Some("dpad-left") => DpadLeft,
+        Some("dpad-right") => DpadRight,
+        _ => bail_expr!(
+            &ac_params[0],
+            "{ERR_MSG}\nValid buttons: south east north west tl tr tl2 tr2 select start mode \
+             thumbl thumbr dpad-up dpad-down dpad-left dpad-right"
+        ),
+    };
     Ok(s.a.sref(Action::Custom(
-        s.a.sref(s.a.sref_slice(CustomAction::DynamicMacroRecord(key))),
+        s.a.sref(s.a.sref_slice(CustomAction::GamepadButton(button))),
     )))
 }
 
-fn parse_dynamic_macro_play(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
-    const ERR_MSG: &str = "dynamic-macro-play expects 1 parameter: <macro ID (number 0-65535)>";
-    if ac_params.len() != 1 {
-        bail!("{ERR_MSG}, found {}", ac_params.len());
+fn parse_gamepad_axis(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
+    const ERR_MSG: &str = "gamepad-axis expects two parameters: <axis> <value>";
+    if ac_params.len() != 2 {
+        bail!("{ERR_MSG}\nfound {} parameters", ac_params.len());
     }
-    let key = parse_u16(&ac_params[0], s, "macro ID")?;
+    let axis = parse_gamepad_axis_name(&ac_params[0], s, ERR_MSG)?;
+    let (min, max) = axis.range();
+    let value = ac_params[1]
+        .atom(s.vars())
+        .and_then(|v| v.parse::<i32>().ok())
+        .filter(|v| (min..=max).contains(v))
+        .ok_or_else(|| {
+            anyhow_expr!(
+                &ac_params[1],
+                "{ERR_MSG}\nThe value for this axis must be a number from {min} to {max}"
+            )
+        })?;
     Ok(s.a.sref(Action::Custom(
-        s.a.sref(s.a.sref_slice(CustomAction::DynamicMacroPlay(key))),
+        s.a.sref(s.a.sref_slice(CustomAction::GamepadAxis { axis, value })),
     )))
 }
 
-fn parse_layers(s: &mut ParsedState) -> Result<Box<KanataLayers>> {
-    // There are two copies/versions of each layer. One is used as the target of "layer-switch" and
-    // the other is the target of "layer-while-held".
-    let mut layers_cfg = new_layers();
-    for (layer_level, layer) in s.layer_exprs.iter().enumerate() {
-        // The skip is done to skip the the `deflayer` and layer name tokens.
-        for (i, ac) in layer.iter().skip(2).enumerate() {
-            // Parse actions in the layer and place them appropriately.
-            let ac = parse_action(ac, s)?;
-            layers_cfg[layer_level * 2][0][s.mapping_order[i]] = *ac;
-            layers_cfg[layer_level * 2 + 1][0][s.mapping_order[i]] = *ac;
-        }
-        for (i, (layer_action, defsrc_action)) in layers_cfg[layer_level * 2][0]
-            .iter_mut()
-            .zip(s.defsrc_layer)
-            .enumerate()
-        {
-            // Set transparent actions in the "layer-switch" version of the layer according to
-            // defsrc action.
-            if *layer_action == Action::Trans {
-                *layer_action = defsrc_action;
-            }
-            // If there is no corresponding action in defsrc, default to the OsCode at the
-            // position. This is done so that `process-unmapped-keys` works correctly.
-            if *layer_action == Action::Trans {
-                *layer_action = OsCode::from_u16(i as u16)
-                    .and_then(|osc| match KeyCode::from(osc) {
-                        KeyCode::No => None,
-                        kc => Some(Action::KeyCode(kc)),
-                    })
-                    .unwrap_or(Action::Trans);
-            }
-        }
-        // Set fake keys on the `layer-switch` version of each layer.
-        for (y, action) in s.fake_keys.values() {
-            let (x, y) = get_fake_key_coords(*y);
-            layers_cfg[layer_level * 2][x as usize][y as usize] = **action;
-        }
+fn parse_gamepad_axis_name(expr: &SExpr, s: &ParsedState, err_msg: &str) -> Result<GamepadAxis> {
+    use GamepadAxis::*;
+    Ok(match expr.atom(s.vars()) {
+        Some("left-x") => LeftX,
+        Some("left-y") => LeftY,
+        Some("right-x") => RightX,
+        Some("right-y") => RightY,
+        Some("left-trigger") => LeftTrigger,
+        Some("right-trigger") => RightTrigger,
+        Some("dpad-x") => DpadX,
+        Some("dpad-y") => DpadY,
+        _ => bail_expr!(
+            expr,
+            "{err_msg}\nValid axes: left-x left-y right-x right-y left-trigger right-trigger \
+             dpad-x dpad-y"
+        ),
+    })
+}
 
-        // If the user has configured delegation to the first (default) layer for transparent keys,
-        // (as opposed to delegation to defsrc), replace the defsrc actions with the actions from
-        // the first layer.
-        if layer_level == 0 && s.delegate_to_first_layer {
-            for (defsrc_ac, default_layer_ac) in s.defsrc_layer.iter_mut().zip(layers_cfg[0][0]) {
-                *defsrc_ac = default_layer_ac;
-            }
-        }
+fn parse_axis_ramp(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
+    const ERR_MSG: &str = "axis expects four or five parameters: <axis> <-|+> <ramp-up time (ms)> <ramp-down time (ms)> [curve]";
+    if !(4..=5).contains(&ac_params.len()) {
+        bail!("{ERR_MSG}\nfound {} parameters", ac_params.len());
     }
-    Ok(layers_cfg)
+    let axis = parse_gamepad_axis_name(&ac_params[0], s, ERR_MSG)?;
+    let direction = match ac_params[1].atom(s.vars()) {
+        Some("-") => AxisDirection::Negative,
+        Some("+") => AxisDirection::Positive,
+        _ => bail_expr!(&ac_params[1], "{ERR_MSG}\nThis must be - or +"),
+    };
+    if direction == AxisDirection::Negative && axis.range().0 == 0 {
+        bail_expr!(
+            &ac_params[1],
+            "{ERR_MSG}\nThe rest value of this axis is its minimum, so it can only move in the + direction"
+        );
+    }
+    let ramp_up = parse_u16(&ac_params[2], s, "ramp-up time")?;
+    let ramp_down = parse_u16(&ac_params[3], s, "ramp-down time")?;
+    let curve = match ac_params.get(4) {
+        None => AxisCurve::Linear,
+        Some(expr) => match expr.atom(s.vars()) {
+            Some("linear") => AxisCurve::Linear,
+            Some("ease-in") => AxisCurve::EaseIn,
+            Some("ease-out") => AxisCurve::EaseOut,
+            Some("ease-in-out") => AxisCurve::EaseInOut,
+            _ => bail_expr!(
+                expr,
+                "{ERR_MSG}\nValid curves: linear ease-in ease-out ease-in-out"
+            ),
+        },
+    };
+    Ok(s.a.sref(Action::Custom(s.a.sref(s.a.sref_slice(
+        CustomAction::GamepadAxisRamp {
+            axis,
+            direction,
+            ramp_up,
+            ramp_down,
+            curve,
+        },
+    )))))
 }
 
-const SEQ_ERR: &str = "defseq expects pairs of parameters: <fake_key_name> <key_list>";
+#[derive(PartialEq, Eq)]
+enum MidiKind {
+    Note,
+    ControlChange,
+}
 
-fn parse_sequences(exprs: &[&Vec<SExpr>], s: &ParsedState) -> Result<KeySeqsToFKeys> {
-    let mut sequences = Trie::new();
-    for expr in exprs {
-        let mut subexprs = check_first_expr(expr.iter(), "defseq")?.peekable();
+fn parse_midi(
+    ac_params: &[SExpr],
+    s: &ParsedState,
+    kind: MidiKind,
+) -> Result<&'static KanataAction> {
+    let err_msg = match kind {
+        MidiKind::Note => "midi-note expects two or three parameters: <note> <velocity> [channel]",
+        MidiKind::ControlChange => {
+            "midi-cc expects two or three parameters: <controller> <value> [channel]"
+        }
+    };
+    if !(2..=3).contains(&ac_params.len()) {
+        bail!("{err_msg}\nfound {} parameters", ac_params.len());
+    }
+    let channel = match ac_params.get(2) {
+        Some(expr) => parse_u8_with_range(expr, s, "channel", 1, 16)?,
+        None => 1,
+    };
+    let action = match kind {
+        MidiKind::Note => CustomAction::MidiNote {
+            channel,
+            note: parse_u8_with_range(&ac_params[0], s, "note", 0, 127)?,
+            velocity: parse_u8_with_range(&ac_params[1], s, "velocity", 1, 127)?,
+        },
+        MidiKind::ControlChange => CustomAction::MidiControlChange {
+            channel,
+            controller: parse_u8_with_range(&ac_params[0], s, "controller", 0, 127)?,
+            value: parse_u8_with_range(&ac_params[1], s, "value", 0, 127)?,
+        },
+    };
+    Ok(s.a.sref(Action::Custom(s.a.sref(s.a.sref_slice(action)))))
+}
 
-        while let Some(fake_key_expr) = subexprs.next() {
-            let fake_key = fake_key_expr.atom(s.vars()).ok_or_else(|| {
-                anyhow_expr!(fake_key_expr, "{SEQ_ERR}\nGot a list for fake_key_name")
+fn parse_osc(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
+    const ERR_MSG: &str = "osc expects an address string starting with / followed by arguments";
+    let address = match ac_params.first().and_then(|p| p.atom(s.vars())) {
+        Some(a) if a.trim_matches('"').starts_with('/') => a.trim_matches('"').to_owned(),
+        Some(_) => bail_expr!(&ac_params[0], "{ERR_MSG}"),
+        None => bail!("{ERR_MSG}"),
+    };
+    let args = ac_params[1..]
+        .iter()
+        .map(|p| {
+            let a = p.atom(s.vars()).ok_or_else(|| {
+                anyhow_expr!(p, "{ERR_MSG}\nArguments must be numbers or strings")
+            })?;
+            // Quoted arguments are always strings, e.g. "1" is sent as a string.
+            Ok(if a.starts_with('"') {
+                OscArg::String(a.trim_matches('"').to_owned())
+            } else if let Ok(i) = a.parse::<i32>() {
+                OscArg::Int(i)
+            } else if let Ok(f) = a.parse::<f32>() {
+                OscArg::Float(f.to_bits())
+            } else {
+                OscArg::String(a.to_owned())
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(s.a.sref(Action::Custom(
+        s.a.sref(s.a.sref_slice(CustomAction::Osc { address, args })),
+    )))
+}
+
+fn parse_http_post(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
+    const ERR_MSG: &str = "http-post expects one or two strings: <url> [body]";
+    if !(1..=2).contains(&ac_params.len()) {
+        bail!("{ERR_MSG}\nfound {} parameters", ac_params.len());
+    }
+    let mut strings = ac_params.iter().map(|p| {
+        p.atom(s.vars())
+            .map(|a| a.trim_matches('"').to_owned())
+            .ok_or_else(|| anyhow_expr!(p, "{ERR_MSG}\nLists are not allowed"))
+    });
+    let url = strings.next().expect("checked length")?;
+    if url.starts_with("https://") {
+        bail_expr!(
+            &ac_params[0],
+            "{ERR_MSG}\nhttps URLs are not supported, use an http URL or cmd with an HTTP client"
+        );
+    }
+    if !url.starts_with("http://") || url.len() == "http://".len() {
+        bail_expr!(&ac_params[0], "{ERR_MSG}\nThe URL must start with http://");
+    }
+    let body = strings.next().transpose()?.unwrap_or_default();
+    Ok(s.a.sref(Action::Custom(
+        s.a.sref(s.a.sref_slice(CustomAction::HttpPost { url, body })),
+    )))
+}
+
+fn parse_notify(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
+    const ERR_MSG: &str = "notify expects one or two strings: <title> [body]";
+    if !(1..=2).contains(&ac_params.len()) {
+        bail!("{ERR_MSG}\nfound {} parameters", ac_params.len());
+    }
+    let mut strings = ac_params.iter().map(|p| {
+        p.atom(s.vars())
+            .map(|a| a.trim_matches('"').to_owned())
+            .ok_or_else(|| anyhow_expr!(p, "{ERR_MSG}\nLists are not allowed"))
+    });
+    let title = strings.next().expect("checked length")?;
+    let body = strings.next().transpose()?.unwrap_or_default();
+    Ok(s.a.sref(Action::Custom(
+        s.a.sref(s.a.sref_slice(CustomAction::Notify { title, body })),
+    )))
+}
+
+fn parse_wm_direction(
+    ac_params: &[SExpr],
+    s: &ParsedState,
+    action_name: &str,
+) -> Result<&'static KanataAction> {
+    let err_msg = format!("{action_name} expects one direction: left, right, up or down");
+    if ac_params.len() != 1 {
+        bail!("{err_msg}\nfound {} parameters", ac_params.len());
+    }
+    let direction = match ac_params[0].atom(s.vars()) {
+        Some("left") => MoveDirection::Left,
+        Some("right") => MoveDirection::Right,
+        Some("up") => MoveDirection::Up,
+        Some("down") => MoveDirection::Down,
+        _ => bail_expr!(&ac_params[0], "{err_msg}"),
+    };
+    let cmd = match action_name {
+        WM_FOCUS => WmCommand::Focus(direction),
+        _ => WmCommand::Move(direction),
+    };
+    Ok(s.a.sref(Action::Custom(
+        s.a.sref(s.a.sref_slice(CustomAction::WindowManager(cmd))),
+    )))
+}
+
+fn parse_wm_workspace(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
+    const ERR_MSG: &str = "wm-workspace expects one workspace name or number, or next or prev";
+    if ac_params.len() != 1 {
+        bail!("{ERR_MSG}\nfound {} parameters", ac_params.len());
+    }
+    let workspace = ac_params[0]
+        .atom(s.vars())
+        .map(|a| a.trim_matches('"'))
+        .filter(|a| !a.is_empty())
+        .ok_or_else(|| anyhow_expr!(&ac_params[0], "{ERR_MSG}"))?;
+    Ok(s.a.sref(Action::Custom(s.a.sref(s.a.sref_slice(
+        CustomAction::WindowManager(WmCommand::Workspace(workspace.to_owned())),
+    )))))
+}
+
+fn parse_clipboard_set(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
+    const ERR_MSG: &str = "clipboard-set expects one string: <text>";
+    if ac_params.len() != 1 {
+        bail!("{ERR_MSG}\nfound {} parameters", ac_params.len());
+    }
+    let text = ac_params[0]
+        .atom(s.vars())
+        .map(|a| a.trim_matches('"').to_owned())
+        .ok_or_else(|| anyhow_expr!(&ac_params[0], "{ERR_MSG}\nLists are not allowed"))?;
+    Ok(s.a.sref(Action::Custom(
+        s.a.sref(s.a.sref_slice(CustomAction::ClipboardSet(text))),
+    )))
+}
+
+fn parse_clipboard_slot(
+    ac_params: &[SExpr],
+    s: &ParsedState,
+    action_name: &str,
+) -> Result<&'static KanataAction> {
+    if ac_params.len() != 1 {
+        bail!(
+            "{action_name} expects one parameter: <slot>\nfound {} parameters",
+            ac_params.len()
+        );
+    }
+    let slot = parse_u16(&ac_params[0], s, "slot")?;
+    let action = match action_name {
+        CLIPBOARD_SAVE_SLOT => CustomAction::ClipboardSaveSlot(slot),
+        _ => CustomAction::ClipboardRestoreSlot(slot),
+    };
+    Ok(s.a.sref(Action::Custom(s.a.sref(s.a.sref_slice(action)))))
+}
+
+fn parse_set_mouse(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
+    if ac_params.len() != 2 {
+        bail!(
+            "movemouse-accel expects two parameters, found {}: <x> <y>",
+            ac_params.len()
+        );
+    }
+    let x = parse_u16(&ac_params[0], s, "x")?;
+    let y = parse_u16(&ac_params[1], s, "y")?;
+    Ok(s.a.sref(Action::Custom(
+        s.a.sref(s.a.sref_slice(CustomAction::SetMouse { x, y })),
+    )))
+}
+
+fn parse_dynamic_macro_record(
+    ac_params: &[SExpr],
+    s: &ParsedState,
+) -> Result<&'static KanataAction> {
+    const ERR_MSG: &str = "dynamic-macro-record expects 1 parameter: <macro ID (0-65535)>";
+    if ac_params.len() != 1 {
+        bail!("{ERR_MSG}, found {}", ac_params.len());
+    }
+    let key = parse_u16(&ac_params[0], s, "macro ID")?;
+    Ok(s.a.sref(Action::Custom(
+        s.a.sref(s.a.sref_slice(CustomAction::DynamicMacroRecord(key))),
+    )))
+}
+
+fn parse_dynamic_macro_play(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
+    const ERR_MSG: &str = "dynamic-macro-play expects 1 parameter: <macro ID (number 0-65535)>";
+    if ac_params.len() != 1 {
+        bail!("{ERR_MSG}, found {}", ac_params.len());
+    }
+    let key = parse_u16(&ac_params[0], s, "macro ID")?;
+    Ok(s.a.sref(Action::Custom(
+        s.a.sref(s.a.sref_slice(CustomAction::DynamicMacroPlay(key))),
+    )))
+}
+
+fn parse_layers(s: &mut ParsedState) -> Result<ParsedLayers> {
+    // There are two copies/versions of each layer. One is used as the target of "layer-switch" and
+    // the other is the target of "layer-while-held".
+    let mut layers_cfg = new_layers(s.layer_exprs.len() * 2);
+    let previous_layers = std::mem::take(&mut s.layer_cache);
+    s.layer_cache = LayerCache::new(s.layer_context, s.a.clone());
+    let mut reused = 0;
+    for (layer_level, layer) in s.layer_exprs.iter().enumerate() {
+        let hash = layer_hash(layer);
+        let actions = match previous_layers.reuse(s.layer_context, hash, &s.a) {
+            Some(actions) => {
+                reused += 1;
+                s.layer_cache.insert(hash, actions.to_vec());
+                actions.to_vec()
+            }
+            None => {
+                let added_before = s.added_state_lens();
+                // The skip is done to skip the the `deflayer` and layer name tokens.
+                let actions = layer
+                    .iter()
+                    .skip(2)
+                    .enumerate()
+                    .map(|(i, ac)| {
+                        Ok(*resolve_defsrc_keys(
+                            parse_action(ac, s)?,
+                            s.mapping_order[i],
+                            s,
+                        ))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                // Reusing a layer would not add to the parsed state again.
+                if s.added_state_lens() == added_before && !actions.iter().any(contains_chords) {
+                    s.layer_cache.insert(hash, actions.clone());
+                }
+                actions
+            }
+        };
+        // Place the actions of the layer appropriately.
+        for (i, ac) in actions.into_iter().enumerate() {
+            layers_cfg[layer_level * 2][0][s.mapping_order[i]] = ac;
+            layers_cfg[layer_level * 2 + 1][0][s.mapping_order[i]] = ac;
+        }
+        for (i, layer_action) in layers_cfg[layer_level * 2][0].iter_mut().enumerate() {
+            *layer_action = layer_switch_action(*layer_action, i, &s.defsrc_layer);
+        }
+        // If the user has configured delegation to the first (default) layer for transparent keys,
+        // (as opposed to delegation to defsrc), replace the defsrc actions with the actions from
+        // the first layer.
+        if layer_level == 0 && s.delegate_to_first_layer {
+            for (defsrc_ac, default_layer_ac) in s.defsrc_layer.iter_mut().zip(layers_cfg[0][0]) {
+                *defsrc_ac = default_layer_ac;
+            }
+        }
+    }
+    if reused > 0 {
+        log::info!("reused {reused} unchanged layers");
+    }
+    // Keys of a `deflayer-for` become a switch on the input device of the most recent key press, so
+    // that the same key can do something different depending on the keyboard it is typed on.
+    for device_layer in s.device_layers.iter() {
+        let mut device_ops = vec![];
+        for name in device_layer.devices.iter() {
+            let idx = input_device_index(name, s)
+                .ok_or_else(|| anyhow!("maximum number of input devices exceeded"))?;
+            device_ops.push(OpCode::new_input_device(idx));
+        }
+        let device_ops = s.a.sref_vec(device_ops);
+        let switch_layer = device_layer.layer_idx * 2;
+        for (item, osc) in device_layer
+            .items
+            .iter()
+            .zip(device_layer.mapping_order.iter().copied())
+        {
+            let ac = resolve_defsrc_keys(parse_action(item, s)?, osc, s);
+            if *ac == Action::Trans {
+                continue;
+            }
+            // Other devices keep the action of the deflayer. A transparent action cannot be used
+            // within switch, so the "layer-while-held" version uses the resolved action of the
+            // "layer-switch" version instead.
+            let switch_fallback = layers_cfg[switch_layer][0][osc];
+            let held_fallback = match layers_cfg[switch_layer + 1][0][osc] {
+                Action::Trans => switch_fallback,
+                ac => ac,
+            };
+            for (layer, fallback) in [
+                (switch_layer, switch_fallback),
+                (switch_layer + 1, held_fallback),
+            ] {
+                let cases = vec![
+                    (device_ops, ac, BreakOrFallthrough::Break),
+                    (
+                        s.a.sref_vec(vec![]),
+                        s.a.sref(fallback),
+                        BreakOrFallthrough::Break,
+                    ),
+                ];
+                layers_cfg[layer][0][osc] = Action::Switch(s.a.sref(Switch {
+                    cases: s.a.sref_vec(cases),
+                }));
+            }
+        }
+    }
+    // Set fake keys on the `layer-switch` version of each layer. This is done after parsing all
+    // layers because actions within layers can create unnamed fake keys.
+    if s.fake_key_count() > KEYS_IN_ROW {
+        bail!(
+            "Maximum number of fake keys is {KEYS_IN_ROW}, found {}",
+            s.fake_key_count()
+        );
+    }
+    for layer_level in 0..s.layer_exprs.len() {
+        for (y, action) in s
+            .fake_keys
+            .values()
+            .chain(s.unnamed_fake_keys.borrow().iter())
+        {
+            let (x, y) = get_fake_key_coords(*y);
+            layers_cfg[layer_level * 2][x as usize][y as usize] = **action;
+        }
+    }
+    Ok(layers_cfg)
+}
+
+/// Returns the action at `osc_idx` of the "layer-switch" version of a layer, in which transparent
+/// actions are set according to the defsrc action.
+fn layer_switch_action(
+    action: KanataAction,
+    osc_idx: usize,
+    defsrc_layer: &[KanataAction; KEYS_IN_ROW],
+) -> KanataAction {
+    if action != Action::Trans {
+        return action;
+    }
+    match defsrc_layer[osc_idx] {
+        // If there is no corresponding action in defsrc, default to the OsCode at the position.
+        // This is done so that `process-unmapped-keys` works correctly.
+        Action::Trans => OsCode::from_u16(osc_idx as u16)
+            .and_then(|osc| match KeyCode::from(osc) {
+                KeyCode::No => None,
+                kc => Some(Action::KeyCode(kc)),
+            })
+            .unwrap_or(Action::Trans),
+        defsrc_action => defsrc_action,
+    }
+}
+
+const SEQ_ERR: &str = "defseq expects pairs of parameters: <fake_key_name> <key_list>";
+
+fn parse_sequences(exprs: &[&Vec<SExpr>], s: &ParsedState) -> Result<KeySeqsToFKeys> {
+    let mut sequences = Trie::new();
+    let mut patterns = vec![];
+    for expr in exprs {
+        let mut subexprs = check_first_expr(expr.iter(), "defseq")?.peekable();
+
+        while let Some(fake_key_expr) = subexprs.next() {
+            let fake_key = fake_key_expr.atom(s.vars()).ok_or_else(|| {
+                anyhow_expr!(fake_key_expr, "{SEQ_ERR}\nGot a list for fake_key_name")
             })?;
             if !s.fake_keys.contains_key(fake_key) {
                 bail_expr!(
@@ -2621,26 +4263,83 @@ fn parse_sequences(exprs: &[&Vec<SExpr>], s: &ParsedState) -> Result<KeySeqsToFK
             if key_seq.is_empty() {
                 bail_expr!(key_seq_expr, "{SEQ_ERR}\nkey_list cannot be empty");
             }
+            let fake_key_coord = s
+                .fake_keys
+                .get(fake_key)
+                .map(|(y, _)| get_fake_key_coords(*y))
+                .expect("fk exists, checked earlier");
+            if let Some(pattern) = parse_sequence_pattern(key_seq_expr, key_seq, s)? {
+                if patterns.iter().any(|(other, _)| *other == pattern) {
+                    bail_expr!(
+                        key_seq_expr,
+                        "Sequence has a conflict: the same sequence is defined earlier"
+                    );
+                }
+                patterns.push((pattern, fake_key_coord));
+                continue;
+            }
             let keycode_seq = parse_sequence_keys(key_seq, s)?;
-            if sequences.ancestor_exists(&keycode_seq) {
+            if matches!(
+                sequences.get_or_descendant_exists(&keycode_seq),
+                GetOrDescendentExistsResult::HasValue(_)
+            ) {
                 bail_expr!(
                     key_seq_expr,
-                    "Sequence has a conflict: its sequence contains an earlier defined sequence"
+                    "Sequence has a conflict: the same sequence is defined earlier"
                 );
             }
-            if sequences.descendant_exists(&keycode_seq) {
-                bail_expr!(key_seq_expr, "Sequence has a conflict: its sequence is contained within an earlier defined seqence");
-            }
-            sequences.insert(
-                keycode_seq,
-                s.fake_keys
-                    .get(fake_key)
-                    .map(|(y, _)| get_fake_key_coords(*y))
-                    .expect("fk exists, checked earlier"),
-            );
+            sequences.insert(keycode_seq, fake_key_coord);
         }
     }
-    Ok(sequences)
+    Ok(KeySequences::new(sequences, patterns))
+}
+
+const SEQ_ANY: &str = "any";
+const SEQ_COUNT: &str = "count";
+
+/// Parses a `defseq` key list that contains `any` or `count`. Returns `None` if it contains
+/// neither, in which case the key list is a sequence of exact keys.
+fn parse_sequence_pattern(
+    key_seq_expr: &SExpr,
+    exprs: &[SExpr],
+    s: &ParsedState,
+) -> Result<Option<Vec<SequencePatternItem>>> {
+    use SequencePatternItem::*;
+    let wildcard = |expr: &SExpr| match expr.atom(s.vars()) {
+        Some(SEQ_ANY) => Some(AnyKey),
+        Some(SEQ_COUNT) => Some(Count),
+        _ => None,
+    };
+    if !exprs.iter().any(|expr| wildcard(expr).is_some()) {
+        return Ok(None);
+    }
+    let mut items = vec![];
+    let mut keys_start = 0;
+    for (i, expr) in exprs.iter().enumerate() {
+        let Some(item) = wildcard(expr) else {
+            continue;
+        };
+        let keys = parse_sequence_keys(&exprs[keys_start..i], s)?;
+        items.extend(keys.into_iter().map(Key));
+        items.push(item);
+        keys_start = i + 1;
+    }
+    let keys = parse_sequence_keys(&exprs[keys_start..], s)?;
+    items.extend(keys.into_iter().map(Key));
+    if items.iter().filter(|item| **item == Count).count() > 1 {
+        bail_expr!(
+            key_seq_expr,
+            "{SEQ_ERR}\nkey_list can only contain one {SEQ_COUNT}"
+        );
+    }
+    if items.last() == Some(&Count) {
+        bail_expr!(
+            key_seq_expr,
+            "{SEQ_ERR}\n{SEQ_COUNT} cannot be the last item of key_list, \
+            since the end of the number would be unknown"
+        );
+    }
+    Ok(Some(items))
 }
 
 fn parse_sequence_keys(exprs: &[SExpr], s: &ParsedState) -> Result<Vec<u16>> {
@@ -2815,6 +4514,177 @@ fn parse_fork(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAct
     }))))
 }
 
+fn parse_mods_passthrough(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
+    const ERR_STR: &str = "mods-passthrough expects 1 or 2 params: <action> [modifier-keys]";
+    if ac_params.is_empty() || ac_params.len() > 2 {
+        bail!("{ERR_STR}\nFound {} params instead", ac_params.len());
+    }
+    let left = *parse_action(&ac_params[0], s)?;
+    let right_triggers = match ac_params.get(1) {
+        Some(mods) => s.a.sref_vec(
+            parse_key_list(mods, s, "modifier-keys")?
+                .into_iter()
+                .map(KeyCode::from)
+                .collect::<Vec<_>>(),
+        ),
+        None => &[
+            KeyCode::LShift,
+            KeyCode::RShift,
+            KeyCode::LCtrl,
+            KeyCode::RCtrl,
+            KeyCode::LAlt,
+            KeyCode::RAlt,
+            KeyCode::LGui,
+            KeyCode::RGui,
+        ],
+    };
+    // The right action is a placeholder that is replaced by the defsrc key at the position the
+    // action is placed in; see `resolve_defsrc_key`.
+    Ok(s.a.sref(Action::Fork(s.a.sref(ForkConfig {
+        left,
+        right: Action::Custom(s.a.sref(s.a.sref_slice(CustomAction::DefsrcKey))),
+        right_triggers,
+    }))))
+}
+
+/// Replaces the `mods-passthrough` placeholders within the action placed at `osc_idx` in a layer
+/// with the defsrc action at that position. Returns the action unchanged if it has none.
+fn resolve_defsrc_keys(
+    ac: &'static KanataAction,
+    osc_idx: usize,
+    s: &ParsedState,
+) -> &'static KanataAction {
+    match resolve_defsrc_key(ac, osc_idx, s) {
+        Some(ac) => s.a.sref(ac),
+        None => ac,
+    }
+}
+
+fn resolve_defsrc_key(
+    action: &KanataAction,
+    osc_idx: usize,
+    s: &ParsedState,
+) -> Option<KanataAction> {
+    match action {
+        Action::Custom(cacs) if matches!(cacs, [CustomAction::DefsrcKey]) => {
+            Some(match s.defsrc_layer[osc_idx] {
+                Action::Trans => OsCode::from_u16(osc_idx as u16)
+                    .and_then(|osc| match KeyCode::from(osc) {
+                        KeyCode::No => None,
+                        kc => Some(Action::KeyCode(kc)),
+                    })
+                    .unwrap_or(Action::Trans),
+                defsrc_action => defsrc_action,
+            })
+        }
+        Action::NoOp
+        | Action::Trans
+        | Action::Repeat
+        | Action::KeyCode(_)
+        | Action::MultipleKeyCodes(_)
+        | Action::Layer(_)
+        | Action::DefaultLayer(_)
+        | Action::Sequence { .. }
+        | Action::RepeatableSequence { .. }
+        | Action::CancelSequences
+        | Action::ReleaseState(_)
+        | Action::Chords(_)
+        | Action::Custom(_) => None,
+        Action::HoldTap(&hta @ HoldTapAction { tap, hold, .. }) => {
+            let new_tap = resolve_defsrc_key(&tap, osc_idx, s);
+            let new_hold = resolve_defsrc_key(&hold, osc_idx, s);
+            if new_tap.is_some() || new_hold.is_some() {
+                Some(Action::HoldTap(s.a.sref(HoldTapAction {
+                    hold: new_hold.unwrap_or(hold),
+                    tap: new_tap.unwrap_or(tap),
+                    ..hta
+                })))
+            } else {
+                None
+            }
+        }
+        Action::OneShot(&os @ OneShot { action: ac, .. }) => resolve_defsrc_key(ac, osc_idx, s)
+            .map(|ac| {
+                Action::OneShot(s.a.sref(OneShot {
+                    action: s.a.sref(ac),
+                    ..os
+                }))
+            }),
+        Action::MultipleActions(actions) => {
+            let new_actions = actions
+                .iter()
+                .map(|ac| resolve_defsrc_key(ac, osc_idx, s))
+                .collect::<Vec<_>>();
+            if new_actions.iter().any(|it| it.is_some()) {
+                let new_actions = new_actions
+                    .iter()
+                    .zip(**actions)
+                    .map(|(new_ac, ac)| new_ac.unwrap_or(*ac))
+                    .collect::<Vec<_>>();
+                Some(Action::MultipleActions(s.a.sref(s.a.sref_vec(new_actions))))
+            } else {
+                None
+            }
+        }
+        Action::TapDance(&td @ TapDance { actions, .. }) => {
+            let new_actions = actions
+                .iter()
+                .map(|ac| resolve_defsrc_key(ac, osc_idx, s))
+                .collect::<Vec<_>>();
+            if new_actions.iter().any(|it| it.is_some()) {
+                let new_actions = new_actions
+                    .iter()
+                    .zip(actions)
+                    .map(|(new_ac, ac)| new_ac.map(|v| s.a.sref(v)).unwrap_or(*ac))
+                    .collect::<Vec<_>>();
+                Some(Action::TapDance(s.a.sref(TapDance {
+                    actions: s.a.sref_vec(new_actions),
+                    ..td
+                })))
+            } else {
+                None
+            }
+        }
+        Action::Fork(&fcfg @ ForkConfig { left, right, .. }) => {
+            let new_left = resolve_defsrc_key(&left, osc_idx, s);
+            let new_right = resolve_defsrc_key(&right, osc_idx, s);
+            if new_left.is_some() || new_right.is_some() {
+                Some(Action::Fork(s.a.sref(ForkConfig {
+                    left: new_left.unwrap_or(left),
+                    right: new_right.unwrap_or(right),
+                    ..fcfg
+                })))
+            } else {
+                None
+            }
+        }
+        Action::Switch(Switch { cases }) => {
+            let new_cases = cases
+                .iter()
+                .map(|case| resolve_defsrc_key(case.1, osc_idx, s))
+                .collect::<Vec<_>>();
+            if new_cases.iter().any(|it| it.is_some()) {
+                let new_cases = new_cases
+                    .into_iter()
+                    .zip(cases.iter())
+                    .map(|(new_ac, case)| {
+                        (
+                            case.0,
+                            new_ac.map(|v| s.a.sref(v)).unwrap_or(case.1),
+                            case.2,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                Some(Action::Switch(s.a.sref(Switch {
+                    cases: s.a.sref_vec(new_cases),
+                })))
+            } else {
+                None
+            }
+        }
+    }
+}
+
 fn parse_caps_word(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAction> {
     const ERR_STR: &str = "caps-word expects 1 param: <timeout>";
     if ac_params.len() != 1 {
@@ -3000,6 +4870,102 @@ fn parse_switch(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataA
     }))))
 }
 
+/// Returns the index of the device name used by `input-device` switch conditions, adding the name
+/// if it is not used yet. Returns `None` if there are too many device names.
+fn input_device_index(name: &str, s: &ParsedState) -> Option<u16> {
+    let mut devices = s.input_devices.borrow_mut();
+    let idx = match devices.iter().position(|d| d == name) {
+        Some(i) => i + 1,
+        None => {
+            devices.push(name.to_owned());
+            devices.len()
+        }
+    };
+    match idx > usize::from(MAX_OPCODE_LEN) {
+        true => None,
+        false => Some(idx as u16),
+    }
+}
+
+/// Index of the flag toggled by `fn-lock-toggle` and checked by `fn-lock` in switch.
+pub const FN_LOCK_FLAG: u16 = 0;
+
+/// Where the value checked by an external state condition is read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExternalStateSource {
+    /// The contents of a file, which can be rewritten at any time by other programs.
+    File(PathBuf),
+    /// An environment variable of the kanata process.
+    EnvVar(String),
+    /// The power source of the system, read as `ac` or `battery`.
+    Power,
+    /// The battery charge in percent.
+    BatteryLevel,
+    /// The local time of day in minutes since midnight.
+    TimeOfDay,
+}
+
+/// What the value read from an external state source is checked against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExternalStateValue {
+    /// The value, with surrounding whitespace trimmed, equals the string.
+    Equals(String),
+    /// The value is a number that is at least the first bound and below the second. If the first
+    /// bound is greater, the range wraps around, e.g. for a time range past midnight.
+    Range(u16, u16),
+}
+
+/// A switch condition on state from outside of kanata. The condition is true when the value read
+/// from `source` matches `value`. Switch checks the condition through the flag at index `flag`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalStateCondition {
+    pub source: ExternalStateSource,
+    pub value: ExternalStateValue,
+    pub flag: u16,
+}
+
+/// Returns the flag of the external state condition, adding the condition if it is not used yet.
+fn external_state_flag(
+    source: ExternalStateSource,
+    value: ExternalStateValue,
+    op_expr: &SExpr,
+    s: &ParsedState,
+) -> Result<u16> {
+    let mut states = s.external_states.borrow_mut();
+    if let Some(st) = states
+        .iter()
+        .find(|st| st.source == source && st.value == value)
+    {
+        return Ok(st.flag);
+    }
+    // Flags after the fn-lock flag are used for external states.
+    let flag = FN_LOCK_FLAG + 1 + states.len() as u16;
+    if flag > MAX_FLAG {
+        bail_expr!(
+            op_expr,
+            "maximum number of external state conditions, e.g. state-file, exceeded"
+        );
+    }
+    states.push(ExternalStateCondition {
+        source,
+        value,
+        flag,
+    });
+    Ok(flag)
+}
+
+/// Parses a time of day written as `HH:MM` into minutes since midnight.
+fn parse_time_of_day(expr: &SExpr, s: &ParsedState) -> Result<u16> {
+    expr.atom(s.vars())
+        .and_then(|time| time.split_once(':'))
+        .filter(|(_, m)| m.len() == 2)
+        .and_then(|(h, m)| match (h.parse::<u16>(), m.parse::<u16>()) {
+            (Ok(h @ 0..=23), Ok(m @ 0..=59)) => Some(h * 60 + m),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow_expr!(expr, "time must be written as HH:MM, e.g. 09:30"))
+}
+
 /// Returns the
 fn parse_switch_case_bool(
     depth: u8,
@@ -3019,7 +4985,25 @@ fn parse_switch_case_bool(
             "maximum key match expression depth {MAX_BOOL_EXPR_DEPTH} is exceeded"
         );
     }
-    if let Some(a) = op_expr.atom(s.vars()) {
+    if let Some(set) = s.key_set(op_expr) {
+        // A key set matches if any of its keys match.
+        let placeholder_index = ops.len() as u16;
+        ops.push(OpCode::new_bool(BooleanOperator::Or, placeholder_index));
+        for osc in key_set_oscodes(set) {
+            ops.push(OpCode::new_key(osc.into()));
+        }
+        if ops.len() > MAX_OPCODE_LEN as usize {
+            bail_expr!(
+                op_expr,
+                "maximum key match size of {MAX_OPCODE_LEN} items is exceeded"
+            );
+        }
+        ops[placeholder_index as usize] = OpCode::new_bool(BooleanOperator::Or, ops.len() as u16);
+        Ok(())
+    } else if op_expr.atom(s.vars()) == Some("fn-lock") {
+        ops.push(OpCode::new_flag(FN_LOCK_FLAG));
+        Ok(())
+    } else if let Some(a) = op_expr.atom(s.vars()) {
         let osc = str_to_oscode(a).ok_or_else(|| anyhow_expr!(op_expr, "invalid key name"))?;
         ops.push(OpCode::new_key(osc.into()));
         Ok(())
@@ -3035,6 +5019,13 @@ fn parse_switch_case_bool(
             Or,
             And,
             KeyHistory,
+            InputDevice,
+            InputDeviceHeld,
+            StateFile,
+            EnvVar,
+            Power,
+            BatteryBelow,
+            TimeBetween,
         }
         let op = l[0]
             .atom(s.vars())
@@ -3042,15 +5033,127 @@ fn parse_switch_case_bool(
                 "or" => Some(AllowedListOps::Or),
                 "and" => Some(AllowedListOps::And),
                 "key-history" => Some(AllowedListOps::KeyHistory),
+                "input-device" => Some(AllowedListOps::InputDevice),
+                "input-device-held" => Some(AllowedListOps::InputDeviceHeld),
+                "state-file" => Some(AllowedListOps::StateFile),
+                "env-var" => Some(AllowedListOps::EnvVar),
+                "power" => Some(AllowedListOps::Power),
+                "battery-below" => Some(AllowedListOps::BatteryBelow),
+                "time-between" => Some(AllowedListOps::TimeBetween),
                 _ => None,
             })
             .ok_or_else(|| {
                 anyhow_expr!(
                     op_expr,
-                    "lists inside key match must begin with one of: or, and, key-history"
+                    "lists inside key match must begin with one of: or, and, key-history, input-device, input-device-held, state-file, env-var, power, battery-below, time-between"
                 )
             })?;
         match op {
+            AllowedListOps::StateFile | AllowedListOps::EnvVar => {
+                let op_name = l[0].atom(s.vars()).expect("checked atom");
+                if l.len() != 3 {
+                    bail_expr!(op_expr, "{op_name} must have 2 parameters: source, value");
+                }
+                let source = l[1]
+                    .atom(s.vars())
+                    .map(|src| src.trim_matches('"'))
+                    .ok_or_else(|| anyhow_expr!(&l[1], "{op_name} source must be a string"))?;
+                let source = match op {
+                    AllowedListOps::StateFile => ExternalStateSource::File(PathBuf::from(source)),
+                    _ => ExternalStateSource::EnvVar(source.to_owned()),
+                };
+                let value = l[2]
+                    .atom(s.vars())
+                    .map(|v| ExternalStateValue::Equals(v.trim_matches('"').to_owned()))
+                    .ok_or_else(|| anyhow_expr!(&l[2], "{op_name} value must be a string"))?;
+                ops.push(OpCode::new_flag(external_state_flag(
+                    source, value, op_expr, s,
+                )?));
+                Ok(())
+            }
+            AllowedListOps::Power => {
+                let power = match l {
+                    [_, power] => power.atom(s.vars()),
+                    _ => None,
+                };
+                if !matches!(power, Some("ac" | "battery")) {
+                    bail_expr!(op_expr, "power must have 1 parameter: ac or battery");
+                }
+                let value = ExternalStateValue::Equals(power.expect("checked").to_owned());
+                ops.push(OpCode::new_flag(external_state_flag(
+                    ExternalStateSource::Power,
+                    value,
+                    op_expr,
+                    s,
+                )?));
+                Ok(())
+            }
+            AllowedListOps::BatteryBelow => {
+                if l.len() != 2 {
+                    bail_expr!(op_expr, "battery-below must have 1 parameter: percent");
+                }
+                let percent = parse_u8_with_range(&l[1], s, "percent", 1, 100)?;
+                let value = ExternalStateValue::Range(0, u16::from(percent));
+                ops.push(OpCode::new_flag(external_state_flag(
+                    ExternalStateSource::BatteryLevel,
+                    value,
+                    op_expr,
+                    s,
+                )?));
+                Ok(())
+            }
+            AllowedListOps::TimeBetween => {
+                if l.len() != 3 {
+                    bail_expr!(op_expr, "time-between must have 2 parameters: start, end");
+                }
+                let value = ExternalStateValue::Range(
+                    parse_time_of_day(&l[1], s)?,
+                    parse_time_of_day(&l[2], s)?,
+                );
+                ops.push(OpCode::new_flag(external_state_flag(
+                    ExternalStateSource::TimeOfDay,
+                    value,
+                    op_expr,
+                    s,
+                )?));
+                Ok(())
+            }
+            AllowedListOps::InputDevice => {
+                if l.len() != 2 {
+                    bail_expr!(op_expr, "input-device must have 1 parameter: device name");
+                }
+                let name = l[1]
+                    .atom(s.vars())
+                    .map(|name| name.trim_matches('"'))
+                    .ok_or_else(|| anyhow_expr!(&l[1], "device name must be a string"))?;
+                let idx = input_device_index(name, s).ok_or_else(|| {
+                    anyhow_expr!(op_expr, "maximum number of input devices exceeded")
+                })?;
+                ops.push(OpCode::new_input_device(idx));
+                Ok(())
+            }
+            AllowedListOps::InputDeviceHeld => {
+                if l.len() != 2 {
+                    bail_expr!(
+                        op_expr,
+                        "input-device-held must have 1 parameter: device name"
+                    );
+                }
+                let name = l[1]
+                    .atom(s.vars())
+                    .map(|name| name.trim_matches('"'))
+                    .ok_or_else(|| anyhow_expr!(&l[1], "device name must be a string"))?;
+                let idx = input_device_index(name, s)
+                    .filter(|idx| *idx <= MAX_HELD_INPUT_DEVICE)
+                    .ok_or_else(|| {
+                        anyhow_expr!(
+                            op_expr,
+                            "input-device-held supports only the first {MAX_HELD_INPUT_DEVICE} input devices"
+                        )
+                    })?;
+                ops.push(OpCode::new_input_device_held(idx));
+                Ok(())
+            }
             AllowedListOps::KeyHistory => {
                 if l.len() != 3 {
                     bail_expr!(
@@ -3150,7 +5253,7 @@ fn parse_unmod(ac_params: &[SExpr], s: &ParsedState) -> Result<&'static KanataAc
 }
 
 /// Creates a `KeyOutputs` from `layers::LAYERS`.
-fn create_key_outputs(layers: &KanataLayers, overrides: &Overrides) -> KeyOutputs {
+fn create_key_outputs(layers: &ParsedLayers, overrides: &Overrides) -> KeyOutputs {
     let mut outs = KeyOutputs::new();
     for layer in layers.iter() {
         let mut layer_outputs = HashMap::default();
@@ -3262,7 +5365,89 @@ fn add_kc_output(
     }
 }
 
-/// Create a layout from `layers::LAYERS`.
-fn create_layout(layers: Box<KanataLayers>, a: Arc<Allocations>) -> KanataLayout {
-    KanataLayout::new(Layout::new(a.bref(layers)), a)
+/// Create a layout from the parsed layers. Each distinct row is allocated once and shared by the
+/// layers that contain it, so the undefined layers and e.g. the fake key rows, which are the same
+/// in every layer, do not each take a full row.
+fn create_layout(
+    layers: &[[Row; LAYER_COLUMNS]],
+    pair_thresholds: &'static [(KCoord, KCoord, u16)],
+    a: Arc<Allocations>,
+) -> KanataLayout {
+    let trans: &'static Row = a.bref(Box::new([KanataAction::Trans; KEYS_IN_ROW]));
+    // Actions are not `Hash`, so the rows are bucketed by `row_hash` and told apart by comparing
+    // them with the parsed rows that are in the same bucket.
+    let mut rows: HashMap<u64, Vec<(&Row, KanataLayerRow)>> = HashMap::default();
+    rows.insert(row_hash(trans), vec![(trans, LayerRow::Dense(trans))]);
+    // The base layers map most keys to themselves, so the first full row is the fallback of the
+    // rows of the other base layers.
+    let mut fallbacks: Vec<&'static Row> = vec![trans];
+    let (mut distinct, mut sparse) = (0, 0);
+    let mut klayers: KanataLayers = [[LayerRow::Dense(trans); LAYER_COLUMNS]; ACTUAL_NUM_LAYERS];
+    for (klayer, layer) in klayers.iter_mut().zip(layers.iter()) {
+        for (krow, row) in klayer.iter_mut().zip(layer.iter()) {
+            let bucket = rows.entry(row_hash(row)).or_default();
+            *krow = match bucket.iter().find(|(parsed, _)| *parsed == row) {
+                Some((_, shared)) => *shared,
+                None => {
+                    let shared = new_layer_row(row, &fallbacks, &a);
+                    distinct += 1;
+                    match shared {
+                        LayerRow::Sparse { .. } => sparse += 1,
+                        LayerRow::Dense(row) if fallbacks.len() == 1 => fallbacks.push(row),
+                        LayerRow::Dense(_) => {}
+                    }
+                    bucket.push((row, shared));
+                    shared
+                }
+            };
+        }
+    }
+    log::debug!("layers use {distinct} distinct rows, {sparse} of them sparse");
+    let mut layout = Layout::new(a.sref(klayers));
+    layout.pair_thresholds = PairThresholds(pair_thresholds);
+    KanataLayout::new(layout, a)
+}
+
+/// Hashes the kind of each action of the row and the key of the `KeyCode` actions.
+fn row_hash(row: &Row) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = rustc_hash::FxHasher::default();
+    for action in row.iter() {
+        std::mem::discriminant(action).hash(&mut hasher);
+        if let Action::KeyCode(kc) = action {
+            kc.hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
+
+/// Stores only the actions that differ from the closest of `fallbacks` if that takes less memory
+/// than a copy of the whole row, which is the case for most rows of a typical layer.
+fn new_layer_row(row: &Row, fallbacks: &[&'static Row], a: &Allocations) -> KanataLayerRow {
+    let differences = |fallback: &Row| {
+        row.iter()
+            .zip(fallback.iter())
+            .filter(|(action, fallback)| action != fallback)
+            .count()
+    };
+    let fallback = fallbacks
+        .iter()
+        .copied()
+        .min_by_key(|fallback| differences(fallback))
+        .expect("at least the transparent fallback");
+    let actions: Vec<(u16, KanataAction)> = row
+        .iter()
+        .zip(fallback.iter())
+        .enumerate()
+        .filter(|(_, (action, fallback))| action != fallback)
+        .map(|(col, (action, _))| (col as u16, *action))
+        .collect();
+    if actions.len() * std::mem::size_of::<(u16, KanataAction)>() < std::mem::size_of::<Row>() {
+        LayerRow::Sparse {
+            actions: a.sref_vec(actions),
+            fallback,
+        }
+    } else {
+        LayerRow::Dense(a.bref(Box::new(*row)))
+    }
 }
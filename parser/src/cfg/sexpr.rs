@@ -1,7 +1,7 @@
 use std::iter;
 use std::ops::Index;
-use std::rc::Rc;
 use std::str::Bytes;
+use std::sync::Arc;
 
 type HashMap<K, V> = rustc_hash::FxHashMap<K, V>;
 
@@ -33,8 +33,8 @@ impl Position {
 pub struct Span {
     pub start: Position,
     pub end: Position,
-    pub file_name: Rc<str>,
-    pub file_content: Rc<str>,
+    pub file_name: Arc<str>,
+    pub file_content: Arc<str>,
 }
 
 impl Default for Span {
@@ -42,14 +42,14 @@ impl Default for Span {
         Self {
             start: Position::default(),
             end: Position::default(),
-            file_name: Rc::from(""),
-            file_content: Rc::from(""),
+            file_name: Arc::from(""),
+            file_content: Arc::from(""),
         }
     }
 }
 
 impl Span {
-    fn new(start: Position, end: Position, file_name: Rc<str>, file_content: Rc<str>) -> Span {
+    fn new(start: Position, end: Position, file_name: Arc<str>, file_content: Arc<str>) -> Span {
         assert!(start.absolute <= end.absolute);
         assert!(start.line <= end.line);
         Span {
@@ -274,8 +274,8 @@ impl<'a> Lexer<'a> {
             bytes: PositionCountingBytesIterator::new(source),
             ignore_whitespace_and_comments,
         };
-        let file_name: Rc<str> = Rc::from(file_name);
-        let file_content: Rc<str> = Rc::from(source);
+        let file_name: Arc<str> = Arc::from(file_name);
+        let file_content: Arc<str> = Arc::from(source);
         iter::from_fn(move || {
             lexer.next_token().map(|(start, t)| {
                 let end = lexer.bytes.pos();
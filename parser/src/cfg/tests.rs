@@ -303,7 +303,7 @@ fn parse_transparent_default() {
         Err(poisoned) => poisoned.into_inner(),
     };
     let mut s = ParsedState::default();
-    let (_, _, layer_strings, layers, _, _) = parse_cfg_raw(
+    let (_, _, layer_strings, layers, _, _, _) = parse_cfg_raw(
         &std::path::PathBuf::from("./test_cfgs/transparent_default.kbd"),
         &mut s,
     )
@@ -343,6 +343,135 @@ fn parse_transparent_default() {
     assert_eq!(layers[3][0][usize::from(OsCode::KEY_F15)], Action::Trans);
 }
 
+#[test]
+fn layout_shares_equal_rows() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let cfg = new_from_file(&std::path::PathBuf::from(
+        "./test_cfgs/transparent_default.kbd",
+    ))
+    .unwrap();
+    let layers = cfg.layout.b().layers;
+    assert_eq!(
+        layers[0][0][usize::from(OsCode::KEY_F14)],
+        Action::DefaultLayer(2)
+    );
+    assert_eq!(layers[2][0][usize::from(OsCode::KEY_F14)], Action::Layer(1));
+    // The first base layer is stored in full and the rows of the layers while held only store
+    // their non-transparent actions.
+    let LayerRow::Dense(base) = layers[0][0] else {
+        panic!("the first base layer is not stored in full");
+    };
+    assert!(matches!(layers[1][0], LayerRow::Sparse { actions, .. } if actions.len() == 2));
+    // The other base layer only stores the actions that differ from the first one.
+    assert!(
+        matches!(layers[2][0], LayerRow::Sparse { actions, fallback }
+        if actions.len() == 3 && std::ptr::eq(base, fallback))
+    );
+    // The fake key rows are the same in every layer and the undefined layers are transparent.
+    let same_row = |r1: LayerRow<KEYS_IN_ROW, _>, r2: LayerRow<KEYS_IN_ROW, _>| match (r1, r2) {
+        (LayerRow::Dense(r1), LayerRow::Dense(r2)) => std::ptr::eq(r1, r2),
+        (LayerRow::Sparse { actions: a1, .. }, LayerRow::Sparse { actions: a2, .. }) => {
+            std::ptr::eq(a1, a2)
+        }
+        _ => false,
+    };
+    assert!(same_row(layers[0][1], layers[2][1]));
+    assert!(same_row(layers[1][1], layers[4][0]));
+    assert!(same_row(layers[4][0], layers[ACTUAL_NUM_LAYERS - 1][1]));
+    assert!(layers[4][0].iter().all(|action| *action == Action::Trans));
+}
+
+#[test]
+fn config_builder_builds_parsed_cfg() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let builder = ConfigBuilder::new()
+        .cfg("process-unmapped-keys", "yes")
+        .cfg("linux-dev-names-exclude", "My Mouse")
+        .src(["caps", "a"])
+        .alias(
+            "cap",
+            BuilderAction::TapHold {
+                tap_timeout: 200,
+                hold_timeout: 200,
+                tap: Box::new(OsCode::KEY_ESC.into()),
+                hold: Box::new(OsCode::KEY_LEFTCTRL.into()),
+            },
+        )
+        .layer(
+            "base",
+            [
+                BuilderAction::Alias("cap".into()),
+                BuilderAction::LayerWhileHeld("nav".into()),
+            ],
+        )
+        .layer("nav", [BuilderAction::Trans, "left".into()]);
+    let text = builder.to_text().unwrap();
+    assert!(text.contains("linux-dev-names-exclude \"My Mouse\""));
+    assert!(text.contains("cap (tap-hold 200 200 esc lctl)"));
+    let cfg = builder.build().unwrap();
+    assert_eq!(cfg.items["linux-dev-names-exclude"], "My Mouse");
+    assert_eq!(
+        cfg.layer_info
+            .iter()
+            .map(|l| l.name.as_str())
+            .collect::<Vec<_>>(),
+        ["base", "base", "nav", "nav"]
+    );
+    assert_eq!(
+        cfg.layout.b().layers[2][0][usize::from(OsCode::KEY_A)],
+        Action::KeyCode(KeyCode::Left)
+    );
+    assert!(ConfigBuilder::new()
+        .src(["a"])
+        .layer("base", ["a"])
+        .form("(include other.kbd)")
+        .build()
+        .is_err());
+    assert!(ConfigBuilder::new()
+        .cfg("linux-dev-names-exclude", "My \"Mouse\"")
+        .to_text()
+        .is_err());
+}
+
+#[test]
+fn analyze_cfg_warns_about_suspicious_layers_and_sequences() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let cfg = new_from_str(
+        "
+(defsrc a b c)
+(deflayer base (tap-hold 200 200 a (layer-switch trap)) (layer-while-held nav) (layer-switch back))
+(deflayer nav _ _ _)
+(deflayer trap _ _ _)
+(deflayer back _ _ (layer-while-held exit))
+(deflayer exit _ _ (layer-switch base))
+(deflayer orphan _ _ _)
+(deffakekeys short a long b)
+(defseq short (any any) long (x any))
+",
+    )
+    .unwrap();
+    assert_eq!(
+        analyze_cfg(&cfg),
+        [
+            "layer orphan is unreachable: no action of the first layer, or of a layer that it \
+            changes to, changes to it",
+            "layer trap has no exit: the hold action of a tap-hold key switches the base layer to \
+            it, but neither it nor the layers held from it have an action that switches to \
+            another base layer",
+            "sequence for long can never complete: the sequence for short completes first",
+        ]
+    );
+}
+
 #[test]
 fn parse_multiline_comment() {
     let _lk = match CFG_PARSE_LOCK.lock() {
@@ -370,30 +499,38 @@ fn disallow_nested_tap_hold() {
 }
 
 #[test]
-fn disallow_ancestor_seq() {
+fn allow_ancestor_seq() {
     let _lk = match CFG_PARSE_LOCK.lock() {
         Ok(guard) => guard,
         Err(poisoned) => poisoned.into_inner(),
     };
-    match new_from_file(&std::path::PathBuf::from("./test_cfgs/ancestor_seq.kbd"))
-        .map_err(|e| format!("{e:?}"))
-    {
-        Ok(_) => panic!("invalid ancestor seq was Ok'd"),
-        Err(e) => assert!(e.contains("is contained")),
-    }
+    new_from_file(&std::path::PathBuf::from("./test_cfgs/ancestor_seq.kbd")).unwrap();
 }
 
 #[test]
-fn disallow_descendent_seq() {
+fn allow_descendent_seq() {
     let _lk = match CFG_PARSE_LOCK.lock() {
         Ok(guard) => guard,
         Err(poisoned) => poisoned.into_inner(),
     };
-    match new_from_file(&std::path::PathBuf::from("./test_cfgs/descendant_seq.kbd"))
-        .map_err(|e| format!("{e:?}"))
-    {
-        Ok(_) => panic!("invalid descendant seq was Ok'd"),
-        Err(e) => assert!(e.contains("contains")),
+    new_from_file(&std::path::PathBuf::from("./test_cfgs/descendant_seq.kbd")).unwrap();
+}
+
+#[test]
+fn disallow_duplicate_seq() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let source = "
+(defsrc a b)
+(deflayer base _ _)
+(deffakekeys x a y b)
+(defseq x (a b) y (a b))
+";
+    match new_from_str(source).map_err(|e| format!("{e:?}")) {
+        Ok(_) => panic!("duplicate seq was Ok'd"),
+        Err(e) => assert!(e.contains("same sequence"), "real e: {e}"),
     }
 }
 
@@ -622,6 +759,110 @@ fn test_include_good() {
     new_from_file(&std::path::PathBuf::from("./test_cfgs/include-good.kbd")).unwrap();
 }
 
+#[test]
+fn test_key_action_edit() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let p = std::path::PathBuf::from("./test_cfgs/include-good.kbd");
+    let mut cfg = new_from_file(&p).unwrap();
+    let parser = &cfg.key_action_parser;
+    let original = cfg.layout.key_actions(0, OsCode::KEY_A);
+    let mut edited = EditedFiles::default();
+    let swap = parser
+        .parse(&p, &mut edited, "base", "a", "(macro h i)")
+        .unwrap();
+    assert_eq!((swap.layer, swap.osc), (0, OsCode::KEY_A));
+    assert!(matches!(swap.actions.action(), Action::Sequence { .. }));
+    assert_eq!(swap.bindings, [("a".to_owned(), "(macro h i)".to_owned())]);
+    assert_ne!(swap.layout_fingerprint, cfg.layout_fingerprint);
+    cfg.layout
+        .set_key_actions(swap.layer, swap.osc, &swap.actions);
+    assert!(matches!(
+        cfg.layout.b().layers[0][0][usize::from(OsCode::KEY_A)],
+        Action::Sequence { .. }
+    ));
+    assert!(matches!(
+        cfg.layout.b().layers[1][0][usize::from(OsCode::KEY_A)],
+        Action::Sequence { .. }
+    ));
+    // The layer is in the included file, so only that file is edited.
+    let included = std::path::PathBuf::from("./test_cfgs/included-good.kbd")
+        .canonicalize()
+        .unwrap();
+    assert_eq!(edited.len(), 1);
+    assert_eq!(edited[&included].trim(), "(deflayer base (macro h i))");
+
+    // Invalid actions are not applied.
+    let parser = &cfg.key_action_parser;
+    assert!(parser.parse(&p, &mut edited, "base", "a", "b c").is_err());
+    assert!(parser
+        .parse(&p, &mut edited, "base", "a", "(nonexistent)")
+        .is_err());
+    assert!(parser.parse(&p, &mut edited, "base", "b", "c").is_err());
+    assert!(parser.parse(&p, &mut edited, "nav", "a", "c").is_err());
+    // Fake keys cannot be added to the running layout.
+    assert!(parser
+        .parse(&p, &mut edited, "base", "a", "(press-release b c)")
+        .is_err());
+    assert_eq!(edited[&included].trim(), "(deflayer base (macro h i))");
+
+    let swap = parser.parse(&p, &mut edited, "base", "a", "_").unwrap();
+    assert_eq!(swap.actions.action(), &Action::KeyCode(KeyCode::A));
+    assert!(swap.bindings.is_empty());
+    assert_eq!(edited[&included].trim(), "(deflayer base _)");
+
+    // Restoring the actions of the unedited file takes the rest from the file.
+    let swap = parser
+        .restore(&p, &EditedFiles::default(), "base", "a", original)
+        .unwrap();
+    assert_eq!(swap.actions.action(), &Action::KeyCode(KeyCode::A));
+    assert_eq!(swap.layout_fingerprint, cfg.layout_fingerprint);
+    assert_eq!(swap.layer_text.trim(), "(deflayer base a)");
+}
+
+#[test]
+fn reload_reuses_unchanged_layers() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let parse = |text: &str, layer_cache: &LayerCache| {
+        new_from_exprs(sexpr::parse(text, "test").unwrap(), layer_cache.clone()).unwrap()
+    };
+    let key_is = |cfg: &Cfg, layer: usize, kc: KeyCode| {
+        cfg.layout.b().layers[layer][0][usize::from(OsCode::KEY_A)] == Action::KeyCode(kc)
+    };
+    let cfg = parse(
+        "(defsrc a) (defalias x b)
+         (deflayer base @x) (deflayer nav c) (deflayer fake (press-release d e))",
+        &LayerCache::default(),
+    );
+    // Layers that add unnamed fake keys are not reused.
+    assert_eq!(cfg.layer_cache.layers.len(), 2);
+
+    // Only the changed layer is parsed, the others keep their actions.
+    let reloaded = parse(
+        "(defsrc a) (defalias x b)
+         (deflayer base @x) (deflayer nav   f) (deflayer fake (press-release d e))",
+        &cfg.layer_cache,
+    );
+    assert!(key_is(&reloaded, 0, KeyCode::B));
+    assert!(key_is(&reloaded, 2, KeyCode::F));
+    assert_eq!(reloaded.layer_cache.a.as_ref().unwrap().depth(), 2);
+    assert_eq!(reloaded.layer_cache.layers.len(), 2);
+
+    // A changed alias can change any layer, so no layer is reused.
+    let reloaded = parse(
+        "(defsrc a) (defalias x g)
+         (deflayer base @x) (deflayer nav f) (deflayer fake (press-release d e))",
+        &reloaded.layer_cache,
+    );
+    assert!(key_is(&reloaded, 0, KeyCode::G));
+    assert_eq!(reloaded.layer_cache.a.as_ref().unwrap().depth(), 1);
+}
+
 #[test]
 fn test_include_bad_has_filename_included() {
     let _lk = match CFG_PARSE_LOCK.lock() {
@@ -1198,3 +1439,2047 @@ fn list_action_not_in_list_error_message_is_good() {
     })
     .unwrap_err();
 }
+
+#[test]
+fn parse_deftest() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a)
+(deflayer base b)
+(deftest a-is-b
+  (input d:a t:10 u:a)
+  (output d:b u:b)
+)
+"#;
+    let res = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    let tests = res.6;
+    assert_eq!(tests.len(), 1);
+    assert_eq!(tests[0].name, "a-is-b");
+    assert_eq!(
+        tests[0].inputs,
+        vec![
+            SimInput::Press(OsCode::KEY_A),
+            SimInput::Wait(10),
+            SimInput::Release(OsCode::KEY_A),
+        ]
+    );
+    assert_eq!(
+        tests[0].expected_outputs,
+        vec![
+            SimOutput::Press(OsCode::KEY_B),
+            SimOutput::Release(OsCode::KEY_B)
+        ]
+    );
+}
+
+#[test]
+fn parse_deftest_bad_input_item() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a)
+(deflayer base b)
+(deftest a-is-b
+  (input p:a)
+  (output d:b u:b)
+)
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .map_err(|e| {
+        assert_eq!(
+            e.msg,
+            "input items must be d:<key> (press), u:<key> (release), or t:<ms> (wait)"
+        );
+    })
+    .unwrap_err();
+}
+
+#[test]
+fn parse_layer_cycle() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a b)
+(deflayer one (layer-cycle (one two three)) (layer-cycle-reverse (one two three)))
+(deflayer two _ _)
+(deflayer three _ _)
+"#;
+    let res = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    assert_eq!(
+        res.3[0][0][OsCode::KEY_A.as_u16() as usize],
+        Action::Custom(&[&CustomAction::LayerCycle(vec![0, 2, 4])].as_slice()),
+    );
+    assert_eq!(
+        res.3[0][0][OsCode::KEY_B.as_u16() as usize],
+        Action::Custom(&[&CustomAction::LayerCycle(vec![4, 2, 0])].as_slice()),
+    );
+}
+
+#[test]
+fn parse_layer_cycle_needs_list() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a)
+(deflayer one (layer-cycle one))
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .map_err(|e| {
+        assert_eq!(
+            e.msg,
+            "layer-cycle expects one item: a list of two or more layer names"
+        );
+    })
+    .unwrap_err();
+}
+
+#[test]
+fn parse_press_release() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a b)
+(deffakekeys fk1 c)
+(deflayer one (press-release d e) _)
+(deflayer two _ (press-release f g))
+"#;
+    let res = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    let fake_key_row = usize::from(FAKE_KEY_ROW);
+    for layer in [0, 2] {
+        assert_eq!(res.3[layer][fake_key_row][0], Action::KeyCode(KeyCode::C));
+        assert_eq!(res.3[layer][fake_key_row][1], Action::KeyCode(KeyCode::D));
+        assert_eq!(res.3[layer][fake_key_row][2], Action::KeyCode(KeyCode::E));
+        assert_eq!(res.3[layer][fake_key_row][3], Action::KeyCode(KeyCode::F));
+        assert_eq!(res.3[layer][fake_key_row][4], Action::KeyCode(KeyCode::G));
+    }
+    assert_eq!(
+        res.3[0][0][OsCode::KEY_A.as_u16() as usize],
+        Action::Custom(
+            &[
+                &CustomAction::FakeKey {
+                    coord: Coord {
+                        x: FAKE_KEY_ROW,
+                        y: 1
+                    },
+                    action: FakeKeyAction::Press,
+                },
+                &CustomAction::FakeKeyOnRelease {
+                    coord: Coord {
+                        x: FAKE_KEY_ROW,
+                        y: 1
+                    },
+                    action: FakeKeyAction::Release,
+                },
+                &CustomAction::FakeKeyOnRelease {
+                    coord: Coord {
+                        x: FAKE_KEY_ROW,
+                        y: 2
+                    },
+                    action: FakeKeyAction::Tap,
+                },
+            ]
+            .as_slice()
+        ),
+    );
+}
+
+#[test]
+fn parse_mods_passthrough() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc caps a lctl b c)
+(defalias mp (mods-passthrough b (lctl rctl)))
+(deflayer one
+  (mods-passthrough esc) @mp lctl
+  (fork x _ (lsft))
+  (tap-hold 200 200 (mods-passthrough y) lalt))
+"#;
+    let res = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    for layer in [0, 1] {
+        match res.3[layer][0][OsCode::KEY_CAPSLOCK.as_u16() as usize] {
+            Action::Fork(fcfg) => {
+                assert_eq!(fcfg.left, Action::KeyCode(KeyCode::Escape));
+                assert_eq!(fcfg.right, Action::KeyCode(KeyCode::CapsLock));
+                assert_eq!(fcfg.right_triggers.len(), 8);
+            }
+            ac => panic!("expected fork, got {ac:?}"),
+        }
+        match res.3[layer][0][OsCode::KEY_A.as_u16() as usize] {
+            Action::Fork(fcfg) => {
+                assert_eq!(fcfg.left, Action::KeyCode(KeyCode::B));
+                assert_eq!(fcfg.right, Action::KeyCode(KeyCode::A));
+                assert_eq!(fcfg.right_triggers, &[KeyCode::LCtrl, KeyCode::RCtrl]);
+            }
+            ac => panic!("expected fork, got {ac:?}"),
+        }
+        // A transparent right action of a user fork is not replaced.
+        match res.3[layer][0][OsCode::KEY_B.as_u16() as usize] {
+            Action::Fork(fcfg) => assert_eq!(fcfg.right, Action::Trans),
+            ac => panic!("expected fork, got {ac:?}"),
+        }
+        match res.3[layer][0][OsCode::KEY_C.as_u16() as usize] {
+            Action::HoldTap(hta) => match hta.tap {
+                Action::Fork(fcfg) => {
+                    assert_eq!(fcfg.left, Action::KeyCode(KeyCode::Y));
+                    assert_eq!(fcfg.right, Action::KeyCode(KeyCode::C));
+                }
+                ac => panic!("expected fork, got {ac:?}"),
+            },
+            ac => panic!("expected tap-hold, got {ac:?}"),
+        }
+    }
+}
+
+#[test]
+fn parse_edit_config_requires_cmd() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defcfg editor vim edit-config-live-reload yes)
+(defsrc a)
+(deflayer one edit-config)
+"#;
+    let err = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .expect_err("edit-config without danger-enable-cmd should error");
+    assert!(err.msg.contains("danger-enable-cmd"));
+}
+
+#[test]
+fn parse_defkeys() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defkeys
+  lh (a s)
+  hr (lh d))
+(defchords ch 50 lh x hr y (d) z)
+(defsrc a s d f caps)
+(deflayer one
+  (chord ch a)
+  (chord ch s)
+  (chord ch d)
+  (switch (hr) x break () y break)
+  (caps-word-custom 2000 hr (lh b)))
+"#;
+    let res = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    assert_eq!(
+        s.key_sets.get("hr").unwrap(),
+        &["a".to_owned(), "s".to_owned(), "d".to_owned()]
+    );
+    match res.3[0][0][OsCode::KEY_CAPSLOCK.as_u16() as usize] {
+        Action::Custom(&[CustomAction::CapsWord(cfg)]) => {
+            assert_eq!(
+                cfg.keys_to_capitalize,
+                &[KeyCode::A, KeyCode::S, KeyCode::D]
+            );
+            assert_eq!(cfg.keys_nonterminal, &[KeyCode::A, KeyCode::S, KeyCode::B]);
+        }
+        ac => panic!("expected caps-word, got {ac:?}"),
+    }
+
+    let mut s = ParsedState::default();
+    let source = r#"
+(defkeys lh (a s) lh (d))
+(defsrc a)
+(deflayer one a)
+"#;
+    let err = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .expect_err("duplicate key set should error");
+    assert!(err.msg.contains("duplicate key set name"));
+}
+
+#[test]
+fn parse_switch_input_device() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a b)
+(deflayer one
+  (switch ((input-device "Macro Pad")) x break () a break)
+  (switch ((and (input-device /dev/input/event3) (input-device "Macro Pad"))) y break))
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    assert_eq!(
+        *s.input_devices.borrow(),
+        vec!["Macro Pad".to_owned(), "/dev/input/event3".to_owned()]
+    );
+}
+
+#[test]
+fn parse_switch_input_device_held() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a b)
+(deflayer one
+  (switch ((and (input-device-held "Foot Pedal") a)) C-a break () a break)
+  (switch ((input-device "Foot Pedal")) XX break () b break))
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    assert_eq!(*s.input_devices.borrow(), vec!["Foot Pedal".to_owned()]);
+
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a)
+(deflayer one (switch ((input-device-held)) b break))
+"#;
+    let err = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .expect_err("missing device name should error");
+    assert!(err.msg.contains("input-device-held must have 1 parameter"));
+}
+
+#[test]
+fn parse_fn_lock() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc esc f1)
+(deflayer one
+  fn-lock-toggle
+  (switch (fn-lock) f1 break () brdn break))
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+}
+
+#[test]
+fn parse_switch_external_state() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a b)
+(deflayer one
+  (switch ((state-file /tmp/vpn-state up)) x break () a break)
+  (switch ((or (env-var HOSTNAME work) (state-file /tmp/vpn-state "up"))) y break))
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    assert_eq!(
+        *s.external_states.borrow(),
+        vec![
+            ExternalStateCondition {
+                source: ExternalStateSource::File(PathBuf::from("/tmp/vpn-state")),
+                value: ExternalStateValue::Equals("up".to_owned()),
+                flag: FN_LOCK_FLAG + 1,
+            },
+            ExternalStateCondition {
+                source: ExternalStateSource::EnvVar("HOSTNAME".to_owned()),
+                value: ExternalStateValue::Equals("work".to_owned()),
+                flag: FN_LOCK_FLAG + 2,
+            },
+        ]
+    );
+}
+
+#[test]
+fn parse_switch_ambient_state() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a b)
+(deflayer one
+  (switch ((and (power battery) (battery-below 20))) x break () a break)
+  (switch ((time-between 22:30 06:00)) y break ((power ac)) z break))
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    let conditions: Vec<_> = s
+        .external_states
+        .borrow()
+        .iter()
+        .map(|st| (st.source.clone(), st.value.clone()))
+        .collect();
+    assert_eq!(
+        conditions,
+        vec![
+            (
+                ExternalStateSource::Power,
+                ExternalStateValue::Equals("battery".to_owned())
+            ),
+            (
+                ExternalStateSource::BatteryLevel,
+                ExternalStateValue::Range(0, 20)
+            ),
+            (
+                ExternalStateSource::TimeOfDay,
+                ExternalStateValue::Range(22 * 60 + 30, 6 * 60)
+            ),
+            (
+                ExternalStateSource::Power,
+                ExternalStateValue::Equals("ac".to_owned())
+            ),
+        ]
+    );
+    for invalid in [
+        "(power mains)",
+        "(battery-below 0)",
+        "(time-between 9:00 24:00)",
+    ] {
+        let source = format!("(defsrc a)\n(deflayer one (switch ({invalid}) x break))");
+        parse_cfg_raw_string(
+            &source,
+            &mut ParsedState::default(),
+            &PathBuf::from("test"),
+            &mut FileContentProvider {
+                get_file_content_fn: &mut |_| unimplemented!(),
+            },
+            DEF_LOCAL_KEYS,
+        )
+        .expect_err(invalid);
+    }
+}
+
+#[test]
+fn parse_tap_hold_opposite_hand() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a s d j k l)
+(defkeys home-left (a s d))
+(defhands left (home-left) right (j k l))
+(deflayer one
+  (tap-hold-opposite-hand 200 200 a lmet) s d j k l)
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    let hands = s.hands.expect("defhands is parsed");
+    assert_eq!(hands.get(&u16::from(OsCode::KEY_S)), Some(&Hand::Left));
+    assert_eq!(hands.get(&u16::from(OsCode::KEY_K)), Some(&Hand::Right));
+
+    let source = r#"
+(defsrc a)
+(deflayer one (tap-hold-opposite-hand 200 200 a lmet))
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .expect_err("tap-hold-opposite-hand without defhands should fail");
+}
+
+#[test]
+fn parse_defoverlap() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a d f ;)
+(defoverlap (d f) 80 (a ;) 30)
+(deflayer one a d f ;)
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    let thresholds = PairThresholds(s.pair_thresholds);
+    let coord = |osc: OsCode| (NORMAL_KEY_ROW, u16::from(osc));
+    assert_eq!(
+        thresholds.get(coord(OsCode::KEY_F), coord(OsCode::KEY_D)),
+        Some(80)
+    );
+    assert_eq!(
+        thresholds.get(coord(OsCode::KEY_A), coord(OsCode::KEY_SEMICOLON)),
+        Some(30)
+    );
+    assert_eq!(
+        thresholds.get(coord(OsCode::KEY_A), coord(OsCode::KEY_D)),
+        None
+    );
+
+    let source = r#"
+(defsrc d f)
+(defoverlap (d f) 80 (f d) 30)
+(deflayer one d f)
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .expect_err("a key pair with two thresholds should fail");
+}
+
+#[test]
+fn parse_sequence_patterns() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a)
+(deflayer one sldr)
+(deffakekeys word-right C-rght git-any (macro g i t spc))
+(defseq word-right (count w) git-any (g any spc))
+"#;
+    let (_, _, _, _, sequences, _, _) = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    let typed = [OsCode::KEY_3, OsCode::KEY_W].map(u16::from);
+    assert!(matches!(
+        sequences.find(&typed),
+        crate::sequences::SequenceMatch::Complete { count: 3, .. }
+    ));
+
+    let source = r#"
+(defsrc a)
+(deflayer one sldr)
+(deffakekeys word-right C-rght)
+(defseq word-right (w count))
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .expect_err("count at the end of a sequence should fail");
+
+    let source = r#"
+(defsrc a)
+(deflayer one sldr)
+(deffakekeys word-right C-rght word-right-2 C-rght)
+(defseq word-right (count w) word-right-2 (count w))
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .expect_err("the same pattern defined twice should fail");
+}
+
+#[test]
+fn parse_layer_bindings() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a h j caps)
+(deflayer base a h j (tap-hold 200 200
+  esc lctl))
+(deflayer nav _ left down show-help)
+"#;
+    let (_, _, layer_info, _, _, _, _) = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    let bindings = |name: &str| {
+        layer_info
+            .iter()
+            .find(|info| info.name == name)
+            .unwrap()
+            .bindings
+            .clone()
+    };
+    assert_eq!(
+        bindings("base"),
+        vec![("caps".into(), "(tap-hold 200 200 esc lctl)".into())]
+    );
+    assert_eq!(
+        bindings("nav"),
+        vec![
+            ("h".into(), "left".into()),
+            ("j".into(), "down".into()),
+            ("caps".into(), "show-help".into()),
+        ]
+    );
+}
+
+#[test]
+fn parse_device_defsrc() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a b)
+(defsrc laptop (devices "AT Translated Set 2 keyboard") a c)
+(deflayer base x y)
+(deflayer-for laptop base z _)
+"#;
+    let (_, mapped_keys, _, klayers, _, _, _) = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    assert!(mapped_keys.contains(&OsCode::KEY_C));
+    assert_eq!(
+        *s.input_devices.borrow(),
+        vec!["AT Translated Set 2 keyboard".to_owned()]
+    );
+    let row = &klayers[0][usize::from(NORMAL_KEY_ROW)];
+    match row[usize::from(OsCode::KEY_A)] {
+        Action::Switch(Switch { cases }) => {
+            assert_eq!(cases.len(), 2);
+            assert_eq!(*cases[0].1, Action::KeyCode(KeyCode::Z));
+            assert_eq!(*cases[1].1, Action::KeyCode(KeyCode::X));
+        }
+        ac => panic!("expected a switch, got {ac:?}"),
+    }
+    assert_eq!(row[usize::from(OsCode::KEY_B)], Action::KeyCode(KeyCode::Y));
+    assert_eq!(row[usize::from(OsCode::KEY_C)], Action::KeyCode(KeyCode::C));
+
+    let source = r#"
+(defsrc a b)
+(defsrc laptop (devices "AT Translated Set 2 keyboard") a c)
+(deflayer base x y)
+(deflayer-for laptop base z)
+"#;
+    let err = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .expect_err("item count must match the defsrc");
+    assert!(err.msg.contains("to match its defsrc"));
+}
+
+#[test]
+fn parse_device_layer_for_default_defsrc() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a b)
+(deflayer base x y)
+(deflayer-for (devices "Mech Board" /dev/input/event5) base _ z)
+"#;
+    let (_, _, _, klayers, _, _, _) = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    assert_eq!(
+        *s.input_devices.borrow(),
+        vec!["Mech Board".to_owned(), "/dev/input/event5".to_owned()]
+    );
+    let row = &klayers[0][usize::from(NORMAL_KEY_ROW)];
+    assert_eq!(row[usize::from(OsCode::KEY_A)], Action::KeyCode(KeyCode::X));
+    match row[usize::from(OsCode::KEY_B)] {
+        Action::Switch(Switch { cases }) => {
+            assert_eq!(cases.len(), 2);
+            assert_eq!(*cases[0].1, Action::KeyCode(KeyCode::Z));
+            assert_eq!(*cases[1].1, Action::KeyCode(KeyCode::Y));
+        }
+        ac => panic!("expected a switch, got {ac:?}"),
+    }
+
+    let source = r#"
+(defsrc a b)
+(deflayer base x y)
+(deflayer-for (devices) base _ z)
+"#;
+    let err = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .expect_err("device list must not be empty");
+    assert!(err.msg.contains("one or more device names"));
+}
+
+#[test]
+fn parse_repeat_accel() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc volu vold)
+(deflayer one (repeat-accel volu 400 50 1000) (repeat-accel (macro vold vold) 400 50 1000))
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+
+    let source = r#"
+(defsrc volu)
+(deflayer one (repeat-accel volu 50 400 1000))
+"#;
+    let err = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .expect_err("end interval greater than start interval should error");
+    assert!(err.msg.contains("end interval"));
+}
+
+#[test]
+fn parse_output_device() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defcfg linux-output-separate-mouse yes)
+(defsrc a b c)
+(deflayer one (output-device mouse lctl) (output-device keyboard mlft) (output-device mouse lctl))
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    assert_eq!(
+        *s.output_device_keys.borrow(),
+        vec![
+            (OutputDevice::Mouse, OsCode::KEY_LEFTCTRL),
+            (OutputDevice::Keyboard, OsCode::BTN_LEFT),
+        ]
+    );
+
+    let source = r#"
+(defsrc a)
+(deflayer one (output-device gamepad a))
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .expect_err("unknown output device should error");
+}
+
+#[test]
+fn parse_gamepad_actions() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defcfg linux-output-gamepad yes)
+(defsrc a b c d)
+(deflayer one
+  (gamepad-button south)
+  (gamepad-button dpad-up)
+  (gamepad-axis left-x -32768)
+  (gamepad-axis right-trigger 255)
+)
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+
+    for (source, expected_err) in [
+        (
+            "(defsrc a) (deflayer one (gamepad-button q))",
+            "Valid buttons",
+        ),
+        ("(defsrc a) (deflayer one (gamepad-axis z 1))", "Valid axes"),
+        (
+            "(defsrc a) (deflayer one (gamepad-axis left-trigger -1))",
+            "from 0 to 255",
+        ),
+        (
+            "(defsrc a) (deflayer one (gamepad-axis dpad-y 2))",
+            "from -1 to 1",
+        ),
+    ] {
+        let err = parse_cfg_raw_string(
+            source,
+            &mut s,
+            &PathBuf::from("test"),
+            &mut FileContentProvider {
+                get_file_content_fn: &mut |_| unimplemented!(),
+            },
+            DEF_LOCAL_KEYS,
+        )
+        .expect_err("invalid gamepad action should error");
+        assert!(err.msg.contains(expected_err), "{}", err.msg);
+    }
+}
+
+#[test]
+fn parse_axis_ramp() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc w s t)
+(deflayer one (axis left-y - 200 100) (axis left-y + 200 100 ease-in) (axis right-trigger + 0 0 ease-in-out))
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+
+    for (source, expected_err) in [
+        (
+            "(defsrc a) (deflayer one (axis left-y up 200 100))",
+            "- or +",
+        ),
+        (
+            "(defsrc a) (deflayer one (axis left-trigger - 200 100))",
+            "+ direction",
+        ),
+        (
+            "(defsrc a) (deflayer one (axis left-y + 200))",
+            "four or five parameters",
+        ),
+        (
+            "(defsrc a) (deflayer one (axis left-y + 200 100 quadratic))",
+            "Valid curves",
+        ),
+    ] {
+        let err = parse_cfg_raw_string(
+            source,
+            &mut s,
+            &PathBuf::from("test"),
+            &mut FileContentProvider {
+                get_file_content_fn: &mut |_| unimplemented!(),
+            },
+            DEF_LOCAL_KEYS,
+        )
+        .expect_err("invalid axis action should error");
+        assert!(err.msg.contains(expected_err), "{}", err.msg);
+    }
+}
+
+#[test]
+fn parse_midi_actions() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defcfg linux-midi-device /dev/snd/midiC1D0)
+(defsrc a b c)
+(deflayer one (midi-note 60 100) (midi-note 61 127 10) (midi-cc 7 0))
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+
+    for (source, expected_err) in [
+        (
+            "(defsrc a) (deflayer one (midi-note 128 100))",
+            "note must be 0-127",
+        ),
+        (
+            "(defsrc a) (deflayer one (midi-note 60 0))",
+            "velocity must be 1-127",
+        ),
+        (
+            "(defsrc a) (deflayer one (midi-cc 7 0 17))",
+            "channel must be 1-16",
+        ),
+        (
+            "(defsrc a) (deflayer one (midi-cc 7))",
+            "two or three parameters",
+        ),
+    ] {
+        let err = parse_cfg_raw_string(
+            source,
+            &mut s,
+            &PathBuf::from("test"),
+            &mut FileContentProvider {
+                get_file_content_fn: &mut |_| unimplemented!(),
+            },
+            DEF_LOCAL_KEYS,
+        )
+        .expect_err("invalid midi action should error");
+        assert!(err.msg.contains(expected_err), "{}", err.msg);
+    }
+}
+
+#[test]
+fn parse_osc_action() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defcfg osc-target 127.0.0.1:9000)
+(defsrc a)
+(deflayer one (osc "/scene/switch" 2 0.5 intro "3"))
+"#;
+    let (_, _, _, klayers, _, _, _) = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    assert_eq!(
+        klayers[0][0][usize::from(OsCode::KEY_A)],
+        Action::Custom(
+            &[&CustomAction::Osc {
+                address: "/scene/switch".into(),
+                args: vec![
+                    OscArg::Int(2),
+                    OscArg::Float(0.5f32.to_bits()),
+                    OscArg::String("intro".into()),
+                    OscArg::String("3".into()),
+                ],
+            }]
+            .as_slice()
+        )
+    );
+
+    let source = r#"
+(defsrc a)
+(deflayer one (osc scene 1))
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .expect_err("address without / should error");
+}
+
+#[test]
+fn parse_http_post() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defcfg http-post-timeout 2000 http-post-min-interval 500)
+(defsrc a b)
+(deflayer one
+  (http-post "http://homeassistant.local:8123/api/webhook/lights" "state=on&brightness=50")
+  (http-post http://192.168.1.5/toggle)
+)
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+
+    for (source, expected_err) in [
+        (
+            r#"(defsrc a) (deflayer one (http-post "https://example.com"))"#,
+            "https URLs are not supported",
+        ),
+        (
+            r#"(defsrc a) (deflayer one (http-post "example.com"))"#,
+            "must start with http://",
+        ),
+        (
+            r#"(defsrc a) (deflayer one (http-post))"#,
+            "one or two strings",
+        ),
+    ] {
+        let err = parse_cfg_raw_string(
+            source,
+            &mut s,
+            &PathBuf::from("test"),
+            &mut FileContentProvider {
+                get_file_content_fn: &mut |_| unimplemented!(),
+            },
+            DEF_LOCAL_KEYS,
+        )
+        .expect_err("invalid http-post should error");
+        assert!(err.msg.contains(expected_err), "{}", err.msg);
+    }
+}
+
+#[test]
+fn parse_wm_actions() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a b c)
+(deflayer one (wm-focus left) (wm-move up) (wm-workspace "2: web"))
+"#;
+    let (_, _, _, klayers, _, _, _) = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    for (osc, cmd) in [
+        (OsCode::KEY_A, WmCommand::Focus(MoveDirection::Left)),
+        (OsCode::KEY_B, WmCommand::Move(MoveDirection::Up)),
+        (OsCode::KEY_C, WmCommand::Workspace("2: web".into())),
+    ] {
+        assert_eq!(
+            klayers[0][0][usize::from(osc)],
+            Action::Custom(&[&CustomAction::WindowManager(cmd)].as_slice())
+        );
+    }
+
+    for source in [
+        "(defsrc a) (deflayer one (wm-focus sideways))",
+        "(defsrc a) (deflayer one (wm-move))",
+        "(defsrc a) (deflayer one (wm-workspace 1 2))",
+    ] {
+        parse_cfg_raw_string(
+            source,
+            &mut s,
+            &PathBuf::from("test"),
+            &mut FileContentProvider {
+                get_file_content_fn: &mut |_| unimplemented!(),
+            },
+            DEF_LOCAL_KEYS,
+        )
+        .expect_err("invalid wm action should error");
+    }
+}
+
+#[test]
+fn parse_macro_guarded() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a rctl)
+(deflayer one (macro-guarded rctl a 100 S-b) rctl)
+"#;
+    let (_, _, _, klayers, _, _, _) = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    let Action::Sequence { events } = klayers[0][0][usize::from(OsCode::KEY_A)] else {
+        panic!("macro-guarded should be a sequence");
+    };
+    let mut prev_is_guard = false;
+    let mut presses = 0;
+    for event in events.iter() {
+        match event {
+            SequenceEvent::Custom(custom) => {
+                assert_eq!(
+                    **custom,
+                    &[&CustomAction::MacroGuard(OsCode::KEY_RIGHTCTRL)]
+                );
+                prev_is_guard = true;
+                continue;
+            }
+            SequenceEvent::Press(_) | SequenceEvent::Tap(_) => {
+                assert!(prev_is_guard, "every press should be guarded: {events:?}");
+                presses += 1;
+            }
+            _ => assert!(!prev_is_guard, "only presses should be guarded: {events:?}"),
+        }
+        prev_is_guard = false;
+    }
+    assert!(presses >= 3);
+
+    for source in [
+        "(defsrc a rctl) (deflayer one (macro-guarded rctl) rctl)",
+        "(defsrc a rctl) (deflayer one (macro-guarded notakey a) rctl)",
+        "(defsrc a) (deflayer one (macro-guarded rctl a))",
+    ] {
+        parse_cfg_raw_string(
+            source,
+            &mut s,
+            &PathBuf::from("test"),
+            &mut FileContentProvider {
+                get_file_content_fn: &mut |_| unimplemented!(),
+            },
+            DEF_LOCAL_KEYS,
+        )
+        .expect_err("invalid macro-guarded should error");
+    }
+}
+
+#[test]
+fn alias_refs_include_nested_aliases() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let cfg = new_from_str(
+        r#"
+(defcfg on-startup @on-start)
+(defvar held @ctl)
+(defsrc j k l a)
+(defalias
+  esc esc
+  ctl lctl
+  th (tap-hold 200 200 @esc $held)
+  in-chord x
+  in-chord-v2 y
+  on-start z
+  unused u
+)
+(defchords grp 50 (a) a (l) @in-chord)
+(defchordsv2 (j k) @in-chord-v2 50 all-released)
+(deflayer base @th (multi a @esc) (chord grp l) (chord grp a))
+"#,
+    )
+    .unwrap();
+    let refs = &cfg.alias_refs;
+    assert_eq!(refs.aliases["th"], ["esc", "ctl"]);
+    assert_eq!(refs.aliases.len(), 7);
+    let binding = |key: &str| refs.bindings.get(&("base".into(), key.into())).cloned();
+    assert_eq!(binding("j"), Some(vec!["th".into()]));
+    assert_eq!(binding("k"), Some(vec!["esc".into()]));
+    assert_eq!(binding("l"), Some(vec!["in-chord".into()]));
+    assert_eq!(
+        refs.chords_v2,
+        [(
+            vec![OsCode::KEY_J, OsCode::KEY_K],
+            vec!["in-chord-v2".into()]
+        )]
+    );
+    assert!(refs.untracked.contains("on-start") && refs.untracked.len() == 1);
+}
+
+#[test]
+fn parse_defchordsv2() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc j k l a)
+(deflayer base 1 _ 3 (layer-while-held other))
+(deflayer other 4 5 _ _)
+(defchordsv2
+  (j k) esc 50 all-released
+  (k l) tab 30 first-release
+)
+"#;
+    let (_, _, _, klayers, _, _, _) = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    let j = usize::from(OsCode::KEY_J);
+    let k = usize::from(OsCode::KEY_K);
+    let Action::Chords(group) = klayers[0][0][j] else {
+        panic!("chord keys should be chords");
+    };
+    assert_eq!(klayers[0][0][k], Action::Chords(group));
+    assert_eq!(
+        klayers[0][0][usize::from(OsCode::KEY_L)],
+        Action::Chords(group)
+    );
+    assert!(matches!(
+        klayers[0][0][usize::from(OsCode::KEY_A)],
+        Action::Layer(_)
+    ));
+    assert_eq!(group.timeout, 50);
+    let mask = |osc: usize| group.get_keys((0, osc as u16)).unwrap();
+    assert_eq!(
+        group.chord_timeout(mask(k) | mask(usize::from(OsCode::KEY_L))),
+        30
+    );
+    assert!(group.releases_on_first(mask(k) | mask(usize::from(OsCode::KEY_L))));
+    assert!(!group.releases_on_first(mask(j) | mask(k)));
+    assert_eq!(
+        group.get_chord(mask(j) | mask(k), 0),
+        Some(&Action::KeyCode(KeyCode::Escape))
+    );
+    // Keys by themselves keep the action of the layer, the transparent k resolves to defsrc.
+    assert_eq!(
+        group.get_chord(mask(j), 0),
+        Some(&Action::KeyCode(KeyCode::Kb1))
+    );
+    assert_eq!(
+        group.get_chord(mask(k), 0),
+        Some(&Action::KeyCode(KeyCode::K))
+    );
+    let Action::Chords(other) = klayers[3][0][k] else {
+        panic!("chord keys should be chords in every layer");
+    };
+    assert_eq!(
+        other.get_chord(mask(k), 0),
+        Some(&Action::KeyCode(KeyCode::Kb5))
+    );
+    assert_eq!(
+        other.get_chord(mask(usize::from(OsCode::KEY_L)), 0),
+        Some(&Action::KeyCode(KeyCode::L))
+    );
+
+    for source in [
+        "(defsrc j k) (deflayer base j k) (defchordsv2 (j k) esc 50)",
+        "(defsrc j k) (deflayer base j k) (defchordsv2 (j) esc 50 all-released)",
+        "(defsrc j k) (deflayer base j k) (defchordsv2 (j l) esc 50 all-released)",
+        "(defsrc j k) (deflayer base j k) (defchordsv2 (j k) esc 50 later)",
+        "(defsrc j k) (deflayer base j k) (defchordsv2 (j k) esc 0 all-released)",
+        "(defsrc j k) (deflayer base j k) (defchordsv2 (j k) esc 50 all-released (k j) tab 50 all-released)",
+    ] {
+        parse_cfg_raw_string(
+            source,
+            &mut s,
+            &PathBuf::from("test"),
+            &mut FileContentProvider {
+                get_file_content_fn: &mut |_| unimplemented!(),
+            },
+            DEF_LOCAL_KEYS,
+        )
+        .expect_err("invalid defchordsv2 should error");
+    }
+}
+
+#[test]
+fn parse_deftemplate() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a s d)
+(deftemplate hrm (key mod)
+  (tap-hold 200 200 $key $mod)
+)
+(deftemplate layer (name)
+  (deflayer $name (t! hrm a lmet) (t! hrm s lalt) d)
+)
+(t! layer base)
+"#;
+    let (_, _, layer_infos, klayers, _, _, _) = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    assert_eq!(layer_infos[0].name, "base");
+    for (osc, hold) in [
+        (OsCode::KEY_A, KeyCode::LGui),
+        (OsCode::KEY_S, KeyCode::LAlt),
+    ] {
+        let Action::HoldTap(hold_tap) = klayers[0][0][usize::from(osc)] else {
+            panic!("template should expand to tap-hold");
+        };
+        assert_eq!(hold_tap.tap, Action::KeyCode(osc.into()));
+        assert_eq!(hold_tap.hold, Action::KeyCode(hold));
+    }
+
+    for source in [
+        "(defsrc a) (deflayer one (t! hrm a lmet))",
+        "(defsrc a) (deftemplate hrm (key) $key) (deflayer one (t! hrm a lmet))",
+        "(defsrc a) (deftemplate hrm (key)) (deflayer one a)",
+        "(defsrc a) (deftemplate x () a) (deftemplate x () b) (deflayer one a)",
+    ] {
+        parse_cfg_raw_string(
+            source,
+            &mut s,
+            &PathBuf::from("test"),
+            &mut FileContentProvider {
+                get_file_content_fn: &mut |_| unimplemented!(),
+            },
+            DEF_LOCAL_KEYS,
+        )
+        .expect_err("invalid template should error");
+    }
+}
+
+#[test]
+fn parse_notify() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a b)
+(deflayer one (notify "gaming layer" "ON") (notify kanata))
+"#;
+    let (_, _, _, klayers, _, _, _) = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    assert_eq!(
+        klayers[0][0][usize::from(OsCode::KEY_A)],
+        Action::Custom(
+            &[&CustomAction::Notify {
+                title: "gaming layer".into(),
+                body: "ON".into(),
+            }]
+            .as_slice()
+        )
+    );
+
+    let source = r#"
+(defsrc a)
+(deflayer one (notify a b c))
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .expect_err("three parameters should error");
+}
+
+#[test]
+fn parse_clipboard_actions() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a b c)
+(deflayer one
+  (clipboard-set "kanata@example.com")
+  (clipboard-save-slot 1)
+  (clipboard-restore-slot 1)
+)
+"#;
+    let (_, _, _, klayers, _, _, _) = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    assert_eq!(
+        klayers[0][0][usize::from(OsCode::KEY_B)],
+        Action::Custom(&[&CustomAction::ClipboardSaveSlot(1)].as_slice())
+    );
+
+    let source = r#"
+(defsrc a)
+(deflayer one (clipboard-restore-slot x))
+"#;
+    let err = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .expect_err("non-numeric slot should error");
+    assert!(err.msg.contains("slot must be 0-65535"));
+}
+
+#[test]
+fn parse_cmd_output_text_requires_danger_enable_cmd() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc a)
+(deflayer one (cmd-output-text (newline space) tesseract screen.png -))
+"#;
+    let err = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .expect_err("cmd-output-text without danger-enable-cmd should error");
+    assert!(err.msg.contains("cmd is not enabled"));
+}
+
+#[test]
+#[cfg(feature = "cmd")]
+fn parse_cmd_output_text() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defcfg danger-enable-cmd yes)
+(defsrc a b)
+(deflayer one
+  (cmd-output-text (newline shift-enter) (ascii unicode) (interval 5) (chunk 4) tesseract screen.png -)
+  (cmd-output-text date)
+)
+"#;
+    let (_, _, _, klayers, _, _, _) = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    assert_eq!(
+        klayers[0][0][usize::from(OsCode::KEY_A)],
+        Action::Custom(
+            &[&CustomAction::CmdOutputText {
+                cmd: vec!["tesseract".into(), "screen.png".into(), "-".into()],
+                typing: TextTypingCfg {
+                    newline: NewlinePolicy::ShiftEnter,
+                    ascii_as_keys: false,
+                    interval: 5,
+                    chunk: 4,
+                },
+            }]
+            .as_slice()
+        )
+    );
+    assert_eq!(
+        klayers[0][0][usize::from(OsCode::KEY_B)],
+        Action::Custom(
+            &[&CustomAction::CmdOutputText {
+                cmd: vec!["date".into()],
+                typing: TextTypingCfg::default(),
+            }]
+            .as_slice()
+        )
+    );
+
+    let source = r#"
+(defcfg danger-enable-cmd yes)
+(defsrc a)
+(deflayer one (cmd-output-text (newline tab) date))
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .expect_err("unknown newline policy should error");
+}
+
+#[test]
+fn parse_macro_type_delay() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defcfg type-delay 10)
+(defsrc a)
+(deflayer one (macro a (type-delay 30) b))
+"#;
+    let (cfg, _, _, klayers, _, _, _) = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    assert_eq!(cfg.get("type-delay").map(String::as_str), Some("10"));
+    assert_eq!(
+        klayers[0][0][usize::from(OsCode::KEY_A)],
+        Action::Sequence {
+            events: &[
+                SequenceEvent::Press(KeyCode::A),
+                SequenceEvent::Release(KeyCode::A),
+                SequenceEvent::TypeDelay { duration: 30 },
+                SequenceEvent::Press(KeyCode::B),
+                SequenceEvent::Release(KeyCode::B),
+                SequenceEvent::Complete,
+            ]
+            .as_slice()
+        }
+    );
+
+    for (source, msg) in [
+        (
+            "(defsrc a)(deflayer one (macro (type-delay)))",
+            "one parameter",
+        ),
+        (
+            "(defsrc a)(deflayer one (macro (type-delay x)))",
+            "type-delay",
+        ),
+    ] {
+        let err = parse_cfg_raw_string(
+            source,
+            &mut s,
+            &PathBuf::from("test"),
+            &mut FileContentProvider {
+                get_file_content_fn: &mut |_| unimplemented!(),
+            },
+            DEF_LOCAL_KEYS,
+        )
+        .expect_err("invalid type-delay should error");
+        assert!(err.msg.contains(msg), "{}", err.msg);
+    }
+}
+
+#[test]
+fn parse_deflayer_cfg() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defcfg type-delay 10 key-repeat yes)
+(defsrc a)
+(deflayer base (layer-while-held game))
+(deflayer game b)
+(deflayer-cfg game key-repeat no movemouse-speed-percent 50)
+"#;
+    let (_, _, layer_info, _, _, _, _) = parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    assert!(layer_info[0].cfg_overrides.is_empty());
+    for info in &layer_info[2..4] {
+        assert_eq!(info.name, "game");
+        assert_eq!(info.cfg_overrides.len(), 2);
+        assert_eq!(info.cfg_overrides["key-repeat"], "no");
+        assert_eq!(info.cfg_overrides["movemouse-speed-percent"], "50");
+    }
+
+    for (cfgs, msg) in [
+        (
+            "(deflayer-cfg nope type-delay 5)",
+            "must be defined with deflayer",
+        ),
+        (
+            "(deflayer-cfg base linux-dev x)",
+            "cannot be overridden per layer",
+        ),
+        ("(deflayer-cfg base type-delay)", "missing a value"),
+        ("(deflayer-cfg base type-delay -1)", "must be 0-65535"),
+        ("(deflayer-cfg base key-repeat maybe)", "must be one of"),
+        ("(deflayer-cfg base type-delay 1 type-delay 2)", "Duplicate"),
+        ("(deflayer-cfg base)(deflayer-cfg base)", "duplicated"),
+    ] {
+        let source = format!("(defsrc a)(deflayer base a){cfgs}");
+        let err = parse_cfg_raw_string(
+            &source,
+            &mut s,
+            &PathBuf::from("test"),
+            &mut FileContentProvider {
+                get_file_content_fn: &mut |_| unimplemented!(),
+            },
+            DEF_LOCAL_KEYS,
+        )
+        .expect_err("invalid deflayer-cfg should error");
+        assert!(err.msg.contains(msg), "{}", err.msg);
+    }
+}
+
+#[test]
+fn parse_deftrainer() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut s = ParsedState::default();
+    let source = r#"
+(defsrc r f d)
+(deflayer base r f d)
+(deflayer learning r f d)
+(defkeys index-keys (r f))
+(deffingers left-index (index-keys) left-middle (d))
+(deftrainer learning bell)
+"#;
+    parse_cfg_raw_string(
+        source,
+        &mut s,
+        &PathBuf::from("test"),
+        &mut FileContentProvider {
+            get_file_content_fn: &mut |_| unimplemented!(),
+        },
+        DEF_LOCAL_KEYS,
+    )
+    .unwrap();
+    assert_eq!(s.trainer.finger_names, ["left-index", "left-middle"]);
+    assert_eq!(s.trainer.fingers[&OsCode::KEY_F], 0);
+    assert_eq!(s.trainer.fingers[&OsCode::KEY_D], 1);
+    assert_eq!(s.trainer.layers[&1], TrainerFeedback::Bell);
+
+    for (source, expected_err) in [
+        (
+            "(defsrc a) (deflayer base a) (deftrainer base bell)",
+            "deftrainer requires the fingers of keys to be set in deffingers",
+        ),
+        (
+            "(defsrc a) (deflayer base a) (deffingers left-pinky (a)) (deftrainer base beep)",
+            "deftrainer expects a layer name followed by a feedback",
+        ),
+        (
+            "(defsrc a) (deflayer base a) (deffingers left-pinky (a) left-ring (a))",
+            "key is assigned a finger more than once",
+        ),
+    ] {
+        let mut s = ParsedState::default();
+        let err = parse_cfg_raw_string(
+            source,
+            &mut s,
+            &PathBuf::from("test"),
+            &mut FileContentProvider {
+                get_file_content_fn: &mut |_| unimplemented!(),
+            },
+            DEF_LOCAL_KEYS,
+        )
+        .expect_err("invalid trainer should error");
+        assert!(err.msg.contains(expected_err), "{}", err.msg);
+    }
+}
+
+#[test]
+fn scancodes_round_trip_through_oscodes() {
+    for sc in (0x01..=0x7F).chain(0xE001..=0xE07F) {
+        let osc = scancode_to_oscode(sc).expect("scancode has an oscode");
+        assert_eq!(oscode_to_scancode(osc), Some(sc));
+    }
+    assert_eq!(scancode_to_oscode(0x80), None);
+    assert_eq!(scancode_to_oscode(0xE100), None);
+    assert_eq!(oscode_to_scancode(OsCode::KEY_A), None);
+}
+
+#[test]
+fn expand_platform_items() {
+    let source = "
+(defsrc a)
+(platform (linux macos) (deflayer unix a))
+(platform (win wintercept) (defalias x b) (platform (wintercept) (deflayer intercept a)))
+";
+    let names = |platform| {
+        expand_conditionals_with(parse(source, "test").unwrap(), &[platform], &|_| None)
+            .unwrap()
+            .iter()
+            .map(|item| item.t[1].atom(None).unwrap_or_default().to_owned())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names("linux"), ["a", "unix"]);
+    assert_eq!(names("win"), ["a", "x"]);
+    assert_eq!(names("wintercept"), ["a", "x", "intercept"]);
+
+    for source in [
+        "(platform)",
+        "(platform linux (defsrc a))",
+        "(platform (bsd) (defsrc a))",
+        "(platform (linux) a)",
+        "(platform (win) (platform (dos) (defsrc a)))",
+    ] {
+        expand_conditionals_with(parse(source, "test").unwrap(), &["linux"], &|_| None)
+            .expect_err("invalid platform should error");
+    }
+}
+
+#[test]
+fn expand_environment_items() {
+    let source = "
+(defsrc a)
+(environment (HOST laptop) (deflayer laptop a) (platform (win) (defalias x b)))
+(environment (HOST desktop) (deflayer desktop a))
+(environment (EMPTY \"\") (defalias empty a))
+";
+    let names = |host: &'static str| {
+        let env_var = move |name: &str| match name {
+            "HOST" => Some(host.to_owned()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        expand_conditionals_with(parse(source, "test").unwrap(), &["linux"], &env_var)
+            .unwrap()
+            .iter()
+            .map(|item| item.t[1].atom(None).unwrap_or_default().to_owned())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names("laptop"), ["a", "laptop", "empty"]);
+    assert_eq!(names("desktop"), ["a", "desktop", "empty"]);
+    assert_eq!(names("server"), ["a", "empty"]);
+
+    for source in [
+        "(environment)",
+        "(environment HOST (defsrc a))",
+        "(environment (HOST) (defsrc a))",
+        "(environment (HOST a b) (defsrc a))",
+        "(environment (HOST (a)) (defsrc a))",
+        "(environment (HOST a) a)",
+        "(environment (HOST a) (platform (dos) (defsrc a)))",
+    ] {
+        expand_conditionals_with(parse(source, "test").unwrap(), &["linux"], &|_| None)
+            .expect_err("invalid environment should error");
+    }
+}
+
+#[test]
+fn parse_defcfg_lifecycle_actions() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let cfg = new_from_str(
+        "
+(defcfg
+  process-unmapped-keys yes
+  on-startup (multi (layer-switch other) a)
+  on-shutdown @b
+)
+(defalias b b)
+(defsrc a)
+(deflayer base a)
+(deflayer other (layer-switch base))
+",
+    )
+    .unwrap();
+    assert_eq!(
+        cfg.items.get("on-startup").map(String::as_str),
+        Some("(multi (layer-switch other) a)")
+    );
+    let fake_key_action = |coord: Option<Coord>| {
+        let Coord { x, y } = coord.expect("lifecycle action is a fake key");
+        cfg.layout.b().layers[0][usize::from(x)][usize::from(y)]
+    };
+    assert!(matches!(
+        fake_key_action(cfg.lifecycle_keys.on_startup),
+        Action::MultipleActions(_)
+    ));
+    assert_eq!(
+        fake_key_action(cfg.lifecycle_keys.on_shutdown),
+        Action::KeyCode(KeyCode::B)
+    );
+    // The layer that on-startup switches to is reachable.
+    assert!(analyze_cfg(&cfg).is_empty());
+
+    for source in [
+        "(defcfg on-startup (nonexistent-action)) (defsrc a) (deflayer base a)",
+        "(defcfg on-startup a on-startup b) (defsrc a) (deflayer base a)",
+        "(defcfg log-layer-changes (a)) (defsrc a) (deflayer base a)",
+    ] {
+        assert!(new_from_str(source).is_err(), "{source}");
+    }
+}
@@ -0,0 +1,123 @@
+//! Parses the `deffingers` and `deftrainer` configuration items. `deffingers` assigns the finger
+//! that types each key. `deftrainer` makes a layer block keys that are pressed while another key
+//! of the same finger is held, since one of the two keys must have been typed with the wrong
+//! finger.
+//!
+//! Example:
+//!
+//! (deffingers
+//!   left-index (r f v t g b)
+//!   right-index (y h n u j m)
+//! )
+//! (deftrainer colemak notify)
+
+use super::sexpr::SExpr;
+use super::*;
+
+const DEFTRAINER_ERR: &str =
+    "deftrainer expects a layer name followed by a feedback: bell, notify, or none";
+
+/// What happens when the trainer blocks a key, other than the key not being output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrainerFeedback {
+    /// Ring the bell of the terminal that kanata runs in.
+    Bell,
+    /// Show a desktop notification.
+    Notify,
+    None,
+}
+
+/// The layers that `deftrainer` applies to and the finger of each key from `deffingers`.
+#[derive(Debug, Clone, Default)]
+pub struct Trainer {
+    /// The finger names, indexed by the finger numbers of `fingers`.
+    pub finger_names: Vec<String>,
+    pub fingers: HashMap<OsCode, usize>,
+    /// The feedback of each layer that has a trainer, keyed by layer index.
+    pub layers: HashMap<usize, TrainerFeedback>,
+}
+
+pub(super) fn parse_trainer(
+    finger_exprs: &[&Vec<SExpr>],
+    trainer_exprs: &[&Vec<SExpr>],
+    s: &mut ParsedState,
+) -> Result<()> {
+    let mut trainer = Trainer::default();
+    for expr in finger_exprs {
+        let mut subexprs = check_first_expr(expr.iter(), "deffingers")?;
+        while let Some(finger_expr) = subexprs.next() {
+            let finger = finger_expr
+                .atom(s.vars())
+                .ok_or_else(|| anyhow_expr!(finger_expr, "expected a finger name"))?;
+            let idx = match trainer.finger_names.iter().position(|f| f == finger) {
+                Some(idx) => idx,
+                None => {
+                    trainer.finger_names.push(finger.to_owned());
+                    trainer.finger_names.len() - 1
+                }
+            };
+            let keys_expr = match subexprs.next() {
+                Some(v) => v,
+                None => bail_expr!(
+                    finger_expr,
+                    "finger has no keys - you should add a list of keys."
+                ),
+            };
+            let keys = keys_expr
+                .list(s.vars())
+                .ok_or_else(|| anyhow_expr!(keys_expr, "finger keys must be a list of keys"))?;
+            for key in keys {
+                let names = match s.key_set(key) {
+                    Some(set) => set.to_vec(),
+                    None => match key.atom(s.vars()) {
+                        Some(k) => vec![k.to_owned()],
+                        None => {
+                            bail_expr!(key, "string of a known key is expected, found list instead")
+                        }
+                    },
+                };
+                for name in names {
+                    let osc = str_to_oscode(&name)
+                        .ok_or_else(|| anyhow_expr!(key, "string of a known key is expected"))?;
+                    if trainer.fingers.insert(osc, idx).is_some() {
+                        bail_expr!(key, "key is assigned a finger more than once: {name}");
+                    }
+                }
+            }
+        }
+    }
+    for expr in trainer_exprs {
+        let mut subexprs = check_first_expr(expr.iter(), "deftrainer")?;
+        let (Some(layer_expr), Some(feedback_expr), None) =
+            (subexprs.next(), subexprs.next(), subexprs.next())
+        else {
+            bail!(DEFTRAINER_ERR);
+        };
+        let layer_name = layer_expr
+            .atom(s.vars())
+            .ok_or_else(|| anyhow_expr!(layer_expr, "{DEFTRAINER_ERR}"))?;
+        let layer_idx = *s.layer_idxs.get(layer_name).ok_or_else(|| {
+            anyhow_expr!(
+                layer_expr,
+                "layer {layer_name} must be defined with deflayer before deftrainer"
+            )
+        })?;
+        let feedback = match feedback_expr.atom(s.vars()) {
+            Some("bell") => TrainerFeedback::Bell,
+            Some("notify") => TrainerFeedback::Notify,
+            Some("none") => TrainerFeedback::None,
+            _ => bail_expr!(feedback_expr, "{DEFTRAINER_ERR}"),
+        };
+        if trainer.layers.insert(layer_idx, feedback).is_some() {
+            bail_expr!(
+                layer_expr,
+                "deftrainer for layer {layer_name} is duplicated"
+            );
+        }
+    }
+    if !trainer.layers.is_empty() && trainer.fingers.is_empty() {
+        bail!("deftrainer requires the fingers of keys to be set in deffingers");
+    }
+    s.trainer = trainer;
+    Ok(())
+}
@@ -11,7 +11,17 @@ use crate::keys::OsCode;
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum CustomAction {
     Cmd(Vec<String>),
+    CmdOnResult {
+        cmd: Vec<String>,
+        on_success: Option<FakeKeyCallback>,
+        on_failure: Option<FakeKeyCallback>,
+    },
     CmdOutputKeys(Vec<String>),
+    /// Run the command and type its output as text.
+    CmdOutputText {
+        cmd: Vec<String>,
+        typing: TextTypingCfg,
+    },
     Unicode(char),
     Mouse(Btn),
     MouseTap(Btn),
@@ -49,13 +59,100 @@ pub enum CustomAction {
     MoveMouseSpeed {
         speed: u16,
     },
+    /// Tap the fake key on press, then repeat the tap while held. The interval between repeats
+    /// shrinks from `start_interval` to `end_interval` over `accel_time` milliseconds.
+    RepeatAccel {
+        coord: Coord,
+        start_interval: u16,
+        end_interval: u16,
+        accel_time: u16,
+    },
+    /// Press and release the key through the given output device when the output is split into
+    /// multiple devices.
+    OutputDeviceKey {
+        device: OutputDevice,
+        key: OsCode,
+    },
+    /// Press the button of the virtual gamepad while the action is held.
+    GamepadButton(GamepadButton),
+    /// Set the axis of the virtual gamepad to the value while the action is held.
+    GamepadAxis {
+        axis: GamepadAxis,
+        value: i32,
+    },
+    /// Move the axis of the virtual gamepad towards the end of its range in `direction` while the
+    /// action is held. The axis takes `ramp_up` milliseconds to move from rest to the end and
+    /// `ramp_down` milliseconds to return. `curve` shapes the value over the ramp. Two actions
+    /// with opposite directions on the same axis cancel each other out while both are held.
+    GamepadAxisRamp {
+        axis: GamepadAxis,
+        direction: AxisDirection,
+        ramp_up: u16,
+        ramp_down: u16,
+        curve: AxisCurve,
+    },
+    /// Send a MIDI note-on message on press and the matching note-off message on release. The
+    /// channel is 1-16.
+    MidiNote {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    /// Send a MIDI control change message on press. The channel is 1-16.
+    MidiControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    /// Send an OSC message to the target of `osc-target`.
+    Osc {
+        address: String,
+        args: Vec<OscArg>,
+    },
+    /// Send an HTTP POST request with the body to the URL.
+    HttpPost {
+        url: String,
+        body: String,
+    },
+    /// Show a desktop notification.
+    Notify {
+        title: String,
+        body: String,
+    },
+    /// Send a command to the window manager.
+    WindowManager(WmCommand),
+    /// Replace the contents of the clipboard with the text.
+    ClipboardSet(String),
+    /// Save the contents of the clipboard to the numbered slot.
+    ClipboardSaveSlot(u16),
+    /// Replace the contents of the clipboard with the contents of the numbered slot.
+    ClipboardRestoreSlot(u16),
     SequenceCancel,
     SequenceLeader(u16, SequenceInputMode),
     LiveReload,
     LiveReloadNext,
     LiveReloadPrev,
+    /// Open the active configuration file in an editor.
+    EditConfig,
     Repeat,
+    /// Release all modifiers and press again the ones that kanata intends to be held.
+    ResyncMods,
+    /// Toggle the fn-lock flag that can be checked in switch.
+    FnLockToggle,
+    /// Toggle whether kanata inverts the direction of scrolling.
+    InvertScrollToggle,
+    /// Send the bindings of the current layer to TCP clients, and type them if `type_text` is set.
+    ShowHelp {
+        type_text: bool,
+    },
+    /// Log the number of presses of each binding since kanata started or the layers were reloaded.
+    ShowActionUsage,
     CancelMacroOnRelease,
+    /// Stop all running macros unless the key is physically held. Placed before the presses of
+    /// `macro-guarded` so that its macro stops as soon as the guard key is released.
+    MacroGuard(OsCode),
+    /// Stop all running macros, including repeating macros and dynamic macro replays.
+    CancelMacros,
     DynamicMacroRecord(u16),
     DynamicMacroRecordStop(u16),
     DynamicMacroPlay(u16),
@@ -68,6 +165,141 @@ pub enum CustomAction {
     Unmodded {
         key: KeyCode,
     },
+    /// Switch the base layer to the layer that follows the current base layer in the list. The
+    /// contained values are keyberon layer indexes.
+    LayerCycle(Vec<usize>),
+    /// The right action of `mods-passthrough`. It is replaced by the defsrc action at the
+    /// position the action is placed in when the layers are parsed, and does nothing otherwise.
+    DefsrcKey,
+}
+
+/// A virtual output device that an action can target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputDevice {
+    Keyboard,
+    Mouse,
+}
+
+/// A button of the virtual gamepad output device. The names follow the Linux gamepad API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GamepadButton {
+    South,
+    East,
+    North,
+    West,
+    TL,
+    TR,
+    TL2,
+    TR2,
+    Select,
+    Start,
+    Mode,
+    ThumbL,
+    ThumbR,
+    DpadUp,
+    DpadDown,
+    DpadLeft,
+    DpadRight,
+}
+
+/// An axis of the virtual gamepad output device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GamepadAxis {
+    LeftX,
+    LeftY,
+    RightX,
+    RightY,
+    LeftTrigger,
+    RightTrigger,
+    DpadX,
+    DpadY,
+}
+
+/// An argument of an OSC message.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum OscArg {
+    Int(i32),
+    /// The bits of an `f32`, so that the argument can derive `Eq` and `Hash`.
+    Float(u32),
+    String(String),
+}
+
+/// How typed text is throttled and what a newline in the text is typed as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TextTypingCfg {
+    pub newline: NewlinePolicy,
+    /// Type printable ASCII characters as the keys that produce them on the US layout instead of
+    /// as unicode.
+    pub ascii_as_keys: bool,
+    /// Milliseconds between typed chunks.
+    pub interval: u16,
+    /// Number of characters typed at once.
+    pub chunk: u16,
+}
+
+impl Default for TextTypingCfg {
+    fn default() -> Self {
+        Self {
+            newline: NewlinePolicy::Enter,
+            ascii_as_keys: true,
+            interval: 1,
+            chunk: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NewlinePolicy {
+    Enter,
+    ShiftEnter,
+    Space,
+    Ignore,
+}
+
+/// The end of an axis range that an `axis` action moves towards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AxisDirection {
+    Negative,
+    Positive,
+}
+
+/// How an `axis` action maps the progress of its ramp to the value of the axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum AxisCurve {
+    #[default]
+    Linear,
+    /// Slow near rest and fast near the end of the range, for fine control around the center.
+    EaseIn,
+    /// Fast near rest and slow near the end of the range.
+    EaseOut,
+    /// Slow near rest and near the end of the range.
+    EaseInOut,
+}
+
+impl AxisCurve {
+    /// Maps the progress of a ramp, from 0.0 at rest to 1.0 at the end of the range, to the
+    /// fraction of the range that the axis is at.
+    pub fn apply(self, progress: f32) -> f32 {
+        let x = progress.clamp(0.0, 1.0);
+        match self {
+            AxisCurve::Linear => x,
+            AxisCurve::EaseIn => x * x,
+            AxisCurve::EaseOut => 1.0 - (1.0 - x) * (1.0 - x),
+            AxisCurve::EaseInOut => x * x * (3.0 - 2.0 * x),
+        }
+    }
+}
+
+impl GamepadAxis {
+    /// The inclusive range of values that the axis accepts. Every axis rests at 0.
+    pub fn range(self) -> (i32, i32) {
+        use GamepadAxis::*;
+        match self {
+            LeftX | LeftY | RightX | RightY => (-32768, 32767),
+            LeftTrigger | RightTrigger => (0, 255),
+            DpadX | DpadY => (-1, 1),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -93,6 +325,13 @@ pub enum FakeKeyAction {
     Toggle,
 }
 
+/// A fake key action to perform when a command started by kanata finishes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FakeKeyCallback {
+    pub coord: Coord,
+    pub action: FakeKeyAction,
+}
+
 /// An active waiting-for-idle state.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct FakeKeyOnIdle {
@@ -123,6 +362,18 @@ impl TryFrom<OsCode> for MWheelDirection {
     }
 }
 
+/// Window manager command of the `wm-*` actions.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum WmCommand {
+    /// Focus the window in the direction.
+    Focus(MoveDirection),
+    /// Move the focused window in the direction.
+    Move(MoveDirection),
+    /// Switch to the workspace with the name or number, or to the next or previous one for
+    /// `next` and `prev`.
+    Workspace(String),
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum MoveDirection {
     Up,
@@ -0,0 +1,148 @@
+// macOS uses the Linux key codes for `OsCode`. The virtual key codes of macOS, the `kVK_*`
+// constants of Carbon's `Events.h`, are converted to and from `OsCode` with the table below. It
+// is used by the macOS backend and to parse `deflocalkeys-macos` on all platforms.
+
+use super::OsCode;
+
+/// Pairs of a macOS virtual key code and its `OsCode`.
+#[rustfmt::skip]
+const MAC_KEYCODES: [(u16, OsCode); 120] = [
+    (0x00, OsCode::KEY_A),
+    (0x01, OsCode::KEY_S),
+    (0x02, OsCode::KEY_D),
+    (0x03, OsCode::KEY_F),
+    (0x04, OsCode::KEY_H),
+    (0x05, OsCode::KEY_G),
+    (0x06, OsCode::KEY_Z),
+    (0x07, OsCode::KEY_X),
+    (0x08, OsCode::KEY_C),
+    (0x09, OsCode::KEY_V),
+    (0x0A, OsCode::KEY_102ND), // ISO section
+    (0x0B, OsCode::KEY_B),
+    (0x0C, OsCode::KEY_Q),
+    (0x0D, OsCode::KEY_W),
+    (0x0E, OsCode::KEY_E),
+    (0x0F, OsCode::KEY_R),
+    (0x10, OsCode::KEY_Y),
+    (0x11, OsCode::KEY_T),
+    (0x12, OsCode::KEY_1),
+    (0x13, OsCode::KEY_2),
+    (0x14, OsCode::KEY_3),
+    (0x15, OsCode::KEY_4),
+    (0x16, OsCode::KEY_6),
+    (0x17, OsCode::KEY_5),
+    (0x18, OsCode::KEY_EQUAL),
+    (0x19, OsCode::KEY_9),
+    (0x1A, OsCode::KEY_7),
+    (0x1B, OsCode::KEY_MINUS),
+    (0x1C, OsCode::KEY_8),
+    (0x1D, OsCode::KEY_0),
+    (0x1E, OsCode::KEY_RIGHTBRACE),
+    (0x1F, OsCode::KEY_O),
+    (0x20, OsCode::KEY_U),
+    (0x21, OsCode::KEY_LEFTBRACE),
+    (0x22, OsCode::KEY_I),
+    (0x23, OsCode::KEY_P),
+    (0x24, OsCode::KEY_ENTER),
+    (0x25, OsCode::KEY_L),
+    (0x26, OsCode::KEY_J),
+    (0x27, OsCode::KEY_APOSTROPHE),
+    (0x28, OsCode::KEY_K),
+    (0x29, OsCode::KEY_SEMICOLON),
+    (0x2A, OsCode::KEY_BACKSLASH),
+    (0x2B, OsCode::KEY_COMMA),
+    (0x2C, OsCode::KEY_SLASH),
+    (0x2D, OsCode::KEY_N),
+    (0x2E, OsCode::KEY_M),
+    (0x2F, OsCode::KEY_DOT),
+    (0x30, OsCode::KEY_TAB),
+    (0x31, OsCode::KEY_SPACE),
+    (0x32, OsCode::KEY_GRAVE),
+    (0x33, OsCode::KEY_BACKSPACE),
+    (0x35, OsCode::KEY_ESC),
+    (0x36, OsCode::KEY_RIGHTMETA),
+    (0x37, OsCode::KEY_LEFTMETA),
+    (0x38, OsCode::KEY_LEFTSHIFT),
+    (0x39, OsCode::KEY_CAPSLOCK),
+    (0x3A, OsCode::KEY_LEFTALT),
+    (0x3B, OsCode::KEY_LEFTCTRL),
+    (0x3C, OsCode::KEY_RIGHTSHIFT),
+    (0x3D, OsCode::KEY_RIGHTALT),
+    (0x3E, OsCode::KEY_RIGHTCTRL),
+    (0x3F, OsCode::KEY_FN),
+    (0x40, OsCode::KEY_F17),
+    (0x41, OsCode::KEY_KPDOT),
+    (0x43, OsCode::KEY_KPASTERISK),
+    (0x45, OsCode::KEY_KPPLUS),
+    // The clear key is where other keyboards have num lock.
+    (0x47, OsCode::KEY_NUMLOCK),
+    (0x48, OsCode::KEY_VOLUMEUP),
+    (0x49, OsCode::KEY_VOLUMEDOWN),
+    (0x4A, OsCode::KEY_MUTE),
+    (0x4B, OsCode::KEY_KPSLASH),
+    (0x4C, OsCode::KEY_KPENTER),
+    (0x4E, OsCode::KEY_KPMINUS),
+    (0x4F, OsCode::KEY_F18),
+    (0x50, OsCode::KEY_F19),
+    (0x51, OsCode::KEY_KPEQUAL),
+    (0x52, OsCode::KEY_KP0),
+    (0x53, OsCode::KEY_KP1),
+    (0x54, OsCode::KEY_KP2),
+    (0x55, OsCode::KEY_KP3),
+    (0x56, OsCode::KEY_KP4),
+    (0x57, OsCode::KEY_KP5),
+    (0x58, OsCode::KEY_KP6),
+    (0x59, OsCode::KEY_KP7),
+    (0x5A, OsCode::KEY_F20),
+    (0x5B, OsCode::KEY_KP8),
+    (0x5C, OsCode::KEY_KP9),
+    (0x5D, OsCode::KEY_YEN),
+    (0x5E, OsCode::KEY_RO),
+    (0x5F, OsCode::KEY_KPCOMMA),
+    (0x60, OsCode::KEY_F5),
+    (0x61, OsCode::KEY_F6),
+    (0x62, OsCode::KEY_F7),
+    (0x63, OsCode::KEY_F3),
+    (0x64, OsCode::KEY_F8),
+    (0x65, OsCode::KEY_F9),
+    (0x66, OsCode::KEY_HANJA), // eisu
+    (0x67, OsCode::KEY_F11),
+    (0x68, OsCode::KEY_HANGEUL), // kana
+    (0x69, OsCode::KEY_F13),
+    (0x6A, OsCode::KEY_F16),
+    (0x6B, OsCode::KEY_F14),
+    (0x6D, OsCode::KEY_F10),
+    (0x6E, OsCode::KEY_COMPOSE),
+    (0x6F, OsCode::KEY_F12),
+    (0x71, OsCode::KEY_F15),
+    // The help key is where other keyboards have insert.
+    (0x72, OsCode::KEY_INSERT),
+    (0x73, OsCode::KEY_HOME),
+    (0x74, OsCode::KEY_PAGEUP),
+    (0x75, OsCode::KEY_DELETE),
+    (0x76, OsCode::KEY_F4),
+    (0x77, OsCode::KEY_END),
+    (0x78, OsCode::KEY_F2),
+    (0x79, OsCode::KEY_PAGEDOWN),
+    (0x7A, OsCode::KEY_F1),
+    (0x7B, OsCode::KEY_LEFT),
+    (0x7C, OsCode::KEY_RIGHT),
+    (0x7D, OsCode::KEY_DOWN),
+    (0x7E, OsCode::KEY_UP),
+];
+
+/// Converts a macOS virtual key code to an `OsCode`.
+pub fn mac_keycode_to_oscode(keycode: u16) -> Option<OsCode> {
+    MAC_KEYCODES
+        .iter()
+        .find(|(kc, _)| *kc == keycode)
+        .map(|(_, osc)| *osc)
+}
+
+/// Converts an `OsCode` to a macOS virtual key code, if macOS has a key for it.
+pub fn oscode_to_mac_keycode(osc: OsCode) -> Option<u16> {
+    MAC_KEYCODES
+        .iter()
+        .find(|(_, o)| *o == osc)
+        .map(|(kc, _)| *kc)
+}
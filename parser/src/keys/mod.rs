@@ -5,11 +5,14 @@ use once_cell::sync::Lazy;
 use parking_lot::Mutex;
 use rustc_hash::FxHashMap as HashMap;
 
-#[cfg(any(target_os = "linux", target_os = "unknown"))]
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "unknown"))]
 mod linux;
-#[cfg(any(target_os = "linux", target_os = "unknown"))]
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "unknown"))]
 pub use linux::*;
 
+mod macos;
+pub use macos::*;
+
 #[cfg(target_os = "windows")]
 mod windows;
 #[cfg(target_os = "windows")]
@@ -242,16 +245,35 @@ pub fn str_to_oscode(s: &str) -> Option<OsCode> {
         "mfwd" | "mouseforward" => OsCode::BTN_EXTRA,
         "mbck" | "mousebackward" => OsCode::BTN_SIDE,
 
-        // NOTE: these are linux and interception-only due to missing implementation for LLHOOK
-        #[cfg(any(target_os = "linux", target_os = "unknown", feature = "interception_driver"))]
         "mwu" | "mousewheelup" => OsCode::MouseWheelUp,
-        #[cfg(any(target_os = "linux", target_os = "unknown", feature = "interception_driver"))]
         "mwd" | "mousewheeldown" => OsCode::MouseWheelDown,
-        #[cfg(any(target_os = "linux", target_os = "unknown", feature = "interception_driver"))]
         "mwl" | "mousewheelleft" => OsCode::MouseWheelLeft,
-        #[cfg(any(target_os = "linux", target_os = "unknown", feature = "interception_driver"))]
         "mwr" | "mousewheelright" => OsCode::MouseWheelRight,
 
+        // NOTE: these are linux-only since touchpads are only read on Linux
+        #[cfg(any(target_os = "linux", target_os = "unknown"))]
+        "sw3u" | "swipe3up" => OsCode::Swipe3Up,
+        #[cfg(any(target_os = "linux", target_os = "unknown"))]
+        "sw3d" | "swipe3down" => OsCode::Swipe3Down,
+        #[cfg(any(target_os = "linux", target_os = "unknown"))]
+        "sw3l" | "swipe3left" => OsCode::Swipe3Left,
+        #[cfg(any(target_os = "linux", target_os = "unknown"))]
+        "sw3r" | "swipe3right" => OsCode::Swipe3Right,
+        #[cfg(any(target_os = "linux", target_os = "unknown"))]
+        "sw4u" | "swipe4up" => OsCode::Swipe4Up,
+        #[cfg(any(target_os = "linux", target_os = "unknown"))]
+        "sw4d" | "swipe4down" => OsCode::Swipe4Down,
+        #[cfg(any(target_os = "linux", target_os = "unknown"))]
+        "sw4l" | "swipe4left" => OsCode::Swipe4Left,
+        #[cfg(any(target_os = "linux", target_os = "unknown"))]
+        "sw4r" | "swipe4right" => OsCode::Swipe4Right,
+
+        // NOTE: these are linux-only since switches are only read on Linux
+        #[cfg(any(target_os = "linux", target_os = "unknown"))]
+        "swlid" | "switchlid" => OsCode::SwitchLid,
+        #[cfg(any(target_os = "linux", target_os = "unknown"))]
+        "swtab" | "switchtabletmode" => OsCode::SwitchTabletMode,
+
         "hmpg" | "homepage" => OsCode::KEY_HOMEPAGE,
         "mdia" | "media" => OsCode::KEY_MEDIA,
         "mail" => OsCode::KEY_MAIL,
@@ -266,6 +288,12 @@ pub fn str_to_oscode(s: &str) -> Option<OsCode> {
         #[cfg(any(target_os = "linux", target_os = "unknown"))]
         "zzz" | "sleep" => OsCode::KEY_SLEEP,
 
+        #[cfg(target_os = "windows")]
+        _ if s.starts_with("sc-0x") => {
+            let sc = u16::from_str_radix(&s["sc-0x".len()..], 16).ok()?;
+            return scancode_to_oscode(sc);
+        }
+
         _ => {
             let custom_mappings = CUSTOM_STRS_TO_OSCODES.lock();
             match custom_mappings.get(s) {
@@ -276,6 +304,61 @@ pub fn str_to_oscode(s: &str) -> Option<OsCode> {
     })
 }
 
+/// Key names that `oscode_to_str` returns, tried in order. The first name that maps to a key is
+/// used, so shorter names come before their aliases.
+#[rustfmt::skip]
+const KEY_NAMES: &[&str] = &[
+    "esc", "f1", "f2", "f3", "f4", "f5", "f6", "f7", "f8", "f9", "f10", "f11", "f12",
+    "f13", "f14", "f15", "f16", "f17", "f18", "f19", "f20", "f21", "f22", "f23", "f24",
+    "grv", "1", "2", "3", "4", "5", "6", "7", "8", "9", "0", "-", "=", "bspc",
+    "tab", "q", "w", "e", "r", "t", "y", "u", "i", "o", "p", "[", "]", "\\",
+    "caps", "a", "s", "d", "f", "g", "h", "j", "k", "l", ";", "'", "ret",
+    "lsft", "102d", "z", "x", "c", "v", "b", "n", "m", ",", ".", "/", "rsft",
+    "lctl", "lmet", "lalt", "spc", "ralt", "rmet", "menu", "rctl",
+    "prtsc", "slck", "pause", "ins", "home", "pgup", "del", "end", "pgdn",
+    "up", "left", "down", "rght",
+    "nlck", "kp/", "kp*", "kp-", "kp+", "kprt", "kp.",
+    "kp0", "kp1", "kp2", "kp3", "kp4", "kp5", "kp6", "kp7", "kp8", "kp9", "kp=",
+    "mute", "vold", "volu", "prev", "pp", "next", "brdn", "brup", "bldn", "blup",
+    "cnv", "ncnv", "kana", "ro", "¥", "calc", "mail", "hmpg",
+];
+
+/// Returns the name of a common key, e.g. to write it in a configuration. Keys that are not in
+/// `KEY_NAMES` have no name.
+pub fn oscode_to_str(osc: OsCode) -> Option<&'static str> {
+    KEY_NAMES
+        .iter()
+        .copied()
+        .find(|&name| str_to_oscode(name) == Some(osc))
+}
+
+/// The first `OsCode` of the range used for keys that are written as hardware scancodes, e.g.
+/// `sc-0x7D`. On Windows no key name maps into this range, so the codes cannot clash with virtual
+/// keys. Scancodes without prefix use the first 0x80 codes and scancodes with the E0 prefix use
+/// the next 0x80.
+const SCANCODE_OSCODE_START: u16 = 0x190;
+
+/// Returns the `OsCode` of a hardware scancode, written like `0x7D`, or `0xE07D` for a scancode
+/// with the E0 prefix.
+pub fn scancode_to_oscode(sc: u16) -> Option<OsCode> {
+    let code = match sc {
+        0x01..=0x7F => SCANCODE_OSCODE_START + sc,
+        0xE001..=0xE07F => SCANCODE_OSCODE_START + 0x80 + (sc & 0xFF),
+        _ => return None,
+    };
+    OsCode::from_u16(code)
+}
+
+/// Returns the hardware scancode of an `OsCode` from `scancode_to_oscode`, in the same format.
+pub fn oscode_to_scancode(osc: OsCode) -> Option<u16> {
+    let code = osc.as_u16();
+    match code.checked_sub(SCANCODE_OSCODE_START)? {
+        sc @ 0x01..=0x7F => Some(sc),
+        sc @ 0x81..=0xFF => Some(0xE000 | (sc - 0x80)),
+        _ => None,
+    }
+}
+
 /// This is a shameless copy of evdev_rs::enums::EV_KEY.
 /// I've added the Copy trait and I'll be able
 /// to added my own Impl(s) to it
@@ -1038,6 +1121,22 @@ pub enum OsCode {
     MouseWheelLeft = 747,
     MouseWheelRight = 748,
 
+    // Like the mouse wheel events, touchpad swipes are not EV_KEY events but are given key codes
+    // so that they can be added to defsrc.
+    Swipe3Up = 749,
+    Swipe3Down = 750,
+    Swipe3Left = 751,
+    Swipe3Right = 752,
+    Swipe4Up = 753,
+    Swipe4Down = 754,
+    Swipe4Left = 755,
+    Swipe4Right = 756,
+
+    // Like the touchpad swipes, switches are not EV_KEY events but are given key codes so that they
+    // can be added to defsrc. Turning a switch on presses its key and turning it off releases it.
+    SwitchLid = 757,
+    SwitchTabletMode = 758,
+
     KEY_MAX = 767,
 }
 
@@ -10,7 +10,10 @@ pub const LAYER_COLUMNS: usize = 2;
 pub const MAX_LAYERS: usize = 25;
 pub const ACTUAL_NUM_LAYERS: usize = MAX_LAYERS * 2;
 
-pub type KanataLayers = Layers<
+/// The layers used by the layout. Rows that are the same in several layers are shared, e.g. the
+/// layers beyond the defined ones are all transparent and share a single row. Rows that are mostly
+/// transparent only store the actions that are not.
+pub type KanataLayers = LayerRows<
     'static,
     KEYS_IN_ROW,
     LAYER_COLUMNS,
@@ -18,21 +21,22 @@ pub type KanataLayers = Layers<
     &'static &'static [&'static CustomAction],
 >;
 
-type Row = [kanata_keyberon::action::Action<'static, &'static &'static [&'static CustomAction]>;
+pub type KanataLayerRow = LayerRow<'static, KEYS_IN_ROW, &'static &'static [&'static CustomAction]>;
+
+pub type Row = [kanata_keyberon::action::Action<'static, &'static &'static [&'static CustomAction]>;
     KEYS_IN_ROW];
 
-pub fn new_layers() -> Box<KanataLayers> {
-    let boxed_slice: Box<[[Row; LAYER_COLUMNS]]> = {
-        let mut layers = Vec::with_capacity(ACTUAL_NUM_LAYERS);
-        for _ in 0..ACTUAL_NUM_LAYERS {
-            layers.push([
-                [KanataAction::Trans; KEYS_IN_ROW],
-                [KanataAction::Trans; KEYS_IN_ROW],
-            ]);
-        }
-        layers
+/// The full table of each layer while the configuration is being parsed.
+pub type ParsedLayers = Box<[[Row; LAYER_COLUMNS]]>;
+
+/// Creates transparent tables for `count` layers.
+pub fn new_layers(count: usize) -> ParsedLayers {
+    let mut layers = Vec::with_capacity(count);
+    for _ in 0..count {
+        layers.push([
+            [KanataAction::Trans; KEYS_IN_ROW],
+            [KanataAction::Trans; KEYS_IN_ROW],
+        ]);
     }
-    .into_boxed_slice();
-    let ptr = Box::into_raw(boxed_slice) as *mut KanataLayers;
-    unsafe { Box::from_raw(ptr) }
+    layers.into_boxed_slice()
 }
@@ -1,5 +1,8 @@
 use kanata_keyberon::key_code::KeyCode;
 
+use crate::keys::OsCode;
+use crate::trie::{GetOrDescendentExistsResult, Trie, TrieVal};
+
 pub const MASK_KEYCODES: u16 = 0x03FF;
 pub const MASK_MODDED: u16 = 0xFC00;
 
@@ -15,8 +18,375 @@ pub fn mod_mask_for_keycode(kc: KeyCode) -> u16 {
     }
 }
 
+/// Returns whether typing the sequence item, which is a key with modifier bits, outputs a
+/// character that can be erased with a backspace. Keys pressed along with control, alt or meta
+/// are assumed to be shortcuts that do not type anything.
+pub fn outputs_character(item: u16) -> bool {
+    const NON_TYPING_MODS: u16 = 0x4000 | 0x2000 | 0x0800;
+    if item & NON_TYPING_MODS != 0 {
+        return false;
+    }
+    use OsCode::*;
+    matches!(
+        OsCode::from(item & MASK_KEYCODES),
+        KEY_A
+            | KEY_B
+            | KEY_C
+            | KEY_D
+            | KEY_E
+            | KEY_F
+            | KEY_G
+            | KEY_H
+            | KEY_I
+            | KEY_J
+            | KEY_K
+            | KEY_L
+            | KEY_M
+            | KEY_N
+            | KEY_O
+            | KEY_P
+            | KEY_Q
+            | KEY_R
+            | KEY_S
+            | KEY_T
+            | KEY_U
+            | KEY_V
+            | KEY_W
+            | KEY_X
+            | KEY_Y
+            | KEY_Z
+            | KEY_1
+            | KEY_2
+            | KEY_3
+            | KEY_4
+            | KEY_5
+            | KEY_6
+            | KEY_7
+            | KEY_8
+            | KEY_9
+            | KEY_0
+            | KEY_MINUS
+            | KEY_EQUAL
+            | KEY_LEFTBRACE
+            | KEY_RIGHTBRACE
+            | KEY_SEMICOLON
+            | KEY_APOSTROPHE
+            | KEY_GRAVE
+            | KEY_BACKSLASH
+            | KEY_COMMA
+            | KEY_DOT
+            | KEY_SLASH
+            | KEY_102ND
+            | KEY_SPACE
+            | KEY_TAB
+            | KEY_ENTER
+            | KEY_KP0
+            | KEY_KP1
+            | KEY_KP2
+            | KEY_KP3
+            | KEY_KP4
+            | KEY_KP5
+            | KEY_KP6
+            | KEY_KP7
+            | KEY_KP8
+            | KEY_KP9
+            | KEY_KPDOT
+            | KEY_KPPLUS
+            | KEY_KPMINUS
+            | KEY_KPASTERISK
+            | KEY_KPSLASH
+            | KEY_KPENTER
+            | KEY_KPEQUAL
+            | KEY_KPCOMMA
+    )
+}
+
+/// An item of a `defseq` key list that contains `any` or `count`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequencePatternItem {
+    /// A key, with modifier bits as in exact sequences.
+    Key(u16),
+    /// Any one key.
+    AnyKey,
+    /// One or more digit keys. The number they type is the number of times the fake key is tapped.
+    Count,
+}
+
+/// The largest number that `count` matches. Typing a digit that makes the number larger ends the
+/// sequence without a match, so that a count cannot tap a fake key for minutes.
+pub const MAX_SEQUENCE_COUNT: u16 = 999;
+
+/// The result of matching typed keys against the defined sequences.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SequenceMatch {
+    /// No sequence starts with the typed keys.
+    NotMatched,
+    /// At least one sequence starts with the typed keys.
+    Prefix,
+    /// The typed keys complete a sequence, whose fake key should be tapped `count` times.
+    Complete { fake_key: TrieVal, count: u16 },
+    /// The typed keys complete a sequence but are also the start of a longer sequence. The
+    /// sequence completes if no key that continues a sequence is typed before the timeout.
+    Ambiguous { fake_key: TrieVal, count: u16 },
+}
+
+/// The sequences defined with `defseq`. Sequences of exact keys are looked up in a trie, while
+/// sequences with `any` or `count` are matched one by one.
+#[derive(Debug, Clone)]
+pub struct KeySequences {
+    exact: Trie,
+    patterns: Vec<(Vec<SequencePatternItem>, TrieVal)>,
+}
+
+impl KeySequences {
+    pub fn new(exact: Trie, patterns: Vec<(Vec<SequencePatternItem>, TrieVal)>) -> Self {
+        Self { exact, patterns }
+    }
+
+    /// Match the typed keys against the sequences. If several sequences match the typed keys,
+    /// exact sequences are checked before the sequences with `any` or `count`, which are checked
+    /// in the order they are defined.
+    pub fn find(&self, typed: &[u16]) -> SequenceMatch {
+        let typed_key = typed.to_vec();
+        let (mut complete, mut is_prefix) = match self.exact.get_or_descendant_exists(&typed_key) {
+            GetOrDescendentExistsResult::HasValue(fake_key) => {
+                (Some((fake_key, 1)), self.exact.longer_exists(&typed_key))
+            }
+            GetOrDescendentExistsResult::InTrie => (None, true),
+            GetOrDescendentExistsResult::NotInTrie => (None, false),
+        };
+        for (items, fake_key) in self.patterns.iter() {
+            match match_pattern(items, typed, None) {
+                PatternMatch::Complete(count) => {
+                    complete = complete.or(Some((*fake_key, count.unwrap_or(1))));
+                }
+                PatternMatch::Prefix => is_prefix = true,
+                PatternMatch::NotMatched => {}
+            }
+        }
+        match (complete, is_prefix) {
+            (Some((fake_key, count)), false) => SequenceMatch::Complete { fake_key, count },
+            (Some((fake_key, count)), true) => SequenceMatch::Ambiguous { fake_key, count },
+            (None, true) => SequenceMatch::Prefix,
+            (None, false) => SequenceMatch::NotMatched,
+        }
+    }
+
+    /// Returns the sequences that can never complete because another sequence completes instead
+    /// for every key list that they match, as `(shadowed, shadowing)` pairs of fake keys.
+    pub fn shadowed(&self) -> Vec<(TrieVal, TrieVal)> {
+        let mut shadowed = vec![];
+        // Exact sequences are checked first, and a pattern always matches more than one key list,
+        // so only a pattern that is defined earlier can shadow a pattern.
+        for (i, (items, fake_key)) in self.patterns.iter().enumerate() {
+            for (other, other_fake_key) in self.patterns[..i].iter() {
+                if other.len() == items.len() && covers(other, items) {
+                    shadowed.push((*fake_key, *other_fake_key));
+                }
+            }
+        }
+        shadowed
+    }
+}
+
+/// Returns whether the pattern matches the start of every key list that starts with `items`.
+/// A `count` only covers a `count`, since it matches a different number of keys than other items.
+fn covers(pattern: &[SequencePatternItem], items: &[SequencePatternItem]) -> bool {
+    use SequencePatternItem::*;
+    pattern.len() <= items.len()
+        && pattern.iter().zip(items).all(|(p, item)| match (p, item) {
+            (AnyKey, AnyKey | Key(_)) | (Count, Count) => true,
+            (Key(p), Key(item)) => p == item,
+            _ => false,
+        })
+}
+
+enum PatternMatch {
+    NotMatched,
+    Prefix,
+    Complete(Option<u16>),
+}
+
+fn match_pattern(items: &[SequencePatternItem], typed: &[u16], count: Option<u16>) -> PatternMatch {
+    use SequencePatternItem::*;
+    let (item, typed_key) = match (items.first(), typed.first()) {
+        (None, None) => return PatternMatch::Complete(count),
+        (None, Some(_)) => return PatternMatch::NotMatched,
+        (Some(_), None) => return PatternMatch::Prefix,
+        (Some(item), Some(typed_key)) => (item, *typed_key),
+    };
+    match item {
+        Key(key) if *key != typed_key => PatternMatch::NotMatched,
+        Key(_) | AnyKey => match_pattern(&items[1..], &typed[1..], count),
+        Count => {
+            // The number can be followed by a digit key in the pattern, so try every length.
+            let mut res = PatternMatch::NotMatched;
+            let mut number: u16 = 0;
+            for (i, digit) in typed.iter().copied().map(digit_value).enumerate() {
+                let Some(digit) = digit else {
+                    break;
+                };
+                number = number * 10 + digit;
+                if number > MAX_SEQUENCE_COUNT {
+                    break;
+                }
+                match match_pattern(&items[1..], &typed[i + 1..], Some(number)) {
+                    complete @ PatternMatch::Complete(_) => return complete,
+                    PatternMatch::Prefix => res = PatternMatch::Prefix,
+                    PatternMatch::NotMatched => {}
+                }
+            }
+            res
+        }
+    }
+}
+
+/// Returns the number of a digit key typed without modifiers.
+fn digit_value(item: u16) -> Option<u16> {
+    use OsCode::*;
+    if item & MASK_MODDED != 0 {
+        return None;
+    }
+    Some(match OsCode::from(item) {
+        KEY_0 | KEY_KP0 => 0,
+        KEY_1 | KEY_KP1 => 1,
+        KEY_2 | KEY_KP2 => 2,
+        KEY_3 | KEY_KP3 => 3,
+        KEY_4 | KEY_KP4 => 4,
+        KEY_5 | KEY_KP5 => 5,
+        KEY_6 | KEY_KP6 => 6,
+        KEY_7 | KEY_KP7 => 7,
+        KEY_8 | KEY_KP8 => 8,
+        KEY_9 | KEY_KP9 => 9,
+        _ => return None,
+    })
+}
+
+#[test]
+fn sequence_patterns_match() {
+    use SequencePatternItem::*;
+    let key = |osc: OsCode| u16::from(osc);
+    let w = key(OsCode::KEY_W);
+    let fake_key = (1, 2);
+    let seqs = KeySequences::new(
+        Trie::new(),
+        vec![
+            (vec![Count, Key(w)], fake_key),
+            (vec![Key(key(OsCode::KEY_G)), AnyKey, Key(w)], fake_key),
+        ],
+    );
+    assert_eq!(seqs.find(&[key(OsCode::KEY_1)]), SequenceMatch::Prefix);
+    assert_eq!(
+        seqs.find(&[key(OsCode::KEY_1), key(OsCode::KEY_2)]),
+        SequenceMatch::Prefix
+    );
+    assert_eq!(
+        seqs.find(&[key(OsCode::KEY_1), key(OsCode::KEY_2), w]),
+        SequenceMatch::Complete {
+            fake_key,
+            count: 12
+        }
+    );
+    assert_eq!(seqs.find(&[w]), SequenceMatch::NotMatched);
+    let nines = [key(OsCode::KEY_9); 3];
+    assert_eq!(
+        seqs.find(&[&nines[..], &[w]].concat()),
+        SequenceMatch::Complete {
+            fake_key,
+            count: MAX_SEQUENCE_COUNT
+        }
+    );
+    assert_eq!(
+        seqs.find(&[&nines[..], &[key(OsCode::KEY_0)]].concat()),
+        SequenceMatch::NotMatched
+    );
+    assert_eq!(
+        seqs.find(&[key(OsCode::KEY_G), key(OsCode::KEY_X)]),
+        SequenceMatch::Prefix
+    );
+    assert_eq!(
+        seqs.find(&[key(OsCode::KEY_G), key(OsCode::KEY_X), w]),
+        SequenceMatch::Complete { fake_key, count: 1 }
+    );
+}
+
+#[test]
+fn shadowed_sequences() {
+    use SequencePatternItem::*;
+    let key = |osc: OsCode| u16::from(osc);
+    let (g, w) = (key(OsCode::KEY_G), key(OsCode::KEY_W));
+    let mut exact = Trie::new();
+    exact.insert(vec![g, w], (1, 0));
+    exact.insert(vec![w, g, key(OsCode::KEY_X)], (1, 5));
+    let seqs = KeySequences::new(
+        exact,
+        vec![
+            (vec![Key(g), AnyKey, AnyKey], (1, 6)),
+            (vec![Key(g), Key(w), AnyKey], (1, 1)),
+            (vec![Key(g), AnyKey], (1, 2)),
+            (vec![Key(w), AnyKey], (1, 3)),
+            (vec![Key(w), Key(g), Count], (1, 4)),
+            (vec![Count, AnyKey], (1, 7)),
+            (vec![Count, Key(w)], (1, 8)),
+        ],
+    );
+    // Shorter sequences do not shadow longer ones since they wait for the longer ones.
+    assert_eq!(seqs.shadowed(), vec![((1, 1), (1, 6)), ((1, 8), (1, 7))]);
+}
+
+#[test]
+fn overlapping_sequences_are_ambiguous() {
+    use SequencePatternItem::*;
+    let key = |osc: OsCode| u16::from(osc);
+    let (a, b, c) = (key(OsCode::KEY_A), key(OsCode::KEY_B), key(OsCode::KEY_C));
+    let mut exact = Trie::new();
+    exact.insert(vec![a, b], (1, 0));
+    exact.insert(vec![a, b, c], (1, 1));
+    exact.insert(vec![b], (1, 2));
+    let seqs = KeySequences::new(exact, vec![(vec![Key(b), AnyKey], (1, 3))]);
+    assert_eq!(seqs.find(&[a]), SequenceMatch::Prefix);
+    assert_eq!(
+        seqs.find(&[a, b]),
+        SequenceMatch::Ambiguous {
+            fake_key: (1, 0),
+            count: 1
+        }
+    );
+    assert_eq!(
+        seqs.find(&[a, b, c]),
+        SequenceMatch::Complete {
+            fake_key: (1, 1),
+            count: 1
+        }
+    );
+    assert_eq!(
+        seqs.find(&[b]),
+        SequenceMatch::Ambiguous {
+            fake_key: (1, 2),
+            count: 1
+        }
+    );
+    assert_eq!(
+        seqs.find(&[b, c]),
+        SequenceMatch::Complete {
+            fake_key: (1, 3),
+            count: 1
+        }
+    );
+}
+
+#[test]
+fn outputs_character_ignores_shortcuts() {
+    let a = u16::from(OsCode::KEY_A);
+    assert!(outputs_character(a));
+    assert!(outputs_character(a | mod_mask_for_keycode(KeyCode::LShift)));
+    assert!(outputs_character(a | mod_mask_for_keycode(KeyCode::RAlt)));
+    assert!(!outputs_character(a | mod_mask_for_keycode(KeyCode::LCtrl)));
+    assert!(!outputs_character(u16::from(OsCode::KEY_LEFTSHIFT)));
+    assert!(!outputs_character(u16::from(OsCode::KEY_LEFT)));
+}
+
 #[test]
 fn keys_fit_within_mask() {
-    use crate::keys::OsCode;
     assert!(MASK_KEYCODES >= u16::from(OsCode::KEY_MAX));
 }
@@ -34,10 +34,21 @@ impl Trie {
         self.inner.get_raw_descendant(key).is_some()
     }
 
+    /// Returns whether a key that is longer than `key` and starts with it exists.
+    pub fn longer_exists(&self, key: &TrieKey) -> bool {
+        self.inner
+            .get_raw_descendant(key)
+            .is_some_and(|subtrie| subtrie.keys().any(|k| k.len() > key.len()))
+    }
+
     pub fn insert(&mut self, key: TrieKey, val: TrieVal) {
         self.inner.insert(key, val);
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = (&TrieKey, &TrieVal)> {
+        self.inner.iter()
+    }
+
     pub fn get_or_descendant_exists(&self, key: &TrieKey) -> GetOrDescendentExistsResult {
         let descendant = self.inner.get_raw_descendant(key);
         match descendant {
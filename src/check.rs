@@ -0,0 +1,138 @@
+//! Implements `--check`: parse the configuration files, warn about suspicious parts of them and
+//! run the tests defined in `deftest` against a simulated keyboard instead of real devices.
+
+use anyhow::{bail, Result};
+use std::path::PathBuf;
+
+use kanata_keyberon::key_code::KeyCode;
+use kanata_keyberon::layout::Event;
+use kanata_parser::cfg::{self, CfgTest, OverrideStates, SimInput, SimOutput};
+use kanata_parser::keys::OsCode;
+
+/// Parse every configuration file and run its tests. Returns an error if any configuration fails
+/// to parse or any test fails.
+pub fn check_cfgs(paths: &[PathBuf]) -> Result<()> {
+    let mut failed_count = 0;
+    for path in paths {
+        let tests = match cfg::new_from_file(path) {
+            Ok(cfg) => {
+                for warning in cfg::analyze_cfg(&cfg) {
+                    log::warn!("{}: {warning}", path.display());
+                }
+                cfg.tests
+            }
+            Err(e) => {
+                log::error!("{e:?}");
+                bail!("failed to parse config file {}", path.display());
+            }
+        };
+        log::info!("{}: parsed successfully", path.display());
+        for test in tests.iter() {
+            // Use a freshly parsed configuration for each test so that state from a previous test
+            // cannot affect the result of the next one.
+            let mut cfg = match cfg::new_from_file(path) {
+                Ok(cfg) => cfg,
+                Err(e) => {
+                    log::error!("{e:?}");
+                    bail!("failed to parse config file {}", path.display());
+                }
+            };
+            let outputs = simulate(&mut cfg, test);
+            if outputs == test.expected_outputs {
+                log::info!("deftest {}: ok", test.name);
+            } else {
+                failed_count += 1;
+                log::error!(
+                    "deftest {}: FAILED\nexpected: {}\nactual:   {}",
+                    test.name,
+                    fmt_outputs(&test.expected_outputs),
+                    fmt_outputs(&outputs),
+                );
+            }
+        }
+    }
+    if failed_count > 0 {
+        bail!("{failed_count} deftest(s) failed");
+    }
+    Ok(())
+}
+
+/// Run the test inputs through the configuration's layout and return the key outputs that kanata
+/// would have sent to the OS. Like the real processing loop, each press or release is followed by
+/// a 1ms tick.
+///
+/// Custom actions such as macros, mouse actions and commands are not simulated.
+pub fn simulate(cfg: &mut cfg::Cfg, test: &CfgTest) -> Vec<SimOutput> {
+    let mut sim = Simulation {
+        cfg,
+        prev_keys: vec![],
+        cur_keys: vec![],
+        override_states: OverrideStates::new(),
+        outputs: vec![],
+    };
+    for input in test.inputs.iter().copied() {
+        match input {
+            SimInput::Press(osc) => sim.key_event(osc, true),
+            SimInput::Release(osc) => sim.key_event(osc, false),
+            SimInput::Wait(ms) => (0..ms).for_each(|_| sim.tick()),
+        }
+    }
+    sim.outputs
+}
+
+struct Simulation<'a> {
+    cfg: &'a mut cfg::Cfg,
+    prev_keys: Vec<KeyCode>,
+    cur_keys: Vec<KeyCode>,
+    override_states: OverrideStates,
+    outputs: Vec<SimOutput>,
+}
+
+impl Simulation<'_> {
+    fn key_event(&mut self, osc: OsCode, is_press: bool) {
+        if !self.cfg.mapped_keys.contains(&osc) {
+            // Unmapped keys are passed through as-is.
+            self.outputs.push(match is_press {
+                true => SimOutput::Press(osc),
+                false => SimOutput::Release(osc),
+            });
+            return;
+        }
+        let evc = u16::from(osc);
+        self.cfg.layout.bm().event(match is_press {
+            true => Event::Press(0, evc),
+            false => Event::Release(0, evc),
+        });
+        self.tick();
+    }
+
+    fn tick(&mut self) {
+        let layout = self.cfg.layout.bm();
+        layout.tick();
+        self.cur_keys.clear();
+        self.cur_keys.extend(layout.keycodes());
+        self.cfg
+            .overrides
+            .override_keys(&mut self.cur_keys, &mut self.override_states);
+        for k in self.prev_keys.iter() {
+            if !self.cur_keys.contains(k) {
+                self.outputs.push(SimOutput::Release(k.into()));
+            }
+        }
+        for (i, k) in self.cur_keys.iter().enumerate() {
+            // keyberon can return duplicates of a key, so only count the first of each.
+            if !self.prev_keys.contains(k) && !self.cur_keys[..i].contains(k) {
+                self.outputs.push(SimOutput::Press(k.into()));
+            }
+        }
+        std::mem::swap(&mut self.prev_keys, &mut self.cur_keys);
+    }
+}
+
+fn fmt_outputs(outputs: &[SimOutput]) -> String {
+    outputs
+        .iter()
+        .map(|o| o.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,417 @@
+//! Implements `kanata doctor`: check the permissions, drivers and session that kanata depends on,
+//! and print how to fix the problems that are found.
+
+use anyhow::{bail, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Severity {
+    Ok,
+    /// Not necessarily a problem, but worth knowing about.
+    Note,
+    /// Kanata will likely not work until the fix is applied.
+    Problem,
+}
+
+#[derive(Debug)]
+struct Finding {
+    severity: Severity,
+    msg: String,
+    fix: Option<String>,
+}
+
+// Not every platform has checks that can find notes or problems.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+impl Finding {
+    fn ok(msg: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Ok,
+            msg: msg.into(),
+            fix: None,
+        }
+    }
+
+    fn note(msg: impl Into<String>, fix: Option<String>) -> Self {
+        Self {
+            severity: Severity::Note,
+            msg: msg.into(),
+            fix,
+        }
+    }
+
+    fn problem(msg: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Problem,
+            msg: msg.into(),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+/// Run every check for the current platform and log the findings. Returns an error if any problem
+/// was found.
+pub fn run() -> Result<()> {
+    let findings = checks();
+    for finding in findings.iter() {
+        let fix = finding
+            .fix
+            .as_ref()
+            .map(|fix| format!("\nTo fix this:\n{fix}"))
+            .unwrap_or_default();
+        match finding.severity {
+            Severity::Ok => log::info!("ok: {}", finding.msg),
+            Severity::Note => log::warn!("note: {}{fix}", finding.msg),
+            Severity::Problem => log::error!("problem: {}{fix}", finding.msg),
+        }
+    }
+    let problems = findings
+        .iter()
+        .filter(|f| f.severity == Severity::Problem)
+        .count();
+    if problems > 0 {
+        bail!("kanata doctor found {problems} problem(s)");
+    }
+    log::info!("kanata doctor found no problems");
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn checks() -> Vec<Finding> {
+    use std::fs;
+
+    let ids = ProcessIds::parse(&fs::read_to_string("/proc/self/status").unwrap_or_default());
+    let group_file = fs::read_to_string("/etc/group").unwrap_or_default();
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .ok();
+    let mut findings = vec![];
+    if ids.uid == Some(0) {
+        findings.push(Finding::note(
+            "kanata is running as root, so group membership is not checked",
+            Some(
+                "Run kanata as a user in the input and uinput groups instead, as described in \
+                 docs/avoid-sudo-linux.md."
+                    .into(),
+            ),
+        ));
+    } else {
+        for group in ["input", "uinput"] {
+            findings.push(group_finding(group, &ids, &group_file, user.as_deref()));
+        }
+    }
+    findings.push(uinput_finding());
+    findings.push(udev_rule_finding());
+    findings.push(input_devices_finding());
+    findings.push(session_finding(|var| std::env::var(var).ok()));
+    findings
+}
+
+#[cfg(target_os = "windows")]
+fn checks() -> Vec<Finding> {
+    #[cfg(feature = "interception_driver")]
+    {
+        interception_findings()
+    }
+    #[cfg(not(feature = "interception_driver"))]
+    {
+        vec![Finding::ok(
+            "this kanata uses LLHOOK and SendInput, which need no driver",
+        )]
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn checks() -> Vec<Finding> {
+    vec![Finding::note(
+        "kanata doctor has no checks for this platform",
+        None,
+    )]
+}
+
+/// The user and group ids of the process, from `/proc/self/status`.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ProcessIds {
+    uid: Option<u32>,
+    /// The primary group and the supplementary groups.
+    gids: Vec<u32>,
+}
+
+#[cfg(target_os = "linux")]
+impl ProcessIds {
+    fn parse(status: &str) -> Self {
+        let mut ids = Self::default();
+        let first_id = |s: &str| s.split_whitespace().next().and_then(|id| id.parse().ok());
+        for line in status.lines() {
+            if let Some(rest) = line.strip_prefix("Uid:") {
+                ids.uid = first_id(rest);
+            } else if let Some(rest) = line.strip_prefix("Gid:") {
+                ids.gids.extend(first_id(rest));
+            } else if let Some(rest) = line.strip_prefix("Groups:") {
+                ids.gids.extend(
+                    rest.split_whitespace()
+                        .filter_map(|id| id.parse::<u32>().ok()),
+                );
+            }
+        }
+        ids
+    }
+}
+
+/// A group of `/etc/group`.
+#[cfg(target_os = "linux")]
+#[derive(Debug, PartialEq, Eq)]
+struct Group<'a> {
+    gid: u32,
+    members: Vec<&'a str>,
+}
+
+#[cfg(target_os = "linux")]
+fn find_group<'a>(group_file: &'a str, name: &str) -> Option<Group<'a>> {
+    group_file.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        if fields.next()? != name {
+            return None;
+        }
+        let gid = fields.nth(1)?.parse().ok()?;
+        let members = fields
+            .next()
+            .unwrap_or_default()
+            .split(',')
+            .filter(|m| !m.is_empty())
+            .collect();
+        Some(Group { gid, members })
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn group_finding(name: &str, ids: &ProcessIds, group_file: &str, user: Option<&str>) -> Finding {
+    let usermod = format!("  sudo usermod -aG {name} {}", user.unwrap_or("$USER"));
+    let Some(group) = find_group(group_file, name) else {
+        return Finding::problem(
+            format!("the {name} group was not found in /etc/group"),
+            format!("  sudo groupadd {name}\n{usermod}\nthen log out and log in again."),
+        );
+    };
+    if ids.gids.contains(&group.gid) {
+        return Finding::ok(format!("kanata is running in the {name} group"));
+    }
+    if user.map(|u| group.members.contains(&u)).unwrap_or_default() {
+        return Finding::problem(
+            format!("you were added to the {name} group, but this session is not in it yet"),
+            "Log out and log in again, or reboot.",
+        );
+    }
+    Finding::problem(
+        format!("kanata is not running in the {name} group"),
+        format!("{usermod}\nthen log out and log in again."),
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn uinput_finding() -> Finding {
+    const UINPUT: &str = "/dev/uinput";
+    if !std::path::Path::new(UINPUT).exists() {
+        return Finding::problem(
+            format!("{UINPUT} does not exist, so kanata cannot create its output device"),
+            "Load the uinput kernel module:\n  sudo modprobe uinput\n\
+             To load it on every boot:\n  echo uinput | sudo tee /etc/modules-load.d/uinput.conf",
+        );
+    }
+    // Opening uinput does not create a device, so this is harmless.
+    match std::fs::OpenOptions::new().write(true).open(UINPUT) {
+        Ok(_) => Finding::ok(format!("{UINPUT} is writable")),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Finding::problem(
+            format!("{UINPUT} is not writable: {e}"),
+            format!(
+                "Join the uinput group and add the udev rule below to \
+                 /etc/udev/rules.d/99-input.rules:\n  {UDEV_RULE}\nthen reload the rules:\n  \
+                 sudo udevadm control --reload-rules && sudo udevadm trigger"
+            ),
+        ),
+        Err(e) => Finding::problem(
+            format!("{UINPUT} could not be opened: {e}"),
+            "Check that the uinput kernel module is loaded:\n  sudo modprobe uinput",
+        ),
+    }
+}
+
+/// The udev rule that gives the uinput group access to `/dev/uinput`.
+#[cfg(target_os = "linux")]
+const UDEV_RULE: &str =
+    r#"KERNEL=="uinput", MODE="0660", GROUP="uinput", OPTIONS+="static_node=uinput""#;
+
+/// Returns whether the content of a udev rules file has a rule for uinput.
+#[cfg(target_os = "linux")]
+fn has_uinput_rule(rules: &str) -> bool {
+    rules
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .any(|line| line.contains(r#"KERNEL=="uinput""#))
+}
+
+#[cfg(target_os = "linux")]
+fn udev_rule_finding() -> Finding {
+    let rule_files = [
+        "/etc/udev/rules.d",
+        "/lib/udev/rules.d",
+        "/usr/lib/udev/rules.d",
+    ]
+    .into_iter()
+    .filter_map(|dir| std::fs::read_dir(dir).ok())
+    .flatten()
+    .filter_map(|entry| entry.ok().map(|e| e.path()))
+    .filter(|path| {
+        path.extension()
+            .map(|ext| ext == "rules")
+            .unwrap_or_default()
+    });
+    for path in rule_files {
+        let rules = std::fs::read_to_string(&path).unwrap_or_default();
+        if has_uinput_rule(&rules) {
+            return Finding::ok(format!(
+                "found a udev rule for uinput in {}",
+                path.display()
+            ));
+        }
+    }
+    Finding::note(
+        "no udev rule for uinput was found, so /dev/uinput may not be accessible to the uinput \
+         group",
+        Some(format!(
+            "Add the rule below to /etc/udev/rules.d/99-input.rules:\n  {UDEV_RULE}"
+        )),
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn input_devices_finding() -> Finding {
+    let devices = std::fs::read_dir("/dev/input")
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.starts_with("event"))
+                .unwrap_or_default()
+        })
+        .collect::<Vec<_>>();
+    if devices.is_empty() {
+        return Finding::problem(
+            "no input devices were found in /dev/input",
+            "Run kanata on the machine with the keyboard, not in a container or over ssh.",
+        );
+    }
+    let readable = devices
+        .iter()
+        .filter(|path| std::fs::File::open(path).is_ok())
+        .count();
+    if readable == 0 {
+        return Finding::problem(
+            format!("none of the {} input devices can be read", devices.len()),
+            "Join the input group:\n  sudo usermod -aG input $USER\n\
+             then log out and log in again.",
+        );
+    }
+    Finding::ok(format!(
+        "{readable} of {} input devices can be read",
+        devices.len()
+    ))
+}
+
+/// Describes the graphical session from the environment variables.
+#[cfg(target_os = "linux")]
+fn session_finding(var: impl Fn(&str) -> Option<String>) -> Finding {
+    let session_type = var("XDG_SESSION_TYPE").unwrap_or_default();
+    if session_type == "wayland" || var("WAYLAND_DISPLAY").is_some() {
+        Finding::ok(
+            "Wayland session. Note that linux-x11-repeat-delay-rate has no effect on Wayland; \
+             set the repeat rate in the compositor instead",
+        )
+    } else if session_type == "x11" || var("DISPLAY").is_some() {
+        Finding::ok("X11 session")
+    } else {
+        Finding::note(
+            "no graphical session was detected, e.g. running from a tty, ssh or a service. \
+             Unless kanata runs as a service, run kanata doctor in the same environment as kanata",
+            None,
+        )
+    }
+}
+
+#[cfg(all(target_os = "windows", feature = "interception_driver"))]
+fn interception_findings() -> Vec<Finding> {
+    const INSTALL: &str = "Download Interception from \
+        https://github.com/oblitum/Interception/releases, run\n  \
+        install-interception.exe /install\nas administrator, then reboot.";
+    let system_root = std::env::var("SystemRoot").unwrap_or_else(|_| r"C:\Windows".into());
+    let driver = std::path::Path::new(&system_root).join(r"System32\drivers\keyboard.sys");
+    let driver_finding = if driver.exists() {
+        Finding::ok(format!(
+            "the Interception driver is installed: {}",
+            driver.display()
+        ))
+    } else {
+        Finding::problem(
+            format!(
+                "the Interception driver was not found at {}",
+                driver.display()
+            ),
+            INSTALL,
+        )
+    };
+    let dll_dirs = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.to_path_buf()))
+        .into_iter()
+        .chain(
+            std::env::var_os("PATH")
+                .map(|path| std::env::split_paths(&path).collect::<Vec<_>>())
+                .unwrap_or_default(),
+        );
+    let dll_finding = match dll_dirs
+        .map(|dir| dir.join("interception.dll"))
+        .find(|dll| dll.exists())
+    {
+        Some(dll) => Finding::ok(format!("found {}", dll.display())),
+        None => Finding::problem(
+            "interception.dll was not found next to kanata or in PATH",
+            "Copy interception.dll from the library folder of the Interception release to the \
+             folder of kanata.exe.",
+        ),
+    };
+    vec![driver_finding, dll_finding]
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn doctor_parsing() {
+    let status = "Name:\tkanata\nUid:\t1000\t1000\t1000\t1000\nGid:\t1000\t1000\t1000\t1000\n\
+                  Groups:\t4 24 993 \n";
+    assert_eq!(
+        ProcessIds::parse(status),
+        ProcessIds {
+            uid: Some(1000),
+            gids: vec![1000, 4, 24, 993],
+        }
+    );
+    let group_file = "root:x:0:\ninput:x:993:\nuinput:x:994:alice,bob\n";
+    assert_eq!(
+        find_group(group_file, "uinput"),
+        Some(Group {
+            gid: 994,
+            members: vec!["alice", "bob"],
+        })
+    );
+    assert_eq!(
+        find_group(group_file, "input").map(|g| g.members),
+        Some(vec![])
+    );
+    assert_eq!(find_group(group_file, "plugdev"), None);
+    assert!(has_uinput_rule(&format!("# comment\n{UDEV_RULE}\n")));
+    assert!(!has_uinput_rule(r#"# KERNEL=="uinput", MODE="0660""#));
+    let wayland = session_finding(|var| (var == "WAYLAND_DISPLAY").then(|| "wayland-0".into()));
+    assert!(wayland.msg.starts_with("Wayland"));
+    assert_eq!(session_finding(|_| None).severity, Severity::Note);
+}
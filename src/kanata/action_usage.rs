@@ -0,0 +1,235 @@
+//! Counts how often the bindings of each layer are pressed during a session, so that users can
+//! find the aliases and actions of a large configuration that they never use. The counts are only
+//! kept in memory and start over when a live reload rebuilds the layers.
+
+use kanata_parser::cfg::{AliasRefs, LayerInfo};
+use kanata_parser::keys::{str_to_oscode, OsCode};
+
+use super::{HashMap, HashSet};
+use crate::tcp_server::BindingUsage;
+
+#[derive(Debug, Default)]
+pub(super) struct ActionUsage {
+    /// The keys that each layer changes, indexed like `layer_info`.
+    bound: Vec<Vec<OsCode>>,
+    /// Presses of each binding, by index in `layer_info` and key.
+    presses: HashMap<(usize, OsCode), u32>,
+    /// Every key that was pressed, for the chords of `defchordsv2`.
+    pressed: HashSet<OsCode>,
+    alias_refs: AliasRefs,
+}
+
+impl ActionUsage {
+    pub(super) fn new(layer_info: &[LayerInfo], alias_refs: AliasRefs) -> Self {
+        Self {
+            bound: layer_info
+                .iter()
+                .map(|info| {
+                    info.bindings
+                        .iter()
+                        .filter_map(|(key, _)| str_to_oscode(key))
+                        .collect()
+                })
+                .collect(),
+            presses: HashMap::default(),
+            pressed: HashSet::default(),
+            alias_refs,
+        }
+    }
+
+    /// Updates the bindings of the layer whose first version is at `layer` after `SetKeyAction`
+    /// changed its item for `key`. The presses that were counted are kept.
+    pub(super) fn set_binding(
+        &mut self,
+        layer_info: &[LayerInfo],
+        layer: usize,
+        key: &str,
+        alias_refs: Vec<String>,
+    ) {
+        let bound: Vec<OsCode> = layer_info[layer]
+            .bindings
+            .iter()
+            .filter_map(|(key, _)| str_to_oscode(key))
+            .collect();
+        for layer_bound in self.bound[layer..=layer + 1].iter_mut() {
+            layer_bound.clone_from(&bound);
+        }
+        let binding = (layer_info[layer].name.clone(), key.to_owned());
+        if alias_refs.is_empty() {
+            self.alias_refs.bindings.remove(&binding);
+        } else {
+            self.alias_refs.bindings.insert(binding, alias_refs);
+        }
+    }
+
+    /// Records a press of the key while `layer` is active. If the active layer does not change
+    /// the key, the press is counted for the binding of the default layer.
+    pub(super) fn record(&mut self, osc: OsCode, layer: usize, default_layer: usize) {
+        self.pressed.insert(osc);
+        let is_bound = |layer: usize| self.bound.get(layer).map(|keys| keys.contains(&osc));
+        let layer = match (is_bound(layer), is_bound(default_layer)) {
+            (Some(true), _) => layer,
+            (_, Some(true)) => default_layer,
+            _ => return,
+        };
+        *self.presses.entry((layer, osc)).or_default() += 1;
+    }
+
+    /// Returns the number of presses of every binding, and the defined aliases that were never
+    /// used. An alias is used if a pressed binding refers to it, directly or through other
+    /// aliases. An alias in a chord of `defchordsv2` is used once every key of the chord was
+    /// pressed, and aliases whose use is not tracked, e.g. in `defseq`, are never unused.
+    pub(super) fn report(&self, layer_info: &[LayerInfo]) -> (Vec<BindingUsage>, Vec<String>) {
+        let mut bindings = vec![];
+        // The keyberon layout has two versions of each layer with the same bindings.
+        for (layer, info) in layer_info.iter().enumerate().step_by(2) {
+            for (key, action) in info.bindings.iter() {
+                let presses = str_to_oscode(key)
+                    .map(|osc| {
+                        self.presses.get(&(layer, osc)).copied().unwrap_or_default()
+                            + self
+                                .presses
+                                .get(&(layer + 1, osc))
+                                .copied()
+                                .unwrap_or_default()
+                    })
+                    .unwrap_or_default();
+                bindings.push(BindingUsage {
+                    layer: info.name.clone(),
+                    key: key.clone(),
+                    action: action.clone(),
+                    presses,
+                });
+            }
+        }
+        let refs = &self.alias_refs;
+        let mut used: HashSet<&str> = refs.untracked.iter().map(String::as_str).collect();
+        let mut to_visit: Vec<&str> = bindings
+            .iter()
+            .filter(|b| b.presses > 0)
+            .filter_map(|b| refs.bindings.get(&(b.layer.clone(), b.key.clone())))
+            .chain(
+                refs.chords_v2
+                    .iter()
+                    .filter(|(keys, _)| keys.iter().all(|key| self.pressed.contains(key)))
+                    .map(|(_, chord_refs)| chord_refs),
+            )
+            .flatten()
+            .map(String::as_str)
+            .collect();
+        while let Some(alias) = to_visit.pop() {
+            if used.insert(alias) {
+                let nested = refs.aliases.get(alias).into_iter().flatten();
+                to_visit.extend(nested.map(String::as_str));
+            }
+        }
+        let mut unused_aliases = refs
+            .aliases
+            .keys()
+            .filter(|alias| !used.contains(alias.as_str()))
+            .cloned()
+            .collect::<Vec<_>>();
+        unused_aliases.sort();
+        (bindings, unused_aliases)
+    }
+}
+
+/// Returns the report as text for the log.
+pub(super) fn action_usage_text(bindings: &[BindingUsage], unused_aliases: &[String]) -> String {
+    let mut text = String::from("action usage since kanata started or the layers were reloaded\n");
+    let mut layer = None;
+    for b in bindings.iter() {
+        if layer != Some(&b.layer) {
+            text.push_str(&format!("layer {}\n", b.layer));
+            layer = Some(&b.layer);
+        }
+        text.push_str(&format!("  {}: {}: {}\n", b.key, b.action, b.presses));
+    }
+    text.push_str(&format!("unused aliases: {}\n", unused_aliases.join(" ")));
+    text
+}
+
+#[test]
+fn action_usage_counts_bindings_of_active_layer() {
+    let layer_info = |name: &str, bindings: &[(&str, &str)]| LayerInfo {
+        name: name.into(),
+        cfg_text: String::new(),
+        bindings: bindings
+            .iter()
+            .map(|(k, a)| (k.to_string(), a.to_string()))
+            .collect(),
+        cfg_overrides: HashMap::default(),
+    };
+    let base = [("caps", "@cap"), ("a", "@nav")];
+    let nav = [("a", "@nav"), ("h", "left")];
+    let layer_info = [
+        layer_info("base", &base),
+        layer_info("base", &base),
+        layer_info("nav", &nav),
+        layer_info("nav", &nav),
+    ];
+    let owned = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+    let mut alias_refs = AliasRefs::default();
+    for (alias, nested) in [
+        ("cap", &["inner"][..]),
+        ("inner", &[]),
+        ("nav", &[]),
+        ("unused", &[]),
+        ("chorded", &[]),
+        ("in-seq", &[]),
+    ] {
+        alias_refs.aliases.insert(alias.into(), owned(nested));
+    }
+    for (layer, key, alias) in [
+        ("base", "caps", "cap"),
+        ("base", "a", "nav"),
+        ("nav", "a", "nav"),
+    ] {
+        alias_refs
+            .bindings
+            .insert((layer.into(), key.into()), owned(&[alias]));
+    }
+    alias_refs
+        .chords_v2
+        .push((vec![OsCode::KEY_H, OsCode::KEY_J], owned(&["chorded"])));
+    alias_refs.untracked.insert("in-seq".into());
+    let mut usage = ActionUsage::new(&layer_info, alias_refs);
+    usage.record(OsCode::KEY_H, 3, 0);
+    usage.record(OsCode::KEY_H, 3, 0);
+    // Transparent in nav, so the press is of the base layer binding.
+    usage.record(OsCode::KEY_CAPSLOCK, 3, 0);
+    // Bound in no layer, but completes the keys of the chord.
+    usage.record(OsCode::KEY_J, 0, 0);
+    let (bindings, unused_aliases) = usage.report(&layer_info);
+    assert_eq!(
+        bindings
+            .iter()
+            .map(|b| (b.layer.as_str(), b.key.as_str(), b.presses))
+            .collect::<Vec<_>>(),
+        [
+            ("base", "caps", 1),
+            ("base", "a", 0),
+            ("nav", "a", 0),
+            ("nav", "h", 2),
+        ]
+    );
+    // The alias nested in cap is used, the one bound to unpressed keys or not used at all is not.
+    assert_eq!(unused_aliases, ["nav", "unused"]);
+
+    // A binding changed by SetKeyAction keeps the presses that were counted.
+    let mut layer_info = layer_info;
+    for info in layer_info[0..2].iter_mut() {
+        info.bindings[1].1 = "@unused".into();
+    }
+    usage.set_binding(&layer_info, 0, "a", owned(&["unused"]));
+    usage.record(OsCode::KEY_A, 1, 0);
+    let (bindings, unused_aliases) = usage.report(&layer_info);
+    assert_eq!(
+        bindings
+            .iter()
+            .map(|b| (b.action.as_str(), b.presses))
+            .collect::<Vec<_>>(),
+        [("@cap", 1), ("@unused", 1), ("@nav", 0), ("left", 2)]
+    );
+    assert_eq!(unused_aliases, ["nav"]);
+}
@@ -0,0 +1,88 @@
+//! Default layers that follow the frontmost application on macOS, from `macos-app-layers`.
+
+use anyhow::{anyhow, Result};
+
+use kanata_parser::cfg::LayerInfo;
+
+use super::HashMap;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(super) struct AppLayers {
+    /// The layer of each listed bundle identifier.
+    layers: HashMap<String, usize>,
+    /// The default layer from before a listed application switched it. It is restored when an
+    /// application that is not listed becomes frontmost.
+    prev_default: Option<usize>,
+}
+
+impl AppLayers {
+    pub(super) fn from_cfg(
+        cfg_items: &HashMap<String, String>,
+        layer_info: &[LayerInfo],
+    ) -> Result<Self> {
+        let mut app_layers = AppLayers::default();
+        let Some(entries) = cfg_items.get("macos-app-layers") else {
+            return Ok(app_layers);
+        };
+        for entry in entries.split_whitespace() {
+            // Bundle identifiers never contain a colon, so the layer name is after the last one.
+            let (bundle_id, layer_name) = entry.rsplit_once(':').ok_or_else(|| {
+                anyhow!(
+                    "macos-app-layers entries must be a bundle identifier and a layer name \
+                    separated by a colon, e.g. com.apple.Terminal:term. Got {entry}"
+                )
+            })?;
+            let layer = layer_info
+                .iter()
+                .position(|l| l.name == layer_name)
+                .ok_or_else(|| anyhow!("macos-app-layers: {layer_name} is not a layer name"))?;
+            app_layers.layers.insert(bundle_id.to_owned(), layer);
+        }
+        Ok(app_layers)
+    }
+
+    /// Returns the default layer to switch to because the application `bundle_id` became
+    /// frontmost, or `None` to keep the current one.
+    pub(super) fn activate(&mut self, bundle_id: &str, default_layer: usize) -> Option<usize> {
+        match self.layers.get(bundle_id) {
+            Some(&layer) => {
+                self.prev_default.get_or_insert(default_layer);
+                Some(layer)
+            }
+            None => self.prev_default.take(),
+        }
+    }
+}
+
+#[test]
+fn app_layers_activate() {
+    let layer_info = |name: &str| LayerInfo {
+        name: name.into(),
+        cfg_text: String::new(),
+        bindings: vec![],
+        cfg_overrides: HashMap::default(),
+    };
+    let layer_info = [layer_info("base"), layer_info("term"), layer_info("web")];
+    let mut cfg_items = HashMap::default();
+    cfg_items.insert(
+        "macos-app-layers".to_owned(),
+        "com.apple.Terminal:term com.google.Chrome:web org.mozilla.firefox:web".to_owned(),
+    );
+    let mut app_layers = AppLayers::from_cfg(&cfg_items, &layer_info).unwrap();
+    assert_eq!(app_layers.activate("com.apple.finder", 0), None);
+    assert_eq!(app_layers.activate("com.apple.Terminal", 0), Some(1));
+    assert_eq!(app_layers.activate("com.google.Chrome", 1), Some(2));
+    assert_eq!(app_layers.activate("com.apple.finder", 2), Some(0));
+    assert_eq!(app_layers.activate("com.apple.finder", 0), None);
+
+    cfg_items.insert(
+        "macos-app-layers".to_owned(),
+        "com.apple.Terminal".to_owned(),
+    );
+    assert!(AppLayers::from_cfg(&cfg_items, &layer_info).is_err());
+    cfg_items.insert(
+        "macos-app-layers".to_owned(),
+        "com.apple.Terminal:other".to_owned(),
+    );
+    assert!(AppLayers::from_cfg(&cfg_items, &layer_info).is_err());
+}
@@ -0,0 +1,249 @@
+//! Enforced micro-breaks against repetitive strain. After `break-after-typing` minutes of
+//! continuous typing, the `break-layer` becomes the default layer for `break-duration` seconds.
+//! Typing is continuous as long as there is no pause at least as long as a break. Holding all keys
+//! of `break-override-keys` ends a break early.
+
+use anyhow::{anyhow, bail, Result};
+
+use std::time::{Duration, Instant};
+
+use kanata_parser::cfg::LayerInfo;
+use kanata_parser::keys::{str_to_oscode, OsCode};
+
+use super::HashMap;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BreakCfg {
+    typing: Duration,
+    duration: Duration,
+    /// Index of the break layer in the keyberon layout.
+    layer: usize,
+    override_keys: Vec<OsCode>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct OnBreak {
+    end: Instant,
+    /// The default layer to go back to when the break ends. None if the layout was replaced by
+    /// live reload during the break.
+    restore_layer: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum BreakChange {
+    /// A break started. The default layer should be changed to `layer`.
+    Started { layer: usize, duration: Duration },
+    /// The break ended. The default layer should be changed back to `restore_layer`, if any.
+    Ended {
+        restore_layer: Option<usize>,
+        overridden: bool,
+    },
+}
+
+#[derive(Debug, Default)]
+pub(super) struct BreakReminder {
+    cfg: Option<BreakCfg>,
+    typing_start: Option<Instant>,
+    last_press: Option<Instant>,
+    on_break: Option<OnBreak>,
+    overridden: bool,
+}
+
+impl BreakReminder {
+    pub(super) fn from_cfg(
+        cfg_items: &HashMap<String, String>,
+        layer_info: &[LayerInfo],
+    ) -> Result<Self> {
+        Ok(Self {
+            cfg: parse_break_cfg(cfg_items, layer_info)?,
+            ..Default::default()
+        })
+    }
+
+    /// Changes the configuration on live reload. A break in progress ends at the next tick since
+    /// the new layout no longer has the break layer active.
+    pub(super) fn update_cfg(
+        &mut self,
+        cfg_items: &HashMap<String, String>,
+        layer_info: &[LayerInfo],
+    ) -> Result<()> {
+        self.cfg = parse_break_cfg(cfg_items, layer_info)?;
+        if let Some(on_break) = &mut self.on_break {
+            on_break.end = Instant::now();
+            on_break.restore_layer = None;
+        }
+        Ok(())
+    }
+
+    pub(super) fn on_break(&self) -> bool {
+        self.on_break.is_some()
+    }
+
+    /// Records an input key press. `held_input_keys` are the input keys held including this one.
+    pub(super) fn key_pressed(&mut self, now: Instant, held_input_keys: &[(u16, OsCode)]) {
+        let Some(cfg) = &self.cfg else {
+            return;
+        };
+        if self.on_break.is_some() {
+            self.overridden |= !cfg.override_keys.is_empty()
+                && cfg
+                    .override_keys
+                    .iter()
+                    .all(|k| held_input_keys.iter().any(|(_, held)| held == k));
+            return;
+        }
+        let rested = self
+            .last_press
+            .map(|t| now.duration_since(t) >= cfg.duration)
+            .unwrap_or(true);
+        if rested {
+            self.typing_start = Some(now);
+        }
+        self.last_press = Some(now);
+    }
+
+    /// Starts or ends a break if it is time to. `default_layer` is the current default layer of
+    /// the layout, which is restored after the break.
+    pub(super) fn tick(&mut self, now: Instant, default_layer: usize) -> Option<BreakChange> {
+        if let Some(on_break) = self.on_break {
+            if !self.overridden && now < on_break.end {
+                return None;
+            }
+            let overridden = std::mem::take(&mut self.overridden);
+            self.on_break = None;
+            self.typing_start = None;
+            self.last_press = None;
+            return Some(BreakChange::Ended {
+                restore_layer: on_break.restore_layer,
+                overridden,
+            });
+        }
+        let cfg = self.cfg.as_ref()?;
+        let (typing_start, last_press) = (self.typing_start?, self.last_press?);
+        // No break is needed if typing already stopped for long enough.
+        if now.duration_since(typing_start) < cfg.typing
+            || now.duration_since(last_press) >= cfg.duration
+        {
+            return None;
+        }
+        self.on_break = Some(OnBreak {
+            end: now + cfg.duration,
+            restore_layer: Some(default_layer),
+        });
+        Some(BreakChange::Started {
+            layer: cfg.layer,
+            duration: cfg.duration,
+        })
+    }
+}
+
+fn parse_break_cfg(
+    cfg_items: &HashMap<String, String>,
+    layer_info: &[LayerInfo],
+) -> Result<Option<BreakCfg>> {
+    let minutes = cfg_items
+        .get("break-after-typing")
+        .map(|s| s.parse::<u16>())
+        .unwrap_or(Ok(0))
+        .map_err(|e| anyhow!("break-after-typing must be 0-65535: {e}"))?;
+    if minutes == 0 {
+        return Ok(None);
+    }
+    let secs = cfg_items
+        .get("break-duration")
+        .map(|s| s.parse::<u16>())
+        .unwrap_or(Ok(30))
+        .map_err(|e| anyhow!("break-duration must be 1-65535: {e}"))?;
+    if secs == 0 {
+        bail!("break-duration must be 1-65535, got 0");
+    }
+    let layer_name = cfg_items
+        .get("break-layer")
+        .ok_or_else(|| anyhow!("break-after-typing requires break-layer to be set"))?;
+    let layer = layer_info
+        .iter()
+        .position(|l| &l.name == layer_name)
+        .ok_or_else(|| anyhow!("break-layer {layer_name} is not a layer name"))?;
+    let override_keys = cfg_items
+        .get("break-override-keys")
+        .map(|keys| {
+            keys.split_whitespace()
+                .map(|k| {
+                    str_to_oscode(k)
+                        .ok_or_else(|| anyhow!("break-override-keys has an unknown key: {k}"))
+                })
+                .collect::<Result<Vec<_>>>()
+        })
+        .transpose()?
+        .unwrap_or_default();
+    Ok(Some(BreakCfg {
+        typing: Duration::from_secs(u64::from(minutes) * 60),
+        duration: Duration::from_secs(secs.into()),
+        layer,
+        override_keys,
+    }))
+}
+
+#[test]
+fn break_reminder_starts_and_ends_breaks() {
+    let layer_info = |name: &str| LayerInfo {
+        name: name.into(),
+        cfg_text: String::new(),
+        bindings: vec![],
+        cfg_overrides: HashMap::default(),
+    };
+    let layer_info = [layer_info("base"), layer_info("rest")];
+    let mut cfg_items = HashMap::default();
+    cfg_items.insert("break-after-typing".to_owned(), "1".to_owned());
+    cfg_items.insert("break-duration".to_owned(), "10".to_owned());
+    cfg_items.insert("break-layer".to_owned(), "rest".to_owned());
+    cfg_items.insert("break-override-keys".to_owned(), "lctl esc".to_owned());
+    let mut br = BreakReminder::from_cfg(&cfg_items, &layer_info).unwrap();
+    let start = Instant::now();
+    let secs = |n| start + Duration::from_secs(n);
+
+    // A pause as long as a break restarts the typing time.
+    br.key_pressed(start, &[]);
+    for i in 4..=16 {
+        br.key_pressed(secs(i * 5), &[]);
+    }
+    assert_eq!(br.tick(secs(79), 0), None);
+    assert_eq!(
+        br.tick(secs(80), 0),
+        Some(BreakChange::Started {
+            layer: 1,
+            duration: Duration::from_secs(10)
+        })
+    );
+    assert!(br.on_break());
+    assert_eq!(br.tick(secs(89), 1), None);
+    assert_eq!(
+        br.tick(secs(90), 1),
+        Some(BreakChange::Ended {
+            restore_layer: Some(0),
+            overridden: false
+        })
+    );
+
+    // The override keys end a break early.
+    for i in 20..=32 {
+        br.key_pressed(secs(i * 5), &[]);
+    }
+    assert!(br.tick(secs(160), 0).is_some());
+    br.key_pressed(secs(161), &[(0, OsCode::KEY_LEFTCTRL)]);
+    assert_eq!(br.tick(secs(161), 1), None);
+    br.key_pressed(
+        secs(162),
+        &[(0, OsCode::KEY_LEFTCTRL), (0, OsCode::KEY_ESC)],
+    );
+    assert_eq!(
+        br.tick(secs(162), 1),
+        Some(BreakChange::Ended {
+            restore_layer: Some(0),
+            overridden: true
+        })
+    );
+
+    cfg_items.insert("break-layer".to_owned(), "nope".to_owned());
+    assert!(br.update_cfg(&cfg_items, &layer_info).is_err());
+}
@@ -0,0 +1,199 @@
+//! Clipboard actions. The clipboard is accessed through the clipboard programs of the platform.
+//!
+//! The programs run on a worker so that they never delay the processing of other keys. The actions
+//! run in order, one at a time, and the running macros are paused until they are done. This
+//! guarantees that the clipboard has been changed before the next action of a macro, e.g. a
+//! paste, is processed.
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+
+use super::worker;
+use super::HashMap;
+
+/// Saved clipboard contents by slot.
+static SLOTS: Lazy<Mutex<HashMap<u16, String>>> = Lazy::new(|| Mutex::new(HashMap::default()));
+
+/// Clipboard actions that have not run yet, in the order they were activated.
+static JOBS: Mutex<VecDeque<Job>> = Mutex::new(VecDeque::new());
+
+/// Whether a worker is running the clipboard actions. Only changed while holding `JOBS`.
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+enum Job {
+    Set(String),
+    SaveSlot(u16),
+    RestoreSlot(u16),
+}
+
+pub(super) fn set(text: String) {
+    push_job(Job::Set(text));
+}
+
+pub(super) fn save_slot(slot: u16) {
+    push_job(Job::SaveSlot(slot));
+}
+
+pub(super) fn restore_slot(slot: u16) {
+    push_job(Job::RestoreSlot(slot));
+}
+
+/// Returns true while clipboard actions are running or waiting to run.
+pub(super) fn is_busy() -> bool {
+    BUSY.load(SeqCst)
+}
+
+fn push_job(job: Job) {
+    let mut jobs = JOBS.lock();
+    jobs.push_back(job);
+    if !BUSY.swap(true, SeqCst) {
+        worker::run_on_worker(run_jobs);
+    }
+}
+
+fn run_jobs() {
+    loop {
+        let job = {
+            let mut jobs = JOBS.lock();
+            match jobs.pop_front() {
+                Some(job) => job,
+                None => {
+                    BUSY.store(false, SeqCst);
+                    break;
+                }
+            }
+        };
+        job.run();
+    }
+    worker::wake_up_processing_loop();
+}
+
+impl Job {
+    fn run(self) {
+        match self {
+            Job::Set(text) => {
+                if let Err(e) = write_clipboard(&text) {
+                    log::warn!("could not set clipboard: {e}");
+                }
+            }
+            Job::SaveSlot(slot) => match read_clipboard() {
+                Ok(text) => {
+                    SLOTS.lock().insert(slot, text);
+                }
+                Err(e) => log::warn!("could not save clipboard to slot {slot}: {e}"),
+            },
+            Job::RestoreSlot(slot) => {
+                let Some(text) = SLOTS.lock().get(&slot).cloned() else {
+                    log::warn!("clipboard slot {slot} is empty");
+                    return;
+                };
+                if let Err(e) = write_clipboard(&text) {
+                    log::warn!("could not restore clipboard from slot {slot}: {e}");
+                }
+            }
+        }
+    }
+}
+
+fn read_clipboard() -> io::Result<String> {
+    let output = read_cmd().stdin(Stdio::null()).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let text = String::from_utf8_lossy(&output.stdout);
+    // PowerShell ends its output with a newline that is not part of the clipboard.
+    #[cfg(target_os = "windows")]
+    let text = text.strip_suffix("\r\n").unwrap_or(&text);
+    Ok(text.to_string())
+}
+
+fn write_clipboard(text: &str) -> io::Result<()> {
+    // Some clipboard programs keep running in the background to serve the clipboard, so their
+    // output is not captured, which would wait for them to exit.
+    let mut child = write_cmd(text)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::other(status.to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn is_wayland() -> bool {
+    std::env::var_os("WAYLAND_DISPLAY").is_some()
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn read_cmd() -> Command {
+    if is_wayland() {
+        let mut cmd = Command::new("wl-paste");
+        cmd.arg("--no-newline");
+        cmd
+    } else {
+        let mut cmd = Command::new("xclip");
+        cmd.args(["-selection", "clipboard", "-out"]);
+        cmd
+    }
+}
+
+/// The text is written to stdin of the returned command.
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn write_cmd(_text: &str) -> Command {
+    if is_wayland() {
+        Command::new("wl-copy")
+    } else {
+        let mut cmd = Command::new("xclip");
+        cmd.args(["-selection", "clipboard", "-in"]);
+        cmd
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn powershell(script: &str) -> Command {
+    use std::os::windows::process::CommandExt;
+    const CREATE_NO_WINDOW: u32 = 0x08000000;
+    let mut cmd = Command::new("powershell");
+    cmd.args(["-NoProfile", "-NonInteractive", "-Command", script])
+        .creation_flags(CREATE_NO_WINDOW);
+    cmd
+}
+
+#[cfg(target_os = "windows")]
+fn read_cmd() -> Command {
+    powershell("[Console]::OutputEncoding = [Text.Encoding]::UTF8; Get-Clipboard -Raw")
+}
+
+/// The text is passed through an environment variable so that it is never interpreted as script.
+#[cfg(target_os = "windows")]
+fn write_cmd(text: &str) -> Command {
+    let mut cmd = powershell("Set-Clipboard -Value $env:KANATA_CLIPBOARD");
+    cmd.env("KANATA_CLIPBOARD", text);
+    cmd
+}
+
+#[cfg(target_os = "macos")]
+fn read_cmd() -> Command {
+    Command::new("pbpaste")
+}
+
+/// The text is written to stdin of the returned command.
+#[cfg(target_os = "macos")]
+fn write_cmd(_text: &str) -> Command {
+    Command::new("pbcopy")
+}
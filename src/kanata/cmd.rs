@@ -1,41 +1,207 @@
 use kanata_parser::cfg::parse_mod_prefix;
 use kanata_parser::cfg::sexpr::*;
+use kanata_parser::custom_action::{FakeKeyCallback, TextTypingCfg};
 use kanata_parser::keys::*;
 
+use std::path::PathBuf;
+use std::sync::mpsc::{SendError, SyncSender};
+
+use super::worker::{run_on_worker, wake_up_processing_loop};
+
 // local log prefix
 const LP: &str = "cmd-out:";
 
-pub(super) fn run_cmd_in_thread(cmd_and_args: Vec<String>) -> std::thread::JoinHandle<()> {
-    std::thread::spawn(move || {
+/// Runs the command and returns whether it exited successfully. This blocks until the command
+/// finishes, so it should be called from a worker thread.
+pub(super) fn run_cmd(cmd_and_args: &[String]) -> bool {
+    let mut args = cmd_and_args.iter();
+    let mut cmd = std::process::Command::new(
+        args.next()
+            .expect("parsing should have forbidden empty cmd"),
+    );
+    for arg in args {
+        cmd.arg(arg);
+    }
+    match cmd.output() {
+        Ok(output) => {
+            log::info!(
+                "Successfully ran cmd {}\nstatus: {}\nstdout:\n{}\nstderr:\n{}",
+                {
+                    let mut printable_cmd = Vec::new();
+                    printable_cmd.push(format!("{:?}", cmd.get_program()));
+
+                    let printable_cmd = cmd.get_args().fold(printable_cmd, |mut cmd, arg| {
+                        cmd.push(format!("{arg:?}"));
+                        cmd
+                    });
+                    printable_cmd.join(" ")
+                },
+                output.status,
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            );
+            output.status.success()
+        }
+        Err(e) => {
+            log::error!("Failed to execute cmd: {}", e);
+            false
+        }
+    }
+}
+
+/// A command to run, along with the fake key actions to perform when it finishes.
+pub(super) struct CmdJob {
+    pub cmd: Vec<String>,
+    pub on_success: Option<FakeKeyCallback>,
+    pub on_failure: Option<FakeKeyCallback>,
+}
+
+impl CmdJob {
+    pub(super) fn has_callbacks(&self) -> bool {
+        self.on_success.is_some() || self.on_failure.is_some()
+    }
+}
+
+/// Sent to the processing loop by command workers when a command it is waiting on finishes.
+pub(super) enum CmdCompletion {
+    /// A command with `on-success` or `on-failure` finished. Contains the fake key action to
+    /// perform, if any.
+    Callback(Option<FakeKeyCallback>),
+    /// The edit started by `edit-config` is done. Contains whether the configuration file was
+    /// changed.
+    ConfigEdited { changed: bool },
+    /// A command of `cmd-output-text` finished. Contains its output, which is empty if the
+    /// command failed.
+    TypeText { text: String, typing: TextTypingCfg },
+}
+
+/// Sends the completion to the processing loop and wakes it up, so that the completion is handled
+/// even if the processing loop was waiting for input.
+pub(super) fn send_completion(
+    tx: &SyncSender<CmdCompletion>,
+    completion: CmdCompletion,
+) -> Result<(), SendError<CmdCompletion>> {
+    tx.send(completion)?;
+    wake_up_processing_loop();
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+const DEFAULT_EDITOR: &[&str] = &["notepad"];
+#[cfg(target_os = "macos")]
+const DEFAULT_EDITOR: &[&str] = &["open", "-W", "-t"];
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+const DEFAULT_EDITOR: &[&str] = &["xdg-open"];
+
+/// Returns the editor command and arguments to use for `edit-config`. The `editor` defcfg item
+/// takes precedence over the `VISUAL` and `EDITOR` environment variables, which take precedence
+/// over the platform default.
+pub(super) fn editor_cmd(cfg_editor: Option<&str>) -> Vec<String> {
+    cfg_editor
+        .map(str::to_owned)
+        .into_iter()
+        .chain(std::env::var("VISUAL").ok())
+        .chain(std::env::var("EDITOR").ok())
+        .find(|editor| !editor.trim().is_empty())
+        .map(|editor| editor.split_whitespace().map(str::to_owned).collect())
+        .unwrap_or_else(|| DEFAULT_EDITOR.iter().map(|s| s.to_string()).collect())
+}
+
+/// How often the configuration file is checked for changes while waiting on the editor.
+const EDIT_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// How long to keep watching the configuration file after the editor command exits without the
+/// file having changed. Launchers such as `xdg-open` exit immediately and leave the file open in
+/// another process, so the file being saved is the only sign that the edit is done.
+const EDIT_WATCH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30 * 60);
+
+/// Opens the configuration file in the editor on a worker thread. If `completion_tx` is
+/// given, whether the file was changed is sent to it once the edit is done, so that the
+/// configuration can be live reloaded.
+///
+/// The edit is done when the editor exits after the file was saved. If the editor exits
+/// successfully without the file having changed, the file is watched until it is saved or until
+/// `EDIT_WATCH_TIMEOUT` passes.
+pub(super) fn open_in_editor(
+    editor: Vec<String>,
+    cfg_path: PathBuf,
+    completion_tx: Option<SyncSender<CmdCompletion>>,
+) {
+    run_on_worker(move || {
+        let mut args = editor.iter();
+        let mut cmd = std::process::Command::new(
+            args.next()
+                .expect("editor_cmd should never return an empty command"),
+        );
+        cmd.args(args).arg(&cfg_path);
+        let modified = || std::fs::metadata(&cfg_path).and_then(|m| m.modified()).ok();
+        let initial_modified = modified();
+        log::info!("Opening {} with {:?}", cfg_path.display(), editor);
+        let success = match cmd.status() {
+            Ok(status) => {
+                log::info!("Editor exited with {status}");
+                status.success()
+            }
+            Err(e) => {
+                log::error!("Failed to launch editor {:?}: {e}", editor);
+                false
+            }
+        };
+        let Some(tx) = completion_tx else {
+            return;
+        };
+        let mut changed = success && modified() != initial_modified;
+        if success && !changed {
+            log::info!(
+                "Waiting for {} to be saved before live reloading",
+                cfg_path.display()
+            );
+            let start = std::time::Instant::now();
+            while !changed && start.elapsed() < EDIT_WATCH_TIMEOUT {
+                std::thread::sleep(EDIT_POLL_INTERVAL);
+                changed = modified() != initial_modified;
+            }
+        }
+        if send_completion(&tx, CmdCompletion::ConfigEdited { changed }).is_err() {
+            log::warn!("could not send editor result: processing loop is gone");
+        }
+    });
+}
+
+/// Runs the command on a worker and sends its output back to the processing loop to be typed.
+pub(super) fn type_cmd_output(
+    cmd_and_args: Vec<String>,
+    typing: TextTypingCfg,
+    completion_tx: SyncSender<CmdCompletion>,
+) {
+    run_on_worker(move || {
         let mut args = cmd_and_args.iter();
         let mut cmd = std::process::Command::new(
             args.next()
                 .expect("parsing should have forbidden empty cmd"),
         );
-        for arg in args {
-            cmd.arg(arg);
-        }
-        match cmd.output() {
+        cmd.args(args);
+        let text = match cmd.output() {
+            Ok(output) if output.status.success() => {
+                String::from_utf8_lossy(&output.stdout).into_owned()
+            }
             Ok(output) => {
-                log::info!(
-                    "Successfully ran cmd {}\nstdout:\n{}\nstderr:\n{}",
-                    {
-                        let mut printable_cmd = Vec::new();
-                        printable_cmd.push(format!("{:?}", cmd.get_program()));
-
-                        let printable_cmd = cmd.get_args().fold(printable_cmd, |mut cmd, arg| {
-                            cmd.push(format!("{arg:?}"));
-                            cmd
-                        });
-                        printable_cmd.join(" ")
-                    },
-                    String::from_utf8_lossy(&output.stdout),
+                log::error!(
+                    "cmd {cmd_and_args:?} failed with {}, not typing its output\nstderr:\n{}",
+                    output.status,
                     String::from_utf8_lossy(&output.stderr)
                 );
+                String::new()
+            }
+            Err(e) => {
+                log::error!("Failed to execute cmd: {e}");
+                String::new()
             }
-            Err(e) => log::error!("Failed to execute cmd: {}", e),
         };
-    })
+        if send_completion(&completion_tx, CmdCompletion::TypeText { text, typing }).is_err() {
+            log::warn!("could not send cmd output: processing loop is gone");
+        }
+    });
 }
 
 pub(super) type Item = (KeyAction, OsCode);
@@ -0,0 +1,226 @@
+//! Reads state from outside of kanata for the `state-file`, `env-var`, `power`, `battery-below`
+//! and `time-between` switch conditions.
+
+use super::*;
+
+/// How often the sources of external state conditions are read.
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(500);
+
+fn is_satisfied(cond: &ExternalStateCondition) -> bool {
+    match &cond.value {
+        ExternalStateValue::Equals(value) => {
+            read_text(&cond.source).is_some_and(|v| v.trim() == value)
+        }
+        ExternalStateValue::Range(from, to) => {
+            read_number(&cond.source).is_some_and(|n| in_range(n, *from, *to))
+        }
+    }
+}
+
+/// Returns whether `n` is in the range of `ExternalStateValue::Range`.
+fn in_range(n: u16, from: u16, to: u16) -> bool {
+    match from <= to {
+        true => from <= n && n < to,
+        false => from <= n || n < to,
+    }
+}
+
+fn read_text(source: &ExternalStateSource) -> Option<String> {
+    match source {
+        ExternalStateSource::File(path) => std::fs::read_to_string(path).ok(),
+        ExternalStateSource::EnvVar(var) => std::env::var(var).ok(),
+        ExternalStateSource::Power => on_ac_power().map(|ac| match ac {
+            true => "ac".to_owned(),
+            false => "battery".to_owned(),
+        }),
+        ExternalStateSource::BatteryLevel | ExternalStateSource::TimeOfDay => None,
+    }
+}
+
+fn read_number(source: &ExternalStateSource) -> Option<u16> {
+    match source {
+        ExternalStateSource::BatteryLevel => battery_level(),
+        ExternalStateSource::TimeOfDay => minutes_since_midnight(),
+        _ => None,
+    }
+}
+
+/// The directory with the power supplies of the system, e.g. `AC` and `BAT0`.
+#[cfg(target_os = "linux")]
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+/// Returns the `(type, value of the file)` of each power supply, e.g. `("Battery", "87")` for the
+/// file `capacity`. Supplies without the file are skipped.
+#[cfg(target_os = "linux")]
+fn power_supplies(file: &str) -> Vec<(String, String)> {
+    let Ok(entries) = std::fs::read_dir(POWER_SUPPLY_DIR) else {
+        return vec![];
+    };
+    entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let kind = std::fs::read_to_string(path.join("type")).ok()?;
+            let value = std::fs::read_to_string(path.join(file)).ok()?;
+            Some((kind.trim().to_owned(), value.trim().to_owned()))
+        })
+        .collect()
+}
+
+/// Returns whether the system runs on AC power. A system without batteries, e.g. a desktop, is
+/// always on AC power.
+#[cfg(target_os = "linux")]
+fn on_ac_power() -> Option<bool> {
+    let mains: Vec<_> = power_supplies("online")
+        .into_iter()
+        .filter(|(kind, _)| kind == "Mains")
+        .collect();
+    match mains.is_empty() {
+        true => Some(battery_level().is_none()),
+        false => Some(mains.iter().any(|(_, online)| online == "1")),
+    }
+}
+
+/// Returns the charge of the first battery in percent.
+#[cfg(target_os = "linux")]
+fn battery_level() -> Option<u16> {
+    power_supplies("capacity")
+        .into_iter()
+        .filter(|(kind, _)| kind == "Battery")
+        .find_map(|(_, capacity)| capacity.parse().ok())
+}
+
+#[cfg(target_os = "linux")]
+fn minutes_since_midnight() -> Option<u16> {
+    use nix::libc;
+    // SAFETY: `localtime_r` writes the broken down time to `tm`, which outlives the call.
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            return None;
+        }
+        Some((tm.tm_hour * 60 + tm.tm_min) as u16)
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn power_status() -> Option<winapi::um::winbase::SYSTEM_POWER_STATUS> {
+    // SAFETY: `GetSystemPowerStatus` only writes to the status that it is given.
+    unsafe {
+        let mut status = std::mem::zeroed();
+        match winapi::um::winbase::GetSystemPowerStatus(&mut status) {
+            0 => None,
+            _ => Some(status),
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn on_ac_power() -> Option<bool> {
+    // 255 means the status is unknown.
+    match power_status()?.ACLineStatus {
+        0 => Some(false),
+        1 => Some(true),
+        _ => None,
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn battery_level() -> Option<u16> {
+    // 255 means the level is unknown, e.g. because there is no battery.
+    match power_status()?.BatteryLifePercent {
+        percent @ 0..=100 => Some(u16::from(percent)),
+        _ => None,
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn minutes_since_midnight() -> Option<u16> {
+    // SAFETY: `GetLocalTime` only writes to the time that it is given.
+    let time = unsafe {
+        let mut time = std::mem::zeroed();
+        winapi::um::sysinfoapi::GetLocalTime(&mut time);
+        time
+    };
+    Some(time.wHour * 60 + time.wMinute)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn on_ac_power() -> Option<bool> {
+    None
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn battery_level() -> Option<u16> {
+    None
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn minutes_since_midnight() -> Option<u16> {
+    None
+}
+
+/// Returns the switch flags of the conditions that are currently true.
+pub(super) fn satisfied_flags(conditions: &[ExternalStateCondition]) -> u64 {
+    conditions
+        .iter()
+        .filter(|cond| is_satisfied(cond))
+        .fold(0, |flags, cond| flags | 1 << cond.flag)
+}
+
+/// Returns whether the condition can change while kanata runs, so that its source must be read
+/// again periodically. The environment of kanata cannot be changed from outside, so `env-var`
+/// conditions are only checked when the configuration is loaded.
+fn is_polled(cond: &ExternalStateCondition) -> bool {
+    !matches!(cond.source, ExternalStateSource::EnvVar(_))
+}
+
+/// Returns the switch flags used by the conditions.
+fn flag_mask(conditions: &[ExternalStateCondition]) -> u64 {
+    conditions
+        .iter()
+        .fold(0, |flags, cond| flags | 1 << cond.flag)
+}
+
+impl Kanata {
+    /// Starts a new thread that periodically reads the sources of external state conditions,
+    /// except for environment variables, and updates the switch flags to match.
+    pub fn start_external_state_watcher(kanata: Arc<Mutex<Self>>) {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(POLL_INTERVAL);
+            let conditions = kanata.lock().external_states.clone();
+            let polled: Vec<_> = conditions
+                .iter()
+                .filter(|c| is_polled(c))
+                .cloned()
+                .collect();
+            if polled.is_empty() {
+                continue;
+            }
+            // Read the sources without holding the lock so that slow reads, e.g. from a network
+            // drive, do not delay key processing.
+            let flags = satisfied_flags(&polled);
+            let mut k = kanata.lock();
+            if k.external_states != conditions {
+                // Live reload happened while reading, the flags may no longer be correct.
+                continue;
+            }
+            let layout = k.layout.bm();
+            let new_flags = (layout.flags & !flag_mask(&polled)) | flags;
+            if new_flags != layout.flags {
+                log::debug!("external state changed, flags: {new_flags:#x}");
+                layout.flags = new_flags;
+            }
+        });
+    }
+}
+
+#[test]
+fn test_in_range() {
+    assert!(in_range(0, 0, 20));
+    assert!(!in_range(20, 0, 20));
+    // 22:00 to 06:00
+    assert!(in_range(23 * 60, 22 * 60, 6 * 60));
+    assert!(in_range(60, 22 * 60, 6 * 60));
+    assert!(!in_range(12 * 60, 22 * 60, 6 * 60));
+}
@@ -0,0 +1,40 @@
+//! Persistence of the fn-lock flag across restarts of kanata.
+
+use std::path::PathBuf;
+
+const ON: &str = "on";
+const OFF: &str = "off";
+
+fn state_file() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|dir| dir.join("kanata").join("fn-lock"))
+}
+
+/// Returns whether fn-lock was on when kanata last saved it.
+pub(super) fn load() -> bool {
+    let Some(path) = state_file() else {
+        return false;
+    };
+    match std::fs::read_to_string(&path) {
+        Ok(state) => state.trim() == ON,
+        Err(e) => {
+            log::debug!("could not read fn-lock state from {}: {e}", path.display());
+            false
+        }
+    }
+}
+
+/// Saves the fn-lock state so that it is restored the next time kanata starts.
+pub(super) fn save(on: bool) {
+    let Some(path) = state_file() else {
+        log::warn!("no data directory is known, fn-lock state will not be saved");
+        return;
+    };
+    let res = path
+        .parent()
+        .map(std::fs::create_dir_all)
+        .unwrap_or(Ok(()))
+        .and_then(|_| std::fs::write(&path, if on { ON } else { OFF }));
+    if let Err(e) = res {
+        log::warn!("could not save fn-lock state to {}: {e}", path.display());
+    }
+}
@@ -0,0 +1,309 @@
+//! Swipe gestures of Linux touchpads. The touches of a multitouch touchpad are followed and a swipe
+//! key, e.g. `sw3l` for a three-finger swipe to the left, is sent once the fingers have moved by
+//! `linux-touchpad-swipe-distance` percent of the touchpad size. One swipe is sent per gesture.
+//!
+//! While a gesture with a swipe key in `defsrc` is on the touchpad, its frames are not passed
+//! through, so the desktop does not also act on the gesture. The touches are lifted on the
+//! passthrough device once all fingers have left the touchpad.
+
+use anyhow::{anyhow, bail, Result};
+
+use evdev::{AbsoluteAxisType, InputEvent, InputEventKind, Key};
+
+use kanata_parser::cfg::MappedKeys;
+use kanata_parser::keys::OsCode;
+
+use super::HashMap;
+
+const SWIPES: [[OsCode; 4]; 2] = [
+    [
+        OsCode::Swipe3Up,
+        OsCode::Swipe3Down,
+        OsCode::Swipe3Left,
+        OsCode::Swipe3Right,
+    ],
+    [
+        OsCode::Swipe4Up,
+        OsCode::Swipe4Down,
+        OsCode::Swipe4Left,
+        OsCode::Swipe4Right,
+    ],
+];
+
+/// Returns the swipe keys of the number of fingers, if there are any.
+fn swipes(fingers: u8) -> Option<&'static [OsCode; 4]> {
+    match fingers {
+        3 => Some(&SWIPES[0]),
+        4 => Some(&SWIPES[1]),
+        _ => None,
+    }
+}
+
+/// What to do with the passthrough events of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum FramePassthrough {
+    Write,
+    Drop,
+    /// Drop the frame and lift every touch instead.
+    LiftAll,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Swipe {
+    fingers: u8,
+    start: [i32; 2],
+}
+
+/// The touches of one touchpad.
+#[derive(Debug, Default)]
+pub(super) struct TouchState {
+    slot: usize,
+    /// The last position of each slot. Positions are kept after a touch is lifted, since the
+    /// kernel does not repeat an unchanged position for the next touch of the slot.
+    positions: Vec<[i32; 2]>,
+    active: Vec<bool>,
+    fingers: u8,
+    touching: bool,
+    swipe: Option<Swipe>,
+    /// A swipe was sent for the current gesture.
+    done: bool,
+    suppressing: bool,
+}
+
+impl TouchState {
+    pub(super) fn event(&mut self, event: &InputEvent) {
+        let value = event.value();
+        match event.kind() {
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_MT_SLOT) => {
+                self.slot = value.try_into().unwrap_or(0);
+                if self.positions.len() <= self.slot {
+                    self.positions.resize(self.slot + 1, [0; 2]);
+                    self.active.resize(self.slot + 1, false);
+                }
+            }
+            InputEventKind::AbsAxis(axis) => {
+                if self.positions.is_empty() {
+                    self.positions.push([0; 2]);
+                    self.active.push(false);
+                }
+                match axis {
+                    AbsoluteAxisType::ABS_MT_TRACKING_ID => self.active[self.slot] = value >= 0,
+                    AbsoluteAxisType::ABS_MT_POSITION_X => self.positions[self.slot][0] = value,
+                    AbsoluteAxisType::ABS_MT_POSITION_Y => self.positions[self.slot][1] = value,
+                    _ => {}
+                }
+            }
+            InputEventKind::Key(key) => {
+                let fingers = match key {
+                    Key::BTN_TOUCH => {
+                        self.touching = value != 0;
+                        return;
+                    }
+                    Key::BTN_TOOL_FINGER => 1,
+                    Key::BTN_TOOL_DOUBLETAP => 2,
+                    Key::BTN_TOOL_TRIPLETAP => 3,
+                    Key::BTN_TOOL_QUADTAP => 4,
+                    Key::BTN_TOOL_QUINTTAP => 5,
+                    _ => return,
+                };
+                if value != 0 {
+                    self.fingers = fingers;
+                } else if self.fingers == fingers {
+                    self.fingers = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles the end of a frame. `size` is the extent of the position axes and
+    /// `swipe_distance` the percentage of it that the fingers must move for a swipe. Returns the
+    /// swipe key to send, if any, and what to do with the frame.
+    pub(super) fn end_frame(
+        &mut self,
+        size: (i32, i32),
+        swipe_distance: u8,
+        mapped_keys: &MappedKeys,
+    ) -> (Option<OsCode>, FramePassthrough) {
+        if !self.touching {
+            self.swipe = None;
+            self.done = false;
+            return match std::mem::take(&mut self.suppressing) {
+                true => (None, FramePassthrough::LiftAll),
+                false => (None, FramePassthrough::Write),
+            };
+        }
+        let mapped = swipes(self.fingers)
+            .filter(|codes| codes.iter().any(|code| mapped_keys.contains(code)));
+        self.suppressing |= mapped.is_some();
+        let passthrough = match self.suppressing {
+            true => FramePassthrough::Drop,
+            false => FramePassthrough::Write,
+        };
+        let (Some(codes), Some(centroid), false) = (mapped, self.centroid(), self.done) else {
+            self.swipe = None;
+            return (None, passthrough);
+        };
+        let swipe = match self.swipe {
+            Some(swipe) if swipe.fingers == self.fingers => swipe,
+            _ => {
+                self.swipe = Some(Swipe {
+                    fingers: self.fingers,
+                    start: centroid,
+                });
+                return (None, passthrough);
+            }
+        };
+        let percent = |i: usize, size: i32| {
+            i64::from(centroid[i] - swipe.start[i]) * 100 / i64::from(size.max(1))
+        };
+        let (x, y) = (percent(0, size.0), percent(1, size.1));
+        if x.abs().max(y.abs()) < i64::from(swipe_distance) {
+            return (None, passthrough);
+        }
+        self.done = true;
+        // Touchpad positions grow downwards.
+        let code = match (x.abs() > y.abs(), x > 0, y > 0) {
+            (false, _, false) => codes[0],
+            (false, _, true) => codes[1],
+            (true, false, _) => codes[2],
+            (true, true, _) => codes[3],
+        };
+        (
+            Some(code).filter(|code| mapped_keys.contains(code)),
+            passthrough,
+        )
+    }
+
+    fn centroid(&self) -> Option<[i32; 2]> {
+        let touches = self
+            .positions
+            .iter()
+            .zip(self.active.iter())
+            .filter(|(_, active)| **active)
+            .map(|(position, _)| position);
+        let (count, sum) = touches.fold((0i64, [0i64; 2]), |(count, sum), position| {
+            (
+                count + 1,
+                [
+                    sum[0] + i64::from(position[0]),
+                    sum[1] + i64::from(position[1]),
+                ],
+            )
+        });
+        match count {
+            0 => None,
+            _ => Some([(sum[0] / count) as i32, (sum[1] / count) as i32]),
+        }
+    }
+}
+
+/// The touch states of all touchpads by device path.
+#[derive(Debug, Default)]
+pub(super) struct TouchpadGestures {
+    devices: HashMap<String, TouchState>,
+}
+
+impl TouchpadGestures {
+    pub(super) fn device(&mut self, device_path: &str) -> &mut TouchState {
+        if !self.devices.contains_key(device_path) {
+            self.devices
+                .insert(device_path.to_owned(), TouchState::default());
+        }
+        self.devices.get_mut(device_path).expect("inserted")
+    }
+}
+
+/// Parses `linux-touchpad-swipe-distance`, the percentage of the touchpad size that fingers must
+/// move for a swipe.
+pub(super) fn parse_swipe_distance(cfg_items: &HashMap<String, String>) -> Result<u8> {
+    const ERR: &str = "linux-touchpad-swipe-distance must be a percentage 1-100";
+    let Some(value) = cfg_items.get("linux-touchpad-swipe-distance") else {
+        return Ok(20);
+    };
+    match value.parse::<u8>() {
+        Ok(distance @ 1..=100) => Ok(distance),
+        Ok(_) => bail!("{ERR}, got {value}"),
+        Err(e) => Err(anyhow!("{ERR}, got {value}: {e}")),
+    }
+}
+
+#[test]
+fn touchpad_swipes() {
+    use evdev::EventType;
+
+    let abs = |axis: AbsoluteAxisType, value| InputEvent::new(EventType::ABSOLUTE, axis.0, value);
+    let key = |key: Key, value| InputEvent::new(EventType::KEY, key.code(), value);
+    let touch = |state: &mut TouchState, slot, x, y| {
+        state.event(&abs(AbsoluteAxisType::ABS_MT_SLOT, slot));
+        state.event(&abs(AbsoluteAxisType::ABS_MT_TRACKING_ID, slot));
+        state.event(&abs(AbsoluteAxisType::ABS_MT_POSITION_X, x));
+        state.event(&abs(AbsoluteAxisType::ABS_MT_POSITION_Y, y));
+    };
+    let mut mapped_keys = MappedKeys::default();
+    mapped_keys.insert(OsCode::Swipe3Left);
+    let mut state = TouchState::default();
+    let size = (1000, 500);
+
+    // Two fingers are passed through.
+    state.event(&key(Key::BTN_TOUCH, 1));
+    state.event(&key(Key::BTN_TOOL_DOUBLETAP, 1));
+    touch(&mut state, 0, 500, 200);
+    touch(&mut state, 1, 600, 200);
+    assert_eq!(
+        state.end_frame(size, 20, &mapped_keys),
+        (None, FramePassthrough::Write)
+    );
+
+    // A third finger starts a mapped gesture, which sends one swipe after moving far enough.
+    touch(&mut state, 2, 700, 200);
+    state.event(&key(Key::BTN_TOOL_DOUBLETAP, 0));
+    state.event(&key(Key::BTN_TOOL_TRIPLETAP, 1));
+    assert_eq!(
+        state.end_frame(size, 20, &mapped_keys),
+        (None, FramePassthrough::Drop)
+    );
+    for slot in 0..3 {
+        state.event(&abs(AbsoluteAxisType::ABS_MT_SLOT, slot));
+        state.event(&abs(AbsoluteAxisType::ABS_MT_POSITION_X, 400 + 100 * slot));
+    }
+    assert_eq!(
+        state.end_frame(size, 20, &mapped_keys),
+        (None, FramePassthrough::Drop)
+    );
+    for slot in 0..3 {
+        state.event(&abs(AbsoluteAxisType::ABS_MT_SLOT, slot));
+        state.event(&abs(AbsoluteAxisType::ABS_MT_POSITION_X, 300 + 100 * slot));
+    }
+    assert_eq!(
+        state.end_frame(size, 20, &mapped_keys),
+        (Some(OsCode::Swipe3Left), FramePassthrough::Drop)
+    );
+    for slot in 0..3 {
+        state.event(&abs(AbsoluteAxisType::ABS_MT_SLOT, slot));
+        state.event(&abs(AbsoluteAxisType::ABS_MT_POSITION_X, 100 + 100 * slot));
+    }
+    assert_eq!(
+        state.end_frame(size, 20, &mapped_keys),
+        (None, FramePassthrough::Drop)
+    );
+
+    // Lifting all fingers ends the gesture.
+    state.event(&key(Key::BTN_TOUCH, 0));
+    state.event(&key(Key::BTN_TOOL_TRIPLETAP, 0));
+    assert_eq!(
+        state.end_frame(size, 20, &mapped_keys),
+        (None, FramePassthrough::LiftAll)
+    );
+    assert_eq!(
+        state.end_frame(size, 20, &mapped_keys),
+        (None, FramePassthrough::Write)
+    );
+
+    let mut cfg_items = HashMap::default();
+    assert_eq!(parse_swipe_distance(&cfg_items).unwrap(), 20);
+    cfg_items.insert("linux-touchpad-swipe-distance".into(), "35".into());
+    assert_eq!(parse_swipe_distance(&cfg_items).unwrap(), 35);
+    cfg_items.insert("linux-touchpad-swipe-distance".into(), "0".into());
+    assert!(parse_swipe_distance(&cfg_items).is_err());
+}
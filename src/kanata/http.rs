@@ -0,0 +1,153 @@
+//! Sending of HTTP POST requests for the `http-post` action, e.g. to trigger Home Assistant
+//! webhooks. Only plain `http://` URLs are supported.
+
+use anyhow::{anyhow, Result};
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use super::worker::run_on_worker;
+use super::HashMap;
+
+pub(super) struct HttpPoster {
+    timeout: Duration,
+    min_interval: Duration,
+    /// When each URL was last posted to, used to drop posts that come too quickly.
+    last_post: HashMap<String, Instant>,
+}
+
+impl HttpPoster {
+    pub(super) fn from_cfg(cfg_items: &HashMap<String, String>) -> Result<Self> {
+        let ms = |key: &str, default: u16| -> Result<Duration> {
+            cfg_items
+                .get(key)
+                .map(|s| s.parse::<u16>())
+                .unwrap_or(Ok(default))
+                .map(|ms| Duration::from_millis(ms.into()))
+                .map_err(|e| anyhow!("{key} must be 0-65535: {e}"))
+        };
+        Ok(Self {
+            timeout: ms("http-post-timeout", 5000)?,
+            min_interval: ms("http-post-min-interval", 1000)?,
+            last_post: HashMap::default(),
+        })
+    }
+
+    /// Sends the request on a worker thread, unless the URL was posted to less than the minimum
+    /// interval ago.
+    pub(super) fn post(&mut self, url: &str, body: &str) {
+        let now = Instant::now();
+        if let Some(last) = self.last_post.get(url) {
+            if now.duration_since(*last) < self.min_interval {
+                log::warn!("http-post to {url} dropped: rate limited");
+                return;
+            }
+        }
+        self.last_post.insert(url.to_owned(), now);
+        let (url, body, timeout) = (url.to_owned(), body.to_owned(), self.timeout);
+        run_on_worker(move || match send_post(&url, &body, timeout) {
+            Ok(status) if is_success(&status) => log::info!("http-post to {url}: {status}"),
+            Ok(status) => log::warn!("http-post to {url}: {status}"),
+            Err(e) => log::error!("http-post to {url} failed: {e}"),
+        });
+    }
+}
+
+struct Url<'a> {
+    /// The host and port, e.g. `homeassistant.local:8123`.
+    authority: &'a str,
+    host: &'a str,
+    path: &'a str,
+}
+
+fn parse_url(url: &str) -> io::Result<Url<'_>> {
+    let rest = url.strip_prefix("http://").ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only http:// URLs are supported",
+        )
+    })?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let host = match authority.rfind(':') {
+        Some(i) if !authority.ends_with(']') => &authority[..i],
+        _ => authority,
+    };
+    Ok(Url {
+        authority,
+        host,
+        path,
+    })
+}
+
+/// Encodes the request. The body is sent as JSON if it looks like a JSON object or array, as form
+/// data if it looks like `key=value` pairs, and as plain text otherwise.
+fn encode_request(url: &Url, body: &str) -> String {
+    let content_type = match body.trim_start().chars().next() {
+        Some('{') | Some('[') => "application/json",
+        _ if body.contains('=') && !body.contains(char::is_whitespace) => {
+            "application/x-www-form-urlencoded"
+        }
+        _ => "text/plain; charset=utf-8",
+    };
+    format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: kanata\r\nContent-Type: {content_type}\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        url.path,
+        url.authority,
+        body.len(),
+    )
+}
+
+/// Sends the request and returns the status line of the response.
+fn send_post(url: &str, body: &str, timeout: Duration) -> io::Result<String> {
+    let parsed = parse_url(url)?;
+    let port_given = parsed.authority.len() > parsed.host.len();
+    let addr = if port_given {
+        parsed.authority.to_socket_addrs()
+    } else {
+        (parsed.host.trim_matches(|c| c == '[' || c == ']'), 80).to_socket_addrs()
+    }?
+    .next()
+    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the host has no addresses"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.write_all(encode_request(&parsed, body).as_bytes())?;
+    let mut response = [0u8; 256];
+    let len = stream.read(&mut response)?;
+    let response = String::from_utf8_lossy(&response[..len]);
+    Ok(response.lines().next().unwrap_or_default().to_owned())
+}
+
+/// Returns whether the status line, e.g. `HTTP/1.1 200 OK`, has a 2xx status code.
+fn is_success(status_line: &str) -> bool {
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .map(|code| code.starts_with('2'))
+        .unwrap_or_default()
+}
+
+#[test]
+fn http_request_encoding() {
+    let url = parse_url("http://homeassistant.local:8123/api/webhook/lights").unwrap();
+    assert_eq!(url.host, "homeassistant.local");
+    assert_eq!(
+        encode_request(&url, r#"{"on":true}"#),
+        "POST /api/webhook/lights HTTP/1.1\r\nHost: homeassistant.local:8123\r\n\
+         User-Agent: kanata\r\nContent-Type: application/json\r\nContent-Length: 11\r\n\
+         Connection: close\r\n\r\n{\"on\":true}"
+    );
+    assert!(encode_request(&url, "state=on&brightness=50")
+        .contains("Content-Type: application/x-www-form-urlencoded\r\n"));
+    assert!(encode_request(&url, "on").contains("Content-Type: text/plain"));
+    let url = parse_url("http://[::1]").unwrap();
+    assert_eq!((url.host, url.path), ("[::1]", "/"));
+    assert!(parse_url("https://example.com").is_err());
+    assert!(is_success("HTTP/1.1 204 No Content"));
+    assert!(!is_success("HTTP/1.1 404 Not Found"));
+}
@@ -0,0 +1,153 @@
+//! Describes the action bound to a key as JSON for the `RequestKeyAction` TCP message, e.g. for
+//! "what does this key do?" features of GUIs. The parser has already replaced aliases and
+//! variables by the actions they stand for, so the description is of the action itself rather
+//! than of its text in the configuration.
+
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+
+use kanata_keyberon::action::{Action, SequenceEvent};
+use kanata_parser::cfg::LayerInfo;
+use kanata_parser::custom_action::CustomAction;
+use kanata_parser::keys::str_to_oscode;
+
+use super::Kanata;
+
+impl Kanata {
+    /// Returns the action of the `defsrc` key named `key` in the layer named `layer`.
+    pub fn key_action_json(&self, layer: &str, key: &str) -> Result<Value> {
+        let layer_idx = self.layer_index(layer)?;
+        let osc = str_to_oscode(key).ok_or_else(|| anyhow!("unknown key: {key}"))?;
+        // A change made by `SetKeyAction` is described even before it is swapped into the layout.
+        let actions = self.key_actions(layer_idx, osc);
+        Ok(action_json(actions.action(), &self.layer_info))
+    }
+}
+
+/// Returns the action as an object whose `type` is the kind of action, along with the fields of
+/// that kind. Nested actions are described the same way. Layers are named by `layer_info`.
+fn action_json<'a>(
+    action: &Action<'a, &'a &'a [&'a CustomAction]>,
+    layer_info: &[LayerInfo],
+) -> Value {
+    let layer_name = |idx: usize| {
+        layer_info
+            .get(idx)
+            .map(|l| l.name.clone())
+            .unwrap_or_default()
+    };
+    let nested = |action: &Action<'a, &'a &'a [&'a CustomAction]>| action_json(action, layer_info);
+    match action {
+        Action::NoOp => json!({"type": "NoOp"}),
+        Action::Trans => json!({"type": "Trans"}),
+        Action::KeyCode(kc) => json!({"type": "KeyCode", "key": format!("{kc:?}")}),
+        Action::MultipleKeyCodes(kcs) => json!({
+            "type": "MultipleKeyCodes",
+            "keys": kcs.iter().map(|kc| format!("{kc:?}")).collect::<Vec<_>>(),
+        }),
+        Action::MultipleActions(actions) => json!({
+            "type": "MultipleActions",
+            "actions": actions.iter().map(nested).collect::<Vec<_>>(),
+        }),
+        Action::Layer(idx) => json!({"type": "Layer", "layer": layer_name(*idx)}),
+        Action::DefaultLayer(idx) => json!({"type": "DefaultLayer", "layer": layer_name(*idx)}),
+        Action::Sequence { events } => json!({
+            "type": "Sequence",
+            "events": sequence_json(events),
+        }),
+        Action::RepeatableSequence { events } => json!({
+            "type": "RepeatableSequence",
+            "events": sequence_json(events),
+        }),
+        Action::CancelSequences => json!({"type": "CancelSequences"}),
+        Action::ReleaseState(state) => {
+            json!({"type": "ReleaseState", "state": format!("{state:?}")})
+        }
+        Action::HoldTap(ht) => json!({
+            "type": "HoldTap",
+            "timeout": ht.timeout,
+            "tap_hold_interval": ht.tap_hold_interval,
+            "config": format!("{:?}", ht.config),
+            "tap": nested(&ht.tap),
+            "hold": nested(&ht.hold),
+            "timeout_action": nested(&ht.timeout_action),
+        }),
+        Action::Custom(customs) => json!({
+            "type": "Custom",
+            "actions": customs.iter().map(|c| format!("{c:?}")).collect::<Vec<_>>(),
+        }),
+        Action::OneShot(os) => json!({
+            "type": "OneShot",
+            "timeout": os.timeout,
+            "end_config": format!("{:?}", os.end_config),
+            "action": nested(os.action),
+        }),
+        Action::TapDance(td) => json!({
+            "type": "TapDance",
+            "timeout": td.timeout,
+            "config": format!("{:?}", td.config),
+            "actions": td.actions.iter().map(|a| nested(a)).collect::<Vec<_>>(),
+        }),
+        Action::Chords(group) => json!({
+            "type": "Chords",
+            "timeout": group.timeout,
+            "chords": group
+                .chords
+                .iter()
+                .map(|(keys, action)| json!({"keys": keys, "action": nested(action)}))
+                .collect::<Vec<_>>(),
+        }),
+        Action::Repeat => json!({"type": "Repeat"}),
+        Action::Fork(fork) => json!({
+            "type": "Fork",
+            "left": nested(&fork.left),
+            "right": nested(&fork.right),
+            "right_triggers": fork
+                .right_triggers
+                .iter()
+                .map(|kc| format!("{kc:?}"))
+                .collect::<Vec<_>>(),
+        }),
+        Action::Switch(switch) => json!({
+            "type": "Switch",
+            "cases": switch
+                .cases
+                .iter()
+                .map(|(_, action, brk)| json!({
+                    "action": nested(action),
+                    "break_or_fallthrough": format!("{brk:?}"),
+                }))
+                .collect::<Vec<_>>(),
+        }),
+    }
+}
+
+fn sequence_json<T>(events: &[SequenceEvent<T>]) -> Vec<String> {
+    events.iter().map(|event| format!("{event:?}")).collect()
+}
+
+#[test]
+fn action_json_describes_nested_actions() {
+    use kanata_keyberon::action::{HoldTapAction, HoldTapConfig};
+    use kanata_keyberon::key_code::KeyCode;
+    let layer_info = |name: &str| LayerInfo {
+        name: name.into(),
+        cfg_text: String::new(),
+        bindings: vec![],
+        cfg_overrides: Default::default(),
+    };
+    let layer_info = [layer_info("base"), layer_info("base"), layer_info("nav")];
+    let hold_tap = HoldTapAction {
+        timeout: 200,
+        hold: Action::Layer(2),
+        tap: Action::KeyCode(KeyCode::Escape),
+        timeout_action: Action::Layer(2),
+        config: HoldTapConfig::Default,
+        tap_hold_interval: 0,
+    };
+    let json = action_json(&Action::HoldTap(&hold_tap), &layer_info);
+    assert_eq!(json["type"], "HoldTap");
+    assert_eq!(json["timeout"], 200);
+    assert_eq!(json["tap"], json!({"type": "KeyCode", "key": "Escape"}));
+    assert_eq!(json["hold"], json!({"type": "Layer", "layer": "nav"}));
+}
@@ -0,0 +1,276 @@
+//! Changes made by the `SetKeyAction` TCP message and their history, so that TCP clients can undo
+//! and redo the changes until the next live reload.
+
+use anyhow::{anyhow, bail, Result};
+
+use std::collections::VecDeque;
+use std::path::Path;
+
+use kanata_parser::cfg::{EditedFiles, KeyActions};
+
+/// Older changes are forgotten so that the history does not grow without bound.
+const MAX_KEY_ACTION_HISTORY: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryStep {
+    Undo,
+    Redo,
+}
+
+impl HistoryStep {
+    pub(super) fn verb(self) -> &'static str {
+        match self {
+            HistoryStep::Undo => "undo",
+            HistoryStep::Redo => "redo",
+        }
+    }
+}
+
+/// A state of the edits in the history and the key whose change leads to or from it.
+#[derive(Debug)]
+pub(super) struct KeyActionChange {
+    pub(super) layer: String,
+    pub(super) key: String,
+    pub(super) edits: EditedFiles,
+    /// The actions of the key in this state. Parsing the item of the key again could fail, e.g.
+    /// if its action adds fake keys, so the actions are kept.
+    pub(super) actions: KeyActions,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct KeyActionEdits {
+    /// Edits of the configuration files that are not written to the files.
+    current: EditedFiles,
+    /// The text on disk of the edited files that the edits are based on, read when a file is first
+    /// edited and updated when the edits are written.
+    base: EditedFiles,
+    undo: VecDeque<KeyActionChange>,
+    redo: Vec<KeyActionChange>,
+}
+
+impl KeyActionEdits {
+    pub(super) fn current(&self) -> &EditedFiles {
+        &self.current
+    }
+
+    /// Makes `edits`, which change the action of `key` in `layer` from `actions`, the current
+    /// edits. The changes that were undone can no longer be redone.
+    pub(super) fn set(
+        &mut self,
+        layer: &str,
+        key: &str,
+        edits: EditedFiles,
+        actions: KeyActions,
+    ) -> Result<()> {
+        for path in edits.keys() {
+            if !self.base.contains_key(path) {
+                self.base.insert(path.clone(), read_cfg_file(path)?);
+            }
+        }
+        let previous = std::mem::replace(&mut self.current, edits);
+        self.push_undo(KeyActionChange {
+            layer: layer.to_owned(),
+            key: key.to_owned(),
+            edits: previous,
+            actions,
+        });
+        self.redo.clear();
+        Ok(())
+    }
+
+    /// Returns the change that `step` would go to, if there is one.
+    pub(super) fn peek(&self, step: HistoryStep) -> Option<&KeyActionChange> {
+        match step {
+            HistoryStep::Undo => self.undo.back(),
+            HistoryStep::Redo => self.redo.last(),
+        }
+    }
+
+    /// Makes the edits of the change that `step` goes to the current edits, where `actions` are
+    /// the current actions of the key of the change. Returns the layer and key of the change.
+    pub(super) fn step(
+        &mut self,
+        step: HistoryStep,
+        actions: KeyActions,
+    ) -> Option<(String, String)> {
+        let change = match step {
+            HistoryStep::Undo => self.undo.pop_back(),
+            HistoryStep::Redo => self.redo.pop(),
+        }?;
+        let previous = KeyActionChange {
+            layer: change.layer.clone(),
+            key: change.key.clone(),
+            edits: std::mem::replace(&mut self.current, change.edits),
+            actions,
+        };
+        match step {
+            HistoryStep::Undo => self.redo.push(previous),
+            HistoryStep::Redo => self.push_undo(previous),
+        }
+        Some((change.layer, change.key))
+    }
+
+    /// Writes the current edits to the files. The history keeps the previous text of the files,
+    /// so undoing a change that was written goes back to the text before it.
+    ///
+    /// Fails without writing anything if a file was changed on disk since it was first edited, so
+    /// that changes saved by the user are not overwritten. Each file is written to a temporary file
+    /// that replaces it, so that a failed write never leaves a partially written configuration.
+    pub(super) fn persist(&mut self) -> Result<()> {
+        self.check_unchanged_on_disk(&self.current)?;
+        for (path, text) in self.current.iter() {
+            let previous = self
+                .base
+                .insert(path.clone(), text.clone())
+                .expect("checked");
+            for change in self.undo.iter_mut().chain(self.redo.iter_mut()) {
+                change
+                    .edits
+                    .entry(path.clone())
+                    .or_insert_with(|| previous.clone());
+            }
+            write_cfg_file(path, text)?;
+        }
+        // The files now have the text of the edits.
+        self.current.clear();
+        Ok(())
+    }
+
+    /// Fails if a file of `edits` that was already edited was changed on disk since then. Used to
+    /// check that `edits` can be persisted before making them.
+    pub(super) fn check_unchanged_on_disk(&self, edits: &EditedFiles) -> Result<()> {
+        for path in edits.keys() {
+            let Some(base) = self.base.get(path) else {
+                continue;
+            };
+            if *base != read_cfg_file(path)? {
+                bail!(
+                    "{} was changed on disk since it was loaded, live reload it before \
+                    persisting key action changes",
+                    path.display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Discards the edits and their history, e.g. because the files were reloaded.
+    pub(super) fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    fn push_undo(&mut self, change: KeyActionChange) {
+        if self.undo.len() == MAX_KEY_ACTION_HISTORY {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(change);
+    }
+}
+
+fn read_cfg_file(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).map_err(|e| anyhow!("failed to read {}: {e}", path.display()))
+}
+
+/// Replaces the file at `path`, or the file that it links to, with `text`.
+fn write_cfg_file(path: &Path, text: &str) -> Result<()> {
+    let write = || -> std::io::Result<()> {
+        let path = std::fs::canonicalize(path)?;
+        let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+        tmp_name.push(".kanata-tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+        std::fs::write(&tmp_path, text)?;
+        let renamed = std::fs::metadata(&path)
+            .and_then(|metadata| std::fs::set_permissions(&tmp_path, metadata.permissions()))
+            .and_then(|_| std::fs::rename(&tmp_path, &path));
+        if renamed.is_err() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        renamed
+    };
+    write().map_err(|e| anyhow!("failed to write {}: {e}", path.display()))
+}
+
+#[test]
+fn key_action_edits_undo_redo() {
+    let path = std::env::temp_dir().join(format!("kanata-history-{}.kbd", std::process::id()));
+    std::fs::write(&path, "0").unwrap();
+    let edits = |text: &str| {
+        let mut edits = EditedFiles::default();
+        edits.insert(path.clone(), text.to_owned());
+        edits
+    };
+    let cfg = kanata_parser::cfg::new_from_str("(defsrc a b c) (deflayer base a b c)").unwrap();
+    let actions = || {
+        cfg.layout
+            .key_actions(0, kanata_parser::keys::OsCode::KEY_A)
+    };
+    let mut history = KeyActionEdits::default();
+    assert!(history.peek(HistoryStep::Undo).is_none());
+    history.set("base", "a", edits("1"), actions()).unwrap();
+    history.set("base", "b", edits("2"), actions()).unwrap();
+    assert_eq!(history.current(), &edits("2"));
+
+    assert_eq!(
+        history.peek(HistoryStep::Undo).map(|c| &c.edits),
+        Some(&edits("1"))
+    );
+    assert_eq!(
+        history.step(HistoryStep::Undo, actions()),
+        Some(("base".into(), "b".into()))
+    );
+    assert_eq!(history.current(), &edits("1"));
+    assert_eq!(
+        history.step(HistoryStep::Undo, actions()),
+        Some(("base".into(), "a".into()))
+    );
+    assert!(history.current().is_empty());
+    assert_eq!(history.step(HistoryStep::Undo, actions()), None);
+
+    assert_eq!(
+        history.step(HistoryStep::Redo, actions()),
+        Some(("base".into(), "a".into()))
+    );
+    assert_eq!(history.current(), &edits("1"));
+    // A new change drops the changes that can be redone.
+    history.set("nav", "c", edits("3"), actions()).unwrap();
+    assert!(history.peek(HistoryStep::Redo).is_none());
+
+    for i in 0..MAX_KEY_ACTION_HISTORY + 10 {
+        history
+            .set("base", "a", edits(&i.to_string()), actions())
+            .unwrap();
+    }
+    let mut undone = 0;
+    while history.step(HistoryStep::Undo, actions()).is_some() {
+        undone += 1;
+    }
+    assert_eq!(undone, MAX_KEY_ACTION_HISTORY);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn key_action_edits_persist_only_over_unchanged_files() {
+    let path = std::env::temp_dir().join(format!("kanata-persist-{}.kbd", std::process::id()));
+    std::fs::write(&path, "0").unwrap();
+    let edits = |text: &str| {
+        let mut edits = EditedFiles::default();
+        edits.insert(path.clone(), text.to_owned());
+        edits
+    };
+    let cfg = kanata_parser::cfg::new_from_str("(defsrc a) (deflayer base a)").unwrap();
+    let actions = || {
+        cfg.layout
+            .key_actions(0, kanata_parser::keys::OsCode::KEY_A)
+    };
+    let mut history = KeyActionEdits::default();
+    history.set("base", "a", edits("1"), actions()).unwrap();
+    history.persist().unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "1");
+
+    // The file was saved by the user after it was edited.
+    history.set("base", "a", edits("2"), actions()).unwrap();
+    std::fs::write(&path, "user").unwrap();
+    assert!(history.persist().is_err());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "user");
+    std::fs::remove_file(&path).unwrap();
+}
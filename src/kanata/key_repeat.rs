@@ -0,0 +1,131 @@
+//! The `linux-device-repeat-delay-rate` option. Kanata repeats the most recently pressed key
+//! itself at the configured delay and rate, and ignores the key repeats of the input devices. The
+//! input devices are grabbed, so their own repeat settings are left alone.
+
+use anyhow::{anyhow, Result};
+
+use kanata_parser::keys::OsCode;
+
+use crate::oskbd::{KeyEvent, KeyValue};
+
+use super::HashMap;
+
+pub(super) struct KeyRepeat {
+    /// Delay before the first repeat and period between repeats, in milliseconds. `None` if the
+    /// repeats of the input devices are used.
+    rate: Option<(u16, u16)>,
+    /// The key that is repeated and the ticks until its next repeat.
+    held: Option<(OsCode, u16)>,
+}
+
+impl KeyRepeat {
+    pub(super) fn from_cfg(cfg_items: &HashMap<String, String>) -> Result<Self> {
+        Ok(Self {
+            rate: parse_key_repeat(cfg_items)?,
+            held: None,
+        })
+    }
+
+    /// Changes the delay and rate on live reload. A key that is already repeating keeps its next
+    /// repeat time.
+    pub(super) fn update_cfg(&mut self, cfg_items: &HashMap<String, String>) -> Result<()> {
+        self.rate = parse_key_repeat(cfg_items)?;
+        if self.rate.is_none() {
+            self.held = None;
+        }
+        Ok(())
+    }
+
+    /// Returns true if the repeats of the input devices are ignored because kanata generates its
+    /// own.
+    pub(super) fn generates_repeats(&self) -> bool {
+        self.rate.is_some()
+    }
+
+    /// Starts repeating a pressed key, replacing the previous one like a keyboard does, and stops
+    /// when the key is released.
+    pub(super) fn input_event(&mut self, event: &KeyEvent) {
+        let Some((delay, _)) = self.rate else {
+            return;
+        };
+        match event.value {
+            KeyValue::Press => self.held = Some((event.code, delay.max(1))),
+            KeyValue::Release if matches!(self.held, Some((key, _)) if key == event.code) => {
+                self.held = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// Advances by one millisecond. Returns the key to repeat if a repeat is due.
+    pub(super) fn tick(&mut self) -> Option<OsCode> {
+        let (_, period) = self.rate?;
+        let (key, ticks) = self.held.as_mut()?;
+        *ticks -= 1;
+        if *ticks > 0 {
+            return None;
+        }
+        *ticks = period;
+        Some(*key)
+    }
+
+    pub(super) fn is_idle(&self) -> bool {
+        self.held.is_none()
+    }
+}
+
+/// Parses `linux-device-repeat-delay-rate` into the delay and the period between repeats in
+/// milliseconds.
+fn parse_key_repeat(cfg_items: &HashMap<String, String>) -> Result<Option<(u16, u16)>> {
+    let Some(delay_rate) = cfg_items.get("linux-device-repeat-delay-rate") else {
+        return Ok(None);
+    };
+    let errmsg = || {
+        anyhow!(
+            "Invalid value for linux-device-repeat-delay-rate: \"{delay_rate}\".\n\
+            Expected two numbers separated by a comma, a delay of 0-65535 ms and a rate of 1-1000 \
+            repeats per second, e.g. 200,25"
+        )
+    };
+    let (delay, rate) = delay_rate.split_once(',').ok_or_else(errmsg)?;
+    let delay = delay.trim().parse::<u16>().map_err(|_| errmsg())?;
+    let rate = match rate.trim().parse::<u16>() {
+        Ok(rate @ 1..=1000) => rate,
+        _ => return Err(errmsg()),
+    };
+    Ok(Some((delay, 1000 / rate)))
+}
+
+#[test]
+fn key_repeat_repeats_last_pressed_key() {
+    let mut cfg_items = HashMap::default();
+    cfg_items.insert(
+        "linux-device-repeat-delay-rate".to_owned(),
+        "3,500".to_owned(),
+    );
+    let mut kr = KeyRepeat::from_cfg(&cfg_items).unwrap();
+    assert!(kr.generates_repeats());
+
+    kr.input_event(&KeyEvent::new(OsCode::KEY_A, KeyValue::Press));
+    assert_eq!(kr.tick(), None);
+    assert_eq!(kr.tick(), None);
+    assert_eq!(kr.tick(), Some(OsCode::KEY_A));
+    assert_eq!(kr.tick(), None);
+    assert_eq!(kr.tick(), Some(OsCode::KEY_A));
+
+    // A newer press replaces the repeating key, the release of an older one does not stop it.
+    kr.input_event(&KeyEvent::new(OsCode::KEY_B, KeyValue::Press));
+    kr.input_event(&KeyEvent::new(OsCode::KEY_A, KeyValue::Release));
+    assert_eq!(kr.tick(), None);
+    assert_eq!(kr.tick(), None);
+    assert_eq!(kr.tick(), Some(OsCode::KEY_B));
+    kr.input_event(&KeyEvent::new(OsCode::KEY_B, KeyValue::Release));
+    assert!(kr.is_idle());
+    assert_eq!(kr.tick(), None);
+
+    cfg_items.insert(
+        "linux-device-repeat-delay-rate".to_owned(),
+        "200,0".to_owned(),
+    );
+    assert!(kr.update_cfg(&cfg_items).is_err());
+}
@@ -0,0 +1,110 @@
+//! Settings from defcfg that `deflayer-cfg` can override while a layer is active.
+
+use anyhow::{anyhow, Result};
+
+use kanata_parser::cfg::{LayerInfo, FALSE_VALUES, TRUE_VALUES};
+
+use super::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct LayerCfg {
+    pub(super) type_delay: u16,
+    pub(super) key_repeat: bool,
+    pub(super) movemouse_speed_percent: u16,
+    pub(super) mwheel_speed_percent: u16,
+    #[cfg_attr(
+        not(all(target_os = "windows", not(feature = "interception_driver"))),
+        allow(dead_code)
+    )]
+    pub(super) sendinput_scancode: bool,
+}
+
+impl LayerCfg {
+    /// Returns the settings of each layer: the layer's overrides, falling back to defcfg.
+    pub(super) fn for_layers(
+        cfg_items: &HashMap<String, String>,
+        layer_info: &[LayerInfo],
+    ) -> Result<Vec<Self>> {
+        let base = Self::from_items(|key| cfg_items.get(key))?;
+        layer_info
+            .iter()
+            .map(|info| {
+                if info.cfg_overrides.is_empty() {
+                    return Ok(base);
+                }
+                Self::from_items(|key| info.cfg_overrides.get(key).or_else(|| cfg_items.get(key)))
+                    .map_err(|e| anyhow!("deflayer-cfg of layer {}: {e}", info.name))
+            })
+            .collect()
+    }
+
+    fn from_items<'a>(get: impl Fn(&str) -> Option<&'a String>) -> Result<Self> {
+        let u16_item = |key: &str, default: u16| -> Result<u16> {
+            get(key)
+                .map(|s| s.parse::<u16>())
+                .unwrap_or(Ok(default))
+                .map_err(|e| anyhow!("{key} must be 0-65535: {e}"))
+        };
+        Ok(Self {
+            type_delay: u16_item("type-delay", 0)?,
+            key_repeat: get("key-repeat")
+                .map(|s| !FALSE_VALUES.contains(&s.to_lowercase().as_str()))
+                .unwrap_or(true),
+            movemouse_speed_percent: u16_item("movemouse-speed-percent", 100)?,
+            mwheel_speed_percent: u16_item("mwheel-speed-percent", 100)?,
+            sendinput_scancode: get("windows-sendinput-scancode")
+                .map(|s| TRUE_VALUES.contains(&s.to_lowercase().as_str()))
+                .unwrap_or_default(),
+        })
+    }
+}
+
+#[test]
+fn layer_cfg_overrides_fall_back_to_defcfg() {
+    let items = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    };
+    let layer = |name: &str, overrides| LayerInfo {
+        name: name.into(),
+        cfg_text: String::new(),
+        bindings: vec![],
+        cfg_overrides: overrides,
+    };
+    let cfgs = LayerCfg::for_layers(
+        &items(&[("type-delay", "5"), ("mwheel-speed-percent", "50")]),
+        &[
+            layer("base", HashMap::default()),
+            layer(
+                "game",
+                items(&[
+                    ("key-repeat", "no"),
+                    ("type-delay", "0"),
+                    ("windows-sendinput-scancode", "yes"),
+                ]),
+            ),
+        ],
+    )
+    .unwrap();
+    assert_eq!(
+        cfgs,
+        [
+            LayerCfg {
+                type_delay: 5,
+                key_repeat: true,
+                movemouse_speed_percent: 100,
+                mwheel_speed_percent: 50,
+                sendinput_scancode: false,
+            },
+            LayerCfg {
+                type_delay: 0,
+                key_repeat: false,
+                movemouse_speed_percent: 100,
+                mwheel_speed_percent: 50,
+                sendinput_scancode: true,
+            },
+        ]
+    );
+}
@@ -0,0 +1,113 @@
+//! Keyboard LEDs driven by the active layer from `linux-layer-leds`, and by the lock keys that
+//! kanata outputs from `linux-lock-leds`. The LEDs of a grabbed device no longer follow the lock
+//! state of the OS, e.g. after remapping caps lock, so kanata sets them itself.
+
+use anyhow::{anyhow, bail, Result};
+use evdev::LedType;
+
+use kanata_parser::cfg::{LayerInfo, TRUE_VALUES};
+
+use super::HashMap;
+use crate::oskbd::{led_bit, set_led_cfg, LedCfg};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(super) struct LayerLeds {
+    /// The LEDs that are on in each layer, indexed like the layers.
+    on: Vec<u8>,
+    /// The LEDs that are set from the active layer.
+    managed: u8,
+    track_locks: bool,
+}
+
+impl LayerLeds {
+    pub(super) fn from_cfg(
+        cfg_items: &HashMap<String, String>,
+        layer_info: &[LayerInfo],
+    ) -> Result<Self> {
+        let mut leds = LayerLeds {
+            on: vec![0; layer_info.len()],
+            managed: 0,
+            track_locks: cfg_items
+                .get("linux-lock-leds")
+                .map(|s| TRUE_VALUES.contains(&s.to_lowercase().as_str()))
+                .unwrap_or_default(),
+        };
+        let Some(layer_leds) = cfg_items.get("linux-layer-leds") else {
+            return Ok(leds);
+        };
+        for entry in layer_leds.split_whitespace() {
+            let (layer_name, led_names) = entry.split_once(':').ok_or_else(|| {
+                anyhow!(
+                    "linux-layer-leds entries must be a layer name and LEDs separated by a colon, \
+                    e.g. nav:scrolllock. Got {entry}"
+                )
+            })?;
+            let layer = layer_info
+                .iter()
+                .position(|l| l.name == layer_name)
+                .ok_or_else(|| anyhow!("linux-layer-leds: {layer_name} is not a layer name"))?;
+            for led_name in led_names.split(',') {
+                let led = match led_name {
+                    "capslock" => LedType::LED_CAPSL,
+                    "numlock" => LedType::LED_NUML,
+                    "scrolllock" => LedType::LED_SCROLLL,
+                    _ => bail!(
+                        "Invalid LED in linux-layer-leds: {led_name}. \
+                        Expected one of: capslock, numlock, scrolllock"
+                    ),
+                };
+                leds.on[layer] |= led_bit(led);
+                leds.managed |= led_bit(led);
+            }
+        }
+        Ok(leds)
+    }
+
+    /// Sets the LEDs of the grabbed devices for the active layer.
+    pub(super) fn apply(&self, layer: usize) {
+        set_led_cfg(LedCfg {
+            layer_managed: self.managed,
+            layer_on: self.on.get(layer).copied().unwrap_or_default(),
+            track_locks: self.track_locks,
+        });
+    }
+}
+
+#[test]
+fn layer_leds_from_cfg() {
+    let layer_info = |name: &str| LayerInfo {
+        name: name.into(),
+        cfg_text: String::new(),
+        bindings: vec![],
+        cfg_overrides: HashMap::default(),
+    };
+    let layer_info = [layer_info("base"), layer_info("nav"), layer_info("fn")];
+    let mut cfg_items = HashMap::default();
+    assert_eq!(
+        LayerLeds::from_cfg(&cfg_items, &layer_info).unwrap(),
+        LayerLeds {
+            on: vec![0; 3],
+            managed: 0,
+            track_locks: false,
+        }
+    );
+    cfg_items.insert(
+        "linux-layer-leds".to_owned(),
+        "nav:scrolllock fn:numlock,scrolllock".to_owned(),
+    );
+    cfg_items.insert("linux-lock-leds".to_owned(), "yes".to_owned());
+    let scroll = led_bit(LedType::LED_SCROLLL);
+    let num = led_bit(LedType::LED_NUML);
+    assert_eq!(
+        LayerLeds::from_cfg(&cfg_items, &layer_info).unwrap(),
+        LayerLeds {
+            on: vec![0, scroll, num | scroll],
+            managed: num | scroll,
+            track_locks: true,
+        }
+    );
+    cfg_items.insert("linux-layer-leds".to_owned(), "nav:kanalock".to_owned());
+    assert!(LayerLeds::from_cfg(&cfg_items, &layer_info).is_err());
+    cfg_items.insert("linux-layer-leds".to_owned(), "other:numlock".to_owned());
+    assert!(LayerLeds::from_cfg(&cfg_items, &layer_info).is_err());
+}
@@ -0,0 +1,60 @@
+//! Runs the `on-startup` and `on-shutdown` actions of `defcfg`.
+
+// Stopping kanata is only watched for on Linux, other platforms warn that on-shutdown does not run.
+#![cfg_attr(not(target_os = "linux"), allow(dead_code))]
+
+use super::*;
+
+/// How long the `on-shutdown` action can delay the exit, e.g. while it types a macro.
+const SHUTDOWN_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+impl Kanata {
+    /// Taps the fake key of the `on-startup` action. The action is processed by the next ticks of
+    /// the processing loop.
+    pub(super) fn tap_on_startup(&mut self) {
+        if let Some(Coord { x, y }) = self.lifecycle_keys.on_startup {
+            log::info!("running on-startup");
+            handle_fakekey_action(FakeKeyAction::Tap, self.layout.bm(), x, y);
+        }
+    }
+
+    /// Taps the fake key of the `on-shutdown` action and processes it until kanata is idle, or
+    /// until `SHUTDOWN_TIMEOUT` has passed.
+    fn run_on_shutdown(&mut self) {
+        let Some(Coord { x, y }) = self.lifecycle_keys.on_shutdown else {
+            return;
+        };
+        log::info!("running on-shutdown");
+        handle_fakekey_action(FakeKeyAction::Tap, self.layout.bm(), x, y);
+        let start = time::Instant::now();
+        while !self.is_idle() && start.elapsed() < SHUTDOWN_TIMEOUT {
+            if let Err(e) = self.handle_time_ticks(&None) {
+                log::error!("failed to run on-shutdown: {e}");
+                return;
+            }
+            std::thread::sleep(time::Duration::from_millis(1));
+        }
+    }
+
+    /// Starts a new thread that runs the `on-shutdown` action when kanata is stopped with SIGINT
+    /// or SIGTERM, then exits.
+    #[cfg(target_os = "linux")]
+    pub fn start_shutdown_watcher(kanata: Arc<Mutex<Self>>) {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        use signal_hook::iterator::Signals;
+
+        let mut signals = Signals::new([SIGINT, SIGTERM]).expect("signals register");
+        std::thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                // Do not keep kanata from exiting if the lock is stuck.
+                match kanata.try_lock_for(SHUTDOWN_TIMEOUT) {
+                    Some(mut k) => k.run_on_shutdown(),
+                    None => log::error!("kanata is busy, exiting without running on-shutdown"),
+                }
+                crate::oskbd::remove_symlink();
+                signal_hook::low_level::emulate_default_handler(signal)
+                    .expect("run original sighandlers");
+            }
+        });
+    }
+}
@@ -1,5 +1,5 @@
 use anyhow::{anyhow, bail, Result};
-use evdev::{InputEvent, InputEventKind, RelativeAxisType};
+use evdev::{EventType, InputEvent, InputEventKind, RelativeAxisType};
 use log::info;
 use parking_lot::Mutex;
 use std::convert::TryFrom;
@@ -8,6 +8,9 @@ use std::sync::Arc;
 
 use super::*;
 
+/// The code of `SYN_REPORT`, which ends a frame of events.
+const SYN_REPORT: u16 = 0;
+
 impl Kanata {
     /// Enter an infinite loop that listens for OS key events and sends them to the processing
     /// thread.
@@ -15,11 +18,18 @@ impl Kanata {
         info!("entering the event loop");
 
         let k = kanata.lock();
+        scheduling::pin_current_thread("event loop", &k.scheduling.event_loop_cpus);
         let mut kbd_in = match KbdIn::new(
             &k.kbd_in_paths,
             k.continue_if_no_devices,
             k.include_names.clone(),
             k.exclude_names.clone(),
+            k.device_detect_mode,
+            k.dev_priority.clone(),
+            MAPPED_KEYS
+                .lock()
+                .iter()
+                .any(|osc| matches!(osc, OsCode::SwitchLid | OsCode::SwitchTabletMode)),
         ) {
             Ok(kbd_in) => kbd_in,
             Err(e) => {
@@ -30,45 +40,123 @@ impl Kanata {
         // In some environments, this needs to be done after the input device grab otherwise it
         // does not work on kanata startup.
         Kanata::set_repeat_rate(&k.defcfg_items)?;
+        // The devices are grabbed and the processing loop is running, so tell systemd that units
+        // ordered after kanata can start.
+        sd_notify::notify(true, &[sd_notify::NotifyState::Ready])?;
+        // Passthrough events are written with their own handle so that they are never delayed by
+        // the processing loop holding the kanata lock, e.g. while running a macro with delays.
+        let mut kbd_out_raw = k.kbd_out.raw_writer();
         drop(k);
+        let mut scroll_dedup = ScrollDedup::default();
+        let mut hi_res_accumulator = HiResAccumulator::default();
+        let mut gestures = TouchpadGestures::default();
 
         loop {
-            let events = kbd_in.read().map_err(|e| anyhow!("failed read: {}", e))?;
-            log::trace!("{events:?}");
+            let (events, device) = kbd_in.read().map_err(|e| anyhow!("failed read: {}", e))?;
+            log::trace!("{device:?}: {events:?}");
+
+            // Lock the mapped keys once per batch of events rather than once per event.
+            let mapped_keys = MAPPED_KEYS.lock();
+            let device_idx = input_device_index(&INPUT_DEVICES.lock(), &device);
+            let now = std::time::Instant::now();
+            scroll_dedup.record_batch(&device.path, &events, now);
+            let mut touch = device
+                .passthrough
+                .as_ref()
+                .map(|_| (gestures.device(&device.path), *SWIPE_DISTANCE.lock()));
 
             for in_event in events.iter().copied() {
-                let key_event = match KeyEvent::try_from(in_event) {
+                if let (Some((touch, swipe_distance)), Some(passthrough)) =
+                    (&mut touch, &device.passthrough)
+                {
+                    touch.event(&in_event);
+                    if in_event.event_type() == EventType::SYNCHRONIZATION
+                        && in_event.code() == SYN_REPORT
+                    {
+                        // The frame can contain events for both the passthrough device and the
+                        // regular output device, e.g. for a keyboard with a touchpad.
+                        kbd_out_raw
+                            .write_raw(in_event)
+                            .map_err(|e| anyhow!("failed write: {}", e))?;
+                        let mut passthrough = passthrough.lock();
+                        let (swipe, frame) =
+                            touch.end_frame(passthrough.size, *swipe_distance, &mapped_keys);
+                        match frame {
+                            FramePassthrough::Write => passthrough.end_frame(true),
+                            FramePassthrough::Drop => passthrough.end_frame(false),
+                            FramePassthrough::LiftAll => passthrough.lift_all(),
+                        }
+                        .map_err(|e| anyhow!("failed write: {}", e))?;
+                        if let Some(code) = swipe {
+                            let mut swipe_event = KeyEvent::new(code, KeyValue::Tap);
+                            swipe_event.device = device_idx;
+                            if let Err(e) = tx.try_send(swipe_event) {
+                                bail!("failed to send on channel: {}", e)
+                            }
+                        }
+                        continue;
+                    }
+                }
+
+                let mut key_event = match KeyEvent::try_from(in_event) {
                     Ok(ev) => ev,
                     _ => {
                         // Pass-through non-key and non-scroll events
-                        let mut kanata = kanata.lock();
-                        kanata
-                            .kbd_out
-                            .write_raw(in_event)
-                            .map_err(|e| anyhow!("failed write: {}", e))?;
+                        write_passthrough(&mut kbd_out_raw, &device, in_event)?;
                         continue;
                     }
                 };
 
                 check_for_exit(&key_event);
+                key_event.device = device_idx;
+                key_event.time = Some(event_instant(&in_event));
 
                 if key_event.value == KeyValue::Tap {
                     // Scroll event for sure. Only scroll events produce Tap.
-                    if !handle_scroll(&kanata, in_event, key_event.code, &events)? {
+                    let is_duplicate = match in_event.kind() {
+                        InputEventKind::RelAxis(axis) => {
+                            scroll_dedup.is_duplicate(&device.path, axis, now)
+                        }
+                        _ => false,
+                    };
+                    let count = handle_scroll(
+                        &mut kbd_out_raw,
+                        &mapped_keys,
+                        in_event,
+                        key_event.code,
+                        is_duplicate,
+                        &mut hi_res_accumulator,
+                        &device.path,
+                    )?;
+                    for _ in 1..count {
+                        if let Err(e) = tx.try_send(key_event) {
+                            bail!("failed to send on channel: {}", e)
+                        }
+                    }
+                    if count == 0 {
                         continue;
                     }
                 } else {
                     // Handle normal keypresses.
                     // Check if this keycode is mapped in the configuration.
-                    // If it hasn't been mapped, send it immediately.
-                    if !MAPPED_KEYS.lock().contains(&key_event.code) {
-                        let mut kanata = kanata.lock();
-                        kanata
-                            .kbd_out
-                            .write_raw(in_event)
-                            .map_err(|e| anyhow!("failed write: {}", e))?;
+                    // If it hasn't been mapped, send it immediately. The rest of the system relies
+                    // on switches, e.g. to suspend when the lid is closed, so they are passed
+                    // through even if they are mapped.
+                    let is_mapped = mapped_keys.contains(&key_event.code);
+                    let is_switch = in_event.event_type() == EventType::SWITCH;
+                    // With output-delay, unmapped keys of the regular output device are delayed
+                    // like the mapped keys by the processing loop so that they stay in order.
+                    let delay_passthrough = !is_mapped
+                        && !is_switch
+                        && device.grabbed
+                        && device.passthrough.is_none()
+                        && DELAY_PASSTHROUGH.load(SeqCst);
+                    if (!is_mapped || is_switch) && !delay_passthrough {
+                        write_passthrough(&mut kbd_out_raw, &device, in_event)?;
+                    }
+                    if !is_mapped && !delay_passthrough {
                         continue;
-                    };
+                    }
                 }
 
                 // Send key events to the processing loop
@@ -76,6 +164,12 @@ impl Kanata {
                     bail!("failed to send on channel: {}", e)
                 }
             }
+
+            drop(mapped_keys);
+            kbd_out_raw
+                .flush()
+                .map_err(|e| anyhow!("failed write: {}", e))?;
+            kbd_in.recycle(events);
         }
     }
 
@@ -83,6 +177,55 @@ impl Kanata {
         Ok(())
     }
 
+    /// Parses `linux-device-detect-mode`. Without it, mice and touchpads are only grabbed if mouse
+    /// buttons, the mouse wheel or touchpad swipes are in `defsrc`, so that mice without remapped
+    /// inputs are not delayed.
+    pub(super) fn parse_device_detect_mode(
+        cfg_items: &HashMap<String, String>,
+        mapped_keys: &cfg::MappedKeys,
+    ) -> Result<DeviceDetectMode> {
+        match cfg_items
+            .get("linux-device-detect-mode")
+            .map(String::as_str)
+        {
+            Some("keyboard-only") => Ok(DeviceDetectMode::KeyboardOnly),
+            Some("keyboard-mice") => Ok(DeviceDetectMode::KeyboardMice),
+            Some("any") => Ok(DeviceDetectMode::Any),
+            Some(mode) => bail!(
+                "Invalid value for linux-device-detect-mode: {mode}. \
+                Expected one of: keyboard-only, keyboard-mice, any"
+            ),
+            None => {
+                let has_mouse_keys = mapped_keys.iter().any(|osc| {
+                    matches!(
+                        osc,
+                        OsCode::BTN_LEFT
+                            | OsCode::BTN_RIGHT
+                            | OsCode::BTN_MIDDLE
+                            | OsCode::BTN_SIDE
+                            | OsCode::BTN_EXTRA
+                            | OsCode::MouseWheelUp
+                            | OsCode::MouseWheelDown
+                            | OsCode::MouseWheelLeft
+                            | OsCode::MouseWheelRight
+                            | OsCode::Swipe3Up
+                            | OsCode::Swipe3Down
+                            | OsCode::Swipe3Left
+                            | OsCode::Swipe3Right
+                            | OsCode::Swipe4Up
+                            | OsCode::Swipe4Down
+                            | OsCode::Swipe4Left
+                            | OsCode::Swipe4Right
+                    )
+                });
+                Ok(match has_mouse_keys {
+                    true => DeviceDetectMode::KeyboardMice,
+                    false => DeviceDetectMode::KeyboardOnly,
+                })
+            }
+        }
+    }
+
     pub fn set_repeat_rate(cfg_items: &HashMap<String, String>) -> Result<()> {
         if let Some(x11_rpt_str) = cfg_items.get("linux-x11-repeat-delay-rate") {
             let delay_rate = x11_rpt_str.split(',').collect::<Vec<_>>();
@@ -123,22 +266,58 @@ impl Kanata {
     }
 }
 
-/// Returns true if the scroll event should be sent to the processing loop, otherwise returns
-/// false.
+/// Returns the index used by `input-device` switch conditions for the device: one more than the
+/// position of the first configured name that matches the device name or path, or 0 if none match.
+fn input_device_index(input_devices: &[String], device: &InputDevice) -> u16 {
+    input_devices
+        .iter()
+        .position(|name| *name == device.name || *name == device.path)
+        .map(|i| i as u16 + 1)
+        .unwrap_or(0)
+}
+
+/// Writes an event that kanata does not handle, unless the device is not grabbed. Events of devices
+/// with absolute axes go to the passthrough copy of the device, since the regular output device has
+/// no absolute axes.
+fn write_passthrough(
+    kbd_out_raw: &mut KbdOutRaw,
+    device: &InputDevice,
+    in_event: InputEvent,
+) -> Result<()> {
+    if !device.grabbed {
+        return Ok(());
+    }
+    match &device.passthrough {
+        Some(passthrough) => passthrough.lock().write_raw(in_event),
+        None => kbd_out_raw
+            .write_raw(in_event)
+            .map_err(|e| anyhow!("failed write: {}", e))?,
+    }
+    Ok(())
+}
+
+/// Returns the number of times the scroll event should be sent to the processing loop.
 fn handle_scroll(
-    kanata: &Mutex<Kanata>,
+    kbd_out_raw: &mut KbdOutRaw,
+    mapped_keys: &cfg::MappedKeys,
     in_event: InputEvent,
     code: OsCode,
-    all_events: &[InputEvent],
-) -> Result<bool> {
+    is_duplicate: bool,
+    hi_res_accumulator: &mut HiResAccumulator,
+    device_path: &str,
+) -> Result<u16> {
     let direction: MWheelDirection = code.try_into().unwrap();
+    // Only the scrolling that is passed through is inverted here. Mapped scroll directions are
+    // inverted by the actions that they trigger.
+    let passthrough_direction = scroll_direction(direction);
     let scroll_distance = in_event.value().unsigned_abs() as u16;
     match in_event.kind() {
         InputEventKind::RelAxis(axis_type) => {
             match axis_type {
                 RelativeAxisType::REL_WHEEL | RelativeAxisType::REL_HWHEEL => {
-                    if MAPPED_KEYS.lock().contains(&code) {
-                        return Ok(true);
+                    if mapped_keys.contains(&code) {
+                        // The hi-res events of the same scroll have already been accumulated.
+                        return Ok(if is_duplicate { 0 } else { 1 });
                     }
                     // If we just used `write_raw` here, some of the scrolls issued by kanata would be
                     // REL_WHEEL_HI_RES + REL_WHEEL and some just REL_WHEEL and an issue like this one
@@ -150,36 +329,33 @@ fn handle_scroll(
                     // However, if this is a normal scroll event, it may be sent alongside a hi-res
                     // scroll event. In this scenario, the hi-res event should be used to call
                     // scroll, and not the normal event. Otherwise, too much scrolling will happen.
-                    let mut kanata = kanata.lock();
-                    if !all_events.iter().any(|ev| {
-                        matches!(
-                            ev.kind(),
-                            InputEventKind::RelAxis(
-                                RelativeAxisType::REL_WHEEL_HI_RES
-                                    | RelativeAxisType::REL_HWHEEL_HI_RES
+                    if !is_duplicate {
+                        kbd_out_raw
+                            .scroll(
+                                passthrough_direction,
+                                scroll_distance * HI_RES_SCROLL_UNITS_IN_LO_RES,
                             )
-                        )
-                    }) {
-                        kanata
-                            .kbd_out
-                            .scroll(direction, scroll_distance * HI_RES_SCROLL_UNITS_IN_LO_RES)
                             .map_err(|e| anyhow!("failed write: {}", e))?;
                     }
-                    Ok(false)
+                    Ok(0)
                 }
                 RelativeAxisType::REL_WHEEL_HI_RES | RelativeAxisType::REL_HWHEEL_HI_RES => {
-                    if !MAPPED_KEYS.lock().contains(&code) {
+                    if !mapped_keys.contains(&code) {
                         // Passthrough if the scroll wheel event is not mapped
                         // in the configuration.
-                        let mut kanata = kanata.lock();
-                        kanata
-                            .kbd_out
-                            .scroll(direction, scroll_distance)
+                        kbd_out_raw
+                            .scroll(passthrough_direction, scroll_distance)
                             .map_err(|e| anyhow!("failed write: {}", e))?;
+                        return Ok(0);
                     }
-                    // Kanata will not handle high resolution scroll events for now.
-                    // Full notch scrolling only.
-                    Ok(false)
+                    // Mapped actions fire each time the scrolled amount reaches the threshold of
+                    // the direction, so that partial notches are not lost.
+                    Ok(hi_res_accumulator.add(
+                        device_path,
+                        axis_type,
+                        in_event.value(),
+                        &HI_RES_SCROLL_THRESHOLDS.lock(),
+                    ))
                 }
                 _ => unreachable!("expect to be handling a wheel event"),
             }
@@ -0,0 +1,155 @@
+use anyhow::{anyhow, Result};
+use block2::RcBlock;
+use core_foundation::base::TCFType;
+use core_foundation::mach_port::CFMachPortRef;
+use core_foundation::runloop::{kCFRunLoopCommonModes, CFRunLoop};
+use core_graphics::event::{
+    CGEventTap, CGEventTapLocation, CGEventTapOptions, CGEventTapPlacement, CGEventType,
+};
+use objc2::rc::Retained;
+use objc2::runtime::ProtocolObject;
+use objc2_app_kit::{NSWorkspace, NSWorkspaceDidActivateApplicationNotification};
+use objc2_foundation::{NSNotification, NSObjectProtocol};
+use parking_lot::Mutex;
+use std::cell::Cell;
+use std::convert::TryFrom;
+use std::ptr::NonNull;
+use std::rc::Rc;
+use std::sync::mpsc::SyncSender as Sender;
+use std::sync::Arc;
+
+use crate::kanata::*;
+
+#[link(name = "CoreGraphics", kind = "framework")]
+extern "C" {
+    fn CGEventTapEnable(tap: CFMachPortRef, enable: bool);
+}
+
+impl Kanata {
+    /// Creates an event tap that receives the key events of all keyboards and runs the run loop
+    /// that delivers them. Key events of mapped keys are sent to the processing loop and dropped,
+    /// other events are passed on to the OS.
+    pub fn event_loop(kanata: Arc<Mutex<Self>>, tx: Sender<KeyEvent>) -> Result<()> {
+        scheduling::pin_current_thread("event loop", &kanata.lock().scheduling.event_loop_cpus);
+
+        // The notifications are delivered by the run loop below, which is the main run loop.
+        let _app_observer = observe_frontmost_app(kanata.clone(), tx.clone());
+
+        // The OS disables the tap if the callback is too slow, e.g. while the system is busy. The
+        // callback is told when that happens and enables it again.
+        let tap_port: Rc<Cell<Option<CFMachPortRef>>> = Rc::new(Cell::new(None));
+        let callback_tap_port = tap_port.clone();
+        let tap = CGEventTap::new(
+            CGEventTapLocation::HID,
+            CGEventTapPlacement::HeadInsertEventTap,
+            CGEventTapOptions::Default,
+            vec![
+                CGEventType::KeyDown,
+                CGEventType::KeyUp,
+                CGEventType::FlagsChanged,
+            ],
+            move |_proxy, etype, event| {
+                if matches!(
+                    etype,
+                    CGEventType::TapDisabledByTimeout | CGEventType::TapDisabledByUserInput
+                ) {
+                    log::warn!("event tap was disabled by the OS, enabling it again");
+                    if let Some(port) = callback_tap_port.get() {
+                        unsafe { CGEventTapEnable(port, true) };
+                    }
+                    return None;
+                }
+                let key_event = InputEvent::from_cg_event(etype, event)
+                    .and_then(|input_event| KeyEvent::try_from(input_event).ok())?;
+                check_for_exit(&key_event);
+                if !MAPPED_KEYS.lock().contains(&key_event.code) {
+                    return None;
+                }
+                log::debug!("event loop: {:?}", key_event);
+                let mut key_events = vec![key_event];
+                // Caps lock does not report its release.
+                if key_event.code == OsCode::KEY_CAPSLOCK {
+                    key_events.push(KeyEvent::new(OsCode::KEY_CAPSLOCK, KeyValue::Release));
+                }
+                for key_event in key_events {
+                    if let Err(e) = tx.try_send(key_event) {
+                        panic!("failed to send on channel: {e:?}")
+                    }
+                }
+                // The event that the callback returns is passed on. An event without a type is
+                // dropped by the OS.
+                event.set_type(CGEventType::Null);
+                None
+            },
+        )
+        .map_err(|_| {
+            anyhow!(
+                "failed to create the event tap. Allow kanata in \
+                System Settings > Privacy & Security > Accessibility and Input Monitoring."
+            )
+        })?;
+        tap_port.set(Some(tap.mach_port.as_concrete_TypeRef()));
+
+        let source = tap
+            .mach_port
+            .create_runloop_source(0)
+            .map_err(|_| anyhow!("failed to create the run loop source of the event tap"))?;
+        CFRunLoop::get_current().add_source(&source, unsafe { kCFRunLoopCommonModes });
+        tap.enable();
+        CFRunLoop::run_current();
+        Ok(())
+    }
+
+    /// Switches the default layer for the application `bundle_id` that became frontmost.
+    fn activate_app(&mut self, bundle_id: &str) {
+        let default_layer = self.layout.bm().default_layer;
+        if let Some(layer) = self.app_layers.activate(bundle_id, default_layer) {
+            log::info!(
+                "{bundle_id} is frontmost, switching to layer {}",
+                self.layer_info[layer].name
+            );
+            self.layout.bm().set_default_layer(layer);
+        }
+    }
+
+    pub fn check_release_non_physical_shift(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn set_repeat_rate(_cfg_items: &HashMap<String, String>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Applies `macos-app-layers` for the frontmost application now and whenever another
+/// application becomes frontmost. Notifications stop when the returned observer is dropped.
+fn observe_frontmost_app(
+    kanata: Arc<Mutex<Kanata>>,
+    tx: Sender<KeyEvent>,
+) -> Retained<ProtocolObject<dyn NSObjectProtocol>> {
+    let on_activate = move || {
+        let Some(bundle_id) = NSWorkspace::sharedWorkspace()
+            .frontmostApplication()
+            .and_then(|app| app.bundleIdentifier())
+        else {
+            return;
+        };
+        kanata.lock().activate_app(&bundle_id.to_string());
+        // Wake up the processing loop so that it handles the layer change right away.
+        if let Err(e) = tx.try_send(KeyEvent::new(OsCode::KEY_RESERVED, KeyValue::WakeUp)) {
+            log::warn!("failed to wake up processing loop: {e:?}");
+        }
+    };
+    on_activate();
+    let block = RcBlock::new(move |_: NonNull<NSNotification>| on_activate());
+    unsafe {
+        NSWorkspace::sharedWorkspace()
+            .notificationCenter()
+            .addObserverForName_object_queue_usingBlock(
+                Some(NSWorkspaceDidActivateApplicationNotification),
+                None,
+                None,
+                &block,
+            )
+    }
+}
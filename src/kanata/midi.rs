@@ -0,0 +1,74 @@
+//! Output of MIDI messages to a raw MIDI device, e.g. a virtual port of the `snd-virmidi` kernel
+//! module, which other applications can then connect to through ALSA sequencer.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+
+use super::HashMap;
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const CONTROL_CHANGE: u8 = 0xB0;
+
+pub(super) struct MidiOut {
+    path: String,
+    device: File,
+}
+
+impl MidiOut {
+    /// Opens the device of `linux-midi-device`, reusing `current` if the device is unchanged.
+    pub(super) fn from_cfg(
+        cfg_items: &HashMap<String, String>,
+        current: Option<MidiOut>,
+    ) -> io::Result<Option<MidiOut>> {
+        let Some(path) = cfg_items
+            .get("linux-midi-device")
+            .map(|p| p.trim_matches('"'))
+        else {
+            return Ok(None);
+        };
+        if let Some(current) = current.filter(|c| c.path == path) {
+            return Ok(Some(current));
+        }
+        let device = OpenOptions::new().write(true).open(path)?;
+        log::info!("sending MIDI messages to {path}");
+        Ok(Some(MidiOut {
+            path: path.to_owned(),
+            device,
+        }))
+    }
+
+    pub(super) fn note_on(&mut self, channel: u8, note: u8, velocity: u8) -> io::Result<()> {
+        self.send(message(NOTE_ON, channel, note, velocity))
+    }
+
+    pub(super) fn note_off(&mut self, channel: u8, note: u8) -> io::Result<()> {
+        self.send(message(NOTE_OFF, channel, note, 0))
+    }
+
+    pub(super) fn control_change(
+        &mut self,
+        channel: u8,
+        controller: u8,
+        value: u8,
+    ) -> io::Result<()> {
+        self.send(message(CONTROL_CHANGE, channel, controller, value))
+    }
+
+    fn send(&mut self, msg: [u8; 3]) -> io::Result<()> {
+        log::debug!("send MIDI message {msg:02x?}");
+        self.device.write_all(&msg)
+    }
+}
+
+/// Encodes a channel message. The channel is 1-16 and the data bytes are 0-127.
+fn message(status: u8, channel: u8, data1: u8, data2: u8) -> [u8; 3] {
+    [status | (channel - 1), data1 & 0x7F, data2 & 0x7F]
+}
+
+#[test]
+fn midi_messages() {
+    assert_eq!(message(NOTE_ON, 1, 60, 100), [0x90, 60, 100]);
+    assert_eq!(message(NOTE_OFF, 10, 60, 0), [0x89, 60, 0]);
+    assert_eq!(message(CONTROL_CHANGE, 16, 7, 127), [0xBF, 7, 127]);
+}
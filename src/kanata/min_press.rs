@@ -0,0 +1,188 @@
+//! The `min-press-duration` option. A press of a listed key is held back until the key has been
+//! held for its minimum duration. If the key is released sooner, the press and the release are
+//! both discarded, which filters out the phantom presses of failing switches.
+
+use anyhow::{anyhow, bail, Result};
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use kanata_parser::keys::{str_to_oscode, OsCode};
+
+use crate::oskbd::{KeyEvent, KeyValue};
+
+use super::HashMap;
+
+/// Presses shorter than this are never intended, but longer ones can be.
+const MAX_MIN_PRESS_DURATION_MS: u16 = 200;
+
+pub(super) struct MinPress {
+    durations: HashMap<OsCode, Duration>,
+    /// Input events waiting to be processed and the time at which they are due. Events after a
+    /// held back press wait for it, so that the order of the input is kept.
+    queue: VecDeque<(Instant, KeyEvent)>,
+}
+
+impl MinPress {
+    pub(super) fn from_cfg(cfg_items: &HashMap<String, String>) -> Result<Self> {
+        Ok(Self {
+            durations: parse_min_press_durations(cfg_items)?,
+            queue: VecDeque::new(),
+        })
+    }
+
+    /// Changes the durations on live reload. Events already waiting keep their due times.
+    pub(super) fn update_cfg(&mut self, cfg_items: &HashMap<String, String>) -> Result<()> {
+        self.durations = parse_min_press_durations(cfg_items)?;
+        Ok(())
+    }
+
+    /// Returns true if the event is held back or discarded instead of being processed now.
+    /// Held back events are returned by `pop_due` once they are due.
+    pub(super) fn hold_back(&mut self, event: &KeyEvent, now: Instant) -> bool {
+        let duration = match event.value {
+            KeyValue::Press => self.durations.get(&event.code).copied(),
+            _ => None,
+        };
+        if duration.is_none() && self.queue.is_empty() {
+            return false;
+        }
+        // Only presses of keys with a minimum can be too short. Other keys wait in the queue just
+        // to keep the order, so their releases must not discard them.
+        let pending_press = if self.durations.contains_key(&event.code) {
+            self.queue
+                .iter()
+                .position(|(_, ev)| ev.code == event.code && ev.value == KeyValue::Press)
+        } else {
+            None
+        };
+        match (event.value, pending_press) {
+            (KeyValue::Release, Some(i)) => {
+                log::debug!(
+                    "discarding press of {:?} shorter than min-press-duration",
+                    event.code
+                );
+                self.queue.remove(i);
+            }
+            // The OS repeats a held key only after it has been held far longer than any minimum
+            // duration, so a repeat of a waiting press is a glitch as well.
+            (KeyValue::Repeat, Some(_)) => {}
+            _ => self
+                .queue
+                .push_back((now + duration.unwrap_or_default(), *event)),
+        }
+        true
+    }
+
+    /// Returns the next held back event if it is due. Events are returned in the order they were
+    /// received, so an event is only due once all events before it have been returned.
+    pub(super) fn pop_due(&mut self, now: Instant) -> Option<KeyEvent> {
+        match self.queue.front() {
+            Some((due, _)) if *due <= now => self.queue.pop_front().map(|(_, ev)| ev),
+            _ => None,
+        }
+    }
+
+    pub(super) fn is_idle(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+fn parse_min_press_durations(
+    cfg_items: &HashMap<String, String>,
+) -> Result<HashMap<OsCode, Duration>> {
+    let Some(entries) = cfg_items.get("min-press-duration") else {
+        return Ok(HashMap::default());
+    };
+    let mut durations = HashMap::default();
+    for entry in entries.split_whitespace() {
+        let (key, ms) = entry.split_once(':').ok_or_else(|| {
+            anyhow!(
+                "min-press-duration entries must be a key and a duration in ms separated by a \
+                colon, e.g. a:20. Got {entry}"
+            )
+        })?;
+        let osc = str_to_oscode(key)
+            .ok_or_else(|| anyhow!("min-press-duration has an unknown key: {key}"))?;
+        let ms = ms
+            .parse::<u16>()
+            .ok()
+            .filter(|ms| (1..=MAX_MIN_PRESS_DURATION_MS).contains(ms))
+            .ok_or_else(|| {
+                anyhow!(
+                    "min-press-duration of {key} must be 1-{MAX_MIN_PRESS_DURATION_MS}, got {ms}"
+                )
+            })?;
+        if durations
+            .insert(osc, Duration::from_millis(ms.into()))
+            .is_some()
+        {
+            bail!("min-press-duration has {key} more than once");
+        }
+    }
+    Ok(durations)
+}
+
+#[test]
+fn min_press_discards_short_presses_in_order() {
+    let mut cfg_items = HashMap::default();
+    cfg_items.insert("min-press-duration".to_owned(), "a:20 b:10".to_owned());
+    let mut mp = MinPress::from_cfg(&cfg_items).unwrap();
+    let start = Instant::now();
+    let ms = |n| start + Duration::from_millis(n);
+    let ev = |code, value| KeyEvent::new(code, value);
+    let popped = |mp: &mut MinPress, now| mp.pop_due(now).map(|ev| (ev.code, ev.value));
+
+    // Keys without a minimum are processed right away while nothing is waiting.
+    assert!(!mp.hold_back(&ev(OsCode::KEY_C, KeyValue::Press), start));
+
+    // A short press is discarded with its release.
+    assert!(mp.hold_back(&ev(OsCode::KEY_A, KeyValue::Press), start));
+    assert!(mp.hold_back(&ev(OsCode::KEY_A, KeyValue::Release), ms(5)));
+    assert!(mp.is_idle());
+
+    // A long enough press is processed once due, and the keys after it wait for it.
+    assert!(mp.hold_back(&ev(OsCode::KEY_A, KeyValue::Press), ms(10)));
+    assert!(mp.hold_back(&ev(OsCode::KEY_C, KeyValue::Release), ms(15)));
+    assert!(mp.hold_back(&ev(OsCode::KEY_B, KeyValue::Press), ms(16)));
+    // b is due at 26ms but waits for a, which is due at 30ms.
+    assert_eq!(popped(&mut mp, ms(29)), None);
+    assert_eq!(
+        popped(&mut mp, ms(30)),
+        Some((OsCode::KEY_A, KeyValue::Press))
+    );
+    assert_eq!(
+        popped(&mut mp, ms(30)),
+        Some((OsCode::KEY_C, KeyValue::Release))
+    );
+    assert_eq!(
+        popped(&mut mp, ms(30)),
+        Some((OsCode::KEY_B, KeyValue::Press))
+    );
+    assert!(mp.is_idle());
+    assert!(!mp.hold_back(&ev(OsCode::KEY_A, KeyValue::Release), ms(50)));
+
+    // A key without a minimum tapped while a press is waiting is kept and waits for it.
+    assert!(mp.hold_back(&ev(OsCode::KEY_A, KeyValue::Press), ms(60)));
+    assert!(mp.hold_back(&ev(OsCode::KEY_C, KeyValue::Press), ms(62)));
+    assert!(mp.hold_back(&ev(OsCode::KEY_C, KeyValue::Release), ms(64)));
+    assert_eq!(popped(&mut mp, ms(79)), None);
+    assert_eq!(
+        popped(&mut mp, ms(80)),
+        Some((OsCode::KEY_A, KeyValue::Press))
+    );
+    assert_eq!(
+        popped(&mut mp, ms(80)),
+        Some((OsCode::KEY_C, KeyValue::Press))
+    );
+    assert_eq!(
+        popped(&mut mp, ms(80)),
+        Some((OsCode::KEY_C, KeyValue::Release))
+    );
+    assert!(mp.is_idle());
+
+    for invalid in ["a", "a:0", "a:201", "nokey:10", "a:10 a:20"] {
+        cfg_items.insert("min-press-duration".to_owned(), invalid.to_owned());
+        assert!(mp.update_cfg(&cfg_items).is_err(), "{invalid}");
+    }
+}
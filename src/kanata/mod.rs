@@ -3,26 +3,27 @@
 use anyhow::{anyhow, bail, Result};
 use log::{error, info};
 use parking_lot::Mutex;
-use std::sync::mpsc::{Receiver, SyncSender as Sender, TryRecvError};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender as Sender, TryRecvError};
 
 use kanata_keyberon::key_code::*;
 use kanata_keyberon::layout::*;
 
 use std::collections::VecDeque;
-use std::io::Write;
-use std::net::TcpStream;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, AtomicU32, Ordering::SeqCst};
 use std::sync::Arc;
 use std::time;
 
 use crate::oskbd::{KeyEvent, *};
-use crate::tcp_server::ServerMessage;
+use crate::tcp_server::{
+    Client, ClientMessage, Connections, EncodedMessage, HelpBinding, ServerMessage,
+};
 use crate::ValidatedArgs;
 use kanata_parser::cfg;
 use kanata_parser::cfg::*;
 use kanata_parser::custom_action::*;
 use kanata_parser::keys::*;
+use kanata_parser::sequences::{mod_mask_for_keycode, MASK_KEYCODES};
 
 #[cfg(feature = "cmd")]
 mod cmd;
@@ -39,9 +40,96 @@ mod linux;
 #[cfg(target_os = "linux")]
 pub use linux::*;
 
+#[cfg(target_os = "macos")]
+mod macos;
+#[cfg(target_os = "macos")]
+pub use macos::*;
+#[cfg(target_os = "linux")]
+mod scroll_dedup;
+#[cfg(target_os = "linux")]
+use scroll_dedup::{HiResAccumulator, HiResThresholds, ScrollDedup};
+#[cfg(target_os = "linux")]
+mod gestures;
+#[cfg(target_os = "linux")]
+use gestures::{FramePassthrough, TouchpadGestures};
+#[cfg(target_os = "linux")]
+mod layer_leds;
+#[cfg(target_os = "linux")]
+mod output_identity;
+#[cfg(target_os = "linux")]
+use layer_leds::LayerLeds;
+#[cfg(target_os = "macos")]
+mod app_layers;
+#[cfg(target_os = "macos")]
+use app_layers::AppLayers;
+
 mod caps_word;
 pub use caps_word::*;
 
+mod fn_lock;
+
+mod introspect;
+
+mod lifecycle;
+
+mod key_action_history;
+pub(crate) use key_action_history::HistoryStep;
+use key_action_history::KeyActionEdits;
+
+mod external_state;
+
+mod worker;
+#[cfg(feature = "cmd")]
+use worker::run_on_worker;
+
+mod midi;
+use midi::MidiOut;
+
+mod osc;
+use osc::OscOut;
+
+mod http;
+use http::HttpPoster;
+
+mod notify;
+
+mod window_manager;
+use window_manager::WindowManager;
+
+mod clipboard;
+
+mod layer_cfg;
+use layer_cfg::LayerCfg;
+
+mod text_typing;
+use text_typing::TextTyping;
+
+mod output_delay;
+use output_delay::OutputDelay;
+mod min_press;
+use min_press::MinPress;
+mod key_repeat;
+use key_repeat::KeyRepeat;
+
+mod typing_stats;
+use typing_stats::TypingStats;
+
+mod action_usage;
+use action_usage::{action_usage_text, ActionUsage};
+
+mod trainer;
+use trainer::KeyTrainer;
+
+mod break_reminder;
+use break_reminder::{BreakChange, BreakReminder};
+
+mod scheduling;
+use scheduling::SchedulingCfg;
+
+mod typing_rhythm;
+use typing_rhythm::TypingRhythm;
+pub use typing_rhythm::TypingRhythmOutput;
+
 type HashSet<T> = rustc_hash::FxHashSet<T>;
 type HashMap<K, V> = rustc_hash::FxHashMap<K, V>;
 
@@ -64,6 +152,19 @@ pub struct Kanata {
     pub key_outputs: cfg::KeyOutputs,
     /// Handle to the keyberon library layout.
     pub layout: cfg::KanataLayout,
+    /// Fingerprint of the configuration that `layout` was built from. Live reload only rebuilds
+    /// the layout if the fingerprint of the configuration changes.
+    layout_fingerprint: u64,
+    /// The compiled layers of the configuration, which live reload reuses for the layers that did
+    /// not change.
+    layer_cache: cfg::LayerCache,
+    /// Edits of the configuration files made by `SetKeyAction` that are not written to the files,
+    /// and their history. Discarded by live reload.
+    key_action_edits: KeyActionEdits,
+    /// Parses the actions of `SetKeyAction` with the state of the parsed configuration.
+    key_action_parser: cfg::KeyActionParser,
+    /// Changes of key actions that wait until no keys are held to be swapped into the layout.
+    pending_key_actions: Vec<cfg::KeyActionSwap>,
     /// Reusable vec (to save on allocations) that stores the currently active output keys.
     pub cur_keys: Vec<KeyCode>,
     /// Reusable vec (to save on allocations) that stores the active output keys from the previous
@@ -73,6 +174,10 @@ pub struct Kanata {
     pub layer_info: Vec<LayerInfo>,
     /// Used to track when a layer change occurs.
     pub prev_layer: usize,
+    /// The settings of each layer, which are defcfg options overridden with `deflayer-cfg`.
+    layer_cfgs: Vec<LayerCfg>,
+    /// The settings of the active layer.
+    layer_cfg: LayerCfg,
     /// Vertical scrolling state tracker. Is Some(...) when a vertical scrolling action is active
     /// and None otherwise.
     pub scroll_state: Option<ScrollState>,
@@ -85,6 +190,12 @@ pub struct Kanata {
     /// Horizontal mouse movement state. Is Some(...) when horizontal mouse movement is active and
     /// None otherwise.
     pub move_mouse_state_horizontal: Option<MoveMouseState>,
+    /// Accelerating key repeat state. Is Some(...) while a `repeat-accel` key is held and None
+    /// otherwise.
+    pub repeat_accel_state: Option<RepeatAccelState>,
+    /// States of the gamepad axes moved by `axis` actions. An axis has a state while one of its
+    /// actions is held or it has not yet returned to rest.
+    pub axis_ramp_states: Vec<AxisRampState>,
     /// A list of mouse speed modifiers in percentages by which mouse travel distance is scaled.
     pub move_mouse_speed_modifiers: Vec<u16>,
     /// The user configuration for backtracking to find valid sequences. See
@@ -92,6 +203,11 @@ pub struct Kanata {
     pub sequence_backtrack_modcancel: bool,
     /// Tracks sequence progress. Is Some(...) when in sequence mode and None otherwise.
     pub sequence_state: Option<SequenceState>,
+    /// Fake key of a completed sequence with a `count`, and how many more times to tap it.
+    sequence_repeats: Option<((u8, u16), u16)>,
+    /// The key, with modifier bits, that ended a shorter sequence instead of continuing a longer
+    /// one. It is typed once the action of the sequence has been output.
+    sequence_replay: Option<u16>,
     /// Valid sequences defined in the user configuration.
     pub sequences: cfg::KeySeqsToFKeys,
     /// Stores the user recored dynamic macros.
@@ -115,6 +231,13 @@ pub struct Kanata {
     time_remainder: u128,
     /// Is true if a live reload was requested by the user and false otherwise.
     live_reload_requested: bool,
+    /// Is set by `show-help` to send the bindings of the current layer to TCP clients.
+    help_requested: bool,
+    /// Typed keys and pending sequence of the sequence state last sent to TCP clients. None if
+    /// sequence mode was not active.
+    sent_sequence_progress: Option<(Vec<u16>, Option<PendingSequence>)>,
+    /// Text waiting to be typed, e.g. from `cmd-output-text`.
+    text_typing: TextTyping,
     #[cfg(target_os = "linux")]
     /// Linux input paths in the user configuration.
     pub kbd_in_paths: Vec<String>,
@@ -129,10 +252,35 @@ pub struct Kanata {
     /// Tracks the Linux user configuration for device names (instead of paths) that should be
     /// excluded for interception and processing by kanata.
     pub exclude_names: Option<Vec<String>>,
+    #[cfg(target_os = "linux")]
+    /// The kinds of devices that are grabbed when they are discovered.
+    device_detect_mode: DeviceDetectMode,
+    #[cfg(target_os = "linux")]
+    /// Names or paths of input devices whose events are processed first when events from several
+    /// devices have the same timestamp, highest priority first.
+    pub dev_priority: Vec<String>,
+    /// Input keys that are currently held and the index of the input device they came from, used
+    /// by `input-device-held` switch conditions.
+    held_input_keys: Vec<(u16, OsCode)>,
+    /// Blocks wrong-finger keys in layers that have a `deftrainer`.
+    trainer: KeyTrainer,
+    /// Enforces breaks from typing if `break-after-typing` is configured.
+    break_reminder: BreakReminder,
+    /// Process priority and the CPUs of the event and processing loop threads.
+    scheduling: SchedulingCfg,
+    #[cfg(target_os = "linux")]
+    /// The keyboard LEDs that are set for each layer.
+    layer_leds: LayerLeds,
+    #[cfg(target_os = "macos")]
+    /// The default layers of the applications listed in `macos-app-layers`.
+    app_layers: AppLayers,
     #[cfg(all(feature = "interception_driver", target_os = "windows"))]
-    /// Used to know which input device to treat as a mouse for intercepting and processing inputs
+    /// Used to know which input devices to treat as mice for intercepting and processing inputs
     /// by kanata.
-    intercept_mouse_hwid: Option<Vec<u8>>,
+    intercept_mice: Option<InterceptMice>,
+    #[cfg(all(feature = "gui", target_os = "windows"))]
+    /// Shows the active layer if the tray icon is running.
+    tray_icon: Option<TrayIcon>,
     /// User configuration to do logging of layer changes or not.
     log_layer_changes: bool,
     /// Tracks the caps-word state. Is Some(...) if caps-word is active and None otherwise.
@@ -159,6 +307,56 @@ pub struct Kanata {
     dynamic_macro_max_presses: u16,
     /// Keys that should be unmodded. If empty, any modifier should be cleared.
     unmodded_keys: Vec<KeyCode>,
+    /// Interval in milliseconds at which to send the mouse position to TCP clients. 0 disables it.
+    mouse_position_interval: u16,
+    /// Interval in milliseconds at which to send typing statistics to TCP clients. 0 disables it.
+    typing_stats_interval: u16,
+    /// Typing speed and accuracy estimated from output key presses.
+    typing_stats: TypingStats,
+    /// Presses of the bindings of each layer, for `show-action-usage` and TCP clients.
+    action_usage: ActionUsage,
+    /// Output keys are force-released after being held for this many milliseconds without any
+    /// input events. 0 disables it.
+    max_held_key_duration: u32,
+    /// Number of ticks that output keys have been held for since the most recent input event.
+    ticks_held_without_input: u32,
+    /// Conditions of `state-file` and `env-var` switch conditions.
+    external_states: Vec<ExternalStateCondition>,
+    /// Fake key names mapped to their index in the fake key row.
+    fake_keys: HashMap<String, usize>,
+    /// The fake keys of the `on-startup` and `on-shutdown` actions.
+    lifecycle_keys: LifecycleKeys,
+    /// Exports the intervals between input key events, if enabled with `--typing-rhythm`.
+    typing_rhythm: Option<TypingRhythm>,
+    /// Receives the messages of MIDI actions, if `linux-midi-device` is configured.
+    midi_out: Option<MidiOut>,
+    /// Sends the messages of `osc` actions, if `osc-target` is configured.
+    osc_out: Option<OscOut>,
+    /// Sends the requests of `http-post` actions.
+    http_poster: HttpPoster,
+    /// Sends the commands of `wm-*` actions.
+    window_manager: WindowManager,
+    /// Holds back key outputs by the fixed delay of `output-delay`.
+    output_delay: OutputDelay,
+    /// Holds back presses of the keys in `min-press-duration`.
+    min_press: MinPress,
+    /// Repeats held keys at the rate of `linux-device-repeat-delay-rate`.
+    key_repeat: KeyRepeat,
+    /// Sent to by command workers when a command with `on-success` or `on-failure` finishes.
+    #[cfg(feature = "cmd")]
+    cmd_callback_tx: Sender<CmdCompletion>,
+    /// Receives the results of finished commands.
+    #[cfg(feature = "cmd")]
+    cmd_callback_rx: Receiver<CmdCompletion>,
+    /// Number of running commands whose result has not yet been received.
+    #[cfg(feature = "cmd")]
+    pending_cmd_callbacks: usize,
+    /// Editor configured in defcfg for the `edit-config` action.
+    #[cfg(feature = "cmd")]
+    editor: Option<String>,
+    /// Whether to live reload the configuration when the editor opened by `edit-config` exits.
+    #[cfg(feature = "cmd")]
+    edit_config_live_reload: bool,
 }
 
 #[derive(PartialEq, Clone, Copy)]
@@ -189,6 +387,100 @@ pub struct ScrollState {
     pub distance: u16,
 }
 
+pub struct RepeatAccelState {
+    /// The fake key that is tapped on each repeat.
+    pub coord: Coord,
+    pub start_interval: u16,
+    pub end_interval: u16,
+    pub accel_time: u16,
+    pub ticks_held: u16,
+    pub ticks_until_repeat: u16,
+}
+
+impl RepeatAccelState {
+    /// Returns the interval until the next repeat, which shrinks linearly from the start interval
+    /// to the end interval over the acceleration time.
+    fn interval(&self) -> u16 {
+        let progress = u32::from(self.ticks_held.min(self.accel_time));
+        let shrink = u32::from(self.start_interval - self.end_interval) * progress
+            / u32::from(self.accel_time);
+        self.start_interval - shrink as u16
+    }
+}
+
+pub struct AxisRampState {
+    pub axis: GamepadAxis,
+    pub negative_held: bool,
+    pub positive_held: bool,
+    pub ramp_up: u16,
+    pub ramp_down: u16,
+    pub curve: AxisCurve,
+    /// Progress of the ramp, from -1.0 at the minimum to 1.0 at the maximum, with 0.0 at rest.
+    /// The value of the axis follows it through `curve`.
+    pub position: f32,
+    /// The value last written to the gamepad.
+    pub value: i32,
+}
+
+impl AxisRampState {
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    fn new(axis: GamepadAxis) -> Self {
+        Self {
+            axis,
+            negative_held: false,
+            positive_held: false,
+            ramp_up: 0,
+            ramp_down: 0,
+            curve: AxisCurve::Linear,
+            position: 0.0,
+            value: 0,
+        }
+    }
+
+    /// Move the position towards the held direction, or back to rest if neither or both
+    /// directions are held, by one millisecond of ramping. Returns the gamepad value for the new
+    /// position.
+    fn tick(&mut self) -> i32 {
+        let target: f32 = match (self.negative_held, self.positive_held) {
+            (true, false) => -1.0,
+            (false, true) => 1.0,
+            _ => 0.0,
+        };
+        // Moving away from rest uses the ramp-up time. Moving back to rest, including on the way
+        // to the opposite direction, uses the ramp-down time.
+        let ramp = if target != 0.0 && self.position * target >= 0.0 {
+            self.ramp_up
+        } else {
+            self.ramp_down
+        };
+        let step = match ramp {
+            0 => f32::INFINITY,
+            ms => 1.0 / f32::from(ms),
+        };
+        let stop = if self.position * target < 0.0 {
+            0.0
+        } else {
+            target
+        };
+        self.position = if self.position < stop {
+            (self.position + step).min(stop)
+        } else {
+            (self.position - step).max(stop)
+        };
+        let (min, max) = self.axis.range();
+        let fraction = self.curve.apply(self.position.abs());
+        if self.position < 0.0 {
+            (fraction * min as f32).round() as i32
+        } else {
+            (fraction * max as f32).round() as i32
+        }
+    }
+
+    fn is_at_rest(&self) -> bool {
+        !self.negative_held && !self.positive_held && self.position == 0.0
+    }
+}
+
 pub struct MoveMouseState {
     pub direction: MoveDirection,
     pub interval: u16,
@@ -211,8 +503,17 @@ pub struct SequenceState {
     pub sequence_input_mode: SequenceInputMode,
     pub ticks_until_timeout: u16,
     pub sequence_timeout: u16,
+    /// Number of characters typed while in `visible-backspaced` mode. These are erased when the
+    /// sequence completes.
+    pub visible_chars: u16,
+    /// Fake key and count of the sequence that the typed keys complete if they are also the start
+    /// of a longer sequence. It completes on timeout or if the next key continues no sequence.
+    pub pending: Option<PendingSequence>,
 }
 
+/// The fake key coordinates of a completed sequence and how many times to tap it.
+pub type PendingSequence = ((u8, u16), u16);
+
 pub struct DynamicMacroReplayState {
     pub active_macros: HashSet<u16>,
     pub delay_remaining: u16,
@@ -243,11 +544,51 @@ impl DynamicMacroRecordState {
 
 static LAST_PRESSED_KEY: AtomicU32 = AtomicU32::new(0);
 
+/// Whether scrolling is inverted, toggled by `invert-scroll-toggle`. The event loops also read it
+/// for the scrolling that they pass through.
+pub static INVERT_SCROLL: AtomicBool = AtomicBool::new(false);
+
+/// Returns the direction to scroll in instead of `direction`, which is the opposite one while
+/// scrolling is inverted.
+pub fn scroll_direction(direction: MWheelDirection) -> MWheelDirection {
+    if !INVERT_SCROLL.load(SeqCst) {
+        return direction;
+    }
+    match direction {
+        MWheelDirection::Up => MWheelDirection::Down,
+        MWheelDirection::Down => MWheelDirection::Up,
+        MWheelDirection::Left => MWheelDirection::Right,
+        MWheelDirection::Right => MWheelDirection::Left,
+    }
+}
+
 use once_cell::sync::Lazy;
 
 static MAPPED_KEYS: Lazy<Mutex<cfg::MappedKeys>> =
     Lazy::new(|| Mutex::new(cfg::MappedKeys::default()));
 
+/// Device names used by `input-device` switch conditions in the active configuration.
+#[cfg(any(
+    target_os = "linux",
+    all(feature = "interception_driver", target_os = "windows")
+))]
+static INPUT_DEVICES: Lazy<Mutex<Vec<String>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Hi-res scroll thresholds of mapped wheel directions from `linux-mwheel-hi-res-threshold`.
+#[cfg(target_os = "linux")]
+static HI_RES_SCROLL_THRESHOLDS: Lazy<Mutex<HiResThresholds>> =
+    Lazy::new(|| Mutex::new(Default::default()));
+
+/// Percentage of the touchpad size that fingers move for a swipe, from
+/// `linux-touchpad-swipe-distance`.
+#[cfg(target_os = "linux")]
+static SWIPE_DISTANCE: Lazy<Mutex<u8>> = Lazy::new(|| Mutex::new(0));
+
+/// Whether the event loop sends unmapped keys to the processing loop instead of passing them
+/// through, so that they are delayed by `output-delay` like the mapped keys.
+#[cfg(target_os = "linux")]
+static DELAY_PASSTHROUGH: AtomicBool = AtomicBool::new(false);
+
 impl Kanata {
     /// Create a new configuration from a file.
     pub fn new(args: &ValidatedArgs) -> Result<Self> {
@@ -260,25 +601,25 @@ impl Kanata {
         };
 
         #[cfg(all(feature = "interception_driver", target_os = "windows"))]
-        let intercept_mouse_hwid = cfg
-            .items
-            .get("windows-interception-mouse-hwid")
-            .map(|hwid: &String| {
-                log::trace!("win hwid: {hwid}");
-                hwid.split_whitespace()
-                    .try_fold(vec![], |mut hwid_bytes, hwid_byte| {
-                        hwid_byte.trim_matches(',').parse::<u8>().map(|b| {
-                            hwid_bytes.push(b);
-                            hwid_bytes
-                        })
-                    })
-                    .ok()
-            })
-            .unwrap_or_default();
+        let intercept_mice = parse_intercept_mice(&cfg.items)?;
+
+        #[cfg(not(target_os = "linux"))]
+        if cfg.lifecycle_keys.on_shutdown.is_some() {
+            log::warn!("on-shutdown is only supported on Linux, its action will not run");
+        }
 
         let kbd_out = match KbdOut::new(
             #[cfg(target_os = "linux")]
             &args.symlink_path,
+            #[cfg(target_os = "linux")]
+            output_identity::parse_output_identity(&cfg.items)?,
+            #[cfg(target_os = "linux")]
+            cfg.items
+                .get("linux-output-separate-mouse")
+                .map(|s| TRUE_VALUES.contains(&s.to_lowercase().as_str()))
+                .unwrap_or_default(),
+            #[cfg(target_os = "linux")]
+            &cfg.output_device_keys,
         ) {
             Ok(kbd_out) => kbd_out,
             Err(err) => {
@@ -287,6 +628,22 @@ impl Kanata {
             }
         };
 
+        #[cfg(target_os = "linux")]
+        if cfg
+            .items
+            .get("linux-output-self-test")
+            .map(|s| TRUE_VALUES.contains(&s.to_lowercase().as_str()))
+            .unwrap_or_default()
+        {
+            match kbd_out.self_test() {
+                Ok(()) => log::info!("output self-test passed"),
+                Err(err) => {
+                    error!("The output uinput device did not emit events: {err}");
+                    bail!("output self-test failed")
+                }
+            }
+        }
+
         #[cfg(target_os = "linux")]
         let kbd_in_paths = cfg
             .items
@@ -306,6 +663,14 @@ impl Kanata {
             .get("linux-dev-names-exclude")
             .cloned()
             .map(|paths| parse_colon_separated_text(&paths));
+        #[cfg(target_os = "linux")]
+        let device_detect_mode = Kanata::parse_device_detect_mode(&cfg.items, &cfg.mapped_keys)?;
+        #[cfg(target_os = "linux")]
+        let dev_priority = cfg
+            .items
+            .get("linux-dev-priority")
+            .map(|devs| parse_colon_separated_text(devs))
+            .unwrap_or_default();
 
         #[cfg(target_os = "windows")]
         unsafe {
@@ -315,17 +680,11 @@ impl Kanata {
             }
         }
 
-        #[cfg(target_os = "windows")]
-        unsafe {
-            log::info!("Asking Windows to increase process priority");
-            winapi::um::processthreadsapi::SetPriorityClass(
-                winapi::um::processthreadsapi::GetCurrentProcess(),
-                winapi::um::winbase::HIGH_PRIORITY_CLASS,
-            );
-        }
+        let scheduling = SchedulingCfg::from_cfg(&cfg.items)?;
+        scheduling.apply_priority();
 
         update_kbd_out(&cfg.items, &kbd_out)?;
-        set_altgr_behaviour(&cfg)?;
+        set_altgr_behaviour(&cfg.items)?;
 
         let sequence_backtrack_modcancel = cfg
             .items
@@ -338,29 +697,100 @@ impl Kanata {
             .map(|s| !FALSE_VALUES.contains(&s.to_lowercase().as_str()))
             .unwrap_or(true);
 
+        let mouse_position_interval = parse_mouse_position_interval(&cfg.items)?;
+        let typing_stats_interval = parse_typing_stats_interval(&cfg.items)?;
+        let max_held_key_duration = parse_max_held_key_duration(&cfg.items)?;
+        let midi_out = MidiOut::from_cfg(&cfg.items, None)
+            .map_err(|e| anyhow!("failed to open linux-midi-device: {e}"))?;
+        let osc_out = OscOut::from_cfg(&cfg.items, None)
+            .map_err(|e| anyhow!("failed to set up osc-target: {e}"))?;
+        let http_poster = HttpPoster::from_cfg(&cfg.items)?;
+        let window_manager = WindowManager::from_cfg(&cfg.items);
+        let output_delay = OutputDelay::from_cfg(&cfg.items)?;
+        let min_press = MinPress::from_cfg(&cfg.items)?;
+        let key_repeat = KeyRepeat::from_cfg(&cfg.items)?;
+        let break_reminder = BreakReminder::from_cfg(&cfg.items, &cfg.layer_info)?;
+        let action_usage = ActionUsage::new(&cfg.layer_info, cfg.alias_refs);
+        #[cfg(target_os = "linux")]
+        let layer_leds = LayerLeds::from_cfg(&cfg.items, &cfg.layer_info)?;
+        #[cfg(target_os = "macos")]
+        let app_layers = AppLayers::from_cfg(&cfg.items, &cfg.layer_info)?;
+
         *MAPPED_KEYS.lock() = cfg.mapped_keys;
+        #[cfg(any(
+            target_os = "linux",
+            all(feature = "interception_driver", target_os = "windows")
+        ))]
+        {
+            *INPUT_DEVICES.lock() = cfg.input_devices;
+        }
+        #[cfg(target_os = "linux")]
+        {
+            *HI_RES_SCROLL_THRESHOLDS.lock() = scroll_dedup::parse_hi_res_thresholds(&cfg.items)?;
+            *SWIPE_DISTANCE.lock() = gestures::parse_swipe_distance(&cfg.items)?;
+            DELAY_PASSTHROUGH.store(output_delay.is_enabled(), SeqCst);
+            RELEASE_KEYS_ON_REMOVAL.store(
+                cfg.items
+                    .get("linux-release-keys-on-device-removal")
+                    .map(|s| TRUE_VALUES.contains(&s.to_lowercase().as_str()))
+                    .unwrap_or_default(),
+                SeqCst,
+            );
+        }
+
+        #[cfg(feature = "cmd")]
+        let (cmd_callback_tx, cmd_callback_rx) = std::sync::mpsc::sync_channel(100);
+
+        let mut layout = cfg.layout;
+        if fn_lock::load() {
+            log::info!("fn-lock is on");
+            layout.bm().flags |= 1 << FN_LOCK_FLAG;
+        }
+        layout.bm().flags |= external_state::satisfied_flags(&cfg.external_states);
+        layout.bm().sequence_delay_percent = parse_macro_delay_percent(&cfg.items)?;
+        let layer_cfgs = LayerCfg::for_layers(&cfg.items, &cfg.layer_info)?;
+        let layer_cfg = layer_cfgs[layout.bm().current_layer()];
+        layout.bm().sequence_type_delay = layer_cfg.type_delay;
+        #[cfg(target_os = "linux")]
+        layer_leds.apply(layout.bm().current_layer());
+        #[cfg(all(target_os = "windows", not(feature = "interception_driver")))]
+        kbd_out.update_sendinput_scancode(layer_cfg.sendinput_scancode);
 
         Ok(Self {
             kbd_out,
             cfg_paths: args.paths.clone(),
             cur_cfg_idx: 0,
+            layout_fingerprint: cfg.layout_fingerprint,
+            layer_cache: cfg.layer_cache,
+            key_action_edits: Default::default(),
+            key_action_parser: cfg.key_action_parser,
+            pending_key_actions: vec![],
             key_outputs: cfg.key_outputs,
-            layout: cfg.layout,
+            layout,
             layer_info: cfg.layer_info,
             cur_keys: Vec::new(),
             prev_keys: Vec::new(),
             prev_layer: 0,
+            layer_cfgs,
+            layer_cfg,
             scroll_state: None,
             hscroll_state: None,
             move_mouse_state_vertical: None,
             move_mouse_state_horizontal: None,
+            repeat_accel_state: None,
+            axis_ramp_states: vec![],
             move_mouse_speed_modifiers: Vec::new(),
             sequence_backtrack_modcancel,
             sequence_state: None,
+            sequence_repeats: None,
+            sequence_replay: None,
             sequences: cfg.sequences,
             last_tick: time::Instant::now(),
             time_remainder: 0,
             live_reload_requested: false,
+            help_requested: false,
+            sent_sequence_progress: None,
+            text_typing: TextTyping::default(),
             overrides: cfg.overrides,
             override_states: OverrideStates::new(),
             #[cfg(target_os = "linux")]
@@ -375,8 +805,22 @@ impl Kanata {
             include_names,
             #[cfg(target_os = "linux")]
             exclude_names,
+            #[cfg(target_os = "linux")]
+            device_detect_mode,
+            #[cfg(target_os = "linux")]
+            dev_priority,
+            held_input_keys: vec![],
+            trainer: KeyTrainer::new(cfg.trainer),
+            break_reminder,
+            scheduling,
+            #[cfg(target_os = "linux")]
+            layer_leds,
+            #[cfg(target_os = "macos")]
+            app_layers,
             #[cfg(all(feature = "interception_driver", target_os = "windows"))]
-            intercept_mouse_hwid,
+            intercept_mice,
+            #[cfg(all(feature = "gui", target_os = "windows"))]
+            tray_icon: None,
             dynamic_macro_replay_state: None,
             dynamic_macro_record_state: None,
             dynamic_macros: Default::default(),
@@ -398,12 +842,50 @@ impl Kanata {
                 .map(|s| s.parse::<u16>())
                 .unwrap_or(Ok(128))
                 .map_err(|e| anyhow!("dynamic-macro-max-presses must be 0-65535: {e}"))?,
+            #[cfg(feature = "cmd")]
+            editor: cfg
+                .items
+                .get("editor")
+                .map(|e| e.trim_matches('"').to_owned()),
+            #[cfg(feature = "cmd")]
+            edit_config_live_reload: cfg
+                .items
+                .get("edit-config-live-reload")
+                .map(|s| TRUE_VALUES.contains(&s.to_lowercase().as_str()))
+                .unwrap_or_default(),
             #[cfg(target_os = "linux")]
             defcfg_items: cfg.items,
             waiting_for_idle: HashSet::default(),
             ticks_since_idle: 0,
             movemouse_buffer: None,
             unmodded_keys: vec![],
+            mouse_position_interval,
+            typing_stats_interval,
+            typing_stats: TypingStats::default(),
+            action_usage,
+            max_held_key_duration,
+            ticks_held_without_input: 0,
+            external_states: cfg.external_states,
+            fake_keys: cfg.fake_keys,
+            lifecycle_keys: cfg.lifecycle_keys,
+            typing_rhythm: args
+                .typing_rhythm
+                .as_ref()
+                .map(TypingRhythm::new)
+                .transpose()?,
+            midi_out,
+            osc_out,
+            http_poster,
+            window_manager,
+            output_delay,
+            min_press,
+            key_repeat,
+            #[cfg(feature = "cmd")]
+            cmd_callback_tx,
+            #[cfg(feature = "cmd")]
+            cmd_callback_rx,
+            #[cfg(feature = "cmd")]
+            pending_cmd_callbacks: 0,
         })
     }
 
@@ -412,50 +894,238 @@ impl Kanata {
         Ok(Arc::new(Mutex::new(Self::new(args)?)))
     }
 
-    fn do_live_reload(&mut self) -> Result<()> {
-        let cfg = match cfg::new_from_file(&self.cfg_paths[self.cur_cfg_idx]) {
-            Ok(c) => c,
-            Err(e) => {
+    /// Replaces the layout and everything else that is built with it by the ones of `cfg`. Returns
+    /// the `defcfg` items of `cfg`.
+    fn replace_layout(&mut self, cfg: cfg::Cfg) -> HashMap<String, String> {
+        self.layout = cfg.layout;
+        self.layout_fingerprint = cfg.layout_fingerprint;
+        self.layer_cache = cfg.layer_cache;
+        self.external_states = cfg.external_states;
+        self.fake_keys = cfg.fake_keys;
+        self.lifecycle_keys = cfg.lifecycle_keys;
+        self.key_outputs = cfg.key_outputs;
+        self.layer_info = cfg.layer_info;
+        self.sequences = cfg.sequences;
+        self.overrides = cfg.overrides;
+        *MAPPED_KEYS.lock() = cfg.mapped_keys;
+        #[cfg(any(
+            target_os = "linux",
+            all(feature = "interception_driver", target_os = "windows")
+        ))]
+        {
+            *INPUT_DEVICES.lock() = cfg.input_devices;
+        }
+        self.trainer.update_cfg(cfg.trainer);
+        self.action_usage = ActionUsage::new(&self.layer_info, cfg.alias_refs);
+        self.key_action_parser = cfg.key_action_parser;
+        cfg.items
+    }
+
+    /// Changes the action of `key` in `layer` to `action` without reloading the configuration
+    /// file. Only the new action is parsed, and it is swapped into the layout once no keys are
+    /// held. The change is written to the file if `persist` is true and is otherwise kept until the
+    /// next live reload. Until then, the change can be undone.
+    pub fn set_key_action(
+        &mut self,
+        layer: &str,
+        key: &str,
+        action: &str,
+        persist: bool,
+    ) -> Result<()> {
+        let cfg_path = &self.cfg_paths[self.cur_cfg_idx];
+        let mut edits = self.key_action_edits.current().clone();
+        let swap = self
+            .key_action_parser
+            .parse(cfg_path, &mut edits, layer, key, action)
+            .map_err(|e| {
+                log::error!("{e:?}");
+                anyhow!("failed to set the action of {key} in layer {layer}: {e}")
+            })?;
+        if persist {
+            self.key_action_edits.check_unchanged_on_disk(&edits)?;
+        }
+        let previous = self.key_actions(swap.layer, swap.osc);
+        self.key_action_edits.set(layer, key, edits, previous)?;
+        self.pending_key_actions.push(swap);
+        if persist {
+            self.key_action_edits.persist()?;
+        }
+        log::info!("set the action of {key} in layer {layer} to {action}");
+        Ok(())
+    }
+
+    /// Undoes or redoes a change made by `set_key_action`. Returns the layer and key of the change.
+    pub fn step_key_action_history(
+        &mut self,
+        step: HistoryStep,
+        persist: bool,
+    ) -> Result<(String, String)> {
+        let change = self
+            .key_action_edits
+            .peek(step)
+            .ok_or_else(|| anyhow!("there is no key action change to {}", step.verb()))?;
+        if persist {
+            self.key_action_edits
+                .check_unchanged_on_disk(&change.edits)?;
+        }
+        let cfg_path = &self.cfg_paths[self.cur_cfg_idx];
+        let swap = self
+            .key_action_parser
+            .restore(
+                cfg_path,
+                &change.edits,
+                &change.layer,
+                &change.key,
+                change.actions.clone(),
+            )
+            .map_err(|e| {
                 log::error!("{e:?}");
-                bail!("failed to parse config file");
+                anyhow!(
+                    "failed to {} the change of {} in layer {}: {e}",
+                    step.verb(),
+                    change.key,
+                    change.layer
+                )
+            })?;
+        let current = self.key_actions(swap.layer, swap.osc);
+        let (layer, key) = self.key_action_edits.step(step, current).expect("peeked");
+        self.pending_key_actions.push(swap);
+        if persist {
+            self.key_action_edits.persist()?;
+        }
+        log::info!(
+            "{} of the change of {key} in layer {layer} done",
+            step.verb()
+        );
+        Ok((layer, key))
+    }
+
+    /// Returns the actions of the key in the layer whose "layer-switch" version is at `layer`,
+    /// including the changes that are not swapped into the layout yet.
+    fn key_actions(&self, layer: usize, osc: OsCode) -> cfg::KeyActions {
+        self.pending_key_actions
+            .iter()
+            .rev()
+            .find(|swap| swap.layer == layer && swap.osc == osc)
+            .map(|swap| swap.actions.clone())
+            .unwrap_or_else(|| self.layout.key_actions(layer, osc))
+    }
+
+    /// Swaps the pending changes of key actions into the layout. The state of the layout and the
+    /// action usage counts are kept.
+    fn apply_pending_key_actions(&mut self) {
+        for swap in std::mem::take(&mut self.pending_key_actions) {
+            self.layout
+                .set_key_actions(swap.layer, swap.osc, &swap.actions);
+            swap.update_key_outputs(&mut self.key_outputs, &self.overrides);
+            swap.update_layer_info(&mut self.layer_info);
+            self.action_usage
+                .set_binding(&self.layer_info, swap.layer, &swap.key, swap.alias_refs);
+            self.layout_fingerprint = swap.layout_fingerprint;
+        }
+    }
+
+    fn do_live_reload(&mut self) -> Result<()> {
+        let cfg_path = &self.cfg_paths[self.cur_cfg_idx];
+        let reload =
+            match cfg::reload_from_file(cfg_path, self.layout_fingerprint, &self.layer_cache) {
+                Ok(r) => r,
+                Err(e) => {
+                    log::error!("{e:?}");
+                    bail!("failed to parse config file");
+                }
+            };
+        // Keep fn-lock. External state flags may have been reassigned so read them again.
+        let fn_lock = self.layout.b().flags & (1 << FN_LOCK_FLAG);
+        let items = match reload {
+            cfg::CfgReload::Unchanged(items) => {
+                log::info!("layout is unchanged, reusing it");
+                self.layout.reset();
+                items
+            }
+            cfg::CfgReload::Changed(cfg) => {
+                for warning in cfg::analyze_cfg(&cfg) {
+                    log::warn!("{warning}");
+                }
+                self.replace_layout(*cfg)
             }
         };
-        update_kbd_out(&cfg.items, &self.kbd_out)?;
-        set_altgr_behaviour(&cfg).map_err(|e| anyhow!("failed to set altgr behaviour {e})"))?;
-        let log_layer_changes = cfg
-            .items
+        // Edits made by SetKeyAction and their history are discarded by reloading the files.
+        self.key_action_edits.clear();
+        self.pending_key_actions.clear();
+        update_kbd_out(&items, &self.kbd_out)?;
+        set_altgr_behaviour(&items).map_err(|e| anyhow!("failed to set altgr behaviour {e})"))?;
+        let log_layer_changes = items
             .get("log-layer-changes")
             .map(|s| !FALSE_VALUES.contains(&s.to_lowercase().as_str()))
             .unwrap_or(true);
-        self.sequence_backtrack_modcancel = cfg
-            .items
+        self.sequence_backtrack_modcancel = items
             .get("sequence-backtrack-modcancel")
             .map(|s| !FALSE_VALUES.contains(&s.to_lowercase().as_str()))
             .unwrap_or(true);
-        self.layout = cfg.layout;
-        self.key_outputs = cfg.key_outputs;
-        self.layer_info = cfg.layer_info;
-        self.sequences = cfg.sequences;
-        self.overrides = cfg.overrides;
+        self.layout.bm().flags = fn_lock | external_state::satisfied_flags(&self.external_states);
+        self.layout.bm().sequence_delay_percent = parse_macro_delay_percent(&items)?;
+        self.layer_cfgs = LayerCfg::for_layers(&items, &self.layer_info)?;
+        #[cfg(target_os = "linux")]
+        {
+            self.layer_leds = LayerLeds::from_cfg(&items, &self.layer_info)?;
+        }
+        #[cfg(target_os = "macos")]
+        {
+            self.app_layers = AppLayers::from_cfg(&items, &self.layer_info)?;
+        }
+        let cur_layer = self.layout.b().current_layer();
+        self.apply_layer_cfg(cur_layer);
+        self.sequence_repeats = None;
         self.log_layer_changes = log_layer_changes;
-        self.movemouse_smooth_diagonals = cfg
-            .items
+        self.movemouse_smooth_diagonals = items
             .get("movemouse-smooth-diagonals")
             .map(|s| TRUE_VALUES.contains(&s.to_lowercase().as_str()))
             .unwrap_or_default();
-        self.movemouse_inherit_accel_state = cfg
-            .items
+        self.movemouse_inherit_accel_state = items
             .get("movemouse-inherit-accel-state")
             .map(|s| TRUE_VALUES.contains(&s.to_lowercase().as_str()))
             .unwrap_or_default();
-        self.dynamic_macro_max_presses = cfg
-            .items
+        self.mouse_position_interval = parse_mouse_position_interval(&items)?;
+        self.typing_stats_interval = parse_typing_stats_interval(&items)?;
+        self.max_held_key_duration = parse_max_held_key_duration(&items)?;
+        self.midi_out = MidiOut::from_cfg(&items, self.midi_out.take())
+            .map_err(|e| anyhow!("failed to open linux-midi-device: {e}"))?;
+        self.osc_out = OscOut::from_cfg(&items, self.osc_out.take())
+            .map_err(|e| anyhow!("failed to set up osc-target: {e}"))?;
+        self.http_poster = HttpPoster::from_cfg(&items)?;
+        self.window_manager.update_cfg(&items);
+        self.output_delay.update_cfg(&items)?;
+        self.min_press.update_cfg(&items)?;
+        self.key_repeat.update_cfg(&items)?;
+        self.break_reminder.update_cfg(&items, &self.layer_info)?;
+        self.dynamic_macro_max_presses = items
             .get("dynamic-macro-max-presses")
             .map(|s| s.parse::<u16>())
             .unwrap_or(Ok(128))
             .map_err(|_| anyhow!("dynamic-macro-max-presses must be 0-65535"))?;
-        *MAPPED_KEYS.lock() = cfg.mapped_keys;
-        Kanata::set_repeat_rate(&cfg.items)?;
+        #[cfg(feature = "cmd")]
+        {
+            self.editor = items.get("editor").map(|e| e.trim_matches('"').to_owned());
+            self.edit_config_live_reload = items
+                .get("edit-config-live-reload")
+                .map(|s| TRUE_VALUES.contains(&s.to_lowercase().as_str()))
+                .unwrap_or_default();
+        }
+        #[cfg(target_os = "linux")]
+        {
+            *HI_RES_SCROLL_THRESHOLDS.lock() = scroll_dedup::parse_hi_res_thresholds(&items)?;
+            *SWIPE_DISTANCE.lock() = gestures::parse_swipe_distance(&items)?;
+            DELAY_PASSTHROUGH.store(self.output_delay.is_enabled(), SeqCst);
+            RELEASE_KEYS_ON_REMOVAL.store(
+                items
+                    .get("linux-release-keys-on-device-removal")
+                    .map(|s| TRUE_VALUES.contains(&s.to_lowercase().as_str()))
+                    .unwrap_or_default(),
+                SeqCst,
+            );
+        }
+        Kanata::set_repeat_rate(&items)?;
         log::info!("Live reload successful");
         Ok(())
     }
@@ -463,10 +1133,50 @@ impl Kanata {
     /// Update keyberon layout state for press/release, handle repeat separately
     fn handle_input_event(&mut self, event: &KeyEvent) -> Result<()> {
         log::debug!("process recv ev {event:?}");
+        if event.value == KeyValue::WakeUp {
+            return Ok(());
+        }
+        #[cfg(target_os = "linux")]
+        if DELAY_PASSTHROUGH.load(SeqCst) && !MAPPED_KEYS.lock().contains(&event.code) {
+            // An unmapped key that the event loop sent only so that it is delayed by
+            // output-delay in order with the mapped keys.
+            self.output_delay
+                .input_received(event.time.unwrap_or_else(time::Instant::now));
+            self.output_delay
+                .write_key(&mut self.kbd_out, event.code, event.value)?;
+            return Ok(());
+        }
+        if self.min_press.hold_back(event, time::Instant::now()) {
+            return Ok(());
+        }
+        self.process_input_event(event)
+    }
+
+    /// Processes an input event that is not held back by `min-press-duration`.
+    fn process_input_event(&mut self, event: &KeyEvent) -> Result<()> {
         let evc: u16 = event.code.into();
         self.ticks_since_idle = 0;
+        self.ticks_held_without_input = 0;
+        // The delay counts from when the device produced the event, so that the time it took to
+        // reach the processing loop is part of the constant latency as well.
+        self.output_delay
+            .input_received(event.time.unwrap_or_else(time::Instant::now));
+        // The keyberon layout has two versions of each layer.
+        let layer = self.layout.b().current_layer() / 2;
+        if self.trainer.blocks(event, layer, &self.held_input_keys) {
+            self.update_held_input_devices(event);
+            return Ok(());
+        }
+        self.update_held_input_devices(event);
+        self.key_repeat.input_event(event);
         let kbrn_ev = match event.value {
             KeyValue::Press => {
+                self.break_reminder
+                    .key_pressed(time::Instant::now(), &self.held_input_keys);
+                let layout = self.layout.b();
+                self.action_usage
+                    .record(event.code, layout.current_layer(), layout.default_layer);
+                self.layout.bm().input_device = event.device;
                 if let Some(state) = &mut self.dynamic_macro_record_state {
                     // This is not 100% accurate since there may be multiple presses before any of
                     // their relesease are received. But it's probably good enough in practice.
@@ -499,19 +1209,40 @@ impl Kanata {
                 Event::Release(0, evc)
             }
             KeyValue::Repeat => {
+                if self.key_repeat.generates_repeats() {
+                    return Ok(());
+                }
                 let ret = self.handle_repeat(event);
                 return ret;
             }
             KeyValue::Tap => {
+                self.layout.bm().input_device = event.device;
                 self.layout.bm().event(Event::Press(0, evc));
                 self.layout.bm().event(Event::Release(0, evc));
                 return Ok(());
             }
+            KeyValue::WakeUp => unreachable!("returned early"),
         };
         self.layout.bm().event(kbrn_ev);
         Ok(())
     }
 
+    /// Tracks which input devices have keys held. This is updated before the event is processed so
+    /// that a key pressed on one device can check if a key, e.g. a foot pedal, is held on another.
+    fn update_held_input_devices(&mut self, event: &KeyEvent) {
+        let held = (event.device, event.code);
+        match event.value {
+            KeyValue::Press => {
+                if !self.held_input_keys.contains(&held) {
+                    self.held_input_keys.push(held);
+                }
+            }
+            KeyValue::Release => self.held_input_keys.retain(|k| *k != held),
+            _ => return,
+        }
+        self.layout.bm().held_input_devices = held_input_devices_mask(&self.held_input_keys);
+    }
+
     /// Advance keyberon layout state and send events based on changes to its state.
     /// Returns the number of ticks that elapsed.
     fn handle_time_ticks(&mut self, tx: &Option<Sender<ServerMessage>>) -> Result<u16> {
@@ -522,17 +1253,33 @@ impl Kanata {
         let ms_elapsed = ns_elapsed_with_rem / NS_IN_MS;
         self.time_remainder = ns_elapsed_with_rem % NS_IN_MS;
 
+        while let Some(event) = self.min_press.pop_due(now) {
+            self.process_input_event(&event)?;
+        }
         for _ in 0..ms_elapsed {
+            // Repeats use and clear cur_keys, so they come before the key state changes fill it.
+            if let Some(key) = self.key_repeat.tick() {
+                self.handle_repeat(&KeyEvent::new(key, KeyValue::Repeat))?;
+            }
             self.live_reload_requested |= self.handle_keystate_changes()?;
             self.handle_scrolling()?;
             self.handle_move_mouse()?;
+            self.handle_repeat_accel()?;
+            self.handle_axis_ramps()?;
+            self.text_typing
+                .tick(&mut self.output_delay, &mut self.kbd_out)?;
             self.tick_sequence_state()?;
             self.tick_dynamic_macro_state()?;
             self.tick_idle_timeout();
+            self.tick_held_key_watchdog();
+            #[cfg(feature = "cmd")]
+            self.tick_cmd_callbacks();
 
             self.prev_keys.clear();
             self.prev_keys.append(&mut self.cur_keys);
         }
+        self.output_delay.flush(&mut self.kbd_out, now)?;
+        self.tick_break_reminder(now, tx);
 
         if ms_elapsed > 0 {
             self.last_tick = match ms_elapsed {
@@ -550,6 +1297,8 @@ impl Kanata {
             // Handle layer change outside the loop. I don't see any practical scenario where it
             // would make a difference, so may as well reduce the amount of processing.
             self.check_handle_layer_change(tx);
+            self.check_handle_help_request(tx);
+            self.check_handle_sequence_progress(tx);
         }
 
         if self.live_reload_requested
@@ -570,6 +1319,14 @@ impl Kanata {
                 log::error!("live reload failed {e}");
             }
         }
+        // Like live reload, key action changes wait for the held keys to be released, so that a
+        // key is not released with a different action than it was pressed with.
+        if !self.pending_key_actions.is_empty()
+            && ((self.prev_keys.is_empty() && self.cur_keys.is_empty())
+                || self.ticks_since_idle > 1000)
+        {
+            self.apply_pending_key_actions();
+        }
 
         #[cfg(feature = "perf_logging")]
         log::info!("ms elapsed: {ms_elapsed}");
@@ -583,8 +1340,14 @@ impl Kanata {
         if let Some(scroll_state) = &mut self.scroll_state {
             if scroll_state.ticks_until_scroll == 0 {
                 scroll_state.ticks_until_scroll = scroll_state.interval - 1;
-                self.kbd_out
-                    .scroll(scroll_state.direction, scroll_state.distance)?;
+                let distance = apply_mouse_distance_modifiers(
+                    scroll_state.distance,
+                    &[self.layer_cfg.mwheel_speed_percent],
+                );
+                let direction = scroll_direction(scroll_state.direction);
+                self.output_delay.write(&mut self.kbd_out, move |out| {
+                    out.scroll(direction, distance)
+                })?;
             } else {
                 scroll_state.ticks_until_scroll -= 1;
             }
@@ -592,8 +1355,14 @@ impl Kanata {
         if let Some(hscroll_state) = &mut self.hscroll_state {
             if hscroll_state.ticks_until_scroll == 0 {
                 hscroll_state.ticks_until_scroll = hscroll_state.interval - 1;
-                self.kbd_out
-                    .scroll(hscroll_state.direction, hscroll_state.distance)?;
+                let distance = apply_mouse_distance_modifiers(
+                    hscroll_state.distance,
+                    &[self.layer_cfg.mwheel_speed_percent],
+                );
+                let direction = scroll_direction(hscroll_state.direction);
+                self.output_delay.write(&mut self.kbd_out, move |out| {
+                    out.scroll(direction, distance)
+                })?;
             } else {
                 hscroll_state.ticks_until_scroll -= 1;
             }
@@ -616,8 +1385,11 @@ impl Kanata {
             }
             if mmsv.ticks_until_move == 0 {
                 mmsv.ticks_until_move = mmsv.interval - 1;
-                let scaled_distance =
-                    apply_mouse_distance_modifiers(mmsv.distance, &self.move_mouse_speed_modifiers);
+                let scaled_distance = apply_mouse_distance_modifiers(
+                    mmsv.distance,
+                    std::iter::once(&self.layer_cfg.movemouse_speed_percent)
+                        .chain(&self.move_mouse_speed_modifiers),
+                );
                 log::debug!("handle_move_mouse: scaled vdistance: {}", scaled_distance);
 
                 let current_move = CalculatedMouseMove {
@@ -629,12 +1401,16 @@ impl Kanata {
                     let axis: Axis = current_move.direction.into();
                     match &self.movemouse_buffer {
                         Some((previous_axis, previous_move)) => {
+                            let previous_move = *previous_move;
                             if axis == *previous_axis {
-                                self.kbd_out.move_mouse(*previous_move)?;
+                                self.output_delay.write(&mut self.kbd_out, move |out| {
+                                    out.move_mouse(previous_move)
+                                })?;
                                 self.movemouse_buffer = Some((axis, current_move));
                             } else {
-                                self.kbd_out
-                                    .move_mouse_many(&[*previous_move, current_move])?;
+                                self.output_delay.write(&mut self.kbd_out, move |out| {
+                                    out.move_mouse_many(&[previous_move, current_move])
+                                })?;
                                 self.movemouse_buffer = None;
                             }
                         }
@@ -643,7 +1419,8 @@ impl Kanata {
                         }
                     }
                 } else {
-                    self.kbd_out.move_mouse(current_move)?;
+                    self.output_delay
+                        .write(&mut self.kbd_out, move |out| out.move_mouse(current_move))?;
                 }
             } else {
                 mmsv.ticks_until_move -= 1;
@@ -663,8 +1440,11 @@ impl Kanata {
             }
             if mmsh.ticks_until_move == 0 {
                 mmsh.ticks_until_move = mmsh.interval - 1;
-                let scaled_distance =
-                    apply_mouse_distance_modifiers(mmsh.distance, &self.move_mouse_speed_modifiers);
+                let scaled_distance = apply_mouse_distance_modifiers(
+                    mmsh.distance,
+                    std::iter::once(&self.layer_cfg.movemouse_speed_percent)
+                        .chain(&self.move_mouse_speed_modifiers),
+                );
                 log::debug!("handle_move_mouse: scaled hdistance: {}", scaled_distance);
 
                 let current_move = CalculatedMouseMove {
@@ -676,12 +1456,16 @@ impl Kanata {
                     let axis: Axis = current_move.direction.into();
                     match &self.movemouse_buffer {
                         Some((previous_axis, previous_move)) => {
+                            let previous_move = *previous_move;
                             if axis == *previous_axis {
-                                self.kbd_out.move_mouse(*previous_move)?;
+                                self.output_delay.write(&mut self.kbd_out, move |out| {
+                                    out.move_mouse(previous_move)
+                                })?;
                                 self.movemouse_buffer = Some((axis, current_move));
                             } else {
-                                self.kbd_out
-                                    .move_mouse_many(&[*previous_move, current_move])?;
+                                self.output_delay.write(&mut self.kbd_out, move |out| {
+                                    out.move_mouse_many(&[previous_move, current_move])
+                                })?;
                                 self.movemouse_buffer = None;
                             }
                         }
@@ -690,7 +1474,8 @@ impl Kanata {
                         }
                     }
                 } else {
-                    self.kbd_out.move_mouse(current_move)?;
+                    self.output_delay
+                        .write(&mut self.kbd_out, move |out| out.move_mouse(current_move))?;
                 }
             } else {
                 mmsh.ticks_until_move -= 1;
@@ -699,15 +1484,86 @@ impl Kanata {
         Ok(())
     }
 
+    fn handle_repeat_accel(&mut self) -> Result<()> {
+        if let Some(state) = &mut self.repeat_accel_state {
+            state.ticks_held = state.ticks_held.saturating_add(1);
+            if state.ticks_until_repeat == 0 {
+                state.ticks_until_repeat = state.interval() - 1;
+                let Coord { x, y } = state.coord;
+                handle_fakekey_action(FakeKeyAction::Tap, self.layout.bm(), x, y);
+            } else {
+                state.ticks_until_repeat -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_axis_ramps(&mut self) -> Result<()> {
+        for state in self.axis_ramp_states.iter_mut() {
+            let value = state.tick();
+            if value != state.value {
+                state.value = value;
+                #[cfg(target_os = "linux")]
+                self.kbd_out.set_gamepad_axis(state.axis, value)?;
+            }
+        }
+        self.axis_ramp_states.retain(|state| !state.is_at_rest());
+        Ok(())
+    }
+
     fn tick_sequence_state(&mut self) -> Result<()> {
         if let Some(state) = &mut self.sequence_state {
             state.ticks_until_timeout -= 1;
             if state.ticks_until_timeout == 0 {
-                log::debug!("sequence timeout; exiting sequence state");
-                cancel_sequence(state, &mut self.kbd_out)?;
+                match state.pending {
+                    Some((fake_key, count)) => {
+                        log::debug!("sequence timeout; completing pending sequence");
+                        complete_sequence(
+                            state,
+                            fake_key,
+                            count,
+                            self.layout.bm(),
+                            &mut self.output_delay,
+                            &mut self.kbd_out,
+                            &mut self.sequence_repeats,
+                        )?;
+                    }
+                    None => {
+                        log::debug!("sequence timeout; exiting sequence state");
+                        cancel_sequence(state, &mut self.output_delay, &mut self.kbd_out)?;
+                    }
+                }
                 self.sequence_state = None;
             }
         }
+        // Tap the fake key of a sequence with a count again once the previous tap, which may be a
+        // macro, has finished.
+        if let Some(((i, j), remaining)) = &mut self.sequence_repeats {
+            let layout = self.layout.bm();
+            if layout.queue.is_empty()
+                && layout.active_sequences.is_empty()
+                && !states_has_coord(&layout.states, *i, *j)
+            {
+                layout.event(Event::Press(*i, *j));
+                layout.event(Event::Release(*i, *j));
+                *remaining -= 1;
+                if *remaining == 0 {
+                    self.sequence_repeats = None;
+                }
+            }
+        }
+        // Type the key that ended a shorter sequence once the fake key of the sequence has been
+        // tapped and its action, e.g. a macro, has been output.
+        if let Some(item) = self.sequence_replay {
+            let layout = self.layout.b();
+            if self.sequence_repeats.is_none()
+                && layout.queue.is_empty()
+                && layout.active_sequences.is_empty()
+            {
+                self.sequence_replay = None;
+                tap_sequence_item(item, &mut self.output_delay, &mut self.kbd_out)?;
+            }
+        }
         Ok(())
     }
 
@@ -757,6 +1613,68 @@ impl Kanata {
         })
     }
 
+    /// Release the output keys of physical key positions if keys have been held for longer than
+    /// `max-held-key-duration` without any input, including key repeats. This protects against
+    /// keys that are stuck due to a missed release event.
+    fn tick_held_key_watchdog(&mut self) {
+        if self.max_held_key_duration == 0 {
+            return;
+        }
+        if self.cur_keys.is_empty() {
+            self.ticks_held_without_input = 0;
+            return;
+        }
+        self.ticks_held_without_input += 1;
+        if self.ticks_held_without_input <= self.max_held_key_duration {
+            return;
+        }
+        log::warn!(
+            "releasing keys held for over {}s without input: {:?}",
+            self.max_held_key_duration / 1000,
+            self.cur_keys
+        );
+        self.ticks_held_without_input = 0;
+        // Fake keys may be intentionally held for a long time, so only clear the states of
+        // physical key positions. The output releases are sent on the next tick.
+        self.layout.bm().states.retain(|s| {
+            !matches!(
+                s,
+                State::NormalKey {
+                    coord: (NORMAL_KEY_ROW, _),
+                    ..
+                }
+            )
+        });
+    }
+
+    #[cfg(feature = "cmd")]
+    fn tick_cmd_callbacks(&mut self) {
+        if self.pending_cmd_callbacks == 0 {
+            return;
+        }
+        while let Ok(completion) = self.cmd_callback_rx.try_recv() {
+            self.pending_cmd_callbacks = self.pending_cmd_callbacks.saturating_sub(1);
+            match completion {
+                CmdCompletion::Callback(Some(FakeKeyCallback {
+                    coord: Coord { x, y },
+                    action,
+                })) => handle_fakekey_action(action, self.layout.bm(), x, y),
+                CmdCompletion::Callback(None) => {}
+                CmdCompletion::ConfigEdited { changed: true } => {
+                    self.live_reload_requested = true;
+                    log::info!(
+                        "Config was edited, requested live reload of file: {}",
+                        self.cfg_paths[self.cur_cfg_idx].display()
+                    );
+                }
+                CmdCompletion::ConfigEdited { changed: false } => {
+                    log::warn!("Config was not changed by the editor, skipping live reload");
+                }
+                CmdCompletion::TypeText { text, typing } => self.text_typing.queue(&text, typing),
+            }
+        }
+    }
+
     /// Sends OS key events according to the change in key state between the current and the
     /// previous keyberon keystate. Also processes any custom actions.
     ///
@@ -765,6 +1683,8 @@ impl Kanata {
     /// Returns whether live reload was requested.
     fn handle_keystate_changes(&mut self) -> Result<bool> {
         let layout = self.layout.bm();
+        // A macro continues once the clipboard programs that it started are done.
+        layout.sequences_paused = clipboard::is_busy();
         let custom_event = layout.tick();
         let mut live_reload_requested = false;
         let cur_keys = &mut self.cur_keys;
@@ -824,7 +1744,10 @@ impl Kanata {
                 continue;
             }
             log::debug!("key release   {:?}", k);
-            if let Err(e) = self.kbd_out.release_key(k.into()) {
+            if let Err(e) =
+                self.output_delay
+                    .write_key(&mut self.kbd_out, k.into(), KeyValue::Release)
+            {
                 bail!("failed to release key: {:?}", e);
             }
         }
@@ -846,10 +1769,21 @@ impl Kanata {
             // allocations and logic.
             self.prev_keys.push(*k);
             LAST_PRESSED_KEY.store(OsCode::from(k).into(), SeqCst);
+            if self.typing_stats_interval > 0 {
+                let modded = cur_keys
+                    .iter()
+                    .fold(u16::from(OsCode::from(*k)), |key, held| {
+                        key | mod_mask_for_keycode(*held)
+                    });
+                self.typing_stats.record(modded, time::Instant::now());
+            }
             match &mut self.sequence_state {
                 None => {
                     log::debug!("key press     {:?}", k);
-                    if let Err(e) = self.kbd_out.press_key(k.into()) {
+                    if let Err(e) =
+                        self.output_delay
+                            .write_key(&mut self.kbd_out, k.into(), KeyValue::Press)
+                    {
                         bail!("failed to press key: {:?}", e);
                     }
                 }
@@ -879,7 +1813,10 @@ impl Kanata {
                     state.sequence.push(pushed_into_seq);
                     match state.sequence_input_mode {
                         SequenceInputMode::VisibleBackspaced => {
-                            self.kbd_out.press_key(osc)?;
+                            self.output_delay.press_key(&mut self.kbd_out, osc)?;
+                            if outputs_character(pushed_into_seq) {
+                                state.visible_chars = state.visible_chars.saturating_add(1);
+                            }
                         }
                         SequenceInputMode::HiddenSuppressed
                         | SequenceInputMode::HiddenDelayType => {}
@@ -887,11 +1824,10 @@ impl Kanata {
                     log::debug!("sequence got {k:?}");
 
                     use kanata_parser::sequences::*;
-                    use kanata_parser::trie::GetOrDescendentExistsResult::*;
 
                     // Check for invalid sequence termination.
-                    let mut res = self.sequences.get_or_descendant_exists(&state.sequence);
-                    if res == NotInTrie {
+                    let mut res = self.sequences.find(&state.sequence);
+                    if res == SequenceMatch::NotMatched {
                         let is_invalid_termination = if self.sequence_backtrack_modcancel
                             && (pushed_into_seq & MASK_MODDED > 0)
                         {
@@ -901,8 +1837,8 @@ impl Kanata {
                                 // Safety: proper bounds are immediately above.
                                 // Note - can't use iter_mut due to borrowing issues.
                                 *unsafe { state.sequence.get_unchecked_mut(i) } &= MASK_KEYCODES;
-                                res = self.sequences.get_or_descendant_exists(&state.sequence);
-                                if res != NotInTrie {
+                                res = self.sequences.find(&state.sequence);
+                                if res != SequenceMatch::NotMatched {
                                     no_valid_seqs = false;
                                     break;
                                 }
@@ -912,13 +1848,31 @@ impl Kanata {
                             true
                         };
                         if is_invalid_termination {
+                            if let Some((fake_key, count)) = state.pending {
+                                // The key ends the shorter sequence instead of continuing a
+                                // longer one. It is typed again after the sequence's action.
+                                log::debug!("sequence terminated; completing pending sequence");
+                                complete_sequence(
+                                    state,
+                                    fake_key,
+                                    count,
+                                    layout,
+                                    &mut self.output_delay,
+                                    &mut self.kbd_out,
+                                    &mut self.sequence_repeats,
+                                )?;
+                                self.sequence_state = None;
+                                self.sequence_replay = Some(pushed_into_seq);
+                                continue;
+                            }
                             log::debug!("got invalid sequence; exiting sequence mode");
                             match state.sequence_input_mode {
                                 SequenceInputMode::HiddenDelayType => {
                                     for code in state.sequence.iter().copied() {
                                         if let Some(osc) = OsCode::from_u16(code) {
-                                            self.kbd_out.press_key(osc)?;
-                                            self.kbd_out.release_key(osc)?;
+                                            self.output_delay.press_key(&mut self.kbd_out, osc)?;
+                                            self.output_delay
+                                                .release_key(&mut self.kbd_out, osc)?;
                                         }
                                     }
                                 }
@@ -931,72 +1885,26 @@ impl Kanata {
                     }
 
                     // Check for and handle valid termination.
-                    if let HasValue((i, j)) = res {
-                        log::debug!("sequence complete; tapping fake key");
-                        match state.sequence_input_mode {
-                            SequenceInputMode::HiddenSuppressed
-                            | SequenceInputMode::HiddenDelayType => {}
-                            SequenceInputMode::VisibleBackspaced => {
-                                // Release all keys since they might modify the behaviour of
-                                // backspace into an undesirable behaviour, for example deleting
-                                // more characters than it should.
-                                layout.states.retain(|s| match s {
-                                    State::NormalKey { keycode, .. } => {
-                                        // Ignore the error, ugly to return it from retain, and
-                                        // this is very unlikely to happen anyway.
-                                        let _ = self.kbd_out.release_key(keycode.into());
-                                        false
-                                    }
-                                    _ => true,
-                                });
-                                for k in state.sequence.iter() {
-                                    // Check for pressed modifiers and don't input backspaces for
-                                    // those since they don't output characters that can be
-                                    // backspaced.
-                                    let kc = OsCode::from(*k & MASK_KEYCODES);
-                                    if matches!(
-                                        kc,
-                                        // Known bug: most non-characters-outputting keys are not
-                                        // listed. I'm too lazy to list them all. Just use
-                                        // character-outputting keys (and modifiers) in sequences
-                                        // please! Or switch to a different input mode? It doesn't
-                                        // really make sense to use non-typing characters other
-                                        // than modifiers does it? Since those would probably be
-                                        // further away from the home row, so why use them? If one
-                                        // desired to fix this, a shorter list of keys would
-                                        // probably be the list of keys that **do** output
-                                        // characters than those that don't.
-                                        OsCode::KEY_LEFTSHIFT
-                                            | OsCode::KEY_RIGHTSHIFT
-                                            | OsCode::KEY_LEFTMETA
-                                            | OsCode::KEY_RIGHTMETA
-                                            | OsCode::KEY_LEFTCTRL
-                                            | OsCode::KEY_RIGHTCTRL
-                                            | OsCode::KEY_LEFTALT
-                                            | OsCode::KEY_RIGHTALT
-                                    ) {
-                                        continue;
-                                    }
-
-                                    self.kbd_out.press_key(OsCode::KEY_BACKSPACE)?;
-                                    self.kbd_out.release_key(OsCode::KEY_BACKSPACE)?;
-                                }
-                            }
+                    state.pending = None;
+                    match res {
+                        SequenceMatch::Complete { fake_key, count } => {
+                            log::debug!("sequence complete; tapping fake key");
+                            complete_sequence(
+                                state,
+                                fake_key,
+                                count,
+                                layout,
+                                &mut self.output_delay,
+                                &mut self.kbd_out,
+                                &mut self.sequence_repeats,
+                            )?;
+                            self.sequence_state = None;
                         }
-
-                        // Make sure to unpress any keys that were pressed as part of the sequence
-                        // so that the keyberon internal sequence mechanism can do press+unpress of
-                        // them.
-                        for k in state.sequence.iter() {
-                            let kc = KeyCode::from(OsCode::from(*k & MASK_KEYCODES));
-                            layout.states.retain(|s| match s {
-                                State::NormalKey { keycode, .. } => kc != *keycode,
-                                _ => true,
-                            });
+                        SequenceMatch::Ambiguous { fake_key, count } => {
+                            log::debug!("sequence complete but a longer one may follow");
+                            state.pending = Some((fake_key, count));
                         }
-                        layout.event(Event::Press(i, j));
-                        layout.event(Event::Release(i, j));
-                        self.sequence_state = None;
+                        SequenceMatch::Prefix | SequenceMatch::NotMatched => {}
                     }
                 }
             }
@@ -1013,7 +1921,11 @@ impl Kanata {
                     match custact {
                         // For unicode, only send on the press. No repeat action is supported for this for
                         // now.
-                        CustomAction::Unicode(c) => self.kbd_out.send_unicode(*c)?,
+                        CustomAction::Unicode(c) => {
+                            let c = *c;
+                            self.output_delay
+                                .write(&mut self.kbd_out, move |out| out.send_unicode(c))?
+                        }
                         CustomAction::LiveReload => {
                             live_reload_requested = true;
                             log::info!(
@@ -1033,6 +1945,27 @@ impl Kanata {
                                 self.cfg_paths[self.cur_cfg_idx].display()
                             );
                         }
+                        CustomAction::EditConfig => {
+                            #[cfg(feature = "cmd")]
+                            {
+                                let completion_tx = match self.edit_config_live_reload {
+                                    true => {
+                                        self.pending_cmd_callbacks += 1;
+                                        Some(self.cmd_callback_tx.clone())
+                                    }
+                                    false => None,
+                                };
+                                open_in_editor(
+                                    editor_cmd(self.editor.as_deref()),
+                                    self.cfg_paths[self.cur_cfg_idx].clone(),
+                                    completion_tx,
+                                );
+                            }
+                            #[cfg(not(feature = "cmd"))]
+                            log::warn!(
+                                "edit-config requires kanata to be built with the cmd feature"
+                            );
+                        }
                         CustomAction::LiveReloadPrev => {
                             live_reload_requested = true;
                             self.cur_cfg_idx = match self.cur_cfg_idx {
@@ -1048,42 +1981,181 @@ impl Kanata {
                             log::debug!("click     {:?}", btn);
                             if let Some(pbtn) = prev_mouse_btn {
                                 log::debug!("unclick   {:?}", pbtn);
-                                self.kbd_out.release_btn(pbtn)?;
+                                self.output_delay
+                                    .write(&mut self.kbd_out, move |out| out.release_btn(pbtn))?;
+                            }
+                            let btn = *btn;
+                            self.output_delay
+                                .write(&mut self.kbd_out, move |out| out.click_btn(btn))?;
+                            prev_mouse_btn = Some(btn);
+                        }
+                        CustomAction::MouseTap(btn) => {
+                            let btn = *btn;
+                            log::debug!("click     {:?}", btn);
+                            self.output_delay
+                                .write(&mut self.kbd_out, move |out| out.click_btn(btn))?;
+                            log::debug!("unclick   {:?}", btn);
+                            self.output_delay
+                                .write(&mut self.kbd_out, move |out| out.release_btn(btn))?;
+                        }
+                        CustomAction::MWheel {
+                            direction,
+                            interval,
+                            distance,
+                        } => match direction {
+                            MWheelDirection::Up | MWheelDirection::Down => {
+                                self.scroll_state = Some(ScrollState {
+                                    direction: *direction,
+                                    distance: *distance,
+                                    ticks_until_scroll: 0,
+                                    interval: *interval,
+                                })
+                            }
+                            MWheelDirection::Left | MWheelDirection::Right => {
+                                self.hscroll_state = Some(ScrollState {
+                                    direction: *direction,
+                                    distance: *distance,
+                                    ticks_until_scroll: 0,
+                                    interval: *interval,
+                                })
+                            }
+                        },
+                        CustomAction::MWheelNotch { direction } => {
+                            let direction = scroll_direction(*direction);
+                            self.output_delay.write(&mut self.kbd_out, move |out| {
+                                out.scroll(direction, HI_RES_SCROLL_UNITS_IN_LO_RES)
+                            })?;
+                        }
+                        CustomAction::OutputDeviceKey { device, key } => {
+                            #[cfg(target_os = "linux")]
+                            {
+                                let (device, key) = (*device, *key);
+                                self.output_delay.write(&mut self.kbd_out, move |out| {
+                                    out.write_key_on(device, key, KeyValue::Press)
+                                })?;
+                            }
+                            #[cfg(not(target_os = "linux"))]
+                            {
+                                let _ = device;
+                                self.output_delay.press_key(&mut self.kbd_out, *key)?;
+                            }
+                        }
+                        CustomAction::GamepadButton(button) => {
+                            #[cfg(target_os = "linux")]
+                            self.kbd_out
+                                .write_gamepad_button(*button, KeyValue::Press)?;
+                            #[cfg(not(target_os = "linux"))]
+                            log::warn!("gamepad output is only supported on Linux: {button:?}");
+                        }
+                        CustomAction::GamepadAxis { axis, value } => {
+                            #[cfg(target_os = "linux")]
+                            self.kbd_out.set_gamepad_axis(*axis, *value)?;
+                            #[cfg(not(target_os = "linux"))]
+                            log::warn!(
+                                "gamepad output is only supported on Linux: {axis:?} {value}"
+                            );
+                        }
+                        CustomAction::MidiNote {
+                            channel,
+                            note,
+                            velocity,
+                        } => match &mut self.midi_out {
+                            Some(midi) => {
+                                if let Err(e) = midi.note_on(*channel, *note, *velocity) {
+                                    log::error!("failed to send MIDI note on: {e}");
+                                }
+                            }
+                            None => log::warn!("midi-note used but linux-midi-device is not set"),
+                        },
+                        CustomAction::MidiControlChange {
+                            channel,
+                            controller,
+                            value,
+                        } => match &mut self.midi_out {
+                            Some(midi) => {
+                                if let Err(e) = midi.control_change(*channel, *controller, *value) {
+                                    log::error!("failed to send MIDI control change: {e}");
+                                }
+                            }
+                            None => log::warn!("midi-cc used but linux-midi-device is not set"),
+                        },
+                        CustomAction::Osc { address, args } => match &self.osc_out {
+                            Some(osc) => {
+                                if let Err(e) = osc.send(address, args) {
+                                    log::error!("failed to send OSC message {address}: {e}");
+                                }
+                            }
+                            None => log::warn!("osc used but osc-target is not set"),
+                        },
+                        CustomAction::HttpPost { url, body } => {
+                            self.http_poster.post(url, body);
+                        }
+                        CustomAction::Notify { title, body } => notify::notify(title, body),
+                        CustomAction::WindowManager(cmd) => {
+                            // Windows has no window manager socket, the commands are shortcuts.
+                            for keys in self.window_manager.run(cmd) {
+                                for key in keys.iter() {
+                                    self.output_delay.press_key(&mut self.kbd_out, *key)?;
+                                }
+                                for key in keys.iter().rev() {
+                                    self.output_delay.release_key(&mut self.kbd_out, *key)?;
+                                }
                             }
-                            self.kbd_out.click_btn(*btn)?;
-                            prev_mouse_btn = Some(*btn);
                         }
-                        CustomAction::MouseTap(btn) => {
-                            log::debug!("click     {:?}", btn);
-                            self.kbd_out.click_btn(*btn)?;
-                            log::debug!("unclick   {:?}", btn);
-                            self.kbd_out.release_btn(*btn)?;
-                        }
-                        CustomAction::MWheel {
+                        CustomAction::ClipboardSet(text) => clipboard::set(text.to_string()),
+                        CustomAction::ClipboardSaveSlot(slot) => clipboard::save_slot(*slot),
+                        CustomAction::ClipboardRestoreSlot(slot) => clipboard::restore_slot(*slot),
+                        CustomAction::GamepadAxisRamp {
+                            axis,
                             direction,
-                            interval,
-                            distance,
-                        } => match direction {
-                            MWheelDirection::Up | MWheelDirection::Down => {
-                                self.scroll_state = Some(ScrollState {
-                                    direction: *direction,
-                                    distance: *distance,
-                                    ticks_until_scroll: 0,
-                                    interval: *interval,
-                                })
+                            ramp_up,
+                            ramp_down,
+                            curve,
+                        } => {
+                            #[cfg(not(target_os = "linux"))]
+                            {
+                                let _ = (ramp_up, ramp_down, curve);
+                                log::warn!(
+                                    "gamepad output is only supported on Linux: {axis:?} {direction:?}"
+                                );
                             }
-                            MWheelDirection::Left | MWheelDirection::Right => {
-                                self.hscroll_state = Some(ScrollState {
-                                    direction: *direction,
-                                    distance: *distance,
-                                    ticks_until_scroll: 0,
-                                    interval: *interval,
-                                })
+                            #[cfg(target_os = "linux")]
+                            {
+                                let state = match self
+                                    .axis_ramp_states
+                                    .iter()
+                                    .position(|state| state.axis == *axis)
+                                {
+                                    Some(i) => &mut self.axis_ramp_states[i],
+                                    None => {
+                                        self.axis_ramp_states.push(AxisRampState::new(*axis));
+                                        self.axis_ramp_states.last_mut().expect("just pushed")
+                                    }
+                                };
+                                match direction {
+                                    AxisDirection::Negative => state.negative_held = true,
+                                    AxisDirection::Positive => state.positive_held = true,
+                                }
+                                state.ramp_up = *ramp_up;
+                                state.ramp_down = *ramp_down;
+                                state.curve = *curve;
                             }
-                        },
-                        CustomAction::MWheelNotch { direction } => {
-                            self.kbd_out
-                                .scroll(*direction, HI_RES_SCROLL_UNITS_IN_LO_RES)?;
+                        }
+                        CustomAction::RepeatAccel {
+                            coord,
+                            start_interval,
+                            end_interval,
+                            accel_time,
+                        } => {
+                            handle_fakekey_action(FakeKeyAction::Tap, layout, coord.x, coord.y);
+                            self.repeat_accel_state = Some(RepeatAccelState {
+                                coord: *coord,
+                                start_interval: *start_interval,
+                                end_interval: *end_interval,
+                                accel_time: *accel_time,
+                                ticks_held: 0,
+                                ticks_until_repeat: *start_interval,
+                            });
                         }
                         CustomAction::MoveMouse {
                             direction,
@@ -1184,19 +2256,56 @@ impl Kanata {
                         }
                         CustomAction::Cmd(_cmd) => {
                             #[cfg(feature = "cmd")]
-                            cmds.push(_cmd.clone());
+                            cmds.push(CmdJob {
+                                cmd: _cmd.clone(),
+                                on_success: None,
+                                on_failure: None,
+                            });
+                        }
+                        CustomAction::CmdOnResult {
+                            cmd: _cmd,
+                            on_success: _on_success,
+                            on_failure: _on_failure,
+                        } => {
+                            #[cfg(feature = "cmd")]
+                            {
+                                self.pending_cmd_callbacks += 1;
+                                cmds.push(CmdJob {
+                                    cmd: _cmd.clone(),
+                                    on_success: *_on_success,
+                                    on_failure: *_on_failure,
+                                });
+                            }
                         }
                         CustomAction::CmdOutputKeys(_cmd) => {
                             #[cfg(feature = "cmd")]
                             {
                                 for (key_action, osc) in keys_for_cmd_output(_cmd) {
                                     match key_action {
-                                        KeyAction::Press => self.kbd_out.press_key(osc)?,
-                                        KeyAction::Release => self.kbd_out.release_key(osc)?,
+                                        KeyAction::Press => {
+                                            self.output_delay.press_key(&mut self.kbd_out, osc)?
+                                        }
+                                        KeyAction::Release => {
+                                            self.output_delay.release_key(&mut self.kbd_out, osc)?
+                                        }
                                     }
                                 }
                             }
                         }
+                        CustomAction::CmdOutputText {
+                            cmd: _cmd,
+                            typing: _typing,
+                        } => {
+                            #[cfg(feature = "cmd")]
+                            {
+                                self.pending_cmd_callbacks += 1;
+                                type_cmd_output(
+                                    _cmd.clone(),
+                                    *_typing,
+                                    self.cmd_callback_tx.clone(),
+                                );
+                            }
+                        }
                         CustomAction::FakeKey { coord, action } => {
                             let (x, y) = (coord.x, coord.y);
                             log::debug!(
@@ -1210,11 +2319,20 @@ impl Kanata {
                             log::debug!("on-press: sleeping for {delay} ms");
                             std::thread::sleep(std::time::Duration::from_millis((*delay).into()));
                         }
+                        CustomAction::LayerCycle(layers) => {
+                            let next_layer = layers
+                                .iter()
+                                .position(|l| *l == layout.default_layer)
+                                .map(|i| layers[(i + 1) % layers.len()])
+                                .unwrap_or(layers[0]);
+                            log::debug!("layer-cycle to layer index {next_layer}");
+                            layout.set_default_layer(next_layer);
+                        }
                         CustomAction::SequenceCancel => {
                             if self.sequence_state.is_some() {
                                 log::debug!("exiting sequence");
                                 let state = self.sequence_state.as_ref().unwrap();
-                                cancel_sequence(state, &mut self.kbd_out)?;
+                                cancel_sequence(state, &mut self.output_delay, &mut self.kbd_out)?;
                                 self.sequence_state = None;
                             }
                         }
@@ -1229,9 +2347,57 @@ impl Kanata {
                                     sequence_input_mode: *input_mode,
                                     ticks_until_timeout: *timeout,
                                     sequence_timeout: *timeout,
+                                    visible_chars: 0,
+                                    pending: None,
                                 });
                             }
                         }
+                        CustomAction::FnLockToggle => {
+                            layout.flags ^= 1 << FN_LOCK_FLAG;
+                            let on = layout.flags & (1 << FN_LOCK_FLAG) != 0;
+                            log::info!("fn-lock is {}", if on { "on" } else { "off" });
+                            fn_lock::save(on);
+                        }
+                        CustomAction::InvertScrollToggle => {
+                            let on = !INVERT_SCROLL.fetch_xor(true, SeqCst);
+                            log::info!("invert-scroll is {}", if on { "on" } else { "off" });
+                        }
+                        CustomAction::CancelMacros => {
+                            log::info!("cancelling all macros");
+                            layout.cancel_all_sequences();
+                            self.dynamic_macro_replay_state = None;
+                        }
+                        CustomAction::MacroGuard(guard) => {
+                            if !self.held_input_keys.iter().any(|(_, k)| k == guard) {
+                                log::info!("{guard:?} is not held, cancelling its macro");
+                                layout.cancel_sequences_with_custom(custacts);
+                            }
+                        }
+                        CustomAction::ResyncMods => {
+                            log::info!("resyncing modifiers");
+                            resync_mods(&mut self.output_delay, &mut self.kbd_out, cur_keys)?;
+                        }
+                        CustomAction::ShowHelp { type_text } => {
+                            self.help_requested = true;
+                            if *type_text {
+                                let layer = layout.current_layer();
+                                let help = help_text(&self.layer_info[layer]);
+                                self.text_typing.queue(
+                                    &help,
+                                    TextTypingCfg {
+                                        newline: NewlinePolicy::Enter,
+                                        ascii_as_keys: true,
+                                        interval: 0,
+                                        chunk: u16::MAX,
+                                    },
+                                );
+                            }
+                        }
+                        CustomAction::ShowActionUsage => {
+                            let (bindings, unused_aliases) =
+                                self.action_usage.report(&self.layer_info);
+                            log::info!("{}", action_usage_text(&bindings, &unused_aliases));
+                        }
                         CustomAction::Repeat => {
                             let key = OsCode::from(LAST_PRESSED_KEY.load(SeqCst));
                             log::debug!("repeating a keypress {key:?}");
@@ -1243,16 +2409,18 @@ impl Kanata {
                                     cw.maybe_add_lsft(cur_keys);
                                     if cur_keys.len() > prev_len {
                                         do_caps_word = true;
-                                        self.kbd_out.press_key(OsCode::KEY_LEFTSHIFT)?;
+                                        self.output_delay
+                                            .press_key(&mut self.kbd_out, OsCode::KEY_LEFTSHIFT)?;
                                     }
                                 }
                             }
                             // Release key in case the most recently pressed key is still pressed.
-                            self.kbd_out.release_key(key)?;
-                            self.kbd_out.press_key(key)?;
-                            self.kbd_out.release_key(key)?;
+                            self.output_delay.release_key(&mut self.kbd_out, key)?;
+                            self.output_delay.press_key(&mut self.kbd_out, key)?;
+                            self.output_delay.release_key(&mut self.kbd_out, key)?;
                             if do_caps_word {
-                                self.kbd_out.release_key(OsCode::KEY_LEFTSHIFT)?;
+                                self.output_delay
+                                    .release_key(&mut self.kbd_out, OsCode::KEY_LEFTSHIFT)?;
                             }
                         }
                         CustomAction::DynamicMacroRecord(macro_id) => {
@@ -1355,13 +2523,18 @@ impl Kanata {
                             }
                         }
                         CustomAction::SendArbitraryCode(code) => {
-                            self.kbd_out.write_code(*code as u32, KeyValue::Press)?;
+                            let code = *code as u32;
+                            self.output_delay.write(&mut self.kbd_out, move |out| {
+                                out.write_code(code, KeyValue::Press)
+                            })?;
                         }
                         CustomAction::CapsWord(cfg) => {
                             self.caps_word = Some(CapsWordState::new(cfg));
                         }
                         CustomAction::SetMouse { x, y } => {
-                            self.kbd_out.set_mouse(*x, *y)?;
+                            let (x, y) = (*x, *y);
+                            self.output_delay
+                                .write(&mut self.kbd_out, move |out| out.set_mouse(x, y))?;
                         }
                         CustomAction::FakeKeyOnIdle(fkd) => {
                             self.ticks_since_idle = 0;
@@ -1370,11 +2543,14 @@ impl Kanata {
                         CustomAction::FakeKeyOnRelease { .. }
                         | CustomAction::DelayOnRelease(_)
                         | CustomAction::Unmodded { .. }
-                        | CustomAction::CancelMacroOnRelease => {}
+                        | CustomAction::CancelMacroOnRelease
+                        | CustomAction::DefsrcKey => {}
                     }
                 }
                 #[cfg(feature = "cmd")]
-                run_multi_cmd(cmds);
+                if !cmds.is_empty() {
+                    run_multi_cmd(cmds, self.cmd_callback_tx.clone());
+                }
             }
 
             CustomEvent::Release(custacts) => {
@@ -1402,6 +2578,76 @@ impl Kanata {
                             }
                             pbtn
                         }
+                        CustomAction::OutputDeviceKey { device, key } => {
+                            #[cfg(target_os = "linux")]
+                            let res = {
+                                let (device, key) = (*device, *key);
+                                self.output_delay.write(&mut self.kbd_out, move |out| {
+                                    out.write_key_on(device, key, KeyValue::Release)
+                                })
+                            };
+                            #[cfg(not(target_os = "linux"))]
+                            let res = {
+                                let _ = device;
+                                self.output_delay.release_key(&mut self.kbd_out, *key)
+                            };
+                            if let Err(e) = res {
+                                log::error!("failed to release {key:?}: {e}");
+                            }
+                            pbtn
+                        }
+                        #[cfg(target_os = "linux")]
+                        CustomAction::GamepadButton(button) => {
+                            if let Err(e) = self
+                                .kbd_out
+                                .write_gamepad_button(*button, KeyValue::Release)
+                            {
+                                log::error!("failed to release gamepad {button:?}: {e}");
+                            }
+                            pbtn
+                        }
+                        #[cfg(target_os = "linux")]
+                        CustomAction::GamepadAxis { axis, value } => {
+                            if let Err(e) = self.kbd_out.reset_gamepad_axis(*axis, *value) {
+                                log::error!("failed to reset gamepad {axis:?}: {e}");
+                            }
+                            pbtn
+                        }
+                        CustomAction::GamepadAxisRamp {
+                            axis,
+                            direction,
+                            ramp_down,
+                            ..
+                        } => {
+                            if let Some(state) = self
+                                .axis_ramp_states
+                                .iter_mut()
+                                .find(|state| state.axis == *axis)
+                            {
+                                match direction {
+                                    AxisDirection::Negative => state.negative_held = false,
+                                    AxisDirection::Positive => state.positive_held = false,
+                                }
+                                state.ramp_down = *ramp_down;
+                            }
+                            pbtn
+                        }
+                        CustomAction::MidiNote { channel, note, .. } => {
+                            if let Some(midi) = &mut self.midi_out {
+                                if let Err(e) = midi.note_off(*channel, *note) {
+                                    log::error!("failed to send MIDI note off: {e}");
+                                }
+                            }
+                            pbtn
+                        }
+                        CustomAction::RepeatAccel { coord, .. } => {
+                            if let Some(state) = &self.repeat_accel_state {
+                                if state.coord == *coord {
+                                    self.repeat_accel_state = None;
+                                }
+                            }
+                            pbtn
+                        }
                         CustomAction::MoveMouse { direction, .. }
                         | CustomAction::MoveMouseAccel { direction, .. } => {
                             match direction {
@@ -1463,8 +2709,10 @@ impl Kanata {
                             pbtn
                         }
                         CustomAction::SendArbitraryCode(code) => {
-                            if let Err(e) = self.kbd_out.write_code(*code as u32, KeyValue::Release)
-                            {
+                            let code = *code as u32;
+                            if let Err(e) = self.output_delay.write(&mut self.kbd_out, move |out| {
+                                out.write_code(code, KeyValue::Release)
+                            }) {
                                 log::error!("failed to send arbitrary code {e:?}");
                             }
                             pbtn
@@ -1473,7 +2721,9 @@ impl Kanata {
                     })
                     .map(|btn| {
                         log::debug!("unclick   {:?}", btn);
-                        self.kbd_out.release_btn(*btn)
+                        let btn = *btn;
+                        self.output_delay
+                            .write(&mut self.kbd_out, move |out| out.release_btn(btn))
                     })
                 {
                     bail!(e);
@@ -1500,6 +2750,9 @@ impl Kanata {
     }
 
     fn handle_repeat_actual(&mut self, event: &KeyEvent) -> Result<()> {
+        if !self.layer_cfg.key_repeat {
+            return Ok(());
+        }
         if self.sequence_state.is_some() {
             // While in sequence mode, don't send key repeats. I can't imagine it's a helpful use
             // case for someone trying to type in a sequence that they want to rely on key repeats
@@ -1518,7 +2771,10 @@ impl Kanata {
                 for kc in outputs_for_key.iter().rev() {
                     if self.cur_keys.contains(&kc.into()) {
                         log::debug!("repeat    {:?}", KeyCode::from(*kc));
-                        if let Err(e) = self.kbd_out.write_key(*kc, KeyValue::Repeat) {
+                        if let Err(e) =
+                            self.output_delay
+                                .write_key(&mut self.kbd_out, *kc, KeyValue::Repeat)
+                        {
                             bail!("could not write key {:?}", e)
                         }
                         return Ok(());
@@ -1543,7 +2799,10 @@ impl Kanata {
         for kc in outputs_for_key.iter().rev() {
             if self.cur_keys.contains(&kc.into()) {
                 log::debug!("repeat    {:?}", KeyCode::from(*kc));
-                if let Err(e) = self.kbd_out.write_key(*kc, KeyValue::Repeat) {
+                if let Err(e) =
+                    self.output_delay
+                        .write_key(&mut self.kbd_out, *kc, KeyValue::Repeat)
+                {
                     bail!("could not write key {:?}", e)
                 }
                 return Ok(());
@@ -1552,6 +2811,14 @@ impl Kanata {
         Ok(())
     }
 
+    /// Stops all running macros and dynamic macro replays.
+    pub fn cancel_macros(&mut self) {
+        log::info!("cancelling all macros");
+        self.layout.bm().cancel_all_sequences();
+        self.dynamic_macro_replay_state = None;
+        self.text_typing.cancel();
+    }
+
     pub fn change_layer(&mut self, layer_name: String) {
         for (i, l) in self.layer_info.iter().enumerate() {
             if l.name == layer_name {
@@ -1561,6 +2828,100 @@ impl Kanata {
         }
     }
 
+    /// Applies a command sent by a TCP client.
+    pub fn handle_client_message(&mut self, msg: ClientMessage) -> Result<()> {
+        match msg {
+            ClientMessage::Hello { .. }
+            | ClientMessage::SetLayerChangeDelay { .. }
+            | ClientMessage::RequestKeyAction { .. }
+            | ClientMessage::SetKeyAction { .. }
+            | ClientMessage::UndoKeyAction { .. }
+            | ClientMessage::RedoKeyAction { .. }
+            | ClientMessage::RequestActionUsage {}
+            | ClientMessage::RequestInvertScroll {} => {
+                bail!("{msg:?} is handled by the TCP server")
+            }
+            ClientMessage::ChangeLayer { new } => {
+                self.layer_index(&new)?;
+                self.change_layer(new);
+            }
+            ClientMessage::ResyncMods => self.resync_mods()?,
+            ClientMessage::CancelMacros => self.cancel_macros(),
+            ClientMessage::SetMacroDelayPercent { percent } => {
+                log::info!("macro delays are now {percent}%");
+                self.layout.bm().sequence_delay_percent = percent;
+            }
+            ClientMessage::ActOnFakeKey { name, action } => {
+                let y = self.fake_key_index(&name)?;
+                handle_fakekey_action(action.into(), self.layout.bm(), FAKE_KEY_ROW, y);
+            }
+            ClientMessage::Batch { commands } => {
+                // The caller holds the lock for the whole batch, so no key events can be processed
+                // between commands. Check every command first so that an invalid command does not
+                // leave the batch partially applied.
+                for cmd in commands.iter() {
+                    self.check_client_message(cmd)?;
+                }
+                for cmd in commands {
+                    self.handle_client_message(cmd)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_client_message(&self, msg: &ClientMessage) -> Result<()> {
+        match msg {
+            ClientMessage::Hello { .. }
+            | ClientMessage::SetLayerChangeDelay { .. }
+            | ClientMessage::RequestKeyAction { .. }
+            | ClientMessage::SetKeyAction { .. }
+            | ClientMessage::UndoKeyAction { .. }
+            | ClientMessage::RedoKeyAction { .. }
+            | ClientMessage::RequestActionUsage {}
+            | ClientMessage::RequestInvertScroll {} => {
+                bail!("{msg:?} cannot be part of a batch")
+            }
+            ClientMessage::ChangeLayer { new } => self.layer_index(new).map(|_| ()),
+            ClientMessage::ResyncMods
+            | ClientMessage::CancelMacros
+            | ClientMessage::SetMacroDelayPercent { .. } => Ok(()),
+            ClientMessage::ActOnFakeKey { name, .. } => self.fake_key_index(name).map(|_| ()),
+            ClientMessage::Batch { commands } => commands
+                .iter()
+                .try_for_each(|cmd| self.check_client_message(cmd)),
+        }
+    }
+
+    /// Returns the reply to a `RequestActionUsage` message.
+    pub fn action_usage_message(&self) -> ServerMessage {
+        let (bindings, unused_aliases) = self.action_usage.report(&self.layer_info);
+        ServerMessage::ActionUsage {
+            bindings,
+            unused_aliases,
+        }
+    }
+
+    fn layer_index(&self, layer_name: &str) -> Result<usize> {
+        self.layer_info
+            .iter()
+            .position(|l| l.name == layer_name)
+            .ok_or_else(|| anyhow!("unknown layer: {layer_name}"))
+    }
+
+    fn fake_key_index(&self, name: &str) -> Result<u16> {
+        self.fake_keys
+            .get(name)
+            .map(|idx| *idx as u16)
+            .ok_or_else(|| anyhow!("unknown fake key: {name}"))
+    }
+
+    /// Release all modifiers and press again the ones that are held in the kanata state. This fixes
+    /// modifiers that an application believes to be stuck.
+    pub fn resync_mods(&mut self) -> Result<()> {
+        resync_mods(&mut self.output_delay, &mut self.kbd_out, &self.prev_keys)
+    }
+
     /// Prints the layer. If the TCP server is enabled, then this will also send a notification to
     /// all connected clients.
     fn check_handle_layer_change(&mut self, tx: &Option<Sender<ServerMessage>>) {
@@ -1569,6 +2930,11 @@ impl Kanata {
             let new = self.layer_info[cur_layer].name.clone();
             self.prev_layer = cur_layer;
             self.print_layer(cur_layer);
+            self.apply_layer_cfg(cur_layer);
+            #[cfg(all(feature = "gui", target_os = "windows"))]
+            if let Some(tray_icon) = &self.tray_icon {
+                tray_icon.set_layer(&new);
+            }
 
             if let Some(tx) = tx {
                 match tx.try_send(ServerMessage::LayerChange { new }) {
@@ -1581,45 +2947,227 @@ impl Kanata {
         }
     }
 
+    /// Applies the settings of the layer, reverting the overrides of the previous layer.
+    fn apply_layer_cfg(&mut self, layer: usize) {
+        let layer_cfg = self.layer_cfgs[layer];
+        if layer_cfg != self.layer_cfg {
+            log::debug!("layer settings are now {layer_cfg:?}");
+        }
+        self.layer_cfg = layer_cfg;
+        self.layout.bm().sequence_type_delay = layer_cfg.type_delay;
+        #[cfg(target_os = "linux")]
+        self.layer_leds.apply(layer);
+        #[cfg(all(target_os = "windows", not(feature = "interception_driver")))]
+        self.kbd_out
+            .update_sendinput_scancode(layer_cfg.sendinput_scancode);
+    }
+
+    /// Starts or ends a break from typing by changing the default layer, and notifies TCP clients.
+    fn tick_break_reminder(&mut self, now: time::Instant, tx: &Option<Sender<ServerMessage>>) {
+        let default_layer = self.layout.b().default_layer;
+        let msg = match self.break_reminder.tick(now, default_layer) {
+            None => return,
+            Some(BreakChange::Started { layer, duration }) => {
+                log::info!("starting a break of {}s", duration.as_secs());
+                self.layout.bm().set_default_layer(layer);
+                ServerMessage::BreakStarted {
+                    seconds: duration.as_secs().try_into().unwrap_or(u16::MAX),
+                }
+            }
+            Some(BreakChange::Ended {
+                restore_layer,
+                overridden,
+            }) => {
+                log::info!("break ended{}", if overridden { " early" } else { "" });
+                if let Some(layer) = restore_layer {
+                    self.layout.bm().set_default_layer(layer);
+                }
+                ServerMessage::BreakEnded { overridden }
+            }
+        };
+        if let Some(tx) = tx {
+            if let Err(e) = tx.try_send(msg) {
+                log::warn!("could not send break notification: {e:?}");
+            }
+        }
+    }
+
+    /// Logs the bindings of the current layer if `show-help` was activated. If the TCP server is
+    /// enabled, then this will also send them to all connected clients.
+    fn check_handle_help_request(&mut self, tx: &Option<Sender<ServerMessage>>) {
+        if !self.help_requested {
+            return;
+        }
+        self.help_requested = false;
+        let cur_layer = self.layout.bm().current_layer();
+        let info = &self.layer_info[cur_layer];
+        log::info!("{}", help_text(info));
+        if let Some(tx) = tx {
+            let msg = ServerMessage::Help {
+                layer: info.name.clone(),
+                bindings: info
+                    .bindings
+                    .iter()
+                    .map(|(key, action)| HelpBinding {
+                        key: key.clone(),
+                        action: action.clone(),
+                    })
+                    .collect(),
+            };
+            if let Err(error) = tx.try_send(msg) {
+                log::error!("could not send help: {}", error);
+            }
+        }
+    }
+
+    /// Sends the keys typed in sequence mode to TCP clients when they change, and when sequence
+    /// mode starts or ends.
+    fn check_handle_sequence_progress(&mut self, tx: &Option<Sender<ServerMessage>>) {
+        let Some(tx) = tx else {
+            return;
+        };
+        let progress = self
+            .sequence_state
+            .as_ref()
+            .map(|state| (state.sequence.as_slice(), state.pending));
+        let sent = self
+            .sent_sequence_progress
+            .as_ref()
+            .map(|(keys, pending)| (keys.as_slice(), *pending));
+        if progress == sent {
+            return;
+        }
+        let msg = ServerMessage::SequenceProgress {
+            active: progress.is_some(),
+            keys: progress
+                .iter()
+                .flat_map(|(keys, _)| keys.iter())
+                .map(|key| sequence_key_name(*key))
+                .collect(),
+            pending: progress
+                .and_then(|(_, pending)| pending)
+                .and_then(|((_, y), _)| {
+                    self.fake_keys
+                        .iter()
+                        .find(|(_, idx)| **idx == usize::from(y))
+                        .map(|(name, _)| name.clone())
+                }),
+        };
+        self.sent_sequence_progress = progress.map(|(keys, pending)| (keys.to_vec(), pending));
+        if let Err(error) = tx.try_send(msg) {
+            log::error!("could not send sequence progress: {}", error);
+        }
+    }
+
     fn print_layer(&self, layer: usize) {
         if self.log_layer_changes {
             log::info!("Entered layer:\n\n{}", self.layer_info[layer].cfg_text);
         }
     }
 
-    pub fn start_notification_loop(
-        rx: Receiver<ServerMessage>,
-        clients: Arc<Mutex<HashMap<String, TcpStream>>>,
-    ) {
+    pub fn start_notification_loop(rx: Receiver<ServerMessage>, clients: Connections) {
         info!("listening for event notifications to relay to connected clients");
         std::thread::spawn(move || {
             loop {
-                match rx.recv() {
-                    Err(_) => {
-                        panic!("channel disconnected")
+                // Wake up in time to send the layer changes that clients want delayed.
+                let next_deadline = clients
+                    .lock()
+                    .values()
+                    .filter_map(Client::pending_deadline)
+                    .min();
+                let event = match next_deadline {
+                    Some(deadline) => match rx
+                        .recv_timeout(deadline.saturating_duration_since(time::Instant::now()))
+                    {
+                        Ok(event) => Some(event),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => panic!("channel disconnected"),
+                    },
+                    None => match rx.recv() {
+                        Ok(event) => Some(event),
+                        Err(_) => panic!("channel disconnected"),
+                    },
+                };
+                let mut notification = event.as_ref().map(EncodedMessage::new);
+                let now = time::Instant::now();
+                let mut clients = clients.lock();
+                let mut stale_clients = vec![];
+                for (id, client) in &mut *clients {
+                    let mut res = client.send_pending_layer(now);
+                    if let Some(notification) = &mut notification {
+                        res = res.and_then(|_| client.notify(notification));
                     }
-                    Ok(event) => {
-                        let notification = event.as_bytes();
-                        let mut clients = clients.lock();
-                        let mut stale_clients = vec![];
-                        for (id, client) in &mut *clients {
-                            match client.write(&notification) {
-                                Ok(_) => {
-                                    log::debug!("layer change notification sent");
-                                }
-                                Err(_) => {
-                                    // the client is no longer connected, let's remove them
-                                    stale_clients.push(id.clone());
-                                }
-                            }
+                    match res {
+                        Ok(_) => {
+                            log::debug!("notification sent");
                         }
-
-                        for id in &stale_clients {
-                            log::warn!("removing disconnected tcp client: {id}");
-                            clients.remove(id);
+                        Err(_) => {
+                            // the client is no longer connected, let's remove them
+                            stale_clients.push(id.clone());
                         }
                     }
                 }
+
+                for id in &stale_clients {
+                    log::warn!("removing disconnected tcp client: {id}");
+                    clients.remove(id);
+                }
+            }
+        });
+    }
+
+    /// Starts a new thread that sends the mouse position to TCP clients whenever it has changed,
+    /// checking at the interval configured by `tcp-mouse-position-interval`.
+    #[cfg(not(target_os = "linux"))]
+    pub fn start_mouse_position_loop(kanata: Arc<Mutex<Self>>, tx: Sender<ServerMessage>) {
+        std::thread::spawn(move || {
+            let mut last_position = None;
+            loop {
+                let interval = kanata.lock().mouse_position_interval;
+                if interval == 0 {
+                    // Check again later in case live reload enables it.
+                    std::thread::sleep(time::Duration::from_secs(1));
+                    continue;
+                }
+                std::thread::sleep(time::Duration::from_millis(interval.into()));
+                let Some((x, y)) = mouse_position() else {
+                    log::warn!("tcp-mouse-position-interval is not supported on this platform");
+                    return;
+                };
+                if last_position == Some((x, y)) {
+                    continue;
+                }
+                last_position = Some((x, y));
+                if let Err(e) = tx.try_send(ServerMessage::MousePosition { x, y }) {
+                    log::warn!("could not send mouse position: {e:?}");
+                }
+            }
+        });
+    }
+
+    /// Starts a new thread that sends the estimated typing speed and accuracy to TCP clients
+    /// whenever they have changed, checking at the interval configured by
+    /// `tcp-typing-stats-interval`.
+    pub fn start_typing_stats_loop(kanata: Arc<Mutex<Self>>, tx: Sender<ServerMessage>) {
+        std::thread::spawn(move || {
+            let mut last_stats = None;
+            loop {
+                let interval = kanata.lock().typing_stats_interval;
+                if interval == 0 {
+                    // Check again later in case live reload enables it.
+                    std::thread::sleep(time::Duration::from_secs(1));
+                    continue;
+                }
+                std::thread::sleep(time::Duration::from_millis(interval.into()));
+                let stats = kanata.lock().typing_stats.estimate(time::Instant::now());
+                if last_stats == Some(stats) {
+                    continue;
+                }
+                last_stats = Some(stats);
+                let (wpm, accuracy) = stats;
+                if let Err(e) = tx.try_send(ServerMessage::TypingStats { wpm, accuracy }) {
+                    log::warn!("could not send typing stats: {e:?}");
+                }
             }
         });
     }
@@ -1628,11 +3176,17 @@ impl Kanata {
     pub fn start_processing_loop(
         kanata: Arc<Mutex<Self>>,
         rx: Receiver<KeyEvent>,
+        wake_up_tx: Sender<KeyEvent>,
         tx: Option<Sender<ServerMessage>>,
         nodelay: bool,
     ) {
         info!("entering the processing loop");
+        worker::set_wake_up_channel(wake_up_tx);
         std::thread::spawn(move || {
+            scheduling::pin_current_thread(
+                "processing loop",
+                &kanata.lock().scheduling.processing_loop_cpus,
+            );
             if !nodelay {
                 info!("Init: catching only releases and sending immediately");
                 for _ in 0..500 {
@@ -1649,6 +3203,7 @@ impl Kanata {
             let mut ms_elapsed = 0;
 
             info!("Starting kanata proper");
+            kanata.lock().tap_on_startup();
             let err = loop {
                 let can_block = {
                     let mut k = kanata.lock();
@@ -1656,8 +3211,9 @@ impl Kanata {
                     // Note: checking waiting_for_idle can not be part of the computation for
                     // is_idle() since incrementing ticks_since_idle is dependent on the return
                     // value of is_idle().
-                    let counting_idle_ticks =
-                        !k.waiting_for_idle.is_empty() || k.live_reload_requested;
+                    let counting_idle_ticks = !k.waiting_for_idle.is_empty()
+                        || k.live_reload_requested
+                        || !k.pending_key_actions.is_empty();
                     if !is_idle {
                         k.ticks_since_idle = 0;
                     } else if is_idle && counting_idle_ticks {
@@ -1670,7 +3226,8 @@ impl Kanata {
                 if can_block {
                     log::trace!("blocking on channel");
                     match rx.recv() {
-                        Ok(kev) => {
+                        #[allow(unused_mut)]
+                        Ok(mut kev) => {
                             let mut k = kanata.lock();
                             let now = time::Instant::now()
                                 .checked_sub(time::Duration::from_millis(1))
@@ -1694,6 +3251,13 @@ impl Kanata {
                                 // the states that might be stuck. A real use case might be to have
                                 // a fake key pressed for a long period of time, so make sure those
                                 // are not cleared.
+                                //
+                                // A shorter block might also mean that a UAC prompt or other
+                                // secure desktop was shown, which can similarly cause missed
+                                // releases. Ask the OS which keys are still held in that case.
+                                if (now - k.last_tick) > time::Duration::from_secs(1) {
+                                    k.reconcile_released_keys(&mut kev);
+                                }
                                 if (now - k.last_tick) > time::Duration::from_secs(60) {
                                     log::debug!(
                                     "clearing keyberon normal key states due to blocking for a while"
@@ -1723,6 +3287,9 @@ impl Kanata {
                             #[cfg(feature = "perf_logging")]
                             let start = std::time::Instant::now();
 
+                            if let Some(typing_rhythm) = &mut k.typing_rhythm {
+                                typing_rhythm.record(&kev, &tx);
+                            }
                             if let Err(e) = k.handle_input_event(&kev) {
                                 break e;
                             }
@@ -1758,6 +3325,9 @@ impl Kanata {
                             #[cfg(feature = "perf_logging")]
                             let start = std::time::Instant::now();
 
+                            if let Some(typing_rhythm) = &mut k.typing_rhythm {
+                                typing_rhythm.record(&kev, &tx);
+                            }
                             if let Err(e) = k.handle_input_event(&kev) {
                                 break e;
                             }
@@ -1811,8 +3381,10 @@ impl Kanata {
     }
 
     pub fn is_idle(&self) -> bool {
-        let pressed_keys_means_not_idle =
-            !self.waiting_for_idle.is_empty() || self.live_reload_requested;
+        let pressed_keys_means_not_idle = !self.waiting_for_idle.is_empty()
+            || self.live_reload_requested
+            || !self.pending_key_actions.is_empty()
+            || self.max_held_key_duration > 0;
         self.layout.b().queue.is_empty()
             && self.layout.b().waiting.is_none()
             && self.layout.b().last_press_tracker.tap_hold_timeout == 0
@@ -1821,10 +3393,19 @@ impl Kanata {
             && self.layout.b().tap_dance_eager.is_none()
             && self.layout.b().action_queue.is_empty()
             && self.sequence_state.is_none()
+            && self.sequence_repeats.is_none()
+            && self.sequence_replay.is_none()
             && self.scroll_state.is_none()
             && self.hscroll_state.is_none()
             && self.move_mouse_state_vertical.is_none()
             && self.move_mouse_state_horizontal.is_none()
+            && self.repeat_accel_state.is_none()
+            && self.axis_ramp_states.is_empty()
+            && self.text_typing.is_idle()
+            && self.output_delay.is_idle()
+            && self.min_press.is_idle()
+            && self.key_repeat.is_idle()
+            && !self.break_reminder.on_break()
             && self.dynamic_macro_replay_state.is_none()
             && self.caps_word.is_none()
             && !self.layout.b().states.iter().any(|s| {
@@ -1834,24 +3415,103 @@ impl Kanata {
     }
 }
 
-fn set_altgr_behaviour(_cfg: &cfg::Cfg) -> Result<()> {
+/// Release every modifier, then press the modifiers in `held_keys`.
+fn resync_mods(
+    output_delay: &mut OutputDelay,
+    kbd_out: &mut KbdOut,
+    held_keys: &[KeyCode],
+) -> Result<()> {
+    const MODS: [KeyCode; 8] = [
+        KeyCode::LShift,
+        KeyCode::RShift,
+        KeyCode::LGui,
+        KeyCode::RGui,
+        KeyCode::LCtrl,
+        KeyCode::RCtrl,
+        KeyCode::LAlt,
+        KeyCode::RAlt,
+    ];
+    for kc in MODS {
+        output_delay.release_key(kbd_out, kc.into())?;
+    }
+    for kc in MODS.iter().filter(|kc| held_keys.contains(kc)) {
+        log::debug!("re-pressing {kc:?}");
+        output_delay.press_key(kbd_out, kc.into())?;
+    }
+    Ok(())
+}
+
+fn set_altgr_behaviour(_cfg: &HashMap<String, String>) -> Result<()> {
     #[cfg(target_os = "windows")]
     set_win_altgr_behaviour(_cfg)?;
     Ok(())
 }
 
 #[cfg(feature = "cmd")]
-fn run_multi_cmd(cmds: Vec<Vec<String>>) {
-    std::thread::spawn(move || {
-        for cmd in cmds {
-            if let Err(e) = run_cmd_in_thread(cmd).join() {
-                log::error!("problem joining thread {:?}", e);
+/// Runs the commands in order on a worker. For each command that has completion actions,
+/// the action matching the command's result is sent back to the processing loop.
+fn run_multi_cmd(cmds: Vec<CmdJob>, callback_tx: Sender<CmdCompletion>) {
+    run_on_worker(move || {
+        for job in cmds {
+            let succeeded = run_cmd(&job.cmd);
+            if job.has_callbacks() {
+                let callback = match succeeded {
+                    true => job.on_success,
+                    false => job.on_failure,
+                };
+                if send_completion(&callback_tx, CmdCompletion::Callback(callback)).is_err() {
+                    log::warn!("could not send cmd result: processing loop is gone");
+                }
             }
         }
     });
 }
 
-fn apply_mouse_distance_modifiers(initial_distance: u16, mods: &Vec<u16>) -> u16 {
+fn parse_mouse_position_interval(cfg_items: &HashMap<String, String>) -> Result<u16> {
+    let interval = cfg_items
+        .get("tcp-mouse-position-interval")
+        .map(|s| s.parse::<u16>())
+        .unwrap_or(Ok(0))
+        .map_err(|e| anyhow!("tcp-mouse-position-interval must be 0-65535: {e}"))?;
+    #[cfg(target_os = "linux")]
+    if interval != 0 {
+        bail!(
+            "tcp-mouse-position-interval is not supported on Linux, \
+             which has no way to query the pointer position independently of the display server"
+        );
+    }
+    Ok(interval)
+}
+
+fn parse_typing_stats_interval(cfg_items: &HashMap<String, String>) -> Result<u16> {
+    cfg_items
+        .get("tcp-typing-stats-interval")
+        .map(|s| s.parse::<u16>())
+        .unwrap_or(Ok(0))
+        .map_err(|e| anyhow!("tcp-typing-stats-interval must be 0-65535: {e}"))
+}
+
+fn parse_max_held_key_duration(cfg_items: &HashMap<String, String>) -> Result<u32> {
+    cfg_items
+        .get("max-held-key-duration")
+        .map(|s| s.parse::<u16>())
+        .unwrap_or(Ok(0))
+        .map(|secs| u32::from(secs) * 1000)
+        .map_err(|e| anyhow!("max-held-key-duration must be 0-65535: {e}"))
+}
+
+fn parse_macro_delay_percent(cfg_items: &HashMap<String, String>) -> Result<u16> {
+    cfg_items
+        .get("macro-delay-percent")
+        .map(|s| s.parse::<u16>())
+        .unwrap_or(Ok(100))
+        .map_err(|e| anyhow!("macro-delay-percent must be 0-65535: {e}"))
+}
+
+fn apply_mouse_distance_modifiers<'a>(
+    initial_distance: u16,
+    mods: impl IntoIterator<Item = &'a u16>,
+) -> u16 {
     let mut scaled_distance = initial_distance;
     for &modifier in mods {
         scaled_distance = u16::max(
@@ -1866,6 +3526,102 @@ fn apply_mouse_distance_modifiers(initial_distance: u16, mods: &Vec<u16>) -> u16
     scaled_distance
 }
 
+/// Returns the bitmask where bit `n` is set if input device `n` has a key held. Devices with
+/// indices that do not fit in the mask are never reported as held.
+fn held_input_devices_mask(held_input_keys: &[(u16, OsCode)]) -> u64 {
+    held_input_keys
+        .iter()
+        .filter_map(|(device, _)| 1u64.checked_shl(u32::from(*device)))
+        .fold(0, |mask, bit| mask | bit)
+}
+
+#[test]
+fn held_input_devices() {
+    assert_eq!(held_input_devices_mask(&[]), 0);
+    assert_eq!(
+        held_input_devices_mask(&[
+            (2, OsCode::KEY_F13),
+            (0, OsCode::KEY_A),
+            (2, OsCode::KEY_F14),
+            (64, OsCode::KEY_B),
+        ]),
+        0b101
+    );
+}
+
+#[test]
+fn sequence_key_names() {
+    let a = u16::from(OsCode::KEY_A);
+    assert_eq!(sequence_key_name(a), "A");
+    assert_eq!(
+        sequence_key_name(
+            a | mod_mask_for_keycode(KeyCode::LShift) | mod_mask_for_keycode(KeyCode::RAlt)
+        ),
+        "S-AG-A"
+    );
+}
+
+#[test]
+fn repeat_accel_interval() {
+    let mut state = RepeatAccelState {
+        coord: Coord { x: 1, y: 0 },
+        start_interval: 400,
+        end_interval: 50,
+        accel_time: 1000,
+        ticks_held: 0,
+        ticks_until_repeat: 0,
+    };
+    assert_eq!(state.interval(), 400);
+    state.ticks_held = 500;
+    assert_eq!(state.interval(), 225);
+    state.ticks_held = 1000;
+    assert_eq!(state.interval(), 50);
+    state.ticks_held = u16::MAX;
+    assert_eq!(state.interval(), 50);
+}
+
+#[test]
+fn axis_ramp_tick() {
+    let mut state = AxisRampState::new(GamepadAxis::LeftY);
+    state.ramp_up = 4;
+    state.ramp_down = 2;
+    state.negative_held = true;
+    assert_eq!(state.tick(), -8192);
+    for _ in 0..3 {
+        state.tick();
+    }
+    assert_eq!(state.tick(), -32768);
+
+    // The opposite direction first returns to rest at the ramp-down rate, then ramps up.
+    state.negative_held = false;
+    state.positive_held = true;
+    assert_eq!(state.tick(), -16384);
+    assert_eq!(state.tick(), 0);
+    assert_eq!(state.tick(), 8192);
+
+    // Both directions held cancel out.
+    state.negative_held = true;
+    assert_eq!(state.tick(), 0);
+    state.negative_held = false;
+    state.positive_held = false;
+    assert!(state.is_at_rest());
+
+    let mut state = AxisRampState::new(GamepadAxis::RightTrigger);
+    state.positive_held = true;
+    assert_eq!(state.tick(), 255);
+
+    let mut state = AxisRampState::new(GamepadAxis::LeftX);
+    state.ramp_up = 4;
+    state.curve = AxisCurve::EaseIn;
+    state.positive_held = true;
+    assert_eq!(state.tick(), 2048);
+    assert_eq!(state.tick(), 8192);
+    state.curve = AxisCurve::EaseOut;
+    assert_eq!(state.tick(), 30719);
+    state.curve = AxisCurve::EaseInOut;
+    assert_eq!(state.tick(), 32767);
+}
+
 #[test]
 fn apply_speed_modifiers() {
     assert_eq!(apply_mouse_distance_modifiers(15, &vec![]), 15);
@@ -1948,17 +3704,139 @@ fn update_kbd_out(_cfg: &HashMap<String, String>, _kbd_out: &KbdOut) -> Result<(
                 })
                 .unwrap_or(Ok(_kbd_out.unicode_u_code.get()))?,
         );
+        _kbd_out
+            .update_mirror(_cfg.get("linux-output-mirror-name").map(String::as_str))
+            .map_err(|e| anyhow!("failed to create linux-output-mirror-name device: {e}"))?;
+        _kbd_out
+            .update_gamepad(
+                _cfg.get("linux-output-gamepad")
+                    .map(|s| TRUE_VALUES.contains(&s.to_lowercase().as_str()))
+                    .unwrap_or_default(),
+            )
+            .map_err(|e| anyhow!("failed to create linux-output-gamepad device: {e}"))?;
+    }
+    Ok(())
+}
+
+/// Taps the fake key of a completed sequence `count` times, after erasing the typed keys in
+/// `visible-backspaced` mode.
+/// Taps the key of a sequence item along with the modifiers of its modifier bits.
+fn tap_sequence_item(
+    item: u16,
+    output_delay: &mut OutputDelay,
+    kbd_out: &mut KbdOut,
+) -> Result<()> {
+    let Some(osc) = OsCode::from_u16(item & MASK_KEYCODES) else {
+        return Ok(());
+    };
+    let mods: Vec<OsCode> = [
+        KeyCode::LShift,
+        KeyCode::LCtrl,
+        KeyCode::LAlt,
+        KeyCode::RAlt,
+        KeyCode::LGui,
+    ]
+    .into_iter()
+    .filter(|kc| item & mod_mask_for_keycode(*kc) != 0)
+    .map(OsCode::from)
+    .filter(|m| *m != osc)
+    .collect();
+    for m in mods.iter() {
+        output_delay.press_key(kbd_out, *m)?;
+    }
+    output_delay.press_key(kbd_out, osc)?;
+    output_delay.release_key(kbd_out, osc)?;
+    for m in mods.iter().rev() {
+        output_delay.release_key(kbd_out, *m)?;
+    }
+    Ok(())
+}
+
+fn complete_sequence(
+    state: &SequenceState,
+    (i, j): (u8, u16),
+    count: u16,
+    layout: &mut BorrowedKLayout,
+    output_delay: &mut OutputDelay,
+    kbd_out: &mut KbdOut,
+    sequence_repeats: &mut Option<((u8, u16), u16)>,
+) -> Result<()> {
+    match state.sequence_input_mode {
+        SequenceInputMode::HiddenSuppressed | SequenceInputMode::HiddenDelayType => {}
+        SequenceInputMode::VisibleBackspaced => {
+            // Release all keys since they might modify the behaviour of backspace into an
+            // undesirable behaviour, for example deleting more characters than it should.
+            layout.states.retain(|s| match s {
+                State::NormalKey { keycode, .. } => {
+                    // Ignore the error, ugly to return it from retain, and this is very unlikely
+                    // to happen anyway.
+                    let _ = output_delay.release_key(kbd_out, keycode.into());
+                    false
+                }
+                _ => true,
+            });
+            // Only erase the keys that typed a character, since e.g. modifiers and arrow keys do
+            // not leave anything to erase.
+            for _ in 0..state.visible_chars {
+                output_delay.press_key(kbd_out, OsCode::KEY_BACKSPACE)?;
+                output_delay.release_key(kbd_out, OsCode::KEY_BACKSPACE)?;
+            }
+        }
+    }
+
+    // Make sure to unpress any keys that were pressed as part of the sequence so that the
+    // keyberon internal sequence mechanism can do press+unpress of them.
+    for k in state.sequence.iter() {
+        let kc = KeyCode::from(OsCode::from(*k & MASK_KEYCODES));
+        layout.states.retain(|s| match s {
+            State::NormalKey { keycode, .. } => kc != *keycode,
+            _ => true,
+        });
+    }
+    if count > 0 {
+        layout.event(Event::Press(i, j));
+        layout.event(Event::Release(i, j));
+    }
+    if count > 1 {
+        *sequence_repeats = Some(((i, j), count - 1));
     }
     Ok(())
 }
 
-fn cancel_sequence(state: &SequenceState, kbd_out: &mut KbdOut) -> Result<()> {
+/// Returns the name of a key typed in sequence mode, with the prefixes of the modifiers that were
+/// held, e.g. `S-A`.
+fn sequence_key_name(key: u16) -> String {
+    let mods = [
+        (KeyCode::LShift, "S-"),
+        (KeyCode::LCtrl, "C-"),
+        (KeyCode::LAlt, "A-"),
+        (KeyCode::RAlt, "AG-"),
+        (KeyCode::LGui, "M-"),
+    ];
+    let mut name = String::new();
+    for (kc, prefix) in mods {
+        if key & mod_mask_for_keycode(kc) != 0 {
+            name.push_str(prefix);
+        }
+    }
+    name.push_str(&format!(
+        "{:?}",
+        KeyCode::from(OsCode::from(key & MASK_KEYCODES))
+    ));
+    name
+}
+
+fn cancel_sequence(
+    state: &SequenceState,
+    output_delay: &mut OutputDelay,
+    kbd_out: &mut KbdOut,
+) -> Result<()> {
     match state.sequence_input_mode {
         SequenceInputMode::HiddenDelayType => {
             for code in state.sequence.iter().copied() {
                 if let Some(osc) = OsCode::from_u16(code) {
-                    kbd_out.press_key(osc)?;
-                    kbd_out.release_key(osc)?;
+                    output_delay.press_key(kbd_out, osc)?;
+                    output_delay.release_key(kbd_out, osc)?;
                 }
             }
         }
@@ -2000,3 +3878,12 @@ fn states_has_coord<T>(states: &[State<T>], x: u8, y: u16) -> bool {
         _ => false,
     })
 }
+
+/// Returns a summary of the keys that the layer changes, one `key: action` line per key.
+fn help_text(info: &LayerInfo) -> String {
+    let mut text = format!("layer {}\n", info.name);
+    for (key, action) in info.bindings.iter() {
+        text.push_str(&format!("{key}: {action}\n"));
+    }
+    text
+}
@@ -0,0 +1,84 @@
+//! Desktop notifications for the `notify` action. The notification is shown by the notifier
+//! program of the platform so that no notification library is needed.
+
+use std::process::Command;
+
+use super::worker::run_on_worker;
+
+/// Shows the notification in the background.
+pub(super) fn notify(title: &str, body: &str) {
+    let mut cmd = notify_cmd(title, body);
+    run_on_worker(move || match cmd.output() {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => log::warn!(
+            "notification program failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ),
+        Err(e) => log::warn!(
+            "could not run notification program {:?}: {e}",
+            cmd.get_program()
+        ),
+    });
+}
+
+/// `notify-send` from libnotify, which sends the notification over D-Bus.
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn notify_cmd(title: &str, body: &str) -> Command {
+    let mut cmd = Command::new("notify-send");
+    cmd.args(["--app-name=kanata", "--", title, body]);
+    cmd
+}
+
+/// A toast notification shown through PowerShell. The text is passed through environment
+/// variables so that it is never interpreted as script.
+#[cfg(target_os = "windows")]
+fn notify_cmd(title: &str, body: &str) -> Command {
+    const SCRIPT: &str = r#"
+[Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, ContentType = WindowsRuntime] > $null
+$template = [Windows.UI.Notifications.ToastNotificationManager]::GetTemplateContent([Windows.UI.Notifications.ToastTemplateType]::ToastText02)
+$text = $template.GetElementsByTagName('text')
+$text.Item(0).AppendChild($template.CreateTextNode($env:KANATA_NOTIFY_TITLE)) > $null
+$text.Item(1).AppendChild($template.CreateTextNode($env:KANATA_NOTIFY_BODY)) > $null
+$appId = '{1AC14E77-02E7-4E5D-B744-2EB1AE5198B7}\WindowsPowerShell\v1.0\powershell.exe'
+[Windows.UI.Notifications.ToastNotificationManager]::CreateToastNotifier($appId).Show([Windows.UI.Notifications.ToastNotification]::new($template))
+"#;
+    let mut cmd = Command::new("powershell");
+    cmd.args(["-NoProfile", "-NonInteractive", "-Command", SCRIPT])
+        .env("KANATA_NOTIFY_TITLE", title)
+        .env("KANATA_NOTIFY_BODY", body);
+    {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+        cmd.creation_flags(CREATE_NO_WINDOW);
+    }
+    cmd
+}
+
+/// `osascript`, with the text passed as arguments so that it is never interpreted as script.
+#[cfg(target_os = "macos")]
+fn notify_cmd(title: &str, body: &str) -> Command {
+    let mut cmd = Command::new("osascript");
+    cmd.args([
+        "-e",
+        "on run argv",
+        "-e",
+        "display notification (item 2 of argv) with title (item 1 of argv)",
+        "-e",
+        "end run",
+        title,
+        body,
+    ]);
+    cmd
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+#[test]
+fn notify_send_args() {
+    let cmd = notify_cmd("gaming", "layer on");
+    assert_eq!(cmd.get_program(), "notify-send");
+    assert_eq!(
+        cmd.get_args().collect::<Vec<_>>(),
+        ["--app-name=kanata", "--", "gaming", "layer on"]
+    );
+}
@@ -0,0 +1,103 @@
+//! Sending of OSC (Open Sound Control) messages over UDP for the `osc` action.
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+use kanata_parser::custom_action::OscArg;
+
+use super::HashMap;
+
+pub(super) struct OscOut {
+    target_cfg: String,
+    target: SocketAddr,
+    socket: UdpSocket,
+}
+
+impl OscOut {
+    /// Resolves the target of `osc-target`, reusing `current` if the target is unchanged.
+    pub(super) fn from_cfg(
+        cfg_items: &HashMap<String, String>,
+        current: Option<OscOut>,
+    ) -> io::Result<Option<OscOut>> {
+        let Some(target_cfg) = cfg_items.get("osc-target").map(|t| t.trim_matches('"')) else {
+            return Ok(None);
+        };
+        if let Some(current) = current.filter(|c| c.target_cfg == target_cfg) {
+            return Ok(Some(current));
+        }
+        let target = target_cfg
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the host has no addresses"))?;
+        let bind_addr: SocketAddr = match target {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(bind_addr)?;
+        log::info!("sending OSC messages to {target}");
+        Ok(Some(OscOut {
+            target_cfg: target_cfg.to_owned(),
+            target,
+            socket,
+        }))
+    }
+
+    pub(super) fn send(&self, address: &str, args: &[OscArg]) -> io::Result<()> {
+        log::debug!("send OSC message {address} {args:?}");
+        self.socket
+            .send_to(&encode_message(address, args), self.target)
+            .map(|_| ())
+    }
+}
+
+/// Encodes an OSC message: the address, the type tag string, then the arguments. Strings are
+/// null-terminated and padded to a multiple of 4 bytes, numbers are big-endian.
+fn encode_message(address: &str, args: &[OscArg]) -> Vec<u8> {
+    let mut msg = vec![];
+    push_string(&mut msg, address);
+    let type_tags: String = std::iter::once(',')
+        .chain(args.iter().map(|arg| match arg {
+            OscArg::Int(_) => 'i',
+            OscArg::Float(_) => 'f',
+            OscArg::String(_) => 's',
+        }))
+        .collect();
+    push_string(&mut msg, &type_tags);
+    for arg in args {
+        match arg {
+            OscArg::Int(i) => msg.extend_from_slice(&i.to_be_bytes()),
+            OscArg::Float(bits) => msg.extend_from_slice(&bits.to_be_bytes()),
+            OscArg::String(s) => push_string(&mut msg, s),
+        }
+    }
+    msg
+}
+
+fn push_string(msg: &mut Vec<u8>, s: &str) {
+    msg.extend_from_slice(s.as_bytes());
+    let padding = 4 - s.len() % 4;
+    msg.resize(msg.len() + padding, 0);
+}
+
+#[test]
+fn osc_encoding() {
+    assert_eq!(encode_message("/a", &[]), b"/a\0\0,\0\0\0");
+    assert_eq!(
+        encode_message(
+            "/obs",
+            &[
+                OscArg::Int(1),
+                OscArg::Float(0.5f32.to_bits()),
+                OscArg::String("hi".into()),
+            ]
+        ),
+        [
+            b"/obs\0\0\0\0".as_slice(),
+            b",ifs\0\0\0\0",
+            &[0, 0, 0, 1],
+            &[0x3f, 0, 0, 0],
+            b"hi\0\0",
+        ]
+        .concat()
+    );
+}
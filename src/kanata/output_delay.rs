@@ -0,0 +1,199 @@
+//! The `output-delay` option. Output, e.g. key presses and releases, unicode and mouse events, is
+//! held back until a fixed time after the input event that caused it, so the total latency stays
+//! the same instead of varying with processing time and tick alignment.
+//!
+//! All keyboard and mouse output of the processing loop goes through `OutputDelay` so that it is
+//! sent in the order it was produced.
+
+use anyhow::{anyhow, bail, Result};
+
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, Instant};
+
+use kanata_parser::keys::OsCode;
+
+use crate::oskbd::{KbdOut, KeyValue};
+
+use super::HashMap;
+
+/// Delays longer than this would be noticeable as lag rather than smoothing out jitter.
+const MAX_OUTPUT_DELAY_MS: u16 = 100;
+
+type WriteOutput = Box<dyn FnOnce(&mut KbdOut) -> io::Result<()> + Send>;
+
+/// Output that is waiting to be sent.
+enum DelayedOutput {
+    Key(OsCode, KeyValue),
+    /// Any other output, e.g. unicode or mouse events.
+    Other(WriteOutput),
+}
+
+pub(super) struct OutputDelay {
+    delay: Duration,
+    /// When the most recent input event was produced by its device.
+    input_time: Instant,
+    /// Output waiting to be sent and the time at which to send it, in the order it was produced.
+    queue: VecDeque<(Instant, DelayedOutput)>,
+}
+
+impl OutputDelay {
+    pub(super) fn from_cfg(cfg_items: &HashMap<String, String>) -> Result<Self> {
+        Ok(Self {
+            delay: parse_output_delay(cfg_items)?,
+            input_time: Instant::now(),
+            queue: VecDeque::new(),
+        })
+    }
+
+    /// Changes the delay on live reload. Output already waiting keeps its send time.
+    pub(super) fn update_cfg(&mut self, cfg_items: &HashMap<String, String>) -> Result<()> {
+        self.delay = parse_output_delay(cfg_items)?;
+        Ok(())
+    }
+
+    pub(super) fn is_enabled(&self) -> bool {
+        !self.delay.is_zero()
+    }
+
+    /// Sets the time of the most recent input, i.e. when the input device produced it.
+    pub(super) fn input_received(&mut self, now: Instant) {
+        self.input_time = now;
+    }
+
+    /// Sends the key right away if there is no delay. Otherwise the key is sent by `flush` once
+    /// the delay after the most recent input has elapsed.
+    pub(super) fn write_key(
+        &mut self,
+        kbd_out: &mut KbdOut,
+        key: OsCode,
+        value: KeyValue,
+    ) -> io::Result<()> {
+        if self.delay.is_zero() && self.queue.is_empty() {
+            return kbd_out.write_key(key, value);
+        }
+        self.queue
+            .push_back((self.input_time + self.delay, DelayedOutput::Key(key, value)));
+        Ok(())
+    }
+
+    pub(super) fn press_key(&mut self, kbd_out: &mut KbdOut, key: OsCode) -> io::Result<()> {
+        self.write_key(kbd_out, key, KeyValue::Press)
+    }
+
+    pub(super) fn release_key(&mut self, kbd_out: &mut KbdOut, key: OsCode) -> io::Result<()> {
+        self.write_key(kbd_out, key, KeyValue::Release)
+    }
+
+    /// Like `write_key`, for output other than a key, e.g. `|out| out.send_unicode(c)`.
+    pub(super) fn write(
+        &mut self,
+        kbd_out: &mut KbdOut,
+        output: impl FnOnce(&mut KbdOut) -> io::Result<()> + Send + 'static,
+    ) -> io::Result<()> {
+        if self.delay.is_zero() && self.queue.is_empty() {
+            return output(kbd_out);
+        }
+        self.queue.push_back((
+            self.input_time + self.delay,
+            DelayedOutput::Other(Box::new(output)),
+        ));
+        Ok(())
+    }
+
+    /// Sends the output whose time has come.
+    pub(super) fn flush(&mut self, kbd_out: &mut KbdOut, now: Instant) -> io::Result<()> {
+        while let Some(output) = self.pop_due(now) {
+            match output {
+                DelayedOutput::Key(key, value) => kbd_out.write_key(key, value)?,
+                DelayedOutput::Other(output) => output(kbd_out)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Output is sent in the order it was produced, so it is only due once all output before it
+    /// has been sent.
+    fn pop_due(&mut self, now: Instant) -> Option<DelayedOutput> {
+        match self.queue.front() {
+            Some((send_time, _)) if *send_time <= now => {
+                self.queue.pop_front().map(|(_, output)| output)
+            }
+            _ => None,
+        }
+    }
+
+    pub(super) fn is_idle(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+fn parse_output_delay(cfg_items: &HashMap<String, String>) -> Result<Duration> {
+    let ms = cfg_items
+        .get("output-delay")
+        .map(|s| s.parse::<u16>())
+        .unwrap_or(Ok(0))
+        .map_err(|e| anyhow!("output-delay must be 0-{MAX_OUTPUT_DELAY_MS}: {e}"))?;
+    if ms > MAX_OUTPUT_DELAY_MS {
+        bail!("output-delay must be 0-{MAX_OUTPUT_DELAY_MS}, got {ms}");
+    }
+    Ok(Duration::from_millis(ms.into()))
+}
+
+#[test]
+fn output_delay_sends_in_order_after_delay() {
+    let mut cfg_items = HashMap::default();
+    cfg_items.insert("output-delay".to_owned(), "10".to_owned());
+    let mut od = OutputDelay::from_cfg(&cfg_items).unwrap();
+    let start = Instant::now();
+    let ms = |n| start + Duration::from_millis(n);
+
+    let key = |key, value| DelayedOutput::Key(key, value);
+    let popped_key = |output: Option<DelayedOutput>| match output {
+        Some(DelayedOutput::Key(key, value)) => Some((key, value)),
+        Some(DelayedOutput::Other(_)) => panic!("expected a key"),
+        None => None,
+    };
+
+    od.input_received(start);
+    od.queue.push_back((
+        od.input_time + od.delay,
+        key(OsCode::KEY_A, KeyValue::Press),
+    ));
+    od.input_received(ms(3));
+    od.queue.push_back((
+        od.input_time + od.delay,
+        key(OsCode::KEY_A, KeyValue::Release),
+    ));
+    // Output produced later by a timer, long after its input, is sent as soon as possible but
+    // still after the output before it.
+    od.input_received(start);
+    od.queue.push_back((
+        od.input_time + od.delay,
+        DelayedOutput::Other(Box::new(|_| Ok(()))),
+    ));
+    od.queue.push_back((
+        od.input_time + od.delay,
+        key(OsCode::KEY_B, KeyValue::Press),
+    ));
+
+    assert!(od.pop_due(ms(9)).is_none());
+    assert_eq!(
+        popped_key(od.pop_due(ms(10))),
+        Some((OsCode::KEY_A, KeyValue::Press))
+    );
+    assert!(od.pop_due(ms(12)).is_none());
+    assert_eq!(
+        popped_key(od.pop_due(ms(13))),
+        Some((OsCode::KEY_A, KeyValue::Release))
+    );
+    assert!(matches!(od.pop_due(ms(13)), Some(DelayedOutput::Other(_))));
+    assert_eq!(
+        popped_key(od.pop_due(ms(13))),
+        Some((OsCode::KEY_B, KeyValue::Press))
+    );
+    assert!(od.is_idle());
+
+    cfg_items.insert("output-delay".to_owned(), "101".to_owned());
+    assert!(od.update_cfg(&cfg_items).is_err());
+}
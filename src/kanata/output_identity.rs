@@ -0,0 +1,108 @@
+//! The identity of the uinput output device from `linux-output-name`, `linux-output-id`,
+//! `linux-output-bus` and `linux-output-events`. Some programs and libinput quirks recognize
+//! devices by their name or ID, so these let kanata's output device match what they expect.
+
+use anyhow::{bail, Result};
+use evdev::BusType;
+
+use super::HashMap;
+use crate::oskbd::{OutputEvents, OutputIdentity};
+
+/// The kernel truncates longer names, `UINPUT_MAX_NAME_SIZE` minus the terminating zero.
+const MAX_NAME_LEN: usize = 79;
+
+pub(super) fn parse_output_identity(cfg_items: &HashMap<String, String>) -> Result<OutputIdentity> {
+    let mut identity = OutputIdentity::default();
+    if let Some(name) = cfg_items.get("linux-output-name") {
+        if name.is_empty() || name.len() > MAX_NAME_LEN {
+            bail!("linux-output-name must be 1-{MAX_NAME_LEN} bytes long, got \"{name}\"");
+        }
+        identity.name = name.clone();
+    }
+    if let Some(id) = cfg_items.get("linux-output-id") {
+        let parse_hex = |s: &str| match s.len() {
+            4 => u16::from_str_radix(s, 16).ok(),
+            _ => None,
+        };
+        match id
+            .split_once(':')
+            .map(|(v, p)| (parse_hex(v), parse_hex(p)))
+        {
+            Some((Some(vendor), Some(product))) => {
+                identity.vendor = vendor;
+                identity.product = product;
+            }
+            _ => bail!(
+                "linux-output-id must be a vendor and product ID of four hexadecimal digits each, \
+                separated by a colon, e.g. 046d:c52b. Got {id}"
+            ),
+        }
+    }
+    if let Some(bus) = cfg_items.get("linux-output-bus") {
+        identity.bus = match bus.as_str() {
+            "usb" => BusType::BUS_USB,
+            "bluetooth" => BusType::BUS_BLUETOOTH,
+            "i8042" => BusType::BUS_I8042,
+            "virtual" => BusType::BUS_VIRTUAL,
+            _ => bail!(
+                "Invalid value for linux-output-bus: {bus}. \
+                Expected one of: usb, bluetooth, i8042, virtual"
+            ),
+        };
+    }
+    if let Some(events) = cfg_items.get("linux-output-events") {
+        identity.events = OutputEvents {
+            keys: false,
+            mouse_buttons: false,
+            mouse_motion: false,
+            mouse_wheel: false,
+        };
+        for event in events.split(',').map(str::trim) {
+            match event {
+                "keys" => identity.events.keys = true,
+                "mouse-buttons" => identity.events.mouse_buttons = true,
+                "mouse-motion" => identity.events.mouse_motion = true,
+                "mouse-wheel" => identity.events.mouse_wheel = true,
+                _ => bail!(
+                    "Invalid event kind in linux-output-events: {event}. \
+                    Expected a comma-separated list of: keys, mouse-buttons, mouse-motion, \
+                    mouse-wheel"
+                ),
+            }
+        }
+    }
+    Ok(identity)
+}
+
+#[test]
+fn parse_output_identity_cfg() {
+    let mut cfg_items = HashMap::default();
+    assert_eq!(
+        parse_output_identity(&cfg_items).unwrap(),
+        OutputIdentity::default()
+    );
+    cfg_items.insert("linux-output-name".into(), "Logitech USB Receiver".into());
+    cfg_items.insert("linux-output-id".into(), "046d:C52B".into());
+    cfg_items.insert("linux-output-bus".into(), "bluetooth".into());
+    cfg_items.insert("linux-output-events".into(), "keys, mouse-wheel".into());
+    assert_eq!(
+        parse_output_identity(&cfg_items).unwrap(),
+        OutputIdentity {
+            name: "Logitech USB Receiver".into(),
+            vendor: 0x046d,
+            product: 0xc52b,
+            bus: BusType::BUS_BLUETOOTH,
+            events: OutputEvents {
+                keys: true,
+                mouse_buttons: false,
+                mouse_motion: false,
+                mouse_wheel: true,
+            },
+        }
+    );
+    cfg_items.insert("linux-output-id".into(), "46d:c52b".into());
+    assert!(parse_output_identity(&cfg_items).is_err());
+    cfg_items.insert("linux-output-id".into(), "046d:c52b".into());
+    cfg_items.insert("linux-output-events".into(), "keys,touch".into());
+    assert!(parse_output_identity(&cfg_items).is_err());
+}
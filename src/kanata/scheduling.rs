@@ -0,0 +1,190 @@
+//! Process priority and CPU affinity from `process-priority`, `event-loop-cpus` and
+//! `processing-loop-cpus`. These are applied once at startup. Failing to apply them, e.g. for lack
+//! of privileges, only logs a warning since kanata works without them.
+
+use anyhow::{bail, Result};
+
+use super::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ProcessPriority {
+    Normal,
+    High,
+    Realtime,
+}
+
+/// Scheduling settings that are applied at startup.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(super) struct SchedulingCfg {
+    /// None keeps the default priority of the platform.
+    priority: Option<ProcessPriority>,
+    /// CPUs to pin the event loop thread to. Empty means any CPU.
+    pub(super) event_loop_cpus: Vec<usize>,
+    /// CPUs to pin the processing loop thread to. Empty means any CPU.
+    pub(super) processing_loop_cpus: Vec<usize>,
+}
+
+impl SchedulingCfg {
+    pub(super) fn from_cfg(cfg_items: &HashMap<String, String>) -> Result<Self> {
+        let priority = match cfg_items.get("process-priority").map(String::as_str) {
+            None => None,
+            Some("normal") => Some(ProcessPriority::Normal),
+            Some("high") => Some(ProcessPriority::High),
+            Some("realtime") => Some(ProcessPriority::Realtime),
+            Some(p) => bail!(
+                "Invalid value for process-priority: {p}. Expected one of: normal, high, realtime"
+            ),
+        };
+        Ok(Self {
+            priority,
+            event_loop_cpus: parse_cpus(cfg_items, "event-loop-cpus")?,
+            processing_loop_cpus: parse_cpus(cfg_items, "processing-loop-cpus")?,
+        })
+    }
+
+    /// Sets the priority of the process. Threads that are started afterwards inherit it. Windows
+    /// defaults to a high priority, other platforms to the priority kanata was started with.
+    pub(super) fn apply_priority(&self) {
+        #[cfg(target_os = "windows")]
+        let priority = self.priority.unwrap_or(ProcessPriority::High);
+        #[cfg(not(target_os = "windows"))]
+        let Some(priority) = self.priority
+        else {
+            return;
+        };
+        log::info!("setting process priority to {priority:?}");
+        if let Err(e) = set_priority(priority) {
+            log::warn!("could not set process priority to {priority:?}: {e}");
+        }
+    }
+}
+
+/// Parses a comma-separated list of CPU numbers, e.g. `2,3`.
+fn parse_cpus(cfg_items: &HashMap<String, String>, key: &str) -> Result<Vec<usize>> {
+    let Some(value) = cfg_items.get(key) else {
+        return Ok(vec![]);
+    };
+    value
+        .split(',')
+        .map(|cpu| match cpu.trim().parse::<usize>() {
+            Ok(cpu) if cpu < MAX_CPUS => Ok(cpu),
+            _ => bail!(
+                "{key} must be CPU numbers 0-{} separated by commas, e.g. 2,3. Got {value}",
+                MAX_CPUS - 1
+            ),
+        })
+        .collect()
+}
+
+/// The CPUs that can be selected, limited by the affinity mask on Windows.
+#[cfg(target_os = "windows")]
+const MAX_CPUS: usize = usize::BITS as usize;
+#[cfg(not(target_os = "windows"))]
+const MAX_CPUS: usize = 1024;
+
+/// Pins the calling thread to the CPUs, if any are given.
+pub(super) fn pin_current_thread(thread_name: &str, cpus: &[usize]) {
+    if cpus.is_empty() {
+        return;
+    }
+    log::info!("pinning the {thread_name} to CPUs {cpus:?}");
+    if let Err(e) = set_affinity(cpus) {
+        log::warn!("could not pin the {thread_name} to CPUs {cpus:?}: {e}");
+    }
+}
+
+/// `high` lowers the niceness and `realtime` uses the `SCHED_FIFO` policy, which both need
+/// `CAP_SYS_NICE` or a matching rlimit.
+#[cfg(target_os = "linux")]
+fn set_priority(priority: ProcessPriority) -> std::io::Result<()> {
+    use nix::libc;
+    // SAFETY: the calls only change the scheduling of the calling thread.
+    let ret = unsafe {
+        match priority {
+            ProcessPriority::Normal => libc::setpriority(libc::PRIO_PROCESS, 0, 0),
+            ProcessPriority::High => libc::setpriority(libc::PRIO_PROCESS, 0, -10),
+            ProcessPriority::Realtime => libc::sched_setscheduler(
+                0,
+                libc::SCHED_FIFO,
+                &libc::sched_param { sched_priority: 50 },
+            ),
+        }
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_affinity(cpus: &[usize]) -> nix::Result<()> {
+    use nix::sched::{sched_setaffinity, CpuSet};
+    let mut cpu_set = CpuSet::new();
+    for cpu in cpus {
+        cpu_set.set(*cpu)?;
+    }
+    // Pid 0 is the calling thread.
+    sched_setaffinity(nix::unistd::Pid::from_raw(0), &cpu_set)
+}
+
+/// `realtime` needs administrator rights, without which Windows uses `high` instead.
+#[cfg(target_os = "windows")]
+fn set_priority(priority: ProcessPriority) -> std::io::Result<()> {
+    use winapi::um::processthreadsapi::{GetCurrentProcess, SetPriorityClass};
+    use winapi::um::winbase::*;
+    let class = match priority {
+        ProcessPriority::Normal => NORMAL_PRIORITY_CLASS,
+        ProcessPriority::High => HIGH_PRIORITY_CLASS,
+        ProcessPriority::Realtime => REALTIME_PRIORITY_CLASS,
+    };
+    match unsafe { SetPriorityClass(GetCurrentProcess(), class) } {
+        0 => Err(std::io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn set_affinity(cpus: &[usize]) -> std::io::Result<()> {
+    use winapi::um::processthreadsapi::GetCurrentThread;
+    use winapi::um::winbase::SetThreadAffinityMask;
+    let mask = cpus.iter().fold(0usize, |mask, cpu| mask | 1 << cpu);
+    match unsafe { SetThreadAffinityMask(GetCurrentThread(), mask) } {
+        0 => Err(std::io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn set_priority(_priority: ProcessPriority) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn set_affinity(_cpus: &[usize]) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[test]
+fn parse_scheduling_cfg() {
+    let mut cfg_items = HashMap::default();
+    assert_eq!(
+        SchedulingCfg::from_cfg(&cfg_items).unwrap(),
+        SchedulingCfg::default()
+    );
+    cfg_items.insert("process-priority".into(), "realtime".into());
+    cfg_items.insert("event-loop-cpus".into(), "2, 3".into());
+    cfg_items.insert("processing-loop-cpus".into(), "1".into());
+    assert_eq!(
+        SchedulingCfg::from_cfg(&cfg_items).unwrap(),
+        SchedulingCfg {
+            priority: Some(ProcessPriority::Realtime),
+            event_loop_cpus: vec![2, 3],
+            processing_loop_cpus: vec![1],
+        }
+    );
+    cfg_items.insert("processing-loop-cpus".into(), "1,x".into());
+    assert!(SchedulingCfg::from_cfg(&cfg_items).is_err());
+    cfg_items.insert("processing-loop-cpus".into(), "1".into());
+    cfg_items.insert("process-priority".into(), "max".into());
+    assert!(SchedulingCfg::from_cfg(&cfg_items).is_err());
+}
@@ -0,0 +1,206 @@
+//! Deduplication of Linux scroll events. The kernel reports one wheel movement of a hi-res mouse
+//! as both a hi-res event and, once a full notch has accumulated, a lo-res event. Only one of them
+//! must be passed through. The two events do not always arrive in the same read, so the hi-res
+//! events of each device are remembered for a short time rather than matched within a batch.
+//! Mice that only send lo-res events never have their events dropped.
+//!
+//! Mapped wheel directions are driven by the hi-res events of mice that send them, accumulated up
+//! to the thresholds of `linux-mwheel-hi-res-threshold`. Partial notches are therefore not lost,
+//! and free-spinning wheels can trigger actions more often than once per notch.
+
+use anyhow::{anyhow, bail, Result};
+
+use std::time::{Duration, Instant};
+
+use evdev::{InputEvent, InputEventKind, RelativeAxisType};
+
+use super::HashMap;
+
+/// How long after a hi-res event the lo-res events of the same device and axis are duplicates.
+/// The kernel sends both in the same frame, so this only needs to cover the delay between reads.
+const HI_RES_WINDOW: Duration = Duration::from_millis(100);
+
+const VERTICAL: usize = 0;
+const HORIZONTAL: usize = 1;
+
+#[derive(Default)]
+pub(super) struct ScrollDedup {
+    /// The time of the most recent vertical and horizontal hi-res event by device path.
+    last_hi_res: HashMap<String, [Option<Instant>; 2]>,
+}
+
+impl ScrollDedup {
+    /// Records the hi-res scroll events of a batch read from the device. This is done before the
+    /// events of the batch are handled, so the order of events within the batch does not matter.
+    pub(super) fn record_batch(&mut self, device_path: &str, events: &[InputEvent], now: Instant) {
+        for ev in events {
+            let axis = match ev.kind() {
+                InputEventKind::RelAxis(RelativeAxisType::REL_WHEEL_HI_RES) => VERTICAL,
+                InputEventKind::RelAxis(RelativeAxisType::REL_HWHEEL_HI_RES) => HORIZONTAL,
+                _ => continue,
+            };
+            match self.last_hi_res.get_mut(device_path) {
+                Some(times) => times[axis] = Some(now),
+                None => {
+                    let mut times = [None; 2];
+                    times[axis] = Some(now);
+                    self.last_hi_res.insert(device_path.to_owned(), times);
+                }
+            }
+        }
+    }
+
+    /// Returns whether the lo-res event duplicates a recent hi-res event of the same device.
+    pub(super) fn is_duplicate(
+        &self,
+        device_path: &str,
+        axis_type: RelativeAxisType,
+        now: Instant,
+    ) -> bool {
+        let axis = match axis_type {
+            RelativeAxisType::REL_WHEEL => VERTICAL,
+            RelativeAxisType::REL_HWHEEL => HORIZONTAL,
+            _ => return false,
+        };
+        self.last_hi_res
+            .get(device_path)
+            .and_then(|times| times[axis])
+            .map(|time| now.duration_since(time) <= HI_RES_WINDOW)
+            .unwrap_or(false)
+    }
+}
+
+/// Thresholds of the up, down, left and right wheel directions.
+pub(super) type HiResThresholds = [u16; 4];
+
+/// Hi-res scroll amounts of mapped wheel directions that have not yet reached a threshold.
+#[derive(Default)]
+pub(super) struct HiResAccumulator {
+    /// The vertical and horizontal remainders by device path.
+    remainders: HashMap<String, [i32; 2]>,
+}
+
+impl HiResAccumulator {
+    /// Adds the value of a hi-res event and returns the number of times the mapped action of its
+    /// direction should fire. A change of direction discards the remainder of the old direction.
+    pub(super) fn add(
+        &mut self,
+        device_path: &str,
+        axis_type: RelativeAxisType,
+        value: i32,
+        thresholds: &HiResThresholds,
+    ) -> u16 {
+        let (axis, threshold) = match axis_type {
+            RelativeAxisType::REL_WHEEL_HI_RES if value > 0 => (VERTICAL, thresholds[0]),
+            RelativeAxisType::REL_WHEEL_HI_RES => (VERTICAL, thresholds[1]),
+            RelativeAxisType::REL_HWHEEL_HI_RES if value < 0 => (HORIZONTAL, thresholds[2]),
+            RelativeAxisType::REL_HWHEEL_HI_RES => (HORIZONTAL, thresholds[3]),
+            _ => return 0,
+        };
+        if !self.remainders.contains_key(device_path) {
+            self.remainders.insert(device_path.to_owned(), [0; 2]);
+        }
+        let remainder = &mut self.remainders.get_mut(device_path).expect("inserted")[axis];
+        if remainder.signum() == -value.signum() {
+            *remainder = 0;
+        }
+        *remainder += value;
+        let threshold = i32::from(threshold.max(1));
+        let count = *remainder / threshold;
+        *remainder -= count * threshold;
+        count.unsigned_abs().try_into().unwrap_or(u16::MAX)
+    }
+}
+
+/// Parses `linux-mwheel-hi-res-threshold`, either one threshold for all directions or four
+/// comma-separated thresholds for up, down, left and right.
+pub(super) fn parse_hi_res_thresholds(
+    cfg_items: &HashMap<String, String>,
+) -> Result<HiResThresholds> {
+    const ERR: &str = "linux-mwheel-hi-res-threshold must be one number 1-65535 or four numbers \
+        separated by commas for up,down,left,right, e.g. 60,60,120,120";
+    let Some(value) = cfg_items.get("linux-mwheel-hi-res-threshold") else {
+        return Ok([HI_RES_UNITS_IN_NOTCH; 4]);
+    };
+    let thresholds = value
+        .split(',')
+        .map(|t| match t.trim().parse::<u16>() {
+            Ok(0) | Err(_) => Err(anyhow!("{ERR}, got {value}")),
+            Ok(t) => Ok(t),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    match thresholds[..] {
+        [t] => Ok([t; 4]),
+        [up, down, left, right] => Ok([up, down, left, right]),
+        _ => bail!("{ERR}, got {value}"),
+    }
+}
+
+/// The hi-res value of one notch of a wheel.
+const HI_RES_UNITS_IN_NOTCH: u16 = 120;
+
+#[test]
+fn scroll_dedup_across_reads() {
+    use evdev::EventType;
+
+    let rel = |axis: RelativeAxisType, value| InputEvent::new(EventType::RELATIVE, axis.0, value);
+    let start = Instant::now();
+    let ms = |n| start + Duration::from_millis(n);
+    let mut dedup = ScrollDedup::default();
+
+    // Lo-res only mice are never deduplicated.
+    dedup.record_batch(
+        "/dev/input/event1",
+        &[rel(RelativeAxisType::REL_WHEEL, 1)],
+        start,
+    );
+    assert!(!dedup.is_duplicate("/dev/input/event1", RelativeAxisType::REL_WHEEL, start));
+
+    // The lo-res event of a hi-res mouse is a duplicate even if it is read separately, but only
+    // on the same axis and within the window.
+    dedup.record_batch(
+        "/dev/input/event2",
+        &[rel(RelativeAxisType::REL_WHEEL_HI_RES, 120)],
+        start,
+    );
+    dedup.record_batch(
+        "/dev/input/event2",
+        &[rel(RelativeAxisType::REL_WHEEL, 1)],
+        ms(2),
+    );
+    assert!(dedup.is_duplicate("/dev/input/event2", RelativeAxisType::REL_WHEEL, ms(2)));
+    assert!(!dedup.is_duplicate("/dev/input/event2", RelativeAxisType::REL_HWHEEL, ms(2)));
+    assert!(!dedup.is_duplicate("/dev/input/event1", RelativeAxisType::REL_WHEEL, ms(2)));
+    assert!(!dedup.is_duplicate("/dev/input/event2", RelativeAxisType::REL_WHEEL, ms(101)));
+}
+
+#[test]
+fn hi_res_accumulation() {
+    let mut acc = HiResAccumulator::default();
+    let thresholds = [60, 120, 120, 120];
+    let dev = "/dev/input/event2";
+    let add = |acc: &mut HiResAccumulator, axis, value| acc.add(dev, axis, value, &thresholds);
+
+    // Partial notches add up, with a threshold per direction.
+    assert_eq!(add(&mut acc, RelativeAxisType::REL_WHEEL_HI_RES, 30), 0);
+    assert_eq!(add(&mut acc, RelativeAxisType::REL_WHEEL_HI_RES, 30), 1);
+    assert_eq!(add(&mut acc, RelativeAxisType::REL_WHEEL_HI_RES, 150), 2);
+    assert_eq!(add(&mut acc, RelativeAxisType::REL_WHEEL_HI_RES, -90), 0);
+    assert_eq!(add(&mut acc, RelativeAxisType::REL_WHEEL_HI_RES, -30), 1);
+    // Changing direction discards the remainder and axes are independent.
+    assert_eq!(add(&mut acc, RelativeAxisType::REL_HWHEEL_HI_RES, 100), 0);
+    assert_eq!(add(&mut acc, RelativeAxisType::REL_HWHEEL_HI_RES, -100), 0);
+    assert_eq!(add(&mut acc, RelativeAxisType::REL_WHEEL_HI_RES, 60), 1);
+    assert_eq!(add(&mut acc, RelativeAxisType::REL_HWHEEL_HI_RES, -20), 1);
+
+    let mut cfg_items = HashMap::default();
+    assert_eq!(parse_hi_res_thresholds(&cfg_items).unwrap(), [120; 4]);
+    cfg_items.insert("linux-mwheel-hi-res-threshold".into(), "30".into());
+    assert_eq!(parse_hi_res_thresholds(&cfg_items).unwrap(), [30; 4]);
+    cfg_items.insert("linux-mwheel-hi-res-threshold".into(), "1,2,3,4".into());
+    assert_eq!(parse_hi_res_thresholds(&cfg_items).unwrap(), [1, 2, 3, 4]);
+    cfg_items.insert("linux-mwheel-hi-res-threshold".into(), "1,2".into());
+    assert!(parse_hi_res_thresholds(&cfg_items).is_err());
+    cfg_items.insert("linux-mwheel-hi-res-threshold".into(), "0".into());
+    assert!(parse_hi_res_thresholds(&cfg_items).is_err());
+}
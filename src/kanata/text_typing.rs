@@ -0,0 +1,255 @@
+//! Typing of text, e.g. the output of `cmd-output-text`. Text is typed a chunk of characters at a
+//! time so that long text does not overwhelm the receiving application or block the processing
+//! loop.
+
+use std::collections::VecDeque;
+
+use kanata_parser::custom_action::{NewlinePolicy, TextTypingCfg};
+use kanata_parser::keys::OsCode;
+
+use crate::oskbd::KbdOut;
+
+use super::OutputDelay;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TextOutput {
+    Char(char),
+    /// Keys pressed in order, then released in reverse order.
+    Keys(&'static [OsCode]),
+}
+
+struct TextJob {
+    outputs: VecDeque<TextOutput>,
+    cfg: TextTypingCfg,
+    ticks_until_next: u16,
+}
+
+#[derive(Default)]
+pub(super) struct TextTyping {
+    jobs: VecDeque<TextJob>,
+}
+
+impl TextTyping {
+    /// Queues the text to be typed after any text queued before it. Trailing newlines are not
+    /// typed since command output usually ends with one.
+    pub(super) fn queue(&mut self, text: &str, cfg: TextTypingCfg) {
+        let outputs: VecDeque<_> = text_outputs(text, cfg).collect();
+        if outputs.is_empty() {
+            return;
+        }
+        log::debug!("queueing {} characters to type", outputs.len());
+        self.jobs.push_back(TextJob {
+            outputs,
+            cfg,
+            ticks_until_next: 0,
+        });
+    }
+
+    /// Types the next chunk of the current text if its interval has elapsed.
+    pub(super) fn tick(
+        &mut self,
+        output_delay: &mut OutputDelay,
+        kbd_out: &mut KbdOut,
+    ) -> anyhow::Result<()> {
+        let Some(job) = self.jobs.front_mut() else {
+            return Ok(());
+        };
+        if job.ticks_until_next > 0 {
+            job.ticks_until_next -= 1;
+            return Ok(());
+        }
+        for _ in 0..job.cfg.chunk {
+            match job.outputs.pop_front() {
+                Some(TextOutput::Char(c)) => {
+                    output_delay.write(kbd_out, move |out| out.send_unicode(c))?
+                }
+                Some(TextOutput::Keys(keys)) => {
+                    for key in keys {
+                        output_delay.press_key(kbd_out, *key)?;
+                    }
+                    for key in keys.iter().rev() {
+                        output_delay.release_key(kbd_out, *key)?;
+                    }
+                }
+                None => break,
+            }
+        }
+        job.ticks_until_next = job.cfg.interval.saturating_sub(1);
+        if job.outputs.is_empty() {
+            self.jobs.pop_front();
+        }
+        Ok(())
+    }
+
+    pub(super) fn cancel(&mut self) {
+        self.jobs.clear();
+    }
+
+    pub(super) fn is_idle(&self) -> bool {
+        self.jobs.is_empty()
+    }
+}
+
+fn text_outputs(text: &str, cfg: TextTypingCfg) -> impl Iterator<Item = TextOutput> + '_ {
+    text.trim_end_matches(['\r', '\n'])
+        .chars()
+        .filter_map(move |c| match c {
+            '\n' => match cfg.newline {
+                NewlinePolicy::Enter => Some(TextOutput::Keys(&[OsCode::KEY_ENTER])),
+                NewlinePolicy::ShiftEnter => Some(TextOutput::Keys(&[
+                    OsCode::KEY_LEFTSHIFT,
+                    OsCode::KEY_ENTER,
+                ])),
+                NewlinePolicy::Space => Some(TextOutput::Keys(&[OsCode::KEY_SPACE])),
+                NewlinePolicy::Ignore => None,
+            },
+            ' ' => Some(TextOutput::Keys(&[OsCode::KEY_SPACE])),
+            '\t' => Some(TextOutput::Keys(&[OsCode::KEY_TAB])),
+            c if c.is_control() => None,
+            c => match us_layout_keys(c) {
+                Some(keys) if cfg.ascii_as_keys => Some(TextOutput::Keys(keys)),
+                _ => Some(TextOutput::Char(c)),
+            },
+        })
+}
+
+/// The keys that type a printable ASCII character on the US layout. Other characters have no keys
+/// and are typed as unicode.
+fn us_layout_keys(c: char) -> Option<&'static [OsCode]> {
+    use OsCode::*;
+    Some(match c {
+        'a' => &[KEY_A],
+        'b' => &[KEY_B],
+        'c' => &[KEY_C],
+        'd' => &[KEY_D],
+        'e' => &[KEY_E],
+        'f' => &[KEY_F],
+        'g' => &[KEY_G],
+        'h' => &[KEY_H],
+        'i' => &[KEY_I],
+        'j' => &[KEY_J],
+        'k' => &[KEY_K],
+        'l' => &[KEY_L],
+        'm' => &[KEY_M],
+        'n' => &[KEY_N],
+        'o' => &[KEY_O],
+        'p' => &[KEY_P],
+        'q' => &[KEY_Q],
+        'r' => &[KEY_R],
+        's' => &[KEY_S],
+        't' => &[KEY_T],
+        'u' => &[KEY_U],
+        'v' => &[KEY_V],
+        'w' => &[KEY_W],
+        'x' => &[KEY_X],
+        'y' => &[KEY_Y],
+        'z' => &[KEY_Z],
+        'A' => &[KEY_LEFTSHIFT, KEY_A],
+        'B' => &[KEY_LEFTSHIFT, KEY_B],
+        'C' => &[KEY_LEFTSHIFT, KEY_C],
+        'D' => &[KEY_LEFTSHIFT, KEY_D],
+        'E' => &[KEY_LEFTSHIFT, KEY_E],
+        'F' => &[KEY_LEFTSHIFT, KEY_F],
+        'G' => &[KEY_LEFTSHIFT, KEY_G],
+        'H' => &[KEY_LEFTSHIFT, KEY_H],
+        'I' => &[KEY_LEFTSHIFT, KEY_I],
+        'J' => &[KEY_LEFTSHIFT, KEY_J],
+        'K' => &[KEY_LEFTSHIFT, KEY_K],
+        'L' => &[KEY_LEFTSHIFT, KEY_L],
+        'M' => &[KEY_LEFTSHIFT, KEY_M],
+        'N' => &[KEY_LEFTSHIFT, KEY_N],
+        'O' => &[KEY_LEFTSHIFT, KEY_O],
+        'P' => &[KEY_LEFTSHIFT, KEY_P],
+        'Q' => &[KEY_LEFTSHIFT, KEY_Q],
+        'R' => &[KEY_LEFTSHIFT, KEY_R],
+        'S' => &[KEY_LEFTSHIFT, KEY_S],
+        'T' => &[KEY_LEFTSHIFT, KEY_T],
+        'U' => &[KEY_LEFTSHIFT, KEY_U],
+        'V' => &[KEY_LEFTSHIFT, KEY_V],
+        'W' => &[KEY_LEFTSHIFT, KEY_W],
+        'X' => &[KEY_LEFTSHIFT, KEY_X],
+        'Y' => &[KEY_LEFTSHIFT, KEY_Y],
+        'Z' => &[KEY_LEFTSHIFT, KEY_Z],
+        '1' => &[KEY_1],
+        '2' => &[KEY_2],
+        '3' => &[KEY_3],
+        '4' => &[KEY_4],
+        '5' => &[KEY_5],
+        '6' => &[KEY_6],
+        '7' => &[KEY_7],
+        '8' => &[KEY_8],
+        '9' => &[KEY_9],
+        '0' => &[KEY_0],
+        '!' => &[KEY_LEFTSHIFT, KEY_1],
+        '@' => &[KEY_LEFTSHIFT, KEY_2],
+        '#' => &[KEY_LEFTSHIFT, KEY_3],
+        '$' => &[KEY_LEFTSHIFT, KEY_4],
+        '%' => &[KEY_LEFTSHIFT, KEY_5],
+        '^' => &[KEY_LEFTSHIFT, KEY_6],
+        '&' => &[KEY_LEFTSHIFT, KEY_7],
+        '*' => &[KEY_LEFTSHIFT, KEY_8],
+        '(' => &[KEY_LEFTSHIFT, KEY_9],
+        ')' => &[KEY_LEFTSHIFT, KEY_0],
+        '`' => &[KEY_GRAVE],
+        '~' => &[KEY_LEFTSHIFT, KEY_GRAVE],
+        '-' => &[KEY_MINUS],
+        '_' => &[KEY_LEFTSHIFT, KEY_MINUS],
+        '=' => &[KEY_EQUAL],
+        '+' => &[KEY_LEFTSHIFT, KEY_EQUAL],
+        '[' => &[KEY_LEFTBRACE],
+        '{' => &[KEY_LEFTSHIFT, KEY_LEFTBRACE],
+        ']' => &[KEY_RIGHTBRACE],
+        '}' => &[KEY_LEFTSHIFT, KEY_RIGHTBRACE],
+        '\\' => &[KEY_BACKSLASH],
+        '|' => &[KEY_LEFTSHIFT, KEY_BACKSLASH],
+        ';' => &[KEY_SEMICOLON],
+        ':' => &[KEY_LEFTSHIFT, KEY_SEMICOLON],
+        '\'' => &[KEY_APOSTROPHE],
+        '"' => &[KEY_LEFTSHIFT, KEY_APOSTROPHE],
+        ',' => &[KEY_COMMA],
+        '<' => &[KEY_LEFTSHIFT, KEY_COMMA],
+        '.' => &[KEY_DOT],
+        '>' => &[KEY_LEFTSHIFT, KEY_DOT],
+        '/' => &[KEY_SLASH],
+        '?' => &[KEY_LEFTSHIFT, KEY_SLASH],
+        _ => return None,
+    })
+}
+
+#[test]
+fn text_outputs_newline_policy() {
+    use TextOutput::*;
+    let cfg = |newline| TextTypingCfg {
+        newline,
+        ascii_as_keys: false,
+        ..Default::default()
+    };
+    let enter = Keys(&[OsCode::KEY_ENTER]);
+    let space = Keys(&[OsCode::KEY_SPACE]);
+    assert_eq!(
+        text_outputs("a b\r\nç\n\n", cfg(NewlinePolicy::Enter)).collect::<Vec<_>>(),
+        [Char('a'), space, Char('b'), enter, Char('ç')]
+    );
+    assert_eq!(
+        text_outputs("a\nb\n", cfg(NewlinePolicy::Space)).collect::<Vec<_>>(),
+        [Char('a'), space, Char('b')]
+    );
+    assert_eq!(
+        text_outputs("a\nb", cfg(NewlinePolicy::Ignore)).collect::<Vec<_>>(),
+        [Char('a'), Char('b')]
+    );
+}
+
+#[test]
+fn text_outputs_ascii_as_keys() {
+    use TextOutput::*;
+    assert_eq!(
+        text_outputs("aB:ç", TextTypingCfg::default()).collect::<Vec<_>>(),
+        [
+            Keys(&[OsCode::KEY_A]),
+            Keys(&[OsCode::KEY_LEFTSHIFT, OsCode::KEY_B]),
+            Keys(&[OsCode::KEY_LEFTSHIFT, OsCode::KEY_SEMICOLON]),
+            Char('ç'),
+        ]
+    );
+}
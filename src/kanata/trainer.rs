@@ -0,0 +1,118 @@
+//! Blocking of wrong-finger keys in layers that have a `deftrainer`. A key is blocked if another
+//! key assigned to the same finger in `deffingers` is held, since a finger can only hold one key.
+//! The release and repeats of a blocked key are blocked too.
+
+use std::io::Write;
+
+use kanata_parser::cfg::{Trainer, TrainerFeedback};
+use kanata_parser::keys::OsCode;
+
+use crate::oskbd::{KeyEvent, KeyValue};
+
+use super::notify;
+
+pub(super) struct KeyTrainer {
+    cfg: Trainer,
+    /// Keys that were blocked and are still held.
+    blocked: Vec<OsCode>,
+}
+
+impl KeyTrainer {
+    pub(super) fn new(cfg: Trainer) -> Self {
+        Self {
+            cfg,
+            blocked: vec![],
+        }
+    }
+
+    /// Changes the configuration on live reload. Keys that are blocked stay blocked until they
+    /// are released.
+    pub(super) fn update_cfg(&mut self, cfg: Trainer) {
+        self.cfg = cfg;
+    }
+
+    /// Returns whether the event should be dropped. `layer` is the index of the active layer in
+    /// the order of `deflayer`, and `held_input_keys` are the input keys held before this event.
+    pub(super) fn blocks(
+        &mut self,
+        event: &KeyEvent,
+        layer: usize,
+        held_input_keys: &[(u16, OsCode)],
+    ) -> bool {
+        match event.value {
+            KeyValue::Press => {
+                let Some(feedback) = self.cfg.layers.get(&layer).copied() else {
+                    return false;
+                };
+                let Some(held) = self.same_finger_key(event.code, held_input_keys) else {
+                    return false;
+                };
+                let finger = &self.cfg.finger_names[self.cfg.fingers[&event.code]];
+                log::info!(
+                    "trainer blocked {:?}: {finger} is holding {held:?}",
+                    event.code
+                );
+                give_feedback(feedback, event.code, held, finger);
+                self.blocked.push(event.code);
+                true
+            }
+            KeyValue::Release => match self.blocked.iter().position(|k| *k == event.code) {
+                Some(i) => {
+                    self.blocked.swap_remove(i);
+                    true
+                }
+                None => false,
+            },
+            KeyValue::Repeat => self.blocked.contains(&event.code),
+            KeyValue::Tap | KeyValue::WakeUp => false,
+        }
+    }
+
+    /// Returns a held key that is typed by the same finger as `key`.
+    fn same_finger_key(&self, key: OsCode, held_input_keys: &[(u16, OsCode)]) -> Option<OsCode> {
+        let finger = self.cfg.fingers.get(&key)?;
+        held_input_keys
+            .iter()
+            .map(|(_, held)| *held)
+            .find(|held| *held != key && self.cfg.fingers.get(held) == Some(finger))
+    }
+}
+
+fn give_feedback(feedback: TrainerFeedback, key: OsCode, held: OsCode, finger: &str) {
+    match feedback {
+        TrainerFeedback::Bell => {
+            let mut stdout = std::io::stdout();
+            let _ = stdout.write_all(b"\x07").and_then(|()| stdout.flush());
+        }
+        TrainerFeedback::Notify => notify::notify(
+            "Wrong finger",
+            &format!("{key:?} was blocked: {finger} is holding {held:?}"),
+        ),
+        TrainerFeedback::None => {}
+    }
+}
+
+#[test]
+fn trainer_blocks_same_finger_keys() {
+    let mut cfg = Trainer {
+        finger_names: vec!["left-index".into(), "left-middle".into()],
+        ..Default::default()
+    };
+    cfg.fingers.insert(OsCode::KEY_F, 0);
+    cfg.fingers.insert(OsCode::KEY_R, 0);
+    cfg.fingers.insert(OsCode::KEY_D, 1);
+    cfg.layers.insert(1, TrainerFeedback::None);
+    let mut trainer = KeyTrainer::new(cfg);
+    let ev = |code, value| KeyEvent::new(code, value);
+
+    // Different fingers and layers without a trainer are not blocked.
+    let held = [(0, OsCode::KEY_F)];
+    assert!(!trainer.blocks(&ev(OsCode::KEY_D, KeyValue::Press), 1, &held));
+    assert!(!trainer.blocks(&ev(OsCode::KEY_R, KeyValue::Press), 0, &held));
+
+    // The same finger is blocked, including its repeats and release.
+    assert!(trainer.blocks(&ev(OsCode::KEY_R, KeyValue::Press), 1, &held));
+    assert!(trainer.blocks(&ev(OsCode::KEY_R, KeyValue::Repeat), 1, &held));
+    assert!(trainer.blocks(&ev(OsCode::KEY_R, KeyValue::Release), 0, &held));
+    assert!(!trainer.blocks(&ev(OsCode::KEY_R, KeyValue::Release), 1, &held));
+}
@@ -0,0 +1,102 @@
+//! Export of the intervals between input key events for typing rhythm analysis. Only the timing
+//! and whether a key was pressed or released are exported, never which key it was.
+
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+
+use crate::oskbd::{KeyEvent, KeyValue};
+use crate::tcp_server::{KeyTimingEvent, ServerMessage};
+
+use super::Sender;
+
+/// Where to export the intervals between input key events.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypingRhythmOutput {
+    /// Append one JSON message per line to the file.
+    File(PathBuf),
+    /// Send messages to the clients of the TCP server.
+    Tcp,
+}
+
+impl FromStr for TypingRhythmOutput {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s {
+            "tcp" => Self::Tcp,
+            path => Self::File(PathBuf::from(path)),
+        })
+    }
+}
+
+enum Output {
+    File(LineWriter<File>),
+    Tcp,
+}
+
+pub(super) struct TypingRhythm {
+    output: Output,
+    last_event: Option<Instant>,
+}
+
+impl TypingRhythm {
+    pub(super) fn new(output: &TypingRhythmOutput) -> Result<Self> {
+        log::info!("exporting the intervals between key events to {output:?}");
+        let output = match output {
+            TypingRhythmOutput::File(path) => Output::File(LineWriter::new(
+                File::options()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| {
+                        anyhow!("could not open typing rhythm file {}: {e}", path.display())
+                    })?,
+            )),
+            TypingRhythmOutput::Tcp => Output::Tcp,
+        };
+        Ok(Self {
+            output,
+            last_event: None,
+        })
+    }
+
+    /// Exports the interval between this input event and the previous one. OS key repeats and
+    /// events that are not from a key are ignored. The intervals are measured between the times
+    /// that the input device reports for the events, if the platform reports them, so that they do
+    /// not depend on how long kanata takes to process the events.
+    pub(super) fn record(&mut self, event: &KeyEvent, tx: &Option<Sender<ServerMessage>>) {
+        let time = event.time.unwrap_or_else(Instant::now);
+        let event = match event.value {
+            KeyValue::Press => KeyTimingEvent::Press,
+            KeyValue::Release => KeyTimingEvent::Release,
+            _ => return,
+        };
+        let interval_ms = self
+            .last_event
+            .map(|last| time.saturating_duration_since(last).as_millis() as u64);
+        self.last_event = Some(time);
+        let msg = ServerMessage::KeyTiming { event, interval_ms };
+        match &mut self.output {
+            Output::File(file) => {
+                let result = serde_json::to_string(&msg)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|line| Ok(writeln!(file, "{line}")?));
+                if let Err(e) = result {
+                    log::warn!("could not write key timing: {e}");
+                }
+            }
+            Output::Tcp => {
+                if let Some(tx) = tx {
+                    if let Err(e) = tx.try_send(msg) {
+                        log::warn!("could not send key timing: {e:?}");
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Estimation of typing speed and accuracy from the keys that kanata outputs, sent to TCP clients
+//! at the interval configured by `tcp-typing-stats-interval` for use by e.g. status bar widgets.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use kanata_parser::keys::OsCode;
+use kanata_parser::sequences::{outputs_character, MASK_KEYCODES};
+
+/// Presses older than this are not part of the estimate.
+const WINDOW: Duration = Duration::from_secs(60);
+/// Shortest time span to estimate over, so that the first few presses do not report an absurd
+/// speed.
+const MIN_SPAN: Duration = Duration::from_secs(5);
+/// Words are counted as 5 characters, as is usual for typing speed.
+const CHARS_PER_WORD: f32 = 5.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Press {
+    Char,
+    Backspace,
+}
+
+#[derive(Default)]
+pub(super) struct TypingStats {
+    presses: VecDeque<(Instant, Press)>,
+}
+
+impl TypingStats {
+    /// Records an output key press. The key has the modifier bits of `mod_mask_for_keycode` set
+    /// for the modifiers held with it, so that shortcuts are not counted as typing.
+    pub(super) fn record(&mut self, key: u16, now: Instant) {
+        let press = if outputs_character(key) {
+            Press::Char
+        } else if key & MASK_KEYCODES == u16::from(OsCode::KEY_BACKSPACE) {
+            Press::Backspace
+        } else {
+            return;
+        };
+        self.presses.push_back((now, press));
+        self.expire(now);
+    }
+
+    /// Returns the words per minute and the accuracy as a percentage. The accuracy assumes that
+    /// every backspace corrects one mistyped character.
+    pub(super) fn estimate(&mut self, now: Instant) -> (u16, u8) {
+        self.expire(now);
+        let chars = self
+            .presses
+            .iter()
+            .filter(|(_, press)| *press == Press::Char)
+            .count();
+        let backspaces = self.presses.len() - chars;
+        let span = self
+            .presses
+            .front()
+            .map(|(time, _)| now.duration_since(*time))
+            .unwrap_or_default()
+            .clamp(MIN_SPAN, WINDOW);
+        let wpm = chars as f32 / CHARS_PER_WORD / (span.as_secs_f32() / 60.0);
+        let accuracy = match chars {
+            0 => 100,
+            _ => (chars.saturating_sub(backspaces) * 100 / chars) as u8,
+        };
+        (wpm.round() as u16, accuracy)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((time, _)) = self.presses.front() {
+            if now.duration_since(*time) <= WINDOW {
+                break;
+            }
+            self.presses.pop_front();
+        }
+    }
+}
+
+#[test]
+fn typing_stats_estimate() {
+    use kanata_keyberon::key_code::KeyCode;
+    use kanata_parser::sequences::mod_mask_for_keycode;
+
+    let start = Instant::now();
+    let ms = |n| start + Duration::from_millis(n);
+    let a = u16::from(OsCode::KEY_A);
+    let mut stats = TypingStats::default();
+    assert_eq!(stats.estimate(start), (0, 100));
+
+    // 50 characters in 10 seconds is 10 words in 1/6 minute.
+    for i in 0..50 {
+        stats.record(a, ms(i * 200));
+    }
+    assert_eq!(stats.estimate(ms(10_000)), (60, 100));
+
+    // Shortcuts and non-typing keys are ignored, backspaces lower the accuracy.
+    stats.record(a | mod_mask_for_keycode(KeyCode::LCtrl), ms(10_000));
+    stats.record(u16::from(OsCode::KEY_LEFT), ms(10_000));
+    for _ in 0..5 {
+        stats.record(u16::from(OsCode::KEY_BACKSPACE), ms(10_000));
+    }
+    assert_eq!(stats.estimate(ms(10_000)), (60, 90));
+
+    // Old presses expire.
+    assert_eq!(stats.estimate(ms(80_000)), (0, 100));
+}
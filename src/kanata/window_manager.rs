@@ -0,0 +1,469 @@
+//! Window manager commands for the `wm-*` actions. On Linux they are sent to the IPC socket of
+//! i3 or sway, or to the socket of Hyprland, so that no program is run for every key press. On
+//! Windows they are the keyboard shortcuts of snapping windows and switching virtual desktops.
+
+use kanata_parser::custom_action::WmCommand;
+use kanata_parser::keys::OsCode;
+
+use super::HashMap;
+
+#[cfg(target_os = "linux")]
+use parking_lot::Mutex;
+#[cfg(target_os = "linux")]
+use std::path::PathBuf;
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
+
+pub(super) struct WindowManager {
+    /// The socket of `wm-socket`, or `None` to find it from the session.
+    #[cfg(target_os = "linux")]
+    socket_cfg: Option<PathBuf>,
+    /// The socket that the last command was sent to. It is found again when it cannot be
+    /// connected to, e.g. after the window manager was restarted.
+    #[cfg(target_os = "linux")]
+    socket: Arc<Mutex<Option<linux::Socket>>>,
+}
+
+impl WindowManager {
+    #[cfg(target_os = "linux")]
+    pub(super) fn from_cfg(cfg_items: &HashMap<String, String>) -> Self {
+        Self {
+            socket_cfg: parse_socket_cfg(cfg_items),
+            socket: Default::default(),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(super) fn from_cfg(_cfg_items: &HashMap<String, String>) -> Self {
+        Self {}
+    }
+
+    /// Changes the socket on live reload. The cached socket is kept if `wm-socket` is unchanged.
+    #[cfg(target_os = "linux")]
+    pub(super) fn update_cfg(&mut self, cfg_items: &HashMap<String, String>) {
+        let socket_cfg = parse_socket_cfg(cfg_items);
+        if socket_cfg != self.socket_cfg {
+            *self = Self {
+                socket_cfg,
+                socket: Default::default(),
+            };
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(super) fn update_cfg(&mut self, _cfg_items: &HashMap<String, String>) {}
+
+    /// Sends the command to the window manager in the background. Returns the keyboard shortcuts
+    /// that kanata must tap instead on platforms without a window manager socket.
+    #[cfg(target_os = "linux")]
+    pub(super) fn run(&self, cmd: &WmCommand) -> Vec<Vec<OsCode>> {
+        let cmd = cmd.clone();
+        let socket_cfg = self.socket_cfg.clone();
+        let socket = self.socket.clone();
+        super::worker::run_on_worker(move || {
+            if let Err(e) = linux::send(&cmd, socket_cfg.as_deref(), &mut socket.lock()) {
+                log::warn!("window manager command {cmd:?} failed: {e}");
+            }
+        });
+        vec![]
+    }
+
+    #[cfg(target_os = "windows")]
+    pub(super) fn run(&self, cmd: &WmCommand) -> Vec<Vec<OsCode>> {
+        windows::shortcuts(cmd)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    pub(super) fn run(&self, cmd: &WmCommand) -> Vec<Vec<OsCode>> {
+        log::warn!(
+            "{cmd:?} not sent: window manager actions are only supported on Linux and Windows"
+        );
+        vec![]
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn parse_socket_cfg(cfg_items: &HashMap<String, String>) -> Option<PathBuf> {
+    cfg_items
+        .get("wm-socket")
+        .map(|socket| PathBuf::from(socket.trim_matches('"')))
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use anyhow::{anyhow, bail, Result};
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use kanata_parser::custom_action::{MoveDirection, WmCommand};
+
+    const I3_IPC_MAGIC: &[u8] = b"i3-ipc";
+    const I3_IPC_RUN_COMMAND: u32 = 0;
+    const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub(super) enum Socket {
+        /// i3 or sway, which have the same IPC protocol.
+        I3(PathBuf),
+        Hyprland(PathBuf),
+    }
+
+    impl Socket {
+        /// Returns the socket of `wm-socket`. Hyprland sockets are named `.socket.sock`, any other
+        /// socket is expected to be the IPC socket of i3 or sway.
+        fn from_cfg(path: &Path) -> Self {
+            match path.file_name() {
+                Some(name) if name == ".socket.sock" => Socket::Hyprland(path.into()),
+                _ => Socket::I3(path.into()),
+            }
+        }
+
+        fn path(&self) -> &Path {
+            match self {
+                Socket::I3(path) | Socket::Hyprland(path) => path,
+            }
+        }
+    }
+
+    /// Sends the command to the socket of `wm-socket`, or else to the cached socket, or else to
+    /// the socket that is found for the session. The socket is cached only if it could be
+    /// connected to.
+    pub(super) fn send(
+        cmd: &WmCommand,
+        socket_cfg: Option<&Path>,
+        cached: &mut Option<Socket>,
+    ) -> Result<()> {
+        let socket = match (socket_cfg, cached.take()) {
+            (Some(path), _) => Socket::from_cfg(path),
+            (None, Some(socket)) => socket,
+            (None, None) => find_socket()?,
+        };
+        let stream = UnixStream::connect(socket.path())
+            .map_err(|e| anyhow!("failed to connect to {}: {e}", socket.path().display()))?;
+        stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        let result = match &socket {
+            Socket::I3(_) => send_i3(stream, &i3_command(cmd)),
+            Socket::Hyprland(_) => send_hyprland(stream, &hyprland_command(cmd)),
+        };
+        *cached = Some(socket);
+        result
+    }
+
+    /// Finds the socket from the environment of the session. Kanata often runs as a service
+    /// without that environment, so the runtime directory of the user of the session is searched
+    /// otherwise.
+    fn find_socket() -> Result<Socket> {
+        if let Some(socket) = std::env::var_os("SWAYSOCK").or_else(|| std::env::var_os("I3SOCK")) {
+            return Ok(Socket::I3(socket.into()));
+        }
+        if let (Some(runtime_dir), Some(signature)) = (
+            std::env::var_os("XDG_RUNTIME_DIR"),
+            std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE"),
+        ) {
+            let socket = Path::new(&runtime_dir)
+                .join("hypr")
+                .join(signature)
+                .join(".socket.sock");
+            if socket.exists() {
+                return Ok(Socket::Hyprland(socket));
+            }
+        }
+        let uid = session_uid().ok_or_else(|| {
+            anyhow!("the user of the session is unknown, set the socket with wm-socket in defcfg")
+        })?;
+        let runtime_dir = Path::new("/run/user").join(uid.to_string());
+        find_in_runtime_dir(&runtime_dir).ok_or_else(|| {
+            anyhow!(
+                "no i3, sway or Hyprland socket found in {}, set the socket with wm-socket in defcfg",
+                runtime_dir.display()
+            )
+        })
+    }
+
+    /// Returns the user of kanata, or the user of the active session of the first seat if kanata
+    /// runs as root, e.g. as a system service.
+    fn session_uid() -> Option<u32> {
+        let uid = nix::unistd::geteuid();
+        if !uid.is_root() {
+            return Some(uid.as_raw());
+        }
+        // The state of the seat is written by systemd-logind.
+        std::fs::read_to_string("/run/systemd/seats/seat0")
+            .ok()?
+            .lines()
+            .find_map(|line| line.strip_prefix("ACTIVE_UID="))?
+            .parse()
+            .ok()
+    }
+
+    /// Finds a socket in the runtime directory that can be connected to. The sockets of window
+    /// managers that have exited are often left behind.
+    fn find_in_runtime_dir(runtime_dir: &Path) -> Option<Socket> {
+        let entries = |dir: &Path| {
+            std::fs::read_dir(dir)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| UnixStream::connect(path).is_ok())
+        };
+        let file_name = |path: &Path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        };
+        if let Some(socket) = entries(runtime_dir).find(|path| {
+            let name = file_name(path);
+            name.starts_with("sway-ipc.") && name.ends_with(".sock")
+        }) {
+            return Some(Socket::I3(socket));
+        }
+        if let Some(socket) =
+            entries(&runtime_dir.join("i3")).find(|path| file_name(path).starts_with("ipc-socket."))
+        {
+            return Some(Socket::I3(socket));
+        }
+        std::fs::read_dir(runtime_dir.join("hypr"))
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|instance| instance.path().join(".socket.sock"))
+            .find(|socket| UnixStream::connect(socket).is_ok())
+            .map(Socket::Hyprland)
+    }
+
+    fn i3_command(cmd: &WmCommand) -> String {
+        let direction = |direction: &MoveDirection| match direction {
+            MoveDirection::Left => "left",
+            MoveDirection::Right => "right",
+            MoveDirection::Up => "up",
+            MoveDirection::Down => "down",
+        };
+        match cmd {
+            WmCommand::Focus(d) => format!("focus {}", direction(d)),
+            WmCommand::Move(d) => format!("move {}", direction(d)),
+            WmCommand::Workspace(ws) => match ws.as_str() {
+                "next" | "prev" => format!("workspace {ws}"),
+                // Workspaces are often named after their number, e.g. "1: web".
+                _ if ws.parse::<u32>().is_ok() => format!("workspace number {ws}"),
+                _ => format!(
+                    "workspace \"{}\"",
+                    ws.replace('\\', "\\\\").replace('"', "\\\"")
+                ),
+            },
+        }
+    }
+
+    fn hyprland_command(cmd: &WmCommand) -> String {
+        let direction = |direction: &MoveDirection| match direction {
+            MoveDirection::Left => "l",
+            MoveDirection::Right => "r",
+            MoveDirection::Up => "u",
+            MoveDirection::Down => "d",
+        };
+        match cmd {
+            WmCommand::Focus(d) => format!("dispatch movefocus {}", direction(d)),
+            WmCommand::Move(d) => format!("dispatch movewindow {}", direction(d)),
+            WmCommand::Workspace(ws) => match ws.as_str() {
+                "next" => "dispatch workspace e+1".to_owned(),
+                "prev" => "dispatch workspace e-1".to_owned(),
+                _ if ws.parse::<u32>().is_ok() => format!("dispatch workspace {ws}"),
+                _ => format!("dispatch workspace name:{ws}"),
+            },
+        }
+    }
+
+    /// Returns a RUN_COMMAND message of the i3 IPC protocol.
+    fn i3_message(payload: &str) -> Vec<u8> {
+        let mut msg = I3_IPC_MAGIC.to_vec();
+        msg.extend((payload.len() as u32).to_ne_bytes());
+        msg.extend(I3_IPC_RUN_COMMAND.to_ne_bytes());
+        msg.extend(payload.as_bytes());
+        msg
+    }
+
+    fn send_i3(mut stream: UnixStream, payload: &str) -> Result<()> {
+        stream.write_all(&i3_message(payload))?;
+        let mut header = [0u8; 14];
+        stream.read_exact(&mut header)?;
+        if &header[..6] != I3_IPC_MAGIC {
+            bail!("invalid reply from i3 or sway");
+        }
+        let len = u32::from_ne_bytes(header[6..10].try_into().expect("4 bytes"));
+        let mut reply = vec![0u8; len as usize];
+        stream.read_exact(&mut reply)?;
+        // The reply has one result for each command, e.g. [{"success":false,"error":"..."}].
+        let results: Vec<serde_json::Value> = serde_json::from_slice(&reply)
+            .map_err(|e| anyhow!("invalid reply from i3 or sway: {e}"))?;
+        for result in results {
+            if result["success"] != serde_json::Value::Bool(true) {
+                bail!("{}", result["error"].as_str().unwrap_or("command failed"));
+            }
+        }
+        Ok(())
+    }
+
+    fn send_hyprland(mut stream: UnixStream, command: &str) -> Result<()> {
+        stream.write_all(command.as_bytes())?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply)?;
+        if reply.trim() != "ok" {
+            bail!("{}", reply.trim());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn wm_commands() {
+        let cmds = [
+            WmCommand::Focus(MoveDirection::Left),
+            WmCommand::Move(MoveDirection::Down),
+            WmCommand::Workspace("next".into()),
+            WmCommand::Workspace("3".into()),
+            WmCommand::Workspace("my \"web\"".into()),
+        ];
+        assert_eq!(
+            cmds.iter().map(i3_command).collect::<Vec<_>>(),
+            [
+                "focus left",
+                "move down",
+                "workspace next",
+                "workspace number 3",
+                r#"workspace "my \"web\"""#,
+            ]
+        );
+        assert_eq!(
+            cmds.iter().map(hyprland_command).collect::<Vec<_>>(),
+            [
+                "dispatch movefocus l",
+                "dispatch movewindow d",
+                "dispatch workspace e+1",
+                "dispatch workspace 3",
+                "dispatch workspace name:my \"web\"",
+            ]
+        );
+        let msg = i3_message("focus left");
+        assert_eq!(&msg[..6], b"i3-ipc");
+        assert_eq!(&msg[6..10], 10u32.to_ne_bytes());
+        assert_eq!(&msg[10..14], 0u32.to_ne_bytes());
+        assert_eq!(&msg[14..], b"focus left");
+
+        assert_eq!(
+            Socket::from_cfg(Path::new("/run/user/1000/hypr/abc/.socket.sock")),
+            Socket::Hyprland("/run/user/1000/hypr/abc/.socket.sock".into())
+        );
+        assert_eq!(
+            Socket::from_cfg(Path::new("/run/user/1000/sway-ipc.1000.42.sock")),
+            Socket::I3("/run/user/1000/sway-ipc.1000.42.sock".into())
+        );
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod windows {
+    use std::ptr::null_mut;
+
+    use winapi::um::processthreadsapi::{GetCurrentProcessId, ProcessIdToSessionId};
+    use winapi::um::winreg::{RegGetValueW, HKEY_CURRENT_USER, RRF_RT_REG_BINARY};
+
+    use kanata_parser::custom_action::{MoveDirection, WmCommand};
+    use kanata_parser::keys::OsCode;
+
+    const EXPLORER_KEY: &str = r"Software\Microsoft\Windows\CurrentVersion\Explorer";
+
+    /// Returns the shortcuts of the command. Windows snaps the focused window with Win+arrow and
+    /// switches to the adjacent virtual desktop with Ctrl+Win+Left or Ctrl+Win+Right.
+    pub(super) fn shortcuts(cmd: &WmCommand) -> Vec<Vec<OsCode>> {
+        let arrow = |direction: &MoveDirection| match direction {
+            MoveDirection::Left => OsCode::KEY_LEFT,
+            MoveDirection::Right => OsCode::KEY_RIGHT,
+            MoveDirection::Up => OsCode::KEY_UP,
+            MoveDirection::Down => OsCode::KEY_DOWN,
+        };
+        let switch_desktop =
+            |arrow: OsCode| vec![OsCode::KEY_LEFTCTRL, OsCode::KEY_LEFTMETA, arrow];
+        match cmd {
+            WmCommand::Focus(_) => {
+                log::warn!("{cmd:?} not sent: Windows cannot focus the window in a direction");
+                vec![]
+            }
+            WmCommand::Move(d) => vec![vec![OsCode::KEY_LEFTMETA, arrow(d)]],
+            WmCommand::Workspace(ws) => match ws.as_str() {
+                "next" => vec![switch_desktop(OsCode::KEY_RIGHT)],
+                "prev" => vec![switch_desktop(OsCode::KEY_LEFT)],
+                _ => {
+                    let Ok(number @ 1..) = ws.parse::<usize>() else {
+                        log::warn!("{cmd:?} not sent: virtual desktops are switched by number");
+                        return vec![];
+                    };
+                    let Some((current, count)) = virtual_desktops() else {
+                        log::warn!("{cmd:?} not sent: failed to read the virtual desktops");
+                        return vec![];
+                    };
+                    if number > count {
+                        log::warn!("{cmd:?} not sent: there are only {count} virtual desktops");
+                        return vec![];
+                    }
+                    let target = number - 1;
+                    if target > current {
+                        vec![switch_desktop(OsCode::KEY_RIGHT); target - current]
+                    } else {
+                        vec![switch_desktop(OsCode::KEY_LEFT); current - target]
+                    }
+                }
+            },
+        }
+    }
+
+    /// Returns the index of the current virtual desktop and the number of virtual desktops. They
+    /// are stored by Explorer as the list of desktop GUIDs and the GUID of the current desktop,
+    /// which Windows 10 stores per session.
+    fn virtual_desktops() -> Option<(usize, usize)> {
+        let desktops_key = format!(r"{EXPLORER_KEY}\VirtualDesktops");
+        let ids = read_binary_value(&desktops_key, "VirtualDesktopIDs")?;
+        let current = read_binary_value(&desktops_key, "CurrentVirtualDesktop").or_else(|| {
+            let mut session = 0;
+            // Safety: the session ID is written to a valid u32.
+            if unsafe { ProcessIdToSessionId(GetCurrentProcessId(), &mut session) } == 0 {
+                return None;
+            }
+            read_binary_value(
+                &format!(r"{EXPLORER_KEY}\SessionInfo\{session}\VirtualDesktops"),
+                "CurrentVirtualDesktop",
+            )
+        })?;
+        let index = ids.chunks_exact(16).position(|id| id == current)?;
+        Some((index, ids.len() / 16))
+    }
+
+    fn read_binary_value(key: &str, value: &str) -> Option<Vec<u8>> {
+        let wide = |s: &str| s.encode_utf16().chain([0]).collect::<Vec<u16>>();
+        let (key, value) = (wide(key), wide(value));
+        let mut size = 0;
+        // Safety: the strings are null-terminated and the size of the buffer is passed with it.
+        unsafe {
+            let read = |data: *mut u8, size: &mut u32| {
+                RegGetValueW(
+                    HKEY_CURRENT_USER,
+                    key.as_ptr(),
+                    value.as_ptr(),
+                    RRF_RT_REG_BINARY,
+                    null_mut(),
+                    data.cast(),
+                    size,
+                )
+            };
+            if read(null_mut(), &mut size) != 0 {
+                return None;
+            }
+            let mut data = vec![0u8; size as usize];
+            if read(data.as_mut_ptr(), &mut size) != 0 {
+                return None;
+            }
+            data.truncate(size as usize);
+            Some(data)
+        }
+    }
+}
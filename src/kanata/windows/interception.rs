@@ -4,10 +4,10 @@ use parking_lot::Mutex;
 use std::sync::mpsc::SyncSender as Sender;
 use std::sync::Arc;
 
-use super::PRESSED_KEYS;
+use super::{is_paused_for, PRESSED_KEYS};
 use crate::kanata::*;
 use crate::oskbd::KeyValue;
-use kanata_parser::keys::OsCode;
+use kanata_parser::keys::{scancode_to_oscode, OsCode};
 
 const HWID_ARR_SZ: usize = 128;
 
@@ -21,38 +21,31 @@ impl Kanata {
             information: 0,
         }; 32];
 
-        let mouse_to_intercept_hwid: Option<[u8; HWID_ARR_SZ]> = kanata
-            .lock()
-            .intercept_mouse_hwid.as_ref()
-            .map(|hwid| {
-                hwid.iter().copied().enumerate()
-                    .fold([0u8; HWID_ARR_SZ], |mut hwid, idx_byte| {
-                        let (i, b) = idx_byte;
-                        if i > HWID_ARR_SZ {
-                            panic!("windows-interception-mouse-hwid is too long; it should be up to {HWID_ARR_SZ} 8-bit unsigned integers");
-                        }
-                        hwid[i] = b;
-                        hwid
-                    })
-            });
-        if mouse_to_intercept_hwid.is_some() {
+        scheduling::pin_current_thread("event loop", &kanata.lock().scheduling.event_loop_cpus);
+
+        let intercept_mice = kanata.lock().intercept_mice.clone();
+        if intercept_mice.is_some() {
             intrcptn.set_filter(
                 ic::is_mouse,
                 ic::Filter::MouseFilter(ic::MouseState::all() & (!ic::MouseState::MOVE)),
             );
         }
         let mut is_dev_interceptable: HashMap<ic::Device, bool> = HashMap::default();
+        let mut hwids: HashMap<ic::Device, String> = HashMap::default();
 
         loop {
             let dev = intrcptn.wait();
             if dev > 0 {
                 let num_strokes = intrcptn.receive(dev, &mut strokes) as usize;
                 for i in 0..num_strokes {
-                    let mut key_event = match strokes[i] {
+                    match strokes[i] {
                         ic::Stroke::Keyboard { state, .. } => {
                             log::debug!("got stroke {:?}", strokes[i]);
-                            let code = match OsCodeWrapper::try_from(strokes[i]) {
-                                Ok(c) => c.0,
+                            let code = match mapped_scancode_oscode(strokes[i]).map_or_else(
+                                || OsCodeWrapper::try_from(strokes[i]).map(|c| c.0),
+                                Ok,
+                            ) {
+                                Ok(c) => c,
                                 _ => {
                                     log::debug!("could not map code to oscode");
                                     intrcptn.send(dev, &strokes[i..i + 1]);
@@ -63,160 +56,298 @@ impl Kanata {
                                 false => KeyValue::Press,
                                 true => KeyValue::Release,
                             };
-                            KeyEvent { code, value }
+                            let mut key_event = KeyEvent::new(code, value);
+                            key_event.device = input_device_index(dev, &intrcptn, &mut hwids);
+                            check_for_exit(&key_event);
+                            if !MAPPED_KEYS.lock().contains(&key_event.code) {
+                                log::debug!("{key_event:?} is not mapped");
+                                intrcptn.send(dev, &strokes[i..i + 1]);
+                                continue;
+                            }
+                            if is_paused_for(&key_event) {
+                                intrcptn.send(dev, &strokes[i..i + 1]);
+                                continue;
+                            }
+                            send_key_event(key_event, &tx)?;
                         }
-                        ic::Stroke::Mouse { state, rolling, .. } => {
-                            if let Some(hwid) = mouse_to_intercept_hwid {
-                                log::trace!("checking mouse stroke {:?}", strokes[i]);
-                                if let Some(event) = mouse_state_to_event(
+                        ic::Stroke::Mouse { .. } => {
+                            let interceptable = match &intercept_mice {
+                                Some(mice) => is_mouse_interceptable(
                                     dev,
-                                    &hwid,
-                                    state,
-                                    rolling,
+                                    mice,
                                     &intrcptn,
                                     &mut is_dev_interceptable,
-                                ) {
-                                    event
-                                } else {
-                                    intrcptn.send(dev, &strokes[i..i + 1]);
-                                    continue;
-                                }
-                            } else {
+                                ),
+                                None => false,
+                            };
+                            if !interceptable {
                                 intrcptn.send(dev, &strokes[i..i + 1]);
                                 continue;
                             }
-                        }
-                    };
-                    check_for_exit(&key_event);
-                    if !MAPPED_KEYS.lock().contains(&key_event.code) {
-                        log::debug!("{key_event:?} is not mapped");
-                        intrcptn.send(dev, &strokes[i..i + 1]);
-                        continue;
-                    }
-                    log::debug!("sending {key_event:?} to processing loop");
-                    match key_event.value {
-                        KeyValue::Release => {
-                            PRESSED_KEYS.lock().remove(&key_event.code);
-                        }
-                        KeyValue::Press => {
-                            let mut pressed_keys = PRESSED_KEYS.lock();
-                            if pressed_keys.contains(&key_event.code) {
-                                key_event.value = KeyValue::Repeat;
-                            } else {
-                                pressed_keys.insert(key_event.code);
+                            log::trace!("checking mouse stroke {:?}", strokes[i]);
+                            let (key_events, rest) = split_mouse_stroke(strokes[i]);
+                            if let Some(rest) = rest {
+                                intrcptn.send(dev, &[rest]);
+                            }
+                            let device = input_device_index(dev, &intrcptn, &mut hwids);
+                            for mut key_event in key_events {
+                                key_event.device = device;
+                                check_for_exit(&key_event);
+                                send_key_event(key_event, &tx)?;
                             }
                         }
-                        _ => {}
                     }
-                    tx.try_send(key_event)?;
                 }
             }
         }
     }
 }
 
-fn mouse_state_to_event(
+/// Sends an event of a mapped key to the processing loop.
+fn send_key_event(mut key_event: KeyEvent, tx: &Sender<KeyEvent>) -> Result<()> {
+    log::debug!("sending {key_event:?} to processing loop");
+    match key_event.value {
+        KeyValue::Release => {
+            PRESSED_KEYS.lock().remove(&key_event.code);
+        }
+        KeyValue::Press => {
+            let mut pressed_keys = PRESSED_KEYS.lock();
+            if pressed_keys.contains(&key_event.code) {
+                key_event.value = KeyValue::Repeat;
+            } else {
+                pressed_keys.insert(key_event.code);
+            }
+        }
+        _ => {}
+    }
+    tx.try_send(key_event)?;
+    Ok(())
+}
+
+/// Returns the index used by `input-device` switch conditions for the device: one more than the
+/// position of the first configured name that is the Interception device number or the hardware
+/// ID of the device, or 0 if none match. The hardware ID of each device is read and logged the
+/// first time that the device is used.
+fn input_device_index(
+    dev: ic::Device,
+    intrcptn: &ic::Interception,
+    hwids: &mut HashMap<ic::Device, String>,
+) -> u16 {
+    let hwid = hwids.entry(dev).or_insert_with(|| {
+        let hwid = hardware_id(dev, intrcptn);
+        log::info!("input device #{dev} has hardware id {hwid}");
+        hwid
+    });
+    INPUT_DEVICES
+        .lock()
+        .iter()
+        .position(|name| name.parse::<ic::Device>() == Ok(dev) || name.eq_ignore_ascii_case(hwid))
+        .map(|i| i as u16 + 1)
+        .unwrap_or(0)
+}
+
+/// Returns the first hardware ID of the device. Interception reports the IDs as a list of
+/// null-terminated UTF-16 strings.
+fn hardware_id(dev: ic::Device, intrcptn: &ic::Interception) -> String {
+    let mut buf = [0u8; HWID_ARR_SZ];
+    let len = intrcptn.get_hardware_id(dev, &mut buf) as usize;
+    let wide = buf[..len.min(HWID_ARR_SZ)]
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect::<Vec<_>>();
+    String::from_utf16_lossy(&wide)
+}
+
+/// The mice whose buttons and wheel are intercepted, from `windows-interception-mouse-hwid`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterceptMice {
+    All,
+    Hwids(Vec<[u8; HWID_ARR_SZ]>),
+}
+
+/// Parses `windows-interception-mouse-hwid`: `all`, or the hardware IDs of one or more mice as
+/// comma-separated bytes, with the IDs separated by semicolons.
+pub fn parse_intercept_mice(cfg_items: &HashMap<String, String>) -> Result<Option<InterceptMice>> {
+    let Some(hwids) = cfg_items.get("windows-interception-mouse-hwid") else {
+        return Ok(None);
+    };
+    log::trace!("win hwid: {hwids}");
+    if hwids.trim() == "all" {
+        return Ok(Some(InterceptMice::All));
+    }
+    hwids
+        .split(';')
+        .map(|hwid| {
+            let bytes = hwid
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|b| !b.is_empty())
+                .map(|b| b.parse::<u8>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| {
+                    anyhow!(
+                        "windows-interception-mouse-hwid must be all or hardware IDs of \
+                        comma-separated numbers 0-255, separated by semicolons. Got {hwid}"
+                    )
+                })?;
+            if bytes.len() > HWID_ARR_SZ {
+                bail!(
+                    "windows-interception-mouse-hwid is too long; \
+                    each ID should be up to {HWID_ARR_SZ} numbers"
+                );
+            }
+            let mut padded = [0u8; HWID_ARR_SZ];
+            padded[..bytes.len()].copy_from_slice(&bytes);
+            Ok(padded)
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(|hwids| Some(InterceptMice::Hwids(hwids)))
+}
+
+/// Returns whether the strokes of the mouse are intercepted. The hardware ID of each mouse is
+/// read and logged the first time that the mouse is used.
+fn is_mouse_interceptable(
     input_dev: ic::Device,
-    allowed_hwid: &[u8; HWID_ARR_SZ],
-    state: ic::MouseState,
-    rolling: i16,
+    mice: &InterceptMice,
     intrcptn: &ic::Interception,
     is_dev_interceptable: &mut HashMap<ic::Device, bool>,
-) -> Option<KeyEvent> {
-    if !match is_dev_interceptable.get(&input_dev) {
-        Some(v) => *v,
-        None => {
-            let mut hwid = [0u8; HWID_ARR_SZ];
-            log::trace!("getting hardware id for input dev: {input_dev}");
-            let res = intrcptn.get_hardware_id(input_dev, &mut hwid);
-            let dev_is_interceptable = hwid == *allowed_hwid;
-            log::info!("res {res}; device #{input_dev} hwid {hwid:?} matches allowed mouse input: {dev_is_interceptable}");
-            is_dev_interceptable.insert(input_dev, dev_is_interceptable);
-            dev_is_interceptable
-        }
-    } {
-        return None;
+) -> bool {
+    if let Some(v) = is_dev_interceptable.get(&input_dev) {
+        return *v;
     }
+    let mut hwid = [0u8; HWID_ARR_SZ];
+    log::trace!("getting hardware id for input dev: {input_dev}");
+    let res = intrcptn.get_hardware_id(input_dev, &mut hwid);
+    let dev_is_interceptable = match mice {
+        InterceptMice::All => true,
+        InterceptMice::Hwids(hwids) => hwids.contains(&hwid),
+    };
+    log::info!("res {res}; device #{input_dev} hwid {hwid:?} matches allowed mouse input: {dev_is_interceptable}");
+    is_dev_interceptable.insert(input_dev, dev_is_interceptable);
+    dev_is_interceptable
+}
 
-    if state.contains(ic::MouseState::RIGHT_BUTTON_DOWN) {
-        Some(KeyEvent {
-            code: OsCode::BTN_RIGHT,
-            value: KeyValue::Press,
-        })
-    } else if state.contains(ic::MouseState::RIGHT_BUTTON_UP) {
-        Some(KeyEvent {
-            code: OsCode::BTN_RIGHT,
-            value: KeyValue::Release,
-        })
-    } else if state.contains(ic::MouseState::LEFT_BUTTON_DOWN) {
-        Some(KeyEvent {
-            code: OsCode::BTN_LEFT,
-            value: KeyValue::Press,
-        })
-    } else if state.contains(ic::MouseState::LEFT_BUTTON_UP) {
-        Some(KeyEvent {
-            code: OsCode::BTN_LEFT,
-            value: KeyValue::Release,
-        })
-    } else if state.contains(ic::MouseState::MIDDLE_BUTTON_DOWN) {
-        Some(KeyEvent {
-            code: OsCode::BTN_MIDDLE,
-            value: KeyValue::Press,
-        })
-    } else if state.contains(ic::MouseState::MIDDLE_BUTTON_UP) {
-        Some(KeyEvent {
-            code: OsCode::BTN_MIDDLE,
-            value: KeyValue::Release,
-        })
-    } else if state.contains(ic::MouseState::BUTTON_4_DOWN) {
-        Some(KeyEvent {
-            code: OsCode::BTN_SIDE,
-            value: KeyValue::Press,
-        })
-    } else if state.contains(ic::MouseState::BUTTON_4_UP) {
-        Some(KeyEvent {
-            code: OsCode::BTN_SIDE,
-            value: KeyValue::Release,
-        })
-    } else if state.contains(ic::MouseState::BUTTON_5_DOWN) {
-        Some(KeyEvent {
-            code: OsCode::BTN_EXTRA,
-            value: KeyValue::Press,
-        })
-    } else if state.contains(ic::MouseState::BUTTON_5_UP) {
-        Some(KeyEvent {
-            code: OsCode::BTN_EXTRA,
-            value: KeyValue::Release,
-        })
-    } else if state.contains(ic::MouseState::WHEEL) {
-        let osc = if rolling >= 0 {
-            OsCode::MouseWheelUp
-        } else {
-            OsCode::MouseWheelDown
-        };
-        if MAPPED_KEYS.lock().contains(&osc) {
-            Some(KeyEvent {
-                code: osc,
-                value: KeyValue::Tap,
-            })
-        } else {
-            None
+/// The button states of mouse strokes and the key events that they are read as.
+const MOUSE_BUTTONS: [(ic::MouseState, OsCode, KeyValue); 10] = [
+    (
+        ic::MouseState::LEFT_BUTTON_DOWN,
+        OsCode::BTN_LEFT,
+        KeyValue::Press,
+    ),
+    (
+        ic::MouseState::LEFT_BUTTON_UP,
+        OsCode::BTN_LEFT,
+        KeyValue::Release,
+    ),
+    (
+        ic::MouseState::RIGHT_BUTTON_DOWN,
+        OsCode::BTN_RIGHT,
+        KeyValue::Press,
+    ),
+    (
+        ic::MouseState::RIGHT_BUTTON_UP,
+        OsCode::BTN_RIGHT,
+        KeyValue::Release,
+    ),
+    (
+        ic::MouseState::MIDDLE_BUTTON_DOWN,
+        OsCode::BTN_MIDDLE,
+        KeyValue::Press,
+    ),
+    (
+        ic::MouseState::MIDDLE_BUTTON_UP,
+        OsCode::BTN_MIDDLE,
+        KeyValue::Release,
+    ),
+    (
+        ic::MouseState::BUTTON_4_DOWN,
+        OsCode::BTN_SIDE,
+        KeyValue::Press,
+    ),
+    (
+        ic::MouseState::BUTTON_4_UP,
+        OsCode::BTN_SIDE,
+        KeyValue::Release,
+    ),
+    (
+        ic::MouseState::BUTTON_5_DOWN,
+        OsCode::BTN_EXTRA,
+        KeyValue::Press,
+    ),
+    (
+        ic::MouseState::BUTTON_5_UP,
+        OsCode::BTN_EXTRA,
+        KeyValue::Release,
+    ),
+];
+
+/// Returns the `OsCode` of the stroke's scancode if the key is written as a scancode in `defsrc`,
+/// e.g. `sc-0x7D`.
+fn mapped_scancode_oscode(stroke: ic::Stroke) -> Option<OsCode> {
+    let ic::Stroke::Keyboard { code, state, .. } = stroke else {
+        return None;
+    };
+    let prefix = match state.contains(ic::KeyState::E0) {
+        true => 0xE000,
+        false => 0,
+    };
+    scancode_to_oscode(prefix | code as u16).filter(|osc| MAPPED_KEYS.lock().contains(osc))
+}
+
+/// Splits a mouse stroke into the events of its buttons and wheel that are mapped, and the rest of
+/// the stroke, e.g. unmapped buttons and movement, to pass through. The rest is None if nothing is
+/// left of the stroke.
+fn split_mouse_stroke(stroke: ic::Stroke) -> (Vec<KeyEvent>, Option<ic::Stroke>) {
+    let ic::Stroke::Mouse {
+        mut state,
+        flags,
+        mut rolling,
+        x,
+        y,
+        information,
+    } = stroke
+    else {
+        return (vec![], Some(stroke));
+    };
+    let mapped_keys = MAPPED_KEYS.lock();
+    let mut key_events = vec![];
+    for (button, osc, value) in MOUSE_BUTTONS {
+        if state.contains(button) && mapped_keys.contains(&osc) {
+            state.remove(button);
+            key_events.push(KeyEvent::new(osc, value));
+        }
+    }
+    for (wheel, positive, negative) in [
+        (
+            ic::MouseState::WHEEL,
+            OsCode::MouseWheelUp,
+            OsCode::MouseWheelDown,
+        ),
+        (
+            ic::MouseState::HWHEEL,
+            OsCode::MouseWheelRight,
+            OsCode::MouseWheelLeft,
+        ),
+    ] {
+        if !state.contains(wheel) {
+            continue;
         }
-    } else if state.contains(ic::MouseState::HWHEEL) {
-        let osc = if rolling >= 0 {
-            OsCode::MouseWheelRight
-        } else {
-            OsCode::MouseWheelLeft
-        };
-        if MAPPED_KEYS.lock().contains(&osc) {
-            Some(KeyEvent {
-                code: osc,
-                value: KeyValue::Tap,
-            })
-        } else {
-            None
+        let osc = if rolling >= 0 { positive } else { negative };
+        if mapped_keys.contains(&osc) {
+            state.remove(wheel);
+            rolling = 0;
+            key_events.push(KeyEvent::new(osc, KeyValue::Tap));
+        } else if INVERT_SCROLL.load(SeqCst) {
+            rolling = rolling.saturating_neg();
         }
-    } else {
-        None
     }
+    let rest = (!state.is_empty() || x != 0 || y != 0).then_some(ic::Stroke::Mouse {
+        state,
+        flags,
+        rolling,
+        x,
+        y,
+        information,
+    });
+    (key_events, rest)
 }
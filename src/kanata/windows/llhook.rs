@@ -4,13 +4,16 @@ use std::sync::mpsc::{sync_channel, Receiver, SyncSender as Sender, TryRecvError
 use std::sync::Arc;
 use std::time;
 
-use super::PRESSED_KEYS;
+use super::{is_paused_for, PRESSED_KEYS};
+
+/// Wheel distance of one notch.
+const WHEEL_DELTA: i32 = 120;
 use crate::kanata::*;
 
 impl Kanata {
     /// Initialize the callback that is passed to the Windows low level hook to receive key events
     /// and run the native_windows_gui event loop.
-    pub fn event_loop(_kanata: Arc<Mutex<Self>>, tx: Sender<KeyEvent>) -> Result<()> {
+    pub fn event_loop(kanata: Arc<Mutex<Self>>, tx: Sender<KeyEvent>) -> Result<()> {
         // Display debug and panic output when launched from a terminal.
         unsafe {
             use winapi::um::wincon::*;
@@ -19,23 +22,77 @@ impl Kanata {
             }
         };
         native_windows_gui::init()?;
+        scheduling::pin_current_thread("event loop", &kanata.lock().scheduling.event_loop_cpus);
 
         let (preprocess_tx, preprocess_rx) = sync_channel(100);
         start_event_preprocessor(preprocess_rx, tx);
 
+        // Wheel directions are handled like on Linux: only the directions in defsrc are taken
+        // from the OS and each one is sent as a tap per notch scrolled. Partial notches of
+        // high-resolution wheels are added up, per axis, until they reach a full notch.
+        let wheel_tx = preprocess_tx.clone();
+        let mut wheel_remainders = [0i32; 2];
+        let mut wheel_kbd_out = KbdOut::new()?;
+        let _mousehook = MouseHook::set_input_cb(move |wheel_event| {
+            let osc = match (wheel_event.horizontal, wheel_event.delta >= 0) {
+                (false, true) => OsCode::MouseWheelUp,
+                (false, false) => OsCode::MouseWheelDown,
+                (true, true) => OsCode::MouseWheelRight,
+                (true, false) => OsCode::MouseWheelLeft,
+            };
+            if !MAPPED_KEYS.lock().contains(&osc) {
+                if !INVERT_SCROLL.load(SeqCst) {
+                    return false;
+                }
+                // The OS cannot be told to scroll the other way, so the event is dropped and
+                // the inverted scroll is sent instead.
+                let direction = scroll_direction(osc.try_into().expect("wheel key"));
+                let _ = wheel_kbd_out.scroll(direction, wheel_event.delta.unsigned_abs());
+                return true;
+            }
+            let remainder = &mut wheel_remainders[usize::from(wheel_event.horizontal)];
+            let delta = i32::from(wheel_event.delta);
+            if remainder.signum() == -delta.signum() {
+                *remainder = 0;
+            }
+            *remainder += delta;
+            let notches = *remainder / WHEEL_DELTA;
+            *remainder -= notches * WHEEL_DELTA;
+            log::debug!("event loop: {osc:?} x{}", notches.unsigned_abs());
+            for _ in 0..notches.unsigned_abs() {
+                try_send_panic(&wheel_tx, KeyEvent::new(osc, KeyValue::Tap));
+            }
+            true
+        });
+
         // This callback should return `false` if the input event is **not** handled by the
         // callback and `true` if the input event **is** handled by the callback. Returning false
         // informs the callback caller that the input event should be handed back to the OS for
         // normal processing.
         let _kbhook = KeyboardHook::set_input_cb(move |input_event| {
+            // Kanata sends its own ralt, for which the OS adds the lctl again.
+            if input_event.is_altgr_lctl()
+                && *ALTGR_BEHAVIOUR.lock() == AltGrBehaviour::StripAltGrLctl
+                && MAPPED_KEYS.lock().contains(&OsCode::KEY_RIGHTALT)
+            {
+                log::debug!("altgr strip: dropping lctl of altgr");
+                return true;
+            }
+
             let mut key_event = match KeyEvent::try_from(input_event) {
                 Ok(ev) => ev,
                 _ => return false,
             };
 
+            // A key written as a scancode in defsrc takes precedence over its virtual key.
+            if let Some(osc) = scancode_to_oscode(input_event.scancode) {
+                if MAPPED_KEYS.lock().contains(&osc) {
+                    key_event.code = osc;
+                }
+            }
+
             check_for_exit(&key_event);
-            let oscode = OsCode::from(input_event.code);
-            if !MAPPED_KEYS.lock().contains(&oscode) {
+            if !MAPPED_KEYS.lock().contains(&key_event.code) || is_paused_for(&key_event) {
                 return false;
             }
 
@@ -66,10 +123,44 @@ impl Kanata {
             true
         });
 
-        // The event loop is also required for the low-level keyboard hook to work.
+        // The event loop is also required for the low-level hooks to work.
         native_windows_gui::dispatch_thread_events();
         Ok(())
     }
+
+    /// Release the keys that kanata thinks are held but that the OS reports as released.
+    ///
+    /// Releases can be missed when a UAC prompt or another secure desktop takes the input focus,
+    /// since the LLHOOK does not receive events while it is active. The OS does know the keys have
+    /// been released, so its view is used to correct the kanata state. If `kev` is a repeat of a
+    /// key that was released this way, it is turned back into a press since the hook only
+    /// classified it as a repeat due to the missed release.
+    pub fn reconcile_released_keys(&mut self, kev: &mut KeyEvent) {
+        let mut pressed_keys = PRESSED_KEYS.lock();
+        let mut released_inputs = vec![];
+        self.layout.bm().states.retain(|s| match s {
+            State::NormalKey {
+                keycode,
+                coord: (NORMAL_KEY_ROW, input),
+                ..
+            } => {
+                if os_key_is_pressed(keycode.into()) {
+                    return true;
+                }
+                log::info!("{keycode:?} was released outside of kanata, releasing it");
+                released_inputs.push(OsCode::from(*input));
+                false
+            }
+            _ => true,
+        });
+        for input in released_inputs {
+            if input == kev.code && kev.value == KeyValue::Repeat {
+                kev.value = KeyValue::Press;
+            } else {
+                pressed_keys.remove(&input);
+            }
+        }
+    }
 }
 
 fn try_send_panic(tx: &Sender<KeyEvent>, kev: KeyEvent) {
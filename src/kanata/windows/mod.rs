@@ -1,9 +1,9 @@
 use anyhow::{bail, Result};
 
 use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use crate::kanata::*;
-use kanata_parser::cfg;
 
 #[cfg(not(feature = "interception_driver"))]
 mod llhook;
@@ -15,32 +15,51 @@ mod interception;
 #[cfg(feature = "interception_driver")]
 pub use self::interception::*;
 
+#[cfg(feature = "gui")]
+mod tray;
+#[cfg(feature = "gui")]
+pub(crate) use tray::*;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AltGrBehaviour {
     DoNothing,
     CancelLctlPress,
     AddLctlRelease,
+    StripAltGrLctl,
 }
 
 static PRESSED_KEYS: Lazy<Mutex<HashSet<OsCode>>> = Lazy::new(|| Mutex::new(HashSet::default()));
 
+/// Set from the tray icon to pass input events to the OS instead of processing them.
+static PROCESSING_PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether the event should be passed to the OS because processing is paused. Releases of
+/// keys that were pressed before the pause are still processed so that they do not get stuck.
+fn is_paused_for(kev: &KeyEvent) -> bool {
+    PROCESSING_PAUSED.load(Ordering::SeqCst)
+        && !(kev.value == KeyValue::Release && PRESSED_KEYS.lock().contains(&kev.code))
+}
+
 pub static ALTGR_BEHAVIOUR: Lazy<Mutex<AltGrBehaviour>> =
     Lazy::new(|| Mutex::new(AltGrBehaviour::DoNothing));
 
-pub fn set_win_altgr_behaviour(cfg: &cfg::Cfg) -> Result<()> {
+pub fn set_win_altgr_behaviour(cfg_items: &HashMap<String, String>) -> Result<()> {
     *ALTGR_BEHAVIOUR.lock() = {
         const CANCEL: &str = "cancel-lctl-press";
         const ADD: &str = "add-lctl-release";
-        match cfg.items.get("windows-altgr") {
+        const STRIP: &str = "strip-altgr-lctl";
+        match cfg_items.get("windows-altgr") {
             None => AltGrBehaviour::DoNothing,
             Some(cfg_val) => match cfg_val.as_str() {
                 CANCEL => AltGrBehaviour::CancelLctlPress,
                 ADD => AltGrBehaviour::AddLctlRelease,
+                STRIP => AltGrBehaviour::StripAltGrLctl,
                 _ => bail!(
-                    "Invalid value for windows-altgr: {}. Valid values are {},{}",
+                    "Invalid value for windows-altgr: {}. Valid values are {},{},{}",
                     cfg_val,
                     CANCEL,
-                    ADD
+                    ADD,
+                    STRIP
                 ),
             },
         }
@@ -0,0 +1,133 @@
+//! Tray icon that shows the active layer in its tooltip, with menu entries to pause processing and
+//! to reload the configuration. The icon runs its own native_windows_gui event loop in a separate
+//! thread, so that it works with both the hook and Interception.
+
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+
+use anyhow::Result;
+use native_windows_gui as nwg;
+use parking_lot::Mutex;
+
+use super::PROCESSING_PAUSED;
+use crate::kanata::*;
+
+/// Used by the processing loop to show the active layer in the tray icon.
+pub(crate) struct TrayIcon {
+    layer: Arc<Mutex<String>>,
+    notice: nwg::NoticeSender,
+}
+
+impl TrayIcon {
+    /// Shows the layer in the tooltip of the icon.
+    pub(crate) fn set_layer(&self, name: &str) {
+        *self.layer.lock() = name.to_owned();
+        self.notice.notice();
+    }
+}
+
+impl Kanata {
+    /// Starts the thread of the tray icon.
+    pub fn start_tray_icon(kanata: Arc<Mutex<Self>>) {
+        let layer = {
+            let k = kanata.lock();
+            Arc::new(Mutex::new(
+                k.layer_info[k.layout.b().current_layer()].name.clone(),
+            ))
+        };
+        let (notice_tx, notice_rx) = channel();
+        let tray_layer = layer.clone();
+        let tray_kanata = kanata.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = run_tray_icon(tray_kanata, tray_layer, notice_tx) {
+                log::error!("tray icon failed: {e}");
+            }
+        });
+        match notice_rx.recv() {
+            Ok(notice) => kanata.lock().tray_icon = Some(TrayIcon { layer, notice }),
+            Err(_) => log::warn!("tray icon is not shown"),
+        }
+    }
+}
+
+fn tray_tip(layer: &str) -> String {
+    match PROCESSING_PAUSED.load(Ordering::SeqCst) {
+        true => format!("kanata: {layer} (paused)"),
+        false => format!("kanata: {layer}"),
+    }
+}
+
+fn run_tray_icon(
+    kanata: Arc<Mutex<Kanata>>,
+    layer: Arc<Mutex<String>>,
+    notice_tx: std::sync::mpsc::Sender<nwg::NoticeSender>,
+) -> Result<()> {
+    nwg::init()?;
+    let mut window = Default::default();
+    nwg::MessageWindow::builder().build(&mut window)?;
+    let mut icon = Default::default();
+    nwg::Icon::builder()
+        .source_system(Some(nwg::OemIcon::WinLogo))
+        .build(&mut icon)?;
+    let mut tray = Default::default();
+    nwg::TrayNotification::builder()
+        .parent(&window)
+        .icon(Some(&icon))
+        .tip(Some(&tray_tip(&layer.lock())))
+        .build(&mut tray)?;
+    let mut menu = Default::default();
+    nwg::Menu::builder()
+        .popup(true)
+        .parent(&window)
+        .build(&mut menu)?;
+    let mut pause_item = Default::default();
+    nwg::MenuItem::builder()
+        .text("Pause")
+        .parent(&menu)
+        .build(&mut pause_item)?;
+    let mut reload_item = Default::default();
+    nwg::MenuItem::builder()
+        .text("Reload configuration")
+        .parent(&menu)
+        .build(&mut reload_item)?;
+    let mut notice = Default::default();
+    nwg::Notice::builder().parent(&window).build(&mut notice)?;
+    let _ = notice_tx.send(notice.sender());
+
+    let handler = nwg::full_bind_event_handler(&window.handle, move |evt, _, handle| match evt {
+        nwg::Event::OnContextMenu if handle == tray.handle => {
+            let (x, y) = nwg::GlobalCursor::position();
+            menu.popup(x, y);
+        }
+        nwg::Event::OnMenuItemSelected if handle == pause_item.handle => {
+            let paused = !PROCESSING_PAUSED.load(Ordering::SeqCst);
+            PROCESSING_PAUSED.store(paused, Ordering::SeqCst);
+            pause_item.set_checked(paused);
+            log::info!(
+                "processing is {}",
+                if paused { "paused" } else { "resumed" }
+            );
+            tray.set_tip(&tray_tip(&layer.lock()));
+        }
+        nwg::Event::OnMenuItemSelected if handle == reload_item.handle => {
+            let mut k = kanata.lock();
+            // Reloading while keys are held is left to the processing loop, which waits for them
+            // to be released.
+            if k.prev_keys.is_empty() && k.cur_keys.is_empty() {
+                if let Err(e) = k.do_live_reload() {
+                    log::error!("live reload failed {e}");
+                }
+            } else {
+                k.live_reload_requested = true;
+            }
+        }
+        nwg::Event::OnNotice if handle == notice.handle => {
+            tray.set_tip(&tray_tip(&layer.lock()));
+        }
+        _ => {}
+    });
+    nwg::dispatch_thread_events();
+    nwg::unbind_event_handler(&handler);
+    Ok(())
+}
@@ -0,0 +1,83 @@
+//! Pool of threads for work that must not block the processing loop, such as running commands
+//! and sending HTTP requests.
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+use std::sync::mpsc::{channel, Receiver, Sender, SyncSender};
+use std::sync::Arc;
+
+use crate::oskbd::{KeyEvent, KeyValue};
+use kanata_parser::keys::OsCode;
+
+type Work = Box<dyn FnOnce() + Send>;
+
+/// Pool of threads that run work so that the processing loop never waits on it.
+///
+/// A new worker is only spawned when all existing workers are busy. Since commands can run for an
+/// arbitrarily long time, e.g. when launching an application, the pool is not bounded.
+struct Workers {
+    tx: Sender<Work>,
+    rx: Arc<Mutex<Receiver<Work>>>,
+    idle_count: Arc<AtomicUsize>,
+}
+
+static WORKERS: Lazy<Mutex<Workers>> = Lazy::new(|| {
+    let (tx, rx) = channel();
+    Mutex::new(Workers {
+        tx,
+        rx: Arc::new(Mutex::new(rx)),
+        idle_count: Arc::new(AtomicUsize::new(0)),
+    })
+});
+
+/// Channel of the processing loop, used to wake it up when work that it waits on is done.
+static WAKE_UP_TX: Mutex<Option<SyncSender<KeyEvent>>> = Mutex::new(None);
+
+impl Workers {
+    fn spawn_worker(&self) {
+        let rx = self.rx.clone();
+        let idle_count = self.idle_count.clone();
+        let spawn_result = std::thread::Builder::new()
+            .name("kanata-worker".into())
+            .spawn(move || loop {
+                idle_count.fetch_add(1, SeqCst);
+                let work = rx.lock().recv();
+                idle_count.fetch_sub(1, SeqCst);
+                match work {
+                    Ok(work) => work(),
+                    Err(_) => return,
+                }
+            });
+        if let Err(e) = spawn_result {
+            log::error!("failed to spawn worker: {e}");
+        }
+    }
+}
+
+/// Run the given work on a worker thread.
+pub(super) fn run_on_worker(work: impl FnOnce() + Send + 'static) {
+    let workers = WORKERS.lock();
+    if workers.idle_count.load(SeqCst) == 0 {
+        workers.spawn_worker();
+    }
+    if workers.tx.send(Box::new(work)).is_err() {
+        log::error!("workers are unavailable");
+    }
+}
+
+pub(super) fn set_wake_up_channel(tx: SyncSender<KeyEvent>) {
+    *WAKE_UP_TX.lock() = Some(tx);
+}
+
+/// Sends an event to the processing loop so that it handles the result of work that was done on a
+/// worker even if it was waiting for input.
+pub(super) fn wake_up_processing_loop() {
+    let Some(tx) = &*WAKE_UP_TX.lock() else {
+        return;
+    };
+    if let Err(e) = tx.try_send(KeyEvent::new(OsCode::KEY_RESERVED, KeyValue::WakeUp)) {
+        log::warn!("failed to wake up processing loop: {e:?}");
+    }
+}
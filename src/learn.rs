@@ -0,0 +1,192 @@
+//! Implements `kanata learn`: the user presses every key of their keyboard in order and a
+//! configuration is generated with a `defsrc` of the pressed keys and blank layers. Keys without a
+//! kanata name are given one with `deflocalkeys-linux`.
+
+// Keys are only read on Linux, other platforms report that learn is not supported.
+#![cfg_attr(not(target_os = "linux"), allow(dead_code))]
+
+use anyhow::{bail, Result};
+use std::path::Path;
+
+use kanata_parser::keys::*;
+
+const HEADER: &str = ";; generated by kanata learn";
+
+/// How long a key must be held to abort learning. Learning only uses presses, so a held key cannot
+/// be confused with learning a key.
+const ABORT_HOLD: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// What happened to a key press while learning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// The key was added to the current row.
+    Added,
+    /// The last key was pressed again and a new row was started.
+    NewRow,
+    /// The key was already learned and is not the first key, so it was ignored.
+    Duplicate,
+    /// The first key was pressed again; learning is done.
+    Done,
+}
+
+/// The keys learned so far, by row.
+#[derive(Debug, Default)]
+pub struct Learner {
+    rows: Vec<Vec<OsCode>>,
+}
+
+impl Learner {
+    pub fn press(&mut self, osc: OsCode) -> Step {
+        let first = self.rows.first().and_then(|row| row.first()).copied();
+        let last = self.rows.last().and_then(|row| row.last()).copied();
+        if last == Some(osc) {
+            if self.rows.last().is_some_and(|row| !row.is_empty()) {
+                self.rows.push(vec![]);
+            }
+            return Step::NewRow;
+        }
+        if first == Some(osc) {
+            self.rows.retain(|row| !row.is_empty());
+            return Step::Done;
+        }
+        if self.rows.iter().flatten().any(|&learned| learned == osc) {
+            return Step::Duplicate;
+        }
+        match self.rows.last_mut() {
+            Some(row) => row.push(osc),
+            None => self.rows.push(vec![osc]),
+        }
+        Step::Added
+    }
+
+    /// Returns the configuration with a `defsrc` of the learned keys and a blank `deflayer` for
+    /// every name in `layers`.
+    pub fn config(&self, layers: &[String]) -> String {
+        let names: Vec<Vec<String>> = self
+            .rows
+            .iter()
+            .map(|row| row.iter().map(|&osc| key_name(osc)).collect())
+            .collect();
+        let width = names.iter().flatten().map(|n| n.len()).max().unwrap_or(0);
+        let mut cfg = format!("{HEADER}\n\n");
+        let unnamed: Vec<OsCode> = self
+            .rows
+            .iter()
+            .flatten()
+            .copied()
+            .filter(|&osc| oscode_to_str(osc).is_none())
+            .collect();
+        if !unnamed.is_empty() {
+            cfg.push_str("(deflocalkeys-linux\n");
+            for osc in unnamed {
+                cfg.push_str(&format!("  {} {}\n", key_name(osc), osc.as_u16()));
+            }
+            cfg.push_str(")\n\n");
+        }
+        cfg.push_str("(defsrc\n");
+        for row in names.iter() {
+            push_row(&mut cfg, row.iter().map(String::as_str), width);
+        }
+        cfg.push_str(")\n");
+        for layer in layers {
+            cfg.push_str(&format!("\n(deflayer {layer}\n"));
+            for row in names.iter() {
+                push_row(&mut cfg, row.iter().map(|_| "_"), width);
+            }
+            cfg.push_str(")\n");
+        }
+        cfg
+    }
+}
+
+fn push_row<'a>(cfg: &mut String, row: impl Iterator<Item = &'a str>, width: usize) {
+    let row: Vec<String> = row.map(|name| format!("{name:width$}")).collect();
+    cfg.push_str("  ");
+    cfg.push_str(row.join(" ").trim_end());
+    cfg.push('\n');
+}
+
+fn key_name(osc: OsCode) -> String {
+    oscode_to_str(osc)
+        .map(str::to_owned)
+        .unwrap_or_else(|| format!("k{}", osc.as_u16()))
+}
+
+/// Reads key presses until the first key is pressed again and writes the configuration to
+/// `output`, or prints it if there is no output. Holding any key for `ABORT_HOLD` aborts without
+/// writing anything.
+#[cfg(target_os = "linux")]
+pub fn run(output: Option<&Path>, layers: &[String]) -> Result<()> {
+    use crate::oskbd::{event_instant, DeviceDetectMode, KbdIn, KeyEvent, KeyValue};
+
+    if let Some(output) = output {
+        if output.exists() {
+            bail!("{} already exists, not overwriting it", output.display());
+        }
+    }
+    // The devices are grabbed so that the presses do not reach other programs. Like when kanata
+    // starts, each device is only grabbed once its keys are released, e.g. the Enter key that
+    // started `kanata learn`.
+    let mut kbd_in = KbdIn::new(
+        &[],
+        false,
+        None,
+        None,
+        DeviceDetectMode::KeyboardOnly,
+        vec![],
+        false,
+    )
+    .map_err(|e| anyhow::anyhow!("failed to open keyboard device(s): {e}"))?;
+    log::info!(
+        "Press every key of your keyboard once, row by row from the top left. \
+        Press the last key again to start a new row. \
+        Press the first key again when you are done. \
+        Hold any key for {} seconds to abort.",
+        ABORT_HOLD.as_secs()
+    );
+    let mut learner = Learner::default();
+    let mut last_press = None;
+    'read: loop {
+        let (events, _) = kbd_in
+            .read()
+            .map_err(|e| anyhow::anyhow!("failed read: {e}"))?;
+        for event in events.iter().copied() {
+            let Ok(KeyEvent { code, value, .. }) = KeyEvent::try_from(event) else {
+                continue;
+            };
+            match value {
+                KeyValue::Press => last_press = Some((code, event_instant(&event))),
+                KeyValue::Repeat => match last_press {
+                    Some((key, pressed_at))
+                        if key == code && pressed_at.elapsed() >= ABORT_HOLD =>
+                    {
+                        bail!("aborted learning, {} was held", key_name(code))
+                    }
+                    _ => continue,
+                },
+                _ => continue,
+            }
+            match learner.press(code) {
+                Step::Added => log::info!("learned {}", key_name(code)),
+                Step::NewRow => log::info!("starting a new row"),
+                Step::Duplicate => log::warn!("{} was already learned", key_name(code)),
+                Step::Done => break 'read,
+            }
+        }
+    }
+    let cfg = learner.config(layers);
+    match output {
+        Some(output) => {
+            std::fs::write(output, cfg)
+                .map_err(|e| anyhow::anyhow!("failed to write {}: {e}", output.display()))?;
+            log::info!("wrote the learned configuration to {}", output.display());
+        }
+        None => println!("{cfg}"),
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn run(_output: Option<&Path>, _layers: &[String]) -> Result<()> {
+    bail!("kanata learn is only supported on Linux")
+}
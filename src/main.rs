@@ -3,7 +3,10 @@ use log::info;
 use simplelog::*;
 use std::path::PathBuf;
 
+mod check;
+mod doctor;
 mod kanata;
+mod learn;
 mod oskbd;
 mod tcp_server;
 
@@ -11,7 +14,7 @@ mod tcp_server;
 mod tests;
 
 use clap::Parser;
-use kanata::Kanata;
+use kanata::{Kanata, TypingRhythmOutput};
 use tcp_server::TcpServer;
 
 type CfgPath = PathBuf;
@@ -22,6 +25,11 @@ pub struct ValidatedArgs {
     #[cfg(target_os = "linux")]
     symlink_path: Option<String>,
     nodelay: bool,
+    check: bool,
+    doctor: bool,
+    /// The file to write the learned configuration to and the names of its layers, if learning.
+    learn: Option<(Option<PathBuf>, Vec<String>)>,
+    typing_rhythm: Option<TypingRhythmOutput>,
 }
 
 fn default_cfg() -> Vec<PathBuf> {
@@ -80,7 +88,8 @@ kanata.kbd in the current working directory and
     cfg: Option<Vec<PathBuf>>,
 
     /// Port to run the optional TCP server on. If blank, no TCP port will be
-    /// listened on.
+    /// listened on. On Linux, a socket passed by systemd socket activation
+    /// is listened on instead of the port.
     #[arg(short, long, verbatim_doc_comment)]
     port: Option<i32>,
 
@@ -102,6 +111,42 @@ kanata.kbd in the current working directory and
     /// issues on startup.
     #[arg(short, long)]
     nodelay: bool,
+
+    /// Parse the configuration file(s), run the tests defined with deftest,
+    /// and exit without grabbing any devices.
+    #[arg(long, verbatim_doc_comment)]
+    check: bool,
+
+    /// Export the time between key events, without the keys, for
+    /// typing rhythm analysis. The value is `tcp` to send the
+    /// timings to TCP clients, which requires --port, or the path
+    /// of a file to append the timings to.
+    #[arg(long, value_name = "tcp|FILE", verbatim_doc_comment)]
+    typing_rhythm: Option<TypingRhythmOutput>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug, PartialEq, Eq)]
+enum Command {
+    /// Check the permissions, drivers and session that kanata needs, and
+    /// print how to fix the problems found.
+    #[command(verbatim_doc_comment)]
+    Doctor,
+    /// Press every key of the keyboard in order to generate a configuration
+    /// with a defsrc of the pressed keys and blank layers. Hold any key for
+    /// 3 seconds to abort.
+    #[command(verbatim_doc_comment)]
+    Learn {
+        /// The file to write the configuration to. If not specified, the
+        /// configuration is printed.
+        #[arg(short, long, verbatim_doc_comment)]
+        output: Option<PathBuf>,
+        /// The names of the blank layers to generate, separated by commas.
+        #[arg(long, value_delimiter = ',', default_value = "base")]
+        layers: Vec<String>,
+    },
 }
 
 /// Parse CLI arguments and initialize logging.
@@ -133,12 +178,21 @@ fn cli_init() -> Result<ValidatedArgs> {
     log::info!("using LLHOOK+SendInput for keyboard IO");
     #[cfg(all(feature = "interception_driver", target_os = "windows"))]
     log::info!("using the Interception driver for keyboard IO");
+    #[cfg(target_os = "macos")]
+    log::info!("using a Quartz event tap and posted events for keyboard IO");
     log::info!(
         "You may forcefully exit kanata by pressing lctl+spc+esc at any time. \
                 These keys refer to defsrc input, meaning BEFORE kanata remaps keys."
     );
 
-    if let Some(config_file) = cfg_paths.first() {
+    let doctor = args.command == Some(Command::Doctor);
+    let learn = match args.command {
+        Some(Command::Learn { output, layers }) => Some((output, layers)),
+        _ => None,
+    };
+    if doctor || learn.is_some() {
+        // The configuration is not needed to diagnose the environment or to learn the keys.
+    } else if let Some(config_file) = cfg_paths.first() {
         if !config_file.exists() {
             bail!(
                 "Could not find the config file ({})\nFor more info, pass the `-h` or `--help` flags.",
@@ -149,17 +203,34 @@ fn cli_init() -> Result<ValidatedArgs> {
         bail!("No config files provided\nFor more info, pass the `-h` or `--help` flags.");
     }
 
+    if args.typing_rhythm == Some(TypingRhythmOutput::Tcp) && args.port.is_none() {
+        bail!("--typing-rhythm tcp requires the TCP server to be enabled with --port");
+    }
+
     Ok(ValidatedArgs {
         paths: cfg_paths,
         port: args.port,
         #[cfg(target_os = "linux")]
         symlink_path: args.symlink_path,
         nodelay: args.nodelay,
+        check: args.check,
+        doctor,
+        learn,
+        typing_rhythm: args.typing_rhythm,
     })
 }
 
 fn main_impl() -> Result<()> {
     let args = cli_init()?;
+    if args.check {
+        return check::check_cfgs(&args.paths);
+    }
+    if args.doctor {
+        return doctor::run();
+    }
+    if let Some((output, layers)) = &args.learn {
+        return learn::run(output.as_deref(), layers);
+    }
     let kanata_arc = Kanata::new_arc(&args)?;
 
     if !args.nodelay {
@@ -173,25 +244,47 @@ fn main_impl() -> Result<()> {
     // events, which it sends to the "processing loop". The processing loop handles keyboard events
     // while also maintaining `tick()` calls to keyberon.
 
-    let (server, ntx, nrx) = if let Some(port) = args.port {
-        let mut server = TcpServer::new(port);
-        server.start(kanata_arc.clone());
+    let (tx, rx) = std::sync::mpsc::sync_channel(100);
+
+    // A socket passed by systemd socket activation is used instead of --port.
+    #[cfg(target_os = "linux")]
+    let activated_listener = tcp_server::socket_activated_listener()?;
+    #[cfg(not(target_os = "linux"))]
+    let activated_listener = None;
+    let server = match (activated_listener, args.port) {
+        (Some(listener), _) => Some(TcpServer::from_listener(listener)),
+        (None, Some(port)) => Some(TcpServer::new(port)),
+        (None, None) => None,
+    };
+
+    let (server, ntx, nrx) = if let Some(mut server) = server {
+        server.start(kanata_arc.clone(), tx.clone());
         let (ntx, nrx) = std::sync::mpsc::sync_channel(100);
         (Some(server), Some(ntx), Some(nrx))
     } else {
         (None, None, None)
     };
 
-    let (tx, rx) = std::sync::mpsc::sync_channel(100);
-    Kanata::start_processing_loop(kanata_arc.clone(), rx, ntx, args.nodelay);
+    if let Some(ntx) = ntx.clone() {
+        #[cfg(not(target_os = "linux"))]
+        Kanata::start_mouse_position_loop(kanata_arc.clone(), ntx.clone());
+        Kanata::start_typing_stats_loop(kanata_arc.clone(), ntx);
+    }
+
+    Kanata::start_external_state_watcher(kanata_arc.clone());
+
+    #[cfg(target_os = "linux")]
+    Kanata::start_shutdown_watcher(kanata_arc.clone());
+
+    #[cfg(all(feature = "gui", target_os = "windows"))]
+    Kanata::start_tray_icon(kanata_arc.clone());
+
+    Kanata::start_processing_loop(kanata_arc.clone(), rx, tx.clone(), ntx, args.nodelay);
 
     if let (Some(server), Some(nrx)) = (server, nrx) {
         Kanata::start_notification_loop(nrx, server.connections);
     }
 
-    #[cfg(target_os = "linux")]
-    sd_notify::notify(true, &[sd_notify::NotifyState::Ready])?;
-
     Kanata::event_loop(kanata_arc, tx)?;
 
     Ok(())
@@ -1,42 +1,96 @@
 //! Contains the input/output code for keyboards on Linux.
 
-use evdev::{uinput, Device, EventType, InputEvent, RelativeAxisType};
+use evdev::{
+    uinput, AbsoluteAxisType, Device, EventType, InputEvent, MiscType, RelativeAxisType, SwitchType,
+};
 use inotify::{Inotify, WatchMask};
 use mio::{unix::SourceFd, Events, Interest, Poll, Token};
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use nix::ioctl_read_buf;
+use nix::poll::{PollFd, PollFlags};
+use parking_lot::Mutex;
 use rustc_hash::FxHashMap as HashMap;
-use signal_hook::{
-    consts::{SIGINT, SIGTERM},
-    iterator::Signals,
-};
 
+use std::collections::VecDeque;
 use std::convert::TryFrom;
 use std::fs;
 use std::io;
 use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
 
+use once_cell::sync::Lazy;
+
 use super::*;
 use crate::{kanata::CalculatedMouseMove, oskbd::KeyEvent};
 use kanata_parser::custom_action::*;
 use kanata_parser::keys::*;
 
 pub struct KbdIn {
-    devices: HashMap<Token, (Device, String)>,
+    /// The registered devices by their token in `poll`. Their file descriptors are non-blocking.
+    devices: HashMap<Token, (Device, Arc<InputDevice>)>,
     /// Some(_) if devices are explicitly listed, otherwise None.
     missing_device_paths: Option<Vec<String>>,
+    /// Waits for the devices and `inotify` to become ready.
     poll: Poll,
     events: Events,
+    /// The tokens of `events`, copied so that the sources can be read while iterating.
+    ready: Vec<Token>,
     token_counter: usize,
-    /// stored to prevent dropping
-    _inotify: Inotify,
+    /// Watches `/dev/input` for new devices.
+    inotify: Inotify,
+    /// Event buffers returned via `recycle`, reused to avoid allocating a new buffer for every
+    /// batch of events.
+    spare_bufs: Vec<Vec<InputEvent>>,
     include_names: Option<Vec<String>>,
     exclude_names: Option<Vec<String>>,
+    detect_mode: DeviceDetectMode,
+    /// Names or paths of devices from `linux-dev-priority`, highest priority first.
+    dev_priority: Vec<String>,
+    /// Batches of events that were read in the same cycle as a batch returned by `read`, in the
+    /// order that they will be returned.
+    pending: VecDeque<(Vec<InputEvent>, Arc<InputDevice>)>,
+    /// The key codes that each device holds, tracked if `RELEASE_KEYS_ON_REMOVAL` is set.
+    held_keys: HashMap<Token, Vec<u16>>,
+    /// Whether discovered devices with a lid or tablet-mode switch are read, set if a switch is in
+    /// `defsrc`.
+    observe_switches: bool,
+}
+
+const INOTIFY_TOKEN: Token = Token(0);
+/// Sources other than devices take the tokens below this one.
+const FIRST_DEVICE_TOKEN: usize = 1;
+
+/// From `linux-release-keys-on-device-removal`. If set, the keys that a device holds when it is
+/// removed are released as if the device had sent the releases.
+pub static RELEASE_KEYS_ON_REMOVAL: AtomicBool = AtomicBool::new(false);
+
+/// Identifies the physical device that a batch of events was read from.
+#[derive(Debug)]
+pub struct InputDevice {
+    pub name: String,
+    pub path: String,
+    /// Position of the device in `linux-dev-priority`. Devices that are not listed have the
+    /// lowest priority, i.e. the highest value.
+    pub priority: usize,
+    /// Receives the passthrough events of a device with absolute axes, e.g. a touchpad.
+    pub passthrough: Option<Mutex<AbsPassthrough>>,
+    /// False for a device that is only read for its switches, e.g. a lid switch. Its events reach
+    /// the rest of the system directly, so they must not be passed through.
+    pub grabbed: bool,
 }
 
-const INOTIFY_TOKEN_VALUE: usize = 0;
-const INOTIFY_TOKEN: Token = Token(INOTIFY_TOKEN_VALUE);
+/// The kinds of devices that are grabbed when devices are discovered rather than listed in
+/// `linux-dev`, from `linux-device-detect-mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceDetectMode {
+    KeyboardOnly,
+    KeyboardMice,
+    /// Any device with keys, buttons or relative axes.
+    Any,
+}
 
 impl KbdIn {
     pub fn new(
@@ -44,9 +98,10 @@ impl KbdIn {
         continue_if_no_devices: bool,
         include_names: Option<Vec<String>>,
         exclude_names: Option<Vec<String>>,
+        detect_mode: DeviceDetectMode,
+        dev_priority: Vec<String>,
+        observe_switches: bool,
     ) -> Result<Self, io::Error> {
-        let poll = Poll::new()?;
-
         let mut missing_device_paths = None;
         let devices = if !dev_paths.is_empty() {
             missing_device_paths = Some(vec![]);
@@ -55,7 +110,12 @@ impl KbdIn {
                 missing_device_paths.as_mut().expect("initialized"),
             )
         } else {
-            discover_devices(include_names.as_deref(), exclude_names.as_deref())
+            discover_devices(
+                include_names.as_deref(),
+                exclude_names.as_deref(),
+                detect_mode,
+                observe_switches,
+            )
         };
         if devices.is_empty() {
             if continue_if_no_devices {
@@ -67,25 +127,34 @@ impl KbdIn {
                 ));
             }
         }
-        let _inotify = watch_devinput().map_err(|e| {
+
+        let poll = Poll::new()?;
+        let inotify = watch_devinput().map_err(|e| {
             log::error!("failed to watch files: {e:?}");
             e
         })?;
         poll.registry().register(
-            &mut SourceFd(&_inotify.as_raw_fd()),
+            &mut SourceFd(&inotify.as_raw_fd()),
             INOTIFY_TOKEN,
             Interest::READABLE,
         )?;
 
         let mut kbdin = Self {
-            poll,
             missing_device_paths,
-            _inotify,
-            events: Events::with_capacity(32),
             devices: HashMap::default(),
-            token_counter: INOTIFY_TOKEN_VALUE + 1,
+            poll,
+            events: Events::with_capacity(32),
+            ready: vec![],
+            token_counter: FIRST_DEVICE_TOKEN,
+            inotify,
+            spare_bufs: vec![],
             include_names,
             exclude_names,
+            detect_mode,
+            dev_priority,
+            pending: VecDeque::new(),
+            held_keys: HashMap::default(),
+            observe_switches,
         };
 
         for (device, dev_path) in devices.into_iter() {
@@ -100,85 +169,207 @@ impl KbdIn {
         Ok(kbdin)
     }
 
+    /// Returns false for a device that is only read for its switches, e.g. a lid switch. It is not
+    /// grabbed so that the rest of the system still sees its events, e.g. to suspend when the lid
+    /// is closed.
+    fn should_grab(&self, dev: &Device) -> bool {
+        is_input_device(dev, self.detect_mode)
+            || !(self.observe_switches && has_mapped_switches(dev))
+    }
+
     fn register_device(&mut self, mut dev: Device, path: String) -> Result<(), io::Error> {
         log::info!("registering {path}: {:?}", dev.name().unwrap_or(""));
         wait_for_all_keys_unpressed(&dev)?;
-        // NOTE: This grab-ungrab-grab sequence magically fixes an issue with a Lenovo Yoga
-        // trackpad not working. No idea why this works.
-        dev.grab()?;
-        dev.ungrab()?;
-        dev.grab()?;
+        let grabbed = self.should_grab(&dev);
+        if grabbed {
+            // NOTE: This grab-ungrab-grab sequence magically fixes an issue with a Lenovo Yoga
+            // trackpad not working. No idea why this works.
+            dev.grab()?;
+            dev.ungrab()?;
+            dev.grab()?;
+        } else {
+            log::info!("reading {path} without grabbing it since it is only read for its switches");
+        }
 
-        let tok = Token(self.token_counter);
+        let name = dev.name().unwrap_or("");
+        let priority = self
+            .dev_priority
+            .iter()
+            .position(|dev| *dev == path || dev == name)
+            .unwrap_or(self.dev_priority.len());
+        let passthrough = match dev.supported_absolute_axes() {
+            Some(_) => Some(Mutex::new(AbsPassthrough::new(&dev)?)),
+            None => None,
+        };
+        let device = Arc::new(InputDevice {
+            name: name.to_owned(),
+            path: path.clone(),
+            priority,
+            passthrough,
+            grabbed,
+        });
+        // Switches that are already on, e.g. when kanata starts in tablet mode, are reported as
+        // pressed since the device only sends an event when they change.
+        let active_switches = active_switch_events(&dev);
+        if !active_switches.is_empty() {
+            self.pending.push_back((active_switches, device.clone()));
+        }
+        fcntl(dev.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        let token = Token(self.token_counter);
         self.token_counter += 1;
-        let fd = dev.as_raw_fd();
-        self.poll
-            .registry()
-            .register(&mut SourceFd(&fd), tok, Interest::READABLE)?;
-        self.devices.insert(tok, (dev, path));
+        self.poll.registry().register(
+            &mut SourceFd(&dev.as_raw_fd()),
+            token,
+            Interest::READABLE,
+        )?;
+        register_led_device(&path);
+        self.devices.insert(token, (dev, device));
         Ok(())
     }
 
-    pub fn read(&mut self) -> Result<Vec<InputEvent>, io::Error> {
-        let mut input_events = vec![];
+    /// Block until events are available from any registered device, then return the batch of
+    /// events that was read from that device along with the device itself. Device removals and
+    /// new files in `/dev/input` are handled while waiting.
+    ///
+    /// Batches from different devices that are read in the same cycle are returned in a
+    /// deterministic order: by hardware timestamp, then by device priority.
+    pub fn read(&mut self) -> Result<(Vec<InputEvent>, Arc<InputDevice>), io::Error> {
         loop {
+            if let Some(batch) = self.pending.pop_front() {
+                return Ok(batch);
+            }
             log::trace!("polling");
-
             if let Err(e) = self.poll.poll(&mut self.events, None) {
-                log::error!("failed poll: {:?}", e);
-                return Ok(vec![]);
-            }
-
-            let mut do_rediscover = false;
-            for event in &self.events {
-                if let Some((device, _)) = self.devices.get_mut(&event.token()) {
-                    if let Err(e) = device
-                        .fetch_events()
-                        .map(|evs| evs.into_iter().for_each(|ev| input_events.push(ev)))
-                    {
-                        // Currently the kind() is uncategorized... not helpful, need to match
-                        // on os error (19)
-                        match e.raw_os_error() {
-                            Some(19) => {
-                                self.poll
-                                    .registry()
-                                    .deregister(&mut SourceFd(&device.as_raw_fd()))?;
-                                if let Some((_, path)) = self.devices.remove(&event.token()) {
-                                    log::warn!("removing kbd device: {path}");
-                                    if let Some(ref mut missing) = self.missing_device_paths {
-                                        missing.push(path);
-                                    }
-                                }
-                            }
-                            _ => {
-                                log::error!("failed fetch events due to {e}, kind: {}", e.kind());
-                                return Err(e);
-                            }
-                        };
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                log::error!("failed poll: {e:?}");
+                return Err(e);
+            }
+            let mut ready = std::mem::take(&mut self.ready);
+            ready.extend(self.events.iter().map(|event| event.token()));
+            // Plugging in a device creates several files and changes their permissions, so many
+            // file watch events arrive together. Look for new devices only once for all of them.
+            let mut rediscover = false;
+            for token in ready.drain(..) {
+                match token {
+                    INOTIFY_TOKEN => {
+                        rediscover = true;
+                        self.drain_inotify();
                     }
-                } else if event.token() == INOTIFY_TOKEN {
-                    do_rediscover = true;
-                } else {
-                    panic!("encountered unexpected epoll event {event:?}");
+                    _ => self.read_device(token),
                 }
             }
-            if do_rediscover {
+            self.ready = ready;
+            if rediscover {
                 log::info!("watch found file changes, looking for new devices");
-                self.rediscover_devices()?;
+                self.rediscover_devices();
             }
-            if !input_events.is_empty() {
-                return Ok(input_events);
+            order_batches(self.pending.make_contiguous());
+        }
+    }
+
+    /// Reads every batch of events that is available from the device. A device that fails to be
+    /// read is removed, so that the other devices keep working.
+    fn read_device(&mut self, token: Token) {
+        let Some((dev, device)) = self.devices.get_mut(&token) else {
+            return;
+        };
+        let e = loop {
+            match dev.fetch_events() {
+                Ok(events) => {
+                    let mut buf = self.spare_bufs.pop().unwrap_or_default();
+                    buf.extend(events);
+                    if RELEASE_KEYS_ON_REMOVAL.load(Ordering::SeqCst) {
+                        track_held_keys(self.held_keys.entry(token).or_default(), &buf);
+                    }
+                    self.pending.push_back((buf, device.clone()));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => break e,
             }
+        };
+        // Currently the kind() is uncategorized... not helpful, need to match on os error (19)
+        match e.raw_os_error() {
+            Some(19) => log::warn!("removing kbd device: {}", device.path),
+            _ => log::error!(
+                "failed fetch events from {} due to {e}, kind: {}, removing it",
+                device.path,
+                e.kind()
+            ),
+        }
+        let held = self.held_keys.remove(&token).unwrap_or_default();
+        if !held.is_empty() && RELEASE_KEYS_ON_REMOVAL.load(Ordering::SeqCst) {
+            log::info!("releasing keys held by {}: {held:?}", device.path);
+            let mut buf = self.spare_bufs.pop().unwrap_or_default();
+            buf.extend(
+                held.iter()
+                    .map(|code| InputEvent::new(EventType::KEY, *code, 0)),
+            );
+            buf.push(InputEvent::new(EventType::SYNCHRONIZATION, 0, 0));
+            self.pending.push_back((buf, device.clone()));
+        }
+        self.remove_device(token);
+    }
+
+    fn remove_device(&mut self, token: Token) {
+        self.held_keys.remove(&token);
+        let Some((dev, device)) = self.devices.remove(&token) else {
+            return;
+        };
+        if let Err(e) = self
+            .poll
+            .registry()
+            .deregister(&mut SourceFd(&dev.as_raw_fd()))
+        {
+            log::warn!("failed to stop polling {}: {e}", device.path);
+        }
+        unregister_led_device(&device.path);
+        if let Some(ref mut missing) = self.missing_device_paths {
+            missing.push(device.path.clone());
         }
     }
 
-    fn rediscover_devices(&mut self) -> Result<(), io::Error> {
+    /// Reads the pending file watch events so that `inotify` is ready again on the next change.
+    fn drain_inotify(&mut self) {
+        let mut buf = [0u8; 1024];
+        loop {
+            match self.inotify.read_events(&mut buf) {
+                Ok(events) => {
+                    if events.count() == 0 {
+                        return;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    log::error!("failed to read file watch events: {e:?}");
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Give back a buffer previously returned by `read` so that it can be reused.
+    pub fn recycle(&mut self, mut events: Vec<InputEvent>) {
+        events.clear();
+        self.spare_bufs.push(events);
+    }
+
+    fn is_registered(&self, path: &str) -> bool {
+        self.devices.values().any(|(_, device)| device.path == path)
+    }
+
+    /// Registers devices that appeared since they were last looked for. A device that cannot be
+    /// registered is skipped so that it does not stop kanata; it is tried again on the next file
+    /// change, e.g. when udev gives access to it.
+    fn rediscover_devices(&mut self) {
         // This function is kinda ugly but the borrow checker doesn't like all this mutation.
         let mut paths_registered = vec![];
         if let Some(ref mut missing) = self.missing_device_paths {
             if missing.is_empty() {
                 log::info!("no devices are missing, doing nothing");
-                return Ok(());
+                return;
             }
             log::info!("checking for {missing:?}");
             let discovered_devices = missing
@@ -207,34 +398,283 @@ impl KbdIn {
         } else {
             log::info!("sleeping for a moment to let devices become ready");
             std::thread::sleep(std::time::Duration::from_millis(200));
-            discover_devices(self.include_names.as_deref(), self.exclude_names.as_deref())
-                .into_iter()
-                .try_for_each(|(dev, path)| {
-                    if !self
-                        .devices
-                        .values()
-                        .any(|(_, registered_path)| &path == registered_path)
-                    {
-                        self.register_device(dev, path)
-                    } else {
-                        Ok(())
-                    }
-                })?;
+            for (dev, path) in discover_devices(
+                self.include_names.as_deref(),
+                self.exclude_names.as_deref(),
+                self.detect_mode,
+                self.observe_switches,
+            ) {
+                if self.is_registered(&path) {
+                    continue;
+                }
+                if let Err(e) = self.register_device(dev, path.clone()) {
+                    log::warn!("found device {path} but could not register it {e:?}");
+                }
+            }
         }
-        Ok(())
     }
 }
 
-pub fn is_input_device(device: &Device) -> bool {
+/// The LEDs that kanata sets on the grabbed devices. The LED changes of the OS do not reach a
+/// grabbed device, so kanata sets them instead. Each bit of a mask is the LED whose `LedType` code
+/// is the index of the bit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LedCfg {
+    /// The LEDs that are set from the active layer, from `linux-layer-leds`.
+    pub layer_managed: u8,
+    /// The LEDs that are on in the active layer.
+    pub layer_on: u8,
+    /// Whether the lock LEDs that are not set from the active layer follow the lock keys that
+    /// kanata outputs, from `linux-lock-leds`.
+    pub track_locks: bool,
+}
+
+impl LedCfg {
+    /// Returns whether kanata sets any LEDs.
+    fn is_enabled(&self) -> bool {
+        self.layer_managed != 0 || self.track_locks
+    }
+}
+
+const LED_TYPES: [evdev::LedType; 3] = [
+    evdev::LedType::LED_NUML,
+    evdev::LedType::LED_CAPSL,
+    evdev::LedType::LED_SCROLLL,
+];
+
+pub const fn led_bit(led: evdev::LedType) -> u8 {
+    1 << led.0
+}
+
+#[derive(Default)]
+struct LedState {
+    cfg: LedCfg,
+    /// The lock LEDs that are on, read from the first device with LEDs and then toggled by the
+    /// lock keys that kanata outputs.
+    locks: Option<u8>,
+    /// Paths of the registered devices.
+    paths: Vec<String>,
+    /// Handles of the registered devices that have LEDs, only opened while kanata sets any LEDs.
+    /// They are separate from the handles of `KbdIn` so that the LEDs can be set from the
+    /// processing thread.
+    devices: Vec<(String, Device)>,
+}
+
+static LEDS: Lazy<Mutex<LedState>> = Lazy::new(|| Mutex::new(LedState::default()));
+
+impl LedState {
+    /// Returns the LEDs that kanata sets and which of them are on.
+    fn leds(&self) -> (u8, u8) {
+        let mut managed = self.cfg.layer_managed;
+        let mut on = self.cfg.layer_on & managed;
+        if let (true, Some(locks)) = (self.cfg.track_locks, self.locks) {
+            managed = LED_TYPES.iter().fold(managed, |m, &led| m | led_bit(led));
+            on |= locks & !self.cfg.layer_managed;
+        }
+        (managed, on)
+    }
+
+    fn write(&mut self, mask: u8, on: u8) {
+        for (path, dev) in self.devices.iter_mut() {
+            write_leds(dev, path, mask, on);
+        }
+    }
+
+    /// Opens another handle to a registered device to set its LEDs, if it has any.
+    fn open_device(&mut self, path: &str) {
+        let mut dev = match Device::open(path) {
+            Ok(dev) => dev,
+            Err(e) => {
+                log::warn!("could not open {path} to set its LEDs: {e}");
+                return;
+            }
+        };
+        if dev.supported_leds().is_none() {
+            return;
+        }
+        if self.locks.is_none() {
+            if let Ok(state) = dev.get_led_state() {
+                self.locks = Some(
+                    LED_TYPES
+                        .iter()
+                        .filter(|&&led| state.contains(led))
+                        .fold(0, |locks, &led| locks | led_bit(led)),
+                );
+            }
+        }
+        let (managed, on) = self.leds();
+        write_leds(&mut dev, path, managed, on);
+        self.devices.push((path.to_owned(), dev));
+    }
+}
+
+/// Sets the LEDs of the grabbed devices. LEDs that kanata no longer sets are turned off.
+pub fn set_led_cfg(cfg: LedCfg) {
+    let mut leds = LEDS.lock();
+    if leds.cfg == cfg {
+        return;
+    }
+    let was_enabled = leds.cfg.is_enabled();
+    let (prev_managed, _) = leds.leds();
+    leds.cfg = cfg;
+    let (managed, on) = leds.leds();
+    leds.write(prev_managed | managed, on);
+    if !cfg.is_enabled() {
+        leds.devices.clear();
+        leds.locks = None;
+    } else if !was_enabled {
+        for path in leds.paths.clone() {
+            leds.open_device(&path);
+        }
+    }
+}
+
+fn write_leds(dev: &mut Device, path: &str, mask: u8, on: u8) {
+    let Some(supported) = dev.supported_leds() else {
+        return;
+    };
+    let events = LED_TYPES
+        .iter()
+        .filter(|&&led| mask & led_bit(led) != 0 && supported.contains(led))
+        .map(|&led| InputEvent::new(EventType::LED, led.0, i32::from(on & led_bit(led) != 0)))
+        .collect::<Vec<_>>();
+    if events.is_empty() {
+        return;
+    }
+    if let Err(e) = dev.send_events(&events) {
+        log::warn!("could not set the LEDs of {path}: {e}");
+    }
+}
+
+/// Registers a device whose LEDs are set while kanata sets any LEDs.
+fn register_led_device(path: &str) {
+    let mut leds = LEDS.lock();
+    leds.paths.push(path.to_owned());
+    if leds.cfg.is_enabled() {
+        leds.open_device(path);
+    }
+}
+
+fn unregister_led_device(path: &str) {
+    let mut leds = LEDS.lock();
+    leds.paths.retain(|p| p != path);
+    leds.devices.retain(|(p, _)| p != path);
+}
+
+/// Toggles the tracked lock LEDs for the presses of lock keys in events that are written to the
+/// output device.
+fn track_lock_keys(events: &[InputEvent]) {
+    for event in events {
+        if event.event_type() != EventType::KEY || event.value() != 1 {
+            continue;
+        }
+        let led = match evdev::Key(event.code()) {
+            evdev::Key::KEY_CAPSLOCK => evdev::LedType::LED_CAPSL,
+            evdev::Key::KEY_NUMLOCK => evdev::LedType::LED_NUML,
+            evdev::Key::KEY_SCROLLLOCK => evdev::LedType::LED_SCROLLL,
+            _ => continue,
+        };
+        let mut leds = LEDS.lock();
+        let Some(locks) = leds.locks.as_mut() else {
+            continue;
+        };
+        *locks ^= led_bit(led);
+        let on = *locks;
+        if leds.cfg.track_locks && leds.cfg.layer_managed & led_bit(led) == 0 {
+            leds.write(led_bit(led), on);
+        }
+    }
+}
+
+/// Updates the key codes that a device holds from a batch of its events.
+fn track_held_keys(held: &mut Vec<u16>, events: &[InputEvent]) {
+    for event in events.iter().filter(|e| e.event_type() == EventType::KEY) {
+        match event.value() {
+            0 => held.retain(|code| *code != event.code()),
+            1 if !held.contains(&event.code()) => held.push(event.code()),
+            _ => {}
+        }
+    }
+}
+
+/// The switches that can be in `defsrc`.
+const MAPPED_SWITCHES: [SwitchType; 2] = [SwitchType::SW_LID, SwitchType::SW_TABLET_MODE];
+
+/// Returns whether the device has a switch that can be in `defsrc`.
+fn has_mapped_switches(dev: &Device) -> bool {
+    dev.supported_switches()
+        .is_some_and(|switches| MAPPED_SWITCHES.iter().any(|sw| switches.contains(*sw)))
+}
+
+/// Returns switch events for the switches of the device that are currently on, followed by a
+/// `SYN_REPORT`, or nothing if none are on.
+fn active_switch_events(dev: &Device) -> Vec<InputEvent> {
+    if !has_mapped_switches(dev) {
+        return vec![];
+    }
+    let state = match dev.get_switch_state() {
+        Ok(state) => state,
+        Err(e) => {
+            log::warn!("could not read the switches of {:?}: {e}", dev.name());
+            return vec![];
+        }
+    };
+    let mut events: Vec<_> = MAPPED_SWITCHES
+        .iter()
+        .filter(|sw| state.contains(**sw))
+        .map(|sw| InputEvent::new(EventType::SWITCH, sw.0, 1))
+        .collect();
+    if !events.is_empty() {
+        events.push(InputEvent::new(EventType::SYNCHRONIZATION, 0, 0));
+    }
+    events
+}
+
+/// Sorts batches of events from different devices by the hardware timestamp of their first event,
+/// then by device priority for batches with the same timestamp. The sort is stable, so the batches
+/// of one device keep their order.
+fn order_batches(batches: &mut [(Vec<InputEvent>, Arc<InputDevice>)]) {
+    batches.sort_by_key(|(events, device)| {
+        (
+            events.first().map(|event| event.timestamp()),
+            device.priority,
+        )
+    });
+}
+
+/// Name of the output device, `kanata` unless `linux-output-name` is set. Used so that kanata never
+/// grabs its own output device as an input device.
+static OUTPUT_DEVICE_NAME: Lazy<Mutex<String>> = Lazy::new(|| Mutex::new("kanata".to_owned()));
+
+/// Name of the mirror output device, if one exists. Used so that kanata never grabs its own mirror
+/// device as an input device.
+static MIRROR_DEVICE_NAME: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+
+pub fn is_input_device(device: &Device, detect_mode: DeviceDetectMode) -> bool {
     use evdev::Key;
     let is_keyboard = device
         .supported_keys()
         .map_or(false, |keys| keys.contains(Key::KEY_ENTER));
     let is_mouse = device
         .supported_relative_axes()
-        .map_or(false, |axes| axes.contains(RelativeAxisType::REL_X));
-    if is_keyboard || is_mouse {
-        if device.name() == Some("kanata") {
+        .map_or(false, |axes| axes.contains(RelativeAxisType::REL_X))
+        || is_touchpad(device);
+    let is_detected = match detect_mode {
+        DeviceDetectMode::KeyboardOnly => is_keyboard,
+        DeviceDetectMode::KeyboardMice => is_keyboard || is_mouse,
+        DeviceDetectMode::Any => {
+            device.supported_keys().is_some() || device.supported_relative_axes().is_some()
+        }
+    };
+    if is_detected {
+        let name = device.name();
+        if name == Some(OUTPUT_DEVICE_NAME.lock().as_str())
+            || name == Some(MOUSE_DEVICE_NAME)
+            || name
+                .map(|name| name.ends_with(PASSTHROUGH_DEVICE_SUFFIX))
+                .unwrap_or(false)
+            || (name.is_some() && name == MIRROR_DEVICE_NAME.lock().as_deref())
+        {
             return false;
         }
         log::debug!(
@@ -243,8 +683,10 @@ pub fn is_input_device(device: &Device) -> bool {
                 "Keyboard/Mouse"
             } else if is_keyboard {
                 "Keyboard"
-            } else {
+            } else if is_mouse {
                 "Mouse"
+            } else {
+                "other input device"
             },
             device.name().unwrap_or("unknown device name"),
             device.physical_path()
@@ -259,15 +701,35 @@ pub fn is_input_device(device: &Device) -> bool {
     }
 }
 
+/// Returns true for touchpads, which are pointing devices like mice but report the positions of
+/// fingers as absolute axes.
+fn is_touchpad(device: &Device) -> bool {
+    device.properties().contains(evdev::PropType::POINTER)
+        && device
+            .supported_absolute_axes()
+            .map(|axes| axes.contains(AbsoluteAxisType::ABS_MT_POSITION_X))
+            .unwrap_or(false)
+}
+
+/// Returns when the input device produced the event. The kernel timestamps events with the wall
+/// clock, so the time is converted by subtracting the age of the event from the current instant.
+pub fn event_instant(event: &InputEvent) -> std::time::Instant {
+    let age = std::time::SystemTime::now()
+        .duration_since(event.timestamp())
+        .unwrap_or_default();
+    let now = std::time::Instant::now();
+    now.checked_sub(age).unwrap_or(now)
+}
+
 impl TryFrom<InputEvent> for KeyEvent {
     type Error = ();
     fn try_from(item: InputEvent) -> Result<Self, Self::Error> {
         use OsCode::*;
         match item.kind() {
-            evdev::InputEventKind::Key(k) => Ok(Self {
-                code: OsCode::from_u16(k.0).ok_or(())?,
-                value: KeyValue::from(item.value()),
-            }),
+            evdev::InputEventKind::Key(k) => Ok(Self::new(
+                OsCode::from_u16(k.0).ok_or(())?,
+                KeyValue::from(item.value()),
+            )),
             evdev::InputEventKind::RelAxis(axis_type) => {
                 let dist = item.value();
                 let code: OsCode = match axis_type {
@@ -287,10 +749,15 @@ impl TryFrom<InputEvent> for KeyEvent {
                     }
                     _ => return Err(()),
                 };
-                Ok(KeyEvent {
-                    code,
-                    value: KeyValue::Tap,
-                })
+                Ok(KeyEvent::new(code, KeyValue::Tap))
+            }
+            evdev::InputEventKind::Switch(switch) => {
+                let code = match switch {
+                    SwitchType::SW_LID => SwitchLid,
+                    SwitchType::SW_TABLET_MODE => SwitchTabletMode,
+                    _ => return Err(()),
+                };
+                Ok(KeyEvent::new(code, KeyValue::from(item.value())))
             }
             _ => Err(()),
         }
@@ -314,28 +781,87 @@ pub enum UnicodeTermination {
 use std::cell::Cell;
 
 pub struct KbdOut {
-    device: uinput::VirtualDevice,
-    accumulated_scroll: u16,
-    accumulated_hscroll: u16,
+    out: Arc<Mutex<OutDevice>>,
+    identity: OutputIdentity,
     #[allow(dead_code)] // stored here for persistence+cleanup on exit
     symlink: Option<Symlink>,
-    raw_buf: Vec<InputEvent>,
     pub unicode_termination: Cell<UnicodeTermination>,
     pub unicode_u_code: Cell<OsCode>,
 }
 
 pub const HI_RES_SCROLL_UNITS_IN_LO_RES: u16 = 120;
 
-impl KbdOut {
-    pub fn new(symlink_path: &Option<String>) -> Result<Self, io::Error> {
-        // Support pretty much every feature of a Keyboard or a Mouse in a VirtualDevice so that no event from the original input devices gets lost
-        // TODO investigate the rare possibility that a device is e.g. a Joystick and a Keyboard or a Mouse at the same time, which could lead to lost events
+/// Name of the output device that receives mouse events when `linux-output-separate-mouse` is
+/// enabled.
+const MOUSE_DEVICE_NAME: &str = "kanata-mouse";
 
-        // For some reason 0..0x300 (max value for a key) doesn't work, the closest that I've got to work is 560
-        let keys = evdev::AttributeSet::from_iter((0..560).map(evdev::Key));
-        let relative_axes = evdev::AttributeSet::from_iter([
-            RelativeAxisType::REL_WHEEL,
-            RelativeAxisType::REL_HWHEEL,
+/// Key codes of the mouse buttons, `BTN_LEFT` through `BTN_TASK`. When the output is split, these
+/// are sent to the mouse device instead of the keyboard device.
+const MOUSE_BUTTONS: std::ops::Range<u16> = 0x110..0x118;
+
+/// Returns true if the event is sent to the mouse device when the output is split.
+fn is_mouse_event(event: &InputEvent) -> bool {
+    match event.event_type() {
+        EventType::RELATIVE => true,
+        EventType::KEY => MOUSE_BUTTONS.contains(&event.code()),
+        _ => false,
+    }
+}
+
+/// The identity of the output device and the events that it advertises, from `linux-output-name`,
+/// `linux-output-id`, `linux-output-bus` and `linux-output-events`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputIdentity {
+    pub name: String,
+    pub vendor: u16,
+    pub product: u16,
+    pub bus: evdev::BusType,
+    pub events: OutputEvents,
+}
+
+impl Default for OutputIdentity {
+    fn default() -> Self {
+        Self {
+            name: "kanata".to_owned(),
+            vendor: 1,
+            product: 1,
+            bus: evdev::BusType::BUS_USB,
+            events: OutputEvents::default(),
+        }
+    }
+}
+
+/// The kinds of events that the output devices advertise. Events of other kinds are dropped by
+/// the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputEvents {
+    pub keys: bool,
+    pub mouse_buttons: bool,
+    pub mouse_motion: bool,
+    pub mouse_wheel: bool,
+}
+
+impl Default for OutputEvents {
+    fn default() -> Self {
+        Self {
+            keys: true,
+            mouse_buttons: true,
+            mouse_motion: true,
+            mouse_wheel: true,
+        }
+    }
+}
+
+impl OutputEvents {
+    fn advertises_key(&self, code: u16) -> bool {
+        match MOUSE_BUTTONS.contains(&code) {
+            true => self.mouse_buttons,
+            false => self.keys,
+        }
+    }
+
+    fn relative_axes(&self) -> evdev::AttributeSet<RelativeAxisType> {
+        let motion = [
             RelativeAxisType::REL_X,
             RelativeAxisType::REL_Y,
             RelativeAxisType::REL_Z,
@@ -344,16 +870,189 @@ impl KbdOut {
             RelativeAxisType::REL_RZ,
             RelativeAxisType::REL_DIAL,
             RelativeAxisType::REL_MISC,
+        ];
+        let wheel = [
+            RelativeAxisType::REL_WHEEL,
+            RelativeAxisType::REL_HWHEEL,
             RelativeAxisType::REL_WHEEL_HI_RES,
             RelativeAxisType::REL_HWHEEL_HI_RES,
-        ]);
-
-        let mut device = uinput::VirtualDeviceBuilder::new()?
-            .name("kanata")
-            .input_id(evdev::InputId::new(evdev::BusType::BUS_USB, 1, 1, 1))
-            .with_keys(&keys)?
-            .with_relative_axes(&relative_axes)?
-            .build()?;
+        ];
+        let motion = motion.iter().filter(|_| self.mouse_motion);
+        let wheel = wheel.iter().filter(|_| self.mouse_wheel);
+        evdev::AttributeSet::from_iter(motion.chain(wheel).copied())
+    }
+}
+
+/// Create a virtual device that supports every event that kanata may output.
+fn new_virtual_device(
+    identity: &OutputIdentity,
+    name: &str,
+) -> Result<uinput::VirtualDevice, io::Error> {
+    // Support pretty much every feature of a Keyboard or a Mouse in a VirtualDevice so that no event from the original input devices gets lost
+    // TODO investigate the rare possibility that a device is e.g. a Joystick and a Keyboard or a Mouse at the same time, which could lead to lost events
+
+    // For some reason 0..0x300 (max value for a key) doesn't work, the closest that I've got to work is 560
+    let input_id = evdev::InputId::new(identity.bus, identity.vendor, identity.product, 1);
+    build_virtual_device(name, input_id, &identity.events, 0..560, true, true)
+}
+
+/// Create the keyboard device of a split output: every key except the mouse buttons, and no
+/// relative axes, along with the keys that `output-device` actions send through it.
+fn new_keyboard_device(
+    identity: &OutputIdentity,
+    extra_keys: &[u16],
+) -> Result<uinput::VirtualDevice, io::Error> {
+    let keys = (0..560)
+        .filter(|code| !MOUSE_BUTTONS.contains(code))
+        .chain(extra_keys.iter().copied());
+    let input_id = evdev::InputId::new(identity.bus, identity.vendor, identity.product, 1);
+    build_virtual_device(
+        &identity.name,
+        input_id,
+        &identity.events,
+        keys,
+        false,
+        true,
+    )
+}
+
+/// Create the mouse device of a split output: the mouse buttons and relative axes, along with the
+/// keys that `output-device` actions send through it. Only the bus type and the events of the
+/// identity apply to it, so that it is not mistaken for the keyboard device.
+fn new_mouse_device(
+    identity: &OutputIdentity,
+    extra_keys: &[u16],
+) -> Result<uinput::VirtualDevice, io::Error> {
+    let keys = MOUSE_BUTTONS.chain(extra_keys.iter().copied());
+    let input_id = evdev::InputId::new(identity.bus, 1, 2, 1);
+    build_virtual_device(
+        MOUSE_DEVICE_NAME,
+        input_id,
+        &identity.events,
+        keys,
+        true,
+        false,
+    )
+}
+
+/// Name of the output device that receives gamepad events when `linux-output-gamepad` is enabled.
+const GAMEPAD_DEVICE_NAME: &str = "kanata-gamepad";
+
+/// Axes of the gamepad device, indexed by their `GamepadAxis` discriminant.
+const GAMEPAD_AXES: [(GamepadAxis, AbsoluteAxisType); 8] = [
+    (GamepadAxis::LeftX, AbsoluteAxisType::ABS_X),
+    (GamepadAxis::LeftY, AbsoluteAxisType::ABS_Y),
+    (GamepadAxis::RightX, AbsoluteAxisType::ABS_RX),
+    (GamepadAxis::RightY, AbsoluteAxisType::ABS_RY),
+    (GamepadAxis::LeftTrigger, AbsoluteAxisType::ABS_Z),
+    (GamepadAxis::RightTrigger, AbsoluteAxisType::ABS_RZ),
+    (GamepadAxis::DpadX, AbsoluteAxisType::ABS_HAT0X),
+    (GamepadAxis::DpadY, AbsoluteAxisType::ABS_HAT0Y),
+];
+
+fn gamepad_button_key(button: GamepadButton) -> evdev::Key {
+    use evdev::Key;
+    use GamepadButton::*;
+    match button {
+        South => Key::BTN_SOUTH,
+        East => Key::BTN_EAST,
+        North => Key::BTN_NORTH,
+        West => Key::BTN_WEST,
+        TL => Key::BTN_TL,
+        TR => Key::BTN_TR,
+        TL2 => Key::BTN_TL2,
+        TR2 => Key::BTN_TR2,
+        Select => Key::BTN_SELECT,
+        Start => Key::BTN_START,
+        Mode => Key::BTN_MODE,
+        ThumbL => Key::BTN_THUMBL,
+        ThumbR => Key::BTN_THUMBR,
+        DpadUp => Key::BTN_DPAD_UP,
+        DpadDown => Key::BTN_DPAD_DOWN,
+        DpadLeft => Key::BTN_DPAD_LEFT,
+        DpadRight => Key::BTN_DPAD_RIGHT,
+    }
+}
+
+/// Create the gamepad device: the buttons from `BTN_SOUTH` through `BTN_THUMBR`, the d-pad
+/// buttons, and the absolute axes of `GAMEPAD_AXES`.
+fn new_gamepad_device() -> Result<uinput::VirtualDevice, io::Error> {
+    let keys = evdev::AttributeSet::from_iter(
+        (evdev::Key::BTN_SOUTH.code()..=evdev::Key::BTN_THUMBR.code())
+            .chain(evdev::Key::BTN_DPAD_UP.code()..=evdev::Key::BTN_DPAD_RIGHT.code())
+            .map(evdev::Key),
+    );
+    let mut builder = uinput::VirtualDeviceBuilder::new()?
+        .name(GAMEPAD_DEVICE_NAME)
+        .input_id(evdev::InputId::new(evdev::BusType::BUS_USB, 1, 3, 1))
+        .with_keys(&keys)?;
+    for (axis, abs) in GAMEPAD_AXES {
+        let (min, max) = axis.range();
+        builder = builder.with_absolute_axis(&evdev::UinputAbsSetup::new(
+            abs,
+            evdev::AbsInfo::new(0, min, max, 0, 0, 0),
+        ))?;
+    }
+    builder.build()
+}
+
+fn build_virtual_device(
+    name: &str,
+    input_id: evdev::InputId,
+    events: &OutputEvents,
+    keys: impl Iterator<Item = u16>,
+    with_relative_axes: bool,
+    with_switches: bool,
+) -> Result<uinput::VirtualDevice, io::Error> {
+    let keys = evdev::AttributeSet::from_iter(
+        keys.filter(|code| events.advertises_key(*code))
+            .map(evdev::Key),
+    );
+    let relative_axes = events.relative_axes();
+
+    let mut builder = uinput::VirtualDeviceBuilder::new()?
+        .name(name)
+        .input_id(input_id)
+        .with_keys(&keys)?;
+    if with_switches {
+        // The switches of grabbed devices are passed through, e.g. of a keyboard that also has a
+        // tablet-mode switch.
+        builder = builder.with_switches(&evdev::AttributeSet::from_iter(MAPPED_SWITCHES))?;
+    }
+    if with_relative_axes && relative_axes.iter().next().is_some() {
+        builder.with_relative_axes(&relative_axes)?.build()
+    } else {
+        builder.build()
+    }
+}
+
+impl KbdOut {
+    /// Create the output device. If `separate_mouse` is true, mouse events are sent through a
+    /// second device and each device only supports its own events, plus the keys that
+    /// `output-device` actions send through it.
+    pub fn new(
+        symlink_path: &Option<String>,
+        identity: OutputIdentity,
+        separate_mouse: bool,
+        output_device_keys: &[(OutputDevice, OsCode)],
+    ) -> Result<Self, io::Error> {
+        let extra_keys = |target: OutputDevice| {
+            output_device_keys
+                .iter()
+                .filter(|(device, _)| *device == target)
+                .map(|(_, key)| u16::from(*key))
+                .collect::<Vec<_>>()
+        };
+        *OUTPUT_DEVICE_NAME.lock() = identity.name.clone();
+        let (mut device, mouse) = match separate_mouse {
+            true => {
+                let device = new_keyboard_device(&identity, &extra_keys(OutputDevice::Keyboard))?;
+                let mouse = new_mouse_device(&identity, &extra_keys(OutputDevice::Mouse))?;
+                log::info!("Created separate mouse output device {MOUSE_DEVICE_NAME}");
+                (device, Some(mouse))
+            }
+            false => (new_virtual_device(&identity, &identity.name)?, None),
+        };
         let devnode = device
             .enumerate_dev_nodes_blocking()?
             .next() // Expect only one. Using fold or calling next again blocks indefinitely
@@ -361,19 +1060,23 @@ impl KbdOut {
         log::info!("Created device {:#?}", devnode);
         let symlink = if let Some(symlink_path) = symlink_path {
             let dest = PathBuf::from(symlink_path);
-            let symlink = Symlink::new(devnode, dest)?;
-            Symlink::clean_when_killed(symlink.clone());
-            Some(symlink)
+            Some(Symlink::new(devnode, dest)?)
         } else {
             None
         };
 
         Ok(KbdOut {
-            device,
-            accumulated_scroll: 0,
-            accumulated_hscroll: 0,
+            out: Arc::new(Mutex::new(OutDevice {
+                device,
+                mouse,
+                gamepad: None,
+                gamepad_axes: [0; GAMEPAD_AXES.len()],
+                mirror: None,
+                accumulated_scroll: 0,
+                accumulated_hscroll: 0,
+            })),
+            identity,
             symlink,
-            raw_buf: vec![],
 
             // historically was the only option, so make Enter the default
             unicode_termination: Cell::new(UnicodeTermination::Enter),
@@ -391,41 +1094,132 @@ impl KbdOut {
         self.unicode_u_code.replace(u);
     }
 
-    pub fn write_raw(&mut self, event: InputEvent) -> Result<(), io::Error> {
-        if event.event_type() == EventType::SYNCHRONIZATION {
-            // Possible codes are:
-            //
-            // SYN_REPORT: probably the only one we'll ever receive, segments atomic reads
-            // SYN_CONFIG: unused
-            // SYN_MT_REPORT: same as SYN_REPORT above but for touch devices, which kanata almost
-            //     certainly shouldn't be dealing with.
-            // SYN_DROPPED: buffer full, events dropped. Not sure what this means or how to handle
-            //     this correctly.
-            //
-            // With this knowledge, seems fine to not bother checking.
-            self.device.emit(&self.raw_buf)?;
-            self.raw_buf.clear();
-        } else {
-            self.raw_buf.push(event);
+    /// Create, rename, or remove the device that receives a copy of the output key events.
+    pub fn update_mirror(&self, name: Option<&str>) -> Result<(), io::Error> {
+        let mut mirror_name = MIRROR_DEVICE_NAME.lock();
+        if mirror_name.as_deref() == name {
+            return Ok(());
+        }
+        let mut out = self.out.lock();
+        out.mirror = None;
+        *mirror_name = None;
+        if let Some(name) = name {
+            if name == self.identity.name {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the mirror device name must differ from the output device name",
+                ));
+            }
+            // Set the name before creating the device so that device discovery racing with this
+            // never grabs the mirror.
+            *mirror_name = Some(name.to_owned());
+            match MirrorDevice::new(&self.identity, name) {
+                Ok(mirror) => out.mirror = Some(mirror),
+                Err(e) => {
+                    *mirror_name = None;
+                    return Err(e);
+                }
+            }
+            log::info!("Created output mirror device {name}");
         }
         Ok(())
     }
 
-    pub fn write(&mut self, event: InputEvent) -> Result<(), io::Error> {
-        if !self.raw_buf.is_empty() {
-            self.device.emit(&self.raw_buf)?;
-            self.raw_buf.clear();
+    /// Verify that the output device can emit events by tapping a key and reading it back from the
+    /// device's own node. The node is grabbed during the test so that no other program receives
+    /// the key.
+    pub fn self_test(&self) -> Result<(), io::Error> {
+        const TIMEOUT_MS: i32 = 1000;
+        let key = evdev::Key::KEY_UNKNOWN.code();
+        let mut out = self.out.lock();
+        let devnode = out
+            .device
+            .enumerate_dev_nodes_blocking()?
+            .next()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "the output device has no node in /dev/input",
+                )
+            })??;
+        // The node may not exist yet if udev has not handled the new device.
+        let mut tries = 0;
+        let mut reader = loop {
+            match Device::open(&devnode) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound && tries < 10 => {
+                    tries += 1;
+                    thread::sleep(std::time::Duration::from_millis(100));
+                }
+                result => break result,
+            }
+        }
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "could not open {} to read back events: {e}. \
+                     Make sure the user executing kanata is in the `input` group",
+                    devnode.display()
+                ),
+            )
+        })?;
+        reader.grab()?;
+        out.device.emit(&[
+            InputEvent::new(EventType::KEY, key, 1),
+            InputEvent::new(EventType::KEY, key, 0),
+        ])?;
+        let mut poll_fds = [PollFd::new(reader.as_raw_fd(), PollFlags::POLLIN)];
+        let ready = nix::poll::poll(&mut poll_fds, TIMEOUT_MS)?;
+        let read_back = ready > 0
+            && reader
+                .fetch_events()?
+                .any(|ev| ev.event_type() == EventType::KEY && ev.code() == key);
+        // Closing the node releases the grab.
+        drop(reader);
+        if !read_back {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "no events were read back from {} within {TIMEOUT_MS}ms",
+                    devnode.display()
+                ),
+            ));
         }
-        self.device.emit(&[event])?;
         Ok(())
     }
 
-    pub fn write_many(&mut self, events: &[InputEvent]) -> Result<(), io::Error> {
-        if !self.raw_buf.is_empty() {
-            self.device.emit(&self.raw_buf)?;
-            self.raw_buf.clear();
+    /// Create or remove the gamepad device.
+    pub fn update_gamepad(&self, enabled: bool) -> Result<(), io::Error> {
+        let mut out = self.out.lock();
+        match (enabled, out.gamepad.is_some()) {
+            (true, false) => {
+                out.gamepad = Some(new_gamepad_device()?);
+                out.gamepad_axes = [0; GAMEPAD_AXES.len()];
+                log::info!("Created gamepad output device {GAMEPAD_DEVICE_NAME}");
+            }
+            (false, true) => out.gamepad = None,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Returns a handle that writes to the same output device but can be used without access to
+    /// this `KbdOut`. This lets the event loop pass through unmapped events without waiting for
+    /// the processing loop to release the kanata state.
+    pub fn raw_writer(&self) -> KbdOutRaw {
+        KbdOutRaw {
+            out: self.out.clone(),
+            raw_buf: vec![],
         }
-        self.device.emit(events)?;
+    }
+
+    pub fn write(&mut self, event: InputEvent) -> Result<(), io::Error> {
+        self.out.lock().emit(&[event])?;
+        Ok(())
+    }
+
+    pub fn write_many(&mut self, events: &[InputEvent]) -> Result<(), io::Error> {
+        self.out.lock().emit(events)?;
         Ok(())
     }
 
@@ -433,16 +1227,61 @@ impl KbdOut {
         let key_ev = KeyEvent::new(key, value);
         let input_ev = key_ev.into();
         log::debug!("send to uinput: {:?}", input_ev);
-        self.device.emit(&[input_ev])?;
+        self.out.lock().emit(&[input_ev])?;
         Ok(())
     }
 
     pub fn write_code(&mut self, code: u32, value: KeyValue) -> Result<(), io::Error> {
         let event = InputEvent::new(EventType::KEY, code as u16, value as i32);
-        self.device.emit(&[event])?;
+        self.out.lock().emit(&[event])?;
         Ok(())
     }
 
+    /// Write the key event to the given device. If the output is not split, there is only one
+    /// device and it is always used.
+    pub fn write_key_on(
+        &mut self,
+        device: OutputDevice,
+        key: OsCode,
+        value: KeyValue,
+    ) -> Result<(), io::Error> {
+        let input_ev = KeyEvent::new(key, value).into();
+        log::debug!("send to uinput {device:?}: {:?}", input_ev);
+        self.out.lock().emit_to(device, &[input_ev])
+    }
+
+    pub fn write_gamepad_button(
+        &mut self,
+        button: GamepadButton,
+        value: KeyValue,
+    ) -> Result<(), io::Error> {
+        let event = InputEvent::new(
+            EventType::KEY,
+            gamepad_button_key(button).code(),
+            value as i32,
+        );
+        log::debug!("send to gamepad: {:?}", event);
+        self.out.lock().emit_gamepad(&[event])
+    }
+
+    /// Set the axis of the gamepad to the value.
+    pub fn set_gamepad_axis(&mut self, axis: GamepadAxis, value: i32) -> Result<(), io::Error> {
+        let mut out = self.out.lock();
+        out.gamepad_axes[axis as usize] = value;
+        let event = InputEvent::new(EventType::ABSOLUTE, GAMEPAD_AXES[axis as usize].1 .0, value);
+        log::debug!("send to gamepad: {:?}", event);
+        out.emit_gamepad(&[event])
+    }
+
+    /// Return the axis of the gamepad to rest, unless another action has since set the axis to a
+    /// different value.
+    pub fn reset_gamepad_axis(&mut self, axis: GamepadAxis, value: i32) -> Result<(), io::Error> {
+        if self.out.lock().gamepad_axes[axis as usize] != value {
+            return Ok(());
+        }
+        self.set_gamepad_axis(axis, 0)
+    }
+
     pub fn press_key(&mut self, key: OsCode) -> Result<(), io::Error> {
         self.write_key(key, KeyValue::Press)
     }
@@ -506,9 +1345,121 @@ impl KbdOut {
         &mut self,
         direction: MWheelDirection,
         hi_res_distance: u16,
+    ) -> Result<(), io::Error> {
+        self.out.lock().scroll(direction, hi_res_distance)
+    }
+
+    pub fn move_mouse(&mut self, mv: CalculatedMouseMove) -> Result<(), io::Error> {
+        let (axis, distance) = match mv.direction {
+            MoveDirection::Up => (RelativeAxisType::REL_Y, -i32::from(mv.distance)),
+            MoveDirection::Down => (RelativeAxisType::REL_Y, i32::from(mv.distance)),
+            MoveDirection::Left => (RelativeAxisType::REL_X, -i32::from(mv.distance)),
+            MoveDirection::Right => (RelativeAxisType::REL_X, i32::from(mv.distance)),
+        };
+        self.write(InputEvent::new(EventType::RELATIVE, axis.0, distance))
+    }
+
+    pub fn move_mouse_many(&mut self, moves: &[CalculatedMouseMove]) -> Result<(), io::Error> {
+        let mut events = vec![];
+        for mv in moves {
+            let (axis, distance) = match mv.direction {
+                MoveDirection::Up => (RelativeAxisType::REL_Y, -i32::from(mv.distance)),
+                MoveDirection::Down => (RelativeAxisType::REL_Y, i32::from(mv.distance)),
+                MoveDirection::Left => (RelativeAxisType::REL_X, -i32::from(mv.distance)),
+                MoveDirection::Right => (RelativeAxisType::REL_X, i32::from(mv.distance)),
+            };
+            events.push(InputEvent::new(EventType::RELATIVE, axis.0, distance));
+        }
+        self.write_many(&events)
+    }
+
+    pub fn set_mouse(&mut self, _x: u16, _y: u16) -> Result<(), io::Error> {
+        log::warn!("setmouse does not work in Linux yet. Maybe try out warpd:\n\thttps://github.com/rvaiya/warpd");
+        Ok(())
+    }
+}
+
+/// The output device along with the state that must be kept consistent across all writers.
+struct OutDevice {
+    device: uinput::VirtualDevice,
+    /// Receives the mouse events instead of `device` if the output is split.
+    mouse: Option<uinput::VirtualDevice>,
+    /// Receives the events of gamepad actions if `linux-output-gamepad` is enabled.
+    gamepad: Option<uinput::VirtualDevice>,
+    /// The last value written to each gamepad axis, indexed like `GAMEPAD_AXES`.
+    gamepad_axes: [i32; GAMEPAD_AXES.len()],
+    /// Receives a copy of the key events written to `device` and `mouse`.
+    mirror: Option<MirrorDevice>,
+    accumulated_scroll: u16,
+    accumulated_hscroll: u16,
+}
+
+impl OutDevice {
+    fn emit(&mut self, events: &[InputEvent]) -> Result<(), io::Error> {
+        match &mut self.mouse {
+            None => self.device.emit(events)?,
+            Some(mouse) => {
+                let (mouse_events, kbd_events): (Vec<_>, Vec<_>) =
+                    events.iter().copied().partition(is_mouse_event);
+                if !kbd_events.is_empty() {
+                    self.device.emit(&kbd_events)?;
+                }
+                if !mouse_events.is_empty() {
+                    mouse.emit(&mouse_events)?;
+                }
+            }
+        }
+        track_lock_keys(events);
+        self.emit_mirror(events);
+        Ok(())
+    }
+
+    /// Write the events to the given device, regardless of the kind of events.
+    fn emit_to(&mut self, target: OutputDevice, events: &[InputEvent]) -> Result<(), io::Error> {
+        match (target, &mut self.mouse) {
+            (OutputDevice::Mouse, Some(mouse)) => mouse.emit(events)?,
+            _ => self.device.emit(events)?,
+        }
+        track_lock_keys(events);
+        self.emit_mirror(events);
+        Ok(())
+    }
+
+    fn emit_gamepad(&mut self, events: &[InputEvent]) -> Result<(), io::Error> {
+        match &mut self.gamepad {
+            Some(gamepad) => gamepad.emit(events),
+            None => {
+                log::warn!("gamepad action used but linux-output-gamepad is not enabled");
+                Ok(())
+            }
+        }
+    }
+
+    fn emit_mirror(&mut self, events: &[InputEvent]) {
+        if let Some(mirror) = &mut self.mirror {
+            if let Err(e) = mirror.emit(events) {
+                log::warn!("failed to write to output mirror device: {e}");
+            }
+        }
+    }
+
+    fn scroll(
+        &mut self,
+        direction: MWheelDirection,
+        hi_res_distance: u16,
     ) -> Result<(), io::Error> {
         log::debug!("scroll: {direction:?} {hi_res_distance:?}");
+        let events = self.scroll_events(direction, hi_res_distance);
+        self.emit(&events)
+    }
 
+    /// Returns the hi-res scroll event for the distance, followed by a lo-res scroll event once
+    /// the hi-res distance adds up to a full notch.
+    fn scroll_events(
+        &mut self,
+        direction: MWheelDirection,
+        hi_res_distance: u16,
+    ) -> Vec<InputEvent> {
         let mut lo_res_distance = hi_res_distance / HI_RES_SCROLL_UNITS_IN_LO_RES;
         let leftover_hi_res_distance = hi_res_distance % HI_RES_SCROLL_UNITS_IN_LO_RES;
 
@@ -540,7 +1491,7 @@ impl KbdOut {
         );
 
         if lo_res_distance > 0 {
-            self.write_many(&[
+            vec![
                 hi_res_scroll_event,
                 InputEvent::new(
                     EventType::RELATIVE,
@@ -559,38 +1510,342 @@ impl KbdOut {
                         }
                     },
                 ),
-            ])
+            ]
         } else {
-            self.write(hi_res_scroll_event)
+            vec![hi_res_scroll_event]
         }
     }
+}
 
-    pub fn move_mouse(&mut self, mv: CalculatedMouseMove) -> Result<(), io::Error> {
-        let (axis, distance) = match mv.direction {
-            MoveDirection::Up => (RelativeAxisType::REL_Y, -i32::from(mv.distance)),
-            MoveDirection::Down => (RelativeAxisType::REL_Y, i32::from(mv.distance)),
-            MoveDirection::Left => (RelativeAxisType::REL_X, -i32::from(mv.distance)),
-            MoveDirection::Right => (RelativeAxisType::REL_X, i32::from(mv.distance)),
+/// Added to the name of a grabbed device to name its passthrough copy.
+const PASSTHROUGH_DEVICE_SUFFIX: &str = " (kanata)";
+
+mod uinput_ioctl {
+    use nix::libc::{uinput_abs_setup, uinput_setup};
+    use nix::{ioctl_none, ioctl_write_int, ioctl_write_ptr};
+
+    ioctl_none!(ui_dev_create, b'U', 1);
+    ioctl_write_ptr!(ui_dev_setup, b'U', 3, uinput_setup);
+    ioctl_write_ptr!(ui_abs_setup, b'U', 4, uinput_abs_setup);
+    ioctl_write_int!(ui_set_evbit, b'U', 100);
+    ioctl_write_int!(ui_set_keybit, b'U', 101);
+    ioctl_write_int!(ui_set_relbit, b'U', 102);
+    ioctl_write_int!(ui_set_absbit, b'U', 103);
+    ioctl_write_int!(ui_set_mscbit, b'U', 104);
+    ioctl_write_int!(ui_set_propbit, b'U', 110);
+}
+
+/// A uinput copy of a grabbed device that has absolute axes, e.g. a touchpad, tablet or
+/// touchscreen. The axes of such devices have ranges and resolutions of their own, which the
+/// `kanata` output device cannot have for every device, so their passthrough events are written to
+/// the copy instead. The copy has the same events, input properties and ID as the device, so
+/// libinput treats it like the device itself.
+///
+/// The uinput device is set up with raw ioctls because the evdev crate cannot set input
+/// properties, without which touchpads are treated as touchscreens.
+#[derive(Debug)]
+pub struct AbsPassthrough {
+    file: fs::File,
+    /// Events of the current frame.
+    buf: Vec<InputEvent>,
+    /// The number of multitouch slots, or 0 if the device does not use slots.
+    slots: i32,
+    /// The extent of the multitouch position axes.
+    pub size: (i32, i32),
+}
+
+impl AbsPassthrough {
+    fn new(dev: &Device) -> Result<Self, io::Error> {
+        use nix::libc::{input_id, uinput_abs_setup, uinput_setup, UINPUT_MAX_NAME_SIZE};
+        use uinput_ioctl::*;
+
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/uinput")?;
+        let fd = file.as_raw_fd();
+        let abs_state = dev.get_abs_state()?;
+        let absinfo = |axis: AbsoluteAxisType| abs_state[axis.0 as usize];
+        // SAFETY: the ioctls only read the arguments and the file descriptor stays open.
+        unsafe {
+            if let Some(keys) = dev.supported_keys() {
+                ui_set_evbit(fd, EventType::KEY.0.into())?;
+                for key in keys.iter() {
+                    ui_set_keybit(fd, key.0.into())?;
+                }
+            }
+            if let Some(axes) = dev.supported_relative_axes() {
+                ui_set_evbit(fd, EventType::RELATIVE.0.into())?;
+                for axis in axes.iter() {
+                    ui_set_relbit(fd, axis.0.into())?;
+                }
+            }
+            if let Some(axes) = dev.supported_absolute_axes() {
+                ui_set_evbit(fd, EventType::ABSOLUTE.0.into())?;
+                for axis in axes.iter() {
+                    ui_set_absbit(fd, axis.0.into())?;
+                    ui_abs_setup(
+                        fd,
+                        &uinput_abs_setup {
+                            code: axis.0,
+                            absinfo: absinfo(axis),
+                        },
+                    )?;
+                }
+            }
+            if let Some(misc) = dev.misc_properties() {
+                ui_set_evbit(fd, EventType::MISC.0.into())?;
+                for msc in misc.iter() {
+                    ui_set_mscbit(fd, msc.0.into())?;
+                }
+            }
+            for prop in dev.properties().iter() {
+                ui_set_propbit(fd, prop.0.into())?;
+            }
+            let id = dev.input_id();
+            let mut setup = uinput_setup {
+                id: input_id {
+                    bustype: id.bus_type().0,
+                    vendor: id.vendor(),
+                    product: id.product(),
+                    version: id.version(),
+                },
+                name: [0; UINPUT_MAX_NAME_SIZE],
+                ff_effects_max: 0,
+            };
+            let name = format!("{}{PASSTHROUGH_DEVICE_SUFFIX}", dev.name().unwrap_or(""));
+            // The name is cut off at a character boundary if it is too long, keeping the suffix so
+            // that kanata never grabs the copy.
+            let max_len = UINPUT_MAX_NAME_SIZE - 1 - PASSTHROUGH_DEVICE_SUFFIX.len();
+            let mut name_len = name.len() - PASSTHROUGH_DEVICE_SUFFIX.len();
+            while name_len > max_len || !name.is_char_boundary(name_len) {
+                name_len -= 1;
+            }
+            let name = format!("{}{PASSTHROUGH_DEVICE_SUFFIX}", &name[..name_len]);
+            for (dst, src) in setup.name.iter_mut().zip(name.bytes()) {
+                *dst = src as nix::libc::c_char;
+            }
+            ui_dev_setup(fd, &setup)?;
+            ui_dev_create(fd)?;
+            log::info!("Created passthrough device {name}");
+        }
+        let has_axis = |axis| {
+            dev.supported_absolute_axes()
+                .map(|axes| axes.contains(axis))
+                .unwrap_or(false)
         };
-        self.write(InputEvent::new(EventType::RELATIVE, axis.0, distance))
+        let slots = match has_axis(AbsoluteAxisType::ABS_MT_SLOT) {
+            true => absinfo(AbsoluteAxisType::ABS_MT_SLOT).maximum + 1,
+            false => 0,
+        };
+        let extent = |axis| {
+            let info = absinfo(axis);
+            info.maximum - info.minimum
+        };
+        Ok(Self {
+            file,
+            buf: vec![],
+            slots,
+            size: (
+                extent(AbsoluteAxisType::ABS_MT_POSITION_X),
+                extent(AbsoluteAxisType::ABS_MT_POSITION_Y),
+            ),
+        })
     }
 
-    pub fn move_mouse_many(&mut self, moves: &[CalculatedMouseMove]) -> Result<(), io::Error> {
-        let mut events = vec![];
-        for mv in moves {
-            let (axis, distance) = match mv.direction {
-                MoveDirection::Up => (RelativeAxisType::REL_Y, -i32::from(mv.distance)),
-                MoveDirection::Down => (RelativeAxisType::REL_Y, i32::from(mv.distance)),
-                MoveDirection::Left => (RelativeAxisType::REL_X, -i32::from(mv.distance)),
-                MoveDirection::Right => (RelativeAxisType::REL_X, i32::from(mv.distance)),
+    /// Adds the event to the current frame. `SYN_REPORT` is not added; the frame ends with
+    /// `end_frame` instead.
+    pub fn write_raw(&mut self, event: InputEvent) {
+        match (event.event_type(), event.code()) {
+            // SYN_MT_REPORT separates the touches of a frame for devices without slots.
+            (EventType::SYNCHRONIZATION, 2) => self.buf.push(event),
+            (EventType::SYNCHRONIZATION, _) => {}
+            _ => self.buf.push(event),
+        }
+    }
+
+    /// Writes the current frame to the device if `write` is true, otherwise drops it.
+    pub fn end_frame(&mut self, write: bool) -> Result<(), io::Error> {
+        let result = match write && !self.buf.is_empty() {
+            true => self.emit_frame(),
+            false => Ok(()),
+        };
+        self.buf.clear();
+        result
+    }
+
+    /// Writes a frame that lifts every touch. Used after frames were dropped, since the device
+    /// would otherwise keep the touches of the last written frame.
+    pub fn lift_all(&mut self) -> Result<(), io::Error> {
+        use evdev::Key;
+        self.buf.clear();
+        let abs =
+            |axis: AbsoluteAxisType, value| InputEvent::new(EventType::ABSOLUTE, axis.0, value);
+        for slot in 0..self.slots {
+            self.buf.push(abs(AbsoluteAxisType::ABS_MT_SLOT, slot));
+            self.buf.push(abs(AbsoluteAxisType::ABS_MT_TRACKING_ID, -1));
+        }
+        for key in [
+            Key::BTN_TOUCH,
+            Key::BTN_TOOL_FINGER,
+            Key::BTN_TOOL_DOUBLETAP,
+            Key::BTN_TOOL_TRIPLETAP,
+            Key::BTN_TOOL_QUADTAP,
+            Key::BTN_TOOL_QUINTTAP,
+        ] {
+            self.buf
+                .push(InputEvent::new(EventType::KEY, key.code(), 0));
+        }
+        self.end_frame(true)
+    }
+
+    fn emit_frame(&mut self) -> Result<(), io::Error> {
+        use std::io::Write;
+        self.buf
+            .push(InputEvent::new(EventType::SYNCHRONIZATION, 0, 0));
+        // SAFETY: InputEvent is a transparent wrapper of input_event, which has no padding on
+        // Linux targets.
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                self.buf.as_ptr() as *const u8,
+                std::mem::size_of_val(self.buf.as_slice()),
+            )
+        };
+        (&self.file).write_all(bytes)
+    }
+}
+
+/// A uinput device that receives a copy of the key events written to the output device, for
+/// programs that observe what kanata types. It has no keys, so that desktop environments do not
+/// treat it as a keyboard and type every key twice. Instead, each key event is written as a
+/// `MSC_SCAN` event containing the key code followed by a `MSC_RAW` event containing the value:
+/// 0 for release, 1 for press and 2 for repeat. Other events are not copied.
+#[derive(Debug)]
+struct MirrorDevice {
+    file: fs::File,
+}
+
+impl MirrorDevice {
+    fn new(identity: &OutputIdentity, name: &str) -> Result<Self, io::Error> {
+        use nix::libc::{input_id, uinput_setup, UINPUT_MAX_NAME_SIZE};
+        use uinput_ioctl::*;
+
+        if name.len() >= UINPUT_MAX_NAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the mirror device name must be shorter than {UINPUT_MAX_NAME_SIZE} bytes"),
+            ));
+        }
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/uinput")?;
+        let fd = file.as_raw_fd();
+        // SAFETY: the ioctls only read the arguments and the file descriptor stays open.
+        unsafe {
+            ui_set_evbit(fd, EventType::MISC.0.into())?;
+            ui_set_mscbit(fd, MiscType::MSC_SCAN.0.into())?;
+            ui_set_mscbit(fd, MiscType::MSC_RAW.0.into())?;
+            let mut setup = uinput_setup {
+                id: input_id {
+                    bustype: identity.bus.0,
+                    vendor: identity.vendor,
+                    product: identity.product,
+                    version: 1,
+                },
+                name: [0; UINPUT_MAX_NAME_SIZE],
+                ff_effects_max: 0,
             };
-            events.push(InputEvent::new(EventType::RELATIVE, axis.0, distance));
+            for (dst, src) in setup.name.iter_mut().zip(name.bytes()) {
+                *dst = src as nix::libc::c_char;
+            }
+            ui_dev_setup(fd, &setup)?;
+            ui_dev_create(fd)?;
         }
-        self.write_many(&events)
+        Ok(Self { file })
     }
 
-    pub fn set_mouse(&mut self, _x: u16, _y: u16) -> Result<(), io::Error> {
-        log::warn!("setmouse does not work in Linux yet. Maybe try out warpd:\n\thttps://github.com/rvaiya/warpd");
+    /// Writes a copy of the key events, if any, as one frame.
+    fn emit(&mut self, events: &[InputEvent]) -> Result<(), io::Error> {
+        use std::io::Write;
+        let mut frame = vec![];
+        for event in events.iter().filter(|ev| ev.event_type() == EventType::KEY) {
+            frame.push(InputEvent::new(
+                EventType::MISC,
+                MiscType::MSC_SCAN.0,
+                event.code().into(),
+            ));
+            frame.push(InputEvent::new(
+                EventType::MISC,
+                MiscType::MSC_RAW.0,
+                event.value(),
+            ));
+        }
+        if frame.is_empty() {
+            return Ok(());
+        }
+        frame.push(InputEvent::new(EventType::SYNCHRONIZATION, 0, 0));
+        // SAFETY: InputEvent is a transparent wrapper of input_event, which has no padding on
+        // Linux targets.
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                frame.as_ptr() as *const u8,
+                std::mem::size_of_val(frame.as_slice()),
+            )
+        };
+        (&self.file).write_all(bytes)
+    }
+}
+
+/// Writes passthrough events to the same output device as a `KbdOut`. Events are buffered until
+/// the end of their frame, so each frame of the input device is written as one frame.
+pub struct KbdOutRaw {
+    out: Arc<Mutex<OutDevice>>,
+    raw_buf: Vec<InputEvent>,
+}
+
+impl KbdOutRaw {
+    pub fn write_raw(&mut self, event: InputEvent) -> Result<(), io::Error> {
+        if event.event_type() == EventType::SYNCHRONIZATION {
+            // Possible codes are:
+            //
+            // SYN_REPORT: probably the only one we'll ever receive, segments atomic reads
+            // SYN_CONFIG: unused
+            // SYN_MT_REPORT: same as SYN_REPORT above but for touch devices, which kanata almost
+            //     certainly shouldn't be dealing with.
+            // SYN_DROPPED: buffer full, events dropped. Not sure what this means or how to handle
+            //     this correctly.
+            //
+            // With this knowledge, seems fine to not bother checking.
+            //
+            // A frame whose events were all sent to the processing loop is not written, since an
+            // empty frame would only be noise to downstream consumers.
+            self.flush()?;
+        } else {
+            self.raw_buf.push(event);
+        }
+        Ok(())
+    }
+
+    /// Writes the events of an unfinished frame, e.g. because a batch of events ended without a
+    /// sync event, so that they are not held until the next frame of the device.
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        if !self.raw_buf.is_empty() {
+            self.out.lock().emit(&self.raw_buf)?;
+            self.raw_buf.clear();
+        }
+        Ok(())
+    }
+
+    /// Adds the scroll to the current frame rather than writing it right away, so that it stays
+    /// together with e.g. the pointer movement of the same frame.
+    pub fn scroll(
+        &mut self,
+        direction: MWheelDirection,
+        hi_res_distance: u16,
+    ) -> Result<(), io::Error> {
+        let events = self.out.lock().scroll_events(direction, hi_res_distance);
+        self.raw_buf.extend(events);
         Ok(())
     }
 }
@@ -616,6 +1871,8 @@ fn devices_from_input_paths(
 fn discover_devices(
     include_names: Option<&[String]>,
     exclude_names: Option<&[String]>,
+    detect_mode: DeviceDetectMode,
+    observe_switches: bool,
 ) -> Vec<(Device, String)> {
     log::info!("looking for devices in /dev/input");
     let devices: Vec<_> = evdev::enumerate()
@@ -628,48 +1885,149 @@ fn discover_devices(
             )
         })
         .filter(|pd| {
-            is_input_device(&pd.0)
-                && match include_names {
-                    None => true,
-                    Some(include_names) => {
-                        let name = pd.0.name().unwrap_or("");
-                        if include_names.iter().any(|include| name == include) {
-                            log::info!("device [{}:{name}] is included", &pd.1);
-                            true
-                        } else {
-                            log::info!("device [{}:{name}] is ignored", &pd.1);
-                            false
-                        }
+            let is_switch = observe_switches && has_mapped_switches(&pd.0);
+            if !is_switch && !is_input_device(&pd.0, detect_mode) {
+                return false;
+            }
+            let name = pd.0.name().unwrap_or("");
+            let uses_paths = include_names
+                .iter()
+                .chain(exclude_names.iter())
+                .flat_map(|entries| entries.iter())
+                .any(|entry| entry.starts_with('/'));
+            let links = if uses_paths {
+                device_links(&pd.1)
+            } else {
+                vec![]
+            };
+            let matches = |entry: &String| device_matches(entry, name, &pd.0.input_id(), &links);
+            (match include_names {
+                None => true,
+                Some(include_names) => {
+                    if include_names.iter().any(matches) {
+                        log::info!("device [{}:{name}] is included", &pd.1);
+                        true
+                    } else {
+                        log::info!("device [{}:{name}] is ignored", &pd.1);
+                        false
                     }
                 }
-                && match exclude_names {
-                    None => true,
-                    Some(exclude_names) => {
-                        let name = pd.0.name().unwrap_or("");
-                        if exclude_names.iter().any(|exclude| name == exclude) {
-                            log::info!("device [{}:{name}] is excluded", &pd.1);
-                            false
-                        } else {
-                            true
-                        }
+            }) && match exclude_names {
+                None => true,
+                Some(exclude_names) => {
+                    let excluded = exclude_names.iter().any(matches);
+                    if excluded {
+                        log::info!("device [{}:{name}] is excluded", &pd.1);
                     }
+                    !excluded
                 }
+            }
         })
         .collect();
     devices
 }
 
+/// Returns whether an entry of `linux-dev-names-include` or `linux-dev-names-exclude` matches the
+/// device. An entry matches the device name, the `vendor:product` ID of the device written in
+/// hexadecimal, or if it is a path, one of the `links` of the device with `*` and `?` wildcards.
+fn device_matches(entry: &str, name: &str, input_id: &evdev::InputId, links: &[String]) -> bool {
+    if entry == name {
+        return true;
+    }
+    if entry.starts_with('/') {
+        return links.iter().any(|link| glob_matches(entry, link));
+    }
+    let parse_hex = |s: &str| match s.len() {
+        4 => u16::from_str_radix(s, 16).ok(),
+        _ => None,
+    };
+    match entry.split_once(':') {
+        Some((vendor, product)) => {
+            parse_hex(vendor) == Some(input_id.vendor())
+                && parse_hex(product) == Some(input_id.product())
+        }
+        None => false,
+    }
+}
+
+/// Returns the path of the device and the links to it in `/dev/input/by-id` and
+/// `/dev/input/by-path`, which identify devices by their serial number or the port they are
+/// plugged into.
+fn device_links(dev_path: &str) -> Vec<String> {
+    let mut links = vec![dev_path.to_owned()];
+    let Ok(target) = fs::canonicalize(dev_path) else {
+        return links;
+    };
+    for dir in ["/dev/input/by-id", "/dev/input/by-path"] {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        links.extend(
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| fs::canonicalize(path).ok().as_ref() == Some(&target))
+                .filter_map(|path| path.to_str().map(str::to_owned)),
+        );
+    }
+    links
+}
+
+/// Matches the text against a pattern where `*` matches any characters and `?` one character.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern and of the text that it was matched up to.
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_t)) => {
+                    backtrack = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
 fn watch_devinput() -> Result<Inotify, io::Error> {
     let inotify = Inotify::init().expect("Failed to initialize inotify");
-    inotify.watches().add("/dev/input", WatchMask::CREATE)?;
+    // Devices are created before udev gives access to them, so permission changes are watched too
+    // to register devices that could not be opened when they were created.
+    inotify
+        .watches()
+        .add("/dev/input", WatchMask::CREATE | WatchMask::ATTRIB)?;
     Ok(inotify)
 }
 
-#[derive(Clone)]
-struct Symlink {
-    dest: PathBuf,
+/// Path of the symlink to the output device. Kept outside of [`KbdOut`] so that it can be removed
+/// on SIGINT/SIGTERM without taking the kanata lock.
+static SYMLINK_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Removes the symlink to the output device, if there is one. Used when kanata is stopped by a
+/// signal, since the destructors do not run then.
+pub fn remove_symlink() {
+    if let Some(dest) = SYMLINK_PATH.lock().take() {
+        let _ = fs::remove_file(&dest);
+        log::info!("Deleted symlink {:#?}", dest);
+    }
 }
 
+struct Symlink;
+
 impl Symlink {
     fn new(source: PathBuf, dest: PathBuf) -> Result<Self, io::Error> {
         if let Ok(metadata) = fs::symlink_metadata(&dest) {
@@ -687,24 +2045,8 @@ impl Symlink {
         }
         std::os::unix::fs::symlink(&source, &dest)?;
         log::info!("Created symlink {:#?} -> {:#?}", dest, source);
-        Ok(Self { dest })
-    }
-
-    fn clean_when_killed(symlink: Self) {
-        thread::spawn(|| {
-            let mut signals = Signals::new([SIGINT, SIGTERM]).expect("signals register");
-            for signal in &mut signals {
-                match signal {
-                    SIGINT | SIGTERM => {
-                        drop(symlink);
-                        signal_hook::low_level::emulate_default_handler(signal)
-                            .expect("run original sighandlers");
-                        unreachable!();
-                    }
-                    _ => unreachable!(),
-                }
-            }
-        });
+        *SYMLINK_PATH.lock() = Some(dest);
+        Ok(Self)
     }
 }
 
@@ -763,9 +2105,127 @@ fn test_parse_dev_paths() {
     assert_eq!(parse_colon_separated_text("h\\:w\\"), ["h:w\\"]);
 }
 
+#[test]
+fn test_device_matches() {
+    let id = evdev::InputId::new(evdev::BusType::BUS_USB, 0x046d, 0xc52b, 1);
+    let links = [
+        "/dev/input/event3".to_owned(),
+        "/dev/input/by-id/usb-Logi-kbd".to_owned(),
+    ];
+    assert!(device_matches("Logi Kbd", "Logi Kbd", &id, &links));
+    assert!(device_matches("046d:C52B", "Logi Kbd", &id, &links));
+    assert!(!device_matches("046d:c52c", "Logi Kbd", &id, &links));
+    assert!(device_matches(
+        "/dev/input/by-id/usb-Logi*",
+        "",
+        &id,
+        &links
+    ));
+    assert!(device_matches("/dev/input/event?", "", &id, &links));
+    assert!(!device_matches("/dev/input/by-path/*", "", &id, &links));
+    assert!(glob_matches("*a*b", "xaybab"));
+    assert!(!glob_matches("*a*b", "xayba"));
+}
+
+#[test]
+fn test_is_mouse_event() {
+    let key = |code: u16| InputEvent::new(EventType::KEY, code, 1);
+    assert!(is_mouse_event(&key(evdev::Key::BTN_LEFT.code())));
+    assert!(is_mouse_event(&key(evdev::Key::BTN_TASK.code())));
+    assert!(!is_mouse_event(&key(evdev::Key::KEY_A.code())));
+    assert!(!is_mouse_event(&key(evdev::Key::BTN_TRIGGER.code())));
+    assert!(is_mouse_event(&InputEvent::new(
+        EventType::RELATIVE,
+        RelativeAxisType::REL_WHEEL.0,
+        1
+    )));
+}
+
+#[test]
+fn test_gamepad_axes_indexed_by_axis() {
+    for (i, (axis, _)) in GAMEPAD_AXES.iter().enumerate() {
+        assert_eq!(*axis as usize, i);
+    }
+}
+
 impl Drop for Symlink {
     fn drop(&mut self) {
-        let _ = fs::remove_file(&self.dest);
-        log::info!("Deleted symlink {:#?}", self.dest);
+        remove_symlink();
     }
 }
+
+#[test]
+fn test_order_batches_by_timestamp_then_priority() {
+    let device = |name: &str, priority| {
+        Arc::new(InputDevice {
+            name: name.into(),
+            path: format!("/dev/input/{name}"),
+            priority,
+            passthrough: None,
+            grabbed: true,
+        })
+    };
+    let (pedal, kbd1, kbd2) = (device("pedal", 0), device("kbd1", 1), device("kbd2", 1));
+    let press = |code| InputEvent::new_now(EventType::KEY, code, 1);
+    let early = press(30);
+    thread::sleep(std::time::Duration::from_millis(2));
+    let late = press(31);
+    thread::sleep(std::time::Duration::from_millis(2));
+    let latest = press(32);
+    // Events with the same timestamp are ordered by priority.
+    let tied = |code| InputEvent::new(EventType::KEY, code, 1);
+    let mut batches = vec![
+        (vec![late], kbd1.clone()),
+        (vec![latest], pedal.clone()),
+        (vec![early], kbd2.clone()),
+        (vec![], kbd1.clone()),
+        (vec![tied(33)], kbd1.clone()),
+        (vec![tied(34)], pedal.clone()),
+    ];
+    order_batches(&mut batches);
+    let order = batches
+        .iter()
+        .map(|(events, device)| (device.name.as_str(), events.first().map(|e| e.code())))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        order,
+        [
+            ("kbd1", None),
+            ("pedal", Some(34)),
+            ("kbd1", Some(33)),
+            ("kbd2", Some(30)),
+            ("kbd1", Some(31)),
+            ("pedal", Some(32)),
+        ]
+    );
+}
+
+#[test]
+fn test_switch_events() {
+    let switch = |sw: SwitchType, value| {
+        KeyEvent::try_from(InputEvent::new(EventType::SWITCH, sw.0, value))
+            .map(|ev| (ev.code, ev.value))
+    };
+    assert_eq!(
+        switch(SwitchType::SW_TABLET_MODE, 1),
+        Ok((OsCode::SwitchTabletMode, KeyValue::Press))
+    );
+    assert_eq!(
+        switch(SwitchType::SW_LID, 0),
+        Ok((OsCode::SwitchLid, KeyValue::Release))
+    );
+    assert_eq!(switch(SwitchType::SW_HEADPHONE_INSERT, 1), Err(()));
+}
+
+#[test]
+fn test_track_held_keys() {
+    let key = |code, value| InputEvent::new(EventType::KEY, code, value);
+    let mut held = vec![];
+    track_held_keys(&mut held, &[key(42, 1), key(30, 1), key(30, 2)]);
+    assert_eq!(held, [42, 30]);
+    track_held_keys(
+        &mut held,
+        &[InputEvent::new(EventType::RELATIVE, 8, 1), key(42, 0)],
+    );
+    assert_eq!(held, [30]);
+}
@@ -0,0 +1,322 @@
+//! Keyboard input with a Quartz event tap and output with posted Quartz events.
+
+use std::io;
+
+use core_graphics::display::CGDisplay;
+use core_graphics::event::{
+    CGEvent, CGEventFlags, CGEventTapLocation, CGEventType, CGMouseButton, EventField,
+    ScrollEventUnit,
+};
+use core_graphics::event_source::{CGEventSource, CGEventSourceStateID};
+use core_graphics::geometry::CGPoint;
+
+use crate::kanata::CalculatedMouseMove;
+use crate::oskbd::{KeyEvent, KeyValue};
+use kanata_parser::custom_action::*;
+use kanata_parser::keys::*;
+
+pub const HI_RES_SCROLL_UNITS_IN_LO_RES: u16 = 120;
+
+/// Written to the user data field of the events that kanata posts, so that the event tap passes
+/// them through instead of processing them again.
+pub const KANATA_EVENT_MARKER: i64 = 0x6b61_6e61;
+
+const MAC_KEYCODE_CAPSLOCK: u16 = 0x39;
+
+// Device dependent modifier flags from IOLLEvent.h, which tell the left and right keys apart.
+const NX_DEVICELCTLKEYMASK: u64 = 0x0000_0001;
+const NX_DEVICELSHIFTKEYMASK: u64 = 0x0000_0002;
+const NX_DEVICERSHIFTKEYMASK: u64 = 0x0000_0004;
+const NX_DEVICELCMDKEYMASK: u64 = 0x0000_0008;
+const NX_DEVICERCMDKEYMASK: u64 = 0x0000_0010;
+const NX_DEVICELALTKEYMASK: u64 = 0x0000_0020;
+const NX_DEVICERALTKEYMASK: u64 = 0x0000_0040;
+const NX_DEVICERCTLKEYMASK: u64 = 0x0000_2000;
+
+/// Returns the modifier flags that are set while the key is held, or `None` if the key is not a
+/// modifier.
+fn modifier_flags(osc: OsCode) -> Option<u64> {
+    use CGEventFlags as F;
+    Some(match osc {
+        OsCode::KEY_LEFTCTRL => F::CGEventFlagControl.bits() | NX_DEVICELCTLKEYMASK,
+        OsCode::KEY_RIGHTCTRL => F::CGEventFlagControl.bits() | NX_DEVICERCTLKEYMASK,
+        OsCode::KEY_LEFTSHIFT => F::CGEventFlagShift.bits() | NX_DEVICELSHIFTKEYMASK,
+        OsCode::KEY_RIGHTSHIFT => F::CGEventFlagShift.bits() | NX_DEVICERSHIFTKEYMASK,
+        OsCode::KEY_LEFTALT => F::CGEventFlagAlternate.bits() | NX_DEVICELALTKEYMASK,
+        OsCode::KEY_RIGHTALT => F::CGEventFlagAlternate.bits() | NX_DEVICERALTKEYMASK,
+        OsCode::KEY_LEFTMETA => F::CGEventFlagCommand.bits() | NX_DEVICELCMDKEYMASK,
+        OsCode::KEY_RIGHTMETA => F::CGEventFlagCommand.bits() | NX_DEVICERCMDKEYMASK,
+        OsCode::KEY_FN => F::CGEventFlagSecondaryFn.bits(),
+        _ => return None,
+    })
+}
+
+/// Key event received by the event tap.
+#[derive(Debug, Clone, Copy)]
+pub struct InputEvent {
+    /// macOS virtual key code.
+    pub code: u16,
+
+    /// Key was released
+    pub up: bool,
+
+    /// The OS sent the event as an auto-repeat of a held key.
+    pub repeat: bool,
+}
+
+impl InputEvent {
+    /// Reads the key event of an event from the event tap. Returns `None` for other events and for
+    /// events that kanata posted itself.
+    pub fn from_cg_event(etype: CGEventType, event: &CGEvent) -> Option<Self> {
+        if event.get_integer_value_field(EventField::EVENT_SOURCE_USER_DATA) == KANATA_EVENT_MARKER
+        {
+            return None;
+        }
+        let code = event.get_integer_value_field(EventField::KEYBOARD_EVENT_KEYCODE) as u16;
+        let up = match etype {
+            CGEventType::KeyDown => false,
+            CGEventType::KeyUp => true,
+            // Caps lock only reports that its lock state changed, when it is pressed. The event
+            // loop handles it as a tap.
+            CGEventType::FlagsChanged if code == MAC_KEYCODE_CAPSLOCK => false,
+            // Modifier keys only report that the flags changed. The key is pressed if its flags
+            // are set now.
+            CGEventType::FlagsChanged => {
+                let flags = modifier_flags(mac_keycode_to_oscode(code)?)?;
+                event.get_flags().bits() & flags != flags
+            }
+            _ => return None,
+        };
+        let repeat = event.get_integer_value_field(EventField::KEYBOARD_EVENT_AUTOREPEAT) != 0;
+        Some(Self { code, up, repeat })
+    }
+}
+
+impl TryFrom<InputEvent> for KeyEvent {
+    type Error = ();
+    fn try_from(item: InputEvent) -> Result<Self, Self::Error> {
+        let code = mac_keycode_to_oscode(item.code).ok_or(())?;
+        let value = match (item.up, item.repeat) {
+            (true, _) => KeyValue::Release,
+            (false, true) => KeyValue::Repeat,
+            (false, false) => KeyValue::Press,
+        };
+        Ok(KeyEvent::new(code, value))
+    }
+}
+
+fn event_source() -> Result<CGEventSource, io::Error> {
+    CGEventSource::new(CGEventSourceStateID::HIDSystemState)
+        .map_err(|_| io::Error::other("failed to create an event source"))
+}
+
+/// Returns the position of the mouse pointer in screen coordinates.
+pub fn mouse_position() -> Option<(i32, i32)> {
+    let location = CGEvent::new(event_source().ok()?).ok()?.location();
+    Some((location.x as i32, location.y as i32))
+}
+
+/// Posts events to the HID system, where the event tap of kanata sees them but passes them
+/// through because of `KANATA_EVENT_MARKER`.
+pub struct KbdOut {
+    /// Modifier flags of the modifier keys that kanata holds. Events are posted with these flags
+    /// since macOS does not derive them from posted modifier key events.
+    flags: u64,
+    /// Mouse buttons that kanata holds, so that mouse movement is posted as dragging.
+    held_btns: Vec<Btn>,
+}
+
+impl KbdOut {
+    pub fn new() -> Result<Self, io::Error> {
+        Ok(Self {
+            flags: 0,
+            held_btns: vec![],
+        })
+    }
+
+    fn post(&self, event: CGEvent) {
+        event.set_integer_value_field(EventField::EVENT_SOURCE_USER_DATA, KANATA_EVENT_MARKER);
+        event.post(CGEventTapLocation::HID);
+    }
+
+    pub fn write_key(&mut self, key: OsCode, value: KeyValue) -> Result<(), io::Error> {
+        let Some(code) = oscode_to_mac_keycode(key) else {
+            log::warn!("{key:?} has no macOS key code, not sending it");
+            return Ok(());
+        };
+        let down = match value {
+            KeyValue::Press | KeyValue::Repeat => true,
+            KeyValue::Release => false,
+            KeyValue::Tap | KeyValue::WakeUp => panic!("invalid value attempted to be sent"),
+        };
+        let modifier = modifier_flags(key);
+        if let Some(flags) = modifier {
+            match down {
+                true => self.flags |= flags,
+                // Keep the device independent flag if the other key of the modifier is held.
+                false => {
+                    self.flags &= !flags;
+                    for osc in [
+                        OsCode::KEY_LEFTCTRL,
+                        OsCode::KEY_RIGHTCTRL,
+                        OsCode::KEY_LEFTSHIFT,
+                        OsCode::KEY_RIGHTSHIFT,
+                        OsCode::KEY_LEFTALT,
+                        OsCode::KEY_RIGHTALT,
+                        OsCode::KEY_LEFTMETA,
+                        OsCode::KEY_RIGHTMETA,
+                    ] {
+                        let other = modifier_flags(osc).expect("modifier");
+                        if self.flags & other & !flags != 0 {
+                            self.flags |= other;
+                        }
+                    }
+                }
+            }
+        }
+        let event = CGEvent::new_keyboard_event(event_source()?, code, down)
+            .map_err(|_| io::Error::other("failed to create a key event"))?;
+        if modifier.is_some() {
+            event.set_type(CGEventType::FlagsChanged);
+        }
+        event.set_flags(CGEventFlags::from_bits_retain(self.flags));
+        self.post(event);
+        Ok(())
+    }
+
+    pub fn write_code(&mut self, code: u32, value: KeyValue) -> Result<(), io::Error> {
+        match OsCode::from_u16(code as u16) {
+            Some(osc) => self.write_key(osc, value),
+            None => Ok(()),
+        }
+    }
+
+    pub fn press_key(&mut self, key: OsCode) -> Result<(), io::Error> {
+        self.write_key(key, KeyValue::Press)
+    }
+
+    pub fn release_key(&mut self, key: OsCode) -> Result<(), io::Error> {
+        self.write_key(key, KeyValue::Release)
+    }
+
+    /// Sends the character as the text of a key event, without a key code.
+    pub fn send_unicode(&mut self, c: char) -> Result<(), io::Error> {
+        let mut buf = [0; 2];
+        let text = c.encode_utf16(&mut buf);
+        for down in [true, false] {
+            let event = CGEvent::new_keyboard_event(event_source()?, 0, down)
+                .map_err(|_| io::Error::other("failed to create a key event"))?;
+            event.set_string_from_utf16_unchecked(text);
+            self.post(event);
+        }
+        Ok(())
+    }
+
+    fn mouse_location(&self) -> Result<CGPoint, io::Error> {
+        CGEvent::new(event_source()?)
+            .map(|event| event.location())
+            .map_err(|_| io::Error::other("failed to read the mouse position"))
+    }
+
+    fn post_btn(&mut self, btn: Btn, down: bool) -> Result<(), io::Error> {
+        let (etype, button, number) = match (btn, down) {
+            (Btn::Left, true) => (CGEventType::LeftMouseDown, CGMouseButton::Left, 0),
+            (Btn::Left, false) => (CGEventType::LeftMouseUp, CGMouseButton::Left, 0),
+            (Btn::Right, true) => (CGEventType::RightMouseDown, CGMouseButton::Right, 1),
+            (Btn::Right, false) => (CGEventType::RightMouseUp, CGMouseButton::Right, 1),
+            (Btn::Mid, true) => (CGEventType::OtherMouseDown, CGMouseButton::Center, 2),
+            (Btn::Mid, false) => (CGEventType::OtherMouseUp, CGMouseButton::Center, 2),
+            (Btn::Backward, true) => (CGEventType::OtherMouseDown, CGMouseButton::Center, 3),
+            (Btn::Backward, false) => (CGEventType::OtherMouseUp, CGMouseButton::Center, 3),
+            (Btn::Forward, true) => (CGEventType::OtherMouseDown, CGMouseButton::Center, 4),
+            (Btn::Forward, false) => (CGEventType::OtherMouseUp, CGMouseButton::Center, 4),
+        };
+        let event =
+            CGEvent::new_mouse_event(event_source()?, etype, self.mouse_location()?, button)
+                .map_err(|_| io::Error::other("failed to create a mouse event"))?;
+        event.set_integer_value_field(EventField::MOUSE_EVENT_BUTTON_NUMBER, number);
+        event.set_flags(CGEventFlags::from_bits_retain(self.flags));
+        self.post(event);
+        self.held_btns.retain(|held| *held != btn);
+        if down {
+            self.held_btns.push(btn);
+        }
+        Ok(())
+    }
+
+    pub fn click_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
+        log::debug!("click btn: {:?}", btn);
+        self.post_btn(btn, true)
+    }
+
+    pub fn release_btn(&mut self, btn: Btn) -> Result<(), io::Error> {
+        log::debug!("release btn: {:?}", btn);
+        self.post_btn(btn, false)
+    }
+
+    pub fn scroll(&mut self, direction: MWheelDirection, distance: u16) -> Result<(), io::Error> {
+        log::debug!("scroll: {direction:?} {distance:?}");
+        let lines = i32::from((distance / HI_RES_SCROLL_UNITS_IN_LO_RES).max(1));
+        let (vertical, horizontal) = match direction {
+            MWheelDirection::Up => (lines, 0),
+            MWheelDirection::Down => (-lines, 0),
+            MWheelDirection::Left => (0, lines),
+            MWheelDirection::Right => (0, -lines),
+        };
+        let event = CGEvent::new_scroll_event(
+            event_source()?,
+            ScrollEventUnit::LINE,
+            2,
+            vertical,
+            horizontal,
+            0,
+        )
+        .map_err(|_| io::Error::other("failed to create a scroll event"))?;
+        self.post(event);
+        Ok(())
+    }
+
+    /// Moves the mouse pointer to the position, dragging with the first held button.
+    fn move_mouse_to(&mut self, location: CGPoint) -> Result<(), io::Error> {
+        let (etype, button) = match self.held_btns.first() {
+            Some(Btn::Left) => (CGEventType::LeftMouseDragged, CGMouseButton::Left),
+            Some(Btn::Right) => (CGEventType::RightMouseDragged, CGMouseButton::Right),
+            Some(_) => (CGEventType::OtherMouseDragged, CGMouseButton::Center),
+            None => (CGEventType::MouseMoved, CGMouseButton::Left),
+        };
+        let event = CGEvent::new_mouse_event(event_source()?, etype, location, button)
+            .map_err(|_| io::Error::other("failed to create a mouse event"))?;
+        self.post(event);
+        Ok(())
+    }
+
+    pub fn move_mouse(&mut self, mv: CalculatedMouseMove) -> Result<(), io::Error> {
+        self.move_mouse_many(&[mv])
+    }
+
+    pub fn move_mouse_many(&mut self, moves: &[CalculatedMouseMove]) -> Result<(), io::Error> {
+        let mut location = self.mouse_location()?;
+        for mv in moves {
+            let distance = f64::from(mv.distance);
+            match mv.direction {
+                MoveDirection::Up => location.y -= distance,
+                MoveDirection::Down => location.y += distance,
+                MoveDirection::Left => location.x -= distance,
+                MoveDirection::Right => location.x += distance,
+            }
+        }
+        self.move_mouse_to(location)
+    }
+
+    /// Moves the mouse pointer to the position, where 0,0 is the upper-left corner of the main
+    /// display and 65535,65535 is its lower-right corner.
+    pub fn set_mouse(&mut self, x: u16, y: u16) -> Result<(), io::Error> {
+        log::info!("setting mouse {x} {y}");
+        let bounds = CGDisplay::main().bounds();
+        let scale = |pos: u16, size: f64| f64::from(pos) * size / f64::from(u16::MAX);
+        self.move_mouse_to(CGPoint::new(
+            bounds.origin.x + scale(x, bounds.size.width),
+            bounds.origin.y + scale(y, bounds.size.height),
+        ))
+    }
+}
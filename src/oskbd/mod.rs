@@ -10,6 +10,11 @@ mod windows;
 #[cfg(target_os = "windows")]
 pub use windows::*;
 
+#[cfg(target_os = "macos")]
+mod macos;
+#[cfg(target_os = "macos")]
+pub use macos::*;
+
 // ------------------ KeyValue --------------------
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -18,6 +23,9 @@ pub enum KeyValue {
     Press = 1,
     Repeat = 2,
     Tap,
+    /// Not a key event. Sent to wake up the processing loop so that it handles state changes that
+    /// were made outside of it, e.g. fake key actions sent by TCP clients.
+    WakeUp,
 }
 
 impl From<i32> for KeyValue {
@@ -52,11 +60,22 @@ use kanata_parser::keys::OsCode;
 pub struct KeyEvent {
     pub code: OsCode,
     pub value: KeyValue,
+    /// Index of the input device that produced the event, as used by the `input-device` switch
+    /// condition. This is 0 if the device does not match any `input-device` condition or if the
+    /// platform does not report the device.
+    pub device: u16,
+    /// When the input device produced the event, if the platform reports it. Kanata receives
+    /// events some time after they happen, e.g. while it is busy processing earlier events.
+    pub time: Option<std::time::Instant>,
 }
 
-#[cfg(not(all(feature = "interception_driver", target_os = "windows")))]
 impl KeyEvent {
     pub fn new(code: OsCode, value: KeyValue) -> Self {
-        Self { code, value }
+        Self {
+            code,
+            value,
+            device: 0,
+            time: None,
+        }
     }
 }
@@ -24,7 +24,9 @@ impl InputEvent {
                     match val {
                         KeyValue::Press | KeyValue::Repeat => KeyState::DOWN,
                         KeyValue::Release => KeyState::UP,
-                        KeyValue::Tap => panic!("invalid value attempted to be sent"),
+                        KeyValue::Tap | KeyValue::WakeUp => {
+                            panic!("invalid value attempted to be sent")
+                        }
                     },
                     true,
                 );
@@ -167,7 +169,7 @@ impl KbdOut {
     }
 
     pub fn write_code(&mut self, code: u32, value: KeyValue) -> Result<(), io::Error> {
-        super::write_code(code as u16, value)
+        super::write_code(code as u16, value, false)
     }
 
     pub fn write_key(&mut self, key: OsCode, value: KeyValue) -> Result<(), io::Error> {
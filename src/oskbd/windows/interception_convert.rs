@@ -185,7 +185,7 @@ enum Scancode {
 */
 
 use kanata_interception::*;
-use kanata_parser::keys::OsCode;
+use kanata_parser::keys::{oscode_to_scancode, OsCode};
 
 // We need to wrap OsCode to impl TryFrom<..> for it, because it's in external crate.
 pub struct OsCodeWrapper(pub OsCode);
@@ -532,7 +532,15 @@ impl TryFrom<OsCodeWrapper> for Stroke {
             // 0x6B => OsCode::KEY_TODO, // sc_launch_app1
             // 0x6C => OsCode::KEY_TODO, // sc_launch_email
             // 0x6D => OsCode::KEY_TODO, // sc_launch_media
-            _ => return Err(()),
+            osc => {
+                // Keys written as scancodes in the configuration, e.g. `sc-0x7D`.
+                let sc = oscode_to_scancode(osc).ok_or(())?;
+                let state = match sc & 0xE000 {
+                    0 => KeyState::empty(),
+                    _ => KeyState::E0,
+                };
+                (ScanCode::try_from(sc & 0xFF).map_err(|_| ())?, state)
+            }
         };
         Ok(Stroke::Keyboard {
             code,
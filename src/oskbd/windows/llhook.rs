@@ -18,10 +18,13 @@ use kanata_parser::custom_action::*;
 use kanata_parser::keys::*;
 
 type HookFn = dyn FnMut(InputEvent) -> bool;
+type MouseHookFn = dyn FnMut(MouseWheelEvent) -> bool;
 
 thread_local! {
     /// Stores the hook callback for the current thread.
     static HOOK: Cell<Option<Box<HookFn>>> = Cell::default();
+    /// Stores the mouse hook callback for the current thread.
+    static MOUSE_HOOK: Cell<Option<Box<MouseHookFn>>> = Cell::default();
 }
 
 /// Wrapper for the low-level keyboard hook API.
@@ -62,6 +65,86 @@ impl Drop for KeyboardHook {
     }
 }
 
+/// Wrapper for the low-level mouse hook API. Only mouse wheel events are passed to the callback.
+/// Automatically unregisters the hook when dropped.
+pub struct MouseHook {
+    handle: HHOOK,
+}
+
+impl MouseHook {
+    /// Sets the low-level mouse hook for this thread.
+    ///
+    /// Panics when a hook is already registered from the same thread.
+    #[must_use = "The hook will immediatelly be unregistered and not work."]
+    pub fn set_input_cb(callback: impl FnMut(MouseWheelEvent) -> bool + 'static) -> MouseHook {
+        MOUSE_HOOK.with(|state| {
+            assert!(
+                state.take().is_none(),
+                "Only one mouse hook can be registered per thread."
+            );
+
+            state.set(Some(Box::new(callback)));
+
+            MouseHook {
+                handle: unsafe {
+                    SetWindowsHookExW(WH_MOUSE_LL, Some(mouse_hook_proc), ptr::null_mut(), 0)
+                        .as_mut()
+                        .expect("install low-level mouse hook successfully")
+                },
+            }
+        })
+    }
+}
+
+impl Drop for MouseHook {
+    fn drop(&mut self) {
+        unsafe { UnhookWindowsHookEx(self.handle) };
+        MOUSE_HOOK.with(|state| state.take());
+    }
+}
+
+/// Mouse wheel event received by the low level mouse hook.
+#[derive(Debug, Clone, Copy)]
+pub struct MouseWheelEvent {
+    /// Horizontal wheel instead of vertical wheel.
+    pub horizontal: bool,
+
+    /// Scroll distance, where 120 is one notch. Positive values are up or right.
+    pub delta: i16,
+}
+
+/// The WinAPI compatible callback of the mouse hook.
+unsafe extern "system" fn mouse_hook_proc(code: c_int, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    let horizontal = match wparam as UINT {
+        WM_MOUSEWHEEL => false,
+        WM_MOUSEHWHEEL => true,
+        _ => return CallNextHookEx(ptr::null_mut(), code, wparam, lparam),
+    };
+    let hook_lparam = &*(lparam as *const MSLLHOOKSTRUCT);
+    // Same as for the keyboard hook, scrolling sent by kanata itself must not be processed again.
+    if code != HC_ACTION || hook_lparam.flags & LLMHF_INJECTED != 0 {
+        return CallNextHookEx(ptr::null_mut(), code, wparam, lparam);
+    }
+    let wheel_event = MouseWheelEvent {
+        horizontal,
+        delta: HIWORD(hook_lparam.mouseData) as i16,
+    };
+    log::trace!("{wheel_event:?}");
+
+    let mut handled = false;
+    MOUSE_HOOK.with(|state| {
+        let mut hook = state.take().expect("no recurse");
+        handled = hook(wheel_event);
+        state.set(Some(hook));
+    });
+
+    if handled {
+        1
+    } else {
+        CallNextHookEx(ptr::null_mut(), code, wparam, lparam)
+    }
+}
+
 /// Key event received by the low level keyboard hook.
 #[derive(Debug, Clone, Copy)]
 pub struct InputEvent {
@@ -69,6 +152,10 @@ pub struct InputEvent {
 
     /// Key was released
     pub up: bool,
+
+    /// Hardware scancode of the key, with the E0 prefix as 0xE000 for extended keys. This is 0
+    /// for events that did not come from the hook.
+    pub scancode: u16,
 }
 
 impl InputEvent {
@@ -76,13 +163,24 @@ impl InputEvent {
         Self {
             code: lparam.vkCode,
             up: lparam.flags & LLKHF_UP != 0,
+            scancode: match lparam.flags & LLKHF_EXTENDED {
+                0 => lparam.scanCode as u16,
+                _ => 0xE000 | lparam.scanCode as u16,
+            },
         }
     }
 
+    /// Returns whether this is the lctl that the OS sends along with ralt for layouts with AltGr.
+    /// Unlike a physical lctl, it has a scancode with the 0x200 bit set.
+    pub fn is_altgr_lctl(&self) -> bool {
+        self.code == u32::from(OsCode::KEY_LEFTCTRL) && self.scancode & 0x200 != 0
+    }
+
     fn from_oscode(code: OsCode, val: KeyValue) -> Self {
         Self {
             code: code.into(),
             up: val.into(),
+            scancode: 0,
         }
     }
 }
@@ -90,13 +188,15 @@ impl InputEvent {
 impl TryFrom<InputEvent> for KeyEvent {
     type Error = ();
     fn try_from(item: InputEvent) -> Result<Self, Self::Error> {
-        Ok(Self {
-            code: OsCode::from_u16(item.code as u16).ok_or(())?,
-            value: match item.up {
+        Ok(Self::new(
+            OsCode::from_u16(item.code as u16)
+                .or_else(|| scancode_to_oscode(item.scancode))
+                .ok_or(())?,
+            match item.up {
                 true => KeyValue::Release,
                 false => KeyValue::Press,
             },
-        })
+        ))
     }
 }
 
@@ -105,6 +205,7 @@ impl From<KeyEvent> for InputEvent {
         Self {
             code: item.code.into(),
             up: item.value.into(),
+            scancode: 0,
         }
     }
 }
@@ -147,16 +248,49 @@ unsafe extern "system" fn hook_proc(code: c_int, wparam: WPARAM, lparam: LPARAM)
     }
 }
 
+/// Returns whether the OS considers the key to be held down.
+pub fn os_key_is_pressed(key: OsCode) -> bool {
+    // Not defined in winapi. Maps a scancode with a 0xE0 prefix for extended keys.
+    const MAPVK_VSC_TO_VK_EX: u32 = 3;
+    let vk: u32 = match oscode_to_scancode(key) {
+        // Keys written as scancodes have no virtual key of their own. Without a virtual key the
+        // state is unknown, so the key is reported as held to not release it early.
+        Some(sc) => match unsafe { MapVirtualKeyW(sc.into(), MAPVK_VSC_TO_VK_EX) } {
+            0 => return true,
+            vk => vk,
+        },
+        None => key.into(),
+    };
+    // The most significant bit is set if the key is down.
+    unsafe { GetAsyncKeyState(vk as c_int) as u16 & 0x8000 != 0 }
+}
+
 /// Handle for writing keys to the OS.
-pub struct KbdOut {}
+pub struct KbdOut {
+    /// Whether keys are sent as scancodes instead of virtual keys.
+    sendinput_scancode: Cell<bool>,
+}
 
 impl KbdOut {
     pub fn new() -> Result<Self, io::Error> {
-        Ok(Self {})
+        Ok(Self {
+            sendinput_scancode: Cell::new(false),
+        })
+    }
+
+    pub fn update_sendinput_scancode(&self, sendinput_scancode: bool) {
+        self.sendinput_scancode.set(sendinput_scancode);
     }
 
     pub fn write(&mut self, event: InputEvent) -> Result<(), io::Error> {
-        super::send_key_sendinput(event.code as u16, event.up);
+        match OsCode::from_u16(event.code as u16).and_then(oscode_to_scancode) {
+            Some(sc) => super::send_scancode_sendinput(sc, event.up),
+            None => super::send_key_sendinput(
+                event.code as u16,
+                event.up,
+                self.sendinput_scancode.get(),
+            ),
+        }
         Ok(())
     }
 
@@ -166,7 +300,7 @@ impl KbdOut {
     }
 
     pub fn write_code(&mut self, code: u32, value: KeyValue) -> Result<(), io::Error> {
-        super::write_code(code as u16, value)
+        super::write_code(code as u16, value, self.sendinput_scancode.get())
     }
 
     pub fn press_key(&mut self, key: OsCode) -> Result<(), io::Error> {
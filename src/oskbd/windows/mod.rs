@@ -22,6 +22,15 @@ pub use interception_convert::*;
 
 pub const HI_RES_SCROLL_UNITS_IN_LO_RES: u16 = 120;
 
+/// Returns the position of the mouse pointer in screen coordinates.
+pub fn mouse_position() -> Option<(i32, i32)> {
+    let mut point: winapi::shared::windef::POINT = unsafe { mem::zeroed() };
+    match unsafe { GetCursorPos(&mut point) } {
+        0 => None,
+        _ => Some((point.x, point.y)),
+    }
+}
+
 fn send_uc(c: char, up: bool) {
     log::debug!("sending unicode {c}");
     let mut inputs: [INPUT; 2] = unsafe { mem::zeroed() };
@@ -50,25 +59,64 @@ fn send_uc(c: char, up: bool) {
     }
 }
 
-fn write_code(code: u16, value: KeyValue) -> Result<(), std::io::Error> {
+fn write_code(code: u16, value: KeyValue, scancode: bool) -> Result<(), std::io::Error> {
     send_key_sendinput(
         code,
         match value {
             KeyValue::Press | KeyValue::Repeat => false,
             KeyValue::Release => true,
-            KeyValue::Tap => panic!("invalid value attempted to be sent"),
+            KeyValue::Tap | KeyValue::WakeUp => panic!("invalid value attempted to be sent"),
         },
+        scancode,
     );
     Ok(())
 }
 
-fn send_key_sendinput(code: u16, is_key_up: bool) {
+/// Sends the virtual key code. If `scancode` is true, the key is sent as the scancode that the
+/// virtual key maps to instead, for applications that read scancodes and ignore virtual keys.
+fn send_key_sendinput(code: u16, is_key_up: bool, scancode: bool) {
+    // Not defined in winapi. Maps to a scancode with a 0xE0 prefix for extended keys.
+    const MAPVK_VK_TO_VSC_EX: u32 = 4;
+    unsafe {
+        let mut kb_input: KEYBDINPUT = mem::zeroed();
+        if is_key_up {
+            kb_input.dwFlags |= KEYEVENTF_KEYUP;
+        }
+        let sc = match scancode {
+            true => MapVirtualKeyW(code.into(), MAPVK_VK_TO_VSC_EX),
+            false => 0,
+        };
+        if sc == 0 {
+            kb_input.wVk = code;
+        } else {
+            kb_input.wScan = (sc & 0xFF) as u16;
+            kb_input.dwFlags |= KEYEVENTF_SCANCODE;
+            if sc & 0xFF00 == 0xE000 {
+                kb_input.dwFlags |= KEYEVENTF_EXTENDEDKEY;
+            }
+        }
+
+        let mut inputs: [INPUT; 1] = mem::zeroed();
+        inputs[0].type_ = INPUT_KEYBOARD;
+        *inputs[0].u.ki_mut() = kb_input;
+        SendInput(1, inputs.as_mut_ptr(), mem::size_of::<INPUT>() as _);
+    }
+}
+
+/// Sends the hardware scancode, written with the E0 prefix as 0xE000 for extended keys. This is
+/// for keys that are written as scancodes in the configuration and have no virtual key.
+#[cfg(not(feature = "interception_driver"))]
+fn send_scancode_sendinput(sc: u16, is_key_up: bool) {
     unsafe {
         let mut kb_input: KEYBDINPUT = mem::zeroed();
+        kb_input.wScan = sc & 0xFF;
+        kb_input.dwFlags |= KEYEVENTF_SCANCODE;
+        if sc & 0xFF00 == 0xE000 {
+            kb_input.dwFlags |= KEYEVENTF_EXTENDEDKEY;
+        }
         if is_key_up {
             kb_input.dwFlags |= KEYEVENTF_KEYUP;
         }
-        kb_input.wVk = code;
 
         let mut inputs: [INPUT; 1] = mem::zeroed();
         inputs[0].type_ = INPUT_KEYBOARD;
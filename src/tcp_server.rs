@@ -1,16 +1,181 @@
+use crate::kanata::{HistoryStep, INVERT_SCROLL};
+use crate::oskbd::{KeyEvent, KeyValue};
 use crate::Kanata;
+use kanata_parser::custom_action::FakeKeyAction;
+use kanata_parser::keys::OsCode;
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::net::{TcpListener, TcpStream};
 use std::str::FromStr;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::mpsc::SyncSender as Sender;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 type HashMap<K, V> = rustc_hash::FxHashMap<K, V>;
 
+/// Version of the TCP protocol. This is incremented when existing messages change in a way that
+/// is not compatible with older clients. Adding new messages does not change the version; clients
+/// can check `Hello` for the messages that are supported instead.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Names of the messages that the server sends.
+const SERVER_MESSAGES: &[&str] = &[
+    "LayerChange",
+    "MousePosition",
+    "KeyTiming",
+    "TypingStats",
+    "BreakStarted",
+    "BreakEnded",
+    "Help",
+    "Hello",
+    "KeyAction",
+    "ActionUsage",
+    "InvertScroll",
+    "SequenceProgress",
+    "Error",
+];
+
+/// Names of the messages that the server accepts from clients.
+const CLIENT_MESSAGES: &[&str] = &[
+    "ChangeLayer",
+    "ResyncMods",
+    "ActOnFakeKey",
+    "Batch",
+    "Hello",
+    "SetLayerChangeDelay",
+    "CancelMacros",
+    "SetMacroDelayPercent",
+    "RequestKeyAction",
+    "SetKeyAction",
+    "UndoKeyAction",
+    "RedoKeyAction",
+    "RequestActionUsage",
+    "RequestInvertScroll",
+];
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ServerMessage {
-    LayerChange { new: String },
+    LayerChange {
+        new: String,
+    },
+    MousePosition {
+        x: i32,
+        y: i32,
+    },
+    /// Sent for every input key event when the typing rhythm export is enabled. The key is never
+    /// included. `interval_ms` is the time since the previous key event, if there was one.
+    KeyTiming {
+        event: KeyTimingEvent,
+        interval_ms: Option<u64>,
+    },
+    /// Sent when the estimated typing speed or accuracy changes, if `tcp-typing-stats-interval` is
+    /// configured. `wpm` is words per minute over the last minute and `accuracy` is a percentage.
+    TypingStats {
+        wpm: u16,
+        accuracy: u8,
+    },
+    /// Sent when a break from typing starts because of `break-after-typing`. The default layer is
+    /// `break-layer` for the next `seconds`.
+    BreakStarted {
+        seconds: u16,
+    },
+    /// Sent when a break from typing ends. `overridden` is true if it was ended early by
+    /// `break-override-keys`.
+    BreakEnded {
+        overridden: bool,
+    },
+    /// Sent when `show-help` is activated. Contains the keys that the current layer changes.
+    Help {
+        layer: String,
+        bindings: Vec<HelpBinding>,
+    },
+    /// Reply to a `Hello` message from a client. This is always sent as JSON. Messages that follow
+    /// it use `encoding`.
+    Hello {
+        version: String,
+        protocol_version: u32,
+        server_messages: Vec<String>,
+        client_messages: Vec<String>,
+        encodings: Vec<Encoding>,
+        encoding: Encoding,
+    },
+    /// Reply to a `RequestKeyAction` message from a client, sent only to that client. `action`
+    /// describes the action with aliases resolved, see `Kanata::key_action_json`.
+    KeyAction {
+        layer: String,
+        key: String,
+        action: serde_json::Value,
+    },
+    /// Reply to a `RequestActionUsage` message from a client, sent only to that client. Contains
+    /// the number of presses of each binding since kanata started or a live reload rebuilt the
+    /// layers, and the defined aliases that were never used.
+    ActionUsage {
+        bindings: Vec<BindingUsage>,
+        unused_aliases: Vec<String>,
+    },
+    /// Reply to a `RequestInvertScroll` message from a client, sent only to that client. `on` is
+    /// true if `invert-scroll-toggle` has inverted scrolling.
+    InvertScroll {
+        on: bool,
+    },
+    /// Sent when sequence mode starts or ends and when a key is typed in sequence mode. `keys` are
+    /// the typed keys, with the prefixes of held modifiers, e.g. `S-A`. `pending` is the fake key of
+    /// the sequence that the keys complete if no key that continues a longer sequence follows.
+    SequenceProgress {
+        active: bool,
+        keys: Vec<String>,
+        pending: Option<String>,
+    },
+    /// Reply to a request from a client that could not be answered, sent only to that client.
+    Error {
+        msg: String,
+    },
+}
+
+/// A `defsrc` key and the text of its item in the layer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HelpBinding {
+    pub key: String,
+    pub action: String,
+}
+
+/// A binding of a layer and the number of times that it was pressed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BindingUsage {
+    pub layer: String,
+    pub key: String,
+    pub action: String,
+    pub presses: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyTimingEvent {
+    Press,
+    Release,
+}
+
+/// Encoding of the messages sent by the server. Messages sent by clients are always JSON.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encoding {
+    #[default]
+    Json,
+    /// MessagePack, with the same structure as the JSON messages.
+    MessagePack,
+}
+
+impl ServerMessage {
+    fn hello(encoding: Encoding) -> Self {
+        Self::Hello {
+            version: env!("CARGO_PKG_VERSION").into(),
+            protocol_version: PROTOCOL_VERSION,
+            server_messages: SERVER_MESSAGES.iter().map(|m| m.to_string()).collect(),
+            client_messages: CLIENT_MESSAGES.iter().map(|m| m.to_string()).collect(),
+            encodings: vec![Encoding::Json, Encoding::MessagePack],
+            encoding,
+        }
+    }
 }
 
 #[test]
@@ -21,9 +186,289 @@ fn layer_change_serializes() {
     .expect("ServerMessage serializes");
 }
 
+#[test]
+fn mouse_position_serializes() {
+    assert_eq!(
+        serde_json::to_string(&ServerMessage::MousePosition { x: 10, y: -20 })
+            .expect("ServerMessage serializes"),
+        r#"{"MousePosition":{"x":10,"y":-20}}"#
+    );
+}
+
+#[test]
+fn key_timing_serializes() {
+    assert_eq!(
+        serde_json::to_string(&ServerMessage::KeyTiming {
+            event: KeyTimingEvent::Release,
+            interval_ms: Some(87),
+        })
+        .expect("ServerMessage serializes"),
+        r#"{"KeyTiming":{"event":"Release","interval_ms":87}}"#,
+    );
+}
+
+#[test]
+fn help_serializes() {
+    assert_eq!(
+        serde_json::to_string(&ServerMessage::Help {
+            layer: "nav".into(),
+            bindings: vec![HelpBinding {
+                key: "h".into(),
+                action: "left".into(),
+            }],
+        })
+        .expect("ServerMessage serializes"),
+        r#"{"Help":{"layer":"nav","bindings":[{"key":"h","action":"left"}]}}"#,
+    );
+}
+
+#[test]
+fn sequence_progress_serializes() {
+    assert_eq!(
+        serde_json::to_string(&ServerMessage::SequenceProgress {
+            active: true,
+            keys: vec!["G".into(), "S-S".into()],
+            pending: Some("git-status".into()),
+        })
+        .expect("ServerMessage serializes"),
+        r#"{"SequenceProgress":{"active":true,"keys":["G","S-S"],"pending":"git-status"}}"#,
+    );
+}
+
+#[test]
+fn client_messages_are_read_until_complete() {
+    // The second read ends in the middle of the last message.
+    let first_read: &[u8] = br#"{"ChangeLayer":{"new":"nav"}}{"RequestActionUsage":{}}
+{"ChangeLayer":"#;
+    let second_read: &[u8] = br#"
+  {"new":"base"}}
+"#;
+    let messages: Vec<_> = ClientMessageReader::new(first_read.chain(second_read))
+        .map(|m| format!("{:?}", m.expect("message is valid")))
+        .collect();
+    assert_eq!(
+        messages,
+        [
+            r#"ChangeLayer { new: "nav" }"#,
+            "RequestActionUsage",
+            r#"ChangeLayer { new: "base" }"#,
+        ]
+    );
+}
+
+#[test]
+fn hello_serializes() {
+    let hello = serde_json::to_string(&ServerMessage::hello(Encoding::Json))
+        .expect("ServerMessage serializes");
+    assert!(hello.starts_with(r#"{"Hello":{"version":"#));
+    assert!(hello.contains(r#""protocol_version":2"#));
+    assert!(hello.contains(r#""ResyncMods""#));
+    assert!(hello.ends_with(r#""encodings":["Json","MessagePack"],"encoding":"Json"}}"#));
+}
+
+#[test]
+fn layer_change_encodes_to_msgpack() {
+    let mut expected = vec![0x81, 0xab];
+    expected.extend(b"LayerChange");
+    expected.extend([0x81, 0xa3]);
+    expected.extend(b"new");
+    expected.extend([0xa1, b'a']);
+    assert_eq!(
+        ServerMessage::LayerChange { new: "a".into() }.encode(Encoding::MessagePack),
+        expected
+    );
+}
+
+#[test]
+fn mouse_position_encodes_to_msgpack() {
+    let mut expected = vec![0x81, 0xad];
+    expected.extend(b"MousePosition");
+    expected.extend([0x82, 0xa1, b'x', 0xcd, 0x01, 0x2c, 0xa1, b'y', 0xec]);
+    assert_eq!(
+        ServerMessage::MousePosition { x: 300, y: -20 }.encode(Encoding::MessagePack),
+        expected
+    );
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientMessage {
-    ChangeLayer { new: String },
+    /// Requests a `Hello` reply describing the protocol that the server supports. The server
+    /// sends the messages that follow the reply with `encoding`.
+    Hello {
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    ChangeLayer {
+        new: String,
+    },
+    ResyncMods,
+    ActOnFakeKey {
+        name: String,
+        action: FakeKeyActionMessage,
+    },
+    /// Only send layer changes to this client once the new layer has stayed active for `ms`
+    /// milliseconds. Layers that are active for a shorter time, e.g. momentary layers used while
+    /// typing fast, are not sent. 0, the default, sends every layer change immediately.
+    SetLayerChangeDelay {
+        ms: u16,
+    },
+    /// Stops all running macros, like the `cancel-macros` action.
+    CancelMacros,
+    /// Scales the delays of macros by `percent`, e.g. 50 to run macros twice as fast. This lasts
+    /// until the configuration is reloaded.
+    SetMacroDelayPercent {
+        percent: u16,
+    },
+    /// Applies all of the commands together, without any key events being processed in between.
+    /// If any command is invalid, none of them are applied.
+    Batch {
+        commands: Vec<ClientMessage>,
+    },
+    /// Requests a `KeyAction` reply with the action of the `defsrc` key named `key` in `layer`.
+    RequestKeyAction {
+        layer: String,
+        key: String,
+    },
+    /// Changes the action of the `defsrc` key named `key` in `layer` to `action`, written as in
+    /// the configuration, without reloading it. The action takes effect once no keys are held.
+    /// The reply is a `KeyAction` with the new action.
+    /// The change is written to the configuration file if `persist` is true; otherwise the next
+    /// live reload discards it.
+    SetKeyAction {
+        layer: String,
+        key: String,
+        action: String,
+        #[serde(default)]
+        persist: bool,
+    },
+    /// Undoes the last change made by `SetKeyAction` since the last live reload. The reply is a
+    /// `KeyAction` with the action of the key that changed back. `persist` is as in `SetKeyAction`.
+    UndoKeyAction {
+        #[serde(default)]
+        persist: bool,
+    },
+    /// Redoes the last change undone by `UndoKeyAction`. Changes can no longer be redone once
+    /// another change is made with `SetKeyAction`.
+    RedoKeyAction {
+        #[serde(default)]
+        persist: bool,
+    },
+    /// Requests an `ActionUsage` reply with the number of presses of each binding.
+    RequestActionUsage {},
+    /// Requests an `InvertScroll` reply with whether scrolling is inverted.
+    RequestInvertScroll {},
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum FakeKeyActionMessage {
+    Press,
+    Release,
+    Tap,
+    Toggle,
+}
+
+impl From<FakeKeyActionMessage> for FakeKeyAction {
+    fn from(action: FakeKeyActionMessage) -> Self {
+        match action {
+            FakeKeyActionMessage::Press => FakeKeyAction::Press,
+            FakeKeyActionMessage::Release => FakeKeyAction::Release,
+            FakeKeyActionMessage::Tap => FakeKeyAction::Tap,
+            FakeKeyActionMessage::Toggle => FakeKeyAction::Toggle,
+        }
+    }
+}
+
+#[test]
+fn batch_deserializes() {
+    let msg = r#"{"Batch":{"commands":[{"ChangeLayer":{"new":"nav"}},{"ActOnFakeKey":{"name":"f1","action":"Tap"}},"ResyncMods"]}}"#;
+    match msg.parse::<ClientMessage>() {
+        Ok(ClientMessage::Batch { commands }) => assert_eq!(commands.len(), 3),
+        other => panic!("unexpected parse result {other:?}"),
+    }
+}
+
+#[test]
+fn key_action_messages() {
+    assert!(matches!(
+        r#"{"RequestKeyAction":{"layer":"nav","key":"h"}}"#.parse::<ClientMessage>(),
+        Ok(ClientMessage::RequestKeyAction { layer, key }) if layer == "nav" && key == "h"
+    ));
+    assert_eq!(
+        serde_json::to_string(&ServerMessage::KeyAction {
+            layer: "nav".into(),
+            key: "h".into(),
+            action: serde_json::json!({"type": "KeyCode", "key": "Left"}),
+        })
+        .expect("ServerMessage serializes"),
+        r#"{"KeyAction":{"layer":"nav","key":"h","action":{"key":"Left","type":"KeyCode"}}}"#,
+    );
+    assert!(matches!(
+        r#"{"SetKeyAction":{"layer":"base","key":"f13","action":"(macro h i)"}}"#.parse::<ClientMessage>(),
+        Ok(ClientMessage::SetKeyAction { layer, key, action, persist: false })
+            if layer == "base" && key == "f13" && action == "(macro h i)"
+    ));
+    assert!(matches!(
+        r#"{"UndoKeyAction":{}}"#.parse::<ClientMessage>(),
+        Ok(ClientMessage::UndoKeyAction { persist: false })
+    ));
+    assert!(matches!(
+        r#"{"RedoKeyAction":{"persist":true}}"#.parse::<ClientMessage>(),
+        Ok(ClientMessage::RedoKeyAction { persist: true })
+    ));
+}
+
+#[test]
+fn action_usage_messages() {
+    assert!(matches!(
+        r#"{"RequestActionUsage":{}}"#.parse::<ClientMessage>(),
+        Ok(ClientMessage::RequestActionUsage {})
+    ));
+    assert_eq!(
+        serde_json::to_string(&ServerMessage::ActionUsage {
+            bindings: vec![BindingUsage {
+                layer: "nav".into(),
+                key: "h".into(),
+                action: "left".into(),
+                presses: 3,
+            }],
+            unused_aliases: vec!["cap".into()],
+        })
+        .expect("ServerMessage serializes"),
+        r#"{"ActionUsage":{"bindings":[{"layer":"nav","key":"h","action":"left","presses":3}],"unused_aliases":["cap"]}}"#,
+    );
+}
+
+#[test]
+fn invert_scroll_messages() {
+    assert!(matches!(
+        r#"{"RequestInvertScroll":{}}"#.parse::<ClientMessage>(),
+        Ok(ClientMessage::RequestInvertScroll {})
+    ));
+    assert_eq!(
+        serde_json::to_string(&ServerMessage::InvertScroll { on: true })
+            .expect("ServerMessage serializes"),
+        r#"{"InvertScroll":{"on":true}}"#,
+    );
+}
+
+#[test]
+fn resync_mods_deserializes() {
+    assert!(matches!(
+        "\"ResyncMods\"".parse::<ClientMessage>(),
+        Ok(ClientMessage::ResyncMods)
+    ));
+}
+
+#[test]
+fn macro_controls_deserialize() {
+    assert!(matches!(
+        "\"CancelMacros\"".parse::<ClientMessage>(),
+        Ok(ClientMessage::CancelMacros)
+    ));
+    assert!(matches!(
+        r#"{"SetMacroDelayPercent":{"percent":50}}"#.parse::<ClientMessage>(),
+        Ok(ClientMessage::SetMacroDelayPercent { percent: 50 })
+    ));
 }
 
 impl ServerMessage {
@@ -33,6 +478,16 @@ impl ServerMessage {
             .as_bytes()
             .to_vec()
     }
+
+    pub fn encode(&self, encoding: Encoding) -> Vec<u8> {
+        match encoding {
+            Encoding::Json => self.as_bytes(),
+            // Structs are written as maps so that the fields have the same names as in JSON.
+            Encoding::MessagePack => {
+                rmp_serde::to_vec_named(self).expect("ServerMessage should serialize")
+            }
+        }
+    }
 }
 
 impl FromStr for ClientMessage {
@@ -43,9 +498,174 @@ impl FromStr for ClientMessage {
     }
 }
 
+/// Longest client message that is accepted. Longer messages are treated as invalid, so that a
+/// client can not make the server buffer an unbounded amount of data.
+const MAX_CLIENT_MESSAGE_LEN: usize = 1024 * 1024;
+
+/// Reads the messages sent by a client, in order.
+///
+/// The messages are JSON values that are read until they are complete, so a message may arrive in
+/// several reads and a read may contain several messages. Whitespace such as newlines between the
+/// messages is ignored. The iterator ends when the client disconnects.
+struct ClientMessageReader<R> {
+    stream: R,
+    /// Bytes that were read but are not part of a returned message yet.
+    buf: Vec<u8>,
+}
+
+impl<R: Read> ClientMessageReader<R> {
+    fn new(stream: R) -> Self {
+        Self {
+            stream,
+            buf: vec![],
+        }
+    }
+}
+
+impl<R: Read> Iterator for ClientMessageReader<R> {
+    type Item = serde_json::Result<ClientMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut messages =
+                serde_json::Deserializer::from_slice(&self.buf).into_iter::<ClientMessage>();
+            match messages.next() {
+                Some(Ok(message)) => {
+                    let len = messages.byte_offset();
+                    self.buf.drain(..len);
+                    return Some(Ok(message));
+                }
+                // Only whitespace was read.
+                None => self.buf.clear(),
+                Some(Err(e)) if e.is_eof() => {
+                    if self.buf.len() > MAX_CLIENT_MESSAGE_LEN {
+                        return Some(Err(serde::de::Error::custom("message is too long")));
+                    }
+                }
+                Some(Err(e)) => return Some(Err(e)),
+            }
+            let mut chunk = [0; 1024];
+            match self.stream.read(&mut chunk) {
+                Ok(0) | Err(_) => return None,
+                Ok(size) => self.buf.extend_from_slice(&chunk[..size]),
+            }
+        }
+    }
+}
+
+/// A connected client.
+pub struct Client {
+    pub stream: TcpStream,
+    /// Encoding of the messages sent to the client.
+    pub encoding: Encoding,
+    /// Layer changes are only sent once the new layer has stayed active for this long.
+    layer_change_delay: Duration,
+    /// The most recent layer change that has not been sent yet, and when it happened.
+    pending_layer: Option<(String, Instant)>,
+    /// The layer of the most recent layer change sent to the client.
+    sent_layer: String,
+}
+
+impl Client {
+    fn new(stream: TcpStream, layer: String) -> Self {
+        Self {
+            stream,
+            encoding: Encoding::Json,
+            layer_change_delay: Duration::ZERO,
+            pending_layer: None,
+            sent_layer: layer,
+        }
+    }
+
+    /// Returns when the delayed layer change should be sent, if there is one.
+    pub fn pending_deadline(&self) -> Option<Instant> {
+        self.pending_layer
+            .as_ref()
+            .map(|(_, since)| *since + self.layer_change_delay)
+    }
+
+    /// Sends the delayed layer change if its layer has stayed active for long enough. Nothing is
+    /// sent if the layer is the one the client was last told about.
+    pub fn send_pending_layer(&mut self, now: Instant) -> io::Result<()> {
+        match self.pending_deadline() {
+            Some(deadline) if deadline <= now => {}
+            _ => return Ok(()),
+        }
+        let (new, _) = self.pending_layer.take().expect("deadline exists");
+        if new == self.sent_layer {
+            return Ok(());
+        }
+        self.sent_layer = new.clone();
+        self.stream
+            .write_all(&ServerMessage::LayerChange { new }.encode(self.encoding))
+    }
+
+    /// Sends a reply to a message of the client. Callers hold the lock of the connections while
+    /// calling this, so that the reply is never written in the middle of a notification.
+    fn reply(&mut self, msg: &ServerMessage) -> io::Result<()> {
+        self.stream.write_all(&msg.encode(self.encoding))
+    }
+
+    /// Sends the message, unless it is a layer change that the client wants delayed.
+    pub fn notify(&mut self, msg: &mut EncodedMessage) -> io::Result<()> {
+        if let ServerMessage::LayerChange { new } = msg.msg {
+            if !self.layer_change_delay.is_zero() {
+                self.pending_layer = Some((new.clone(), Instant::now()));
+                return Ok(());
+            }
+            self.sent_layer = new.clone();
+        }
+        self.stream.write_all(msg.get(self.encoding))
+    }
+}
+
+/// A message to send to clients. Each encoding of the message is only computed once.
+pub struct EncodedMessage<'a> {
+    msg: &'a ServerMessage,
+    json: Option<Vec<u8>>,
+    msgpack: Option<Vec<u8>>,
+}
+
+impl<'a> EncodedMessage<'a> {
+    pub fn new(msg: &'a ServerMessage) -> Self {
+        Self {
+            msg,
+            json: None,
+            msgpack: None,
+        }
+    }
+
+    fn get(&mut self, encoding: Encoding) -> &[u8] {
+        let msg = self.msg;
+        match encoding {
+            Encoding::Json => self.json.get_or_insert_with(|| msg.as_bytes()),
+            Encoding::MessagePack => self
+                .msgpack
+                .get_or_insert_with(|| msg.encode(Encoding::MessagePack)),
+        }
+    }
+}
+
+pub type Connections = Arc<Mutex<HashMap<String, Client>>>;
+
+/// Returns the first socket passed to kanata by systemd socket activation, if there is one.
+#[cfg(target_os = "linux")]
+pub fn socket_activated_listener() -> io::Result<Option<TcpListener>> {
+    use std::os::unix::io::FromRawFd;
+    let Some(fd) = sd_notify::listen_fds()?.next() else {
+        return Ok(None);
+    };
+    // SAFETY: the service manager hands the file descriptor over to kanata, which is its only
+    // owner from here on.
+    Ok(Some(unsafe { TcpListener::from_raw_fd(fd) }))
+}
+
 pub struct TcpServer {
     pub port: i32,
-    pub connections: Arc<Mutex<HashMap<String, TcpStream>>>,
+    pub connections: Connections,
+    /// A socket that is already listening, e.g. one passed by systemd socket activation. If there
+    /// is none, the server binds to `port`.
+    listener: Option<TcpListener>,
 }
 
 impl TcpServer {
@@ -53,12 +673,29 @@ impl TcpServer {
         Self {
             port,
             connections: Arc::new(Mutex::new(HashMap::default())),
+            listener: None,
         }
     }
 
-    pub fn start(&mut self, kanata: Arc<Mutex<Kanata>>) {
-        let listener =
-            TcpListener::bind(format!("0.0.0.0:{}", self.port)).expect("TCP server starts");
+    /// Creates a server that accepts clients on a socket that is already listening.
+    pub fn from_listener(listener: TcpListener) -> Self {
+        let port = listener
+            .local_addr()
+            .map(|addr| i32::from(addr.port()))
+            .unwrap_or_default();
+        log::info!("using the socket passed by the service manager, port {port}");
+        Self {
+            listener: Some(listener),
+            ..Self::new(port)
+        }
+    }
+
+    /// Starts accepting clients. Events are sent to `wakeup_channel` after handling a client
+    /// message, so that the processing loop applies the changes even if it was waiting for input.
+    pub fn start(&mut self, kanata: Arc<Mutex<Kanata>>, wakeup_channel: Sender<KeyEvent>) {
+        let listener = self.listener.take().unwrap_or_else(|| {
+            TcpListener::bind(format!("0.0.0.0:{}", self.port)).expect("TCP server starts")
+        });
 
         let connections = self.connections.clone();
 
@@ -66,20 +703,18 @@ impl TcpServer {
             for stream in listener.incoming() {
                 match stream {
                     Ok(mut stream) => {
-                        {
+                        let layer = {
                             let k = kanata.lock();
-                            log::info!(
-                                "new client connection, sending initial LayerChange event to inform them of current layer"
-                            );
-                            if let Err(e) = stream.write(
-                                &ServerMessage::LayerChange {
-                                    new: k.layer_info[k.layout.b().current_layer()].name.clone(),
-                                }
-                                .as_bytes(),
-                            ) {
-                                log::warn!("failed to write to stream, dropping it: {e:?}");
-                                continue;
-                            }
+                            k.layer_info[k.layout.b().current_layer()].name.clone()
+                        };
+                        log::info!(
+                            "new client connection, sending initial LayerChange event to inform them of current layer"
+                        );
+                        if let Err(e) = stream
+                            .write(&ServerMessage::LayerChange { new: layer.clone() }.as_bytes())
+                        {
+                            log::warn!("failed to write to stream, dropping it: {e:?}");
+                            continue;
                         }
 
                         let addr = stream
@@ -89,28 +724,22 @@ impl TcpServer {
 
                         connections.lock().insert(
                             addr.clone(),
-                            stream.try_clone().expect("stream is clonable"),
+                            Client::new(stream.try_clone().expect("stream is clonable"), layer),
                         );
 
                         log::info!("listening for incoming messages {}", &addr);
 
                         let connections = connections.clone();
                         let kanata = kanata.clone();
-                        std::thread::spawn(move || loop {
-                            let mut buf = vec![0; 1024];
-                            match stream.read(&mut buf) {
-                                Ok(size) => {
-                                    if let Ok(event) = ClientMessage::from_str(
-                                        &String::from_utf8_lossy(&buf[..size]),
-                                    ) {
-                                        match event {
-                                            ClientMessage::ChangeLayer { new } => {
-                                                kanata.lock().change_layer(new);
-                                            }
-                                        }
-                                    } else {
+                        let wakeup_channel = wakeup_channel.clone();
+                        std::thread::spawn(move || {
+                            let reader = stream.try_clone().expect("stream is clonable");
+                            for message in ClientMessageReader::new(reader) {
+                                let event = match message {
+                                    Ok(event) => event,
+                                    Err(e) => {
                                         log::warn!(
-                                            "client sent an invalid message of size {size}, disconnecting them"
+                                            "client sent an invalid message, disconnecting them: {e}"
                                         );
                                         // Ignore write result because we're about to disconnect
                                         // the client anyway.
@@ -119,15 +748,117 @@ impl TcpServer {
                                                 .as_bytes(),
                                         );
                                         connections.lock().remove(&addr);
-                                        break;
+                                        return;
                                     }
+                                };
+                                if let ClientMessage::Hello { encoding } = event {
+                                    if let Some(client) = connections.lock().get_mut(&addr) {
+                                        // Hello is always JSON, the messages that follow
+                                        // it use the requested encoding.
+                                        client.encoding = Encoding::Json;
+                                        if let Err(e) =
+                                            client.reply(&ServerMessage::hello(encoding))
+                                        {
+                                            log::warn!("failed to write hello to {addr}: {e:?}");
+                                        }
+                                        client.encoding = encoding;
+                                    }
+                                    continue;
+                                }
+                                let reply = match &event {
+                                    ClientMessage::RequestKeyAction { layer, key } => {
+                                        Some(match kanata.lock().key_action_json(layer, key) {
+                                            Ok(action) => ServerMessage::KeyAction {
+                                                layer: layer.clone(),
+                                                key: key.clone(),
+                                                action,
+                                            },
+                                            Err(e) => ServerMessage::Error { msg: e.to_string() },
+                                        })
+                                    }
+                                    ClientMessage::SetKeyAction {
+                                        layer,
+                                        key,
+                                        action,
+                                        persist,
+                                    } => Some({
+                                        let mut k = kanata.lock();
+                                        match k
+                                            .set_key_action(layer, key, action, *persist)
+                                            .and_then(|_| k.key_action_json(layer, key))
+                                        {
+                                            Ok(action) => ServerMessage::KeyAction {
+                                                layer: layer.clone(),
+                                                key: key.clone(),
+                                                action,
+                                            },
+                                            Err(e) => ServerMessage::Error { msg: e.to_string() },
+                                        }
+                                    }),
+                                    ClientMessage::UndoKeyAction { persist }
+                                    | ClientMessage::RedoKeyAction { persist } => Some({
+                                        let step =
+                                            if matches!(event, ClientMessage::UndoKeyAction { .. })
+                                            {
+                                                HistoryStep::Undo
+                                            } else {
+                                                HistoryStep::Redo
+                                            };
+                                        let mut k = kanata.lock();
+                                        match k.step_key_action_history(step, *persist).and_then(
+                                            |(layer, key)| {
+                                                let action = k.key_action_json(&layer, &key)?;
+                                                Ok((layer, key, action))
+                                            },
+                                        ) {
+                                            Ok((layer, key, action)) => {
+                                                ServerMessage::KeyAction { layer, key, action }
+                                            }
+                                            Err(e) => ServerMessage::Error { msg: e.to_string() },
+                                        }
+                                    }),
+                                    ClientMessage::RequestActionUsage {} => {
+                                        Some(kanata.lock().action_usage_message())
+                                    }
+                                    ClientMessage::RequestInvertScroll {} => {
+                                        Some(ServerMessage::InvertScroll {
+                                            on: INVERT_SCROLL.load(SeqCst),
+                                        })
+                                    }
+                                    _ => None,
+                                };
+                                if let Some(reply) = reply {
+                                    if let Some(client) = connections.lock().get_mut(&addr) {
+                                        if let Err(e) = client.reply(&reply) {
+                                            log::warn!("failed to write reply to {addr}: {e:?}");
+                                        }
+                                    }
+                                    // Changed key actions are swapped into the layout by
+                                    // the processing loop.
+                                    if matches!(
+                                        event,
+                                        ClientMessage::SetKeyAction { .. }
+                                            | ClientMessage::UndoKeyAction { .. }
+                                            | ClientMessage::RedoKeyAction { .. }
+                                    ) {
+                                        wake_up_processing_loop(&wakeup_channel);
+                                    }
+                                    continue;
                                 }
-                                Err(_) => {
-                                    log::warn!("removing disconnected tcp client: {addr}");
-                                    connections.lock().remove(&addr);
-                                    break;
+                                if let ClientMessage::SetLayerChangeDelay { ms } = event {
+                                    if let Some(client) = connections.lock().get_mut(&addr) {
+                                        client.layer_change_delay =
+                                            Duration::from_millis(ms.into());
+                                    }
+                                    continue;
                                 }
+                                if let Err(e) = kanata.lock().handle_client_message(event) {
+                                    log::error!("failed to handle client message: {e:?}");
+                                }
+                                wake_up_processing_loop(&wakeup_channel);
                             }
+                            log::warn!("removing disconnected tcp client: {addr}");
+                            connections.lock().remove(&addr);
                         });
                     }
                     Err(_) => log::error!("not able to accept client connection"),
@@ -136,3 +867,11 @@ impl TcpServer {
         });
     }
 }
+
+/// Sends an event to the processing loop so that it applies a change made by a client message
+/// even if it was waiting for input.
+fn wake_up_processing_loop(wakeup_channel: &Sender<KeyEvent>) {
+    if let Err(e) = wakeup_channel.try_send(KeyEvent::new(OsCode::KEY_RESERVED, KeyValue::WakeUp)) {
+        log::warn!("failed to wake up processing loop: {e:?}");
+    }
+}
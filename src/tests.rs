@@ -72,3 +72,43 @@ fn sizeof_state() {
         2 * std::mem::size_of::<usize>()
     );
 }
+
+#[test]
+fn run_deftests_simple() {
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    crate::check::check_cfgs(&[std::path::PathBuf::from("./cfg_samples/simple.kbd")]).unwrap();
+}
+
+#[test]
+fn learn_generates_parsable_cfg() {
+    use crate::learn::{Learner, Step};
+    use kanata_parser::keys::OsCode;
+
+    let _lk = match CFG_PARSE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut learner = Learner::default();
+    for (osc, step) in [
+        (OsCode::KEY_ESC, Step::Added),
+        (OsCode::KEY_1, Step::Added),
+        (OsCode::KEY_1, Step::NewRow),
+        (OsCode::KEY_TAB, Step::Added),
+        (OsCode::KEY_1, Step::Duplicate),
+        (OsCode::KEY_PROG1, Step::Added),
+        (OsCode::KEY_ESC, Step::Done),
+    ] {
+        assert_eq!(learner.press(osc), step, "{osc:?}");
+    }
+    let cfg = learner.config(&["base".into(), "nav".into()]);
+    assert!(cfg.contains("(deflocalkeys-linux\n  k148 148\n)"), "{cfg}");
+    assert!(cfg.contains("(defsrc\n  esc  1\n  tab  k148\n)"), "{cfg}");
+    assert!(
+        cfg.contains("(deflayer nav\n  _    _\n  _    _\n)"),
+        "{cfg}"
+    );
+    new_from_str(&cfg).unwrap();
+}